@@ -1,10 +1,11 @@
 mod asn_count;
 mod listen_forward;
+mod sosistab_stats;
 
 use std::{
     net::{IpAddr, SocketAddr},
     str::FromStr,
-    sync::Arc,
+    sync::{Arc, Mutex},
     time::{Duration, SystemTime},
 };
 
@@ -17,12 +18,20 @@ use sillad::{
     dialer::DialerExt,
     tcp::{TcpDialer, TcpListener},
 };
-use sillad_sosistab3::{listener::SosistabListener, Cookie};
+use sillad_sosistab3::{listener::SosistabListener, Cookie, CookieSet, ProbeAction};
 use smol::future::FutureExt as _;
 
 use smol_timeout2::TimeoutExt;
+use sosistab_stats::{take_stats, StatsListener};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
+/// How often the bridge switches to a fresh cookie. Clients that learned of the previous cookie can still connect until the rotation after.
+const COOKIE_ROTATION_INTERVAL: Duration = Duration::from_secs(6 * 3600);
+
+fn new_control_cookie() -> String {
+    format!("bridge-cookie-{}", rand::random::<u128>())
+}
+
 #[global_allocator]
 static ALLOC: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
 
@@ -79,28 +88,55 @@ fn main() {
 
         let port = rand::thread_rng().gen_range(1024..10000);
         let control_listen = SocketAddr::new(my_ip, port);
-        let control_cookie = format!("bridge-cookie-{}", rand::random::<u128>());
+        let control_cookie = Arc::new(Mutex::new(new_control_cookie()));
+        let cookies = CookieSet::new(Cookie::new(&control_cookie.lock().unwrap()));
+
+        let probe_action: ProbeAction = std::env::var("GEPH5_BRIDGE_PROBE_ACTION")
+            .ok()
+            .and_then(|s| {
+                s.parse()
+                    .inspect_err(|err| {
+                        tracing::warn!(
+                            err = %err,
+                            "invalid GEPH5_BRIDGE_PROBE_ACTION, falling back to the default"
+                        )
+                    })
+                    .ok()
+            })
+            .unwrap_or_default();
 
         let upload_loop = broker_loop(control_listen, control_cookie.clone());
+        let rotate_loop = async {
+            loop {
+                smol::Timer::after(COOKIE_ROTATION_INTERVAL).await;
+                let new_cookie = new_control_cookie();
+                cookies.rotate(Cookie::new(&new_cookie));
+                *control_cookie.lock().unwrap() = new_cookie;
+                tracing::info!("rotated control cookie");
+            }
+        };
         let listen_loop = async {
             loop {
                 let listener = TcpListener::bind(format!("0.0.0.0:{port}").parse().unwrap())
                     .await
                     .unwrap();
 
-                let control_listener =
-                    SosistabListener::new(listener, Cookie::new(&control_cookie));
+                let control_listener = StatsListener(SosistabListener::with_cookies(
+                    listener,
+                    cookies.clone(),
+                    probe_action,
+                ));
                 if let Err(err) = listen_forward_loop(my_ip, control_listener).await {
                     tracing::error!(err = %err, "error in listen_forward_loop");
                 }
                 smol::Timer::after(Duration::from_secs(1)).await;
             }
         };
-        upload_loop.race(listen_loop).await
+        upload_loop.race(rotate_loop).race(listen_loop).await
     })
 }
 
-async fn broker_loop(control_listen: SocketAddr, control_cookie: String) {
+async fn broker_loop(control_listen: SocketAddr, control_cookie: Arc<Mutex<String>>) {
     let auth_token = std::env::var("GEPH5_BRIDGE_TOKEN").unwrap();
     let pool = std::env::var("GEPH5_BRIDGE_POOL").unwrap();
     let broker_addr: SocketAddr = std::env::var("GEPH5_BROKER_ADDR").unwrap().parse().unwrap();
@@ -129,6 +165,7 @@ async fn broker_loop(control_listen: SocketAddr, control_cookie: String) {
                 "uploading..."
             );
 
+            let control_cookie = control_cookie.lock().unwrap().clone();
             let res = async {
                 broker_rpc
                     .insert_bridge(Mac::new(
@@ -168,6 +205,28 @@ async fn broker_loop(control_listen: SocketAddr, control_cookie: String) {
                     .await
                     .context("incrementing bytes timed out")??;
 
+                // sosistab3 stats help spot DPI boxes that tamper with, or throttle, our control connections
+                let sosistab = take_stats();
+                for (name, value) in [
+                    ("decrypt_failures", sosistab.decrypt_failures),
+                    (
+                        "padding_bytes",
+                        sosistab.wire_bytes_sent - sosistab.data_bytes_sent,
+                    ),
+                    ("wire_bytes", sosistab.wire_bytes_sent),
+                ] {
+                    if value > 0 {
+                        broker_rpc
+                            .incr_stat(
+                                format!("{bridge_key}.sosistab.{name}"),
+                                value.min(i32::MAX as u64) as i32,
+                            )
+                            .timeout(Duration::from_secs(2))
+                            .await
+                            .context("incrementing sosistab stats timed out")??;
+                    }
+                }
+
                 let asn_bytes: Vec<(u32, u64)> = ASN_BYTES
                     .iter()
                     .map(|item| {
@@ -0,0 +1,155 @@
+use std::{
+    io::IoSlice,
+    pin::Pin,
+    sync::{Arc, Mutex, Weak},
+    task::{Context, Poll},
+};
+
+use async_trait::async_trait;
+use futures_util::{AsyncRead, AsyncWrite};
+use sillad::{listener::Listener, Pipe};
+use sillad_sosistab3::{listener::SosistabListener, PipeStats, SosistabPipe};
+
+/// Stats of all the sosistab3 pipes that closed since the last time they were taken.
+static CLOSED_STATS: Mutex<PipeStats> = Mutex::new(PipeStats {
+    records_sent: 0,
+    records_received: 0,
+    data_bytes_sent: 0,
+    data_bytes_received: 0,
+    wire_bytes_sent: 0,
+    wire_bytes_received: 0,
+    decrypt_failures: 0,
+});
+
+/// The stats of every sosistab3 pipe that is still open, so that long-lived pipes get reported too rather than only when they close.
+static LIVE_STATS: Mutex<Vec<Weak<Mutex<StatsSlot>>>> = Mutex::new(Vec::new());
+
+#[derive(Default)]
+struct StatsSlot {
+    /// The stats of the pipe as of its last read or write.
+    latest: PipeStats,
+    /// The part of `latest` that was already taken.
+    taken: PipeStats,
+}
+
+impl StatsSlot {
+    fn take_delta(&mut self) -> PipeStats {
+        let (latest, taken) = (self.latest, self.taken);
+        self.taken = latest;
+        PipeStats {
+            records_sent: latest.records_sent - taken.records_sent,
+            records_received: latest.records_received - taken.records_received,
+            data_bytes_sent: latest.data_bytes_sent - taken.data_bytes_sent,
+            data_bytes_received: latest.data_bytes_received - taken.data_bytes_received,
+            wire_bytes_sent: latest.wire_bytes_sent - taken.wire_bytes_sent,
+            wire_bytes_received: latest.wire_bytes_received - taken.wire_bytes_received,
+            decrypt_failures: latest.decrypt_failures - taken.decrypt_failures,
+        }
+    }
+}
+
+/// Takes the stats accumulated by sosistab3 pipes since the last call, whether they are still open or already closed.
+pub fn take_stats() -> PipeStats {
+    let mut stats = std::mem::take(&mut *CLOSED_STATS.lock().unwrap());
+    LIVE_STATS
+        .lock()
+        .unwrap()
+        .retain(|slot| match slot.upgrade() {
+            Some(slot) => {
+                stats += slot.lock().unwrap().take_delta();
+                true
+            }
+            None => false,
+        });
+    stats
+}
+
+/// Wraps a sosistab3 listener so that the stats of every pipe it accepts get accumulated.
+pub struct StatsListener<P: Pipe>(pub SosistabListener<P>);
+
+#[async_trait]
+impl<P: Pipe> Listener for StatsListener<P> {
+    type P = StatsPipe<P>;
+
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        let pipe = self.inner.accept().await?;
+        let slot = Arc::new(Mutex::new(StatsSlot::default()));
+        LIVE_STATS.lock().unwrap().push(Arc::downgrade(&slot));
+        Ok(StatsPipe { inner: pipe, slot })
+    }
+}
+
+pub struct StatsPipe<P: Pipe> {
+    inner: SosistabPipe<P>,
+    slot: Arc<Mutex<StatsSlot>>,
+}
+
+impl<P: Pipe> StatsPipe<P> {
+    fn record<T>(&self, result: T) -> T {
+        self.slot.lock().unwrap().latest = self.inner.stats();
+        result
+    }
+}
+
+impl<P: Pipe> Drop for StatsPipe<P> {
+    fn drop(&mut self) {
+        let mut slot = self.slot.lock().unwrap();
+        slot.latest = self.inner.stats();
+        *CLOSED_STATS.lock().unwrap() += slot.take_delta();
+    }
+}
+
+impl<P: Pipe> AsyncRead for StatsPipe<P> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+        self.record(result)
+    }
+}
+
+impl<P: Pipe> AsyncWrite for StatsPipe<P> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write(cx, buf);
+        self.record(result)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        let result = Pin::new(&mut self.inner).poll_write_vectored(cx, bufs);
+        self.record(result)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let result = Pin::new(&mut self.inner).poll_flush(cx);
+        self.record(result)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let result = Pin::new(&mut self.inner).poll_close(cx);
+        self.record(result)
+    }
+}
+
+impl<P: Pipe> Pipe for StatsPipe<P> {
+    fn protocol(&self) -> &str {
+        self.inner.protocol()
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.inner.remote_addr()
+    }
+
+    fn shared_secret(&self) -> Option<&[u8]> {
+        self.inner.shared_secret()
+    }
+}
@@ -0,0 +1,19 @@
+-- news items shown in the clients, one row per language
+create table if not exists news (
+    lang text not null,
+    title text not null,
+    date_unix bigint not null,
+    contents text not null
+);
+
+create index if not exists news_lang_date on news (lang, date_unix desc);
+
+-- logs and other debugging information uploaded by logged-in users
+create table if not exists debug_packs (
+    id text primary key,
+    user_id integer not null,
+    created bigint not null,
+    contents text not null
+);
+
+create index if not exists debug_packs_user_created on debug_packs (user_id, created);
@@ -6,7 +6,7 @@ use std::{
 };
 
 use async_io::Timer;
-use geph5_broker_protocol::BridgeDescriptor;
+use geph5_broker_protocol::{BridgeDescriptor, NewsItem};
 use moka::future::Cache;
 
 use rand::Rng;
@@ -55,6 +55,15 @@ pub async fn database_gc_loop() -> anyhow::Result<()> {
             .execute(POSTGRES.deref())
             .await?;
         tracing::debug!(rows_affected = res.rows_affected(), "cleaned up bridges");
+        let res =
+            sqlx::query("delete from debug_packs where created < extract(epoch from now()) - $1")
+                .bind(DEBUG_PACK_LIFETIME_SECS)
+                .execute(POSTGRES.deref())
+                .await?;
+        tracing::debug!(
+            rows_affected = res.rows_affected(),
+            "cleaned up debug packs"
+        );
     }
 }
 
@@ -169,3 +178,85 @@ JOIN updated u ON u.listen = sb.listen;
         .await
         .map_err(|e| anyhow::anyhow!(e))
 }
+
+/// The news in the given language, newest first. Languages that have no news of their own get the English news instead.
+pub async fn query_news(lang: &str) -> anyhow::Result<Vec<NewsItem>> {
+    static CACHE: LazyLock<Cache<String, Vec<NewsItem>>> = LazyLock::new(|| {
+        Cache::builder()
+            .time_to_live(Duration::from_secs(300))
+            .build()
+    });
+
+    CACHE
+        .try_get_with(lang.to_string(), async {
+            let mut news = vec![];
+            for lang in [lang, "en"] {
+                let raw: Vec<(String, i64, String)> = sqlx::query_as(
+                    "select title, date_unix, contents from news where lang = $1 order by date_unix desc limit 50",
+                )
+                .bind(lang)
+                .fetch_all(POSTGRES.deref())
+                .await?;
+                news = raw
+                    .into_iter()
+                    .map(|(title, date_unix, contents)| NewsItem {
+                        title,
+                        date_unix: date_unix as _,
+                        contents,
+                    })
+                    .collect();
+                if !news.is_empty() {
+                    break;
+                }
+            }
+            anyhow::Ok(news)
+        })
+        .await
+        .map_err(|e| anyhow::anyhow!(e))
+}
+
+/// How many debug packs one user can upload in a day.
+const DEBUG_PACKS_PER_DAY: i64 = 5;
+
+/// How many bytes of debug packs are stored at most, across all users. Uploads past this are refused until old packs are cleaned up.
+const DEBUG_PACK_QUOTA: i64 = 2_000_000_000;
+
+/// How long debug packs are kept.
+const DEBUG_PACK_LIFETIME_SECS: i64 = 30 * 86400;
+
+/// Stores a debug pack from the given user, returning the ID it can be looked up by. Fails if the user has uploaded too many recently, or if the storage quota is used up.
+pub async fn insert_debug_pack(user_id: i32, pack: &str) -> anyhow::Result<String> {
+    let mut txn = POSTGRES.begin().await?;
+    // serializes uploads from the same user, so that concurrent ones can't all slip under the limit
+    sqlx::query("select pg_advisory_xact_lock($1)")
+        .bind(user_id as i64)
+        .execute(&mut *txn)
+        .await?;
+    let (recent,): (i64,) = sqlx::query_as(
+        "select count(*) from debug_packs where user_id = $1 and created > extract(epoch from now()) - 86400",
+    )
+    .bind(user_id)
+    .fetch_one(&mut *txn)
+    .await?;
+    if recent >= DEBUG_PACKS_PER_DAY {
+        anyhow::bail!("too many debug packs uploaded today");
+    }
+    let (stored,): (i64,) =
+        sqlx::query_as("select coalesce(sum(octet_length(contents)), 0)::bigint from debug_packs")
+            .fetch_one(&mut *txn)
+            .await?;
+    if stored + pack.len() as i64 > DEBUG_PACK_QUOTA {
+        anyhow::bail!("debug pack storage is full");
+    }
+    let id = format!("{:016x}", rand::random::<u64>());
+    sqlx::query(
+        "insert into debug_packs (id, user_id, created, contents) values ($1, $2, extract(epoch from now()), $3)",
+    )
+    .bind(&id)
+    .bind(user_id)
+    .bind(pack)
+    .execute(&mut *txn)
+    .await?;
+    txn.commit().await?;
+    Ok(id)
+}
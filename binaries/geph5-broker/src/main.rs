@@ -124,6 +124,10 @@ async fn main() -> anyhow::Result<()> {
     Lazy::force(&PLUS_MIZARU_SK);
     Lazy::force(&FREE_MIZARU_SK);
     LazyLock::force(&database::POSTGRES);
+    sqlx::migrate!()
+        .run(&*database::POSTGRES)
+        .await
+        .context("Failed to migrate the database")?;
 
     let _gc_loop = Immortal::respawn(RespawnStrategy::Immediate, database_gc_loop);
     let _self_stat_loop = Immortal::respawn(RespawnStrategy::Immediate, self_stat_loop);
@@ -6,8 +6,8 @@ use ed25519_dalek::VerifyingKey;
 use futures_util::{future::join_all, TryFutureExt};
 use geph5_broker_protocol::{
     AccountLevel, AuthError, AvailabilityData, BridgeDescriptor, BrokerProtocol, BrokerService,
-    Credential, ExitDescriptor, ExitList, GenericError, Mac, RouteDescriptor, Signed, UserInfo,
-    DOMAIN_EXIT_DESCRIPTOR,
+    Credential, ExitDescriptor, ExitList, GenericError, Mac, NewsItem, RouteDescriptor, Signed,
+    UserInfo, DOMAIN_EXIT_DESCRIPTOR,
 };
 use isocountry::CountryCode;
 use mizaru2::{BlindedClientToken, BlindedSignature, ClientToken, UnblindedSignature};
@@ -24,7 +24,7 @@ use std::{
 use crate::{auth::get_subscription_expiry, log_error};
 use crate::{
     auth::{new_auth_token, valid_auth_token, validate_username_pwd},
-    database::{insert_exit, query_bridges, ExitRow, POSTGRES},
+    database::{insert_debug_pack, insert_exit, query_bridges, query_news, ExitRow, POSTGRES},
     routes::bridge_to_leaf_route,
     CONFIG_FILE, FREE_MIZARU_SK, MASTER_SECRET, PLUS_MIZARU_SK,
 };
@@ -195,6 +195,10 @@ impl BrokerProtocol for BrokerImpl {
         ))
     }
 
+    async fn get_news(&self, lang: String) -> Result<Vec<NewsItem>, GenericError> {
+        Ok(query_news(&lang).await?)
+    }
+
     async fn get_user_info(&self, auth_token: String) -> Result<Option<UserInfo>, AuthError> {
         static USER_INFO_CACHE: Lazy<Cache<String, Option<UserInfo>>> = Lazy::new(|| {
             Cache::builder()
@@ -364,8 +368,25 @@ impl BrokerProtocol for BrokerImpl {
         )
         .detach();
     }
+
+    async fn upload_debug_pack(
+        &self,
+        auth_token: String,
+        pack: String,
+    ) -> Result<String, GenericError> {
+        if pack.len() > MAX_DEBUG_PACK_SIZE {
+            return Err(GenericError("debug pack too large".into()));
+        }
+        let Some((user_id, _)) = valid_auth_token(&auth_token).await? else {
+            return Err(GenericError("not logged in".into()));
+        };
+        Ok(insert_debug_pack(user_id, &pack).await?)
+    }
 }
 
+/// The largest debug pack that gets stored, in bytes.
+const MAX_DEBUG_PACK_SIZE: usize = 2_000_000;
+
 pub static STATSD_CLIENT: Lazy<Option<StatsdClient>> = Lazy::new(|| {
     if let Some(statsd_addr) = CONFIG_FILE.wait().statsd_addr {
         let socket = std::net::UdpSocket::bind("0.0.0.0:0").unwrap();
@@ -0,0 +1,3 @@
+pub fn set_autostart(_enabled: bool) -> anyhow::Result<()> {
+    anyhow::bail!("launching at login is not supported on this platform")
+}
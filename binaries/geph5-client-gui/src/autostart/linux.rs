@@ -0,0 +1,69 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+/// Where the XDG autostart entry goes, which desktop environments read at login.
+fn desktop_entry_path() -> anyhow::Result<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .context("no config directory")?;
+    Ok(config_dir.join("autostart").join("geph5.desktop"))
+}
+
+/// Quotes a path as one argument of an Exec key. The Desktop Entry spec first has us quote the argument and escape field codes, and then escape the whole thing again as a string value.
+fn exec_quote(path: &str) -> String {
+    let mut quoted = String::from("\"");
+    for c in path.chars() {
+        match c {
+            '"' | '`' | '$' | '\\' => {
+                quoted.push('\\');
+                quoted.push(c);
+            }
+            '%' => quoted.push_str("%%"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    let mut escaped = String::new();
+    for c in quoted.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Adds or removes the GUI from the programs that the desktop starts at login.
+pub fn set_autostart(enabled: bool) -> anyhow::Result<()> {
+    let path = desktop_entry_path()?;
+    if enabled {
+        // inside an AppImage, the current executable lives in a mount that's gone once we exit, so we start the AppImage itself instead
+        let exe = match std::env::var_os("APPIMAGE") {
+            Some(appimage) => PathBuf::from(appimage),
+            None => std::env::current_exe()?,
+        };
+        let exe = exe.to_str().context("executable path is not valid UTF-8")?;
+        std::fs::create_dir_all(path.parent().context("no autostart directory")?)?;
+        std::fs::write(
+            &path,
+            format!(
+                "[Desktop Entry]\nType=Application\nName=Geph5\nExec={}\nIcon=geph5\nX-GNOME-Autostart-enabled=true\n",
+                exec_quote(exe)
+            ),
+        )
+        .context("Failed to write the autostart entry")?;
+    } else {
+        match std::fs::remove_file(&path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                return Err(err).context("Failed to remove the autostart entry")
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,57 @@
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+const AGENT_LABEL: &str = "io.geph.geph5";
+
+fn launch_agent_path() -> anyhow::Result<PathBuf> {
+    let home = std::env::var_os("HOME").context("no home directory")?;
+    Ok(PathBuf::from(home)
+        .join("Library/LaunchAgents")
+        .join(format!("{AGENT_LABEL}.plist")))
+}
+
+/// Adds or removes a LaunchAgent that starts the GUI when the current user logs in.
+pub fn set_autostart(enabled: bool) -> anyhow::Result<()> {
+    let path = launch_agent_path()?;
+    if enabled {
+        let exe = std::env::current_exe()?;
+        std::fs::create_dir_all(path.parent().context("no LaunchAgents directory")?)?;
+        std::fs::write(
+            &path,
+            format!(
+                r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <key>Label</key>
+    <string>{AGENT_LABEL}</string>
+    <key>ProgramArguments</key>
+    <array>
+        <string>{}</string>
+    </array>
+    <key>RunAtLoad</key>
+    <true/>
+</dict>
+</plist>
+"#,
+                xml_escape(&exe.display().to_string())
+            ),
+        )
+        .context("Failed to write the LaunchAgent")?;
+    } else {
+        match std::fs::remove_file(&path) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                return Err(err).context("Failed to remove the LaunchAgent")
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
@@ -0,0 +1,29 @@
+use anyhow::Context;
+use winreg::enums::*;
+use winreg::RegKey;
+
+const RUN_REG_PATH: &str = r"Software\Microsoft\Windows\CurrentVersion\Run";
+
+const RUN_VALUE_NAME: &str = "Geph5";
+
+/// Adds or removes the GUI from the programs that the current user's login starts.
+pub fn set_autostart(enabled: bool) -> anyhow::Result<()> {
+    let hkcu = RegKey::predef(HKEY_CURRENT_USER);
+    let (run_key, _) = hkcu
+        .create_subkey(RUN_REG_PATH)
+        .context("Failed to open the Run registry key")?;
+    if enabled {
+        let exe = std::env::current_exe()?;
+        run_key
+            .set_value(RUN_VALUE_NAME, &format!("\"{}\"", exe.display()))
+            .context("Failed to add the Run registry value")?;
+    } else {
+        match run_key.delete_value(RUN_VALUE_NAME) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => {
+                return Err(err).context("Failed to delete the Run registry value")
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
@@ -1,15 +1,26 @@
 mod inline;
 mod subproc;
 
-use std::sync::Arc;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
 
 use geph5_client::{Config, ControlClient};
 
 use once_cell::sync::Lazy;
 
-use crate::timeseries::TimeSeries;
+use crate::{
+    pac::{set_http_proxy, unset_http_proxy},
+    settings::{get_config, PROXY_AUTOCONF},
+    timeseries::TimeSeries,
+};
 
-pub static TOTAL_BYTES_TIMESERIES: TimeSeries = TimeSeries::new(60 * 600);
+/// Bytes received from the exit so far, sampled every frame.
+pub static RX_BYTES_TIMESERIES: TimeSeries = TimeSeries::new(60 * 600);
+
+/// Bytes sent to the exit so far, sampled every frame.
+pub static TX_BYTES_TIMESERIES: TimeSeries = TimeSeries::new(60 * 600);
 
 #[cfg(unix)]
 pub static DAEMON_HANDLE: Lazy<Arc<dyn Daemon>> =
@@ -18,6 +29,31 @@ pub static DAEMON_HANDLE: Lazy<Arc<dyn Daemon>> =
 #[cfg(windows)]
 pub static DAEMON_HANDLE: Lazy<Arc<dyn Daemon>> = Lazy::new(|| Arc::new(subproc::SubprocDaemon));
 
+static WANT_CONNECTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the user last asked to be connected, so that losing the connection can be told apart from disconnecting on purpose.
+pub fn want_connected() -> bool {
+    WANT_CONNECTED.load(Ordering::SeqCst)
+}
+
+/// Starts the daemon with the current settings, pointing the system proxy at it if that's turned on.
+pub fn connect() -> anyhow::Result<()> {
+    WANT_CONNECTED.store(true, Ordering::SeqCst);
+    DAEMON_HANDLE.start(get_config()?)?;
+    if PROXY_AUTOCONF.get() {
+        set_http_proxy(get_config()?.http_proxy_listen.unwrap())?;
+    }
+    Ok(())
+}
+
+/// Stops the daemon and puts the system proxy back.
+pub fn disconnect() -> anyhow::Result<()> {
+    WANT_CONNECTED.store(false, Ordering::SeqCst);
+    DAEMON_HANDLE.stop()?;
+    unset_http_proxy()?;
+    Ok(())
+}
+
 pub trait Daemon: Sync + Send + 'static {
     fn start(&self, cfg: Config) -> anyhow::Result<()>;
 
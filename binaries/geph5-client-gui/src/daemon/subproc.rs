@@ -1,8 +1,10 @@
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 
-use geph5_client::Config;
+use geph5_client::{Config, ControlListen};
+use once_cell::sync::Lazy;
+use smol_str::SmolStr;
 
-use crate::prefs::PREF_DIR;
+use crate::prefs::{pref_read, pref_write, PREF_DIR};
 
 use super::Daemon;
 
@@ -10,12 +12,25 @@ pub struct SubprocDaemon;
 
 const CONTROL_PORT: u16 = 8964;
 
+/// The secret the daemon demands on its control port. It's kept across restarts so that we can still stop a daemon left over from an earlier run.
+static CONTROL_TOKEN: Lazy<SmolStr> = Lazy::new(|| {
+    pref_read("control_token").unwrap_or_else(|_| {
+        let token = SmolStr::from(format!("{:032x}", rand::random::<u128>()));
+        if let Err(err) = pref_write("control_token", &token) {
+            tracing::warn!(err = debug(err), "could not save control token");
+        }
+        token
+    })
+});
+
 impl Daemon for SubprocDaemon {
     fn start(&self, mut cfg: Config) -> anyhow::Result<()> {
-        cfg.control_listen = Some(SocketAddr::new(
+        // not a socket path, which the GUI couldn't open when the daemon runs elevated for VPN mode
+        cfg.control_listen = Some(ControlListen::Tcp(SocketAddr::new(
             IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
             CONTROL_PORT,
-        ));
+        )));
+        cfg.control_token = Some(CONTROL_TOKEN.to_string());
         let cfg_path = PREF_DIR.join("config.yaml");
         std::fs::write(
             cfg_path.clone(),
@@ -52,9 +67,11 @@ impl Daemon for SubprocDaemon {
     }
 
     fn control_client(&self) -> geph5_client::ControlClient {
-        geph5_client::ControlClient::from(nanorpc_sillad::DialerTransport(sillad::tcp::TcpDialer {
-            dest_addr: SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), CONTROL_PORT),
-        }))
+        ControlListen::Tcp(SocketAddr::new(
+            IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
+            CONTROL_PORT,
+        ))
+        .client(Some(&CONTROL_TOKEN))
     }
 
     fn check_dead(&self) -> anyhow::Result<()> {
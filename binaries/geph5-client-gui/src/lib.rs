@@ -2,24 +2,37 @@
 
 use std::time::Duration;
 
-use daemon::{DAEMON_HANDLE, TOTAL_BYTES_TIMESERIES};
-use egui::{FontData, FontDefinitions, FontFamily, Visuals};
+use daemon::{connect, DAEMON_HANDLE, RX_BYTES_TIMESERIES, TX_BYTES_TIMESERIES};
+use egui::{FontData, FontDefinitions, FontFamily};
 use l10n::l10n;
 
 use once_cell::sync::OnceCell;
 use refresh_cell::RefreshCell;
-use settings::USERNAME;
-use tabs::{dashboard::Dashboard, login::Login, logs::Logs, settings::Settings};
+use settings::{AUTO_CONNECT, USERNAME};
+use share::{render_pending_import, SharedSettings, PENDING_IMPORT, URI_PREFIX};
+use tabs::{
+    account::Account, dashboard::Dashboard, login::Login, logs::Logs, news::News,
+    settings::Settings,
+};
+use theme::apply_theme;
+pub mod autostart;
 pub mod daemon;
 pub mod l10n;
 pub mod logs;
+#[cfg(not(target_os = "android"))]
+pub mod notifications;
 pub mod pac;
 pub mod prefs;
+pub mod profiles;
 pub mod refresh_cell;
 pub mod settings;
+pub mod share;
 pub mod store_cell;
 pub mod tabs;
+pub mod theme;
 pub mod timeseries;
+#[cfg(not(target_os = "android"))]
+pub mod tray;
 
 pub static SHOW_KEYBOARD_CALLBACK: OnceCell<Box<dyn Fn(bool) + Send + Sync + 'static>> =
     OnceCell::new();
@@ -34,16 +47,21 @@ pub(crate) fn show_keyboard(show: bool) {
 enum TabName {
     Dashboard,
     Logs,
+    News,
+    Account,
     Settings,
 }
 
 pub struct App {
-    total_bytes: RefreshCell<f64>,
+    total_bytes: RefreshCell<(f64, f64)>,
     selected_tab: TabName,
+    system_dark: bool,
     login: Login,
 
     dashboard: Dashboard,
     logs: Logs,
+    news: News,
+    account: Account,
     settings: Settings,
 }
 
@@ -69,51 +87,97 @@ impl App {
         }
 
         ctx.set_fonts(fonts);
+        #[cfg(not(target_os = "android"))]
+        {
+            tray::init_tray(ctx);
+            notifications::init_notifications();
+        }
         ctx.style_mut(|style| {
             style.spacing.item_spacing = egui::vec2(8.0, 8.0);
-
-            style.visuals = Visuals::light();
         });
 
+        // opening a share link launches us with it as an argument
+        if let Some(uri) = std::env::args().find(|arg| arg.starts_with(URI_PREFIX)) {
+            match SharedSettings::from_uri(&uri) {
+                Ok(settings) => *PENDING_IMPORT.lock() = Some(settings),
+                Err(err) => tracing::warn!(err = debug(err), "could not open settings link"),
+            }
+        }
+
+        if AUTO_CONNECT.get() && !USERNAME.get().is_empty() {
+            tracing::info!("connecting automatically on startup");
+            if let Err(err) = connect() {
+                tracing::warn!(err = debug(err), "could not connect automatically");
+            }
+        }
+
         Self {
             total_bytes: RefreshCell::new(),
             selected_tab: TabName::Dashboard,
+            system_dark: false,
             login: Login::new(),
 
             dashboard: Dashboard::new(),
             logs: Logs::new(),
+            news: News::new(),
+            account: Account::new(),
             settings: Settings::new(),
         }
     }
 }
 
 impl App {
+    /// Tells the app whether the system is in dark mode, for when the theme follows the system.
+    pub fn set_system_dark(&mut self, dark: bool) {
+        self.system_dark = dark;
+    }
+
     pub fn render(&mut self, ctx: &egui::Context) {
         ctx.set_zoom_factor(1.1);
+        apply_theme(ctx, self.system_dark);
         ctx.request_repaint_after(Duration::from_millis(200));
 
+        #[cfg(not(target_os = "android"))]
         {
-            let count = self
+            tray::refresh_tray();
+            // closing the window only hides it, so that we stay connected in the tray
+            if ctx.input(|i| i.viewport().close_requested())
+                && settings::MINIMIZE_TO_TRAY.get()
+                && tray::tray_ready()
+                && !tray::quit_requested()
+            {
+                ctx.send_viewport_cmd(egui::ViewportCommand::CancelClose);
+                ctx.send_viewport_cmd(egui::ViewportCommand::Visible(false));
+            }
+        }
+
+        {
+            let (rx, tx) = self
                 .total_bytes
                 .get_or_refresh(Duration::from_millis(200), || {
-                    smol::future::block_on(
-                        DAEMON_HANDLE
-                            .control_client()
-                            .stat_num("total_rx_bytes".to_string()),
-                    )
-                    .unwrap_or_default()
-                        + smol::future::block_on(
+                    (
+                        smol::future::block_on(
+                            DAEMON_HANDLE
+                                .control_client()
+                                .stat_num("total_rx_bytes".to_string()),
+                        )
+                        .unwrap_or_default(),
+                        smol::future::block_on(
                             DAEMON_HANDLE
                                 .control_client()
                                 .stat_num("total_tx_bytes".to_string()),
                         )
-                        .unwrap_or_default()
+                        .unwrap_or_default(),
+                    )
                 })
                 .copied()
                 .unwrap_or_default();
-            TOTAL_BYTES_TIMESERIES.record(count);
+            RX_BYTES_TIMESERIES.record(rx);
+            TX_BYTES_TIMESERIES.record(tx);
         }
 
+        render_pending_import(ctx);
+
         if USERNAME.get().is_empty() {
             egui::CentralPanel::default().show(ctx, |ui| {
                 self.login.render(ui).unwrap();
@@ -130,6 +194,12 @@ impl App {
                     l10n("dashboard"),
                 );
                 ui.selectable_value(&mut self.selected_tab, TabName::Logs, l10n("logs"));
+                let news = match self.news.unread() {
+                    0 => l10n("news").to_string(),
+                    unread => format!("{} ({unread})", l10n("news")),
+                };
+                ui.selectable_value(&mut self.selected_tab, TabName::News, news);
+                ui.selectable_value(&mut self.selected_tab, TabName::Account, l10n("account"));
                 ui.selectable_value(&mut self.selected_tab, TabName::Settings, l10n("settings"));
             });
         });
@@ -137,6 +207,8 @@ impl App {
         let result = egui::CentralPanel::default().show(ctx, |ui| match self.selected_tab {
             TabName::Dashboard => self.dashboard.render(ui),
             TabName::Logs => self.logs.render(ui),
+            TabName::News => self.news.render(ui),
+            TabName::Account => self.account.render(ui),
             TabName::Settings => self.settings.render(ui),
         });
 
@@ -79,8 +79,9 @@ fn main() {
     };
 
     let mut cell = None;
-    eframe::run_simple_native(l10n("geph"), native_options, move |ctx, _frame| {
+    eframe::run_simple_native(l10n("geph"), native_options, move |ctx, frame| {
         let app = cell.get_or_insert_with(|| geph5_client_gui::App::new(ctx));
+        app.set_system_dark(frame.info().system_theme == Some(eframe::Theme::Dark));
         app.render(ctx)
     })
     .unwrap();
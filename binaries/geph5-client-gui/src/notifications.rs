@@ -0,0 +1,112 @@
+use std::time::{Duration, SystemTime};
+
+use geph5_client::{Client, ConnInfo};
+use smol_timeout2::TimeoutExt;
+
+use crate::{
+    daemon::{want_connected, DAEMON_HANDLE},
+    l10n::{l10n, l10n_country},
+    settings::{get_config, NOTIFICATIONS, USERNAME},
+};
+
+const CONNECTION_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// How often the account and updates are checked on.
+const ACCOUNT_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// How long before Plus runs out the user gets warned.
+const EXPIRY_WARNING: Duration = Duration::from_secs(3 * 86400);
+
+/// Starts watching for things worth a desktop notification.
+pub fn init_notifications() {
+    std::thread::spawn(connection_loop);
+    std::thread::spawn(account_loop);
+}
+
+fn notify(body: &str) {
+    if !NOTIFICATIONS.get() {
+        return;
+    }
+    if let Err(err) = notify_rust::Notification::new()
+        .summary(l10n("geph"))
+        .body(body)
+        .show()
+    {
+        tracing::warn!(err = debug(err), "could not show notification");
+    }
+}
+
+/// Notifies when the connection comes up, and when it goes down without the user asking.
+fn connection_loop() {
+    let mut was_connected = false;
+    loop {
+        let conn_info = smol::future::block_on(
+            DAEMON_HANDLE
+                .control_client()
+                .conn_info()
+                .timeout(Duration::from_millis(500)),
+        )
+        .and_then(|s| s.ok());
+        match &conn_info {
+            Some(ConnInfo::Connected(info)) if !was_connected => notify(&format!(
+                "{}: {} / {}",
+                l10n("connected"),
+                l10n_country(info.exit.country),
+                info.exit.city
+            )),
+            Some(ConnInfo::Connected(_)) => {}
+            _ if was_connected && want_connected() => notify(l10n("connection_lost")),
+            _ => {}
+        }
+        was_connected = matches!(conn_info, Some(ConnInfo::Connected(_)));
+        std::thread::sleep(CONNECTION_POLL_INTERVAL);
+    }
+}
+
+/// Notifies, once each, when Plus is about to run out and when an update has been downloaded.
+fn account_loop() {
+    let mut warned_expiry = None;
+    let mut announced_update = None;
+    loop {
+        if !USERNAME.get().is_empty() {
+            match plus_expiring_soon() {
+                Ok(Some(expires)) if warned_expiry != Some(expires) => {
+                    let days_left = expires.saturating_sub(unix_now()) / 86400;
+                    notify(&format!("{} ({days_left}d)", l10n("plus_expires_soon")));
+                    warned_expiry = Some(expires);
+                }
+                Ok(_) => {}
+                Err(err) => tracing::debug!(err = debug(err), "could not check plan expiry"),
+            }
+        }
+
+        // the daemon only answers this while running, and only if updates are configured
+        if let Ok(Ok(Some(version))) =
+            smol::future::block_on(DAEMON_HANDLE.control_client().check_for_update())
+        {
+            if announced_update.as_ref() != Some(&version) {
+                notify(&format!("{}: {version}", l10n("update_ready")));
+                announced_update = Some(version);
+            }
+        }
+
+        std::thread::sleep(ACCOUNT_CHECK_INTERVAL);
+    }
+}
+
+/// When Plus runs out, if that's soon.
+fn plus_expiring_soon() -> anyhow::Result<Option<u64>> {
+    let client = Client::start(get_config()?.inert());
+    let info = smolscale::block_on(client.user_info())?;
+    let now = unix_now();
+    Ok(info
+        .plus_expires_unix
+        .filter(|&expires| expires > now && expires - now < EXPIRY_WARNING.as_secs()))
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
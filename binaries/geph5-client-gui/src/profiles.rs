@@ -0,0 +1,59 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    daemon::{connect, disconnect, want_connected},
+    settings::{ACTIVE_PROFILE, PROFILES},
+    share::SharedSettings,
+};
+
+/// A named set of settings, such as for another account or another exit, that can be switched to in one go.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Profile {
+    pub name: String,
+    pub settings: SharedSettings,
+}
+
+/// Saves the current settings, login included, as the named profile, replacing any profile of the same name. It becomes the active profile.
+pub fn save_profile(name: &str) -> anyhow::Result<()> {
+    let name = name.trim();
+    anyhow::ensure!(!name.is_empty(), "profile name is empty");
+    let profile = Profile {
+        name: name.to_string(),
+        settings: SharedSettings::current(true),
+    };
+    PROFILES.modify(
+        |profiles| match profiles.iter_mut().find(|p| p.name == name) {
+            Some(existing) => *existing = profile,
+            None => profiles.push(profile),
+        },
+    );
+    ACTIVE_PROFILE.set(Some(name.to_string()));
+    Ok(())
+}
+
+/// Switches to the named profile, reconnecting with it if connected. The current settings are saved into the active profile first, so that changes made since switching to it aren't lost.
+pub fn switch_profile(name: &str) -> anyhow::Result<()> {
+    let profile = PROFILES
+        .get()
+        .into_iter()
+        .find(|p| p.name == name)
+        .context("no such profile")?;
+    if let Some(active) = ACTIVE_PROFILE.get() {
+        save_profile(&active)?;
+    }
+    profile.settings.apply();
+    ACTIVE_PROFILE.set(Some(profile.name));
+    if want_connected() {
+        disconnect()?;
+        connect()?;
+    }
+    Ok(())
+}
+
+pub fn delete_profile(name: &str) {
+    PROFILES.modify(|profiles| profiles.retain(|p| p.name != name));
+    if ACTIVE_PROFILE.get().as_deref() == Some(name) {
+        ACTIVE_PROFILE.set(None);
+    }
+}
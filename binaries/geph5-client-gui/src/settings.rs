@@ -2,13 +2,14 @@ use std::net::{Ipv4Addr, SocketAddrV4};
 
 use base32::Alphabet;
 use geph5_broker_protocol::Credential;
-use geph5_client::{BridgeMode, BrokerSource, Config, ExitConstraint};
+use geph5_client::{BridgeMode, BrokerSource, Config, CustomBridge, ExitConstraint};
 use isocountry::CountryCode;
 
 use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
 use smol_str::{SmolStr, ToSmolStr};
 
-use crate::store_cell::StoreCell;
+use crate::{profiles::Profile, store_cell::StoreCell};
 
 pub static DEFAULT_SETTINGS: Lazy<serde_yaml::Value> = Lazy::new(|| {
     serde_yaml::from_slice(
@@ -29,15 +30,24 @@ pub fn get_config() -> anyhow::Result<Config> {
         username: USERNAME.get(),
         password: PASSWORD.get(),
     };
-    cfg.exit_constraint = match (SELECTED_COUNTRY.get(), SELECTED_CITY.get()) {
-        (Some(country), Some(city)) => ExitConstraint::CountryCity(country, city),
-        (Some(country), None) => ExitConstraint::Country(country),
+    cfg.exit_constraint = match (
+        SELECTED_EXIT.get(),
+        SELECTED_COUNTRY.get(),
+        SELECTED_CITY.get(),
+    ) {
+        (Some(exit), _, _) => ExitConstraint::Hostname(exit),
+        (None, Some(country), Some(city)) => ExitConstraint::CountryCity(country, city),
+        (None, Some(country), None) => ExitConstraint::Country(country),
         _ => ExitConstraint::Auto,
     };
     cfg.bridge_mode = BRIDGE_MODE.get();
     if let Some(custom_broker) = CUSTOM_BROKER.get() {
         cfg.broker = Some(custom_broker);
     }
+    cfg.custom_bridges.extend(CUSTOM_BRIDGES.get());
+    if CUSTOM_BRIDGES_ONLY.get() {
+        cfg.custom_bridges_only = true;
+    }
     cfg.socks5_listen = Some(std::net::SocketAddr::V4(SocketAddrV4::new(
         Ipv4Addr::new(127, 0, 0, 1),
         SOCKS5_PORT.get(),
@@ -60,6 +70,17 @@ pub static PASSWORD: Lazy<StoreCell<String>> =
 pub static LANG_CODE: Lazy<StoreCell<SmolStr>> =
     Lazy::new(|| StoreCell::new_persistent("lang_code", || "en".to_smolstr()));
 
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Theme {
+    /// Follows whether the system is in dark mode.
+    System,
+    Light,
+    Dark,
+}
+
+pub static THEME: Lazy<StoreCell<Theme>> =
+    Lazy::new(|| StoreCell::new_persistent("theme", || Theme::System));
+
 pub static PROXY_AUTOCONF: Lazy<StoreCell<bool>> =
     Lazy::new(|| StoreCell::new_persistent("proxy_autoconff", || true));
 
@@ -75,9 +96,21 @@ pub static PASSTHROUGH_CHINA: Lazy<StoreCell<bool>> =
 pub static SELECTED_CITY: Lazy<StoreCell<Option<String>>> =
     Lazy::new(|| StoreCell::new_persistent("selected_city", || None));
 
+/// A particular exit picked by the user, by its bridge-facing address. It overrides the selected country and city.
+pub static SELECTED_EXIT: Lazy<StoreCell<Option<String>>> =
+    Lazy::new(|| StoreCell::new_persistent("selected_exit", || None));
+
 pub static CUSTOM_BROKER: Lazy<StoreCell<Option<BrokerSource>>> =
     Lazy::new(|| StoreCell::new_persistent("custom_broker_1", || None));
 
+/// The user's own bridges, used alongside the broker's.
+pub static CUSTOM_BRIDGES: Lazy<StoreCell<Vec<CustomBridge>>> =
+    Lazy::new(|| StoreCell::new_persistent("custom_bridges", Vec::new));
+
+/// Whether to use only the user's own bridges, never the broker's.
+pub static CUSTOM_BRIDGES_ONLY: Lazy<StoreCell<bool>> =
+    Lazy::new(|| StoreCell::new_persistent("custom_bridges_only", || false));
+
 pub static SOCKS5_PORT: Lazy<StoreCell<u16>> =
     Lazy::new(|| StoreCell::new_persistent("socks5_port", || 9999));
 
@@ -86,3 +119,30 @@ pub static HTTP_PROXY_PORT: Lazy<StoreCell<u16>> =
 
 pub static VPN_MODE: Lazy<StoreCell<bool>> =
     Lazy::new(|| StoreCell::new_persistent("vpn_mode", || false));
+
+pub static MINIMIZE_TO_TRAY: Lazy<StoreCell<bool>> =
+    Lazy::new(|| StoreCell::new_persistent("minimize_to_tray", || true));
+
+/// Whether the OS should start the app at login. Changing it adds or removes the OS's autostart entry.
+pub static LAUNCH_AT_LOGIN: Lazy<StoreCell<bool>> =
+    Lazy::new(|| StoreCell::new_persistent("launch_at_login", || false));
+
+/// Whether to connect with the saved settings as soon as the app starts.
+pub static AUTO_CONNECT: Lazy<StoreCell<bool>> =
+    Lazy::new(|| StoreCell::new_persistent("auto_connect", || false));
+
+/// The date of the newest news item the user has seen, so that anything newer counts as unread.
+pub static NEWS_READ_UNTIL: Lazy<StoreCell<u64>> =
+    Lazy::new(|| StoreCell::new_persistent("news_read_until", || 0));
+
+/// Whether to show desktop notifications when the connection changes, Plus is running out, or an update is ready.
+pub static NOTIFICATIONS: Lazy<StoreCell<bool>> =
+    Lazy::new(|| StoreCell::new_persistent("notifications", || true));
+
+/// Named sets of settings that can be switched between.
+pub static PROFILES: Lazy<StoreCell<Vec<Profile>>> =
+    Lazy::new(|| StoreCell::new_persistent("profiles", Vec::new));
+
+/// The profile that the current settings were last switched to or saved as, if any.
+pub static ACTIVE_PROFILE: Lazy<StoreCell<Option<String>>> =
+    Lazy::new(|| StoreCell::new_persistent("active_profile", || None));
@@ -0,0 +1,195 @@
+use std::sync::LazyLock;
+
+use base32::Alphabet;
+use egui::{mutex::Mutex, Color32};
+use geph5_client::{BridgeMode, BrokerSource, CustomBridge};
+use isocountry::CountryCode;
+use qrcode::QrCode;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    l10n::{l10n, l10n_country},
+    settings::{
+        BRIDGE_MODE, CUSTOM_BRIDGES, CUSTOM_BRIDGES_ONLY, CUSTOM_BROKER, HTTP_PROXY_PORT,
+        PASSTHROUGH_CHINA, PASSWORD, PROXY_AUTOCONF, SELECTED_CITY, SELECTED_COUNTRY,
+        SELECTED_EXIT, SOCKS5_PORT, USERNAME, VPN_MODE,
+    },
+};
+
+/// What share links start with. The rest is the settings as JSON, in Crockford base32, which QR codes can hold compactly and which survives being typed or read out.
+pub const URI_PREFIX: &str = "geph5://import/";
+
+/// Settings that were opened from a link and are waiting for the user to confirm them, since a link could come from anyone.
+pub static PENDING_IMPORT: LazyLock<Mutex<Option<SharedSettings>>> =
+    LazyLock::new(Default::default);
+
+/// The settings that go into a share link.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SharedSettings {
+    pub credentials: Option<(String, String)>,
+    pub country: Option<CountryCode>,
+    pub city: Option<String>,
+    pub exit: Option<String>,
+    pub bridge_mode: BridgeMode,
+    pub broker: Option<BrokerSource>,
+    #[serde(default)]
+    pub custom_bridges: Vec<CustomBridge>,
+    #[serde(default)]
+    pub custom_bridges_only: bool,
+    pub vpn_mode: bool,
+    pub passthrough_china: bool,
+    pub proxy_autoconf: bool,
+    pub socks5_port: u16,
+    pub http_proxy_port: u16,
+}
+
+impl SharedSettings {
+    /// The settings in use right now, with or without the login.
+    pub fn current(with_credentials: bool) -> Self {
+        Self {
+            credentials: with_credentials.then(|| (USERNAME.get(), PASSWORD.get())),
+            country: SELECTED_COUNTRY.get(),
+            city: SELECTED_CITY.get(),
+            exit: SELECTED_EXIT.get(),
+            bridge_mode: BRIDGE_MODE.get(),
+            broker: CUSTOM_BROKER.get(),
+            custom_bridges: CUSTOM_BRIDGES.get(),
+            custom_bridges_only: CUSTOM_BRIDGES_ONLY.get(),
+            vpn_mode: VPN_MODE.get(),
+            passthrough_china: PASSTHROUGH_CHINA.get(),
+            proxy_autoconf: PROXY_AUTOCONF.get(),
+            socks5_port: SOCKS5_PORT.get(),
+            http_proxy_port: HTTP_PROXY_PORT.get(),
+        }
+    }
+
+    /// Replaces the current settings with these. The login is only replaced if the link has one. They take effect on the next connection.
+    pub fn apply(self) {
+        if let Some((username, password)) = self.credentials {
+            USERNAME.set(username);
+            PASSWORD.set(password);
+        }
+        SELECTED_COUNTRY.set(self.country);
+        SELECTED_CITY.set(self.city);
+        SELECTED_EXIT.set(self.exit);
+        BRIDGE_MODE.set(self.bridge_mode);
+        CUSTOM_BROKER.set(self.broker);
+        CUSTOM_BRIDGES.set(self.custom_bridges);
+        CUSTOM_BRIDGES_ONLY.set(self.custom_bridges_only);
+        VPN_MODE.set(self.vpn_mode);
+        PASSTHROUGH_CHINA.set(self.passthrough_china);
+        PROXY_AUTOCONF.set(self.proxy_autoconf);
+        SOCKS5_PORT.set(self.socks5_port);
+        HTTP_PROXY_PORT.set(self.http_proxy_port);
+    }
+
+    pub fn to_uri(&self) -> anyhow::Result<String> {
+        let json = serde_json::to_vec(self)?;
+        Ok(format!(
+            "{URI_PREFIX}{}",
+            base32::encode(Alphabet::Crockford, &json)
+        ))
+    }
+
+    pub fn from_uri(uri: &str) -> anyhow::Result<Self> {
+        let uri = uri.trim();
+        let encoded = uri
+            .get(..URI_PREFIX.len())
+            .filter(|prefix| prefix.eq_ignore_ascii_case(URI_PREFIX))
+            .map(|_| &uri[URI_PREFIX.len()..])
+            .ok_or_else(|| anyhow::anyhow!("not a Geph settings link"))?;
+        let json = base32::decode(Alphabet::Crockford, encoded.trim_end_matches('/'))
+            .ok_or_else(|| anyhow::anyhow!("settings link is corrupted"))?;
+        Ok(serde_json::from_slice(&json)?)
+    }
+
+    /// A short description of what importing these would change, for the user to check before confirming.
+    pub fn summary(&self) -> Vec<String> {
+        let mut lines = vec![];
+        if let Some((username, _)) = &self.credentials {
+            lines.push(format!("{}: {username}", l10n("username")));
+        }
+        let location = match (&self.country, &self.city) {
+            (Some(country), Some(city)) => format!("{} / {city}", l10n_country(*country)),
+            (Some(country), None) => l10n_country(*country).to_string(),
+            _ => l10n("auto").to_string(),
+        };
+        lines.push(format!("{}: {location}", l10n("exit_location")));
+        if self.broker.is_some() {
+            lines.push(format!("{}: {}", l10n("broker"), l10n("custom")));
+        }
+        if !self.custom_bridges.is_empty() {
+            lines.push(format!(
+                "{}: {}",
+                l10n("custom_bridges"),
+                self.custom_bridges.len()
+            ));
+        }
+        lines
+    }
+}
+
+/// Draws a QR code for the given data, as large as fits, black on white whatever the theme so that cameras can read it.
+pub fn render_qr(ui: &mut egui::Ui, data: &str) -> anyhow::Result<()> {
+    // the standard asks for four modules of blank space around the code
+    const QUIET: usize = 4;
+    let code = QrCode::new(data.as_bytes())?;
+    let width = code.width();
+    let module = (ui.available_width() / (width + QUIET * 2) as f32).clamp(2.0, 6.0);
+    let size = (width + QUIET * 2) as f32 * module;
+    let (rect, _) = ui.allocate_exact_size(egui::vec2(size, size), egui::Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 0.0, Color32::WHITE);
+    for (i, color) in code.to_colors().into_iter().enumerate() {
+        if color == qrcode::Color::Dark {
+            let (x, y) = ((i % width + QUIET) as f32, (i / width + QUIET) as f32);
+            painter.rect_filled(
+                egui::Rect::from_min_size(
+                    rect.min + egui::vec2(x * module, y * module),
+                    egui::vec2(module, module),
+                ),
+                0.0,
+                Color32::BLACK,
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Asks whether to import settings that came from a link, applying them if the user agrees.
+pub fn render_pending_import(ctx: &egui::Context) {
+    let mut pending = PENDING_IMPORT.lock();
+    let Some(settings) = pending.as_ref() else {
+        return;
+    };
+    let mut accepted = None;
+    egui::Window::new(l10n("import_settings"))
+        .collapsible(false)
+        .resizable(false)
+        .anchor(egui::Align2::CENTER_CENTER, egui::Vec2::ZERO)
+        .show(ctx, |ui| {
+            ui.label(l10n("import_settings_confirm"));
+            for line in settings.summary() {
+                ui.label(line);
+            }
+            ui.horizontal(|ui| {
+                if ui.button(l10n("ok")).clicked() {
+                    accepted = Some(true);
+                }
+                if ui.button(l10n("cancel")).clicked() {
+                    accepted = Some(false);
+                }
+            });
+        });
+    match accepted {
+        Some(true) => {
+            if let Some(settings) = pending.take() {
+                settings.apply();
+            }
+        }
+        Some(false) => {
+            *pending = None;
+        }
+        None => {}
+    }
+}
@@ -0,0 +1,134 @@
+use std::time::{Duration, SystemTime};
+
+use geph5_broker_protocol::UserInfo;
+use geph5_client::{Client, UsageRecord};
+
+use crate::{
+    daemon::disconnect,
+    l10n::l10n,
+    refresh_cell::RefreshCell,
+    settings::{get_config, PASSWORD, USERNAME},
+    theme::palette,
+};
+
+/// How many days of traffic the data used adds up.
+const USAGE_DAYS: u32 = 30;
+
+pub struct Account {
+    user_info: RefreshCell<anyhow::Result<UserInfo>>,
+    usage: RefreshCell<anyhow::Result<Vec<UsageRecord>>>,
+}
+
+impl Default for Account {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Account {
+    pub fn new() -> Self {
+        Self {
+            user_info: RefreshCell::new(),
+            usage: RefreshCell::new(),
+        }
+    }
+
+    pub fn render(&mut self, ui: &mut egui::Ui) -> anyhow::Result<()> {
+        let palette = palette(ui.visuals());
+        let inert_config = get_config()?.inert();
+        let user_info = self.user_info.get_or_refresh(Duration::from_secs(10), || {
+            let client = Client::start(inert_config);
+            smolscale::block_on(async move { client.user_info().await })
+        });
+        // the usage ledger is in the local database, so it's there even while disconnected
+        let inert_config = get_config()?.inert();
+        let usage = self.usage.get_or_refresh(Duration::from_secs(10), || {
+            let client = Client::start(inert_config);
+            smolscale::block_on(async move {
+                client
+                    .control_client()
+                    .usage_history(USAGE_DAYS)
+                    .await?
+                    .map_err(|e| anyhow::anyhow!(e))
+            })
+        });
+
+        match user_info {
+            Some(Ok(info)) => {
+                egui::Grid::new("account").num_columns(2).show(ui, |ui| {
+                    ui.label(l10n("user_id"));
+                    ui.label(info.user_id.to_string());
+                    ui.end_row();
+
+                    ui.label(l10n("plan"));
+                    if info.plus_expires_unix.is_some() {
+                        ui.colored_label(palette.good, l10n("plan_plus"));
+                    } else {
+                        ui.label(l10n("plan_free"));
+                    }
+                    ui.end_row();
+
+                    if let Some(expires) = info.plus_expires_unix {
+                        ui.label(l10n("expires"));
+                        ui.label(format_expiry(expires));
+                        ui.end_row();
+                    }
+
+                    ui.label(format!("{} ({USAGE_DAYS}d)", l10n("data_used")));
+                    match usage {
+                        Some(Ok(usage)) => {
+                            let total: u64 = usage
+                                .iter()
+                                .map(|record| record.rx_bytes + record.tx_bytes)
+                                .sum();
+                            ui.label(format_bytes(total));
+                        }
+                        Some(Err(err)) => {
+                            ui.colored_label(palette.bad, err.to_string());
+                        }
+                        None => {
+                            ui.spinner();
+                        }
+                    }
+                    ui.end_row();
+                });
+            }
+            Some(Err(err)) => {
+                ui.colored_label(palette.bad, err.to_string());
+            }
+            None => {
+                ui.spinner();
+            }
+        }
+
+        ui.separator();
+        if ui.button(l10n("logout")).clicked() {
+            let _ = disconnect();
+            USERNAME.set("".into());
+            PASSWORD.set("".into());
+        }
+        Ok(())
+    }
+}
+
+/// Formats when a subscription expires, along with how many days are left.
+fn format_expiry(expires_unix: u64) -> String {
+    let date = chrono::DateTime::from_timestamp(expires_unix as i64, 0)
+        .map(|date| date.format("%Y-%m-%d").to_string())
+        .unwrap_or_default();
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let days_left = expires_unix.saturating_sub(now) / 86400;
+    format!("{date} ({days_left}d)")
+}
+
+fn format_bytes(bytes: u64) -> String {
+    let bytes = bytes as f64;
+    if bytes >= 1e9 {
+        format!("{:.2} GB", bytes / 1e9)
+    } else {
+        format!("{:.1} MB", bytes / 1e6)
+    }
+}
@@ -1,20 +1,31 @@
 use std::time::{Duration, Instant};
 
-use egui_plot::{Line, Plot, PlotPoints};
-use geph5_client::ConnInfo;
+use egui_plot::{Corner, Legend, Line, Plot, PlotPoints};
+use geph5_client::{ConnInfo, SpeedTestResult};
 use once_cell::sync::Lazy;
+use poll_promise::Promise;
 use smol_timeout2::TimeoutExt;
 
 use crate::{
-    daemon::{DAEMON_HANDLE, TOTAL_BYTES_TIMESERIES},
+    daemon::{connect, disconnect, DAEMON_HANDLE, RX_BYTES_TIMESERIES, TX_BYTES_TIMESERIES},
     l10n::{l10n, l10n_country},
-    pac::{set_http_proxy, unset_http_proxy},
+    profiles::switch_profile,
     refresh_cell::RefreshCell,
-    settings::{get_config, PROXY_AUTOCONF},
+    settings::{ACTIVE_PROFILE, PROFILES},
+    theme::palette,
+    timeseries::TimeSeries,
 };
 
+/// How many points the bandwidth graph is drawn with, whatever its time window.
+const GRAPH_POINTS: u32 = 1000;
+
+/// The interval that the current rates are averaged over.
+const RATE_INTERVAL: Duration = Duration::from_secs(3);
+
 pub struct Dashboard {
     conn_info: RefreshCell<Option<ConnInfo>>,
+    graph_window: GraphWindow,
+    speed_test: Option<Promise<anyhow::Result<SpeedTestResult>>>,
 }
 
 impl Default for Dashboard {
@@ -27,6 +38,8 @@ impl Dashboard {
     pub fn new() -> Self {
         Self {
             conn_info: RefreshCell::new(),
+            graph_window: GraphWindow::OneMinute,
+            speed_test: None,
         }
     }
     pub fn render(&mut self, ui: &mut egui::Ui) -> anyhow::Result<()> {
@@ -46,15 +59,40 @@ impl Dashboard {
         let style = ui.style().clone();
         let font_id = style.text_styles.get(&egui::TextStyle::Body).unwrap();
         let font_color = style.visuals.text_color();
+        let palette = palette(&style.visuals);
+
+        let profiles = PROFILES.get();
+        if !profiles.is_empty() {
+            let active = ACTIVE_PROFILE.get();
+            let mut selected = active.clone();
+            ui.columns(2, |columns| {
+                columns[0].label(l10n("profile"));
+                egui::ComboBox::from_id_source("profile")
+                    .selected_text(active.as_deref().unwrap_or("-"))
+                    .show_ui(&mut columns[1], |ui| {
+                        for profile in &profiles {
+                            ui.selectable_value(
+                                &mut selected,
+                                Some(profile.name.clone()),
+                                &profile.name,
+                            );
+                        }
+                    });
+            });
+            if let Some(name) = selected.filter(|name| Some(name) != active.as_ref()) {
+                switch_profile(&name)?;
+            }
+        }
+
         ui.columns(2, |columns| {
             columns[0].label(l10n("status"));
 
             match &conn_info {
                 Some(ConnInfo::Connecting) => {
-                    columns[1].colored_label(egui::Color32::DARK_BLUE, l10n("connecting"));
+                    columns[1].colored_label(palette.busy, l10n("connecting"));
                 }
                 Some(ConnInfo::Connected(info)) => {
-                    columns[1].colored_label(egui::Color32::DARK_GREEN, l10n("connected"));
+                    columns[1].colored_label(palette.good, l10n("connected"));
 
                     let mut job = egui::text::LayoutJob::default();
                     job.append(
@@ -72,7 +110,7 @@ impl Dashboard {
                         0.0,
                         egui::TextFormat {
                             font_id: font_id.clone(),
-                            color: egui::Color32::GRAY,
+                            color: palette.muted,
                             ..Default::default()
                         },
                     );
@@ -81,7 +119,7 @@ impl Dashboard {
                     columns[1].label(info.bridge.split(':').next().unwrap());
                 }
                 None => {
-                    columns[1].colored_label(egui::Color32::DARK_RED, l10n("disconnected"));
+                    columns[1].colored_label(palette.bad, l10n("disconnected"));
                 }
             }
             columns[0].label(l10n("exit_location").to_string() + "\n\n");
@@ -92,49 +130,63 @@ impl Dashboard {
             if conn_info.is_none() {
                 if ui.button(l10n("connect")).clicked() {
                     tracing::warn!("connect clicked");
-                    DAEMON_HANDLE.start(get_config()?)?;
-                    if PROXY_AUTOCONF.get() {
-                        set_http_proxy(get_config()?.http_proxy_listen.unwrap())?;
-                    }
+                    connect()?;
                 }
             } else if ui.button(l10n("disconnect")).clicked() {
                 tracing::warn!("disconnect clicked");
-                DAEMON_HANDLE.stop()?;
-                unset_http_proxy()?;
+                disconnect()?;
             }
             anyhow::Ok(())
         })
         .inner?;
 
-        static START: Lazy<Instant> = Lazy::new(Instant::now);
         let now = Instant::now();
-        let quantum_ms = 200;
+        let (down, up) = (
+            rate_mbps(&RX_BYTES_TIMESERIES, now, RATE_INTERVAL),
+            rate_mbps(&TX_BYTES_TIMESERIES, now, RATE_INTERVAL),
+        );
+        ui.columns(2, |columns| {
+            columns[0].label(l10n("download_speed"));
+            columns[1].colored_label(palette.download, format!("{down:.2} Mbps"));
+            columns[0].label(l10n("upload_speed"));
+            columns[1].colored_label(palette.upload, format!("{up:.2} Mbps"));
+        });
+
+        if matches!(conn_info, Some(ConnInfo::Connected(_))) {
+            self.render_speed_test(ui);
+        }
+
+        ui.horizontal(|ui| {
+            for window in [
+                GraphWindow::OneMinute,
+                GraphWindow::TenMinutes,
+                GraphWindow::OneHour,
+            ] {
+                ui.selectable_value(&mut self.graph_window, window, window.label());
+            }
+        });
+
+        // snap to whole steps, so that the graph scrolls smoothly instead of jittering between samples
+        static START: Lazy<Instant> = Lazy::new(Instant::now);
+        let step = self.graph_window.duration() / GRAPH_POINTS;
+        let step_ms = step.as_millis().max(1);
         let now = *START
             + Duration::from_millis(
-                (now.saturating_duration_since(*START).as_millis() / quantum_ms * quantum_ms) as _,
+                (now.saturating_duration_since(*START).as_millis() / step_ms * step_ms) as _,
             );
-        let range = 1000;
-
-        let line = Line::new(
-            (0..range)
-                .map(|i| {
-                    let x = i as f64;
-                    [
-                        (range as f64) - x,
-                        ((TOTAL_BYTES_TIMESERIES
-                            .get_at(now - Duration::from_millis(i * (quantum_ms as u64)))
-                            - TOTAL_BYTES_TIMESERIES.get_at(
-                                now - Duration::from_millis(i * (quantum_ms as u64) + 3000),
-                            ))
-                        .max(0.0)
-                            / 1000.0
-                            / 1000.0
-                            * 8.0)
-                            / 3.0,
-                    ]
+        // long windows average over each step, so that short bursts don't turn into noise
+        let interval = step.max(RATE_INTERVAL);
+        let area = |series: &TimeSeries| {
+            (0..GRAPH_POINTS)
+                .filter_map(|i| {
+                    let ago = step * i;
+                    Some([
+                        -ago.as_secs_f64(),
+                        rate_mbps(series, now.checked_sub(ago)?, interval),
+                    ])
                 })
-                .collect::<PlotPoints>(),
-        );
+                .collect::<PlotPoints>()
+        };
 
         Plot::new("my_plot")
             .allow_drag(false)
@@ -148,8 +200,92 @@ impl Dashboard {
             .include_y(1.0)
             .show_x(false)
             .show_axes(egui::Vec2b { x: false, y: true })
-            .show(ui, |plot| plot.line(line));
+            .legend(Legend::default().position(Corner::LeftTop))
+            .show(ui, |plot| {
+                plot.line(
+                    Line::new(area(&RX_BYTES_TIMESERIES))
+                        .color(palette.download)
+                        .fill(0.0)
+                        .name(l10n("download_speed")),
+                );
+                plot.line(
+                    Line::new(area(&TX_BYTES_TIMESERIES))
+                        .color(palette.upload)
+                        .fill(0.0)
+                        .name(l10n("upload_speed")),
+                );
+            });
 
         Ok(())
     }
+
+    fn render_speed_test(&mut self, ui: &mut egui::Ui) {
+        let palette = palette(ui.visuals());
+        let running = self
+            .speed_test
+            .as_ref()
+            .is_some_and(|test| test.ready().is_none());
+        ui.horizontal(|ui| {
+            if ui
+                .add_enabled(!running, egui::Button::new(l10n("speed_test")))
+                .clicked()
+            {
+                self.speed_test = Some(Promise::spawn_thread("speed_test", || {
+                    smol::future::block_on(DAEMON_HANDLE.control_client().speed_test())?
+                        .map_err(|e| anyhow::anyhow!(e))
+                }));
+            }
+            match self.speed_test.as_ref().map(|test| test.ready()) {
+                Some(None) => {
+                    ui.spinner();
+                }
+                Some(Some(Ok(result))) => {
+                    ui.colored_label(
+                        palette.download,
+                        format!("↓ {:.2} Mbps", result.download_mbps),
+                    );
+                    ui.colored_label(palette.upload, format!("↑ {:.2} Mbps", result.upload_mbps));
+                    ui.label(format!("{}: {:.0} ms", l10n("latency"), result.latency_ms));
+                }
+                Some(Some(Err(err))) => {
+                    ui.colored_label(palette.bad, err.to_string());
+                }
+                None => {}
+            }
+        });
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum GraphWindow {
+    OneMinute,
+    TenMinutes,
+    OneHour,
+}
+
+impl GraphWindow {
+    fn duration(self) -> Duration {
+        match self {
+            GraphWindow::OneMinute => Duration::from_secs(60),
+            GraphWindow::TenMinutes => Duration::from_secs(600),
+            GraphWindow::OneHour => Duration::from_secs(3600),
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            GraphWindow::OneMinute => "1m",
+            GraphWindow::TenMinutes => "10m",
+            GraphWindow::OneHour => "1h",
+        }
+    }
+}
+
+/// The average rate, in megabits per second, over the `interval` leading up to `at`.
+fn rate_mbps(series: &TimeSeries, at: Instant, interval: Duration) -> f64 {
+    let Some(start) = at.checked_sub(interval) else {
+        return 0.0;
+    };
+    let bytes = (series.get_at(at) - series.get_at(start)).max(0.0);
+    bytes * 8.0 / 1_000_000.0 / interval.as_secs_f64()
 }
@@ -6,6 +6,7 @@ use crate::{
     l10n::l10n,
     settings::{get_config, PASSWORD, USERNAME},
     show_keyboard,
+    theme::palette,
 };
 
 pub struct Login {
@@ -44,7 +45,7 @@ impl Login {
                     Err(err) => {
                         let err = format!("{:?}", err);
                         ui.vertical_centered(|ui| {
-                            ui.colored_label(egui::Color32::DARK_RED, err);
+                            ui.colored_label(palette(ui.visuals()).bad, err);
                             if ui.button(l10n("ok")).clicked() {
                                 self.check_login = None;
                             }
@@ -1,11 +1,63 @@
 use std::time::Duration;
 
+use geph5_client::Client;
 use itertools::Itertools;
+use poll_promise::Promise;
 
-use crate::{daemon::DAEMON_HANDLE, l10n, logs::LOGS, refresh_cell::RefreshCell};
+use crate::{
+    daemon::DAEMON_HANDLE, l10n, logs::LOGS, refresh_cell::RefreshCell, settings::get_config,
+    theme::palette,
+};
+
+/// How many of the latest lines are shown.
+const SHOWN_LINES: usize = 1000;
+
+/// How much of the logs go into a debug pack, in bytes, keeping the latest.
+const DEBUG_PACK_LOGS: usize = 1_000_000;
 
 pub struct Logs {
     log_cache: RefreshCell<anyhow::Result<Vec<String>>>,
+    /// The logs as last fetched, with colors stripped. They stay as they are while paused.
+    logs: String,
+    min_level: LevelFilter,
+    module: String,
+    search: String,
+    paused: bool,
+    auto_scroll: bool,
+    upload: Option<Promise<anyhow::Result<String>>>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum LevelFilter {
+    All,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+impl LevelFilter {
+    fn label(self) -> &'static str {
+        match self {
+            LevelFilter::All => l10n("all"),
+            LevelFilter::Debug => "DEBUG",
+            LevelFilter::Info => "INFO",
+            LevelFilter::Warn => "WARN",
+            LevelFilter::Error => "ERROR",
+        }
+    }
+
+    /// Whether a line at the given level gets shown. The variants are ordered from least to most severe, so a line passes if its level is at least as severe.
+    fn allows(self, level: &str) -> bool {
+        let severity = match level {
+            "DEBUG" => LevelFilter::Debug,
+            "INFO" => LevelFilter::Info,
+            "WARN" => LevelFilter::Warn,
+            "ERROR" => LevelFilter::Error,
+            _ => LevelFilter::All,
+        };
+        severity as u8 >= self as u8
+    }
 }
 
 impl Default for Logs {
@@ -18,30 +70,68 @@ impl Logs {
     pub fn new() -> Self {
         Logs {
             log_cache: RefreshCell::new(),
+            logs: String::new(),
+            min_level: LevelFilter::All,
+            module: String::new(),
+            search: String::new(),
+            paused: false,
+            auto_scroll: true,
+            upload: None,
         }
     }
 
     pub fn render(&mut self, ui: &mut egui::Ui) -> anyhow::Result<()> {
-        let logs = self
-            .log_cache
-            .get_or_refresh(Duration::from_millis(500), || {
-                smol::future::block_on(async {
-                    let mut remote_logs = DAEMON_HANDLE.control_client().recent_logs().await?;
-                    {
-                        let raw_logs = LOGS.lock();
-                        let raw_logs = String::from_utf8_lossy(&raw_logs);
-                        for log in raw_logs.split('\n') {
-                            remote_logs.push(log.to_string());
+        if !self.paused {
+            let logs = self
+                .log_cache
+                .get_or_refresh(Duration::from_millis(500), || {
+                    smol::future::block_on(async {
+                        let mut remote_logs = DAEMON_HANDLE.control_client().recent_logs().await?;
+                        {
+                            let raw_logs = LOGS.lock();
+                            let raw_logs = String::from_utf8_lossy(&raw_logs);
+                            for log in raw_logs.split('\n') {
+                                remote_logs.push(log.to_string());
+                            }
                         }
-                    }
 
-                    Ok(remote_logs)
-                })
-            });
+                        Ok(remote_logs)
+                    })
+                });
+            if let Some(Ok(logs)) = logs {
+                self.logs = strip_ansi_escapes::strip_str(logs.join("\n"));
+            }
+        }
 
-        if let Some(Ok(logs)) = logs {
-            let logs = strip_ansi_escapes::strip_str(logs.join("\n"));
+        ui.horizontal_wrapped(|ui| {
+            egui::ComboBox::from_id_source("log_level")
+                .selected_text(self.min_level.label())
+                .show_ui(ui, |ui| {
+                    for level in [
+                        LevelFilter::All,
+                        LevelFilter::Debug,
+                        LevelFilter::Info,
+                        LevelFilter::Warn,
+                        LevelFilter::Error,
+                    ] {
+                        ui.selectable_value(&mut self.min_level, level, level.label());
+                    }
+                });
+            ui.add(
+                egui::TextEdit::singleline(&mut self.module)
+                    .hint_text(l10n("module"))
+                    .desired_width(120.0),
+            );
+            ui.add(
+                egui::TextEdit::singleline(&mut self.search)
+                    .hint_text(l10n("search"))
+                    .desired_width(120.0),
+            );
+            ui.checkbox(&mut self.paused, l10n("pause"));
+            ui.checkbox(&mut self.auto_scroll, l10n("auto_scroll"));
+        });
 
+        ui.horizontal(|ui| {
             #[cfg(not(target_os = "android"))]
             if ui.button(l10n("export_logs")).clicked() {
                 use native_dialog::FileDialog;
@@ -52,35 +142,122 @@ impl Logs {
                     .unwrap();
 
                 if let Some(path) = path {
-                    let _ = std::fs::write(path, logs.as_bytes());
+                    let _ = std::fs::write(path, self.logs.as_bytes());
                 }
             }
 
-            let last_1000_lines: String = logs
-                .lines()
-                .rev()
-                .take(1000)
-                .collect::<Vec<_>>()
-                .into_iter()
-                .rev()
-                .join("\n");
-            ui.centered_and_justified(|ui| {
-                egui::ScrollArea::vertical()
-                    .stick_to_bottom(true)
-                    .show(ui, |ui| {
-                        let style = ui.style_mut(); // Clone the current style
-                        style
-                            .text_styles
-                            .get_mut(&egui::TextStyle::Monospace)
-                            .unwrap()
-                            .size = 8.0; // Change font size
-
-                        ui.add(
-                            egui::TextEdit::multiline(&mut last_1000_lines.as_str()).code_editor(),
-                        )
-                    })
-            });
-        }
+            let uploading = self
+                .upload
+                .as_ref()
+                .is_some_and(|upload| upload.ready().is_none());
+            if ui
+                .add_enabled(!uploading, egui::Button::new(l10n("upload_debug_pack")))
+                .clicked()
+            {
+                let pack = debug_pack(&self.logs);
+                let config = get_config();
+                self.upload = Some(Promise::spawn_thread("upload_debug_pack", move || {
+                    let client = Client::start(config?.inert());
+                    smolscale::block_on(client.upload_debug_pack(pack))
+                }));
+            }
+            match self.upload.as_ref().map(|upload| upload.ready()) {
+                Some(None) => {
+                    ui.spinner();
+                }
+                Some(Some(Ok(id))) => {
+                    ui.label(format!("{}: {id}", l10n("debug_pack_id")));
+                }
+                Some(Some(Err(err))) => {
+                    ui.colored_label(palette(ui.visuals()).bad, err.to_string());
+                }
+                None => {}
+            }
+        });
+
+        let shown = self.filtered();
+        ui.centered_and_justified(|ui| {
+            egui::ScrollArea::vertical()
+                .stick_to_bottom(self.auto_scroll)
+                .show(ui, |ui| {
+                    let style = ui.style_mut(); // Clone the current style
+                    style
+                        .text_styles
+                        .get_mut(&egui::TextStyle::Monospace)
+                        .unwrap()
+                        .size = 8.0; // Change font size
+
+                    let mut layouter = log_layouter;
+                    ui.add(
+                        egui::TextEdit::multiline(&mut shown.as_str())
+                            .code_editor()
+                            .layouter(&mut layouter),
+                    )
+                })
+        });
         Ok(())
     }
+
+    /// The latest lines that pass the filters. Lines without a level of their own, such as the rest of a multi-line message, go along with the line before them.
+    fn filtered(&self) -> String {
+        let module = self.module.trim();
+        let search = self.search.trim().to_lowercase();
+        let mut passing = true;
+        let lines = self
+            .logs
+            .lines()
+            .filter(|line| {
+                if let Some((level, target)) = parse_line(line) {
+                    passing = self.min_level.allows(level)
+                        && (module.is_empty() || target.starts_with(module));
+                }
+                passing && (search.is_empty() || line.to_lowercase().contains(&search))
+            })
+            .collect_vec();
+        lines[lines.len().saturating_sub(SHOWN_LINES)..].join("\n")
+    }
+}
+
+/// Picks out the level and module of a log line, which comes out of the tracing formatter as "timestamp LEVEL module: message".
+fn parse_line(line: &str) -> Option<(&str, &str)> {
+    let mut tokens = line.split_whitespace().skip(1);
+    let level = tokens.next()?;
+    if !["TRACE", "DEBUG", "INFO", "WARN", "ERROR"].contains(&level) {
+        return None;
+    }
+    let target = tokens.next().unwrap_or_default().trim_end_matches(':');
+    Some((level, target))
+}
+
+/// Puts together what gets uploaded for support to look at: the version and the latest logs.
+fn debug_pack(logs: &str) -> String {
+    let mut start = logs.len().saturating_sub(DEBUG_PACK_LOGS);
+    while !logs.is_char_boundary(start) {
+        start += 1;
+    }
+    format!(
+        "version: {}\nos: {}\n\n{}",
+        env!("CARGO_PKG_VERSION"),
+        std::env::consts::OS,
+        &logs[start..]
+    )
+}
+
+/// Lays out the logs with warnings and errors picked out in color.
+fn log_layouter(ui: &egui::Ui, text: &str, wrap_width: f32) -> std::sync::Arc<egui::Galley> {
+    let palette = palette(ui.visuals());
+    let font_id = egui::TextStyle::Monospace.resolve(ui.style());
+    let mut job = egui::text::LayoutJob::default();
+    for line in text.split_inclusive('\n') {
+        let color = if line.contains("ERROR") {
+            palette.bad
+        } else if line.contains("WARN") {
+            palette.warn
+        } else {
+            ui.visuals().text_color()
+        };
+        job.append(line, 0.0, egui::TextFormat::simple(font_id.clone(), color));
+    }
+    job.wrap.max_width = wrap_width;
+    ui.fonts(|fonts| fonts.layout_job(job))
 }
@@ -1,4 +1,6 @@
+pub mod account;
 pub mod dashboard;
 pub mod login;
 pub mod logs;
+pub mod news;
 pub mod settings;
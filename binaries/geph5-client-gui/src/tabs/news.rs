@@ -0,0 +1,105 @@
+use std::time::Duration;
+
+use geph5_broker_protocol::NewsItem;
+use geph5_client::Client;
+use smol_str::SmolStr;
+
+use crate::{
+    l10n::l10n,
+    refresh_cell::RefreshCell,
+    settings::{get_config, LANG_CODE, NEWS_READ_UNTIL},
+    theme::palette,
+};
+
+pub struct News {
+    lang: SmolStr,
+    news: RefreshCell<anyhow::Result<Vec<NewsItem>>>,
+    /// What had been read when the news was first opened, so that new items stay marked as such for the rest of the session.
+    read_before: Option<u64>,
+}
+
+impl Default for News {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl News {
+    pub fn new() -> Self {
+        Self {
+            lang: LANG_CODE.get(),
+            news: RefreshCell::new(),
+            read_before: None,
+        }
+    }
+
+    /// The news in the current language, fetched in the background and refreshed every so often.
+    fn news(&mut self) -> Option<&anyhow::Result<Vec<NewsItem>>> {
+        let lang = LANG_CODE.get();
+        if lang != self.lang {
+            self.lang = lang.clone();
+            self.news = RefreshCell::new();
+        }
+        let config = get_config();
+        self.news.get_or_refresh(Duration::from_secs(600), move || {
+            let client = Client::start(config?.inert());
+            smolscale::block_on(async move { client.latest_news(&lang).await })
+        })
+    }
+
+    /// How many news items are newer than what the user has already seen.
+    pub fn unread(&mut self) -> usize {
+        let read_until = NEWS_READ_UNTIL.get();
+        match self.news() {
+            Some(Ok(news)) => news
+                .iter()
+                .filter(|item| item.date_unix > read_until)
+                .count(),
+            _ => 0,
+        }
+    }
+
+    pub fn render(&mut self, ui: &mut egui::Ui) -> anyhow::Result<()> {
+        let palette = palette(ui.visuals());
+        let read_until = NEWS_READ_UNTIL.get();
+        let read_before = *self.read_before.get_or_insert(read_until);
+        match self.news() {
+            Some(Ok(news)) if news.is_empty() => {
+                ui.colored_label(palette.muted, l10n("no_news"));
+            }
+            Some(Ok(news)) => {
+                egui::ScrollArea::vertical().show(ui, |ui| {
+                    for item in news {
+                        ui.horizontal(|ui| {
+                            ui.heading(&item.title);
+                            if item.date_unix > read_before {
+                                ui.colored_label(palette.warn, l10n("new"));
+                            }
+                        });
+                        ui.colored_label(palette.muted, format_date(item.date_unix));
+                        ui.label(&item.contents);
+                        ui.separator();
+                    }
+                });
+                if let Some(newest) = news.iter().map(|item| item.date_unix).max() {
+                    if newest > read_until {
+                        NEWS_READ_UNTIL.set(newest);
+                    }
+                }
+            }
+            Some(Err(err)) => {
+                ui.colored_label(palette.bad, err.to_string());
+            }
+            None => {
+                ui.spinner();
+            }
+        }
+        Ok(())
+    }
+}
+
+fn format_date(unix: u64) -> String {
+    chrono::DateTime::from_timestamp(unix as i64, 0)
+        .map(|date| date.format("%Y-%m-%d").to_string())
+        .unwrap_or_default()
+}
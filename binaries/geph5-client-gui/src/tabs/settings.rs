@@ -1,26 +1,54 @@
 use std::{sync::LazyLock, time::Duration};
 
 use egui::mutex::Mutex;
-use geph5_broker_protocol::{BrokerClient, ExitList, UserInfo};
-use geph5_client::{BridgeMode, Client};
+use geph5_broker_protocol::{BrokerClient, ExitDescriptor, ExitList, UserInfo};
+use geph5_client::{BridgeMode, Client, ExitBenchmark};
 use itertools::Itertools as _;
-use smol_str::format_smolstr;
 
 use crate::{
-    daemon::DAEMON_HANDLE,
+    autostart::set_autostart,
     l10n::{l10n, l10n_country},
+    profiles::{delete_profile, save_profile},
     refresh_cell::RefreshCell,
     settings::{
-        get_config, BRIDGE_MODE, HTTP_PROXY_PORT, LANG_CODE, PASSTHROUGH_CHINA, PASSWORD,
-        PROXY_AUTOCONF, SELECTED_CITY, SELECTED_COUNTRY, SOCKS5_PORT, USERNAME, VPN_MODE,
+        get_config, Theme, ACTIVE_PROFILE, AUTO_CONNECT, BRIDGE_MODE, HTTP_PROXY_PORT, LANG_CODE,
+        LAUNCH_AT_LOGIN, MINIMIZE_TO_TRAY, NOTIFICATIONS, PASSTHROUGH_CHINA, PROFILES,
+        PROXY_AUTOCONF, SELECTED_CITY, SELECTED_COUNTRY, SELECTED_EXIT, SOCKS5_PORT, THEME,
+        VPN_MODE,
     },
+    share::{render_qr, SharedSettings, PENDING_IMPORT, URI_PREFIX},
+    theme::palette,
 };
 
+mod network;
+
+use network::NetworkEditor;
+
+/// How often exits are benchmarked while the settings are open.
+const BENCHMARK_INTERVAL: Duration = Duration::from_secs(60);
+
+const MAX_BENCHMARKED_EXITS: usize = 100;
+
 pub static LOCATION_LIST: LazyLock<Mutex<RefreshCell<ExitList>>> =
     LazyLock::new(|| Mutex::new(RefreshCell::new()));
 
 pub struct Settings {
     user_info: RefreshCell<anyhow::Result<UserInfo>>,
+    benchmarks: RefreshCell<anyhow::Result<Vec<ExitBenchmark>>>,
+    exit_sort: ExitSort,
+    share_credentials: bool,
+    import_link: String,
+    import_error: Option<String>,
+    network: NetworkEditor,
+    profile_name: String,
+    profile_error: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ExitSort {
+    Location,
+    Load,
+    Latency,
 }
 
 impl Default for Settings {
@@ -33,6 +61,14 @@ impl Settings {
     pub fn new() -> Self {
         Settings {
             user_info: RefreshCell::new(),
+            benchmarks: RefreshCell::new(),
+            exit_sort: ExitSort::Location,
+            share_credentials: false,
+            import_link: String::new(),
+            import_error: None,
+            network: NetworkEditor::new(),
+            profile_name: String::new(),
+            profile_error: None,
         }
     }
 
@@ -43,38 +79,60 @@ impl Settings {
             let client = Client::start(inert_config);
             smolscale::block_on(async move { client.user_info().await })
         });
-        if let Some(user_info) = user_info {
-            match user_info {
-                Ok(info) => {
-                    let user_info_str = format_smolstr!(
-                        "{} / expires {}",
-                        info.user_id,
-                        info.plus_expires_unix.unwrap_or_default()
-                    );
-                    ui.label(user_info_str.as_str());
-                }
-                Err(err) => {
-                    ui.colored_label(egui::Color32::DARK_RED, err.to_string());
-                }
-            }
-        } else {
-            ui.colored_label(egui::Color32::DARK_GRAY, "Loading user info...");
-        }
-
-        if ui.button(l10n("logout")).clicked() {
-            let _ = DAEMON_HANDLE.stop();
-            USERNAME.set("".into());
-            PASSWORD.set("".into());
-        }
 
         // Preferences
-        ui.separator();
 
         ui.columns(2, |columns| {
             columns[0].label(l10n("language"));
             render_language_settings(&mut columns[1])
         })?;
 
+        ui.columns(2, |columns| {
+            columns[0].label(l10n("theme"));
+            render_theme_settings(&mut columns[1])
+        });
+
+        #[cfg(not(target_os = "android"))]
+        MINIMIZE_TO_TRAY.modify(|minimize_to_tray| {
+            ui.columns(2, |columns| {
+                columns[0].label(l10n("minimize_to_tray"));
+                columns[1].add(egui::Checkbox::new(minimize_to_tray, ""));
+            })
+        });
+
+        #[cfg(not(target_os = "android"))]
+        NOTIFICATIONS.modify(|notifications| {
+            ui.columns(2, |columns| {
+                columns[0].label(l10n("notifications"));
+                columns[1].add(egui::Checkbox::new(notifications, ""));
+            })
+        });
+
+        #[cfg(not(target_os = "android"))]
+        {
+            let mut launch_at_login = LAUNCH_AT_LOGIN.get();
+            ui.columns(2, |columns| {
+                columns[0].label(l10n("launch_at_login"));
+                columns[1].add(egui::Checkbox::new(&mut launch_at_login, ""));
+            });
+            // only remember the change if the OS took it
+            if launch_at_login != LAUNCH_AT_LOGIN.get() {
+                match set_autostart(launch_at_login) {
+                    Ok(()) => {
+                        LAUNCH_AT_LOGIN.set(launch_at_login);
+                    }
+                    Err(err) => tracing::warn!(err = debug(err), "could not change autostart"),
+                }
+            }
+        }
+
+        AUTO_CONNECT.modify(|auto_connect| {
+            ui.columns(2, |columns| {
+                columns[0].label(l10n("auto_connect"));
+                columns[1].add(egui::Checkbox::new(auto_connect, ""));
+            })
+        });
+
         // Network settings
         ui.separator();
 
@@ -93,44 +151,44 @@ impl Settings {
             })
         });
 
-        ui.columns(2, |columns| {
-            columns[0].label(l10n("exit_location"));
-            let is_plus = match user_info {
-                Some(Ok(user_info)) => user_info.plus_expires_unix.is_some(),
-                _ => false,
-            };
-            let mut location_list = LOCATION_LIST.lock();
-            let locations = location_list.get_or_refresh(Duration::from_secs(10), move || {
-                smolscale::block_on(async move {
-                    let rpc_transport = get_config().unwrap().broker.unwrap().rpc_transport();
-                    let client = BrokerClient::from(rpc_transport);
-                    loop {
-                        let fallible = async {
-                            let all_exits =
-                                client.get_exits().await?.map_err(|e| anyhow::anyhow!(e))?;
-                            let all_free_exits = client
-                                .get_free_exits()
-                                .await?
-                                .map_err(|e| anyhow::anyhow!(e))?;
-
-                            let mut exits = if is_plus {
-                                all_exits.inner
-                            } else {
-                                all_free_exits.inner
-                            };
-                            exits
-                                .all_exits
-                                .sort_unstable_by_key(|s| (s.1.country, s.1.city.clone()));
-                            anyhow::Ok(exits)
+        let is_plus = match user_info {
+            Some(Ok(user_info)) => user_info.plus_expires_unix.is_some(),
+            _ => false,
+        };
+        let mut location_list = LOCATION_LIST.lock();
+        let locations = location_list.get_or_refresh(Duration::from_secs(10), move || {
+            smolscale::block_on(async move {
+                let rpc_transport = get_config().unwrap().broker.unwrap().rpc_transport();
+                let client = BrokerClient::from(rpc_transport);
+                loop {
+                    let fallible = async {
+                        let all_exits =
+                            client.get_exits().await?.map_err(|e| anyhow::anyhow!(e))?;
+                        let all_free_exits = client
+                            .get_free_exits()
+                            .await?
+                            .map_err(|e| anyhow::anyhow!(e))?;
+
+                        let mut exits = if is_plus {
+                            all_exits.inner
+                        } else {
+                            all_free_exits.inner
                         };
-                        match fallible.await {
-                            Ok(v) => return v,
-                            Err(err) => tracing::warn!("Failed to get country list: {}", err),
-                        }
+                        exits
+                            .all_exits
+                            .sort_unstable_by_key(|s| (s.1.country, s.1.city.clone()));
+                        anyhow::Ok(exits)
+                    };
+                    match fallible.await {
+                        Ok(v) => return v,
+                        Err(err) => tracing::warn!("Failed to get country list: {}", err),
                     }
-                })
-            });
+                }
+            })
+        });
 
+        ui.columns(2, |columns| {
+            columns[0].label(l10n("exit_location"));
             columns[1].vertical(|ui| {
                 egui::ComboBox::from_id_source("country")
                     .selected_text(
@@ -160,6 +218,7 @@ impl Settings {
                         });
                         if SELECTED_COUNTRY.get() != former {
                             SELECTED_CITY.set(None);
+                            SELECTED_EXIT.set(None);
                         }
                     });
                 if let Some(country) = SELECTED_COUNTRY.get() {
@@ -171,6 +230,7 @@ impl Settings {
                         )
                         .show_ui(ui, |ui| {
                             if let Some(locations) = locations {
+                                let former = SELECTED_CITY.get();
                                 SELECTED_CITY.modify(|selected| {
                                     ui.selectable_value(selected, None, l10n("auto"));
                                     for city in locations
@@ -186,7 +246,10 @@ impl Settings {
                                             city.to_string(),
                                         );
                                     }
-                                })
+                                });
+                                if SELECTED_CITY.get() != former {
+                                    SELECTED_EXIT.set(None);
+                                }
                             } else {
                                 ui.spinner();
                             }
@@ -195,6 +258,23 @@ impl Settings {
             });
         });
 
+        if let Some(locations) = locations {
+            let inert_config = get_config()?.inert();
+            let benchmarks = self
+                .benchmarks
+                .get_or_refresh(BENCHMARK_INTERVAL, move || {
+                    let client = Client::start(inert_config);
+                    smolscale::block_on(async move {
+                        client
+                            .control_client()
+                            .benchmark_exits(MAX_BENCHMARKED_EXITS)
+                            .await?
+                            .map_err(|e| anyhow::anyhow!(e))
+                    })
+                })
+                .and_then(|benchmarks| benchmarks.as_ref().ok());
+            render_exit_picker(ui, locations, benchmarks, &mut self.exit_sort);
+        }
         ui.collapsing(l10n("advanced_settings"), |ui| {
             BRIDGE_MODE.modify(|bridge_mode| {
                 let mode_label = |bm: BridgeMode| match bm {
@@ -238,10 +318,200 @@ impl Settings {
                     ui.add(egui::DragValue::new(http_proxy_port));
                 })
             });
+
+            ui.separator();
+            self.network.render(ui);
         });
+        ui.collapsing(l10n("profiles"), |ui| self.render_profiles(ui));
+        ui.collapsing(l10n("share_settings"), |ui| self.render_share(ui));
 
         Ok(())
     }
+
+    /// Lists the saved profiles, and saves the current settings as one.
+    fn render_profiles(&mut self, ui: &mut egui::Ui) {
+        let active = ACTIVE_PROFILE.get();
+        for profile in PROFILES.get() {
+            ui.horizontal(|ui| {
+                if active.as_ref() == Some(&profile.name) {
+                    ui.strong(&profile.name);
+                } else {
+                    ui.label(&profile.name);
+                }
+                if ui.small_button("✖").clicked() {
+                    delete_profile(&profile.name);
+                }
+            });
+        }
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::TextEdit::singleline(&mut self.profile_name)
+                    .hint_text(l10n("profile_name"))
+                    .desired_width(150.0),
+            );
+            if ui.button(l10n("save_profile")).clicked() {
+                match save_profile(&self.profile_name) {
+                    Ok(()) => {
+                        self.profile_name.clear();
+                        self.profile_error = None;
+                    }
+                    Err(err) => self.profile_error = Some(err.to_string()),
+                }
+            }
+        });
+        if let Some(err) = &self.profile_error {
+            ui.colored_label(palette(ui.visuals()).bad, err);
+        }
+    }
+
+    /// Shows the current settings as a link and a QR code, and takes links to import.
+    fn render_share(&mut self, ui: &mut egui::Ui) {
+        let palette = palette(ui.visuals());
+        ui.checkbox(&mut self.share_credentials, l10n("include_login"));
+        match SharedSettings::current(self.share_credentials).to_uri() {
+            Ok(uri) => {
+                ui.horizontal(|ui| {
+                    ui.add(egui::TextEdit::singleline(&mut uri.as_str()).desired_width(200.0));
+                    if ui.button(l10n("copy")).clicked() {
+                        ui.output_mut(|output| output.copied_text = uri.clone());
+                    }
+                });
+                if let Err(err) = render_qr(ui, &uri) {
+                    ui.colored_label(palette.bad, err.to_string());
+                }
+            }
+            Err(err) => {
+                ui.colored_label(palette.bad, err.to_string());
+            }
+        }
+
+        ui.separator();
+        ui.horizontal(|ui| {
+            ui.add(
+                egui::TextEdit::singleline(&mut self.import_link)
+                    .hint_text(URI_PREFIX)
+                    .desired_width(200.0),
+            );
+            if ui.button(l10n("import")).clicked() {
+                match SharedSettings::from_uri(&self.import_link) {
+                    Ok(settings) => {
+                        *PENDING_IMPORT.lock() = Some(settings);
+                        self.import_link.clear();
+                        self.import_error = None;
+                    }
+                    Err(err) => self.import_error = Some(err.to_string()),
+                }
+            }
+        });
+        if let Some(err) = &self.import_error {
+            ui.colored_label(palette.bad, err);
+        }
+    }
+}
+
+/// Lists the exits within the selected country and city, with their load and measured latency, so that a particular one can be picked.
+fn render_exit_picker(
+    ui: &mut egui::Ui,
+    locations: &ExitList,
+    benchmarks: Option<&Vec<ExitBenchmark>>,
+    sort: &mut ExitSort,
+) {
+    let benchmark = |exit: &ExitDescriptor| {
+        benchmarks?
+            .iter()
+            .find(|bench| bench.exit.b2e_listen == exit.b2e_listen && bench.error.is_none())
+    };
+    let latency = |exit: &ExitDescriptor| benchmark(exit).and_then(|bench| bench.connect_secs);
+
+    let country = SELECTED_COUNTRY.get();
+    let city = SELECTED_CITY.get();
+    let mut exits = locations
+        .all_exits
+        .iter()
+        .map(|(_, exit)| exit)
+        .filter(|exit| country.is_none_or(|country| exit.country == country))
+        .filter(|exit| city.as_ref().is_none_or(|city| &exit.city == city))
+        .collect_vec();
+    match sort {
+        // the list already comes sorted by location
+        ExitSort::Location => {}
+        ExitSort::Load => exits.sort_by(|a, b| a.load.total_cmp(&b.load)),
+        ExitSort::Latency => exits.sort_by(|a, b| {
+            let a = latency(a).unwrap_or(f64::INFINITY);
+            let b = latency(b).unwrap_or(f64::INFINITY);
+            a.total_cmp(&b)
+        }),
+    }
+
+    ui.horizontal(|ui| {
+        let fastest = exits
+            .iter()
+            .filter_map(|exit| Some((*exit, benchmark(exit)?.score)))
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        if ui
+            .add_enabled(fastest.is_some(), egui::Button::new(l10n("fastest")))
+            .clicked()
+        {
+            if let Some((exit, _)) = fastest {
+                SELECTED_EXIT.set(Some(exit.b2e_listen.ip().to_string()));
+            }
+        }
+        if ui.button(l10n("auto")).clicked() {
+            SELECTED_EXIT.set(None);
+        }
+        if benchmarks.is_none() {
+            ui.spinner();
+        }
+    });
+
+    let selected = SELECTED_EXIT.get();
+    egui::ScrollArea::vertical()
+        .max_height(160.0)
+        .show(ui, |ui| {
+            egui::Grid::new("exits").striped(true).show(ui, |ui| {
+                ui.selectable_value(sort, ExitSort::Location, l10n("server"));
+                ui.selectable_value(sort, ExitSort::Load, l10n("load"));
+                ui.selectable_value(sort, ExitSort::Latency, l10n("latency"));
+                ui.end_row();
+                for exit in exits {
+                    let host = exit.b2e_listen.ip().to_string();
+                    let is_selected = selected.as_ref() == Some(&host);
+                    if ui
+                        .selectable_label(
+                            is_selected,
+                            format!("{} / {}", l10n_country(exit.country), exit.city),
+                        )
+                        .clicked()
+                    {
+                        SELECTED_EXIT.set(if is_selected { None } else { Some(host) });
+                    }
+                    ui.label(format!("{:.0}%", exit.load * 100.0));
+                    ui.label(
+                        latency(exit)
+                            .map(|secs| format!("{:.0} ms", secs * 1000.0))
+                            .unwrap_or_else(|| "-".to_string()),
+                    );
+                    ui.end_row();
+                }
+            });
+        });
+}
+
+fn render_theme_settings(ui: &mut egui::Ui) {
+    let theme_label = |theme: Theme| match theme {
+        Theme::System => l10n("theme_system"),
+        Theme::Light => l10n("theme_light"),
+        Theme::Dark => l10n("theme_dark"),
+    };
+    THEME.modify(|theme| {
+        egui::ComboBox::from_id_source("theme")
+            .selected_text(theme_label(*theme))
+            .show_ui(ui, |ui| {
+                for this_theme in [Theme::System, Theme::Light, Theme::Dark] {
+                    ui.selectable_value(theme, this_theme, theme_label(this_theme));
+                }
+            });
+    });
 }
 
 pub fn render_language_settings(ui: &mut egui::Ui) -> anyhow::Result<()> {
@@ -0,0 +1,415 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::Context;
+use geph5_broker_protocol::RouteDescriptor;
+use geph5_client::{BrokerSource, CustomBridge};
+
+use crate::{
+    l10n::l10n,
+    settings::{CUSTOM_BRIDGES, CUSTOM_BRIDGES_ONLY, CUSTOM_BROKER},
+    theme::palette,
+};
+
+/// Edits the custom broker and the user's own bridges. Changes are kept as text until they are saved, and only saved if they all make sense.
+pub struct NetworkEditor {
+    broker: BrokerDraft,
+    bridges: Vec<BridgeDraft>,
+    bridges_only: bool,
+    error: Option<String>,
+    /// What was saved when the drafts were made, so that they can be remade when something else, like importing a share link, changes it.
+    saved: serde_json::Value,
+}
+
+impl NetworkEditor {
+    pub fn new() -> Self {
+        Self {
+            broker: CUSTOM_BROKER
+                .get()
+                .map(|source| BrokerDraft::from_source(&source))
+                .unwrap_or_default(),
+            bridges: CUSTOM_BRIDGES
+                .get()
+                .iter()
+                .map(BridgeDraft::from_bridge)
+                .collect(),
+            bridges_only: CUSTOM_BRIDGES_ONLY.get(),
+            error: None,
+            saved: saved(),
+        }
+    }
+
+    pub fn render(&mut self, ui: &mut egui::Ui) {
+        if saved() != self.saved {
+            *self = Self::new();
+        }
+        ui.label(l10n("broker"));
+        self.broker.render(ui, "broker", true);
+
+        ui.separator();
+        ui.label(l10n("custom_bridges"));
+        let mut removed = None;
+        for (i, bridge) in self.bridges.iter_mut().enumerate() {
+            ui.push_id(i, |ui| {
+                if bridge.render(ui) {
+                    removed = Some(i);
+                }
+            });
+        }
+        if let Some(i) = removed {
+            self.bridges.remove(i);
+        }
+        if ui.button(l10n("add_bridge")).clicked() {
+            self.bridges.push(BridgeDraft::default());
+        }
+        ui.checkbox(&mut self.bridges_only, l10n("custom_bridges_only"));
+
+        ui.horizontal(|ui| {
+            if ui.button(l10n("save")).clicked() {
+                self.error = self.save().err().map(|err| format!("{err:#}"));
+            }
+            if ui.button(l10n("cancel")).clicked() {
+                *self = Self::new();
+            }
+        });
+        if let Some(err) = &self.error {
+            ui.colored_label(palette(ui.visuals()).bad, err);
+        }
+    }
+
+    /// Checks everything and saves it for the next connection. Nothing is saved unless everything checks out.
+    fn save(&mut self) -> anyhow::Result<()> {
+        let broker = self.broker.to_source().context("invalid broker")?;
+        let bridges = self
+            .bridges
+            .iter()
+            .enumerate()
+            .map(|(i, bridge)| {
+                bridge
+                    .to_bridge()
+                    .with_context(|| format!("invalid bridge #{}", i + 1))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        anyhow::ensure!(
+            !self.bridges_only || !bridges.is_empty(),
+            "using only custom bridges needs at least one bridge"
+        );
+        CUSTOM_BROKER.set(broker);
+        CUSTOM_BRIDGES.set(bridges);
+        CUSTOM_BRIDGES_ONLY.set(self.bridges_only);
+        self.saved = saved();
+        Ok(())
+    }
+}
+
+fn saved() -> serde_json::Value {
+    serde_json::json!([
+        CUSTOM_BROKER.get(),
+        CUSTOM_BRIDGES.get(),
+        CUSTOM_BRIDGES_ONLY.get()
+    ])
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+enum BrokerKind {
+    #[default]
+    None,
+    Direct,
+    Fronted,
+    DirectTcp,
+    Race,
+    /// A kind of source that can't be edited here, such as an AWS Lambda one from a share link. It's kept as it is unless another kind is picked.
+    Other,
+}
+
+impl BrokerKind {
+    fn label(self) -> &'static str {
+        match self {
+            BrokerKind::None => l10n("broker_none"),
+            BrokerKind::Direct => l10n("broker_direct"),
+            BrokerKind::Fronted => l10n("broker_fronted"),
+            BrokerKind::DirectTcp => l10n("broker_direct_tcp"),
+            BrokerKind::Race => l10n("broker_race"),
+            BrokerKind::Other => l10n("custom"),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct BrokerDraft {
+    kind: BrokerKind,
+    url: String,
+    front: String,
+    host: String,
+    addr: String,
+    race: Vec<BrokerDraft>,
+    other: Option<BrokerSource>,
+}
+
+impl BrokerDraft {
+    fn from_source(source: &BrokerSource) -> Self {
+        match source {
+            BrokerSource::Direct(url) => Self {
+                kind: BrokerKind::Direct,
+                url: url.clone(),
+                ..Default::default()
+            },
+            BrokerSource::Fronted { front, host } => Self {
+                kind: BrokerKind::Fronted,
+                front: front.clone(),
+                host: host.clone(),
+                ..Default::default()
+            },
+            BrokerSource::DirectTcp(addr) => Self {
+                kind: BrokerKind::DirectTcp,
+                addr: addr.to_string(),
+                ..Default::default()
+            },
+            BrokerSource::Race(sources) => Self {
+                kind: BrokerKind::Race,
+                race: sources.iter().map(Self::from_source).collect(),
+                ..Default::default()
+            },
+            other => Self {
+                kind: BrokerKind::Other,
+                other: Some(other.clone()),
+                ..Default::default()
+            },
+        }
+    }
+
+    fn to_source(&self) -> anyhow::Result<Option<BrokerSource>> {
+        Ok(match self.kind {
+            BrokerKind::None => None,
+            BrokerKind::Direct => Some(BrokerSource::Direct(parse_url(&self.url)?)),
+            BrokerKind::Fronted => {
+                let host = self.host.trim();
+                anyhow::ensure!(
+                    !host.is_empty() && !host.contains(char::is_whitespace),
+                    "invalid host {host:?}"
+                );
+                Some(BrokerSource::Fronted {
+                    front: parse_url(&self.front)?,
+                    host: host.to_string(),
+                })
+            }
+            BrokerKind::DirectTcp => Some(BrokerSource::DirectTcp(parse_addr(&self.addr)?)),
+            BrokerKind::Race => {
+                anyhow::ensure!(!self.race.is_empty(), "racing needs at least one broker");
+                Some(BrokerSource::Race(
+                    self.race
+                        .iter()
+                        .map(|draft| draft.to_source()?.context("empty broker in race"))
+                        .collect::<anyhow::Result<_>>()?,
+                ))
+            }
+            BrokerKind::Other => self.other.clone(),
+        })
+    }
+
+    /// Draws the editor. Brokers within a race can't be races themselves, or be left empty.
+    fn render(&mut self, ui: &mut egui::Ui, id: &str, top_level: bool) {
+        let kinds: &[BrokerKind] = if top_level {
+            &[
+                BrokerKind::None,
+                BrokerKind::Direct,
+                BrokerKind::Fronted,
+                BrokerKind::DirectTcp,
+                BrokerKind::Race,
+            ]
+        } else {
+            &[
+                BrokerKind::Direct,
+                BrokerKind::Fronted,
+                BrokerKind::DirectTcp,
+            ]
+        };
+        egui::ComboBox::from_id_source(id)
+            .selected_text(self.kind.label())
+            .show_ui(ui, |ui| {
+                for &kind in kinds {
+                    ui.selectable_value(&mut self.kind, kind, kind.label());
+                }
+            });
+        match self.kind {
+            BrokerKind::None | BrokerKind::Other => {}
+            BrokerKind::Direct => {
+                ui.add(egui::TextEdit::singleline(&mut self.url).hint_text("https://"));
+            }
+            BrokerKind::Fronted => {
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.front)
+                        .hint_text(l10n("broker_fronted_front")),
+                );
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.host)
+                        .hint_text(l10n("broker_fronted_host")),
+                );
+            }
+            BrokerKind::DirectTcp => {
+                ui.add(egui::TextEdit::singleline(&mut self.addr).hint_text("1.2.3.4:5678"));
+            }
+            BrokerKind::Race => {
+                let mut removed = None;
+                for (i, draft) in self.race.iter_mut().enumerate() {
+                    ui.horizontal(|ui| {
+                        ui.vertical(|ui| draft.render(ui, &format!("{id}-{i}"), false));
+                        if ui.small_button("✖").clicked() {
+                            removed = Some(i);
+                        }
+                    });
+                }
+                if let Some(i) = removed {
+                    self.race.remove(i);
+                }
+                if ui.button(l10n("add_broker")).clicked() {
+                    self.race.push(BrokerDraft {
+                        kind: BrokerKind::Direct,
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Default)]
+enum BridgeKind {
+    #[default]
+    Sosistab3Tcp,
+    Sosistab3Kcp,
+    /// A route that can't be edited here. It's kept as it is unless another kind is picked.
+    Other,
+}
+
+impl BridgeKind {
+    fn label(self) -> &'static str {
+        match self {
+            BridgeKind::Sosistab3Tcp => "sosistab3 / TCP",
+            BridgeKind::Sosistab3Kcp => "sosistab3 / KCP",
+            BridgeKind::Other => l10n("custom"),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct BridgeDraft {
+    kind: BridgeKind,
+    addr: String,
+    cookie: String,
+    /// The exit the bridge leads to. It's used for all of them if this is left empty.
+    exit: String,
+    other: Option<RouteDescriptor>,
+}
+
+impl BridgeDraft {
+    fn from_bridge(bridge: &CustomBridge) -> Self {
+        let exit = bridge.exit.map(|ip| ip.to_string()).unwrap_or_default();
+        if let RouteDescriptor::Sosistab3 { cookie, lower } = &bridge.route {
+            match lower.as_ref() {
+                RouteDescriptor::Tcp(addr) => {
+                    return Self {
+                        kind: BridgeKind::Sosistab3Tcp,
+                        addr: addr.to_string(),
+                        cookie: cookie.clone(),
+                        exit,
+                        other: None,
+                    }
+                }
+                RouteDescriptor::Kcp(addr) => {
+                    return Self {
+                        kind: BridgeKind::Sosistab3Kcp,
+                        addr: addr.to_string(),
+                        cookie: cookie.clone(),
+                        exit,
+                        other: None,
+                    }
+                }
+                _ => {}
+            }
+        }
+        Self {
+            kind: BridgeKind::Other,
+            exit,
+            other: Some(bridge.route.clone()),
+            ..Default::default()
+        }
+    }
+
+    fn to_bridge(&self) -> anyhow::Result<CustomBridge> {
+        let exit = match self.exit.trim() {
+            "" => None,
+            exit => Some(
+                exit.parse::<IpAddr>()
+                    .with_context(|| format!("invalid exit address {exit:?}"))?,
+            ),
+        };
+        let cookie = self.cookie.trim();
+        let route = match self.kind {
+            BridgeKind::Sosistab3Tcp | BridgeKind::Sosistab3Kcp => {
+                anyhow::ensure!(!cookie.is_empty(), "missing cookie");
+                let addr = parse_addr(&self.addr)?;
+                RouteDescriptor::Sosistab3 {
+                    cookie: cookie.to_string(),
+                    lower: Box::new(if self.kind == BridgeKind::Sosistab3Tcp {
+                        RouteDescriptor::Tcp(addr)
+                    } else {
+                        RouteDescriptor::Kcp(addr)
+                    }),
+                }
+            }
+            BridgeKind::Other => self.other.clone().context("missing route")?,
+        };
+        Ok(CustomBridge { exit, route })
+    }
+
+    /// Draws the editor, returning whether the bridge should be removed.
+    fn render(&mut self, ui: &mut egui::Ui) -> bool {
+        let mut remove = false;
+        ui.horizontal_wrapped(|ui| {
+            egui::ComboBox::from_id_source("bridge_kind")
+                .selected_text(self.kind.label())
+                .show_ui(ui, |ui| {
+                    for kind in [BridgeKind::Sosistab3Tcp, BridgeKind::Sosistab3Kcp] {
+                        ui.selectable_value(&mut self.kind, kind, kind.label());
+                    }
+                });
+            if self.kind != BridgeKind::Other {
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.addr)
+                        .hint_text("1.2.3.4:5678")
+                        .desired_width(120.0),
+                );
+                ui.add(
+                    egui::TextEdit::singleline(&mut self.cookie)
+                        .hint_text(l10n("cookie"))
+                        .desired_width(120.0),
+                );
+            }
+            ui.add(
+                egui::TextEdit::singleline(&mut self.exit)
+                    .hint_text(l10n("exit_ip"))
+                    .desired_width(120.0),
+            );
+            remove = ui.small_button("✖").clicked();
+        });
+        remove
+    }
+}
+
+fn parse_url(url: &str) -> anyhow::Result<String> {
+    let url = url.trim();
+    let uri: http::Uri = url
+        .parse()
+        .with_context(|| format!("invalid URL {url:?}"))?;
+    anyhow::ensure!(
+        matches!(uri.scheme_str(), Some("http" | "https")) && uri.host().is_some(),
+        "URL {url:?} must start with http:// or https://"
+    );
+    Ok(url.to_string())
+}
+
+fn parse_addr(addr: &str) -> anyhow::Result<SocketAddr> {
+    let addr = addr.trim();
+    addr.parse()
+        .with_context(|| format!("invalid address {addr:?}, expected something like 1.2.3.4:5678"))
+}
@@ -0,0 +1,54 @@
+use egui::{Color32, Visuals};
+
+use crate::settings::{Theme, THEME};
+
+/// Colors with a meaning, picked to stay readable on both light and dark backgrounds.
+pub struct Palette {
+    pub good: Color32,
+    pub busy: Color32,
+    pub bad: Color32,
+    pub warn: Color32,
+    pub muted: Color32,
+    pub download: Color32,
+    pub upload: Color32,
+}
+
+pub fn palette(visuals: &Visuals) -> Palette {
+    if visuals.dark_mode {
+        Palette {
+            good: Color32::LIGHT_GREEN,
+            busy: Color32::LIGHT_BLUE,
+            bad: Color32::LIGHT_RED,
+            warn: Color32::from_rgb(230, 180, 60),
+            muted: Color32::GRAY,
+            download: Color32::from_rgb(80, 170, 255),
+            upload: Color32::from_rgb(255, 160, 60),
+        }
+    } else {
+        Palette {
+            good: Color32::DARK_GREEN,
+            busy: Color32::DARK_BLUE,
+            bad: Color32::DARK_RED,
+            warn: Color32::from_rgb(170, 110, 0),
+            muted: Color32::DARK_GRAY,
+            download: Color32::from_rgb(0, 120, 215),
+            upload: Color32::from_rgb(220, 120, 0),
+        }
+    }
+}
+
+/// Switches the visuals to the configured theme, if they aren't already. `system_dark` is what the system prefers, for when the theme follows it.
+pub fn apply_theme(ctx: &egui::Context, system_dark: bool) {
+    let dark = match THEME.get() {
+        Theme::System => system_dark,
+        Theme::Light => false,
+        Theme::Dark => true,
+    };
+    if ctx.style().visuals.dark_mode != dark {
+        ctx.set_visuals(if dark {
+            Visuals::dark()
+        } else {
+            Visuals::light()
+        });
+    }
+}
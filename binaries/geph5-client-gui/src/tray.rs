@@ -0,0 +1,231 @@
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        LazyLock,
+    },
+    time::Duration,
+};
+
+use egui::{mutex::Mutex, ViewportCommand};
+use geph5_client::ConnInfo;
+use smol_timeout2::TimeoutExt;
+use tray_icon::{
+    menu::{Menu, MenuEvent, MenuItem, PredefinedMenuItem},
+    Icon, TrayIcon, TrayIconBuilder,
+};
+
+use crate::{
+    daemon::{connect, disconnect, DAEMON_HANDLE},
+    l10n::{l10n, l10n_country},
+};
+
+/// What the tray shows. It's kept fresh by its own thread, so that it stays current while the window is hidden and nothing renders.
+#[derive(Default)]
+struct TrayStatus {
+    conn_info: Option<ConnInfo>,
+    total_bytes: f64,
+}
+
+static TRAY_STATUS: LazyLock<Mutex<TrayStatus>> = LazyLock::new(Default::default);
+
+static TRAY_READY: AtomicBool = AtomicBool::new(false);
+
+static QUITTING: AtomicBool = AtomicBool::new(false);
+
+/// Whether the tray icon is up, so that hiding the window doesn't leave the user with no way back to it.
+pub fn tray_ready() -> bool {
+    TRAY_READY.load(Ordering::SeqCst)
+}
+
+/// Whether "quit" was picked from the tray, in which case closing the window must really close it.
+pub fn quit_requested() -> bool {
+    QUITTING.load(Ordering::SeqCst)
+}
+
+/// Puts up the tray icon. On Linux, the tray needs a GTK main loop, so it lives on its own thread and refreshes itself.
+#[cfg(target_os = "linux")]
+pub fn init_tray(ctx: &egui::Context) {
+    std::thread::spawn(status_loop);
+    let ctx = ctx.clone();
+    std::thread::spawn(move || {
+        if let Err(err) = gtk::init() {
+            tracing::warn!(err = debug(err), "could not start GTK for the tray");
+            return;
+        }
+        match Tray::new(&ctx) {
+            Ok(tray) => {
+                gtk::glib::timeout_add_local(Duration::from_secs(1), move || {
+                    tray.refresh();
+                    gtk::glib::ControlFlow::Continue
+                });
+                TRAY_READY.store(true, Ordering::SeqCst);
+                gtk::main();
+            }
+            Err(err) => tracing::warn!(err = debug(err), "could not create the tray icon"),
+        }
+    });
+}
+
+/// Refreshes the tray from the GUI thread. On Linux, the tray thread does this by itself.
+#[cfg(target_os = "linux")]
+pub fn refresh_tray() {}
+
+#[cfg(not(target_os = "linux"))]
+thread_local! {
+    static TRAY: std::cell::RefCell<Option<Tray>> = const { std::cell::RefCell::new(None) };
+}
+
+/// Puts up the tray icon. Elsewhere than Linux, the tray must live on the thread running the event loop, so this has to be called from the GUI.
+#[cfg(not(target_os = "linux"))]
+pub fn init_tray(ctx: &egui::Context) {
+    std::thread::spawn(status_loop);
+    match Tray::new(ctx) {
+        Ok(tray) => {
+            TRAY.with(|cell| *cell.borrow_mut() = Some(tray));
+            TRAY_READY.store(true, Ordering::SeqCst);
+        }
+        Err(err) => tracing::warn!(err = debug(err), "could not create the tray icon"),
+    }
+}
+
+/// Refreshes the tray from the GUI thread, which owns it.
+#[cfg(not(target_os = "linux"))]
+pub fn refresh_tray() {
+    TRAY.with(|cell| {
+        if let Some(tray) = cell.borrow().as_ref() {
+            tray.refresh();
+        }
+    });
+}
+
+fn status_loop() {
+    loop {
+        let client = DAEMON_HANDLE.control_client();
+        let conn_info =
+            smol::future::block_on(client.conn_info().timeout(Duration::from_millis(500)))
+                .and_then(|s| s.ok());
+        let total_bytes = smol::future::block_on(async {
+            client
+                .stat_num("total_rx_bytes".to_string())
+                .await
+                .unwrap_or_default()
+                + client
+                    .stat_num("total_tx_bytes".to_string())
+                    .await
+                    .unwrap_or_default()
+        });
+        *TRAY_STATUS.lock() = TrayStatus {
+            conn_info,
+            total_bytes,
+        };
+        std::thread::sleep(Duration::from_secs(1));
+    }
+}
+
+struct Tray {
+    icon: TrayIcon,
+    status: MenuItem,
+    exit: MenuItem,
+    data_used: MenuItem,
+    toggle: MenuItem,
+}
+
+impl Tray {
+    fn new(ctx: &egui::Context) -> anyhow::Result<Self> {
+        let status = MenuItem::new(l10n("disconnected"), false, None);
+        let exit = MenuItem::new("", false, None);
+        let data_used = MenuItem::new("", false, None);
+        let toggle = MenuItem::new(l10n("connect"), true, None);
+        let show = MenuItem::new(l10n("show_window"), true, None);
+        let quit = MenuItem::new(l10n("quit"), true, None);
+        let menu = Menu::new();
+        menu.append_items(&[
+            &status,
+            &exit,
+            &data_used,
+            &PredefinedMenuItem::separator(),
+            &toggle,
+            &show,
+            &PredefinedMenuItem::separator(),
+            &quit,
+        ])?;
+
+        let icon = TrayIconBuilder::new()
+            .with_menu(Box::new(menu))
+            .with_tooltip(l10n("geph"))
+            .with_icon(tray_icon_image()?)
+            .build()?;
+
+        let (toggle_id, show_id, quit_id) =
+            (toggle.id().clone(), show.id().clone(), quit.id().clone());
+        let ctx = ctx.clone();
+        MenuEvent::set_event_handler(Some(move |event: MenuEvent| {
+            let result = if event.id == toggle_id {
+                if TRAY_STATUS.lock().conn_info.is_some() {
+                    disconnect()
+                } else {
+                    connect()
+                }
+            } else if event.id == show_id {
+                ctx.send_viewport_cmd(ViewportCommand::Visible(true));
+                ctx.send_viewport_cmd(ViewportCommand::Focus);
+                Ok(())
+            } else if event.id == quit_id {
+                QUITTING.store(true, Ordering::SeqCst);
+                // a hidden window might never get around to handling the close
+                ctx.send_viewport_cmd(ViewportCommand::Visible(true));
+                ctx.send_viewport_cmd(ViewportCommand::Close);
+                Ok(())
+            } else {
+                Ok(())
+            };
+            if let Err(err) = result {
+                tracing::warn!(err = debug(err), "tray action failed");
+            }
+            ctx.request_repaint();
+        }));
+
+        Ok(Self {
+            icon,
+            status,
+            exit,
+            data_used,
+            toggle,
+        })
+    }
+
+    fn refresh(&self) {
+        let status = TRAY_STATUS.lock();
+        let (state, exit) = match &status.conn_info {
+            Some(ConnInfo::Connecting) => (l10n("connecting"), "-".to_string()),
+            Some(ConnInfo::Connected(info)) => (
+                l10n("connected"),
+                format!("{} / {}", l10n_country(info.exit.country), info.exit.city),
+            ),
+            None => (l10n("disconnected"), "-".to_string()),
+        };
+        self.status
+            .set_text(format!("{}: {}", l10n("status"), state));
+        self.exit
+            .set_text(format!("{}: {}", l10n("exit_location"), exit));
+        self.data_used.set_text(format!(
+            "{}: {:.1} MB",
+            l10n("data_used"),
+            status.total_bytes / 1_000_000.0
+        ));
+        self.toggle.set_text(if status.conn_info.is_some() {
+            l10n("disconnect")
+        } else {
+            l10n("connect")
+        });
+        let _ = self
+            .icon
+            .set_tooltip(Some(format!("{} — {}", l10n("geph"), state)));
+    }
+}
+
+fn tray_icon_image() -> anyhow::Result<Icon> {
+    let image = image::load_from_memory(include_bytes!("../icon.ico"))?.into_rgba8();
+    let (width, height) = image.dimensions();
+    Ok(Icon::from_rgba(image.into_raw(), width, height)?)
+}
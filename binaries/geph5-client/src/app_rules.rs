@@ -0,0 +1,379 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+};
+
+use anyctx::AnyCtx;
+use serde::{Deserialize, Serialize};
+
+use crate::{live_config::live_config, Config};
+
+/// One rule for per-application split tunneling. The first rule that matches the application behind a connection decides whether it is tunneled; connections that no rule matches are tunneled.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AppRule {
+    #[serde(rename = "match")]
+    pub matcher: AppMatcher,
+    pub action: AppAction,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum AppMatcher {
+    /// The executable's file name, like `firefox.exe`, or its full path if this contains a path separator.
+    Process(String),
+    /// The user that owns the socket. Linux only.
+    Uid(u32),
+    /// A cgroup v2 path like `/user.slice/user-1000.slice`, matching everything in it or below it. Linux only.
+    Cgroup(String),
+    /// Every connection, for use as the last rule.
+    Any,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AppAction {
+    Tunnel,
+    Direct,
+}
+
+/// What we could find out about the application that owns a local socket.
+#[derive(Debug, Default)]
+struct SocketOwner {
+    uid: Option<u32>,
+    exe: Option<PathBuf>,
+    cgroup: Option<String>,
+}
+
+impl AppMatcher {
+    fn matches(&self, owner: Option<&SocketOwner>) -> bool {
+        match (self, owner) {
+            (AppMatcher::Any, _) => true,
+            (_, None) => false,
+            (AppMatcher::Process(name), Some(owner)) => owner
+                .exe
+                .as_deref()
+                .is_some_and(|exe| exe_matches(name, exe)),
+            (AppMatcher::Uid(uid), Some(owner)) => owner.uid == Some(*uid),
+            (AppMatcher::Cgroup(cgroup), Some(owner)) => {
+                let cgroup = cgroup.trim_end_matches('/');
+                owner.cgroup.as_deref().is_some_and(|cg| {
+                    cg == cgroup
+                        || cg
+                            .strip_prefix(cgroup)
+                            .is_some_and(|rest| rest.starts_with('/'))
+                })
+            }
+        }
+    }
+
+    fn needs_process(&self) -> bool {
+        matches!(self, AppMatcher::Process(_) | AppMatcher::Cgroup(_))
+    }
+}
+
+fn exe_matches(name: &str, exe: &Path) -> bool {
+    let eq = |a: &str, b: &str| {
+        if cfg!(windows) {
+            a.eq_ignore_ascii_case(b)
+        } else {
+            a == b
+        }
+    };
+    if name.contains(['/', '\\']) {
+        eq(name, &exe.to_string_lossy())
+    } else {
+        exe.file_name()
+            .is_some_and(|file_name| eq(name, &file_name.to_string_lossy()))
+    }
+}
+
+/// Decides whether a connection should be tunneled, by looking up which application owns the local socket `app_addr` that it came from. `protocol` is "tcp" or "udp".
+pub async fn app_action(ctx: &AnyCtx<Config>, protocol: &str, app_addr: SocketAddr) -> AppAction {
+    let config = live_config(ctx);
+    let rules = &config.app_rules;
+    if rules.is_empty() {
+        return AppAction::Tunnel;
+    }
+    let needs_process = rules.iter().any(|rule| rule.matcher.needs_process());
+    let protocol = protocol.to_string();
+    // this walks through procfs or the system's socket tables, so keep it off the executor
+    let owner = smol::unblock(move || socket_owner(&protocol, app_addr, needs_process)).await;
+    let action = rules
+        .iter()
+        .find(|rule| rule.matcher.matches(owner.as_ref()))
+        .map(|rule| rule.action)
+        .unwrap_or(AppAction::Tunnel);
+    tracing::trace!(
+        app_addr = display(app_addr),
+        owner = debug(&owner),
+        action = debug(action),
+        "applied application rules"
+    );
+    action
+}
+
+/// Treats IPv4-mapped IPv6 addresses as the IPv4 addresses they are, since a dual-stack socket shows up with one kind and its peer with the other.
+pub(crate) fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(v6)),
+        v4 => v4,
+    }
+}
+
+/// Whether a socket bound to `bound` is the one that sends from `addr`. Unconnected UDP sockets are usually bound to the unspecified address.
+fn binds(bound: SocketAddr, addr: SocketAddr) -> bool {
+    bound.port() == addr.port()
+        && (canonical(bound.ip()) == canonical(addr.ip()) || bound.ip().is_unspecified())
+}
+
+/// Parses an address out of /proc/net/{tcp,udp}[6]. The kernel prints each 32-bit word of an address as a native-endian hex number.
+#[cfg(target_os = "linux")]
+fn parse_proc_addr(s: &str) -> Option<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    let (ip, port) = s.split_once(':')?;
+    let port = u16::from_str_radix(port, 16).ok()?;
+    let mut octets = vec![];
+    for i in (0..ip.len()).step_by(8) {
+        let word = u32::from_str_radix(ip.get(i..i + 8)?, 16).ok()?;
+        octets.extend_from_slice(&word.to_ne_bytes());
+    }
+    let ip: IpAddr = match octets.len() {
+        4 => Ipv4Addr::from(<[u8; 4]>::try_from(octets).ok()?).into(),
+        16 => Ipv6Addr::from(<[u8; 16]>::try_from(octets).ok()?).into(),
+        _ => return None,
+    };
+    Some(SocketAddr::new(ip, port))
+}
+
+/// How long a walk through every process's open files is trusted for sockets that were already open when it started.
+#[cfg(target_os = "linux")]
+const INODE_PIDS_TTL: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Which process has each socket inode open, as of the last walk through /proc/*/fd.
+#[cfg(target_os = "linux")]
+struct InodePids {
+    scanned_at: std::time::Instant,
+    pids: std::collections::HashMap<u64, u32>,
+}
+
+#[cfg(target_os = "linux")]
+static INODE_PIDS: parking_lot::Mutex<Option<InodePids>> = parking_lot::const_mutex(None);
+
+/// Finds the process that has the socket with the given inode open, where the socket is known to have existed at `since`. Walking /proc/*/fd is expensive, so one walk serves every lookup for sockets that were already open when it started, like a browser's burst of new connections.
+#[cfg(target_os = "linux")]
+fn socket_pid(inode: u64, since: std::time::Instant) -> Option<u32> {
+    let mut cache = INODE_PIDS.lock();
+    if let Some(cached) = cache.as_ref() {
+        match cached.pids.get(&inode) {
+            Some(&pid) if cached.scanned_at.elapsed() < INODE_PIDS_TTL => return Some(pid),
+            // the socket was already open during the walk, so no process has it
+            None if cached.scanned_at >= since => return None,
+            _ => {}
+        }
+    }
+    let scanned_at = std::time::Instant::now();
+    let mut pids = std::collections::HashMap::new();
+    for proc in std::fs::read_dir("/proc").into_iter().flatten().flatten() {
+        let Some(pid) = proc
+            .file_name()
+            .to_str()
+            .and_then(|pid| pid.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        for fd in std::fs::read_dir(proc.path().join("fd"))
+            .into_iter()
+            .flatten()
+            .flatten()
+        {
+            let socket_inode = std::fs::read_link(fd.path()).ok().and_then(|link| {
+                link.to_str()?
+                    .strip_prefix("socket:[")?
+                    .strip_suffix(']')?
+                    .parse::<u64>()
+                    .ok()
+            });
+            if let Some(socket_inode) = socket_inode {
+                pids.insert(socket_inode, pid);
+            }
+        }
+    }
+    let pid = pids.get(&inode).copied();
+    *cache = Some(InodePids { scanned_at, pids });
+    pid
+}
+
+#[cfg(target_os = "linux")]
+fn socket_owner(protocol: &str, addr: SocketAddr, needs_process: bool) -> Option<SocketOwner> {
+    let since = std::time::Instant::now();
+    // an exact match beats a socket bound to the unspecified address
+    let mut found: Option<(bool, u32, u64)> = None;
+    for table in [
+        format!("/proc/net/{protocol}"),
+        format!("/proc/net/{protocol}6"),
+    ] {
+        let Ok(table) = std::fs::read_to_string(table) else {
+            continue;
+        };
+        for line in table.lines().skip(1) {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let (Some(local), Some(uid), Some(inode)) = (
+                fields.get(1).and_then(|s| parse_proc_addr(s)),
+                fields.get(7).and_then(|s| s.parse::<u32>().ok()),
+                fields.get(9).and_then(|s| s.parse::<u64>().ok()),
+            ) else {
+                continue;
+            };
+            if inode == 0 || !binds(local, addr) {
+                continue;
+            }
+            let exact = !local.ip().is_unspecified();
+            if found.is_none_or(|(found_exact, _, _)| exact && !found_exact) {
+                found = Some((exact, uid, inode));
+            }
+        }
+    }
+    let (_, uid, inode) = found?;
+
+    let mut owner = SocketOwner {
+        uid: Some(uid),
+        ..Default::default()
+    };
+    if needs_process {
+        if let Some(pid) = socket_pid(inode, since) {
+            owner.exe = std::fs::read_link(format!("/proc/{pid}/exe")).ok();
+            owner.cgroup = std::fs::read_to_string(format!("/proc/{pid}/cgroup"))
+                .ok()
+                .and_then(|cgroups| {
+                    cgroups
+                        .lines()
+                        .find_map(|line| line.strip_prefix("0::").map(|s| s.to_string()))
+                });
+        }
+    }
+    Some(owner)
+}
+
+#[cfg(windows)]
+fn socket_owner(protocol: &str, addr: SocketAddr, _needs_process: bool) -> Option<SocketOwner> {
+    use std::{
+        ffi::OsString,
+        net::{Ipv4Addr, Ipv6Addr},
+        os::windows::ffi::OsStringExt,
+    };
+
+    use winapi::{
+        shared::{
+            iprtrmib::{TCP_TABLE_OWNER_PID_ALL, UDP_TABLE_OWNER_PID},
+            minwindef::{DWORD, FALSE},
+            tcpmib::{MIB_TCP6ROW_OWNER_PID, MIB_TCPROW_OWNER_PID},
+            udpmib::{MIB_UDP6ROW_OWNER_PID, MIB_UDPROW_OWNER_PID},
+            winerror::{ERROR_INSUFFICIENT_BUFFER, NO_ERROR},
+            ws2def::{AF_INET, AF_INET6},
+        },
+        um::{
+            handleapi::CloseHandle,
+            iphlpapi::{GetExtendedTcpTable, GetExtendedUdpTable},
+            processthreadsapi::OpenProcess,
+            winbase::QueryFullProcessImageNameW,
+            winnt::PROCESS_QUERY_LIMITED_INFORMATION,
+        },
+    };
+
+    /// Fetches one of the system's socket tables. Every such table is a row count followed by the rows.
+    fn table<T: Copy>(tcp: bool, af: i32) -> Vec<T> {
+        let mut size: DWORD = 0;
+        let mut buf: Vec<u32> = vec![];
+        loop {
+            let ret = unsafe {
+                if tcp {
+                    GetExtendedTcpTable(
+                        buf.as_mut_ptr().cast(),
+                        &mut size,
+                        FALSE,
+                        af as _,
+                        TCP_TABLE_OWNER_PID_ALL,
+                        0,
+                    )
+                } else {
+                    GetExtendedUdpTable(
+                        buf.as_mut_ptr().cast(),
+                        &mut size,
+                        FALSE,
+                        af as _,
+                        UDP_TABLE_OWNER_PID,
+                        0,
+                    )
+                }
+            };
+            match ret {
+                NO_ERROR if !buf.is_empty() => break,
+                ERROR_INSUFFICIENT_BUFFER | NO_ERROR => buf = vec![0; size as usize / 4 + 1],
+                _ => return vec![],
+            }
+        }
+        let count = buf[0] as usize;
+        unsafe { std::slice::from_raw_parts(buf.as_ptr().add(1).cast::<T>(), count) }.to_vec()
+    }
+
+    let port = |port: DWORD| u16::from_be(port as u16);
+    let tcp = protocol == "tcp";
+    let v4 =
+        |addr: DWORD, p: DWORD| SocketAddr::new(Ipv4Addr::from(addr.to_ne_bytes()).into(), port(p));
+    let v6 = |addr: [u8; 16], p: DWORD| SocketAddr::new(Ipv6Addr::from(addr).into(), port(p));
+    let mut sockets: Vec<(SocketAddr, u32)> = vec![];
+    if tcp {
+        sockets.extend(
+            table::<MIB_TCPROW_OWNER_PID>(true, AF_INET)
+                .into_iter()
+                .map(|row| (v4(row.dwLocalAddr, row.dwLocalPort), row.dwOwningPid)),
+        );
+        sockets.extend(
+            table::<MIB_TCP6ROW_OWNER_PID>(true, AF_INET6)
+                .into_iter()
+                .map(|row| (v6(row.ucLocalAddr, row.dwLocalPort), row.dwOwningPid)),
+        );
+    } else {
+        sockets.extend(
+            table::<MIB_UDPROW_OWNER_PID>(false, AF_INET)
+                .into_iter()
+                .map(|row| (v4(row.dwLocalAddr, row.dwLocalPort), row.dwOwningPid)),
+        );
+        sockets.extend(
+            table::<MIB_UDP6ROW_OWNER_PID>(false, AF_INET6)
+                .into_iter()
+                .map(|row| (v6(row.ucLocalAddr, row.dwLocalPort), row.dwOwningPid)),
+        );
+    }
+    // an exact match beats a socket bound to the unspecified address
+    let (_, pid) = sockets
+        .into_iter()
+        .filter(|(local, _)| binds(*local, addr))
+        .min_by_key(|(local, _)| local.ip().is_unspecified())?;
+
+    let exe = unsafe {
+        let process = OpenProcess(PROCESS_QUERY_LIMITED_INFORMATION, FALSE, pid);
+        if process.is_null() {
+            None
+        } else {
+            let mut buf = [0u16; 1024];
+            let mut len = buf.len() as DWORD;
+            let ok = QueryFullProcessImageNameW(process, 0, buf.as_mut_ptr(), &mut len);
+            CloseHandle(process);
+            (ok != 0).then(|| PathBuf::from(OsString::from_wide(&buf[..len as usize])))
+        }
+    };
+    Some(SocketOwner {
+        exe,
+        ..Default::default()
+    })
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+fn socket_owner(_protocol: &str, _addr: SocketAddr, _needs_process: bool) -> Option<SocketOwner> {
+    None
+}
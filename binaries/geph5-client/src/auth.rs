@@ -55,6 +55,14 @@ pub async fn auth_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
     loop {
         if let Err(err) = refresh_conn_token(ctx, &auth_token).await {
             tracing::warn!(err = debug(err), "failed to refresh conn token");
+            // while the broker is unreachable, a token that we saved earlier is the best we have
+            let epoch = mizaru2::current_epoch();
+            if db_read(ctx, &format!("conn_token_{epoch}"))
+                .await?
+                .is_some()
+            {
+                CONN_TOKEN_READY.store(true, Ordering::SeqCst);
+            }
             smol::Timer::after(Duration::from_secs(10)).await;
         } else {
             let sleep_secs = rand::thread_rng().gen_range(400..800);
@@ -0,0 +1,88 @@
+use std::time::{Duration, Instant};
+
+use anyctx::AnyCtx;
+use anyhow::Context as _;
+use ed25519_dalek::VerifyingKey;
+use futures_util::future::join_all;
+use geph5_broker_protocol::ExitDescriptor;
+use serde::{Deserialize, Serialize};
+use sillad::{dialer::Dialer as _, tcp::TcpDialer};
+use smol_timeout2::TimeoutExt as _;
+
+use crate::{client_inner::client_auth, route::list_exits, vpn::vpn_whitelist, Config};
+
+/// How long each exit gets to connect and authenticate before it counts as unreachable.
+const BENCHMARK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How one exit did in [benchmark_exits].
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ExitBenchmark {
+    /// The exit's public key, in hex.
+    pub pubkey: String,
+    pub exit: ExitDescriptor,
+    /// Seconds taken by the TCP handshake with the exit.
+    pub connect_secs: Option<f64>,
+    /// Seconds taken by the authentication handshake, which is a round trip through the exit's own processing.
+    pub handshake_secs: Option<f64>,
+    /// Lower is better. Unreachable exits have an infinite score.
+    pub score: f64,
+    pub error: Option<String>,
+}
+
+/// Connects and authenticates to the `n` least-loaded exits at the same time, and returns how each did, best first.
+pub async fn benchmark_exits(ctx: &AnyCtx<Config>, n: usize) -> anyhow::Result<Vec<ExitBenchmark>> {
+    let mut exits = list_exits(ctx).await?;
+    exits.sort_by_key(|(_, exit)| (exit.load * 1000.0) as u64);
+    exits.truncate(n);
+    let mut results = join_all(exits.into_iter().map(|(pubkey, exit)| async move {
+        let mut bench = ExitBenchmark {
+            pubkey: hex::encode(pubkey.as_bytes()),
+            exit: exit.clone(),
+            connect_secs: None,
+            handshake_secs: None,
+            score: f64::INFINITY,
+            error: None,
+        };
+        if let Err(err) = measure(ctx, pubkey, &exit, &mut bench)
+            .timeout(BENCHMARK_TIMEOUT)
+            .await
+            .context("timed out")
+            .and_then(|r| r)
+        {
+            tracing::debug!(
+                exit = debug(&exit),
+                err = debug(&err),
+                "exit benchmark failed"
+            );
+            bench.error = Some(format!("{err:#}"));
+        }
+        bench
+    }))
+    .await;
+    results.sort_by(|a, b| a.score.total_cmp(&b.score));
+    Ok(results)
+}
+
+async fn measure(
+    ctx: &AnyCtx<Config>,
+    pubkey: VerifyingKey,
+    exit: &ExitDescriptor,
+    bench: &mut ExitBenchmark,
+) -> anyhow::Result<()> {
+    vpn_whitelist(exit.c2e_listen.ip());
+    let start = Instant::now();
+    let pipe = TcpDialer {
+        dest_addr: exit.c2e_listen,
+    }
+    .dial()
+    .await?;
+    let connect = start.elapsed();
+    bench.connect_secs = Some(connect.as_secs_f64());
+    let start = Instant::now();
+    client_auth(ctx, pipe, pubkey).await?;
+    let handshake = start.elapsed();
+    bench.handshake_secs = Some(handshake.as_secs_f64());
+    // a busy exit answers quickly now, but will be slow once we and everybody else pile onto it
+    bench.score = (connect + handshake).as_secs_f64() * (1.0 + exit.load as f64);
+    Ok(())
+}
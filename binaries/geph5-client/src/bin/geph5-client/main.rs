@@ -0,0 +1,147 @@
+#[cfg(windows)]
+mod service;
+
+use std::{
+    future::Future,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use clap::Parser;
+use geph5_client::{
+    apply_staged_update,
+    logs::{LogEventLayer, LOGS},
+    Client, Config,
+};
+use smol::future::FutureExt as _;
+use tracing_subscriber::{prelude::*, EnvFilter};
+
+/// Run the Geph5 client.
+#[derive(Parser)]
+struct CliArgs {
+    /// path to a YAML-based config file
+    #[cfg_attr(
+        windows,
+        arg(short, long, required_unless_present = "uninstall_service")
+    )]
+    #[cfg_attr(not(windows), arg(short, long, required = true))]
+    config: Option<PathBuf>,
+
+    #[arg(short, long)]
+    /// don't start the client, but instead dump authentication info
+    dry_run: bool,
+
+    #[cfg(windows)]
+    #[arg(long, conflicts_with = "uninstall_service")]
+    /// install the client as a Windows service that runs with the given config at startup, then start it
+    install_service: bool,
+
+    #[cfg(windows)]
+    #[arg(long)]
+    /// stop and remove the Windows service
+    uninstall_service: bool,
+
+    #[cfg(windows)]
+    #[arg(long, hide = true)]
+    /// run as the Windows service, which is how the service manager starts us
+    service: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    smolscale::permanently_single_threaded();
+    tracing_subscriber::registry()
+        .with(
+            tracing_subscriber::fmt::layer()
+                .compact()
+                .with_writer(std::io::stderr),
+        )
+        .with(
+            tracing_subscriber::fmt::layer()
+                .compact()
+                .with_writer(|| &*LOGS),
+        )
+        .with(LogEventLayer)
+        .with(
+            EnvFilter::builder()
+                .with_default_directive("geph5_client=debug".parse()?)
+                .from_env_lossy(),
+        )
+        .init();
+
+    let args = CliArgs::parse();
+    #[cfg(windows)]
+    if args.uninstall_service {
+        return service::uninstall();
+    }
+    let Some(config_path) = args.config else {
+        anyhow::bail!("a config file is required");
+    };
+    #[cfg(windows)]
+    if args.install_service {
+        return service::install(&config_path);
+    }
+    #[cfg(windows)]
+    if args.service {
+        return service::run(config_path);
+    }
+    run_client(&config_path, args.dry_run, smol::future::pending())
+}
+
+/// Runs the client with the config at the given path until it dies, or until `stop` returns.
+fn run_client(
+    config_path: &Path,
+    dry_run: bool,
+    stop: impl Future<Output = anyhow::Result<()>>,
+) -> anyhow::Result<()> {
+    let mut config = read_config(config_path)?;
+    config.dry_run = dry_run;
+    if let Some(updates) = &config.updates {
+        if let Err(err) = apply_staged_update(updates) {
+            tracing::warn!(err = debug(err), "could not apply staged update");
+        }
+    }
+    let modified = config_modified(config_path);
+    let client = Client::start(config);
+    smolscale::block_on(
+        watch_config(&client, config_path, modified)
+            .race(client.wait_until_dead())
+            .race(stop),
+    )?;
+    Ok(())
+}
+
+fn read_config(path: &Path) -> anyhow::Result<Config> {
+    let config: serde_json::Value = serde_yaml::from_slice(&std::fs::read(path)?)?;
+    Ok(serde_json::from_value(config)?)
+}
+
+fn config_modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
+
+/// Applies changes to the config file to the running client.
+async fn watch_config(
+    client: &Client,
+    path: &Path,
+    mut modified: Option<SystemTime>,
+) -> anyhow::Result<()> {
+    loop {
+        smol::Timer::after(Duration::from_secs(2)).await;
+        let new_modified = config_modified(path);
+        if new_modified.is_none() || new_modified == modified {
+            continue;
+        }
+        modified = new_modified;
+        // a file that's halfway through being rewritten won't parse, and we try again once it changes again
+        match read_config(path).and_then(|config| client.reload_config(config)) {
+            Ok(reload) if !reload.needs_restart.is_empty() => tracing::warn!(
+                needs_restart = debug(&reload.needs_restart),
+                "some config changes only take effect after a restart"
+            ),
+            Ok(_) => {}
+            Err(err) => tracing::warn!(err = debug(err), "could not reload config"),
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+
+use windows_service::{
+    define_windows_service,
+    service::{
+        ServiceAccess, ServiceAction, ServiceActionType, ServiceControl, ServiceControlAccept,
+        ServiceErrorControl, ServiceExitCode, ServiceFailureActions, ServiceFailureResetPeriod,
+        ServiceInfo, ServiceStartType, ServiceState, ServiceStatus, ServiceType,
+    },
+    service_control_handler::{self, ServiceControlHandlerResult},
+    service_dispatcher,
+    service_manager::{ServiceManager, ServiceManagerAccess},
+};
+
+use crate::run_client;
+
+const SERVICE_NAME: &str = "geph5-client";
+
+/// The config that the service was started with, since the service manager calls into us with nothing but the service's own arguments.
+static CONFIG_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Installs the client as a service that starts at boot with the given config, restarts whenever it dies, and runs as LocalSystem, so that VPN mode works without an elevated console and survives logout.
+pub fn install(config_path: &Path) -> anyhow::Result<()> {
+    // the service starts in System32, so a relative path would point at the wrong place
+    let config_path = std::fs::canonicalize(config_path)?;
+    let manager = ServiceManager::local_computer(
+        None::<&str>,
+        ServiceManagerAccess::CONNECT | ServiceManagerAccess::CREATE_SERVICE,
+    )?;
+    let info = ServiceInfo {
+        name: SERVICE_NAME.into(),
+        display_name: "Geph5 Client".into(),
+        service_type: ServiceType::OWN_PROCESS,
+        start_type: ServiceStartType::AutoStart,
+        error_control: ServiceErrorControl::Normal,
+        executable_path: std::env::current_exe()?,
+        launch_arguments: vec![
+            "--service".into(),
+            "--config".into(),
+            config_path.into_os_string(),
+        ],
+        dependencies: vec![],
+        account_name: None,
+        account_password: None,
+    };
+    let service =
+        manager.create_service(&info, ServiceAccess::CHANGE_CONFIG | ServiceAccess::START)?;
+    service.set_description("Connects to the Geph network in the background.")?;
+    service.update_failure_actions(ServiceFailureActions {
+        reset_period: ServiceFailureResetPeriod::After(Duration::from_secs(86400)),
+        reboot_msg: None,
+        command: None,
+        actions: Some(vec![
+            ServiceAction {
+                action_type: ServiceActionType::Restart,
+                delay: Duration::from_secs(5),
+            };
+            3
+        ]),
+    })?;
+    // otherwise only crashes count, and not the client stopping with an error
+    service.set_failure_actions_on_non_crash_failures(true)?;
+    service.start::<&OsStr>(&[])?;
+    tracing::info!("installed and started the {SERVICE_NAME} service");
+    Ok(())
+}
+
+/// Stops the service if it's running, then removes it.
+pub fn uninstall() -> anyhow::Result<()> {
+    let manager = ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)?;
+    let service = manager.open_service(
+        SERVICE_NAME,
+        ServiceAccess::QUERY_STATUS | ServiceAccess::STOP | ServiceAccess::DELETE,
+    )?;
+    if service.query_status()?.current_state != ServiceState::Stopped {
+        service.stop()?;
+    }
+    service.delete()?;
+    tracing::info!("uninstalled the {SERVICE_NAME} service");
+    Ok(())
+}
+
+/// Hands the process over to the service manager, which calls back into [service_main] and returns once the service stops.
+pub fn run(config_path: PathBuf) -> anyhow::Result<()> {
+    let _ = CONFIG_PATH.set(config_path);
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)?;
+    Ok(())
+}
+
+define_windows_service!(ffi_service_main, service_main);
+
+fn service_main(_args: Vec<OsString>) {
+    if let Err(err) = run_service() {
+        tracing::error!(err = debug(err), "service stopped");
+    }
+}
+
+fn run_service() -> anyhow::Result<()> {
+    let (send_stop, recv_stop) = smol::channel::bounded(1);
+    let status_handle =
+        service_control_handler::register(SERVICE_NAME, move |event| match event {
+            ServiceControl::Stop | ServiceControl::Shutdown => {
+                let _ = send_stop.try_send(());
+                ServiceControlHandlerResult::NoError
+            }
+            ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
+            _ => ServiceControlHandlerResult::NotImplemented,
+        })?;
+    let set_state = |state: ServiceState, exit_code: u32| {
+        status_handle.set_service_status(ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: state,
+            controls_accepted: if state == ServiceState::Running {
+                ServiceControlAccept::STOP | ServiceControlAccept::SHUTDOWN
+            } else {
+                ServiceControlAccept::empty()
+            },
+            exit_code: ServiceExitCode::Win32(exit_code),
+            checkpoint: 0,
+            wait_hint: Duration::default(),
+            process_id: None,
+        })
+    };
+
+    set_state(ServiceState::Running, 0)?;
+    let config_path = CONFIG_PATH
+        .get()
+        .ok_or_else(|| anyhow::anyhow!("service started without a config"))?;
+    let result = run_client(config_path, false, async {
+        recv_stop.recv().await?;
+        anyhow::Ok(())
+    });
+    // a nonzero exit code counts as a failure, which is what makes the service manager restart us
+    set_state(ServiceState::Stopped, if result.is_ok() { 0 } else { 1 })?;
+    result
+}
@@ -1,11 +1,14 @@
 mod aws_lambda;
+mod dns_txt;
 mod fronted_http;
 mod race;
+mod socks5;
 
 use anyctx::AnyCtx;
 use anyhow::Context;
 
 use aws_lambda::AwsLambdaTransport;
+use dns_txt::DnsTxtTransport;
 use fronted_http::FrontedHttpTransport;
 use geph5_broker_protocol::BrokerClient;
 use itertools::Itertools;
@@ -14,6 +17,7 @@ use race::RaceTransport;
 use reqwest::Client;
 use serde::{Deserialize, Serialize};
 use sillad::tcp::TcpDialer;
+use socks5::{Socks5Dialer, UnproxiableTransport};
 use std::net::SocketAddr;
 
 use crate::client::{Config, CtxField};
@@ -34,28 +38,59 @@ pub enum BrokerSource {
         secret_access_key: String,
     },
     Race(Vec<BrokerSource>),
+    /// Looks up the TXT records of `domain`, each holding a JSON-encoded broker source such as a front, and races them. This lets fronts be rotated without updating the client.
+    DnsTxt {
+        domain: String,
+        /// A DNS-over-HTTPS URL to look up the records with. They are looked up over plain UDP if this is missing.
+        #[serde(default)]
+        doh: Option<String>,
+    },
+    /// Reaches the broker through a SOCKS5 proxy, such as Tor, for networks where every front is blocked. Everything `inner` connects to goes through the proxy, and sources that can't be proxied fail instead.
+    Socks5 {
+        proxy: SocketAddr,
+        inner: Box<BrokerSource>,
+    },
 }
 
 impl BrokerSource {
     /// Converts to a RpcTransport.
     pub fn rpc_transport(&self) -> DynRpcTransport {
-        let client = Client::builder().no_proxy().build().unwrap();
+        self.rpc_transport_via(None)
+    }
+
+    /// Converts to a RpcTransport that makes all its connections through the given SOCKS5 proxy, if any.
+    fn rpc_transport_via(&self, proxy: Option<SocketAddr>) -> DynRpcTransport {
+        let mut client = Client::builder().no_proxy();
+        if let Some(proxy) = proxy {
+            // socks5h, so that names are resolved by the proxy rather than leaking to the local resolver
+            client = client.proxy(reqwest::Proxy::all(format!("socks5h://{proxy}")).unwrap());
+        }
+        let client = client.build().unwrap();
         match self {
             BrokerSource::Direct(s) => DynRpcTransport::new(FrontedHttpTransport {
                 url: s.clone(),
                 host: None,
                 client,
             }),
-            BrokerSource::DirectTcp(dest_addr) => {
-                DynRpcTransport::new(nanorpc_sillad::DialerTransport(TcpDialer {
+            BrokerSource::DirectTcp(dest_addr) => match proxy {
+                Some(proxy) => {
+                    DynRpcTransport::new(nanorpc_sillad::DialerTransport(Socks5Dialer {
+                        proxy,
+                        dest: *dest_addr,
+                    }))
+                }
+                None => DynRpcTransport::new(nanorpc_sillad::DialerTransport(TcpDialer {
                     dest_addr: *dest_addr,
-                }))
-            }
+                })),
+            },
             BrokerSource::Fronted { front, host } => DynRpcTransport::new(FrontedHttpTransport {
                 url: front.clone(),
                 host: Some(host.clone()),
                 client,
             }),
+            BrokerSource::AwsLambda { .. } if proxy.is_some() => {
+                DynRpcTransport::new(UnproxiableTransport("AWS Lambda broker"))
+            }
             BrokerSource::AwsLambda {
                 function_name,
                 region,
@@ -70,10 +105,19 @@ impl BrokerSource {
             BrokerSource::Race(race_between) => {
                 let transports = race_between
                     .iter()
-                    .map(|bs| bs.rpc_transport())
+                    .map(|bs| bs.rpc_transport_via(proxy))
                     .collect_vec();
                 DynRpcTransport::new(RaceTransport::new(transports))
             }
+            BrokerSource::DnsTxt { domain, doh } => DynRpcTransport::new(DnsTxtTransport {
+                domain: domain.clone(),
+                doh: doh.clone(),
+                proxy,
+                client,
+                resolved: Default::default(),
+            }),
+            // the innermost proxy is the one that counts, though nesting proxies makes little sense
+            BrokerSource::Socks5 { proxy, inner } => inner.rpc_transport_via(Some(*proxy)),
         }
     }
 }
@@ -0,0 +1,122 @@
+use std::{
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use nanorpc::{DynRpcTransport, JrpcRequest, JrpcResponse, RpcTransport};
+use reqwest::Client;
+use simple_dns::{rdata::RData, Name, Packet, PacketFlag, Question, CLASS, QCLASS, QTYPE, TYPE};
+use smol::net::UdpSocket;
+use smol_timeout2::TimeoutExt as _;
+
+use super::BrokerSource;
+
+/// The resolver that TXT records are looked up with when DoH isn't used.
+const PLAIN_RESOLVER: &str = "8.8.8.8:53";
+
+/// How long looked-up broker sources are used before looking them up again.
+const LOOKUP_TTL: Duration = Duration::from_secs(3600);
+
+/// Reaches the broker through whatever broker sources the TXT records of a domain currently list.
+pub struct DnsTxtTransport {
+    pub domain: String,
+    pub doh: Option<String>,
+    /// The SOCKS5 proxy that the lookup, and the sources it finds, must go through.
+    pub proxy: Option<SocketAddr>,
+    pub client: Client,
+    pub resolved: smol::lock::Mutex<Option<(DynRpcTransport, Instant)>>,
+}
+
+#[async_trait]
+impl RpcTransport for DnsTxtTransport {
+    type Error = anyhow::Error;
+
+    async fn call_raw(&self, req: JrpcRequest) -> Result<JrpcResponse, Self::Error> {
+        let mut resolved = self.resolved.lock().await;
+        let transport = match resolved.as_ref() {
+            Some((transport, when)) if when.elapsed() < LOOKUP_TTL => transport.clone(),
+            _ => {
+                let sources = self.lookup().await?;
+                tracing::debug!(
+                    domain = self.domain,
+                    sources = sources.len(),
+                    "found broker sources in TXT records"
+                );
+                let transport = BrokerSource::Race(sources).rpc_transport_via(self.proxy);
+                *resolved = Some((transport.clone(), Instant::now()));
+                transport
+            }
+        };
+        drop(resolved);
+        let res = transport.call_raw(req).await;
+        if res.is_err() {
+            // the fronts may have been rotated since we last looked
+            *self.resolved.lock().await = None;
+        }
+        res
+    }
+}
+
+impl DnsTxtTransport {
+    /// Looks up the TXT records, each of which should be a JSON-encoded broker source. Records that aren't are skipped, so that the domain can carry other TXT records too.
+    async fn lookup(&self) -> anyhow::Result<Vec<BrokerSource>> {
+        let mut query = Packet::new_query(rand::random());
+        query.set_flags(PacketFlag::RECURSION_DESIRED);
+        query.questions.push(Question::new(
+            Name::new(&self.domain)?,
+            QTYPE::TYPE(TYPE::TXT),
+            QCLASS::CLASS(CLASS::IN),
+            false,
+        ));
+        let query = query.build_bytes_vec()?;
+        let response = match &self.doh {
+            Some(doh) => self
+                .client
+                .post(doh)
+                .header("content-type", "application/dns-message")
+                .header("accept", "application/dns-message")
+                .body(query)
+                .send()
+                .await
+                .context("cannot send DoH query")?
+                .error_for_status()?
+                .bytes()
+                .await?
+                .to_vec(),
+            None if self.proxy.is_some() => {
+                anyhow::bail!("TXT records can only be looked up through a proxy with DoH")
+            }
+            None => {
+                let socket = UdpSocket::bind("0.0.0.0:0").await?;
+                socket.send_to(&query, PLAIN_RESOLVER).await?;
+                let mut buf = vec![0u8; 65536];
+                let n = socket
+                    .recv(&mut buf)
+                    .timeout(Duration::from_secs(5))
+                    .await
+                    .context("TXT lookup timed out")??;
+                buf.truncate(n);
+                buf
+            }
+        };
+
+        let response = Packet::parse(&response)?;
+        let sources: Vec<BrokerSource> = response
+            .answers
+            .iter()
+            .filter_map(|answer| match &answer.rdata {
+                RData::TXT(txt) => String::try_from(txt.clone()).ok(),
+                _ => None,
+            })
+            .filter_map(|txt| serde_json::from_str(&txt).ok())
+            // a record pointing at more TXT records could make us loop forever
+            .filter(|source| !matches!(source, BrokerSource::DnsTxt { .. }))
+            .collect();
+        if sources.is_empty() {
+            anyhow::bail!("no broker sources in the TXT records of {}", self.domain);
+        }
+        Ok(sources)
+    }
+}
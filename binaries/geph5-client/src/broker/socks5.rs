@@ -0,0 +1,91 @@
+use std::net::SocketAddr;
+
+use async_trait::async_trait;
+use futures_util::{AsyncReadExt as _, AsyncWriteExt as _};
+use nanorpc::{JrpcRequest, JrpcResponse, RpcTransport};
+use sillad::{
+    dialer::Dialer,
+    tcp::{TcpDialer, TcpPipe},
+};
+
+/// Connects to `dest` through a SOCKS5 proxy that needs no authentication, such as Tor's.
+pub struct Socks5Dialer {
+    pub proxy: SocketAddr,
+    pub dest: SocketAddr,
+}
+
+#[async_trait]
+impl Dialer for Socks5Dialer {
+    type P = TcpPipe;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let mut conn = TcpDialer {
+            dest_addr: self.proxy,
+        }
+        .dial()
+        .await?;
+        conn.write_all(&[5, 1, 0]).await?;
+        let mut reply = [0u8; 2];
+        conn.read_exact(&mut reply).await?;
+        if reply != [5, 0] {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "SOCKS5 proxy requires authentication",
+            ));
+        }
+
+        let mut request = vec![5, 1, 0];
+        match self.dest {
+            SocketAddr::V4(addr) => {
+                request.push(1);
+                request.extend_from_slice(&addr.ip().octets());
+            }
+            SocketAddr::V6(addr) => {
+                request.push(4);
+                request.extend_from_slice(&addr.ip().octets());
+            }
+        }
+        request.extend_from_slice(&self.dest.port().to_be_bytes());
+        conn.write_all(&request).await?;
+
+        let mut header = [0u8; 4];
+        conn.read_exact(&mut header).await?;
+        if header[1] != 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                format!("SOCKS5 proxy could not connect, error {}", header[1]),
+            ));
+        }
+        // skip over the bound address, which we have no use for
+        let addr_len = match header[3] {
+            1 => 4,
+            4 => 16,
+            3 => {
+                let mut len = [0u8; 1];
+                conn.read_exact(&mut len).await?;
+                len[0] as usize
+            }
+            _ => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "bad address type in SOCKS5 reply",
+                ))
+            }
+        };
+        let mut rest = vec![0u8; addr_len + 2];
+        conn.read_exact(&mut rest).await?;
+        Ok(conn)
+    }
+}
+
+/// Stands in for a broker source that can't be reached through a proxy, so that it fails rather than silently bypassing the proxy.
+pub struct UnproxiableTransport(pub &'static str);
+
+#[async_trait]
+impl RpcTransport for UnproxiableTransport {
+    type Error = anyhow::Error;
+
+    async fn call_raw(&self, _req: JrpcRequest) -> Result<JrpcResponse, Self::Error> {
+        anyhow::bail!("{} cannot be reached through a proxy", self.0)
+    }
+}
@@ -3,7 +3,8 @@ use anyctx::AnyCtx;
 use anyhow::Context;
 use bytes::Bytes;
 use futures_util::{future::Shared, task::noop_waker, FutureExt, TryFutureExt};
-use geph5_broker_protocol::{Credential, ExitList, UserInfo};
+use geph5_broker_protocol::{Credential, ExitList, NewsItem, UserInfo};
+use ipnet::{Ipv4Net, Ipv6Net};
 use nanorpc::DynRpcTransport;
 use sillad::Pipe;
 use smol::future::FutureExt as _;
@@ -13,16 +14,32 @@ use serde::{Deserialize, Serialize};
 use smolscale::immortal::Immortal;
 
 use crate::{
+    app_rules::AppRule,
     auth::{auth_loop, get_auth_token},
     broker::{broker_client, BrokerSource},
     client_inner::{client_inner, open_conn},
     control_prot::{
         ControlClient, ControlProtocolImpl, ControlService, DummyControlProtocolTransport,
     },
+    control_socket::{control_serve, ControlListen},
     database::db_read_or_wait,
+    dns::{dns_loop, DirectDns},
     http_proxy::run_http_proxy,
-    route::ExitConstraint,
+    leaks::leak_watchdog_loop,
+    live_config::{reload_config, rerun_on_change, ConfigReload},
+    metrics::metrics_loop,
+    pac::pac_loop,
+    port_forward::port_forward_loop,
+    reverse_forward::{reverse_forward_loop, ReverseForward},
+    route::{CustomBridge, ExitConstraint, SshBridge, TransportFamily},
+    route_bundle::RouteBundleSource,
+    rules::RuleList,
     socks5::socks5_loop,
+    stat_history::stat_history_loop,
+    taskpool::task_limit_loop,
+    tproxy::tproxy_loop,
+    updates::{update_loop, UpdateConfig},
+    usage::usage_ledger_loop,
     vpn::{recv_vpn_packet, send_vpn_packet, vpn_loop},
 };
 
@@ -30,22 +47,94 @@ use crate::{
 pub struct Config {
     pub socks5_listen: Option<SocketAddr>,
     pub http_proxy_listen: Option<SocketAddr>,
+    #[serde(default)]
+    pub tproxy_listen: Option<SocketAddr>,
+    #[serde(default)]
+    pub dns_listen: Option<SocketAddr>,
+    #[serde(default)]
+    pub app_rules: Vec<AppRule>,
+    /// Where to serve Prometheus metrics on `/metrics`.
+    #[serde(default)]
+    pub metrics_listen: Option<SocketAddr>,
+    /// Where to serve a proxy auto-config file on `/proxy.pac`, for configuring browsers.
+    #[serde(default)]
+    pub pac_listen: Option<SocketAddr>,
+    /// Ports on the exit to forward back to local services.
+    #[serde(default)]
+    pub reverse_forwards: Vec<ReverseForward>,
 
-    pub control_listen: Option<SocketAddr>,
+    pub control_listen: Option<ControlListen>,
+    /// A secret that control protocol clients must know. The GUI generates one so that other local programs can't stop the VPN or read stats.
+    #[serde(default)]
+    pub control_token: Option<String>,
     pub exit_constraint: ExitConstraint,
+    /// An exit that sessions pass through on the way to the one picked by `exit_constraint`, so that no single exit sees both who we are and where we connect to.
+    #[serde(default)]
+    pub intermediate_exit: Option<ExitConstraint>,
     #[serde(default)]
     pub bridge_mode: BridgeMode,
+    #[serde(default)]
+    pub ssh_bridge: Option<SshBridge>,
+    /// Bridges to use alongside the broker's, or instead of them if `custom_bridges_only` is set. They are also used alone whenever the broker can't be reached.
+    #[serde(default)]
+    pub custom_bridges: Vec<CustomBridge>,
+    #[serde(default)]
+    pub custom_bridges_only: bool,
+    /// Only use bridges of this transport family.
+    #[serde(default)]
+    pub transport_preference: Option<TransportFamily>,
+    #[serde(default)]
+    pub allow_icmp: bool,
+    /// How many sessions to keep open to the exit at once. Streams are spread across them, so more sessions ride out a dead one better, at the cost of more connections and keepalive traffic.
+    #[serde(default)]
+    pub sessions: Option<usize>,
+    /// How long to wait between starting one session and the next, in milliseconds.
+    #[serde(default)]
+    pub session_stagger_ms: u64,
+    /// Don't connect until something first needs the tunnel, rather than connecting right at startup.
+    #[serde(default)]
+    pub lazy_sessions: bool,
+    /// Caps on traffic through the tunnel, in kilobits per second, for metered or shared connections.
+    #[serde(default)]
+    pub upload_limit_kbps: Option<u32>,
+    #[serde(default)]
+    pub download_limit_kbps: Option<u32>,
     pub cache: Option<PathBuf>,
 
     pub broker: Option<BrokerSource>,
     pub broker_keys: Option<BrokerKeys>,
+    /// A signed bundle of exits and bridges to fall back on when the broker can't be reached.
+    #[serde(default)]
+    pub route_bundle: Option<RouteBundleSource>,
 
     #[serde(default)]
     pub vpn: bool,
+    /// In VPN mode, block all traffic outside the tunnel whenever we are not connected.
+    #[serde(default)]
+    pub kill_switch: bool,
+    /// In VPN mode, watch outgoing packets for DNS queries and traffic that go around the tunnel, and report them through the control protocol. Only supported on Linux and on Windows with WinDivert.
+    #[serde(default)]
+    pub leak_watchdog: bool,
     #[serde(default)]
     pub spoof_dns: bool,
+    /// The pools that spoofed DNS hands out fake addresses from. They default to 240.0.0.0/4 and fd47:6570:6835::/48.
+    #[serde(default)]
+    pub fake_dns_v4_range: Option<Ipv4Net>,
+    #[serde(default)]
+    pub fake_dns_v6_range: Option<Ipv6Net>,
+    /// The TTL of spoofed DNS answers, 1 second by default.
+    #[serde(default)]
+    pub fake_dns_ttl_secs: Option<u32>,
+    /// How long a fake address may go unused before it can be handed out for another name, an hour by default.
+    #[serde(default)]
+    pub fake_dns_expiry_secs: Option<u64>,
     #[serde(default)]
     pub passthrough_china: bool,
+    /// How destinations that skip the tunnel, through routing rules, passthrough_china, or app rules, have their names resolved.
+    #[serde(default)]
+    pub direct_dns: DirectDns,
+    #[serde(default)]
+    pub routing_rules: Vec<RuleList>,
     #[serde(default)]
     pub dry_run: bool,
     #[serde(default)]
@@ -54,6 +143,12 @@ pub struct Config {
     #[serde(default)]
     pub sess_metadata: serde_json::Value,
     pub task_limit: Option<u32>,
+    /// The memory, in megabytes, that the client should stay well under. As memory use nears it, the task limit shrinks and the least important connections are shed first, rather than the whole client being killed.
+    #[serde(default)]
+    pub memory_limit_mb: Option<u32>,
+    /// Where to check for new versions of the client. Updates are downloaded in the background and applied the next time the client starts.
+    #[serde(default)]
+    pub updates: Option<UpdateConfig>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -71,6 +166,11 @@ impl Config {
         this.dry_run = true;
         this.socks5_listen = None;
         this.http_proxy_listen = None;
+        this.tproxy_listen = None;
+        this.dns_listen = None;
+        this.metrics_listen = None;
+        this.pac_listen = None;
+        this.reverse_forwards = vec![];
 
         this.control_listen = None;
         this
@@ -116,8 +216,8 @@ impl Client {
     }
 
     /// Wait until there's an error.
-    pub async fn wait_until_dead(self) -> anyhow::Result<()> {
-        self.task.await.map_err(|e| anyhow::anyhow!(e))
+    pub async fn wait_until_dead(&self) -> anyhow::Result<()> {
+        self.task.clone().await.map_err(|e| anyhow::anyhow!(e))
     }
 
     /// Check for an error.
@@ -153,6 +253,30 @@ impl Client {
         Ok(user_info)
     }
 
+    /// Gets the latest news from the broker, in the given language where possible.
+    pub async fn latest_news(&self, lang: &str) -> anyhow::Result<Vec<NewsItem>> {
+        let news = broker_client(&self.ctx)?
+            .get_news(lang.to_string())
+            .await?
+            .map_err(|e| anyhow::anyhow!("broker returned an error: {e}"))?;
+        Ok(news)
+    }
+
+    /// Uploads a debug pack to the broker, returning the ID that support can look it up by.
+    pub async fn upload_debug_pack(&self, pack: String) -> anyhow::Result<String> {
+        let auth_token = get_auth_token(&self.ctx).await?;
+        let id = broker_client(&self.ctx)?
+            .upload_debug_pack(auth_token, pack)
+            .await?
+            .map_err(|e| anyhow::anyhow!("broker returned an error: {e}"))?;
+        Ok(id)
+    }
+
+    /// Applies a changed config without restarting, as far as possible.
+    pub fn reload_config(&self, cfg: Config) -> anyhow::Result<ConfigReload> {
+        reload_config(&self.ctx, cfg)
+    }
+
     /// Force a particular packet to be sent through VPN mode, regardless of whether VPN mode is on.
     pub async fn send_vpn_packet(&self, bts: Bytes) -> anyhow::Result<()> {
         send_vpn_packet(&self.ctx, bts).await;
@@ -201,29 +325,72 @@ async fn client_main(ctx: AnyCtx<Config>) -> anyhow::Result<()> {
         let _client_loop = Immortal::spawn(client_inner(ctx.clone()));
 
         let rpc_serve = async {
-            if let Some(control_listen) = ctx.init().control_listen {
-                nanorpc_sillad::rpc_serve(
-                    sillad::tcp::TcpListener::bind(control_listen).await?,
-                    ControlService(ControlProtocolImpl { ctx: ctx.clone() }),
-                )
-                .await?;
-                anyhow::Ok(())
+            if let Some(control_listen) = &ctx.init().control_listen {
+                control_serve(&ctx, control_listen).await
             } else {
                 smol::future::pending().await
             }
         };
 
-        socks5_loop(&ctx)
+        rerun_on_change(&ctx, |cfg| cfg.socks5_listen, || socks5_loop(&ctx))
             .inspect_err(|e| tracing::error!(err = debug(e), "socks5 loop stopped"))
             .race(vpn_loop.inspect_err(|e| tracing::error!(err = debug(e), "vpn loop stopped")))
             .race(
-                run_http_proxy(&ctx)
+                rerun_on_change(&ctx, |cfg| cfg.http_proxy_listen, || run_http_proxy(&ctx))
                     .inspect_err(|e| tracing::error!(err = debug(e), "http proxy stopped")),
             )
+            .race(
+                rerun_on_change(&ctx, |cfg| cfg.tproxy_listen, || tproxy_loop(&ctx))
+                    .inspect_err(|e| tracing::error!(err = debug(e), "transparent proxy stopped")),
+            )
+            .race(
+                rerun_on_change(&ctx, |cfg| cfg.dns_listen, || dns_loop(&ctx))
+                    .inspect_err(|e| tracing::error!(err = debug(e), "DNS server stopped")),
+            )
+            .race(
+                rerun_on_change(&ctx, |cfg| cfg.metrics_listen, || metrics_loop(&ctx))
+                    .inspect_err(|e| tracing::error!(err = debug(e), "metrics server stopped")),
+            )
+            .race(
+                rerun_on_change(&ctx, |cfg| cfg.pac_listen, || pac_loop(&ctx))
+                    .inspect_err(|e| tracing::error!(err = debug(e), "PAC server stopped")),
+            )
+            .race(
+                port_forward_loop(&ctx)
+                    .inspect_err(|e| tracing::error!(err = debug(e), "port forwards stopped")),
+            )
+            .race(
+                rerun_on_change(
+                    &ctx,
+                    |cfg| cfg.reverse_forwards.clone(),
+                    || reverse_forward_loop(&ctx),
+                )
+                .inspect_err(|e| tracing::error!(err = debug(e), "reverse forwards stopped")),
+            )
+            .race(
+                stat_history_loop(&ctx)
+                    .inspect_err(|e| tracing::error!(err = debug(e), "stat history stopped")),
+            )
+            .race(
+                usage_ledger_loop(&ctx)
+                    .inspect_err(|e| tracing::error!(err = debug(e), "usage ledger stopped")),
+            )
             .race(
                 auth_loop(&ctx)
                     .inspect_err(|e| tracing::error!(err = debug(e), "auth loop stopped")),
             )
+            .race(
+                task_limit_loop(&ctx)
+                    .inspect_err(|e| tracing::error!(err = debug(e), "task limit loop stopped")),
+            )
+            .race(
+                leak_watchdog_loop(&ctx)
+                    .inspect_err(|e| tracing::error!(err = debug(e), "leak watchdog stopped")),
+            )
+            .race(
+                update_loop(&ctx)
+                    .inspect_err(|e| tracing::error!(err = debug(e), "update loop stopped")),
+            )
             .race(rpc_serve)
             .await
     }
@@ -15,6 +15,7 @@ use rand::Rng;
 use sillad::{dialer::Dialer as _, EitherPipe, Pipe};
 use smol::future::FutureExt as _;
 use smol_timeout2::TimeoutExt;
+use smolscale::immortal::Immortal;
 use std::{
     convert::Infallible,
     net::{IpAddr, SocketAddr},
@@ -29,7 +30,7 @@ use std::{
 use stdcode::StdcodeSerializeExt;
 
 use crate::{
-    auth::get_connect_token, china::is_chinese_host, client::CtxField, control_prot::{ConnectedInfo, CURRENT_CONN_INFO}, refresh_cell::RefreshCell, route::{deprioritize_route, get_dialer}, spoof_dns::fake_dns_backtranslate, stats::{stat_incr_num, stat_set_num}, vpn::vpn_whitelist, ConnInfo
+    app_rules::{app_action, AppAction}, auth::get_connect_token, client::CtxField, control_prot::{ConnectedInfo, CURRENT_CONN_INFO}, dest_usage::dest_counter, dns::resolve_direct_dns, journal::{record_event, ConnectionEventKind}, exit_health::{exit_selected, record_failure, record_rtt, wait_retired, wait_switch_needed, RETIRED_SESSION_LINGER}, refresh_cell::RefreshCell, multihop::relay_through, route::{deprioritize_route, get_dialer, get_final_hop}, rules::{rule_action, RuleAction}, spoof_dns::fake_dns_backtranslate, stats::{stat_incr_num, stat_set_num}, throttle::ThrottledPipe, vpn::vpn_whitelist, ConnInfo
 };
 
 use super::Config;
@@ -39,66 +40,142 @@ pub async fn open_conn(
     protocol: &str,
     dest_addr: &str,
 ) -> anyhow::Result<Box<dyn sillad::Pipe>> {
-    let dest_addr = if let Ok(sock_addr) = SocketAddr::from_str(dest_addr) {
-        if let IpAddr::V4(v4) = sock_addr.ip() {
-            if let Some(orig) = fake_dns_backtranslate(ctx, v4) {
-                format!("{orig}:{}", sock_addr.port())
-            } else {
-                dest_addr.to_string()
-            }
-        } else {
-            dest_addr.to_string()
-        }
-    } else {
-        dest_addr.to_string()
-    };
+    let dest_addr = backtranslate(ctx, dest_addr);
 
-    if let Some((dest_host, _)) = dest_addr.rsplit_once(":") {
-        if whitelist_host(ctx, dest_host) {
-            let addrs = smol::net::resolve(&dest_addr).await?;
-            for addr in addrs.iter() {
-                vpn_whitelist(addr.ip());
-            }
-            tracing::debug!(
-                dest_addr = debug(dest_addr),
-                "passing through whitelisted address"
-            );
-            return Ok(sillad::tcp::HappyEyeballsTcpDialer(addrs).dial().await?);
-        }
+    // UDP flows that go direct never get here, see [crate::udp::UdpFlow]
+    if goes_direct(ctx, &dest_addr)? && protocol == "tcp" {
+        tracing::debug!(
+            dest_addr = debug(&dest_addr),
+            "passing through whitelisted address"
+        );
+        let addrs = resolve_direct(ctx, &dest_addr).await?;
+        return Ok(sillad::tcp::HappyEyeballsTcpDialer(addrs).dial().await?);
     }
 
+    open_exit_stream(ctx, format!("{protocol}${dest_addr}")).await
+}
+
+/// Opens a stream to the exit with the given metadata, without looking at the routing rules.
+pub async fn open_exit_stream(
+    ctx: &AnyCtx<Config>,
+    metadata: String,
+) -> anyhow::Result<Box<dyn sillad::Pipe>> {
     let (send, recv) = oneshot::channel();
-    let elem = (format!("{protocol}${dest_addr}"), send);
-    let _ = ctx.get(CONN_REQ_CHAN).0.send(elem).await;
-    let mut conn = recv.await?;
+    if !ctx.get(DEMANDED).swap(true, Ordering::SeqCst) {
+        ctx.get(DEMANDED_EVENT).notify_all();
+    }
+    let _ = ctx
+        .get(CONN_REQ_CHAN)
+        .0
+        .send(ConnReq {
+            metadata: metadata.clone(),
+            send_back: send,
+            attempts: 0,
+            start: Instant::now(),
+        })
+        .await;
+    let mut conn = recv
+        .timeout(OPEN_BUDGET + Duration::from_secs(5))
+        .await
+        .context("timed out waiting for a session to open the stream on")??;
+    // only streams to destinations count towards them, not ones for things like reverse forwarding
+    let counter = match metadata.split_once('$') {
+        Some(("tcp" | "udp", dest_addr)) => Some(dest_counter(ctx, dest_addr)),
+        _ => None,
+    };
     let ctx = ctx.clone();
-    conn.set_on_read(clone!([ctx], move |n| {
-        stat_incr_num(&ctx, "total_rx_bytes", n as _)
+    conn.set_on_read(clone!([ctx, counter], move |n| {
+        stat_incr_num(&ctx, "total_rx_bytes", n as _);
+        if let Some(counter) = &counter {
+            counter.add_rx(n);
+        }
     }));
     conn.set_on_write(clone!([ctx], move |n| {
-        stat_incr_num(&ctx, "total_tx_bytes", n as _)
+        stat_incr_num(&ctx, "total_tx_bytes", n as _);
+        if let Some(counter) = &counter {
+            counter.add_tx(n);
+        }
     }));
     Ok(Box::new(conn))
 }
 
 
-fn whitelist_host(ctx: &AnyCtx<Config>, host: &str) -> bool {
-    if host.is_empty() || host.contains("[") {
+/// Decides whether a connection to `dest_addr`, which must already be backtranslated, should skip the tunnel. Fails if the routing rules block it.
+pub fn goes_direct(ctx: &AnyCtx<Config>, dest_addr: &str) -> anyhow::Result<bool> {
+    let Some((dest_host, _)) = dest_addr.rsplit_once(':') else {
+        return Ok(false);
+    };
+    Ok(match rule_action(ctx, dest_host) {
+        Some(RuleAction::Block) => anyhow::bail!("{dest_host} is blocked by routing rules"),
+        Some(RuleAction::Tunnel) => false,
+        Some(RuleAction::Direct) => true,
+        None => whitelist_host(dest_host),
+    })
+}
+
+/// Like [open_conn], but first checks the per-application split tunneling rules against the local socket `app_addr` that the connection came from, connecting directly if they say so.
+pub async fn open_app_conn(
+    ctx: &AnyCtx<Config>,
+    app_addr: SocketAddr,
+    protocol: &str,
+    dest_addr: &str,
+) -> anyhow::Result<Box<dyn sillad::Pipe>> {
+    if protocol == "tcp" && app_action(ctx, protocol, app_addr).await == AppAction::Direct {
+        tracing::debug!(
+            app_addr = display(app_addr),
+            dest_addr = debug(dest_addr),
+            "application bypasses the tunnel"
+        );
+        let addrs = resolve_direct(ctx, &backtranslate(ctx, dest_addr)).await?;
+        return Ok(sillad::tcp::HappyEyeballsTcpDialer(addrs).dial().await?);
+    }
+    open_conn(ctx, protocol, dest_addr).await
+}
+
+/// Resolves a destination that is to be reached without the tunnel, and lets it past VPN mode.
+pub async fn resolve_direct(
+    ctx: &AnyCtx<Config>,
+    dest_addr: &str,
+) -> anyhow::Result<Vec<SocketAddr>> {
+    let dest_host = dest_addr.rsplit_once(':').map_or(dest_addr, |(host, _)| host);
+    // public names are looked up so that the local network can't poison them, but local names like printer.lan only the local resolver knows
+    let addrs = if psl::suffix(dest_host.as_bytes()).is_some_and(|suf| suf.is_known()) {
+        resolve_direct_dns(ctx, dest_addr).await?
+    } else {
+        smol::net::resolve(dest_addr).await?
+    };
+    for addr in addrs.iter() {
+        vpn_whitelist(addr.ip());
+    }
+    Ok(addrs)
+}
+
+/// Turns a fake-DNS address back into the name it stands for.
+pub fn backtranslate(ctx: &AnyCtx<Config>, dest_addr: &str) -> String {
+    if let Ok(sock_addr) = SocketAddr::from_str(dest_addr) {
+        if let Some(orig) = fake_dns_backtranslate(ctx, sock_addr.ip()) {
+            return format!("{orig}:{}", sock_addr.port());
+        }
+    }
+    dest_addr.to_string()
+}
+
+fn whitelist_host(host: &str) -> bool {
+    if host.is_empty() {
         return false;
     }
+    let host = host.trim_start_matches('[').trim_end_matches(']');
     if let Ok(ip) = IpAddr::from_str(host) {
         match ip {
             IpAddr::V4(v4) => v4.is_private() || v4.is_loopback() || v4.is_link_local(),
-            IpAddr::V6(v6) => v6.is_loopback(),
-        }
-    } else {
-        if ctx.init().passthrough_china {
-            if let Some(domain) = psl::domain_str(host) {
-                if is_chinese_host(domain) {
-                    return true;
-                }
+            // loopback, unique local fc00::/7 and link-local fe80::/10
+            IpAddr::V6(v6) => {
+                v6.is_loopback()
+                    || (v6.segments()[0] & 0xfe00) == 0xfc00
+                    || (v6.segments()[0] & 0xffc0) == 0xfe80
             }
         }
+    } else {
         match psl::suffix(host.as_bytes()) {
             None => false,
             Some(suf) => !suf.is_known(),
@@ -106,17 +183,57 @@ fn whitelist_host(ctx: &AnyCtx<Config>, host: &str) -> bool {
     }
 }
 
-type ChanElem = (String, oneshot::Sender<picomux::Stream>);
+/// A request for a stream, waiting for a session to open it.
+struct ConnReq {
+    metadata: String,
+    send_back: oneshot::Sender<anyhow::Result<picomux::Stream>>,
+    /// How many sessions have already failed to open it.
+    attempts: u32,
+    start: Instant,
+}
 
 static CONN_REQ_CHAN: CtxField<(
-    smol::channel::Sender<ChanElem>,
-    smol::channel::Receiver<ChanElem>,
+    smol::channel::Sender<ConnReq>,
+    smol::channel::Receiver<ConnReq>,
+)> = |_| {
+    let (a, b) = smol::channel::unbounded();
+    (a, b)
+};
+
+/// Requests that a dead session handed back. Sessions take from here first, so that a retried request doesn't go to the back of the line.
+static CONN_RETRY_CHAN: CtxField<(
+    smol::channel::Sender<ConnReq>,
+    smol::channel::Receiver<ConnReq>,
 )> = |_| {
     let (a, b) = smol::channel::unbounded();
     (a, b)
 };
 
-pub static CONCURRENCY: usize = 6;
+/// How many sessions a stream request is tried on before giving up on it.
+const MAX_OPEN_ATTEMPTS: u32 = 4;
+
+/// How long a stream request can keep being retried for, however many attempts it has left.
+const OPEN_BUDGET: Duration = Duration::from_secs(20);
+
+/// How long a failed stream request waits before being retried. It grows with each attempt, starting from the latency of the session that failed, so that requests don't bounce between dying sessions faster than the sessions can be replaced.
+fn open_backoff(latency: Option<Duration>, attempts: u32) -> Duration {
+    let base = latency
+        .unwrap_or(Duration::from_millis(100))
+        .max(Duration::from_millis(50));
+    (base * 2u32.saturating_pow(attempts)).min(Duration::from_secs(5))
+}
+
+/// How many sessions are kept open at once, unless the config says otherwise.
+const DEFAULT_SESSIONS: usize = 6;
+
+/// How many sessions to keep open at once.
+pub fn session_count(ctx: &AnyCtx<Config>) -> usize {
+    ctx.init().sessions.unwrap_or(DEFAULT_SESSIONS).max(1)
+}
+
+/// Set once something first needs the tunnel, for `lazy_sessions`.
+static DEMANDED: CtxField<AtomicBool> = |_| AtomicBool::new(false);
+static DEMANDED_EVENT: CtxField<async_event::Event> = |_| async_event::Event::new();
 
 #[tracing::instrument(skip_all)]
 pub async fn client_inner(ctx: AnyCtx<Config>) -> Infallible {
@@ -132,7 +249,15 @@ pub async fn client_inner(ctx: AnyCtx<Config>) -> Infallible {
                 let mut sleep_secs: f64 = rand::random();
                 smol::Timer::after(Duration::from_secs_f64(sleep_secs)).await;
                 loop {
-                    let result = get_dialer(&ctx).await;
+                    let result = async {
+                        let (pubkey, exit, dialer) = get_dialer(&ctx).await?;
+                        let final_hop = get_final_hop(&ctx, &pubkey).await?;
+                        let (exit_pubkey, final_exit) =
+                            final_hop.clone().unwrap_or_else(|| (pubkey, exit.clone()));
+                        exit_selected(&ctx, exit_pubkey, final_exit);
+                        anyhow::Ok((pubkey, exit, dialer, final_hop))
+                    }
+                    .await;
                     match result {
                         Ok(res) => {
                             tracing::debug!("obtained a fresh, fresh dialer!");
@@ -155,23 +280,51 @@ pub async fn client_inner(ctx: AnyCtx<Config>) -> Infallible {
 
     tracing::debug!(elapsed = debug(start.elapsed()), "raw dialer constructed");
 
+    let _failover = {
+        let dialer = dialer.clone();
+        let ctx = ctx.clone();
+        Immortal::spawn(async move {
+            loop {
+                wait_switch_needed(&ctx).await;
+                dialer.force_refresh();
+            }
+        })
+    };
+
     #[allow(unreachable_code)]
     let instance_thread = |instance| {
         let dialer = dialer.clone();
         let ctx = ctx.clone();
         smolscale::spawn(async move {
+            if ctx.init().lazy_sessions {
+                ctx.get(DEMANDED_EVENT)
+                    .wait_until(|| ctx.get(DEMANDED).load(Ordering::SeqCst).then_some(()))
+                    .await;
+            }
+            // start sessions one after another rather than all at once, so that slow devices and networks aren't swamped
+            smol::Timer::after(Duration::from_millis(ctx.init().session_stagger_ms) * instance as u32).await;
             loop {
                 let once = async {
                     *ctx.get(CURRENT_CONN_INFO).lock() = ConnInfo::Connecting;
-                    let (authed_pipe, exit) = async {
-                        let (pubkey, exit, raw_dialer) = dialer.get();
+                    let (authed_pipe, exit, exit_pubkey) = async {
+                        let (pubkey, exit, raw_dialer, final_hop) = dialer.get();
                         let start = Instant::now();
-                        let raw_pipe = raw_dialer.dial().await.context("could not dial")?;
+                        let raw_pipe = raw_dialer
+                            .dial()
+                            .await
+                            .context("could not dial")
+                            .inspect_err(|err| {
+                                record_event(&ctx, ConnectionEventKind::DialFailed { error: format!("{err:?}") })
+                            })?;
                         tracing::debug!(
                             elapsed = debug(start.elapsed()),
                             protocol = raw_pipe.protocol(),
                             "dial completed"
                         );
+                        record_event(&ctx, ConnectionEventKind::DialSucceeded {
+                            protocol: raw_pipe.protocol().to_string(),
+                            bridge: raw_pipe.remote_addr().unwrap_or_default().to_string(),
+                        });
                         let died = AtomicBool::new(true);
                         let addr: SocketAddr = raw_pipe.remote_addr().unwrap_or("").parse()?;
                         scopeguard::defer!({
@@ -185,17 +338,41 @@ pub async fn client_inner(ctx: AnyCtx<Config>) -> Infallible {
                         });
                         let authed_pipe = client_auth(&ctx, raw_pipe, pubkey)
                             .await
-                            .context("could not client auth")?;
+                            .context("could not client auth")
+                            .inspect_err(|err| {
+                                record_event(&ctx, ConnectionEventKind::AuthFailed { bridge: addr.to_string(), error: format!("{err:?}") })
+                            })?;
                         died.store(false, Ordering::SeqCst);
+                        let (authed_pipe, exit, exit_pubkey): (Box<dyn Pipe>, _, _) = match final_hop {
+                            Some((final_pubkey, final_exit)) => {
+                                let hop_pipe =
+                                    relay_through(&ctx, authed_pipe, final_exit.c2e_listen)
+                                        .await
+                                        .context("could not relay to the final exit")?;
+                                let authed_pipe = client_auth(&ctx, hop_pipe, final_pubkey)
+                                    .await
+                                    .context("could not client auth with the final exit")
+                                    .inspect_err(|err| {
+                                        record_event(&ctx, ConnectionEventKind::AuthFailed { bridge: final_exit.c2e_listen.to_string(), error: format!("{err:?}") })
+                                    })?;
+                                (Box::new(authed_pipe), final_exit, final_pubkey)
+                            }
+                            None => (Box::new(authed_pipe), exit, pubkey),
+                        };
                         tracing::debug!(
                             elapsed = debug(start.elapsed()),
                             "authentication done, starting mux system"
                         );
-                        anyhow::Ok((authed_pipe, exit))
+                        anyhow::Ok((authed_pipe, exit, exit_pubkey))
                     }
                     .timeout(Duration::from_secs(30))
                     .await
-                    .context("overall dial/mux/auth timeout")??;
+                    .context("overall dial/mux/auth timeout")
+                    .inspect_err(|err| {
+                        record_event(&ctx, ConnectionEventKind::DialFailed { error: format!("{err:?}") })
+                    })
+                    .and_then(|r| r)
+                    .inspect_err(|_| stat_incr_num(&ctx, "dial_failures", 1.0))?;
 
                     *ctx.get(CURRENT_CONN_INFO).lock() = ConnInfo::Connected(ConnectedInfo {
                         protocol: authed_pipe.protocol().to_string(),
@@ -206,10 +383,17 @@ pub async fn client_inner(ctx: AnyCtx<Config>) -> Infallible {
                         exit: exit.clone(),
                     });
                     let addr: SocketAddr = authed_pipe.remote_addr().unwrap_or("").parse()?;
-                    proxy_loop(ctx.clone(), authed_pipe, instance)
+                    let protocol = authed_pipe.protocol().to_string();
+                    proxy_loop(ctx.clone(), ThrottledPipe::new(&ctx, authed_pipe), exit_pubkey, instance)
                         .await
                         .context(format!("inner connection to {addr} failed"))
-                        .inspect_err(|_| {
+                        .inspect_err(|err| {
+                            record_event(&ctx, ConnectionEventKind::SessionDied {
+                                protocol: protocol.clone(),
+                                bridge: addr.to_string(),
+                                reason: format!("{err:?}"),
+                            });
+                            record_failure(&ctx, &exit_pubkey);
                             tracing::debug!(
                                 addr = display(addr),
                                 "deprioritizing route due to failed dial"
@@ -226,7 +410,7 @@ pub async fn client_inner(ctx: AnyCtx<Config>) -> Infallible {
         })
     };
 
-    join_all((0..CONCURRENCY).map(instance_thread)).await;
+    join_all((0..session_count(&ctx)).map(instance_thread)).await;
     unreachable!()
 }
 
@@ -234,6 +418,7 @@ pub async fn client_inner(ctx: AnyCtx<Config>) -> Infallible {
 async fn proxy_loop(
     ctx: AnyCtx<Config>,
     authed_pipe: impl Pipe,
+    exit_pubkey: VerifyingKey,
     instance: usize,
 ) -> anyhow::Result<()> {
     let (read, write) = authed_pipe.split();
@@ -247,39 +432,81 @@ async fn proxy_loop(
     // we first register the session metadata
     mux.open(&serde_json::to_vec(&ctx.init().sess_metadata)?).await?;
 
+    stat_incr_num(&ctx, "active_sessions", 1.0);
+    scopeguard::defer!(stat_incr_num(&ctx, "active_sessions", -1.0));
+
     async {
         nursery!({
             loop {
                 let mux = mux.clone();
                 let ctx = ctx.clone();
-                let (remote_addr, send_back) = ctx.get(CONN_REQ_CHAN).1.recv().await?;
+                let mut req = ctx.get(CONN_RETRY_CHAN).1.recv().or(ctx.get(CONN_REQ_CHAN).1.recv()).await?;
                 if let Some(latency) = mux.last_latency() {
                     stat_set_num(&ctx, "ping", latency.as_secs_f64());
+                    record_rtt(&ctx, &exit_pubkey, latency);
                 }
                 spawn!(async move {
-                    tracing::debug!(remote_addr = display(&remote_addr), "opening tunnel");
-                    let stream = mux.open(remote_addr.as_bytes()).await;
+                    tracing::debug!(remote_addr = display(&req.metadata), "opening tunnel");
+                    let stream = mux.open(req.metadata.as_bytes()).await;
                     match stream {
                         Ok(stream) => {
-                            let _ = send_back.send(stream);
+                            let _ = req.send_back.send(Ok(stream));
                         }
                         Err(err) => {
-                            tracing::warn!(remote_addr = display(&remote_addr), err = debug(&err), "session is dead, hot-potatoing the connection request to somebody else");
-                            let _ = ctx.get(CONN_REQ_CHAN).0.try_send((remote_addr, send_back));
+                            record_failure(&ctx, &exit_pubkey);
+                            req.attempts += 1;
+                            if req.attempts >= MAX_OPEN_ATTEMPTS
+                                || req.start.elapsed() >= OPEN_BUDGET
+                            {
+                                tracing::warn!(
+                                    remote_addr = display(&req.metadata),
+                                    attempts = req.attempts,
+                                    err = debug(&err),
+                                    "session is dead, and the connection request is out of retries"
+                                );
+                                let _ = req.send_back.send(Err(anyhow::anyhow!(
+                                    "could not open a stream after {} attempts: {err}",
+                                    req.attempts
+                                )));
+                            } else {
+                                let backoff = open_backoff(mux.last_latency(), req.attempts);
+                                tracing::warn!(
+                                    remote_addr = display(&req.metadata),
+                                    attempts = req.attempts,
+                                    backoff = debug(backoff),
+                                    err = debug(&err),
+                                    "session is dead, hot-potatoing the connection request to somebody else"
+                                );
+                                // waited out on its own, since this session's tasks die along with it
+                                smolscale::spawn(async move {
+                                    smol::Timer::after(backoff).await;
+                                    let _ = ctx.get(CONN_RETRY_CHAN).0.try_send(req);
+                                })
+                                .detach();
+                            }
                         }
                     }
                     anyhow::Ok(())
-                }) 
+                })
                 .detach();
-
             }
         })
     }.or(mux.wait_until_dead())
+    .or(async {
+        wait_retired(&ctx, &exit_pubkey).await;
+        tracing::info!("exit was replaced, leaving this session to its existing connections");
+        let mux = mux.clone();
+        smolscale::spawn(async move {
+            let _ = mux.wait_until_dead().timeout(RETIRED_SESSION_LINGER).await;
+        })
+        .detach();
+        Ok(())
+    })
     .await
 }
 
 #[tracing::instrument(skip_all, fields(pubkey = hex::encode(pubkey.as_bytes())))]
-async fn client_auth(
+pub async fn client_auth(
     ctx: &AnyCtx<Config>,
     mut pipe: impl Pipe,
     pubkey: VerifyingKey,
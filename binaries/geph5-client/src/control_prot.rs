@@ -1,5 +1,6 @@
 use std::{
     convert::Infallible,
+    net::SocketAddr,
     time::{Duration, SystemTime},
 };
 
@@ -12,17 +13,76 @@ use nanorpc::{nanorpc_derive, JrpcRequest, JrpcResponse, RpcService, RpcTranspor
 use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 
-use crate::{client::CtxField, logs::LOGS, stats::stat_get_num, Config};
+use crate::{
+    benchmark::{benchmark_exits, ExitBenchmark},
+    client::CtxField,
+    dest_usage::{top_destinations, DestinationUsage},
+    diagnostics::{run_diagnostics, DiagnosticsReport},
+    exit_health::{recent_exit_switches, ExitSwitch},
+    journal::{connection_history, ConnectionEvent},
+    leaks::{leak_report, LeakReport},
+    live_config::{live_config, reload_config, ConfigReload},
+    logs::{get_logs, LogEvent, LogFilter, LOGS},
+    port_forward::{
+        add_port_forward, list_port_forwards, remove_port_forward, PortForward, PortForwardStatus,
+    },
+    route::TransportFamily,
+    speed_test::{speed_test, SpeedTestResult},
+    stat_history::stat_history,
+    stats::stat_get_num,
+    updates::check_for_update,
+    usage::{usage_history, UsageRecord},
+    Config,
+};
 
 #[nanorpc_derive]
 #[async_trait]
 pub trait ControlProtocol {
     async fn conn_info(&self) -> ConnInfo;
     async fn stat_num(&self, stat: String) -> f64;
+    async fn stat_history(
+        &self,
+        stat: String,
+        start: SystemTime,
+        end: SystemTime,
+    ) -> Result<Vec<(SystemTime, f64)>, String>;
     async fn start_time(&self) -> SystemTime;
     async fn stop(&self);
 
     async fn recent_logs(&self) -> Vec<String>;
+    async fn get_logs(&self, filter: LogFilter, since: u64) -> Vec<LogEvent>;
+
+    async fn benchmark_exits(&self, n: usize) -> Result<Vec<ExitBenchmark>, String>;
+
+    async fn exit_switches(&self) -> Vec<ExitSwitch>;
+
+    async fn run_diagnostics(&self) -> DiagnosticsReport;
+
+    async fn reload_config(&self, cfg: Config) -> Result<ConfigReload, String>;
+    /// Overrides the configured transport preference until the next reload or restart.
+    async fn set_transport_preference(&self, pref: Option<TransportFamily>) -> Result<(), String>;
+
+    async fn add_port_forward(&self, forward: PortForward) -> Result<(), String>;
+    async fn remove_port_forward(&self, listen: SocketAddr) -> Result<(), String>;
+    async fn port_forwards(&self) -> Result<Vec<PortForwardStatus>, String>;
+
+    async fn usage_history(&self, days: u32) -> Result<Vec<UsageRecord>, String>;
+    /// The `n` destinations with the most traffic since the client started, with the rest rolled up into a final "(other)" entry.
+    async fn top_destinations(&self, n: usize) -> Vec<DestinationUsage>;
+    /// The latest `limit` events in the connection journal since `since`, oldest first, such as dials, authentication errors, and sessions dying.
+    async fn connection_history(
+        &self,
+        since: SystemTime,
+        limit: usize,
+    ) -> Result<Vec<ConnectionEvent>, String>;
+    /// DNS queries and traffic that the leak watchdog recently saw going around the tunnel in VPN mode.
+    async fn leak_report(&self) -> LeakReport;
+
+    /// Checks for an update right away, downloading it if there is one. Returns the version that will be applied on the next start, if any.
+    async fn check_for_update(&self) -> Result<Option<String>, String>;
+
+    /// Downloads and uploads a few dozen megabytes through the current tunnel, reporting the speeds and the latency.
+    async fn speed_test(&self) -> Result<SpeedTestResult, String>;
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -56,6 +116,17 @@ impl ControlProtocol for ControlProtocolImpl {
         stat_get_num(&self.ctx, &stat)
     }
 
+    async fn stat_history(
+        &self,
+        stat: String,
+        start: SystemTime,
+        end: SystemTime,
+    ) -> Result<Vec<(SystemTime, f64)>, String> {
+        stat_history(&self.ctx, &stat, start, end)
+            .await
+            .map_err(|e| format!("{e:?}"))
+    }
+
     async fn start_time(&self) -> SystemTime {
         static START_TIME: CtxField<SystemTime> = |_| SystemTime::now();
         *self.ctx.get(START_TIME)
@@ -76,6 +147,93 @@ impl ControlProtocol for ControlProtocolImpl {
             .map(|s| s.to_string())
             .collect_vec()
     }
+
+    async fn get_logs(&self, filter: LogFilter, since: u64) -> Vec<LogEvent> {
+        get_logs(&filter, since)
+    }
+
+    async fn benchmark_exits(&self, n: usize) -> Result<Vec<ExitBenchmark>, String> {
+        benchmark_exits(&self.ctx, n)
+            .await
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    async fn exit_switches(&self) -> Vec<ExitSwitch> {
+        recent_exit_switches(&self.ctx)
+    }
+
+    async fn run_diagnostics(&self) -> DiagnosticsReport {
+        run_diagnostics(&self.ctx).await
+    }
+
+    async fn reload_config(&self, cfg: Config) -> Result<ConfigReload, String> {
+        reload_config(&self.ctx, cfg).map_err(|e| format!("{e:?}"))
+    }
+
+    async fn set_transport_preference(&self, pref: Option<TransportFamily>) -> Result<(), String> {
+        let mut cfg = (*live_config(&self.ctx)).clone();
+        cfg.transport_preference = pref;
+        reload_config(&self.ctx, cfg).map_err(|e| format!("{e:?}"))?;
+        Ok(())
+    }
+
+    async fn add_port_forward(&self, forward: PortForward) -> Result<(), String> {
+        add_port_forward(&self.ctx, forward)
+            .await
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    async fn remove_port_forward(&self, listen: SocketAddr) -> Result<(), String> {
+        remove_port_forward(&self.ctx, listen)
+            .await
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    async fn port_forwards(&self) -> Result<Vec<PortForwardStatus>, String> {
+        list_port_forwards(&self.ctx)
+            .await
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    async fn usage_history(&self, days: u32) -> Result<Vec<UsageRecord>, String> {
+        usage_history(&self.ctx, days)
+            .await
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    async fn top_destinations(&self, n: usize) -> Vec<DestinationUsage> {
+        top_destinations(&self.ctx, n)
+    }
+
+    async fn connection_history(
+        &self,
+        since: SystemTime,
+        limit: usize,
+    ) -> Result<Vec<ConnectionEvent>, String> {
+        connection_history(&self.ctx, since, limit)
+            .await
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    async fn leak_report(&self) -> LeakReport {
+        leak_report(&self.ctx)
+    }
+
+    async fn check_for_update(&self) -> Result<Option<String>, String> {
+        let updates = self
+            .ctx
+            .init()
+            .updates
+            .as_ref()
+            .ok_or_else(|| "updates are not configured".to_string())?;
+        check_for_update(updates)
+            .await
+            .map_err(|e| format!("{e:?}"))
+    }
+
+    async fn speed_test(&self) -> Result<SpeedTestResult, String> {
+        speed_test(&self.ctx).await.map_err(|e| format!("{e:?}"))
+    }
 }
 
 pub struct DummyControlProtocolTransport(pub ControlService<ControlProtocolImpl>);
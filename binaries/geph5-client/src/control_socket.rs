@@ -0,0 +1,379 @@
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyctx::AnyCtx;
+use async_trait::async_trait;
+use futures_util::{AsyncRead, AsyncWrite};
+use nanorpc_sillad::{rpc_serve, DialerTransport};
+use pin_project::pin_project;
+use serde::{Deserialize, Serialize};
+use sillad::{
+    dialer::Dialer,
+    listener::Listener,
+    tcp::{TcpDialer, TcpListener},
+    Pipe,
+};
+use sillad_sosistab3::{dialer::SosistabDialer, listener::SosistabListener, Cookie};
+
+use crate::{
+    control_prot::{ControlClient, ControlProtocolImpl, ControlService},
+    Config,
+};
+
+/// Where the control protocol is served. Unlike a TCP port, which every local user can reach, a Unix socket path (or a named pipe like `\\.\pipe\geph5` on Windows) is protected by filesystem permissions.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum ControlListen {
+    Tcp(SocketAddr),
+    Path(PathBuf),
+}
+
+impl ControlListen {
+    /// Creates a client for the control protocol served here. The token must match the daemon's `control_token`, if it has one.
+    pub fn client(&self, token: Option<&str>) -> ControlClient {
+        ControlClient::from(DialerTransport(ControlDialer {
+            listen: self.clone(),
+            cookie: token.map(Cookie::new),
+        }))
+    }
+}
+
+pub async fn control_serve(ctx: &AnyCtx<Config>, listen: &ControlListen) -> anyhow::Result<()> {
+    let service = ControlService(ControlProtocolImpl { ctx: ctx.clone() });
+    let token = ctx.init().control_token.as_deref();
+    if token.is_none() && matches!(listen, ControlListen::Tcp(_)) {
+        tracing::warn!("control protocol served over TCP without a control_token, so any local program can use it");
+    }
+    match listen {
+        ControlListen::Tcp(addr) => {
+            serve_with_token(TcpListener::bind(*addr).await?, service, token).await?
+        }
+        ControlListen::Path(path) => {
+            serve_with_token(PathListener::bind(path).await?, service, token).await?
+        }
+    }
+    Ok(())
+}
+
+/// Serves the control protocol, requiring a sosistab3 handshake keyed by the token if there is one. Connections that don't know the token never get to send a request.
+async fn serve_with_token<L: Listener>(
+    listener: L,
+    service: ControlService<ControlProtocolImpl>,
+    token: Option<&str>,
+) -> std::io::Result<()> {
+    match token {
+        Some(token) => {
+            rpc_serve(SosistabListener::new(listener, Cookie::new(token)), service).await
+        }
+        None => rpc_serve(listener, service).await,
+    }
+}
+
+struct ControlDialer {
+    listen: ControlListen,
+    cookie: Option<Cookie>,
+}
+
+#[async_trait]
+impl Dialer for ControlDialer {
+    type P = Box<dyn Pipe>;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let raw = RawControlDialer(self.listen.clone());
+        match self.cookie {
+            Some(cookie) => Ok(Box::new(
+                SosistabDialer { inner: raw, cookie }.dial().await?,
+            )),
+            None => raw.dial().await,
+        }
+    }
+}
+
+struct RawControlDialer(ControlListen);
+
+#[async_trait]
+impl Dialer for RawControlDialer {
+    type P = Box<dyn Pipe>;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        match &self.0 {
+            ControlListen::Tcp(dest_addr) => Ok(Box::new(
+                TcpDialer {
+                    dest_addr: *dest_addr,
+                }
+                .dial()
+                .await?,
+            )),
+            ControlListen::Path(path) => dial_path(path).await,
+        }
+    }
+}
+
+#[cfg(unix)]
+struct PathListener(smol::net::unix::UnixListener);
+
+#[cfg(unix)]
+impl PathListener {
+    async fn bind(path: &Path) -> std::io::Result<Self> {
+        use std::os::unix::fs::{FileTypeExt as _, PermissionsExt as _};
+        // a socket left behind by a client that didn't shut down cleanly would make binding fail
+        if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            std::fs::remove_file(path)?;
+        }
+        let listener = smol::net::unix::UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))?;
+        Ok(Self(listener))
+    }
+}
+
+#[cfg(unix)]
+#[async_trait]
+impl Listener for PathListener {
+    type P = LocalPipe<smol::net::unix::UnixStream>;
+
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        let (stream, _) = self.0.accept().await?;
+        Ok(LocalPipe::new(stream, "unix"))
+    }
+}
+
+#[cfg(unix)]
+async fn dial_path(path: &Path) -> std::io::Result<Box<dyn Pipe>> {
+    let stream = smol::net::unix::UnixStream::connect(path).await?;
+    Ok(Box::new(LocalPipe::new(stream, "unix")))
+}
+
+#[cfg(windows)]
+struct PathListener {
+    name: PathBuf,
+    security: PipeSecurity,
+    // the instance that the next client connects to
+    next: tokio::net::windows::named_pipe::NamedPipeServer,
+}
+
+#[cfg(windows)]
+impl PathListener {
+    async fn bind(path: &Path) -> std::io::Result<Self> {
+        if !path.to_string_lossy().starts_with(r"\\.\pipe\") {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                r"control_listen must be a named pipe like \\.\pipe\geph5 on Windows",
+            ));
+        }
+        let security = PipeSecurity::current_user_only()?;
+        // the first instance refuses to be created if somebody else already owns the name
+        let next = async_compat::Compat::new(async { security.create(path, true) }).await?;
+        Ok(Self {
+            name: path.to_owned(),
+            security,
+            next,
+        })
+    }
+}
+
+#[cfg(windows)]
+#[async_trait]
+impl Listener for PathListener {
+    type P = LocalPipe<async_compat::Compat<tokio::net::windows::named_pipe::NamedPipeServer>>;
+
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        use async_compat::CompatExt as _;
+        async_compat::Compat::new(async {
+            self.next.connect().await?;
+            // the next instance exists before this one is handed out, so that clients never find the name missing
+            let next = self.security.create(&self.name, false)?;
+            let connected = std::mem::replace(&mut self.next, next);
+            Ok(LocalPipe::new(connected.compat(), "named-pipe"))
+        })
+        .await
+    }
+}
+
+/// A security descriptor for the pipe that only lets the current user in. Without one, the pipe gets the default descriptor, which lets other users on the machine read from it.
+#[cfg(windows)]
+struct PipeSecurity(winapi::um::winnt::PSECURITY_DESCRIPTOR);
+
+// the descriptor is never changed after it is made
+#[cfg(windows)]
+unsafe impl Send for PipeSecurity {}
+
+#[cfg(windows)]
+unsafe impl Sync for PipeSecurity {}
+
+#[cfg(windows)]
+impl PipeSecurity {
+    fn current_user_only() -> std::io::Result<Self> {
+        use winapi::{
+            shared::sddl::{
+                ConvertSidToStringSidW, ConvertStringSecurityDescriptorToSecurityDescriptorW,
+                SDDL_REVISION_1,
+            },
+            um::{
+                handleapi::CloseHandle,
+                processthreadsapi::{GetCurrentProcess, OpenProcessToken},
+                securitybaseapi::GetTokenInformation,
+                winbase::LocalFree,
+                winnt::{TokenUser, TOKEN_QUERY, TOKEN_USER},
+            },
+        };
+
+        unsafe {
+            let mut token = std::ptr::null_mut();
+            if OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token) == 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let mut len = 0;
+            GetTokenInformation(token, TokenUser, std::ptr::null_mut(), 0, &mut len);
+            // u64s, so that the TOKEN_USER inside is properly aligned
+            let mut buf = vec![0u64; (len as usize).div_ceil(8)];
+            let ok = GetTokenInformation(token, TokenUser, buf.as_mut_ptr().cast(), len, &mut len);
+            CloseHandle(token);
+            if ok == 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let user = &*(buf.as_ptr() as *const TOKEN_USER);
+
+            let mut sid_ptr = std::ptr::null_mut();
+            if ConvertSidToStringSidW(user.User.Sid, &mut sid_ptr) == 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            let sid_len = (0..).take_while(|&i| *sid_ptr.add(i) != 0).count();
+            let sid = String::from_utf16_lossy(std::slice::from_raw_parts(sid_ptr, sid_len));
+            LocalFree(sid_ptr.cast());
+
+            // a protected DACL with a single entry giving that user full access
+            let sddl: Vec<u16> = format!("D:P(A;;GA;;;{sid})")
+                .encode_utf16()
+                .chain(Some(0))
+                .collect();
+            let mut descriptor = std::ptr::null_mut();
+            if ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                sddl.as_ptr(),
+                SDDL_REVISION_1 as _,
+                &mut descriptor,
+                std::ptr::null_mut(),
+            ) == 0
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(Self(descriptor))
+        }
+    }
+
+    /// Creates a pipe instance with this descriptor. It must be called within a Tokio runtime.
+    fn create(
+        &self,
+        name: &Path,
+        first: bool,
+    ) -> std::io::Result<tokio::net::windows::named_pipe::NamedPipeServer> {
+        use winapi::um::minwinbase::SECURITY_ATTRIBUTES;
+
+        let mut attrs = SECURITY_ATTRIBUTES {
+            nLength: std::mem::size_of::<SECURITY_ATTRIBUTES>() as _,
+            lpSecurityDescriptor: self.0,
+            bInheritHandle: 0,
+        };
+        let mut options = tokio::net::windows::named_pipe::ServerOptions::new();
+        options
+            .first_pipe_instance(first)
+            .reject_remote_clients(true);
+        unsafe {
+            options.create_with_security_attributes_raw(
+                name,
+                (&mut attrs as *mut SECURITY_ATTRIBUTES).cast(),
+            )
+        }
+    }
+}
+
+#[cfg(windows)]
+impl Drop for PipeSecurity {
+    fn drop(&mut self) {
+        unsafe {
+            winapi::um::winbase::LocalFree(self.0);
+        }
+    }
+}
+
+#[cfg(windows)]
+async fn dial_path(path: &Path) -> std::io::Result<Box<dyn Pipe>> {
+    use async_compat::CompatExt as _;
+    use winapi::shared::winerror::ERROR_PIPE_BUSY;
+    async_compat::Compat::new(async {
+        // every instance can be taken for a moment, until the server has made the next one
+        for _ in 0..50 {
+            match tokio::net::windows::named_pipe::ClientOptions::new().open(path) {
+                Ok(client) => {
+                    return Ok(
+                        Box::new(LocalPipe::new(client.compat(), "named-pipe")) as Box<dyn Pipe>
+                    )
+                }
+                Err(err) if err.raw_os_error() == Some(ERROR_PIPE_BUSY as i32) => {
+                    smol::Timer::after(std::time::Duration::from_millis(20)).await;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Err(std::io::Error::new(
+            std::io::ErrorKind::TimedOut,
+            "named pipe stayed busy",
+        ))
+    })
+    .await
+}
+
+/// A connection over a Unix socket or named pipe.
+#[pin_project]
+struct LocalPipe<T> {
+    #[pin]
+    inner: T,
+    protocol: &'static str,
+}
+
+impl<T> LocalPipe<T> {
+    fn new(inner: T, protocol: &'static str) -> Self {
+        Self { inner, protocol }
+    }
+}
+
+impl<T: AsyncRead> AsyncRead for LocalPipe<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.project().inner.poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite> AsyncWrite for LocalPipe<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.project().inner.poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Send + Unpin + 'static> Pipe for LocalPipe<T> {
+    fn protocol(&self) -> &str {
+        self.protocol
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        None
+    }
+}
@@ -5,7 +5,11 @@ use sqlx::{sqlite::SqliteConnectOptions, SqlitePool};
 use std::str::FromStr;
 use stdcode::StdcodeSerializeExt;
 
-use crate::client::{Config, CtxField};
+use crate::{
+    client::{Config, CtxField},
+    stat_history::RESOLUTIONS,
+    usage::UsageRecord,
+};
 
 static DATABASE: CtxField<SqlitePool> = |ctx| {
     // TODO this somehow does not make all the connections share the same db?
@@ -47,6 +51,43 @@ static DATABASE: CtxField<SqlitePool> = |ctx| {
         .await
         .unwrap();
 
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS usage (
+                day TEXT NOT NULL,
+                exit TEXT NOT NULL,
+                rx_bytes INTEGER NOT NULL,
+                tx_bytes INTEGER NOT NULL,
+                PRIMARY KEY (day, exit)
+            );",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS stat_history (
+                stat TEXT NOT NULL,
+                resolution INTEGER NOT NULL,
+                time INTEGER NOT NULL,
+                value REAL NOT NULL,
+                PRIMARY KEY (stat, resolution, time)
+            );",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS connection_journal (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                time INTEGER NOT NULL,
+                event TEXT NOT NULL
+            );",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
         pool
     })
 };
@@ -91,3 +132,136 @@ pub async fn db_remove(ctx: &AnyCtx<Config>, key: &str) -> Result<(), sqlx::Erro
     ctx.get(EVENT).notify(usize::MAX);
     Ok(())
 }
+
+/// Adds traffic to the usage ledger's total for the given day and exit.
+pub async fn db_add_usage(
+    ctx: &AnyCtx<Config>,
+    day: &str,
+    exit: &str,
+    rx_bytes: u64,
+    tx_bytes: u64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO usage (day, exit, rx_bytes, tx_bytes) VALUES (?, ?, ?, ?) ON CONFLICT(day, exit) DO UPDATE SET rx_bytes = rx_bytes + excluded.rx_bytes, tx_bytes = tx_bytes + excluded.tx_bytes")
+        .bind(day)
+        .bind(exit)
+        .bind(rx_bytes as i64)
+        .bind(tx_bytes as i64)
+        .execute(ctx.get(DATABASE))
+        .await?;
+    Ok(())
+}
+
+/// Reads the usage ledger for every day since `since_day`, inclusive, oldest first.
+pub async fn db_usage_since(
+    ctx: &AnyCtx<Config>,
+    since_day: &str,
+) -> Result<Vec<UsageRecord>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT day, exit, rx_bytes, tx_bytes FROM usage WHERE day >= ? ORDER BY day, exit",
+    )
+    .bind(since_day)
+    .fetch_all(ctx.get(DATABASE))
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| UsageRecord {
+            day: row.get("day"),
+            exit: row.get("exit"),
+            rx_bytes: row.get::<i64, _>("rx_bytes") as u64,
+            tx_bytes: row.get::<i64, _>("tx_bytes") as u64,
+        })
+        .collect())
+}
+
+/// Records stat samples, given as (stat, UNIX time, value), at every resolution in [RESOLUTIONS]. Each coarser bucket keeps the latest sample that falls into it. Samples that have aged out of their resolution's retention are deleted.
+pub async fn db_write_stat_samples(
+    ctx: &AnyCtx<Config>,
+    samples: &[(&str, u64, f64)],
+) -> Result<(), sqlx::Error> {
+    let mut txn = ctx.get(DATABASE).begin().await?;
+    for (stat, time, value) in samples {
+        for (resolution, _) in RESOLUTIONS {
+            sqlx::query("INSERT INTO stat_history (stat, resolution, time, value) VALUES (?, ?, ?, ?) ON CONFLICT(stat, resolution, time) DO UPDATE SET value = excluded.value")
+                .bind(stat)
+                .bind(*resolution as i64)
+                .bind((time - time % resolution) as i64)
+                .bind(value)
+                .execute(&mut *txn)
+                .await?;
+        }
+    }
+    if let Some(now) = samples.iter().map(|(_, time, _)| *time).max() {
+        for (resolution, retention) in RESOLUTIONS {
+            sqlx::query("DELETE FROM stat_history WHERE resolution = ? AND time < ?")
+                .bind(*resolution as i64)
+                .bind(now.saturating_sub(*retention) as i64)
+                .execute(&mut *txn)
+                .await?;
+        }
+    }
+    txn.commit().await?;
+    Ok(())
+}
+
+/// Reads the samples of a stat at the given resolution between two UNIX times, inclusive, oldest first.
+pub async fn db_read_stat_history(
+    ctx: &AnyCtx<Config>,
+    stat: &str,
+    resolution: u64,
+    start: u64,
+    end: u64,
+) -> Result<Vec<(u64, f64)>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT time, value FROM stat_history WHERE stat = ? AND resolution = ? AND time >= ? AND time <= ? ORDER BY time",
+    )
+    .bind(stat)
+    .bind(resolution as i64)
+    .bind(start as i64)
+    .bind(end as i64)
+    .fetch_all(ctx.get(DATABASE))
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.get::<i64, _>("time") as u64, row.get("value")))
+        .collect())
+}
+
+/// Appends a JSON-encoded event at the given UNIX time to the connection journal, deleting events from before `expire_before`.
+pub async fn db_add_connection_event(
+    ctx: &AnyCtx<Config>,
+    time: u64,
+    event: &str,
+    expire_before: u64,
+) -> Result<(), sqlx::Error> {
+    let mut txn = ctx.get(DATABASE).begin().await?;
+    sqlx::query("INSERT INTO connection_journal (time, event) VALUES (?, ?)")
+        .bind(time as i64)
+        .bind(event)
+        .execute(&mut *txn)
+        .await?;
+    sqlx::query("DELETE FROM connection_journal WHERE time < ?")
+        .bind(expire_before as i64)
+        .execute(&mut *txn)
+        .await?;
+    txn.commit().await?;
+    Ok(())
+}
+
+/// Reads the latest `limit` events in the connection journal at or after the given UNIX time, as (time, JSON-encoded event), oldest first.
+pub async fn db_connection_events_since(
+    ctx: &AnyCtx<Config>,
+    since: u64,
+    limit: i64,
+) -> Result<Vec<(u64, String)>, sqlx::Error> {
+    let rows = sqlx::query(
+        "SELECT time, event FROM (SELECT id, time, event FROM connection_journal WHERE time >= ? ORDER BY id DESC LIMIT ?) ORDER BY id",
+    )
+    .bind(since as i64)
+    .bind(limit)
+    .fetch_all(ctx.get(DATABASE))
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.get::<i64, _>("time") as u64, row.get("event")))
+        .collect())
+}
@@ -0,0 +1,106 @@
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use anyctx::AnyCtx;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{client::CtxField, Config};
+
+/// How many destinations are counted separately. Traffic to any others is counted under [OTHER], so that the table can't grow without bound.
+const MAX_DESTINATIONS: usize = 1000;
+
+const OTHER: &str = "(other)";
+
+/// Traffic through the tunnel to one destination since the client started.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DestinationUsage {
+    /// The site, as its registrable domain like example.com, or an IP address.
+    pub destination: String,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+#[derive(Default)]
+pub struct DestCounter {
+    rx_bytes: AtomicU64,
+    tx_bytes: AtomicU64,
+}
+
+impl DestCounter {
+    pub fn add_rx(&self, n: usize) {
+        self.rx_bytes.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn add_tx(&self, n: usize) {
+        self.tx_bytes.fetch_add(n as u64, Ordering::Relaxed);
+    }
+}
+
+static DEST_COUNTERS: CtxField<DashMap<String, Arc<DestCounter>>> = |_| DashMap::new();
+
+/// The counter that traffic to `dest_addr`, given as host:port, adds to. Subdomains are rolled up into their site, so that a site's many CDN hostnames show up as one.
+pub fn dest_counter(ctx: &AnyCtx<Config>, dest_addr: &str) -> Arc<DestCounter> {
+    let host = dest_addr
+        .rsplit_once(':')
+        .map_or(dest_addr, |(host, _)| host)
+        .trim_start_matches('[')
+        .trim_end_matches(']');
+    let destination = if host.parse::<IpAddr>().is_ok() {
+        host.to_string()
+    } else {
+        psl::domain_str(host).unwrap_or(host).to_ascii_lowercase()
+    };
+    let counters = ctx.get(DEST_COUNTERS);
+    if let Some(counter) = counters.get(&destination) {
+        return counter.clone();
+    }
+    let destination = if counters.len() >= MAX_DESTINATIONS {
+        OTHER.to_string()
+    } else {
+        destination
+    };
+    counters.entry(destination).or_default().clone()
+}
+
+/// The `n` destinations with the most traffic, most first, with the traffic to all the rest rolled up into one entry at the end.
+pub fn top_destinations(ctx: &AnyCtx<Config>, n: usize) -> Vec<DestinationUsage> {
+    let mut all: Vec<DestinationUsage> = ctx
+        .get(DEST_COUNTERS)
+        .iter()
+        .map(|entry| DestinationUsage {
+            destination: entry.key().clone(),
+            rx_bytes: entry.rx_bytes.load(Ordering::Relaxed),
+            tx_bytes: entry.tx_bytes.load(Ordering::Relaxed),
+        })
+        .collect();
+    // what was already rolled up goes with the rest, rather than competing for the top
+    let mut other = DestinationUsage {
+        destination: OTHER.to_string(),
+        rx_bytes: 0,
+        tx_bytes: 0,
+    };
+    all.retain(|usage| {
+        if usage.destination == OTHER {
+            other.rx_bytes += usage.rx_bytes;
+            other.tx_bytes += usage.tx_bytes;
+            false
+        } else {
+            true
+        }
+    });
+    all.sort_unstable_by_key(|usage| std::cmp::Reverse(usage.rx_bytes + usage.tx_bytes));
+    for usage in all.split_off(n.min(all.len())) {
+        other.rx_bytes += usage.rx_bytes;
+        other.tx_bytes += usage.tx_bytes;
+    }
+    if other.rx_bytes + other.tx_bytes > 0 {
+        all.push(other);
+    }
+    all
+}
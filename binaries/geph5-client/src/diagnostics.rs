@@ -0,0 +1,259 @@
+use std::{
+    future::Future,
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use anyctx::AnyCtx;
+use anyhow::Context as _;
+use futures_util::future::join_all;
+use geph5_broker_protocol::BrokerClient;
+use serde::{Deserialize, Serialize};
+use sillad::{dialer::Dialer as _, Pipe as _};
+use smol::net::{TcpListener, UdpSocket};
+use smol_timeout2::TimeoutExt as _;
+
+use crate::{
+    broker::BrokerSource,
+    client_inner::client_auth,
+    control_socket::ControlListen,
+    dns::resolve_through_tunnel,
+    live_config::live_config,
+    route::{bridge_dialers, get_dialer},
+    Config,
+};
+
+/// How long each check may take before it counts as failed.
+const CHECK_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// A name that public resolvers will always have an answer for.
+const TEST_DOMAIN: &str = "www.wikipedia.org:443";
+
+/// The outcome of one diagnostic check.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DiagnosticCheck {
+    /// What was checked.
+    pub name: String,
+    pub ok: bool,
+    /// What was found, or what went wrong.
+    pub detail: String,
+    pub secs: f64,
+}
+
+/// Everything [run_diagnostics] found, grouped by what it checked.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DiagnosticsReport {
+    /// Each configured way of reaching the broker.
+    pub brokers: Vec<DiagnosticCheck>,
+    /// Each bridge to the exit we would connect to.
+    pub bridges: Vec<DiagnosticCheck>,
+    /// Connecting and authenticating to that exit.
+    pub exit_auth: DiagnosticCheck,
+    /// Name resolution, both locally and through the tunnel, and whether DNS spoofing is on.
+    pub dns: Vec<DiagnosticCheck>,
+    /// Whether each configured listen address is taken, as it should be while the client runs.
+    pub listen_ports: Vec<DiagnosticCheck>,
+}
+
+/// Checks everything that commonly goes wrong between the client and a working connection, all at once.
+pub async fn run_diagnostics(ctx: &AnyCtx<Config>) -> DiagnosticsReport {
+    let (brokers, (exit_auth, bridges), dns, listen_ports) = futures_util::join!(
+        check_brokers(ctx),
+        check_exit(ctx),
+        check_dns(ctx),
+        check_listen_ports(ctx)
+    );
+    DiagnosticsReport {
+        brokers,
+        bridges,
+        exit_auth,
+        dns,
+        listen_ports,
+    }
+}
+
+async fn check(
+    name: impl Into<String>,
+    fut: impl Future<Output = anyhow::Result<String>>,
+) -> DiagnosticCheck {
+    let name = name.into();
+    let start = Instant::now();
+    let result = fut
+        .timeout(CHECK_TIMEOUT)
+        .await
+        .context("timed out")
+        .and_then(|r| r);
+    if let Err(err) = &result {
+        tracing::debug!(
+            name = display(&name),
+            err = debug(err),
+            "diagnostic check failed"
+        );
+    }
+    DiagnosticCheck {
+        name,
+        ok: result.is_ok(),
+        detail: match result {
+            Ok(detail) => detail,
+            Err(err) => format!("{err:#}"),
+        },
+        secs: start.elapsed().as_secs_f64(),
+    }
+}
+
+async fn check_brokers(ctx: &AnyCtx<Config>) -> Vec<DiagnosticCheck> {
+    let Some(broker) = &ctx.init().broker else {
+        return vec![];
+    };
+    let mut sources = vec![];
+    flatten_broker_sources(broker, &mut sources);
+    join_all(sources.into_iter().map(|source| {
+        check(broker_source_name(source), async move {
+            let client = BrokerClient::from(source.rpc_transport());
+            let exits = client
+                .get_exits()
+                .await?
+                .map_err(|e| anyhow::anyhow!("broker returned an error: {e}"))?;
+            Ok(format!(
+                "broker listed {} exits",
+                exits.inner.all_exits.len()
+            ))
+        })
+    }))
+    .await
+}
+
+fn flatten_broker_sources<'a>(source: &'a BrokerSource, out: &mut Vec<&'a BrokerSource>) {
+    match source {
+        BrokerSource::Race(inside) => inside
+            .iter()
+            .for_each(|source| flatten_broker_sources(source, out)),
+        source => out.push(source),
+    }
+}
+
+fn broker_source_name(source: &BrokerSource) -> String {
+    match source {
+        BrokerSource::Direct(url) => format!("broker at {url}"),
+        BrokerSource::Fronted { front, host } => format!("broker fronted by {front} as {host}"),
+        BrokerSource::DirectTcp(addr) => format!("broker at tcp {addr}"),
+        BrokerSource::AwsLambda {
+            function_name,
+            region,
+            ..
+        } => format!("broker on AWS Lambda {function_name} in {region}"),
+        BrokerSource::Race(_) => "raced brokers".to_string(),
+        BrokerSource::DnsTxt { domain, .. } => format!("brokers listed in TXT records of {domain}"),
+        BrokerSource::Socks5 { proxy, inner } => {
+            format!("{} through SOCKS5 proxy {proxy}", broker_source_name(inner))
+        }
+    }
+}
+
+async fn check_exit(ctx: &AnyCtx<Config>) -> (DiagnosticCheck, Vec<DiagnosticCheck>) {
+    let selected = get_dialer(ctx).await;
+    let exit_auth = check("exit authentication", async {
+        let (pubkey, exit, dialer) = selected.as_ref().map_err(|e| anyhow::anyhow!("{e:#}"))?;
+        let pipe = dialer.dial().await.context("could not reach the exit")?;
+        let protocol = pipe.protocol().to_string();
+        client_auth(ctx, pipe, *pubkey).await?;
+        Ok(format!(
+            "authenticated to the exit at {} over {protocol}",
+            exit.c2e_listen
+        ))
+    })
+    .await;
+
+    let has_bridges = ctx.init().broker.is_some() || !live_config(ctx).custom_bridges.is_empty();
+    let bridges = match &selected {
+        Ok((_, exit, _)) if has_bridges => match bridge_dialers(ctx, exit).await {
+            Ok(dialers) => {
+                join_all(dialers.into_iter().map(|(name, dialer)| {
+                    check(format!("bridge {name}"), async move {
+                        let pipe = dialer.dial().await?;
+                        Ok(format!(
+                            "reached {}",
+                            pipe.remote_addr().unwrap_or("bridge")
+                        ))
+                    })
+                }))
+                .await
+            }
+            Err(err) => vec![DiagnosticCheck {
+                name: "bridge list".to_string(),
+                ok: false,
+                detail: format!("{err:#}"),
+                secs: 0.0,
+            }],
+        },
+        _ => vec![],
+    };
+    (exit_auth, bridges)
+}
+
+async fn check_dns(ctx: &AnyCtx<Config>) -> Vec<DiagnosticCheck> {
+    let spoof_dns = ctx.init().spoof_dns;
+    let (local, tunnel, spoofing) = futures_util::join!(
+        check("local resolver", async {
+            let addrs = smol::net::resolve(TEST_DOMAIN).await?;
+            Ok(format!("resolved {TEST_DOMAIN} to {addrs:?}"))
+        }),
+        check("resolver through the tunnel", async {
+            let addrs = resolve_through_tunnel(ctx, TEST_DOMAIN).await?;
+            Ok(format!("resolved {TEST_DOMAIN} to {addrs:?}"))
+        }),
+        check("DNS spoofing", async move {
+            Ok(if spoof_dns {
+                "on, so applications see fake addresses that the client translates back".to_string()
+            } else {
+                "off".to_string()
+            })
+        })
+    );
+    vec![local, tunnel, spoofing]
+}
+
+async fn check_listen_ports(ctx: &AnyCtx<Config>) -> Vec<DiagnosticCheck> {
+    let config = live_config(ctx);
+    let mut tcp_listens: Vec<(&str, SocketAddr)> = [
+        ("SOCKS5 proxy", config.socks5_listen),
+        ("HTTP proxy", config.http_proxy_listen),
+        ("transparent proxy", config.tproxy_listen),
+        ("metrics server", config.metrics_listen),
+        ("PAC server", config.pac_listen),
+    ]
+    .into_iter()
+    .filter_map(|(name, listen)| Some((name, listen?)))
+    .collect();
+    if let Some(ControlListen::Tcp(listen)) = &ctx.init().control_listen {
+        tcp_listens.push(("control protocol", *listen));
+    }
+
+    // if we can bind an address ourselves, then our own listener isn't holding it
+    let mut checks = vec![];
+    for (name, listen) in tcp_listens {
+        checks.push(
+            check(format!("{name} on {listen}"), async move {
+                match TcpListener::bind(listen).await {
+                    Ok(_) => anyhow::bail!("nothing is listening, so the {name} is not running"),
+                    Err(_) => Ok("in use, as expected".to_string()),
+                }
+            })
+            .await,
+        );
+    }
+    if let Some(listen) = config.dns_listen {
+        checks.push(
+            check(format!("DNS server on {listen}"), async move {
+                match UdpSocket::bind(listen).await {
+                    Ok(_) => {
+                        anyhow::bail!("nothing is listening, so the DNS server is not running")
+                    }
+                    Err(_) => Ok("in use, as expected".to_string()),
+                }
+            })
+            .await,
+        );
+    }
+    checks
+}
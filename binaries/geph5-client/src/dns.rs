@@ -0,0 +1,354 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyctx::AnyCtx;
+use anyhow::Context as _;
+use async_trait::async_trait;
+use bytes::Bytes;
+use futures_util::{AsyncReadExt as _, AsyncWriteExt as _};
+use moka::future::Cache;
+use nursery_macro::nursery;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sillad::{dialer::Dialer, tcp::TcpDialer, Pipe};
+use sillad_native_tls::{webpki_roots, ClientHelloProfile, RustlsDialer};
+use simple_dns::{rdata::RData, Name, Packet, PacketFlag, Question, CLASS, QCLASS, QTYPE, TYPE};
+use smol::{
+    future::FutureExt as _,
+    net::{TcpListener, UdpSocket},
+};
+use smol_timeout2::TimeoutExt as _;
+
+use crate::{
+    client::CtxField,
+    client_inner::open_conn,
+    live_config::live_config,
+    spoof_dns::fake_dns_respond,
+    taskpool::{add_task, TaskPriority},
+    udp::UdpFlow,
+    Config,
+};
+
+/// The resolver that queries go to on the other side of the tunnel.
+const UPSTREAM_DNS: &str = "1.1.1.1:53";
+
+/// Serves DNS over both UDP and TCP on the configured address. Queries are answered from the fake-IP pool when spoof_dns is on, and otherwise resolved through the tunnel, so nothing leaks to the local network's resolver.
+#[tracing::instrument(skip_all)]
+pub async fn dns_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    if let Some(listen_addr) = live_config(ctx).dns_listen {
+        let udp = UdpSocket::bind(listen_addr).await?;
+        let tcp = TcpListener::bind(listen_addr).await?;
+        tracing::info!(addr = display(listen_addr), "start DNS server");
+        nursery!({
+            let udp_loop = async {
+                loop {
+                    let mut buf = [0u8; 8192];
+                    let (n, src) = udp.recv_from(&mut buf).await?;
+                    tracing::trace!(n, src = display(src), "received DNS packet");
+                    let udp = udp.clone();
+                    let task = spawn!(async move {
+                        let resp = dns_respond(ctx, &buf[..n]).await?;
+                        udp.send_to(&resp, src).await?;
+                        anyhow::Ok(())
+                    });
+                    spawn_limited(ctx, task);
+                }
+            };
+            let tcp_loop = async {
+                loop {
+                    let (mut client, src) = tcp.accept().await?;
+                    tracing::trace!(src = display(src), "DNS over TCP connection accepted");
+                    let task = spawn!(async move {
+                        // a TCP client may send several length-prefixed queries over one connection
+                        loop {
+                            let mut len_buf = [0u8; 2];
+                            if client.read_exact(&mut len_buf).await.is_err() {
+                                return anyhow::Ok(());
+                            }
+                            let mut buf = vec![0u8; u16::from_be_bytes(len_buf) as usize];
+                            client.read_exact(&mut buf).await?;
+                            let resp = dns_respond(ctx, &buf).await?;
+                            client.write_all(&(resp.len() as u16).to_be_bytes()).await?;
+                            client.write_all(&resp).await?;
+                        }
+                    });
+                    spawn_limited(ctx, task);
+                }
+            };
+            udp_loop.race(tcp_loop).await
+        })
+    } else {
+        smol::future::pending().await
+    }
+}
+
+fn spawn_limited(ctx: &AnyCtx<Config>, task: smol::Task<anyhow::Result<()>>) {
+    add_task(ctx, TaskPriority::Low, task);
+}
+
+async fn dns_respond(ctx: &AnyCtx<Config>, pkt: &[u8]) -> anyhow::Result<Bytes> {
+    if ctx.init().spoof_dns {
+        fake_dns_respond(ctx, pkt)
+    } else {
+        tunnel_dns_resolve(ctx, pkt)
+            .timeout(Duration::from_secs(10))
+            .await
+            .context("DNS query through the tunnel timed out")?
+    }
+}
+
+/// Sends a raw DNS query to the upstream resolver through the tunnel and returns the raw response.
+pub async fn tunnel_dns_resolve(ctx: &AnyCtx<Config>, pkt: &[u8]) -> anyhow::Result<Bytes> {
+    let flow = UdpFlow::tunneled(ctx, UPSTREAM_DNS).await?;
+    flow.send(pkt).await?;
+    flow.recv().await
+}
+
+/// The DNS-over-HTTPS server that names are resolved with.
+const DOH_HOST: &str = "cloudflare-dns.com";
+const DOH_ADDR: &str = "1.1.1.1:443";
+
+/// How long a direct DNS-over-HTTPS lookup may take before we fall back to the system resolver. Much shorter than through the tunnel, since a network that blocks the DoH server tends to just drop packets to it.
+const DIRECT_DOH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long to stop trying direct DNS-over-HTTPS after it timed out, so that every lookup in the meantime doesn't wait out the timeout first.
+const DIRECT_DOH_BACKOFF: Duration = Duration::from_secs(120);
+
+/// How destinations that skip the tunnel have their names resolved.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DirectDns {
+    /// DNS-over-HTTPS over a direct connection, so that CDNs answer for where the traffic really comes from, while the local network still can't poison the lookup. Falls back to the system resolver if the DoH server can't be reached, and keeps using it for a while if the DoH server doesn't answer at all.
+    #[default]
+    Doh,
+    /// The system resolver, which sees every name that goes direct.
+    System,
+    /// DNS-over-HTTPS through the tunnel, which hides even the names of direct destinations from the local network, but gets answers meant for the exit's location.
+    Tunnel,
+}
+
+static TUNNEL_DOH_CACHE: CtxField<Cache<String, Vec<IpAddr>>> = |_| doh_cache();
+
+static DIRECT_DOH_CACHE: CtxField<Cache<String, Vec<IpAddr>>> = |_| doh_cache();
+
+/// Until when direct DNS-over-HTTPS is considered unreachable.
+static DIRECT_DOH_DOWN_UNTIL: CtxField<Mutex<Option<Instant>>> = |_| Mutex::new(None);
+
+fn doh_cache() -> Cache<String, Vec<IpAddr>> {
+    Cache::builder()
+        .time_to_live(Duration::from_secs(300))
+        .max_capacity(10000)
+        .build()
+}
+
+/// Resolves a `host:port` address with DNS-over-HTTPS through the tunnel, so the local network can neither see nor poison the lookup. Results are cached for a few minutes.
+pub async fn resolve_through_tunnel(
+    ctx: &AnyCtx<Config>,
+    dest_addr: &str,
+) -> anyhow::Result<Vec<SocketAddr>> {
+    let dialer = TunnelDialer {
+        ctx: ctx.clone(),
+        dest: DOH_ADDR,
+    };
+    resolve_doh(ctx.get(TUNNEL_DOH_CACHE), dialer, dest_addr)
+        .await
+        .context("DNS-over-HTTPS lookup through the tunnel failed")
+}
+
+/// Resolves a `host:port` address that is to be reached without the tunnel, as configured by [DirectDns].
+pub async fn resolve_direct_dns(
+    ctx: &AnyCtx<Config>,
+    dest_addr: &str,
+) -> anyhow::Result<Vec<SocketAddr>> {
+    match live_config(ctx).direct_dns {
+        DirectDns::Doh => {
+            if ctx
+                .get(DIRECT_DOH_DOWN_UNTIL)
+                .lock()
+                .is_some_and(|until| Instant::now() < until)
+            {
+                return Ok(smol::net::resolve(dest_addr).await?);
+            }
+            let dialer = TcpDialer {
+                dest_addr: DOH_ADDR.parse()?,
+            };
+            let result = resolve_doh(ctx.get(DIRECT_DOH_CACHE), dialer, dest_addr)
+                .timeout(DIRECT_DOH_TIMEOUT)
+                .await;
+            match result {
+                Some(Ok(addrs)) => Ok(addrs),
+                Some(Err(err)) => {
+                    tracing::debug!(
+                        err = debug(err),
+                        dest_addr,
+                        "direct DNS-over-HTTPS failed, using the system resolver"
+                    );
+                    Ok(smol::net::resolve(dest_addr).await?)
+                }
+                None => {
+                    tracing::debug!(
+                        dest_addr,
+                        backoff = debug(DIRECT_DOH_BACKOFF),
+                        "direct DNS-over-HTTPS timed out, using the system resolver for a while"
+                    );
+                    *ctx.get(DIRECT_DOH_DOWN_UNTIL).lock() =
+                        Some(Instant::now() + DIRECT_DOH_BACKOFF);
+                    Ok(smol::net::resolve(dest_addr).await?)
+                }
+            }
+        }
+        DirectDns::System => Ok(smol::net::resolve(dest_addr).await?),
+        DirectDns::Tunnel => resolve_through_tunnel(ctx, dest_addr).await,
+    }
+}
+
+async fn resolve_doh<D: Dialer>(
+    cache: &Cache<String, Vec<IpAddr>>,
+    dialer: D,
+    dest_addr: &str,
+) -> anyhow::Result<Vec<SocketAddr>> {
+    if let Ok(addr) = SocketAddr::from_str(dest_addr) {
+        return Ok(vec![addr]);
+    }
+    let (host, port) = dest_addr
+        .rsplit_once(':')
+        .context("destination has no port")?;
+    let port: u16 = port.parse()?;
+    let dialer = RustlsDialer::new(
+        dialer,
+        &ClientHelloProfile::chrome(),
+        webpki_roots(),
+        DOH_HOST.to_string(),
+    )?;
+    let ips = cache
+        .try_get_with(host.to_string(), async {
+            let (v4, v6) = futures_util::join!(
+                doh_query(&dialer, host, TYPE::A),
+                doh_query(&dialer, host, TYPE::AAAA)
+            );
+            let ips: Vec<IpAddr> = match (v4, v6) {
+                (Err(err), Err(_)) => return Err(err),
+                (v4, v6) => v4.into_iter().chain(v6).flatten().collect(),
+            };
+            if ips.is_empty() {
+                anyhow::bail!("no addresses found for {host}");
+            }
+            tracing::debug!(host, ips = debug(&ips), "resolved with DNS-over-HTTPS");
+            anyhow::Ok(ips)
+        })
+        .timeout(Duration::from_secs(10))
+        .await
+        .context("DNS-over-HTTPS lookup timed out")?
+        .map_err(|err| anyhow::anyhow!("{err}"))?;
+    Ok(ips
+        .into_iter()
+        .map(|ip| SocketAddr::new(ip, port))
+        .collect())
+}
+
+async fn doh_query(dialer: &impl Dialer, host: &str, qtype: TYPE) -> anyhow::Result<Vec<IpAddr>> {
+    let mut query = Packet::new_query(rand::random());
+    query.set_flags(PacketFlag::RECURSION_DESIRED);
+    query.questions.push(Question::new(
+        Name::new(host)?,
+        QTYPE::TYPE(qtype),
+        QCLASS::CLASS(CLASS::IN),
+        false,
+    ));
+    let query = query.build_bytes_vec()?;
+
+    let mut conn = dialer.dial().await?;
+    // HTTP/1.0, so that the server won't send the body chunked, although we can deal with that too
+    let mut request = format!(
+        "POST /dns-query HTTP/1.0\r\nHost: {DOH_HOST}\r\nContent-Type: application/dns-message\r\nAccept: application/dns-message\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        query.len()
+    )
+    .into_bytes();
+    request.extend_from_slice(&query);
+    conn.write_all(&request).await?;
+    conn.flush().await?;
+    let mut response = vec![];
+    conn.take(65536).read_to_end(&mut response).await?;
+
+    let split = response
+        .windows(4)
+        .position(|w| w == b"\r\n\r\n")
+        .context("DoH response has no end of headers")?;
+    let head = String::from_utf8_lossy(&response[..split]);
+    let mut body = &response[split + 4..];
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        anyhow::bail!("DoH server said {status:?}");
+    }
+    let header = |wanted: &str| {
+        head.lines().find_map(|line| {
+            let (name, value) = line.split_once(':')?;
+            name.eq_ignore_ascii_case(wanted).then(|| value.trim())
+        })
+    };
+    let dechunked;
+    if header("transfer-encoding").is_some_and(|enc| enc.eq_ignore_ascii_case("chunked")) {
+        dechunked = dechunk(body)?;
+        body = &dechunked[..];
+    } else if let Some(len) = header("content-length") {
+        body = body
+            .get(..len.parse()?)
+            .context("DoH response was cut short")?;
+    }
+
+    let response = Packet::parse(body)?;
+    Ok(response
+        .answers
+        .iter()
+        .filter_map(|answer| match &answer.rdata {
+            RData::A(a) => Some(Ipv4Addr::from(a.address).into()),
+            RData::AAAA(aaaa) => Some(Ipv6Addr::from(aaaa.address).into()),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Decodes a body sent with `Transfer-Encoding: chunked`, ignoring chunk extensions and trailers.
+fn dechunk(mut body: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let mut out = vec![];
+    loop {
+        let line_end = body
+            .windows(2)
+            .position(|w| w == b"\r\n")
+            .context("DoH response chunk was cut short")?;
+        let size_line = std::str::from_utf8(&body[..line_end])?;
+        let size_hex = size_line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size_hex, 16)?;
+        body = &body[line_end + 2..];
+        if size == 0 {
+            return Ok(out);
+        }
+        out.extend_from_slice(
+            body.get(..size)
+                .context("DoH response chunk was cut short")?,
+        );
+        body = body
+            .get(size + 2..)
+            .context("DoH response chunk was cut short")?;
+    }
+}
+
+/// Dials a fixed destination through the tunnel.
+pub(crate) struct TunnelDialer {
+    pub ctx: AnyCtx<Config>,
+    pub dest: &'static str,
+}
+
+#[async_trait]
+impl Dialer for TunnelDialer {
+    type P = Box<dyn Pipe>;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        open_conn(&self.ctx, "tcp", self.dest)
+            .await
+            .map_err(std::io::Error::other)
+    }
+}
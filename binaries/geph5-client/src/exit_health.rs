@@ -0,0 +1,251 @@
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyctx::AnyCtx;
+use ed25519_dalek::VerifyingKey;
+use geph5_broker_protocol::ExitDescriptor;
+use moka::sync::Cache;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use smol::channel::{Receiver, Sender};
+
+use crate::{client::CtxField, stats::stat_incr_num, Config};
+
+/// How much each new RTT sample moves the running average.
+const RTT_SMOOTHING: f64 = 0.2;
+/// The running RTT counts as degraded once it is this many times the best it has been on the current exit.
+const RTT_DEGRADED_FACTOR: f64 = 3.0;
+/// The running RTT has to fall back under this many times the best before the exit counts as healthy again.
+const RTT_RECOVERED_FACTOR: f64 = 2.0;
+/// The running RTT also has to be at least this many seconds over the best, so that jitter on a very fast exit doesn't count.
+const RTT_DEGRADED_MARGIN: f64 = 0.3;
+/// This many stream failures within [FAILURE_WINDOW] count as degraded.
+const FAILURE_THRESHOLD: usize = 5;
+const FAILURE_WINDOW: Duration = Duration::from_secs(60);
+/// How long the exit has to stay degraded before we switch away from it.
+const DEGRADED_GRACE: Duration = Duration::from_secs(30);
+/// The least time between two switches, so that we don't flap between exits that are both bad.
+const SWITCH_COOLDOWN: Duration = Duration::from_secs(300);
+/// How long exit selection avoids an exit that we switched away from.
+const AVOID_DURATION: Duration = Duration::from_secs(600);
+/// How long sessions to a replaced exit are kept around, so that connections already going through them aren't cut.
+pub const RETIRED_SESSION_LINGER: Duration = Duration::from_secs(600);
+/// How many switches are remembered for [recent_exit_switches].
+const MAX_SWITCHES: usize = 20;
+
+/// An automatic switch away from an exit that degraded.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ExitSwitch {
+    pub time: SystemTime,
+    pub from: ExitDescriptor,
+    pub to: ExitDescriptor,
+    pub reason: String,
+}
+
+#[derive(Default)]
+struct Health {
+    current: Option<(VerifyingKey, ExitDescriptor)>,
+    rtt_avg: Option<f64>,
+    rtt_best: f64,
+    failures: VecDeque<Instant>,
+    degraded_since: Option<Instant>,
+    /// Why we are switching, while a replacement exit is being picked.
+    switching: Option<String>,
+    last_switch: Option<Instant>,
+    switches: VecDeque<ExitSwitch>,
+}
+
+impl Health {
+    fn degradation(&mut self) -> Option<String> {
+        let now = Instant::now();
+        while self
+            .failures
+            .front()
+            .is_some_and(|time| now.saturating_duration_since(*time) > FAILURE_WINDOW)
+        {
+            self.failures.pop_front();
+        }
+        if self.failures.len() >= FAILURE_THRESHOLD {
+            return Some(format!(
+                "{} stream failures in the last {}s",
+                self.failures.len(),
+                FAILURE_WINDOW.as_secs()
+            ));
+        }
+        let avg = self.rtt_avg?;
+        let factor = if self.degraded_since.is_some() {
+            RTT_RECOVERED_FACTOR
+        } else {
+            RTT_DEGRADED_FACTOR
+        };
+        if avg > self.rtt_best * factor && avg - self.rtt_best > RTT_DEGRADED_MARGIN {
+            return Some(format!(
+                "latency rose from {:.0}ms to {:.0}ms",
+                self.rtt_best * 1000.0,
+                avg * 1000.0
+            ));
+        }
+        None
+    }
+
+    /// Returns whether it's time to switch away from the current exit.
+    fn evaluate(&mut self) -> bool {
+        let Some(reason) = self.degradation() else {
+            self.degraded_since = None;
+            return false;
+        };
+        let degraded_since = *self.degraded_since.get_or_insert_with(Instant::now);
+        if self.switching.is_some()
+            || degraded_since.elapsed() < DEGRADED_GRACE
+            || self
+                .last_switch
+                .is_some_and(|time| time.elapsed() < SWITCH_COOLDOWN)
+        {
+            return false;
+        }
+        tracing::warn!(reason, "current exit degraded, selecting another one");
+        self.switching = Some(reason);
+        self.last_switch = Some(Instant::now());
+        true
+    }
+}
+
+static HEALTH: CtxField<Mutex<Health>> = |_| Mutex::new(Health::default());
+
+static AVOIDED_EXITS: CtxField<Cache<VerifyingKey, ()>> =
+    |_| Cache::builder().time_to_live(AVOID_DURATION).build();
+
+/// Exits that we switched away from, whose sessions should stop taking new connections.
+static RETIRED_EXITS: CtxField<Cache<VerifyingKey, ()>> = |_| {
+    Cache::builder()
+        .time_to_live(RETIRED_SESSION_LINGER)
+        .build()
+};
+
+static HEALTH_EVENT: CtxField<async_event::Event> = |_| async_event::Event::new();
+
+static SWITCH_REQ_CHAN: CtxField<(Sender<()>, Receiver<()>)> = |_| smol::channel::unbounded();
+
+/// Records the round-trip time of a session to the given exit.
+pub fn record_rtt(ctx: &AnyCtx<Config>, pubkey: &VerifyingKey, rtt: Duration) {
+    let mut health = ctx.get(HEALTH).lock();
+    if health.current.as_ref().map(|(pk, _)| pk) != Some(pubkey) {
+        return;
+    }
+    let rtt = rtt.as_secs_f64();
+    let avg = match health.rtt_avg {
+        Some(avg) => avg * (1.0 - RTT_SMOOTHING) + rtt * RTT_SMOOTHING,
+        None => rtt,
+    };
+    health.rtt_avg = Some(avg);
+    health.rtt_best = health.rtt_best.min(avg);
+    let switch = health.evaluate();
+    drop(health);
+    if switch {
+        start_switch(ctx, pubkey);
+    }
+}
+
+/// Records a stream or session to the given exit failing.
+pub fn record_failure(ctx: &AnyCtx<Config>, pubkey: &VerifyingKey) {
+    let mut health = ctx.get(HEALTH).lock();
+    if health.current.as_ref().map(|(pk, _)| pk) != Some(pubkey) {
+        return;
+    }
+    health.failures.push_back(Instant::now());
+    let switch = health.evaluate();
+    drop(health);
+    if switch {
+        start_switch(ctx, pubkey);
+    }
+}
+
+fn start_switch(ctx: &AnyCtx<Config>, pubkey: &VerifyingKey) {
+    ctx.get(AVOIDED_EXITS).insert(*pubkey, ());
+    let _ = ctx.get(SWITCH_REQ_CHAN).0.try_send(());
+    ctx.get(HEALTH_EVENT).notify_all();
+}
+
+/// Re-runs exit selection for a reason other than the current exit degrading, such as the user picking a different exit. If a different exit comes out, sessions to the current one are retired like after an automatic switch.
+pub fn reselect_exit(ctx: &AnyCtx<Config>, reason: &str) {
+    ctx.get(HEALTH).lock().switching = Some(reason.to_string());
+    let _ = ctx.get(SWITCH_REQ_CHAN).0.try_send(());
+}
+
+/// Returns whether exit selection should prefer other exits over this one, because we recently switched away from it.
+pub fn is_avoided(ctx: &AnyCtx<Config>, pubkey: &VerifyingKey) -> bool {
+    ctx.get(AVOIDED_EXITS).contains_key(pubkey)
+}
+
+/// Waits until the current exit has degraded enough that exit selection should be re-run.
+pub async fn wait_switch_needed(ctx: &AnyCtx<Config>) {
+    let _ = ctx.get(SWITCH_REQ_CHAN).1.recv().await;
+}
+
+/// Tells the health tracker which exit new sessions now go to, completing any switch in progress.
+pub fn exit_selected(ctx: &AnyCtx<Config>, pubkey: VerifyingKey, exit: ExitDescriptor) {
+    let mut health = ctx.get(HEALTH).lock();
+    let switching = health.switching.take();
+    match health.current.clone() {
+        Some((old_pubkey, _)) if old_pubkey == pubkey => {
+            if switching.is_some() {
+                tracing::warn!("no better exit to switch to, staying with the current one");
+                ctx.get(AVOIDED_EXITS).invalidate(&pubkey);
+            }
+            return;
+        }
+        Some((old_pubkey, old_exit)) => {
+            if let Some(reason) = switching {
+                ctx.get(RETIRED_EXITS).insert(old_pubkey, ());
+                tracing::info!(
+                    from = debug(&old_exit),
+                    to = debug(&exit),
+                    reason,
+                    "switched to another exit"
+                );
+                let switch = ExitSwitch {
+                    time: SystemTime::now(),
+                    from: old_exit,
+                    to: exit.clone(),
+                    reason,
+                };
+                health.switches.push_back(switch);
+                stat_incr_num(ctx, "exit_switches", 1.0);
+                if health.switches.len() > MAX_SWITCHES {
+                    health.switches.pop_front();
+                }
+            }
+        }
+        None => {}
+    }
+    *health = Health {
+        current: Some((pubkey, exit)),
+        rtt_best: f64::INFINITY,
+        last_switch: health.last_switch,
+        switches: std::mem::take(&mut health.switches),
+        ..Default::default()
+    };
+    drop(health);
+    ctx.get(HEALTH_EVENT).notify_all();
+}
+
+/// Waits until new connections should no longer go through a session to this exit, because we switched away from it.
+pub async fn wait_retired(ctx: &AnyCtx<Config>, pubkey: &VerifyingKey) {
+    ctx.get(HEALTH_EVENT)
+        .wait_until(|| {
+            let health = ctx.get(HEALTH).lock();
+            let replaced = health
+                .current
+                .as_ref()
+                .is_some_and(|(current, _)| current != pubkey);
+            (replaced && ctx.get(RETIRED_EXITS).contains_key(pubkey)).then_some(())
+        })
+        .await
+}
+
+/// The most recent automatic exit switches, oldest first.
+pub fn recent_exit_switches(ctx: &AnyCtx<Config>) -> Vec<ExitSwitch> {
+    ctx.get(HEALTH).lock().switches.iter().cloned().collect()
+}
@@ -6,7 +6,7 @@ use std::{net::SocketAddr, str::FromStr as _};
 
 pub async fn run_http_proxy(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
     let shared_server: SharedProxyServer = ProxyServer::new_shared(ctx.clone());
-    let listen = ctx.init().http_proxy_listen;
+    let listen = live_config(ctx).http_proxy_listen;
     if let Some(listen) = listen {
         let tcp_listener = tokio::net::TcpListener::bind(&listen).await?;
         let mut join_set = JoinSet::new();
@@ -144,6 +144,25 @@ async fn server_dispatch(
             host = %host,
             "CONNECT relay connected"
         );
+        // the stream is opened before answering, so that a failure can be reported to the client rather than leaving it hanging
+        let stream = match open_app_conn(&ctx, client_addr, "tcp", &host.to_string()).await {
+            Ok(stream) => stream,
+            Err(err) => {
+                tracing::debug!(
+                    client_addr = %client_addr,
+                    host = %host,
+                    error = ?err,
+                    "could not open CONNECT tunnel"
+                );
+                let mut resp = Response::new(HttpEither::Left(
+                    Full::new(Bytes::from(format!("Could not connect to {host}: {err}")))
+                        .map_err(|_| unreachable!())
+                        .boxed(),
+                ));
+                *resp.status_mut() = StatusCode::BAD_GATEWAY;
+                return Ok(resp);
+            }
+        };
         tokio::spawn(async move {
             match hyper::upgrade::on(&mut req).await {
                 Ok(upgraded) => {
@@ -152,10 +171,7 @@ async fn server_dispatch(
                         host = %host,
                         "CONNECT tunnel upgrade success"
                     );
-                    let stream = open_conn(&ctx, "tcp", &host.to_string()).await;
-                    if let Ok(stream) = stream {
-                        establish_connect_tunnel(upgraded, stream, client_addr).await
-                    }
+                    establish_connect_tunnel(upgraded, stream, client_addr).await
                 }
                 Err(e) => {
                     tracing::info!(
@@ -287,7 +303,7 @@ fn make_bad_request() -> Response<HttpEither<BoxBody<Bytes, hyper::Error>, Empty
 
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
-use crate::{client_inner::open_conn, Config};
+use crate::{client_inner::open_app_conn, live_config::live_config, Config};
 
 use self::address::{host_addr, Address};
 fn authority_addr(scheme_str: Option<&str>, authority: &Authority) -> Option<Address> {
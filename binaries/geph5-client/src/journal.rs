@@ -0,0 +1,92 @@
+use std::time::{Duration, SystemTime};
+
+use anyctx::AnyCtx;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    database::{db_add_connection_event, db_connection_events_since},
+    Config,
+};
+
+/// How long connection events are kept for.
+const RETENTION: Duration = Duration::from_secs(7 * 86400);
+
+/// Something that happened to one of the sessions to the exit, as kept in the connection journal.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ConnectionEvent {
+    pub time: SystemTime,
+    #[serde(flatten)]
+    pub kind: ConnectionEventKind,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ConnectionEventKind {
+    /// A bridge or exit was reached, though not yet authenticated with.
+    DialSucceeded { protocol: String, bridge: String },
+    /// Nothing could be reached, or reaching it took too long.
+    DialFailed { error: String },
+    /// Something was reached, but authenticating with the exit failed.
+    AuthFailed { bridge: String, error: String },
+    /// An established session stopped working.
+    SessionDied {
+        protocol: String,
+        bridge: String,
+        reason: String,
+    },
+}
+
+/// Adds an event to the connection journal. It's written in the background, so that recording never holds up connecting.
+pub fn record_event(ctx: &AnyCtx<Config>, kind: ConnectionEventKind) {
+    let event = ConnectionEvent {
+        time: SystemTime::now(),
+        kind,
+    };
+    tracing::debug!(event = debug(&event), "connection event");
+    let ctx = ctx.clone();
+    smolscale::spawn(async move {
+        let time = unix_secs(event.time);
+        if let Err(err) = db_add_connection_event(
+            &ctx,
+            time,
+            &serde_json::to_string(&event.kind)?,
+            time.saturating_sub(RETENTION.as_secs()),
+        )
+        .await
+        {
+            tracing::warn!(
+                err = debug(err),
+                "could not write to the connection journal"
+            );
+        }
+        anyhow::Ok(())
+    })
+    .detach();
+}
+
+/// The latest `limit` events in the connection journal since the given time, oldest first.
+pub async fn connection_history(
+    ctx: &AnyCtx<Config>,
+    since: SystemTime,
+    limit: usize,
+) -> anyhow::Result<Vec<ConnectionEvent>> {
+    Ok(
+        db_connection_events_since(ctx, unix_secs(since), limit as i64)
+            .await?
+            .into_iter()
+            // rows written by a newer client may have kinds we don't know about
+            .filter_map(|(time, kind)| {
+                Some(ConnectionEvent {
+                    time: SystemTime::UNIX_EPOCH + Duration::from_secs(time),
+                    kind: serde_json::from_str(&kind).ok()?,
+                })
+            })
+            .collect(),
+    )
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
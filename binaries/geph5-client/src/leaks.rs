@@ -0,0 +1,200 @@
+use std::{
+    collections::HashMap,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, SystemTime},
+};
+
+use anyctx::AnyCtx;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{app_rules::canonical, client::CtxField, stats::stat_set_num, Config};
+
+#[cfg(any(target_os = "linux", all(target_os = "windows", feature = "windivert")))]
+use crate::vpn::{leak_watch_start, leaked_flows, vpn_routing_active, vpn_whitelisted};
+#[cfg(not(any(target_os = "linux", all(target_os = "windows", feature = "windivert"))))]
+use unsupported::*;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a leak stays in the report after it was last seen.
+const LEAK_EXPIRY: Duration = Duration::from_secs(600);
+
+/// The most leaks kept at once. Past this, the ones not seen for longest are forgotten first.
+const MAX_LEAKS: usize = 100;
+
+/// What the leak watchdog has found recently.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LeakReport {
+    /// Whether the watchdog is running. It only runs in VPN mode with `leak_watchdog` on, and only on Linux and on Windows with WinDivert.
+    pub watching: bool,
+    /// When the watchdog last collected what it saw.
+    pub checked_at: Option<SystemTime>,
+    pub leaks: Vec<Leak>,
+}
+
+/// A flow that was seen sending packets around the tunnel.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Leak {
+    pub kind: LeakKind,
+    pub protocol: String,
+    pub local: IpAddr,
+    pub remote: SocketAddr,
+    pub first_seen: SystemTime,
+    pub last_seen: SystemTime,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum LeakKind {
+    /// A query to a DNS resolver outside the tunnel, which gives away what is being visited even when the traffic itself is tunneled.
+    Dns,
+    /// Traffic going out the physical interface instead of the tunnel.
+    Traffic,
+}
+
+#[derive(Default)]
+struct LeakState {
+    checked_at: Option<SystemTime>,
+    leaks: HashMap<(String, IpAddr, SocketAddr), Leak>,
+}
+
+static LEAKS: CtxField<Mutex<LeakState>> = |_| Mutex::new(LeakState::default());
+
+fn is_watching(ctx: &AnyCtx<Config>) -> bool {
+    cfg!(any(
+        target_os = "linux",
+        all(target_os = "windows", feature = "windivert")
+    )) && ctx.init().vpn
+        && ctx.init().leak_watchdog
+}
+
+/// The leaks seen in the last while, most recently seen first.
+pub fn leak_report(ctx: &AnyCtx<Config>) -> LeakReport {
+    let state = ctx.get(LEAKS).lock();
+    let mut leaks: Vec<Leak> = state.leaks.values().cloned().collect();
+    leaks.sort_unstable_by(|a, b| b.last_seen.cmp(&a.last_seen));
+    LeakReport {
+        watching: is_watching(ctx),
+        checked_at: state.checked_at,
+        leaks,
+    }
+}
+
+/// In VPN mode, watches every packet that leaves the machine for DNS queries and traffic that go around the tunnel, so that users can check that their setup doesn't leak.
+///
+/// Packets are recorded as they go out, through nftables on Linux and through a sniffing WinDivert handle on Windows, so even a single DNS query is caught. Every few seconds, what was recorded is collected into the report.
+pub async fn leak_watchdog_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    if !is_watching(ctx) {
+        return smol::future::pending().await;
+    }
+    // before routing is set up, nothing is supposed to go through the tunnel yet
+    while !vpn_routing_active() {
+        smol::Timer::after(CHECK_INTERVAL).await;
+    }
+    smol::unblock(leak_watch_start).await?;
+    loop {
+        smol::Timer::after(CHECK_INTERVAL).await;
+        let flows = smol::unblock(leaked_flows).await;
+        let now = SystemTime::now();
+
+        let mut state = ctx.get(LEAKS).lock();
+        state.checked_at = Some(now);
+        for (protocol, local, remote) in flows {
+            let Some(kind) = leak_kind(remote) else {
+                continue;
+            };
+            state
+                .leaks
+                .entry((protocol.to_string(), local, remote))
+                .and_modify(|known| known.last_seen = now)
+                .or_insert_with(|| {
+                    tracing::warn!(
+                        kind = debug(kind),
+                        protocol,
+                        local = display(local),
+                        remote = display(remote),
+                        "traffic is leaking around the tunnel"
+                    );
+                    Leak {
+                        kind,
+                        protocol: protocol.to_string(),
+                        local,
+                        remote,
+                        first_seen: now,
+                        last_seen: now,
+                    }
+                });
+        }
+        state
+            .leaks
+            .retain(|_, leak| now.duration_since(leak.last_seen).unwrap_or_default() < LEAK_EXPIRY);
+        while state.leaks.len() > MAX_LEAKS {
+            let Some(oldest) = state
+                .leaks
+                .iter()
+                .min_by_key(|(_, leak)| leak.last_seen)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            state.leaks.remove(&oldest);
+        }
+        stat_set_num(ctx, "leaks", state.leaks.len() as f64);
+    }
+}
+
+/// Tells what kind of leak a flow that went around the tunnel to `remote` is, if any. Whitelisted bridges are meant to be reached directly. Queries to port 53 are DNS leaks, and so is everything else unless it's to the local network, which VPN mode leaves alone.
+fn leak_kind(remote: SocketAddr) -> Option<LeakKind> {
+    let ip = canonical(remote.ip());
+    if ip.is_loopback() || ip.is_unspecified() || vpn_whitelisted(ip) {
+        None
+    } else if remote.port() == 53 {
+        Some(LeakKind::Dns)
+    } else if is_local_network(ip) {
+        None
+    } else {
+        Some(LeakKind::Traffic)
+    }
+}
+
+#[cfg(not(any(target_os = "linux", all(target_os = "windows", feature = "windivert"))))]
+/// Elsewhere, the watchdog never starts, see [is_watching].
+mod unsupported {
+    use std::net::{IpAddr, SocketAddr};
+
+    pub fn leak_watch_start() -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    pub fn leaked_flows() -> Vec<(&'static str, IpAddr, SocketAddr)> {
+        vec![]
+    }
+
+    pub fn vpn_routing_active() -> bool {
+        false
+    }
+
+    pub fn vpn_whitelisted(_addr: IpAddr) -> bool {
+        false
+    }
+}
+
+/// Whether an address is on the local network, which VPN mode deliberately leaves alone.
+fn is_local_network(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            v4.is_private()
+                || v4.is_link_local()
+                || v4.is_multicast()
+                || v4.is_broadcast()
+                // carrier-grade NAT, 100.64.0.0/10
+                || (v4.octets()[0] == 100 && v4.octets()[1] & 0xc0 == 64)
+        }
+        IpAddr::V6(v6) => {
+            let first = v6.segments()[0];
+            // unique local, link-local, and multicast
+            first & 0xfe00 == 0xfc00 || first & 0xffc0 == 0xfe80 || v6.is_multicast()
+        }
+    }
+}
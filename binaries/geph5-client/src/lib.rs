@@ -1,23 +1,64 @@
 pub use broker::broker_client;
 pub use broker::BrokerSource;
+pub use benchmark::ExitBenchmark;
 pub use client::Client;
 pub use client::{BridgeMode, BrokerKeys, Config};
+pub use exit_health::ExitSwitch;
+pub use live_config::ConfigReload;
+pub use port_forward::{PortForward, PortForwardStatus};
+pub use reverse_forward::ReverseForward;
+pub use usage::UsageRecord;
+pub use dest_usage::DestinationUsage;
+pub use journal::{ConnectionEvent, ConnectionEventKind};
+pub use leaks::{Leak, LeakKind, LeakReport};
+pub use updates::{
+    apply_delta, apply_staged_update, UpdateBuild, UpdateConfig, UpdateDelta, UpdateManifest,
+};
 pub use control_prot::{ConnInfo, ControlClient};
-pub use route::ExitConstraint;
+pub use control_socket::ControlListen;
+pub use diagnostics::{DiagnosticCheck, DiagnosticsReport};
+pub use dns::DirectDns;
+pub use route::{CustomBridge, ExitConstraint, SshBridge, TransportFamily};
+pub use route_bundle::RouteBundleSource;
+pub use speed_test::SpeedTestResult;
 
+mod app_rules;
 mod auth;
+mod benchmark;
 mod broker;
-mod china;
 mod client;
 mod client_inner;
 mod control_prot;
+mod control_socket;
 mod database;
+mod dest_usage;
+mod diagnostics;
+mod dns;
+mod exit_health;
 mod http_proxy;
+mod journal;
+mod leaks;
+mod litecopy;
+mod live_config;
 pub mod logs;
+mod metrics;
+mod multihop;
+mod pac;
+mod port_forward;
 mod refresh_cell;
+mod reverse_forward;
 mod route;
+mod route_bundle;
+mod rules;
 mod socks5;
+mod speed_test;
 mod spoof_dns;
+mod stat_history;
 mod stats;
 mod taskpool;
+mod throttle;
+mod tproxy;
+mod udp;
+mod updates;
+mod usage;
 mod vpn;
@@ -0,0 +1,191 @@
+use futures_util::AsyncReadExt as _;
+use sillad::Pipe;
+use smol::future::FutureExt as _;
+
+/// Relays between a local connection and the connection opened for it, until either side is done. On Linux, data bound for a local TCP socket is moved with splice rather than copied through userspace: straight from the other socket when the connection bypasses the tunnel, or with vmsplice from the tunnel stream, which only exists in userspace.
+///
+/// Data from a local socket into the tunnel is still copied, since the tunnel needs it in userspace anyway to multiplex and encrypt it.
+pub async fn litecopy(local: impl Pipe, remote: impl Pipe) -> std::io::Result<()> {
+    #[cfg(target_os = "linux")]
+    if let Some(local_tcp) = local.as_tcp() {
+        if let Some(remote_tcp) = remote.as_tcp() {
+            splice_copy(remote_tcp, local_tcp)
+                .race(splice_copy(local_tcp, remote_tcp))
+                .await?;
+        } else {
+            let (read_remote, write_remote) = remote.split();
+            vmsplice_copy(read_remote, local_tcp)
+                .race(smol::io::copy(local_tcp, write_remote))
+                .await?;
+        }
+        return Ok(());
+    }
+    let (read_local, write_local) = local.split();
+    let (read_remote, write_remote) = remote.split();
+    smol::io::copy(read_remote, write_local)
+        .race(smol::io::copy(read_local, write_remote))
+        .await?;
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+async fn splice_copy(
+    from: &smol::Async<std::net::TcpStream>,
+    to: &smol::Async<std::net::TcpStream>,
+) -> std::io::Result<u64> {
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    const CHUNK: usize = 65536;
+
+    // splice needs a pipe on one side, so data goes from the socket into a pipe, and from there into the other socket
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let (pipe_read, pipe_write) =
+        unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+    let mut total = 0;
+    loop {
+        let n = from
+            .read_with(|sock| splice(sock.as_raw_fd(), pipe_write.as_raw_fd(), CHUNK))
+            .await?;
+        if n == 0 {
+            return Ok(total);
+        }
+        // the pipe must be drained before reading more, so that the next read never finds it full
+        let mut left = n;
+        while left > 0 {
+            let written = to
+                .write_with(|sock| splice(pipe_read.as_raw_fd(), sock.as_raw_fd(), left))
+                .await?;
+            if written == 0 {
+                return Err(std::io::ErrorKind::WriteZero.into());
+            }
+            left -= written;
+        }
+        total += n as u64;
+    }
+}
+
+/// Moves data from a userspace stream into a socket by reading it into pages that are then handed over to the socket through a pipe.
+#[cfg(target_os = "linux")]
+async fn vmsplice_copy(
+    mut from: impl futures_util::AsyncRead + Unpin,
+    to: &smol::Async<std::net::TcpStream>,
+) -> std::io::Result<u64> {
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+    // no more than a pipe holds by default, so that every chunk fits in the pipe at once
+    const CHUNK: usize = 65536;
+
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let (pipe_read, pipe_write) =
+        unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+
+    let mut total = 0;
+    loop {
+        // every chunk gets fresh pages, since the socket keeps referencing the ones it was given until they are acknowledged. Unmapping them is fine, as that only drops our reference.
+        let mut chunk = Mapping::new(CHUNK)?;
+        let n = from.read(chunk.as_mut_slice()).await?;
+        if n == 0 {
+            return Ok(total);
+        }
+        let mut offset = 0;
+        while offset < n {
+            let moved = vmsplice(pipe_write.as_raw_fd(), &chunk.as_mut_slice()[offset..n])?;
+            // as in splice_copy, the pipe is drained before anything more goes in
+            let mut left = moved;
+            while left > 0 {
+                let written = to
+                    .write_with(|sock| splice(pipe_read.as_raw_fd(), sock.as_raw_fd(), left))
+                    .await?;
+                if written == 0 {
+                    return Err(std::io::ErrorKind::WriteZero.into());
+                }
+                left -= written;
+            }
+            offset += moved;
+        }
+        total += n as u64;
+    }
+}
+
+/// Anonymous memory of our own, so that its pages are never reused for anything else while a socket might still be sending them.
+#[cfg(target_os = "linux")]
+struct Mapping {
+    ptr: *mut libc::c_void,
+    len: usize,
+}
+
+#[cfg(target_os = "linux")]
+unsafe impl Send for Mapping {}
+
+#[cfg(target_os = "linux")]
+impl Mapping {
+    fn new(len: usize) -> std::io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Self { ptr, len })
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.ptr as *mut u8, self.len) }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr, self.len);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn splice(from: i32, to: i32, len: usize) -> std::io::Result<usize> {
+    let n = unsafe {
+        libc::splice(
+            from,
+            std::ptr::null_mut(),
+            to,
+            std::ptr::null_mut(),
+            len,
+            libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+        )
+    };
+    if n < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn vmsplice(to: i32, buf: &[u8]) -> std::io::Result<usize> {
+    let iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    let n = unsafe { libc::vmsplice(to, &iov, 1, libc::SPLICE_F_NONBLOCK) };
+    if n < 0 {
+        Err(std::io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
@@ -0,0 +1,123 @@
+use std::{future::Future, sync::Arc};
+
+use anyctx::AnyCtx;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use smol::future::FutureExt as _;
+
+use crate::{client::CtxField, exit_health::reselect_exit, rules::reload_rule_lists, Config};
+
+/// Settings that [reload_config] applies to the running client. Everything else needs a restart.
+const HOT_FIELDS: &[&str] = &[
+    "socks5_listen",
+    "http_proxy_listen",
+    "tproxy_listen",
+    "dns_listen",
+    "metrics_listen",
+    "upload_limit_kbps",
+    "download_limit_kbps",
+    "pac_listen",
+    "reverse_forwards",
+    "app_rules",
+    "custom_bridges",
+    "custom_bridges_only",
+    "transport_preference",
+    "exit_constraint",
+    "intermediate_exit",
+    "passthrough_china",
+    "direct_dns",
+    "routing_rules",
+];
+
+/// What a config reload changed.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct ConfigReload {
+    /// Settings that now have their new values.
+    pub applied: Vec<String>,
+    /// Settings that changed, but keep their old values until the client is restarted.
+    pub needs_restart: Vec<String>,
+}
+
+static LIVE_CONFIG: CtxField<Mutex<Arc<Config>>> = |ctx| Mutex::new(Arc::new(ctx.init().clone()));
+
+static CONFIG_EVENT: CtxField<async_event::Event> = |_| async_event::Event::new();
+
+/// The config as of the last reload. Settings in [HOT_FIELDS] must be read from here rather than from `ctx.init()`.
+pub fn live_config(ctx: &AnyCtx<Config>) -> Arc<Config> {
+    ctx.get(LIVE_CONFIG).lock().clone()
+}
+
+/// Applies the hot-reloadable settings of a new config to the running client. Sessions stay up unless the exit constraint changed, in which case new connections go to a newly selected exit while the old sessions wind down.
+pub fn reload_config(ctx: &AnyCtx<Config>, new_cfg: Config) -> anyhow::Result<ConfigReload> {
+    let mut live = ctx.get(LIVE_CONFIG).lock();
+    let serde_json::Value::Object(mut merged) = serde_json::to_value(&**live)? else {
+        anyhow::bail!("config did not serialize to an object")
+    };
+    let serde_json::Value::Object(new_fields) = serde_json::to_value(&new_cfg)? else {
+        anyhow::bail!("config did not serialize to an object")
+    };
+    let mut reload = ConfigReload::default();
+    for (key, new_value) in new_fields {
+        // the running client's own settings were never in the file
+        if key == "dry_run" || merged.get(&key) == Some(&new_value) {
+            continue;
+        }
+        if HOT_FIELDS.contains(&key.as_str()) {
+            merged.insert(key.clone(), new_value);
+            reload.applied.push(key);
+        } else {
+            reload.needs_restart.push(key);
+        }
+    }
+    if reload.applied.is_empty() {
+        return Ok(reload);
+    }
+    *live = Arc::new(serde_json::from_value(merged.into())?);
+    drop(live);
+    tracing::info!(
+        applied = debug(&reload.applied),
+        needs_restart = debug(&reload.needs_restart),
+        "reloaded config"
+    );
+
+    if reload.applied.iter().any(|key| key == "routing_rules") {
+        reload_rule_lists(ctx);
+    }
+    if reload
+        .applied
+        .iter()
+        .any(|key| key == "exit_constraint" || key == "intermediate_exit")
+    {
+        reselect_exit(ctx, "exit constraint changed");
+    } else if reload
+        .applied
+        .iter()
+        .any(|key| key.starts_with("custom_bridges") || key == "transport_preference")
+    {
+        // rebuilds the dialer, and with it the bridges that new sessions use
+        reselect_exit(ctx, "bridge settings changed");
+    }
+    ctx.get(CONFIG_EVENT).notify_all();
+    Ok(reload)
+}
+
+/// Runs a listener loop, starting it over whenever the setting it is configured by changes. Connections that the old listener accepted may be dropped.
+pub async fn rerun_on_change<T: PartialEq, F: Future<Output = anyhow::Result<()>>>(
+    ctx: &AnyCtx<Config>,
+    setting: impl Fn(&Config) -> T,
+    mut run: impl FnMut() -> F,
+) -> anyhow::Result<()> {
+    loop {
+        let current = setting(&live_config(ctx));
+        let changed = async {
+            ctx.get(CONFIG_EVENT)
+                .wait_until(|| (setting(&live_config(ctx)) != current).then_some(()))
+                .await;
+            None
+        };
+        if let Some(res) = async { Some(run().await) }.or(changed).await {
+            return res;
+        }
+        tracing::info!("listen address changed, restarting listener");
+    }
+}
@@ -1,4 +1,135 @@
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Debug,
+    time::SystemTime,
+};
+
 use arc_writer::ArcWriter;
 use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use tracing::{field::Field, Event, Level, Subscriber};
+use tracing_subscriber::{layer::Context, Layer};
 
 pub static LOGS: Lazy<ArcWriter<Vec<u8>>> = Lazy::new(|| ArcWriter::new(vec![]));
+
+/// How many structured log events are kept.
+const MAX_EVENTS: usize = 10000;
+
+/// How many events [get_logs] returns at most, unless the filter asks for fewer.
+const MAX_PAGE: usize = 1000;
+
+static EVENTS: Lazy<Mutex<EventRing>> = Lazy::new(|| {
+    Mutex::new(EventRing {
+        events: VecDeque::new(),
+        next_seq: 1,
+    })
+});
+
+struct EventRing {
+    events: VecDeque<LogEvent>,
+    next_seq: u64,
+}
+
+/// One log event, as recorded by [LogEventLayer].
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LogEvent {
+    /// Increases by one with every event, so that it can be passed to [get_logs] to fetch the next page.
+    pub seq: u64,
+    pub time: SystemTime,
+    pub level: String,
+    /// The module the event came from.
+    pub target: String,
+    pub message: String,
+    pub fields: BTreeMap<String, String>,
+}
+
+/// Which log events to return.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct LogFilter {
+    /// The least severe level to include, such as "info". Everything is included if unset.
+    pub level: Option<String>,
+    /// Only include events from modules whose path starts with this.
+    pub module: Option<String>,
+    /// The most events to return.
+    pub limit: Option<usize>,
+}
+
+/// A tracing layer that keeps the latest log events in memory, for [get_logs] to return.
+pub struct LogEventLayer;
+
+impl<S: Subscriber> Layer<S> for LogEventLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut visitor = FieldVisitor::default();
+        event.record(&mut visitor);
+        let mut ring = EVENTS.lock();
+        let seq = ring.next_seq;
+        ring.next_seq += 1;
+        if ring.events.len() >= MAX_EVENTS {
+            ring.events.pop_front();
+        }
+        ring.events.push_back(LogEvent {
+            seq,
+            time: SystemTime::now(),
+            level: event.metadata().level().to_string(),
+            target: event.metadata().target().to_string(),
+            message: visitor.message,
+            fields: visitor.fields,
+        });
+    }
+}
+
+#[derive(Default)]
+struct FieldVisitor {
+    message: String,
+    fields: BTreeMap<String, String>,
+}
+
+impl tracing::field::Visit for FieldVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.fields
+                .insert(field.name().to_string(), value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "message" {
+            self.message = format!("{value:?}");
+        } else {
+            self.fields
+                .insert(field.name().to_string(), format!("{value:?}"));
+        }
+    }
+}
+
+/// Returns the recorded log events after the one numbered `since`, oldest first. Passing the last returned `seq` as `since` fetches the next page.
+pub fn get_logs(filter: &LogFilter, since: u64) -> Vec<LogEvent> {
+    let min_level: Option<Level> = filter.level.as_ref().and_then(|level| level.parse().ok());
+    let limit = filter.limit.unwrap_or(MAX_PAGE).min(MAX_PAGE);
+    EVENTS
+        .lock()
+        .events
+        .iter()
+        .filter(|event| event.seq > since)
+        .filter(|event| {
+            // more verbose levels compare greater
+            min_level.is_none_or(|min_level| {
+                event
+                    .level
+                    .parse::<Level>()
+                    .is_ok_and(|level| level <= min_level)
+            })
+        })
+        .filter(|event| {
+            filter
+                .module
+                .as_ref()
+                .is_none_or(|module| event.target.starts_with(module.as_str()))
+        })
+        .take(limit)
+        .cloned()
+        .collect()
+}
@@ -0,0 +1,120 @@
+use std::{fmt::Write as _, time::Duration};
+
+use anyctx::AnyCtx;
+use futures_util::{io::BufReader, AsyncBufReadExt as _, AsyncReadExt as _, AsyncWriteExt as _};
+use smol::net::{TcpListener, TcpStream};
+use smol_timeout2::TimeoutExt as _;
+
+use crate::{
+    control_prot::CURRENT_CONN_INFO, live_config::live_config, stats::stat_get_num, Config,
+    ConnInfo,
+};
+
+/// The stats that are exported, as (stat, Prometheus name, type, help text).
+const METRICS: &[(&str, &str, &str, &str)] = &[
+    (
+        "total_rx_bytes",
+        "geph5_client_rx_bytes_total",
+        "counter",
+        "Bytes received through the tunnel.",
+    ),
+    (
+        "total_tx_bytes",
+        "geph5_client_tx_bytes_total",
+        "counter",
+        "Bytes sent through the tunnel.",
+    ),
+    (
+        "active_sessions",
+        "geph5_client_active_sessions",
+        "gauge",
+        "Sessions currently open to an exit.",
+    ),
+    (
+        "dial_failures",
+        "geph5_client_dial_failures_total",
+        "counter",
+        "Failed attempts to connect and authenticate to an exit.",
+    ),
+    (
+        "exit_switches",
+        "geph5_client_exit_switches_total",
+        "counter",
+        "Automatic switches away from a degraded exit.",
+    ),
+    (
+        "ping",
+        "geph5_client_ping_seconds",
+        "gauge",
+        "Latest round-trip time to the exit.",
+    ),
+];
+
+/// Serves the client's stats on `/metrics` in the Prometheus text format.
+#[tracing::instrument(skip_all)]
+pub async fn metrics_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    if let Some(listen_addr) = live_config(ctx).metrics_listen {
+        let listener = TcpListener::bind(listen_addr).await?;
+        tracing::info!(addr = display(listen_addr), "start metrics server");
+        loop {
+            let (client, _) = listener.accept().await?;
+            let ctx = ctx.clone();
+            smolscale::spawn(async move {
+                if let Some(Err(err)) = serve(&ctx, client).timeout(Duration::from_secs(10)).await {
+                    tracing::debug!(err = debug(err), "metrics request failed");
+                }
+            })
+            .detach();
+        }
+    } else {
+        smol::future::pending().await
+    }
+}
+
+async fn serve(ctx: &AnyCtx<Config>, client: TcpStream) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(client.clone().take(8192));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    // skip the headers, since nothing in them matters to us
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", render(ctx)),
+        _ => ("404 Not Found", "not found\n".to_string()),
+    };
+    let mut client = client;
+    client
+        .write_all(
+            format!(
+                "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .as_bytes(),
+        )
+        .await?;
+    client.flush().await?;
+    Ok(())
+}
+
+fn render(ctx: &AnyCtx<Config>) -> String {
+    let mut out = String::new();
+    for (stat, name, kind, help) in METRICS {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        let _ = writeln!(out, "{name} {}", stat_get_num(ctx, stat));
+    }
+    let connected = matches!(*ctx.get(CURRENT_CONN_INFO).lock(), ConnInfo::Connected(_));
+    let _ = writeln!(
+        out,
+        "# HELP geph5_client_connected Whether a session to an exit is up."
+    );
+    let _ = writeln!(out, "# TYPE geph5_client_connected gauge");
+    let _ = writeln!(out, "geph5_client_connected {}", connected as u8);
+    out
+}
@@ -0,0 +1,86 @@
+use std::{net::SocketAddr, pin::Pin, sync::Arc, task::Poll};
+
+use anyctx::AnyCtx;
+use futures_util::{AsyncRead, AsyncReadExt as _, AsyncWrite};
+use picomux::PicoMux;
+use pin_project::pin_project;
+use sillad::Pipe;
+
+use crate::Config;
+
+/// Turns an authenticated connection to the intermediate exit into a raw connection to the final exit, relayed by the intermediate exit like any other TCP connection. The intermediate exit only ever sees encrypted traffic to the final exit, and the final exit never sees where we are connecting from.
+pub async fn relay_through(
+    ctx: &AnyCtx<Config>,
+    entry_pipe: impl Pipe,
+    final_c2e: SocketAddr,
+) -> anyhow::Result<HopPipe> {
+    let protocol = format!("multihop-{}", entry_pipe.protocol());
+    let remote_addr = entry_pipe.remote_addr().map(|s| s.to_string());
+    let (read, write) = entry_pipe.split();
+    let mux = Arc::new(PicoMux::new(read, write));
+    // the exit expects the first stream to carry the session metadata, just like in a normal session
+    mux.open(&serde_json::to_vec(&ctx.init().sess_metadata)?)
+        .await?;
+    let stream = mux.open(format!("tcp${final_c2e}").as_bytes()).await?;
+    Ok(HopPipe {
+        stream,
+        _mux: mux,
+        protocol,
+        remote_addr,
+    })
+}
+
+/// A stream to the final exit, carried inside a session with the intermediate exit. It keeps that session alive for as long as it exists.
+#[pin_project]
+pub struct HopPipe {
+    #[pin]
+    stream: picomux::Stream,
+    _mux: Arc<PicoMux>,
+    protocol: String,
+    remote_addr: Option<String>,
+}
+
+impl AsyncRead for HopPipe {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.project().stream.poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for HopPipe {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.project().stream.poll_write(cx, buf)
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.project().stream.poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.project().stream.poll_close(cx)
+    }
+}
+
+impl Pipe for HopPipe {
+    fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    // the address of the first hop, since that's the route that gets deprioritized when the chain fails
+    fn remote_addr(&self) -> Option<&str> {
+        self.remote_addr.as_deref()
+    }
+}
@@ -0,0 +1,155 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use anyctx::AnyCtx;
+use futures_util::{io::BufReader, AsyncBufReadExt as _, AsyncReadExt as _, AsyncWriteExt as _};
+use ipnet::IpNet;
+use serde_json::json;
+use smol::net::{TcpListener, TcpStream};
+use smol_timeout2::TimeoutExt as _;
+
+use crate::{
+    live_config::live_config,
+    rules::{listed_destinations, RuleAction},
+    Config,
+};
+
+const TEMPLATE: &str = include_str!("pac_template.js");
+
+/// Serves a proxy auto-config file on `/proxy.pac`, so that browsers can be pointed at a URL that stays the same while the rules change.
+#[tracing::instrument(skip_all)]
+pub async fn pac_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    if let Some(listen_addr) = live_config(ctx).pac_listen {
+        let listener = TcpListener::bind(listen_addr).await?;
+        tracing::info!(addr = display(listen_addr), "start PAC server");
+        loop {
+            let (client, _) = listener.accept().await?;
+            let ctx = ctx.clone();
+            smolscale::spawn(async move {
+                if let Some(Err(err)) = serve(&ctx, client).timeout(Duration::from_secs(10)).await {
+                    tracing::debug!(err = debug(err), "PAC request failed");
+                }
+            })
+            .detach();
+        }
+    } else {
+        smol::future::pending().await
+    }
+}
+
+async fn serve(ctx: &AnyCtx<Config>, client: TcpStream) -> anyhow::Result<()> {
+    let mut reader = BufReader::new(client.clone().take(8192));
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line).await?;
+    // the Host header tells us how the browser reaches us, which is also how it reaches the proxies
+    let mut request_host = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            let host = strip_port(value.trim());
+            if name.trim().eq_ignore_ascii_case("host")
+                && host
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-.:[]".contains(c))
+            {
+                request_host = Some(host.to_string());
+            }
+        }
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, content_type, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/proxy.pac")) => (
+            "200 OK",
+            "application/x-ns-proxy-autoconfig",
+            generate_pac(ctx, request_host.as_deref()),
+        ),
+        _ => ("404 Not Found", "text/plain", "not found\n".to_string()),
+    };
+    let mut client = client;
+    client
+        .write_all(
+            format!(
+                "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nCache-Control: no-cache\r\nConnection: close\r\n\r\n{body}",
+                body.len()
+            )
+            .as_bytes(),
+        )
+        .await?;
+    client.flush().await?;
+    Ok(())
+}
+
+/// Fills in the PAC template with the current rule lists, sending everything else to the configured HTTP and SOCKS5 proxies.
+fn generate_pac(ctx: &AnyCtx<Config>, request_host: Option<&str>) -> String {
+    let config = live_config(ctx);
+    let mut proxies = vec![];
+    if let Some(listen) = config.http_proxy_listen {
+        proxies.push(format!("PROXY {}", proxy_addr(listen, request_host)));
+    }
+    if let Some(listen) = config.socks5_listen {
+        proxies.push(format!("SOCKS5 {}", proxy_addr(listen, request_host)));
+    }
+    let proxy = if proxies.is_empty() {
+        "DIRECT".to_string()
+    } else {
+        proxies.join("; ")
+    };
+
+    // blocked destinations still go to the proxy, which refuses them
+    let lists: Vec<serde_json::Value> = listed_destinations(ctx)
+        .into_iter()
+        .map(|list| {
+            let domains: serde_json::Map<String, serde_json::Value> = list
+                .domains
+                .into_iter()
+                .map(|domain| (domain, json!(1)))
+                .collect();
+            // PAC files can only match IPv4 ranges
+            let nets: Vec<serde_json::Value> = list
+                .nets
+                .iter()
+                .filter_map(|net| match net {
+                    IpNet::V4(net) => Some(json!([net.network(), net.netmask()])),
+                    IpNet::V6(_) => None,
+                })
+                .collect();
+            json!({
+                "direct": list.action == RuleAction::Direct,
+                "domains": domains,
+                "nets": nets,
+            })
+        })
+        .collect();
+    TEMPLATE
+        .replace("{{PROXY}}", &proxy)
+        .replace("{{LISTS}}", &serde_json::Value::from(lists).to_string())
+}
+
+/// The address that browsers should use for a proxy listening on `listen`.
+fn proxy_addr(listen: SocketAddr, request_host: Option<&str>) -> String {
+    let host = if listen.ip().is_unspecified() {
+        request_host.unwrap_or("127.0.0.1").to_string()
+    } else {
+        match listen.ip() {
+            IpAddr::V4(ip) => ip.to_string(),
+            IpAddr::V6(ip) => format!("[{ip}]"),
+        }
+    };
+    format!("{host}:{}", listen.port())
+}
+
+fn strip_port(host: &str) -> &str {
+    if host.starts_with('[') {
+        host.split_once(']')
+            .map(|(ip, _)| &host[..ip.len() + 1])
+            .unwrap_or(host)
+    } else {
+        host.split(':').next().unwrap_or(host)
+    }
+}
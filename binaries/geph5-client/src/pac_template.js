@@ -0,0 +1,51 @@
+// Generated by geph5-client. Rule lists are checked in order, and the first one that matches decides.
+var LISTS = {{LISTS}};
+var PROXY = "{{PROXY}}";
+
+function isIpv4(host) {
+  return /^\d+\.\d+\.\d+\.\d+$/.test(host);
+}
+
+function listMatches(list, host) {
+  if (isIpv4(host)) {
+    for (var i = 0; i < list.nets.length; i++) {
+      if (isInNet(host, list.nets[i][0], list.nets[i][1])) {
+        return true;
+      }
+    }
+    return false;
+  }
+  var candidate = host;
+  while (true) {
+    if (list.domains.hasOwnProperty(candidate)) {
+      return true;
+    }
+    var dot = candidate.indexOf(".");
+    if (dot < 0) {
+      return false;
+    }
+    candidate = candidate.substring(dot + 1);
+  }
+}
+
+function FindProxyForURL(url, host) {
+  host = host.toLowerCase();
+  if (
+    isPlainHostName(host) ||
+    shExpMatch(host, "*.local") ||
+    (isIpv4(host) &&
+      (isInNet(host, "127.0.0.0", "255.0.0.0") ||
+        isInNet(host, "10.0.0.0", "255.0.0.0") ||
+        isInNet(host, "172.16.0.0", "255.240.0.0") ||
+        isInNet(host, "192.168.0.0", "255.255.0.0") ||
+        isInNet(host, "169.254.0.0", "255.255.0.0")))
+  ) {
+    return "DIRECT";
+  }
+  for (var i = 0; i < LISTS.length; i++) {
+    if (listMatches(LISTS[i], host)) {
+      return LISTS[i].direct ? "DIRECT" : PROXY;
+    }
+  }
+  return PROXY;
+}
@@ -0,0 +1,164 @@
+use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+use anyctx::AnyCtx;
+use dashmap::DashMap;
+use nursery_macro::nursery;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sillad::listener::Listener as _;
+use smol::lock::OnceCell;
+
+use crate::{
+    client::CtxField,
+    client_inner::open_conn,
+    database::{db_read, db_write},
+    litecopy::litecopy,
+    taskpool::{add_task, TaskPriority},
+    Config,
+};
+
+/// A local port whose connections are forwarded through the tunnel to a fixed remote address.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PortForward {
+    pub listen: SocketAddr,
+    /// Where connections go, as host:port.
+    pub remote: String,
+}
+
+/// A port forward, along with whether it's working.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PortForwardStatus {
+    pub forward: PortForward,
+    /// Why the forward is not listening, if it isn't.
+    pub error: Option<String>,
+}
+
+const FORWARDS_KEY: &str = "port_forwards";
+
+static FORWARDS: CtxField<OnceCell<Mutex<Vec<PortForward>>>> = |_| OnceCell::new();
+
+static FORWARD_ERRORS: CtxField<DashMap<SocketAddr, String>> = |_| DashMap::new();
+
+static FORWARDS_EVENT: CtxField<async_event::Event> = |_| async_event::Event::new();
+
+/// The configured forwards, loaded from the database the first time they're needed.
+async fn forwards(ctx: &AnyCtx<Config>) -> anyhow::Result<&Mutex<Vec<PortForward>>> {
+    ctx.get(FORWARDS)
+        .get_or_try_init(|| async {
+            let forwards = match db_read(ctx, FORWARDS_KEY).await? {
+                Some(bts) => serde_json::from_slice(&bts)?,
+                None => vec![],
+            };
+            anyhow::Ok(Mutex::new(forwards))
+        })
+        .await
+}
+
+async fn save_forwards(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    let forwards = forwards(ctx).await?.lock().clone();
+    db_write(ctx, FORWARDS_KEY, &serde_json::to_vec(&forwards)?).await?;
+    ctx.get(FORWARDS_EVENT).notify_all();
+    Ok(())
+}
+
+/// Adds a port forward, which persists across restarts until removed.
+pub async fn add_port_forward(ctx: &AnyCtx<Config>, forward: PortForward) -> anyhow::Result<()> {
+    {
+        let mut forwards = forwards(ctx).await?.lock();
+        if forwards.iter().any(|fwd| fwd.listen == forward.listen) {
+            anyhow::bail!("already forwarding {}", forward.listen)
+        }
+        forwards.push(forward);
+    }
+    save_forwards(ctx).await
+}
+
+/// Removes the port forward listening on `listen`.
+pub async fn remove_port_forward(ctx: &AnyCtx<Config>, listen: SocketAddr) -> anyhow::Result<()> {
+    {
+        let mut forwards = forwards(ctx).await?.lock();
+        let before = forwards.len();
+        forwards.retain(|fwd| fwd.listen != listen);
+        if forwards.len() == before {
+            anyhow::bail!("not forwarding {listen}")
+        }
+    }
+    ctx.get(FORWARD_ERRORS).remove(&listen);
+    save_forwards(ctx).await
+}
+
+/// Lists the port forwards.
+pub async fn list_port_forwards(ctx: &AnyCtx<Config>) -> anyhow::Result<Vec<PortForwardStatus>> {
+    let forwards = forwards(ctx).await?.lock().clone();
+    Ok(forwards
+        .into_iter()
+        .map(|forward| PortForwardStatus {
+            error: ctx
+                .get(FORWARD_ERRORS)
+                .get(&forward.listen)
+                .map(|err| err.clone()),
+            forward,
+        })
+        .collect())
+}
+
+/// Keeps the running forwards in line with the configured ones.
+pub async fn port_forward_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    let mut running: HashMap<PortForward, smol::Task<()>> = HashMap::new();
+    loop {
+        let wanted = forwards(ctx).await?.lock().clone();
+        running.retain(|fwd, _| wanted.contains(fwd));
+        for fwd in wanted.iter() {
+            if !running.contains_key(fwd) {
+                tracing::info!(
+                    listen = display(fwd.listen),
+                    remote = display(&fwd.remote),
+                    "starting port forward"
+                );
+                running.insert(
+                    fwd.clone(),
+                    smolscale::spawn(forward_loop(ctx.clone(), fwd.clone())),
+                );
+            }
+        }
+        ctx.get(FORWARDS_EVENT)
+            .wait_until(|| {
+                ctx.get(FORWARDS)
+                    .get()
+                    .is_some_and(|forwards| *forwards.lock() != wanted)
+                    .then_some(())
+            })
+            .await;
+    }
+}
+
+async fn forward_loop(ctx: AnyCtx<Config>, forward: PortForward) {
+    loop {
+        if let Err(err) = forward_once(&ctx, &forward).await {
+            tracing::warn!(
+                listen = display(forward.listen),
+                err = debug(&err),
+                "port forward failed, retrying"
+            );
+            ctx.get(FORWARD_ERRORS)
+                .insert(forward.listen, format!("{err:?}"));
+        }
+        smol::Timer::after(Duration::from_secs(5)).await;
+    }
+}
+
+async fn forward_once(ctx: &AnyCtx<Config>, forward: &PortForward) -> anyhow::Result<()> {
+    let mut listener = sillad::tcp::TcpListener::bind(forward.listen).await?;
+    ctx.get(FORWARD_ERRORS).remove(&forward.listen);
+    nursery!({
+        loop {
+            let client = listener.accept().await?;
+            let task = spawn!(async {
+                let stream = open_conn(ctx, "tcp", &forward.remote).await?;
+                litecopy(client, stream).await?;
+                anyhow::Ok(())
+            });
+            add_task(ctx, TaskPriority::Normal, task);
+        }
+    })
+}
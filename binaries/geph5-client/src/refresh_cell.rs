@@ -101,4 +101,10 @@ impl<T: Clone + Send + 'static> RefreshCell<T> {
         }
         self.inner.lock().clone().unwrap()
     }
+
+    /// Schedules a refresh right away, without waiting for the value to get out of date.
+    pub fn force_refresh(&self) {
+        *self.last_refresh_start.lock() = SystemTime::now();
+        let _ = self.force_refresh.try_send(());
+    }
 }
@@ -0,0 +1,94 @@
+use std::{net::SocketAddr, time::Duration};
+
+use anyctx::AnyCtx;
+use futures_util::{future::select_all, AsyncReadExt as _};
+use nursery_macro::nursery;
+use serde::{Deserialize, Serialize};
+use sillad::{dialer::Dialer as _, tcp::TcpDialer};
+
+use crate::{
+    client_inner::open_exit_stream,
+    control_prot::{ConnInfo, CURRENT_CONN_INFO},
+    litecopy::litecopy,
+    live_config::live_config,
+    Config,
+};
+
+/// A port on the exit whose inbound connections are forwarded back through the tunnel to a local service, so that the service can be reached from outside without a public address of its own.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct ReverseForward {
+    /// The port to listen on at the exit, or 0 for whichever port the exit picks.
+    pub exit_port: u16,
+    /// Where inbound connections go.
+    pub local: SocketAddr,
+}
+
+pub async fn reverse_forward_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    let forwards = live_config(ctx).reverse_forwards.clone();
+    if forwards.is_empty() {
+        return smol::future::pending().await;
+    }
+    select_all(
+        forwards
+            .into_iter()
+            .map(|forward| Box::pin(reverse_forward_retry(ctx, forward))),
+    )
+    .await
+    .0
+}
+
+async fn reverse_forward_retry(
+    ctx: &AnyCtx<Config>,
+    forward: ReverseForward,
+) -> anyhow::Result<()> {
+    loop {
+        if let Err(err) = reverse_forward_once(ctx, &forward).await {
+            tracing::warn!(
+                exit_port = forward.exit_port,
+                local = display(forward.local),
+                err = debug(err),
+                "reverse forward failed, retrying"
+            );
+        }
+        smol::Timer::after(Duration::from_secs(5)).await;
+    }
+}
+
+async fn reverse_forward_once(
+    ctx: &AnyCtx<Config>,
+    forward: &ReverseForward,
+) -> anyhow::Result<()> {
+    let mut control = open_exit_stream(ctx, format!("rlisten${}", forward.exit_port)).await?;
+    let mut port_buf = [0u8; 2];
+    control.read_exact(&mut port_buf).await?;
+    let exit_port = u16::from_le_bytes(port_buf);
+    let exit_host = match &*ctx.get(CURRENT_CONN_INFO).lock() {
+        ConnInfo::Connected(info) => info.exit.b2e_listen.ip().to_string(),
+        ConnInfo::Connecting => "(unknown)".to_string(),
+    };
+    tracing::info!(
+        exit_host = display(exit_host),
+        exit_port,
+        local = display(forward.local),
+        "exit is listening for reverse forwarding"
+    );
+
+    nursery!({
+        loop {
+            let mut id_buf = [0u8; 8];
+            control.read_exact(&mut id_buf).await?;
+            let id = u64::from_le_bytes(id_buf);
+            spawn!(async move {
+                let stream = open_exit_stream(ctx, format!("raccept${id}")).await?;
+                let local = TcpDialer {
+                    dest_addr: forward.local,
+                }
+                .dial()
+                .await?;
+                litecopy(local, stream).await?;
+                anyhow::Ok(())
+            })
+            .detach();
+        }
+    })
+}
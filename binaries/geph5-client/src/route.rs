@@ -1,5 +1,6 @@
 use std::{
-    net::SocketAddr,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
     time::{Duration, SystemTime},
 };
 
@@ -9,7 +10,7 @@ use anyhow::Context;
 use ed25519_dalek::VerifyingKey;
 use futures_util::TryFutureExt as _;
 use geph5_broker_protocol::{
-    AccountLevel, ExitDescriptor, RouteDescriptor, DOMAIN_EXIT_DESCRIPTOR,
+    AccountLevel, ExitDescriptor, ExitList, RouteDescriptor, Signed, DOMAIN_EXIT_DESCRIPTOR,
 };
 use isocountry::CountryCode;
 use moka::sync::Cache;
@@ -21,13 +22,21 @@ use sillad::{
     dialer::{DialerExt, DynDialer, FailingDialer},
     tcp::TcpDialer,
 };
+use sillad_dns::DnsDialer;
+use sillad_icmp::IcmpDialer;
+use sillad_kcp::KcpDialer;
 use sillad_sosistab3::{dialer::SosistabDialer, Cookie};
+use sillad_ssh::{SshAuth, SshConfig, SshDialer};
 
 use crate::{
     auth::get_connect_token,
     broker::broker_client,
     client::{Config, CtxField},
-    client_inner::CONCURRENCY,
+    client_inner::session_count,
+    database::{db_read, db_write},
+    exit_health::is_avoided,
+    live_config::live_config,
+    route_bundle::route_bundle,
     vpn::vpn_whitelist,
 };
 
@@ -37,8 +46,8 @@ static ROUTE_SHITLIST: Lazy<Cache<SocketAddr, usize>> = Lazy::new(|| {
         .build()
 });
 
-fn shitlist_delay(addr: SocketAddr) -> Duration {
-    let recent_deaths = ROUTE_SHITLIST.get(&addr).unwrap_or_default() as f64 / CONCURRENCY as f64;
+fn shitlist_delay(addr: SocketAddr, sessions: usize) -> Duration {
+    let recent_deaths = ROUTE_SHITLIST.get(&addr).unwrap_or_default() as f64 / sessions as f64;
     Duration::from_secs_f64((recent_deaths - 1.0).max(0.0).powi(2) / 10.0)
 }
 
@@ -57,6 +66,107 @@ pub enum ExitConstraint {
     CountryCity(CountryCode, String),
 }
 
+/// The user's own SSH server, used instead of the broker's bridges to reach exits.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SshBridge {
+    /// The SSH server, as host:port.
+    pub server: String,
+    pub user: String,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// An unencrypted Ed25519 private key in the OpenSSH format, used instead of the password.
+    #[serde(default)]
+    pub private_key_file: Option<PathBuf>,
+    /// The fingerprint of the server's host key, like `SHA256:...` from `ssh-keygen -lf`. This is required, since without it a censor in the middle could pose as the server and collect the password.
+    pub host_key: String,
+}
+
+/// A bridge that doesn't come from the broker, such as one run privately.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CustomBridge {
+    /// The address of the exit that the bridge leads to. The bridge is used for every exit if this is missing.
+    #[serde(default)]
+    pub exit: Option<IpAddr>,
+    /// How to reach the bridge, as a transport stack like the ones the broker hands out.
+    pub route: RouteDescriptor,
+}
+
+/// A family of transports that bridge routes can be narrowed down to, for debugging or for networks that reliably block the others. A route belongs to a family if that transport appears anywhere in its stack.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TransportFamily {
+    /// Routes obfuscated with sosistab3, whatever carries them.
+    Sosistab3,
+    /// Routes carried over TCP, including connecting to the exit directly.
+    Tcp,
+    Kcp,
+    Dns,
+    Icmp,
+}
+
+/// Prunes everything that doesn't belong to `family` out of a route, returning None if nothing is left.
+fn narrow_route(route: &RouteDescriptor, family: TransportFamily) -> Option<RouteDescriptor> {
+    let narrow_all = |routes: &[RouteDescriptor]| {
+        let narrowed: Vec<_> = routes
+            .iter()
+            .filter_map(|route| narrow_route(route, family))
+            .collect();
+        (!narrowed.is_empty()).then_some(narrowed)
+    };
+    match route {
+        RouteDescriptor::Race(inside) => narrow_all(inside).map(RouteDescriptor::Race),
+        RouteDescriptor::Fallback(inside) => narrow_all(inside).map(RouteDescriptor::Fallback),
+        RouteDescriptor::Timeout {
+            milliseconds,
+            lower,
+        } => Some(RouteDescriptor::Timeout {
+            milliseconds: *milliseconds,
+            lower: Box::new(narrow_route(lower, family)?),
+        }),
+        RouteDescriptor::Delay {
+            milliseconds,
+            lower,
+        } => Some(RouteDescriptor::Delay {
+            milliseconds: *milliseconds,
+            lower: Box::new(narrow_route(lower, family)?),
+        }),
+        RouteDescriptor::Sosistab3 { .. } if family == TransportFamily::Sosistab3 => {
+            Some(route.clone())
+        }
+        RouteDescriptor::Sosistab3 { cookie, lower } => Some(RouteDescriptor::Sosistab3 {
+            cookie: cookie.clone(),
+            lower: Box::new(narrow_route(lower, family)?),
+        }),
+        RouteDescriptor::Tcp(_) => (family == TransportFamily::Tcp).then(|| route.clone()),
+        RouteDescriptor::Kcp(_) => (family == TransportFamily::Kcp).then(|| route.clone()),
+        RouteDescriptor::Dns { .. } => (family == TransportFamily::Dns).then(|| route.clone()),
+        RouteDescriptor::Icmp(_) => (family == TransportFamily::Icmp).then(|| route.clone()),
+        RouteDescriptor::Other(_) => None,
+    }
+}
+
+/// Gets a dialer that reaches the given exit address through the SSH bridge.
+async fn ssh_bridge_dialer(bridge: &SshBridge, exit: SocketAddr) -> anyhow::Result<DynDialer> {
+    let auth = match (&bridge.private_key_file, &bridge.password) {
+        (Some(path), _) => SshAuth::from_openssh_key(&smol::fs::read_to_string(path).await?)?,
+        (None, Some(password)) => SshAuth::Password(password.clone()),
+        (None, None) => anyhow::bail!("SSH bridge needs either a password or a private key"),
+    };
+    let config = SshConfig::new(&bridge.user, auth, &bridge.host_key);
+    let server = *smol::net::resolve(&bridge.server)
+        .await?
+        .first()
+        .context("could not resolve the SSH bridge")?;
+    vpn_whitelist(server.ip());
+    Ok(SshDialer::new(
+        TcpDialer { dest_addr: server },
+        config,
+        exit.ip().to_string(),
+        exit.port(),
+    )
+    .dynamic())
+}
+
 type DialerWithInfo = (VerifyingKey, ExitDescriptor, DynDialer);
 
 pub async fn get_cached_dialer(ctx: &AnyCtx<Config>) -> anyhow::Result<DialerWithInfo> {
@@ -72,14 +182,91 @@ pub async fn get_cached_dialer(ctx: &AnyCtx<Config>) -> anyhow::Result<DialerWit
     todo!()
 }
 
-/// Gets a sillad Dialer that produces a single, pre-authentication pipe, as well as the public key.
-pub async fn get_dialer(
+/// Gets the verified list of exits that our account level may use. When the broker can't be reached, the last list it gave us is used, and failing that, the route bundle.
+pub async fn list_exits(
     ctx: &AnyCtx<Config>,
-) -> anyhow::Result<(VerifyingKey, ExitDescriptor, DynDialer)> {
+) -> anyhow::Result<Vec<(VerifyingKey, ExitDescriptor)>> {
+    let (level, _, _) = get_connect_token(ctx)
+        .await
+        .context("could not get connect token")?;
+    let cache_key = format!("cached_exits_{level:?}");
+
+    let broker_exits = async {
+        let broker = broker_client(ctx).context("could not get broker client")?;
+        let exits = match level {
+            AccountLevel::Plus => broker.get_exits().await,
+            AccountLevel::Free => broker.get_free_exits().await,
+        }?
+        .map_err(|e| anyhow::anyhow!("broker refused to serve exits: {e}"))?;
+        let verified = verify_exits(ctx, exits.clone())?;
+        // the list is cached still signed, so that it is checked again when loaded
+        db_write(ctx, &cache_key, &serde_json::to_vec(&exits)?).await?;
+        anyhow::Ok(verified)
+    };
+    let err = match broker_exits.await {
+        Ok(exits) => return Ok(exits),
+        Err(err) => err,
+    };
+
+    let cached = async {
+        let cached = db_read(ctx, &cache_key)
+            .await?
+            .context("no exit list was ever cached")?;
+        verify_exits(ctx, serde_json::from_slice(&cached)?)
+    };
+    match cached.await {
+        Ok(exits) => {
+            tracing::warn!(
+                err = debug(err),
+                "could not get exits from the broker, using the cached list"
+            );
+            return Ok(exits);
+        }
+        Err(cache_err) => tracing::debug!(err = debug(cache_err), "no usable cached exit list"),
+    }
+
+    match route_bundle(ctx).await {
+        Ok(Some(bundle)) => {
+            tracing::warn!(
+                err = debug(err),
+                "could not get exits from the broker, using the route bundle"
+            );
+            Ok(bundle.exits)
+        }
+        Ok(None) => Err(err),
+        Err(bundle_err) => {
+            tracing::warn!(err = debug(bundle_err), "route bundle is unusable too");
+            Err(err)
+        }
+    }
+}
+
+fn verify_exits(
+    ctx: &AnyCtx<Config>,
+    exits: Signed<ExitList>,
+) -> anyhow::Result<Vec<(VerifyingKey, ExitDescriptor)>> {
+    let exits = exits
+        .verify(DOMAIN_EXIT_DESCRIPTOR, |their_pk| {
+            if let Some(broker_pk) = &ctx.init().broker_keys {
+                hex::encode(their_pk.as_bytes()) == broker_pk.master
+            } else {
+                true
+            }
+        })
+        .context("could not verify")?;
+    Ok(exits.all_exits)
+}
+
+/// Picks an exit that satisfies the constraint, other than `exclude`. Exits we recently switched away from are only picked if nothing else fits.
+async fn select_exit(
+    ctx: &AnyCtx<Config>,
+    constraint: &ExitConstraint,
+    exclude: Option<&VerifyingKey>,
+) -> anyhow::Result<(VerifyingKey, ExitDescriptor)> {
     let mut country_constraint = None;
     let mut city_constraint = None;
     let mut hostname_constraint = None;
-    match &ctx.init().exit_constraint {
+    match constraint {
         ExitConstraint::Direct(dir) => {
             let (dir, pubkey) = dir
                 .split_once('/')
@@ -95,18 +282,16 @@ pub async fn get_dialer(
                 .await?
                 .choose(&mut rand::thread_rng())
                 .context("could not resolve destination for direct exit connection")?;
-            vpn_whitelist(dest_addr.ip());
             return Ok((
                 pubkey,
                 ExitDescriptor {
-                    c2e_listen: "0.0.0.0:0".parse()?,
+                    c2e_listen: dest_addr,
                     b2e_listen: "0.0.0.0:0".parse()?,
                     country: CountryCode::ABW,
                     city: "".to_string(),
                     load: 0.0,
                     expiry: 0,
                 },
-                TcpDialer { dest_addr }.dynamic(),
             ));
         }
         ExitConstraint::Country(country) => country_constraint = Some(*country),
@@ -120,31 +305,11 @@ pub async fn get_dialer(
         ExitConstraint::Auto => {}
     }
 
-    // First get the conn token
-    let (level, conn_token, sig) = get_connect_token(ctx)
-        .await
-        .context("could not get connect token")?;
-
-    let broker = broker_client(ctx).context("could not get broker client")?;
-    let exits = match level {
-        AccountLevel::Plus => broker.get_exits().await,
-        AccountLevel::Free => broker.get_free_exits().await,
-    }?
-    .map_err(|e| anyhow::anyhow!("broker refused to serve exits: {e}"))?;
-
-    let exits = exits
-        .verify(DOMAIN_EXIT_DESCRIPTOR, |their_pk| {
-            if let Some(broker_pk) = &ctx.init().broker_keys {
-                hex::encode(their_pk.as_bytes()) == broker_pk.master
-            } else {
-                true
-            }
-        })
-        .context("could not verify")?;
+    let exits = list_exits(ctx).await?;
+    let candidates = exits.iter().filter(|(pubkey, _)| Some(pubkey) != exclude);
     // filter for things that fit
-    let (pubkey, exit) = if let Some(min) = exits
-        .all_exits
-        .iter()
+    let (pubkey, exit) = if let Some(min) = candidates
+        .clone()
         .filter(|(_, exit)| {
             let country_pass = if let Some(country) = &country_constraint {
                 exit.country == *country
@@ -163,49 +328,246 @@ pub async fn get_dialer(
             };
             country_pass && city_pass && hostname_pass
         })
-        .min_by_key(|e| (e.1.load * 1000.0) as u64)
+        .min_by_key(|e| (is_avoided(ctx, &e.0), (e.1.load * 1000.0) as u64))
     {
         min
     } else {
-        exits
-            .all_exits
-            .iter()
-            .min_by_key(|e| (e.1.load * 1000.0) as u64)
+        candidates
+            .min_by_key(|e| (is_avoided(ctx, &e.0), (e.1.load * 1000.0) as u64))
             .context("no exits that fit the criterion")?
     };
+    Ok((*pubkey, exit.clone()))
+}
+
+/// Gets the exit at the far end of a multi-hop chain, which is reached by relaying through the exit that [get_dialer] connects to. Returns None unless an intermediate exit is configured.
+pub async fn get_final_hop(
+    ctx: &AnyCtx<Config>,
+    entry_pubkey: &VerifyingKey,
+) -> anyhow::Result<Option<(VerifyingKey, ExitDescriptor)>> {
+    let config = live_config(ctx);
+    if config.intermediate_exit.is_none() {
+        return Ok(None);
+    }
+    let (pubkey, exit) = select_exit(ctx, &config.exit_constraint, Some(entry_pubkey)).await?;
+    tracing::debug!(exit = debug(&exit), "narrowed down choice of final exit");
+    Ok(Some((pubkey, exit)))
+}
+
+/// Gets a sillad Dialer that produces a single, pre-authentication pipe, as well as the public key. With multi-hop, this is the intermediate exit.
+pub async fn get_dialer(
+    ctx: &AnyCtx<Config>,
+) -> anyhow::Result<(VerifyingKey, ExitDescriptor, DynDialer)> {
+    let config = live_config(ctx);
+    let constraint = config
+        .intermediate_exit
+        .as_ref()
+        .unwrap_or(&config.exit_constraint);
+    let (pubkey, exit) = select_exit(ctx, constraint, None).await?;
+    if let ExitConstraint::Direct(_) = constraint {
+        let dest_addr = exit.c2e_listen;
+        vpn_whitelist(dest_addr.ip());
+        let custom_routes = custom_bridge_routes(ctx, &exit);
+        let dialer = match &ctx.init().ssh_bridge {
+            Some(bridge) => ssh_bridge_dialer(bridge, dest_addr).await?,
+            // direct exits don't go through the broker, so custom bridges are the only bridges they can have
+            None if !custom_routes.is_empty() => with_bridge_mode(
+                ctx,
+                TcpDialer { dest_addr }.dynamic(),
+                route_to_dialer(ctx, &RouteDescriptor::Race(custom_routes)),
+            ),
+            None => TcpDialer { dest_addr }.dynamic(),
+        };
+        return Ok((pubkey, exit, dialer));
+    }
+    let pubkey = &pubkey;
+    let exit = &exit;
 
     tracing::debug!(exit = debug(&exit), "narrowed down choice of exit");
     vpn_whitelist(exit.c2e_listen.ip());
 
     let exit_c2e = exit.c2e_listen;
+    let sessions = session_count(ctx);
     let direct_dialer = TcpDialer {
         dest_addr: exit_c2e,
     }
-    .dyn_delay(move || shitlist_delay(exit_c2e));
+    .dyn_delay(move || shitlist_delay(exit_c2e, sessions));
 
-    tracing::debug!(token = debug(&conn_token), sig = debug(&sig), "CONN TOKEN");
+    if let Some(bridge) = &ctx.init().ssh_bridge {
+        return Ok((
+            *pubkey,
+            exit.clone(),
+            ssh_bridge_dialer(bridge, exit_c2e).await?,
+        ));
+    }
 
     // also get bridges
-    let bridge_routes = broker
-        .get_routes(conn_token, sig, exit.b2e_listen)
-        .await?
-        .map_err(|e| anyhow::anyhow!("broker refused to serve bridge routes: {e}"))?;
+    let bridge_routes = bridge_routes(ctx, exit).await?;
     tracing::debug!(
         bridge_routes = debug(&bridge_routes),
         "bridge routes obtained too"
     );
 
-    let bridge_dialer = route_to_dialer(&bridge_routes);
+    let bridge_dialer = route_to_dialer(ctx, &bridge_routes);
+
+    let final_dialer = with_bridge_mode(ctx, direct_dialer.dynamic(), bridge_dialer);
+
+    Ok((*pubkey, exit.clone(), final_dialer))
+}
 
-    let final_dialer = match ctx.init().bridge_mode {
+fn with_bridge_mode(
+    ctx: &AnyCtx<Config>,
+    direct_dialer: DynDialer,
+    bridge_dialer: DynDialer,
+) -> DynDialer {
+    // connecting directly is plain TCP, so it's out if another family is preferred
+    if live_config(ctx)
+        .transport_preference
+        .is_some_and(|family| family != TransportFamily::Tcp)
+    {
+        return bridge_dialer;
+    }
+    match ctx.init().bridge_mode {
         crate::BridgeMode::Auto => direct_dialer
             .race(bridge_dialer.delay(Duration::from_millis(1000)))
             .dynamic(),
         crate::BridgeMode::ForceBridges => bridge_dialer,
-        crate::BridgeMode::ForceDirect => direct_dialer.dynamic(),
+        crate::BridgeMode::ForceDirect => direct_dialer,
+    }
+}
+
+/// The custom bridges configured for `exit`.
+fn custom_bridge_routes(ctx: &AnyCtx<Config>, exit: &ExitDescriptor) -> Vec<RouteDescriptor> {
+    live_config(ctx)
+        .custom_bridges
+        .iter()
+        .filter(|bridge| {
+            bridge
+                .exit
+                .is_none_or(|ip| ip == exit.c2e_listen.ip() || ip == exit.b2e_listen.ip())
+        })
+        .map(|bridge| bridge.route.clone())
+        .collect()
+}
+
+/// The bridges to `exit`, narrowed down to the preferred transport family if there is one.
+async fn bridge_routes(
+    ctx: &AnyCtx<Config>,
+    exit: &ExitDescriptor,
+) -> anyhow::Result<RouteDescriptor> {
+    let routes = all_bridge_routes(ctx, exit).await?;
+    match live_config(ctx).transport_preference {
+        Some(family) => {
+            narrow_route(&routes, family).with_context(|| format!("no bridges use {family:?}"))
+        }
+        None => Ok(routes),
+    }
+}
+
+/// All the bridges to `exit`: those from the broker, unless `custom_bridges_only` is set, together with the matching custom bridges. When the broker can't be reached, the routes it last gave us, the custom bridges, and those in the route bundle are used instead.
+async fn all_bridge_routes(
+    ctx: &AnyCtx<Config>,
+    exit: &ExitDescriptor,
+) -> anyhow::Result<RouteDescriptor> {
+    let custom_routes = custom_bridge_routes(ctx, exit);
+    if live_config(ctx).custom_bridges_only {
+        return Ok(RouteDescriptor::Race(custom_routes));
+    }
+    let cache_key = format!("cached_routes_{}", exit.b2e_listen);
+    let broker_routes = async {
+        let (_, conn_token, sig) = get_connect_token(ctx)
+            .await
+            .context("could not get connect token")?;
+        tracing::debug!(token = debug(&conn_token), sig = debug(&sig), "CONN TOKEN");
+        let routes = broker_client(ctx)
+            .context("could not get broker client")?
+            .get_routes(conn_token, sig, exit.b2e_listen)
+            .await?
+            .map_err(|e| anyhow::anyhow!("broker refused to serve bridge routes: {e}"))?;
+        db_write(ctx, &cache_key, &serde_json::to_vec(&routes)?).await?;
+        anyhow::Ok(routes)
     };
+    match broker_routes.await {
+        Ok(broker_routes) => Ok(RouteDescriptor::Race(
+            std::iter::once(broker_routes)
+                .chain(custom_routes)
+                .collect(),
+        )),
+        Err(err) => {
+            let mut fallback_routes = custom_routes;
+            // routes aren't signed, but they only decide how we reach the exit, whose key we check anyway
+            match db_read(ctx, &cache_key).await {
+                Ok(Some(cached)) => match serde_json::from_slice(&cached) {
+                    Ok(cached) => fallback_routes.push(cached),
+                    Err(cache_err) => {
+                        tracing::warn!(err = debug(cache_err), "cached routes are corrupt")
+                    }
+                },
+                Ok(None) => {}
+                Err(cache_err) => {
+                    tracing::warn!(err = debug(cache_err), "could not read cached routes")
+                }
+            }
+            match route_bundle(ctx).await {
+                Ok(Some(bundle)) => fallback_routes.extend(
+                    bundle
+                        .routes
+                        .into_iter()
+                        .filter(|(b2e, _)| *b2e == exit.b2e_listen)
+                        .map(|(_, route)| route),
+                ),
+                Ok(None) => {}
+                Err(bundle_err) => {
+                    tracing::warn!(err = debug(bundle_err), "route bundle is unusable")
+                }
+            }
+            if fallback_routes.is_empty() {
+                return Err(err);
+            }
+            tracing::warn!(
+                err = debug(err),
+                "could not get bridges from the broker, using cached, custom, and bundled bridges"
+            );
+            Ok(RouteDescriptor::Race(fallback_routes))
+        }
+    }
+}
 
-    Ok((*pubkey, exit.clone(), final_dialer))
+/// The bridges for reaching `exit`, one dialer each, so that they can be tried one by one.
+pub async fn bridge_dialers(
+    ctx: &AnyCtx<Config>,
+    exit: &ExitDescriptor,
+) -> anyhow::Result<Vec<(String, DynDialer)>> {
+    let routes = bridge_routes(ctx, exit).await?;
+    Ok(flat_routes(&routes)
+        .into_iter()
+        .map(|route| (route_name(route), route_to_dialer(ctx, route)))
+        .collect())
+}
+
+/// The alternatives within a route, with racing, fallbacks, delays and timeouts taken away.
+fn flat_routes(route: &RouteDescriptor) -> Vec<&RouteDescriptor> {
+    match route {
+        RouteDescriptor::Race(inside) | RouteDescriptor::Fallback(inside) => {
+            inside.iter().flat_map(flat_routes).collect()
+        }
+        RouteDescriptor::Timeout { lower, .. } | RouteDescriptor::Delay { lower, .. } => {
+            flat_routes(lower)
+        }
+        route => vec![route],
+    }
+}
+
+fn route_name(route: &RouteDescriptor) -> String {
+    match route {
+        RouteDescriptor::Tcp(addr) => format!("tcp {addr}"),
+        RouteDescriptor::Kcp(addr) => format!("kcp {addr}"),
+        RouteDescriptor::Icmp(addr) => format!("icmp {addr}"),
+        RouteDescriptor::Dns { resolver, domain } => format!("dns {domain} via {resolver}"),
+        RouteDescriptor::Sosistab3 { lower, .. } => {
+            format!("sosistab3 over {}", route_name(lower))
+        }
+        route => format!("{route:?}"),
+    }
 }
 
 // async fn reachability_test(
@@ -292,17 +654,18 @@ pub async fn get_dialer(
 //     }
 // }
 
-fn route_to_dialer(route: &RouteDescriptor) -> DynDialer {
+fn route_to_dialer(ctx: &AnyCtx<Config>, route: &RouteDescriptor) -> DynDialer {
     match route {
         RouteDescriptor::Tcp(addr) => {
             vpn_whitelist(addr.ip());
             let addr = *addr;
+            let sessions = session_count(ctx);
             TcpDialer { dest_addr: addr }
-                .dyn_delay(move || shitlist_delay(addr))
+                .dyn_delay(move || shitlist_delay(addr, sessions))
                 .dynamic()
         }
         RouteDescriptor::Sosistab3 { cookie, lower } => {
-            let inner = route_to_dialer(lower);
+            let inner = route_to_dialer(ctx, lower);
             SosistabDialer {
                 inner,
                 cookie: Cookie::new(cookie),
@@ -311,26 +674,46 @@ fn route_to_dialer(route: &RouteDescriptor) -> DynDialer {
         }
         RouteDescriptor::Race(inside) => inside
             .iter()
-            .map(route_to_dialer)
+            .map(|route| route_to_dialer(ctx, route))
             .reduce(|a, b| a.race(b).dynamic())
             .unwrap_or_else(|| FailingDialer.dynamic()),
         RouteDescriptor::Fallback(a) => a
             .iter()
-            .map(route_to_dialer)
+            .map(|route| route_to_dialer(ctx, route))
             .reduce(|a, b| a.fallback(b).dynamic())
             .unwrap_or_else(|| FailingDialer.dynamic()),
         RouteDescriptor::Timeout {
             milliseconds,
             lower,
-        } => route_to_dialer(lower)
+        } => route_to_dialer(ctx, lower)
             .timeout(Duration::from_millis(*milliseconds as _))
             .dynamic(),
         RouteDescriptor::Delay {
             milliseconds,
             lower,
-        } => route_to_dialer(lower)
+        } => route_to_dialer(ctx, lower)
             .delay(Duration::from_millis((*milliseconds).into()))
             .dynamic(),
+        RouteDescriptor::Dns { resolver, domain } => {
+            vpn_whitelist(resolver.ip());
+            DnsDialer::new(*resolver, domain.clone()).dynamic()
+        }
+        RouteDescriptor::Kcp(addr) => {
+            vpn_whitelist(addr.ip());
+            let addr = *addr;
+            let sessions = session_count(ctx);
+            KcpDialer::new(addr)
+                .dyn_delay(move || shitlist_delay(addr, sessions))
+                .dynamic()
+        }
+        RouteDescriptor::Icmp(addr) => {
+            // raw sockets need privileges that most installs don't have, so this is opt-in
+            if !ctx.init().allow_icmp {
+                return FailingDialer.dynamic();
+            }
+            vpn_whitelist((*addr).into());
+            IcmpDialer::new(*addr).dynamic()
+        }
         RouteDescriptor::Other(_) => FailingDialer.dynamic(),
     }
 }
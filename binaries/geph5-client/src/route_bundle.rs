@@ -0,0 +1,106 @@
+use std::{
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyctx::AnyCtx;
+use anyhow::Context as _;
+use geph5_broker_protocol::{RouteBundle, Signed, DOMAIN_ROUTE_BUNDLE};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use smol_timeout2::TimeoutExt as _;
+
+use crate::{
+    client::CtxField,
+    database::{db_read, db_write},
+    Config,
+};
+
+/// Where to get a signed route bundle from, for use when the broker can't be reached.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum RouteBundleSource {
+    /// An HTTPS URL, fetched with a plain request that doesn't go through the broker.
+    Url(String),
+    File(PathBuf),
+}
+
+const BUNDLE_KEY: &str = "route_bundle";
+
+/// How long a fetched bundle is used before fetching it again.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(1800);
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+static LAST_BUNDLE: CtxField<Mutex<Option<(RouteBundle, Instant)>>> = |_| Mutex::new(None);
+
+/// Gets the route bundle from the configured source, falling back to the last one that was fetched if the source can't be reached. Returns None if no source is configured.
+pub async fn route_bundle(ctx: &AnyCtx<Config>) -> anyhow::Result<Option<RouteBundle>> {
+    let Some(source) = &ctx.init().route_bundle else {
+        return Ok(None);
+    };
+    if let Some((bundle, fetched)) = ctx.get(LAST_BUNDLE).lock().as_ref() {
+        if fetched.elapsed() < REFRESH_INTERVAL {
+            return Ok(Some(bundle.clone()));
+        }
+    }
+
+    let fetched = async {
+        let raw = fetch_raw(source)
+            .timeout(FETCH_TIMEOUT)
+            .await
+            .context("timed out")??;
+        // only keep bundles that check out, so that a bad fetch doesn't overwrite a good bundle
+        let bundle = verify_bundle(ctx, &raw)?;
+        db_write(ctx, BUNDLE_KEY, &raw).await?;
+        anyhow::Ok(bundle)
+    };
+    let bundle = match fetched.await {
+        Ok(bundle) => bundle,
+        Err(err) => {
+            tracing::warn!(
+                err = debug(err),
+                source = debug(source),
+                "could not fetch route bundle, using the saved one"
+            );
+            let raw = db_read(ctx, BUNDLE_KEY)
+                .await?
+                .context("no route bundle was ever fetched")?;
+            verify_bundle(ctx, &raw)?
+        }
+    };
+    *ctx.get(LAST_BUNDLE).lock() = Some((bundle.clone(), Instant::now()));
+    Ok(Some(bundle))
+}
+
+async fn fetch_raw(source: &RouteBundleSource) -> anyhow::Result<Vec<u8>> {
+    match source {
+        RouteBundleSource::Url(url) => {
+            let client = reqwest::Client::builder().no_proxy().build()?;
+            let resp = client.get(url).send().await?.error_for_status()?;
+            Ok(resp.bytes().await?.to_vec())
+        }
+        RouteBundleSource::File(path) => Ok(smol::fs::read(path).await?),
+    }
+}
+
+/// Checks that the bundle is signed by the broker's master key, and that it hasn't expired.
+fn verify_bundle(ctx: &AnyCtx<Config>, raw: &[u8]) -> anyhow::Result<RouteBundle> {
+    let signed: Signed<RouteBundle> =
+        serde_json::from_slice(raw).context("could not parse route bundle")?;
+    let bundle = signed
+        .verify(DOMAIN_ROUTE_BUNDLE, |their_pk| {
+            if let Some(broker_pk) = &ctx.init().broker_keys {
+                hex::encode(their_pk.as_bytes()) == broker_pk.master
+            } else {
+                true
+            }
+        })
+        .context("could not verify route bundle")?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    anyhow::ensure!(bundle.expiry > now, "route bundle has expired");
+    Ok(bundle)
+}
@@ -11,6 +11,11 @@ static DOMAINS: Lazy<HashSet<String>> = Lazy::new(|| {
         .collect()
 });
 
+/// Iterates through all Chinese domains.
+pub fn chinese_domains() -> impl Iterator<Item = &'static str> {
+    DOMAINS.iter().map(|domain| domain.as_str())
+}
+
 /// Returns true if the given host is Chinese
 pub fn is_chinese_host(host: &str) -> bool {
     // explode by dots
@@ -0,0 +1,241 @@
+mod china;
+
+use std::{
+    collections::HashSet,
+    net::IpAddr,
+    path::PathBuf,
+    time::{Duration, Instant, SystemTime},
+};
+
+use anyctx::AnyCtx;
+use ipnet::IpNet;
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::{client::CtxField, live_config::live_config, Config};
+
+use self::china::{chinese_domains, is_chinese_host};
+
+/// How often rule files are checked for changes.
+const RELOAD_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A list of destinations, together with what to do with connections to them. Lists are checked in order, and the first one that matches decides.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct RuleList {
+    /// Domains, which also match all their subdomains, and IP addresses or CIDR ranges. CIDR ranges only match connections made to IP addresses, not to domains.
+    #[serde(default)]
+    pub entries: Vec<String>,
+    /// A file with more entries, one per line, where `#` starts a comment. It is reloaded whenever it changes.
+    #[serde(default)]
+    pub file: Option<PathBuf>,
+    pub action: RuleAction,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RuleAction {
+    Tunnel,
+    Direct,
+    Block,
+}
+
+#[derive(Default)]
+struct Matcher {
+    domains: HashSet<String>,
+    nets: Vec<IpNet>,
+}
+
+impl Matcher {
+    fn add(&mut self, entry: &str) {
+        let entry = entry.split('#').next().unwrap_or_default().trim();
+        if entry.is_empty() {
+            return;
+        }
+        if let Ok(net) = entry.parse::<IpNet>() {
+            self.nets.push(net);
+        } else if let Ok(ip) = entry.parse::<IpAddr>() {
+            self.nets.push(ip.into());
+        } else {
+            let domain = entry.trim_start_matches("*.").trim_matches('.');
+            self.domains.insert(domain.to_ascii_lowercase());
+        }
+    }
+
+    fn matches(&self, host: &str) -> bool {
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return self.nets.iter().any(|net| net.contains(&ip));
+        }
+        let host = host.trim_end_matches('.').to_ascii_lowercase();
+        let mut candidate = host.as_str();
+        loop {
+            if self.domains.contains(candidate) {
+                return true;
+            }
+            match candidate.split_once('.') {
+                Some((_, parent)) => candidate = parent,
+                None => return false,
+            }
+        }
+    }
+}
+
+struct CompiledList {
+    action: RuleAction,
+    inline: Matcher,
+    file: Option<RuleFile>,
+}
+
+struct RuleFile {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    matcher: Matcher,
+}
+
+impl RuleFile {
+    fn reload_if_changed(&mut self) {
+        let modified = std::fs::metadata(&self.path)
+            .and_then(|meta| meta.modified())
+            .ok();
+        if modified.is_some() && modified == self.modified {
+            return;
+        }
+        match std::fs::read_to_string(&self.path) {
+            Ok(contents) => {
+                let mut matcher = Matcher::default();
+                contents.lines().for_each(|line| matcher.add(line));
+                tracing::info!(
+                    path = debug(&self.path),
+                    domains = matcher.domains.len(),
+                    nets = matcher.nets.len(),
+                    "loaded routing rule file"
+                );
+                self.matcher = matcher;
+                self.modified = modified;
+            }
+            // keep the old entries, so a file that's halfway through being rewritten doesn't drop them
+            Err(err) => tracing::warn!(
+                path = debug(&self.path),
+                err = debug(err),
+                "could not read routing rule file"
+            ),
+        }
+    }
+}
+
+struct Rules {
+    lists: Vec<CompiledList>,
+    last_checked: Option<Instant>,
+}
+
+impl Rules {
+    fn reload_files_if_due(&mut self) {
+        if self
+            .last_checked
+            .is_none_or(|checked| checked.elapsed() > RELOAD_INTERVAL)
+        {
+            self.last_checked = Some(Instant::now());
+            for file in self.lists.iter_mut().filter_map(|list| list.file.as_mut()) {
+                file.reload_if_changed();
+            }
+        }
+    }
+}
+
+static RULES: CtxField<Mutex<Rules>> = |ctx| {
+    Mutex::new(Rules {
+        lists: compile_lists(&ctx.init().routing_rules),
+        last_checked: None,
+    })
+};
+
+fn compile_lists(lists: &[RuleList]) -> Vec<CompiledList> {
+    lists
+        .iter()
+        .map(|list| {
+            let mut inline = Matcher::default();
+            list.entries.iter().for_each(|entry| inline.add(entry));
+            CompiledList {
+                action: list.action,
+                inline,
+                file: list.file.clone().map(|path| RuleFile {
+                    path,
+                    modified: None,
+                    matcher: Matcher::default(),
+                }),
+            }
+        })
+        .collect()
+}
+
+/// Recompiles the rule lists after the config changed. Rule files are read again on the next lookup.
+pub fn reload_rule_lists(ctx: &AnyCtx<Config>) {
+    let lists = compile_lists(&live_config(ctx).routing_rules);
+    *ctx.get(RULES).lock() = Rules {
+        lists,
+        last_checked: None,
+    };
+}
+
+/// Decides what to do with a connection to `host`, a domain or an IP address, going by the configured rule lists and then China passthrough. Returns None if nothing matches.
+pub fn rule_action(ctx: &AnyCtx<Config>, host: &str) -> Option<RuleAction> {
+    {
+        let mut rules = ctx.get(RULES).lock();
+        rules.reload_files_if_due();
+        for list in rules.lists.iter() {
+            if list.inline.matches(host)
+                || list
+                    .file
+                    .as_ref()
+                    .is_some_and(|file| file.matcher.matches(host))
+            {
+                return Some(list.action);
+            }
+        }
+    }
+    if live_config(ctx).passthrough_china {
+        if let Some(domain) = psl::domain_str(host) {
+            if is_chinese_host(domain) {
+                return Some(RuleAction::Direct);
+            }
+        }
+    }
+    None
+}
+
+/// The destinations in one rule list, flattened out for other programs, like browsers, to match against.
+pub struct ListedDestinations {
+    pub action: RuleAction,
+    pub domains: Vec<String>,
+    pub nets: Vec<IpNet>,
+}
+
+/// Everything that [rule_action] matches against, in the order it's checked. China passthrough comes last, as a list of direct domains.
+pub fn listed_destinations(ctx: &AnyCtx<Config>) -> Vec<ListedDestinations> {
+    let mut out = vec![];
+    {
+        let mut rules = ctx.get(RULES).lock();
+        rules.reload_files_if_due();
+        for list in rules.lists.iter() {
+            let mut domains: Vec<String> = list.inline.domains.iter().cloned().collect();
+            let mut nets = list.inline.nets.clone();
+            if let Some(file) = &list.file {
+                domains.extend(file.matcher.domains.iter().cloned());
+                nets.extend(file.matcher.nets.iter().copied());
+            }
+            out.push(ListedDestinations {
+                action: list.action,
+                domains,
+                nets,
+            });
+        }
+    }
+    if live_config(ctx).passthrough_china {
+        out.push(ListedDestinations {
+            action: RuleAction::Direct,
+            domains: chinese_domains().map(|domain| domain.to_string()).collect(),
+            nets: vec![],
+        });
+    }
+    out
+}
@@ -1,32 +1,54 @@
-use crate::{client_inner::open_conn, taskpool::add_task};
+use crate::{
+    client_inner::{open_app_conn, open_conn},
+    litecopy::litecopy,
+    live_config::live_config,
+    taskpool::{add_task, TaskPriority},
+    udp::UdpFlow,
+};
 
 use anyctx::AnyCtx;
 
-use futures_util::AsyncReadExt as _;
+use futures_util::{AsyncRead, AsyncReadExt as _, AsyncWrite};
 use nursery_macro::nursery;
-use sillad::listener::Listener as _;
-use smol::future::FutureExt as _;
+use sillad::{listener::Listener as _, Pipe as _};
+use smol::{future::FutureExt as _, net::UdpSocket};
 use socksv5::v5::{
     read_handshake, read_request, write_auth_method, write_request_status, SocksV5AuthMethod,
-    SocksV5Host, SocksV5RequestStatus,
+    SocksV5Command, SocksV5Host, SocksV5RequestStatus,
+};
+use std::{
+    collections::HashMap,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
 };
-use std::net::Ipv4Addr;
 
 use super::Config;
 
 #[tracing::instrument(skip_all)]
 pub async fn socks5_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
-    if let Some(listen_addr) = ctx.init().socks5_listen {
+    if let Some(listen_addr) = live_config(ctx).socks5_listen {
         let mut listener = sillad::tcp::TcpListener::bind(listen_addr).await?;
         nursery!({
             loop {
                 let client = listener.accept().await?;
                 let task = spawn!(async {
                     tracing::trace!("socks5 connection accepted");
+                    let app_addr: Option<SocketAddr> =
+                        client.remote_addr().and_then(|addr| addr.parse().ok());
                     let (mut read_client, mut write_client) = client.split();
                     let _handshake = read_handshake(&mut read_client).await?;
                     write_auth_method(&mut write_client, SocksV5AuthMethod::Noauth).await?;
                     let request = read_request(&mut read_client).await?;
+                    if matches!(request.command, SocksV5Command::UdpAssociate) {
+                        return udp_associate(
+                            ctx,
+                            listen_addr.ip(),
+                            app_addr.map(|addr| addr.ip()),
+                            read_client,
+                            write_client,
+                        )
+                        .await;
+                    }
                     let port = request.port;
                     let domain: String = match &request.host {
                         SocksV5Host::Domain(dom) => String::from_utf8_lossy(dom).parse()?,
@@ -34,14 +56,31 @@ pub async fn socks5_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
                             let v4addr = Ipv4Addr::new(v4[0], v4[1], v4[2], v4[3]);
                             v4addr.to_string()
                         }
-                        _ => anyhow::bail!("IPv6 not supported"),
+                        SocksV5Host::Ipv6(v6) => format!("[{}]", Ipv6Addr::from(*v6)),
                     };
                     let remote_addr = format!("{domain}:{port}");
                     tracing::trace!(
                         remote_addr = display(&remote_addr),
                         "socks5 request received"
                     );
-                    let stream = open_conn(ctx, "tcp", &remote_addr).await?;
+                    let stream = match app_addr {
+                        Some(app_addr) => open_app_conn(ctx, app_addr, "tcp", &remote_addr).await,
+                        None => open_conn(ctx, "tcp", &remote_addr).await,
+                    };
+                    // tell the application, rather than leaving it to time out on its own
+                    let stream = match stream {
+                        Ok(stream) => stream,
+                        Err(err) => {
+                            write_request_status(
+                                &mut write_client,
+                                SocksV5RequestStatus::HostUnreachable,
+                                request.host,
+                                port,
+                            )
+                            .await?;
+                            return Err(err);
+                        }
+                    };
                     write_request_status(
                         &mut write_client,
                         SocksV5RequestStatus::Success,
@@ -50,20 +89,121 @@ pub async fn socks5_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
                     )
                     .await?;
                     tracing::trace!(remote_addr = display(&remote_addr), "connection opened");
-                    let (read_stream, write_stream) = stream.split();
-                    smol::io::copy(read_stream, write_client)
-                        .race(smol::io::copy(read_client, write_stream))
-                        .await?;
+                    litecopy(read_client.reunite(write_client)?, stream).await?;
                     anyhow::Ok(())
                 });
-                if let Some(task_limit) = ctx.init().task_limit {
-                    add_task(task_limit, task);
-                } else {
-                    task.detach();
-                }
+                add_task(ctx, TaskPriority::Normal, task);
             }
         })
     } else {
         smol::future::pending().await
     }
 }
+
+/// Serves a UDP ASSOCIATE request, relaying datagrams between `client_ip` and wherever they are addressed to until the client closes the TCP connection that made the request.
+async fn udp_associate(
+    ctx: &AnyCtx<Config>,
+    listen_ip: IpAddr,
+    client_ip: Option<IpAddr>,
+    mut read_client: impl AsyncRead + Unpin,
+    mut write_client: impl AsyncWrite + Unpin,
+) -> anyhow::Result<()> {
+    let relay = UdpSocket::bind(SocketAddr::new(listen_ip, 0)).await?;
+    let relay_addr = relay.local_addr()?;
+    let relay_host = match relay_addr.ip() {
+        IpAddr::V4(v4) => SocksV5Host::Ipv4(v4.octets()),
+        IpAddr::V6(v6) => SocksV5Host::Ipv6(v6.octets()),
+    };
+    write_request_status(
+        &mut write_client,
+        SocksV5RequestStatus::Success,
+        relay_host,
+        relay_addr.port(),
+    )
+    .await?;
+    tracing::trace!(
+        relay_addr = display(relay_addr),
+        "socks5 UDP association opened"
+    );
+
+    let relay_loop = async {
+        // each destination gets its own flow, with a task that relays its replies back
+        let mut flows: HashMap<String, (Arc<UdpFlow>, smol::Task<anyhow::Result<()>>)> =
+            HashMap::new();
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let (n, src) = relay.recv_from(&mut buf).await?;
+            if client_ip.is_some_and(|ip| ip != src.ip()) {
+                continue;
+            }
+            let Some((dest_addr, header, payload)) = parse_udp_datagram(&buf[..n]) else {
+                tracing::debug!(src = display(src), "dropping malformed socks5 UDP datagram");
+                continue;
+            };
+            if !flows.contains_key(&dest_addr) {
+                let flow = match UdpFlow::open(ctx, &dest_addr).await {
+                    Ok(flow) => Arc::new(flow),
+                    Err(err) => {
+                        tracing::debug!(
+                            dest_addr = display(&dest_addr),
+                            err = debug(err),
+                            "could not open UDP flow"
+                        );
+                        continue;
+                    }
+                };
+                let reply_task = smolscale::spawn({
+                    let flow = flow.clone();
+                    let relay = relay.clone();
+                    let header = header.to_vec();
+                    async move {
+                        loop {
+                            let datagram = flow.recv().await?;
+                            let mut reply = header.clone();
+                            reply.extend_from_slice(&datagram);
+                            relay.send_to(&reply, src).await?;
+                        }
+                    }
+                });
+                flows.insert(dest_addr.clone(), (flow, reply_task));
+            }
+            let (flow, _) = &flows[&dest_addr];
+            if let Err(err) = flow.send(payload).await {
+                tracing::debug!(err = debug(err), "UDP flow died");
+                flows.remove(&dest_addr);
+            }
+        }
+    };
+    let control_loop = async {
+        let mut buf = [0u8; 1];
+        while read_client.read(&mut buf).await? > 0 {}
+        anyhow::Ok(())
+    };
+    relay_loop.race(control_loop).await
+}
+
+/// Splits a SOCKS5 UDP datagram into its destination as host:port, its header, and its payload. Fragmented datagrams aren't supported.
+fn parse_udp_datagram(pkt: &[u8]) -> Option<(String, &[u8], &[u8])> {
+    if pkt.len() < 4 || pkt[2] != 0 {
+        return None;
+    }
+    let (host, port_offset) = match pkt[3] {
+        1 => {
+            let octets: [u8; 4] = pkt.get(4..8)?.try_into().ok()?;
+            (Ipv4Addr::from(octets).to_string(), 8)
+        }
+        3 => {
+            let len = *pkt.get(4)? as usize;
+            let domain = pkt.get(5..5 + len)?;
+            (String::from_utf8_lossy(domain).into_owned(), 5 + len)
+        }
+        4 => {
+            let octets: [u8; 16] = pkt.get(4..20)?.try_into().ok()?;
+            (format!("[{}]", Ipv6Addr::from(octets)), 20)
+        }
+        _ => return None,
+    };
+    let port = u16::from_be_bytes(pkt.get(port_offset..port_offset + 2)?.try_into().ok()?);
+    let (header, payload) = pkt.split_at(port_offset + 2);
+    Some((format!("{host}:{port}"), header, payload))
+}
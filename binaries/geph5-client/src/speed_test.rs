@@ -0,0 +1,170 @@
+use std::time::{Duration, Instant};
+
+use anyctx::AnyCtx;
+use anyhow::Context as _;
+use futures_util::{AsyncRead, AsyncReadExt as _, AsyncWriteExt as _};
+use serde::{Deserialize, Serialize};
+use sillad::{dialer::Dialer as _, Pipe};
+use sillad_native_tls::{webpki_roots, ClientHelloProfile, RustlsDialer};
+use smol_timeout2::TimeoutExt as _;
+
+use crate::{dns::TunnelDialer, Config};
+
+/// Cloudflare's speed test server, which sends and swallows as many bytes as asked. It sits on a CDN close to every exit, so what gets measured is mostly the tunnel.
+const TEST_HOST: &str = "speed.cloudflare.com";
+const TEST_ADDR: &str = "speed.cloudflare.com:443";
+
+const DOWNLOAD_BYTES: usize = 25_000_000;
+const UPLOAD_BYTES: usize = 10_000_000;
+
+/// Each direction stops after this long even if it isn't done, so that slow connections still get a result quickly.
+const PHASE_LIMIT: Duration = Duration::from_secs(8);
+
+const LATENCY_SAMPLES: usize = 3;
+
+/// What [speed_test] measured.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SpeedTestResult {
+    pub download_mbps: f64,
+    pub upload_mbps: f64,
+    /// The quickest of a few HTTP round trips to the test server, in milliseconds.
+    pub latency_ms: f64,
+}
+
+/// Measures latency, then download and upload speed, through the current tunnel. This moves a few dozen megabytes, which count towards usage like any other traffic.
+pub async fn speed_test(ctx: &AnyCtx<Config>) -> anyhow::Result<SpeedTestResult> {
+    let latency = measure_latency(ctx)
+        .timeout(PHASE_LIMIT)
+        .await
+        .context("latency test timed out")??;
+    let download_mbps = measure_download(ctx)
+        .timeout(PHASE_LIMIT * 2)
+        .await
+        .context("download test timed out")??;
+    let upload_mbps = measure_upload(ctx)
+        .timeout(PHASE_LIMIT * 2)
+        .await
+        .context("upload test timed out")??;
+    tracing::debug!(
+        download_mbps,
+        upload_mbps,
+        latency = debug(latency),
+        "speed test done"
+    );
+    Ok(SpeedTestResult {
+        download_mbps,
+        upload_mbps,
+        latency_ms: latency.as_secs_f64() * 1000.0,
+    })
+}
+
+async fn measure_latency(ctx: &AnyCtx<Config>) -> anyhow::Result<Duration> {
+    // one connection for every sample, so that only the first pays for the handshakes
+    let mut conn = dial(ctx).await?;
+    let mut best = Duration::MAX;
+    for _ in 0..LATENCY_SAMPLES {
+        let start = Instant::now();
+        conn.write_all(
+            format!("GET /__down?bytes=0 HTTP/1.1\r\nHost: {TEST_HOST}\r\n\r\n").as_bytes(),
+        )
+        .await?;
+        conn.flush().await?;
+        read_head(&mut conn).await?;
+        best = best.min(start.elapsed());
+    }
+    Ok(best)
+}
+
+async fn measure_download(ctx: &AnyCtx<Config>) -> anyhow::Result<f64> {
+    let mut conn = dial(ctx).await?;
+    conn.write_all(
+        format!(
+            "GET /__down?bytes={DOWNLOAD_BYTES} HTTP/1.1\r\nHost: {TEST_HOST}\r\nConnection: close\r\n\r\n"
+        )
+        .as_bytes(),
+    )
+    .await?;
+    conn.flush().await?;
+    read_head(&mut conn).await?;
+
+    let start = Instant::now();
+    let mut received = 0;
+    let mut buf = vec![0u8; 65536];
+    while let Some(remaining) = PHASE_LIMIT.checked_sub(start.elapsed()) {
+        let Some(n) = conn.read(&mut buf).timeout(remaining).await else {
+            break;
+        };
+        let n = n?;
+        if n == 0 {
+            break;
+        }
+        received += n;
+    }
+    Ok(mbps(received, start.elapsed()))
+}
+
+async fn measure_upload(ctx: &AnyCtx<Config>) -> anyhow::Result<f64> {
+    let mut conn = dial(ctx).await?;
+    conn.write_all(
+        format!(
+            "POST /__up HTTP/1.1\r\nHost: {TEST_HOST}\r\nContent-Type: application/octet-stream\r\nContent-Length: {UPLOAD_BYTES}\r\nConnection: close\r\n\r\n"
+        )
+        .as_bytes(),
+    )
+    .await?;
+
+    let start = Instant::now();
+    let mut sent = 0;
+    let chunk = vec![0u8; 65536];
+    while sent < UPLOAD_BYTES && start.elapsed() < PHASE_LIMIT {
+        let n = chunk.len().min(UPLOAD_BYTES - sent);
+        conn.write_all(&chunk[..n]).await?;
+        sent += n;
+    }
+    conn.flush().await?;
+    if sent == UPLOAD_BYTES {
+        // writes return once they're buffered, so the upload is only over when the server has everything
+        read_head(&mut conn).await?;
+    }
+    Ok(mbps(sent, start.elapsed()))
+}
+
+async fn dial(ctx: &AnyCtx<Config>) -> anyhow::Result<impl Pipe> {
+    let dialer = RustlsDialer::new(
+        TunnelDialer {
+            ctx: ctx.clone(),
+            dest: TEST_ADDR,
+        },
+        &ClientHelloProfile::chrome(),
+        webpki_roots(),
+        TEST_HOST.to_string(),
+    )?;
+    Ok(dialer.dial().await?)
+}
+
+/// Reads up to the end of the response headers, failing unless the status is 200. Whatever of the body came along is thrown away, since only its size matters here.
+async fn read_head(conn: &mut (impl AsyncRead + Unpin)) -> anyhow::Result<()> {
+    let mut head = vec![];
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = conn.read(&mut buf).await?;
+        anyhow::ensure!(n > 0, "speed test server closed the connection early");
+        head.extend_from_slice(&buf[..n]);
+        if let Some(split) = head.windows(4).position(|w| w == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&head[..split]);
+            let status = head.lines().next().unwrap_or_default();
+            if status.split_whitespace().nth(1) != Some("200") {
+                anyhow::bail!("speed test server said {status:?}");
+            }
+            return Ok(());
+        }
+        anyhow::ensure!(
+            head.len() < 65536,
+            "speed test response headers are too long"
+        );
+    }
+}
+
+fn mbps(bytes: usize, elapsed: Duration) -> f64 {
+    bytes as f64 * 8.0 / 1_000_000.0 / elapsed.as_secs_f64().max(0.001)
+}
@@ -1,57 +1,129 @@
-use std::net::Ipv4Addr;
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    time::Duration,
+};
 
 use anyctx::AnyCtx;
 use bytes::Bytes;
-use dashmap::DashMap;
+use ipnet::{Ipv4Net, Ipv6Net};
+use moka::sync::Cache;
 use rand::Rng;
-use simple_dns::{Packet, QTYPE};
+use simple_dns::{Packet, QTYPE, TYPE};
 
 use crate::{client::CtxField, Config};
 
-static FAKE_DNS_FORWARD: CtxField<DashMap<String, Ipv4Addr>> = |_| DashMap::new();
+/// The fake-IP mapping of each name, per address family.
+static FAKE_DNS_FORWARD: CtxField<Cache<String, Ipv4Addr>> = |ctx| mapping_cache(ctx);
 
-static FAKE_DNS_BACKWARD: CtxField<DashMap<Ipv4Addr, String>> = |_| DashMap::new();
+static FAKE_DNS_FORWARD_V6: CtxField<Cache<String, Ipv6Addr>> = |ctx| mapping_cache(ctx);
 
-pub fn fake_dns_backtranslate(ctx: &AnyCtx<Config>, fake: Ipv4Addr) -> Option<String> {
+/// The name behind each fake IP. This is what decides whether a mapping is still alive, since it's touched by every connection to the fake IP.
+static FAKE_DNS_BACKWARD: CtxField<Cache<IpAddr, String>> = |ctx| mapping_cache(ctx);
+
+/// The pools that fake addresses are allocated from, unless the config says otherwise.
+const DEFAULT_V4_RANGE: &str = "240.0.0.0/4";
+const DEFAULT_V6_RANGE: &str = "fd47:6570:6835::/48";
+
+const DEFAULT_RECORD_TTL: u32 = 1;
+const DEFAULT_MAPPING_EXPIRY: Duration = Duration::from_secs(3600);
+
+fn mapping_cache<K, V>(ctx: &AnyCtx<Config>) -> Cache<K, V>
+where
+    K: std::hash::Hash + Eq + Send + Sync + 'static,
+    V: Clone + Send + Sync + 'static,
+{
+    Cache::builder()
+        .time_to_idle(
+            ctx.init()
+                .fake_dns_expiry_secs
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_MAPPING_EXPIRY),
+        )
+        .build()
+}
+
+pub fn fake_dns_backtranslate(ctx: &AnyCtx<Config>, fake: IpAddr) -> Option<String> {
     tracing::trace!(fake = debug(fake), "attempting to backtranslate");
-    ctx.get(FAKE_DNS_BACKWARD)
-        .get(&fake)
-        .map(|entry| entry.clone())
+    ctx.get(FAKE_DNS_BACKWARD).get(&fake)
 }
 
 pub fn fake_dns_allocate(ctx: &AnyCtx<Config>, dns_name: &str) -> Ipv4Addr {
-    *ctx.get(FAKE_DNS_FORWARD)
-        .entry(dns_name.to_string())
-        .or_insert_with(|| {
-            let base = u32::from_be_bytes([240, 0, 0, 0]);
-            let mask = u32::from_be_bytes([240, 0, 0, 0]);
-            let offset = rand::thread_rng().gen_range(0..=!mask);
-            let ip_addr = base | offset;
-            let ip_addr = Ipv4Addr::from(ip_addr);
-            ctx.get(FAKE_DNS_BACKWARD)
-                .insert(ip_addr, dns_name.to_string());
-            tracing::debug!(
-                from = debug(dns_name),
-                to = debug(ip_addr),
-                "created fake dns mapping",
-            );
-            ip_addr
-        })
+    let range = ctx
+        .init()
+        .fake_dns_v4_range
+        .unwrap_or_else(|| DEFAULT_V4_RANGE.parse().unwrap());
+    allocate(ctx, ctx.get(FAKE_DNS_FORWARD), dns_name, || {
+        let offset = rand::thread_rng()
+            .gen_range(0..=(!0u32).checked_shr(range.prefix_len() as u32).unwrap_or(0));
+        Ipv4Addr::from(u32::from(range.network()) | offset)
+    })
+}
+
+pub fn fake_dns_allocate_v6(ctx: &AnyCtx<Config>, dns_name: &str) -> Ipv6Addr {
+    let range = ctx
+        .init()
+        .fake_dns_v6_range
+        .unwrap_or_else(|| DEFAULT_V6_RANGE.parse().unwrap());
+    allocate(ctx, ctx.get(FAKE_DNS_FORWARD_V6), dns_name, || {
+        let offset = rand::thread_rng()
+            .gen_range(0..=(!0u128).checked_shr(range.prefix_len() as u32).unwrap_or(0));
+        Ipv6Addr::from(u128::from(range.network()) | offset)
+    })
+}
+
+/// Returns the fake address that `dns_name` maps to, allocating one if the name has no live mapping. Mappings that nothing has used for a while expire, so that a long-running VPN session doesn't fill up the pool.
+fn allocate<A: Into<IpAddr> + Copy + Send + Sync + 'static>(
+    ctx: &AnyCtx<Config>,
+    forward: &Cache<String, A>,
+    dns_name: &str,
+    random_ip: impl Fn() -> A,
+) -> A {
+    let backward = ctx.get(FAKE_DNS_BACKWARD);
+    if let Some(ip) = forward.get(dns_name) {
+        // the backward mapping may have expired, or been taken over by another name since
+        if backward.get(&ip.into()).as_deref() == Some(dns_name) {
+            return ip;
+        }
+    }
+    // with a small pool, many addresses may be taken, so look for a free one a few times before taking one over
+    let ip = (0..16)
+        .map(|_| random_ip())
+        .find(|ip| !backward.contains_key(&(*ip).into()))
+        .unwrap_or_else(&random_ip);
+    backward.insert(ip.into(), dns_name.to_string());
+    forward.insert(dns_name.to_string(), ip);
+    let ip_addr: IpAddr = ip.into();
+    tracing::debug!(
+        from = debug(dns_name),
+        to = debug(ip_addr),
+        "created fake dns mapping",
+    );
+    ip
 }
 
+/// Answers a DNS query with fake addresses. Names are always answered with addresses directly, never with a CNAME, so that the exit resolves the whole chain. Queries for other record types get an empty answer.
 pub fn fake_dns_respond(ctx: &AnyCtx<Config>, pkt: &[u8]) -> anyhow::Result<Bytes> {
     let pkt = Packet::parse(pkt)?;
     tracing::trace!(pkt = debug(&pkt), "got DNS packet");
+    let ttl = ctx.init().fake_dns_ttl_secs.unwrap_or(DEFAULT_RECORD_TTL);
     let mut answers = vec![];
     for question in pkt.questions.iter() {
-        if question.qtype == QTYPE::TYPE(simple_dns::TYPE::A) {
+        let name = question.qname.to_string();
+        let any = question.qtype == QTYPE::ANY;
+        if any || question.qtype == QTYPE::TYPE(TYPE::A) {
+            answers.push(simple_dns::ResourceRecord::new(
+                question.qname.clone(),
+                simple_dns::CLASS::IN,
+                ttl,
+                simple_dns::rdata::RData::A(fake_dns_allocate(ctx, &name).into()),
+            ));
+        }
+        if any || question.qtype == QTYPE::TYPE(TYPE::AAAA) {
             answers.push(simple_dns::ResourceRecord::new(
                 question.qname.clone(),
                 simple_dns::CLASS::IN,
-                1,
-                simple_dns::rdata::RData::A(
-                    fake_dns_allocate(ctx, &question.qname.to_string()).into(),
-                ),
+                ttl,
+                simple_dns::rdata::RData::AAAA(fake_dns_allocate_v6(ctx, &name).into()),
             ));
         }
     }
@@ -0,0 +1,72 @@
+use std::time::{Duration, Instant, SystemTime};
+
+use anyctx::AnyCtx;
+
+use crate::{
+    database::{db_read_stat_history, db_write_stat_samples},
+    stats::stat_get_num,
+    Config,
+};
+
+/// The stats whose history is kept.
+const TRACKED_STATS: &[&str] = &[
+    "total_rx_bytes",
+    "total_tx_bytes",
+    "active_sessions",
+    "ping",
+];
+
+/// The resolutions that stat history is kept at, as (resolution, retention), both in seconds, finest first.
+pub const RESOLUTIONS: &[(u64, u64)] = &[(1, 3600), (60, 7 * 86400), (3600, 365 * 86400)];
+
+/// How often samples are written to the database. Samples are taken every second regardless.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Samples the tracked stats every second, saving them to the database so that their history survives restarts.
+pub async fn stat_history_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    let mut pending = vec![];
+    let mut last_flush = Instant::now();
+    loop {
+        smol::Timer::after(Duration::from_secs(1)).await;
+        let now = unix_secs(SystemTime::now());
+        for stat in TRACKED_STATS {
+            pending.push((*stat, now, stat_get_num(ctx, stat)));
+        }
+        if last_flush.elapsed() >= FLUSH_INTERVAL {
+            last_flush = Instant::now();
+            if let Err(err) = db_write_stat_samples(ctx, &pending).await {
+                tracing::warn!(err = debug(err), "could not save stat history");
+            }
+            pending.clear();
+        }
+    }
+}
+
+/// The history of a stat between `start` and `end`, at the finest resolution that still goes back to `start`.
+pub async fn stat_history(
+    ctx: &AnyCtx<Config>,
+    stat: &str,
+    start: SystemTime,
+    end: SystemTime,
+) -> anyhow::Result<Vec<(SystemTime, f64)>> {
+    let age = SystemTime::now()
+        .duration_since(start)
+        .unwrap_or_default()
+        .as_secs();
+    let (resolution, _) = RESOLUTIONS
+        .iter()
+        .find(|(_, retention)| *retention >= age)
+        .unwrap_or(&RESOLUTIONS[RESOLUTIONS.len() - 1]);
+    let samples =
+        db_read_stat_history(ctx, stat, *resolution, unix_secs(start), unix_secs(end)).await?;
+    Ok(samples
+        .into_iter()
+        .map(|(time, value)| (SystemTime::UNIX_EPOCH + Duration::from_secs(time), value))
+        .collect())
+}
+
+fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
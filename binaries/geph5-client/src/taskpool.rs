@@ -1,17 +1,174 @@
-use std::collections::VecDeque;
+use std::{collections::VecDeque, time::Duration};
 
-use once_cell::sync::Lazy;
+use anyctx::AnyCtx;
 use parking_lot::Mutex;
 use smol::Task;
 
-static TASK_POOL: Lazy<Mutex<VecDeque<Task<anyhow::Result<()>>>>> =
-    Lazy::new(|| Mutex::new(VecDeque::new()));
+use crate::{client::CtxField, stats::stat_set_num, Config};
 
-/// Add a task to the task pool. If the pool is full, the oldest task will be removed.
-pub fn add_task(task_limit: u32, task: Task<anyhow::Result<()>>) {
-    let mut pool = TASK_POOL.lock();
-    if pool.len() >= task_limit as _ {
-        pool.pop_front();
+/// How important a task is to keep alive when tasks have to be shed.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum TaskPriority {
+    /// Tasks that are cheap to lose, like DNS lookups and UDP flows, which the other end retries anyway.
+    Low,
+    /// TCP connections, which break visibly when killed.
+    Normal,
+}
+
+/// The limit used when only `memory_limit_mb` is configured.
+const DEFAULT_MAX_TASKS: usize = 4096;
+
+/// The limit never shrinks below this, so that the client stays usable however tight memory is.
+const MIN_TASKS: usize = 32;
+
+/// How much of the memory limit, or of the file descriptor limit, can be used before the task limit starts shrinking.
+const PRESSURE_RATIO: f64 = 0.8;
+
+const ADJUST_INTERVAL: Duration = Duration::from_secs(2);
+
+struct TaskPool {
+    tasks: VecDeque<(TaskPriority, Task<anyhow::Result<()>>)>,
+    limit: usize,
+}
+
+impl TaskPool {
+    /// Drops tasks until the pool is within the limit. Finished tasks go first, then the oldest of the lowest priority.
+    fn shed(&mut self) {
+        if self.tasks.len() <= self.limit {
+            return;
+        }
+        self.tasks.retain(|(_, task)| !task.is_finished());
+        while self.tasks.len() > self.limit {
+            let Some(lowest) = self.tasks.iter().map(|(priority, _)| *priority).min() else {
+                break;
+            };
+            if let Some(idx) = self
+                .tasks
+                .iter()
+                .position(|(priority, _)| *priority == lowest)
+            {
+                // dropping the task cancels it
+                self.tasks.remove(idx);
+            }
+        }
+    }
+}
+
+static TASK_POOL: CtxField<Mutex<TaskPool>> = |ctx| {
+    Mutex::new(TaskPool {
+        tasks: VecDeque::new(),
+        limit: max_tasks(ctx),
+    })
+};
+
+fn max_tasks(ctx: &AnyCtx<Config>) -> usize {
+    ctx.init()
+        .task_limit
+        .map(|limit| limit as usize)
+        .unwrap_or(DEFAULT_MAX_TASKS)
+}
+
+fn is_limited(ctx: &AnyCtx<Config>) -> bool {
+    ctx.init().task_limit.is_some() || ctx.init().memory_limit_mb.is_some()
+}
+
+/// Add a task to the task pool. If the pool is full, the oldest task of the lowest priority will be removed. Without a task or memory limit configured, the task is simply detached.
+pub fn add_task(ctx: &AnyCtx<Config>, priority: TaskPriority, task: Task<anyhow::Result<()>>) {
+    if !is_limited(ctx) {
+        task.detach();
+        return;
+    }
+    let mut pool = ctx.get(TASK_POOL).lock();
+    pool.tasks.push_back((priority, task));
+    pool.shed();
+}
+
+/// Adjusts the task limit to how much memory and how many file descriptors are in use. The limit shrinks quickly under pressure, and grows back slowly, up to the configured `task_limit`, while the pool is near full.
+pub async fn task_limit_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    if !is_limited(ctx) {
+        return smol::future::pending().await;
     }
-    pool.push_back(task);
+    let max = max_tasks(ctx);
+    let memory_limit = ctx.init().memory_limit_mb.map(|mb| mb as u64 * 1024 * 1024);
+    loop {
+        smol::Timer::after(ADJUST_INTERVAL).await;
+        let rss = resident_bytes();
+        let fds = open_fds();
+        let memory_pressure = is_pressured(rss, memory_limit);
+        let fd_pressure = is_pressured(fds, fd_limit());
+
+        let mut pool = ctx.get(TASK_POOL).lock();
+        pool.tasks.retain(|(_, task)| !task.is_finished());
+        let old_limit = pool.limit;
+        if memory_pressure || fd_pressure {
+            pool.limit = (pool.limit * 3 / 4).max(MIN_TASKS).min(max);
+        } else if pool.tasks.len() * 10 >= pool.limit * 9 {
+            pool.limit = (pool.limit + pool.limit / 10 + 1).min(max);
+        }
+        if pool.limit < old_limit {
+            tracing::warn!(
+                old_limit,
+                new_limit = pool.limit,
+                rss = debug(rss),
+                fds = debug(fds),
+                "shrinking the task limit under resource pressure"
+            );
+        }
+        pool.shed();
+        stat_set_num(ctx, "task_limit", pool.limit as f64);
+        stat_set_num(ctx, "task_count", pool.tasks.len() as f64);
+        drop(pool);
+        if let Some(rss) = rss {
+            stat_set_num(ctx, "resident_bytes", rss as f64);
+        }
+        if let Some(fds) = fds {
+            stat_set_num(ctx, "open_fds", fds as f64);
+        }
+    }
+}
+
+fn is_pressured(used: Option<u64>, limit: Option<u64>) -> bool {
+    match (used, limit) {
+        (Some(used), Some(limit)) => used as f64 > limit as f64 * PRESSURE_RATIO,
+        _ => false,
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn resident_bytes() -> Option<u64> {
+    let statm = std::fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    Some(pages * page_size.max(0) as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn resident_bytes() -> Option<u64> {
+    None
+}
+
+#[cfg(target_os = "linux")]
+fn open_fds() -> Option<u64> {
+    Some(std::fs::read_dir("/proc/self/fd").ok()?.count() as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn open_fds() -> Option<u64> {
+    None
+}
+
+#[cfg(unix)]
+fn fd_limit() -> Option<u64> {
+    let mut limit: libc::rlimit = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0
+        || limit.rlim_cur == libc::RLIM_INFINITY
+    {
+        return None;
+    }
+    Some(limit.rlim_cur as u64)
+}
+
+#[cfg(not(unix))]
+fn fd_limit() -> Option<u64> {
+    None
 }
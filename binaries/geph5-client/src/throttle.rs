@@ -0,0 +1,125 @@
+use std::{
+    pin::Pin,
+    task::Poll,
+    time::{Duration, Instant},
+};
+
+use anyctx::AnyCtx;
+use futures_util::{AsyncRead, AsyncWrite, FutureExt as _};
+use parking_lot::Mutex;
+use pin_project::pin_project;
+use sillad::Pipe;
+
+use crate::{client::CtxField, live_config::live_config, Config};
+
+/// How far ahead of the cap a quiet link may burst.
+const BURST: Duration = Duration::from_millis(200);
+
+/// When each direction's budget is next free. These are shared by every session, so that the caps hold for the client as a whole.
+static UPLOAD_FREE_AT: CtxField<Mutex<Instant>> = |_| Mutex::new(Instant::now());
+static DOWNLOAD_FREE_AT: CtxField<Mutex<Instant>> = |_| Mutex::new(Instant::now());
+
+/// Charges `bytes` against a cap of `limit_kbps`, returning how long to wait before the next transfer in that direction.
+fn charge(free_at: &Mutex<Instant>, limit_kbps: Option<u32>, bytes: usize) -> Option<Duration> {
+    let limit_kbps = limit_kbps.filter(|limit| *limit > 0)?;
+    let now = Instant::now();
+    let mut free_at = free_at.lock();
+    let start = (*free_at).max(now.checked_sub(BURST).unwrap_or(now));
+    *free_at = start + Duration::from_secs_f64(bytes as f64 * 8.0 / (limit_kbps as f64 * 1000.0));
+    Some(free_at.saturating_duration_since(now)).filter(|wait| !wait.is_zero())
+}
+
+/// A session pipe that keeps its traffic under the configured `upload_limit_kbps` and `download_limit_kbps`, by pausing after each transfer that goes over.
+#[pin_project]
+pub struct ThrottledPipe<P> {
+    #[pin]
+    inner: P,
+    ctx: AnyCtx<Config>,
+    read_wait: Option<smol::Timer>,
+    write_wait: Option<smol::Timer>,
+}
+
+impl<P: Pipe> ThrottledPipe<P> {
+    pub fn new(ctx: &AnyCtx<Config>, inner: P) -> Self {
+        Self {
+            inner,
+            ctx: ctx.clone(),
+            read_wait: None,
+            write_wait: None,
+        }
+    }
+}
+
+impl<P: Pipe> AsyncRead for ThrottledPipe<P> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.project();
+        if let Some(wait) = this.read_wait.as_mut() {
+            futures_util::ready!(wait.poll_unpin(cx));
+            *this.read_wait = None;
+        }
+        let n = futures_util::ready!(this.inner.poll_read(cx, buf))?;
+        if let Some(wait) = charge(
+            this.ctx.get(DOWNLOAD_FREE_AT),
+            live_config(this.ctx).download_limit_kbps,
+            n,
+        ) {
+            *this.read_wait = Some(smol::Timer::after(wait));
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<P: Pipe> AsyncWrite for ThrottledPipe<P> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.project();
+        if let Some(wait) = this.write_wait.as_mut() {
+            futures_util::ready!(wait.poll_unpin(cx));
+            *this.write_wait = None;
+        }
+        let n = futures_util::ready!(this.inner.poll_write(cx, buf))?;
+        if let Some(wait) = charge(
+            this.ctx.get(UPLOAD_FREE_AT),
+            live_config(this.ctx).upload_limit_kbps,
+            n,
+        ) {
+            *this.write_wait = Some(smol::Timer::after(wait));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl<P: Pipe> Pipe for ThrottledPipe<P> {
+    fn shared_secret(&self) -> Option<&[u8]> {
+        self.inner.shared_secret()
+    }
+
+    fn protocol(&self) -> &str {
+        self.inner.protocol()
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.inner.remote_addr()
+    }
+}
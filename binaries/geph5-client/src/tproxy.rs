@@ -0,0 +1,143 @@
+use crate::{
+    client_inner::open_conn,
+    live_config::live_config,
+    taskpool::{add_task, TaskPriority},
+};
+
+use anyctx::AnyCtx;
+
+use super::Config;
+
+/// Accepts TCP connections that iptables diverted to us, with either a REDIRECT or a TPROXY rule, and carries each one through the tunnel to wherever it was originally headed. This lets a router push its whole LAN through Geph without VPN mode.
+///
+/// The rules must leave out Geph's own traffic, for example by matching on its UID with `-m owner`, or its connections to bridges and exits would be diverted right back to it.
+#[tracing::instrument(skip_all)]
+pub async fn tproxy_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    if let Some(listen_addr) = live_config(ctx).tproxy_listen {
+        #[cfg(target_os = "linux")]
+        {
+            linux::tproxy_serve(ctx, listen_addr).await
+        }
+        #[cfg(not(target_os = "linux"))]
+        {
+            let _ = listen_addr;
+            anyhow::bail!("transparent proxying only works on Linux")
+        }
+    } else {
+        smol::future::pending().await
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+        os::fd::{AsRawFd, RawFd},
+    };
+
+    use anyctx::AnyCtx;
+    use nursery_macro::nursery;
+    use sillad::tcp::TcpPipe;
+    use smol::Async;
+    use socket2::{Domain, Socket, Type};
+
+    use super::*;
+    use crate::{app_rules::canonical, litecopy::litecopy};
+
+    pub async fn tproxy_serve(ctx: &AnyCtx<Config>, listen_addr: SocketAddr) -> anyhow::Result<()> {
+        let socket = Socket::new(Domain::for_address(listen_addr), Type::STREAM, None)?;
+        socket.set_reuse_address(true)?;
+        // TPROXY needs this, but it takes CAP_NET_ADMIN, and REDIRECT works fine without it
+        if let Err(err) = socket.set_ip_transparent(true) {
+            tracing::warn!(
+                err = debug(err),
+                "could not make the transparent proxy socket transparent, so only REDIRECT rules will work"
+            );
+        }
+        socket.bind(&listen_addr.into())?;
+        socket.listen(1024)?;
+        let listener = Async::new(std::net::TcpListener::from(socket))?;
+        let listen_addr = listener.get_ref().local_addr()?;
+        nursery!({
+            loop {
+                let (client, peer_addr) = listener.accept().await?;
+                let task = spawn!(async move {
+                    let dest = original_dst(client.as_raw_fd())
+                        .or_else(|_| client.get_ref().local_addr())?;
+                    // without a redirect, the client was talking to us directly, and tunneling that would loop forever
+                    if dest.port() == listen_addr.port() && is_local_ip(canonical(dest.ip())) {
+                        anyhow::bail!("connection to the transparent proxy was not redirected");
+                    }
+                    tracing::trace!(
+                        peer_addr = display(peer_addr),
+                        dest = display(dest),
+                        "transparent proxy connection accepted"
+                    );
+                    client.get_ref().set_nodelay(true)?;
+                    let stream = open_conn(ctx, "tcp", &dest.to_string()).await?;
+                    litecopy(TcpPipe::new(client), stream).await?;
+                    anyhow::Ok(())
+                });
+                add_task(ctx, TaskPriority::Normal, task);
+            }
+        })
+    }
+
+    /// Whether an address belongs to this machine. Only local addresses can be bound to, at least without the transparent option, which a plain UDP socket doesn't have.
+    fn is_local_ip(ip: IpAddr) -> bool {
+        ip.is_loopback() || std::net::UdpSocket::bind(SocketAddr::new(ip, 0)).is_ok()
+    }
+
+    /// Asks netfilter where a REDIRECTed connection was going before it got redirected. With TPROXY there is no NAT, so this fails, but then the socket's local address is the original destination anyway.
+    fn original_dst(fd: RawFd) -> std::io::Result<SocketAddr> {
+        let mut addr: libc::sockaddr_in6 = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+        // the IPv4 option fills in a sockaddr_in, which fits inside the bigger sockaddr_in6
+        let mut res = unsafe {
+            libc::getsockopt(
+                fd,
+                libc::SOL_IP,
+                libc::SO_ORIGINAL_DST,
+                &mut addr as *mut _ as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if res != 0 {
+            len = std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t;
+            res = unsafe {
+                libc::getsockopt(
+                    fd,
+                    libc::SOL_IPV6,
+                    libc::IP6T_SO_ORIGINAL_DST,
+                    &mut addr as *mut _ as *mut libc::c_void,
+                    &mut len,
+                )
+            };
+        }
+        if res != 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+        match addr.sin6_family as libc::c_int {
+            libc::AF_INET => {
+                let addr: libc::sockaddr_in =
+                    unsafe { std::ptr::read(&addr as *const _ as *const libc::sockaddr_in) };
+                Ok(SocketAddrV4::new(
+                    Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)),
+                    u16::from_be(addr.sin_port),
+                )
+                .into())
+            }
+            libc::AF_INET6 => Ok(SocketAddrV6::new(
+                Ipv6Addr::from(addr.sin6_addr.s6_addr),
+                u16::from_be(addr.sin6_port),
+                addr.sin6_flowinfo,
+                addr.sin6_scope_id,
+            )
+            .into()),
+            _ => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "unknown address family for original destination",
+            )),
+        }
+    }
+}
@@ -0,0 +1,105 @@
+use anyctx::AnyCtx;
+use anyhow::Context as _;
+use bytes::Bytes;
+use futures_util::{
+    io::{ReadHalf, WriteHalf},
+    AsyncReadExt as _, AsyncWriteExt as _,
+};
+use sillad::Pipe;
+use smol::{lock::Mutex, net::UdpSocket};
+
+use crate::{
+    client_inner::{backtranslate, goes_direct, open_conn, resolve_direct},
+    Config,
+};
+
+/// A flow of UDP datagrams to one destination, either through the tunnel or straight from this machine.
+///
+/// Through the tunnel, the datagrams are carried over a stream opened with the `udp` protocol, each one prefixed with its length as a little-endian u16.
+pub enum UdpFlow {
+    Tunneled {
+        read: Mutex<ReadHalf<Box<dyn Pipe>>>,
+        write: Mutex<WriteHalf<Box<dyn Pipe>>>,
+    },
+    Direct(UdpSocket),
+}
+
+impl UdpFlow {
+    /// Opens a flow to `dest_addr`, going by the routing rules just like [open_conn] does.
+    pub async fn open(ctx: &AnyCtx<Config>, dest_addr: &str) -> anyhow::Result<Self> {
+        let dest_addr = backtranslate(ctx, dest_addr);
+        if goes_direct(ctx, &dest_addr)? {
+            tracing::debug!(
+                dest_addr = debug(&dest_addr),
+                "passing through whitelisted UDP address"
+            );
+            Self::direct(ctx, &dest_addr).await
+        } else {
+            Self::tunneled(ctx, &dest_addr).await
+        }
+    }
+
+    /// Opens a flow to `dest_addr` through the tunnel.
+    pub async fn tunneled(ctx: &AnyCtx<Config>, dest_addr: &str) -> anyhow::Result<Self> {
+        let (read, write) = open_conn(ctx, "udp", dest_addr).await?.split();
+        Ok(Self::Tunneled {
+            read: Mutex::new(read),
+            write: Mutex::new(write),
+        })
+    }
+
+    /// Opens a flow to `dest_addr` that skips the tunnel.
+    pub async fn direct(ctx: &AnyCtx<Config>, dest_addr: &str) -> anyhow::Result<Self> {
+        let dest = *resolve_direct(ctx, dest_addr)
+            .await?
+            .first()
+            .context("no addresses for direct UDP destination")?;
+        let socket = if dest.is_ipv4() {
+            UdpSocket::bind("0.0.0.0:0").await?
+        } else {
+            UdpSocket::bind("[::]:0").await?
+        };
+        socket.connect(dest).await?;
+        Ok(Self::Direct(socket))
+    }
+
+    /// Sends one datagram.
+    pub async fn send(&self, datagram: &[u8]) -> anyhow::Result<()> {
+        match self {
+            Self::Tunneled { write, .. } => {
+                let len: u16 = datagram
+                    .len()
+                    .try_into()
+                    .context("datagram too long for the tunnel")?;
+                let mut write = write.lock().await;
+                write.write_all(&len.to_le_bytes()).await?;
+                write.write_all(datagram).await?;
+                write.flush().await?;
+            }
+            Self::Direct(socket) => {
+                socket.send(datagram).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Receives one datagram.
+    pub async fn recv(&self) -> anyhow::Result<Bytes> {
+        match self {
+            Self::Tunneled { read, .. } => {
+                let mut read = read.lock().await;
+                let mut len_buf = [0u8; 2];
+                read.read_exact(&mut len_buf).await?;
+                let mut buf = vec![0u8; u16::from_le_bytes(len_buf) as usize];
+                read.read_exact(&mut buf).await?;
+                Ok(buf.into())
+            }
+            Self::Direct(socket) => {
+                let mut buf = vec![0u8; 65536];
+                let n = socket.recv(&mut buf).await?;
+                buf.truncate(n);
+                Ok(buf.into())
+            }
+        }
+    }
+}
@@ -0,0 +1,330 @@
+use std::{collections::BTreeMap, path::PathBuf, time::Duration};
+
+use anyctx::AnyCtx;
+use anyhow::Context as _;
+use ed25519_dalek::VerifyingKey;
+use geph5_broker_protocol::Signed;
+use serde::{Deserialize, Serialize};
+
+use crate::Config;
+
+/// The domain that update manifests are signed under.
+pub const DOMAIN_UPDATE_MANIFEST: &str = "update-manifest";
+
+/// How often to check for updates.
+const CHECK_INTERVAL: Duration = Duration::from_secs(6 * 3600);
+
+/// The biggest build we are willing to download, so that a bad manifest can't fill up the disk.
+const MAX_BUILD_SIZE: u64 = 200_000_000;
+
+/// The biggest manifest we are willing to download, since it's fetched before its signature can be checked.
+const MAX_MANIFEST_SIZE: u64 = 1_000_000;
+
+const STAGED_BINARY: &str = "geph5-client.staged";
+const STAGED_INFO: &str = "staged.json";
+
+/// Where and how to check for new versions of the client.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UpdateConfig {
+    /// A URL serving a JSON-encoded, signed [UpdateManifest]. It can be fetched over any network, since the signature is what's trusted.
+    pub manifest_url: String,
+    /// The ed25519 key that manifests must be signed with, in hexadecimal.
+    pub signing_key: String,
+    /// Where downloaded updates wait until the client next starts.
+    pub staging_dir: PathBuf,
+}
+
+/// The latest version of the client, and where to download it for each platform.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UpdateManifest {
+    pub version: String,
+    /// Keyed by platform, as architecture-os like "x86_64-linux".
+    pub builds: BTreeMap<String, UpdateBuild>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UpdateBuild {
+    pub url: String,
+    /// The blake3 hash of the binary, in hexadecimal.
+    pub blake3: String,
+    pub size: u64,
+    /// Patches that turn older builds into this one, which are much smaller to download than the whole build.
+    #[serde(default)]
+    pub deltas: Vec<UpdateDelta>,
+}
+
+/// A patch from one particular older build, in the format that [apply_delta] reads.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UpdateDelta {
+    /// The blake3 hash of the build that the patch applies to, in hexadecimal.
+    pub from_blake3: String,
+    pub url: String,
+    pub size: u64,
+}
+
+/// Checks for updates every few hours, downloading any newer version so that it's applied the next time the client starts.
+pub async fn update_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    let Some(updates) = &ctx.init().updates else {
+        return smol::future::pending().await;
+    };
+    loop {
+        match check_for_update(updates).await {
+            Ok(Some(version)) => tracing::info!(version, "update staged for the next start"),
+            Ok(None) => tracing::debug!("no update available"),
+            Err(err) => tracing::warn!(err = debug(err), "could not check for updates"),
+        }
+        smol::Timer::after(CHECK_INTERVAL).await;
+    }
+}
+
+/// Fetches the manifest, and downloads and stages the build for this platform if it's newer than both us and whatever is already staged. Returns the staged version, if any.
+pub async fn check_for_update(updates: &UpdateConfig) -> anyhow::Result<Option<String>> {
+    let client = reqwest::Client::builder().no_proxy().build()?;
+    let raw = download_capped(&client, &updates.manifest_url, MAX_MANIFEST_SIZE).await?;
+    let signed: Signed<UpdateManifest> =
+        serde_json::from_slice(&raw).context("could not parse update manifest")?;
+    let manifest = verify_manifest(updates, signed.clone())?;
+
+    if !is_newer(&manifest.version, env!("CARGO_PKG_VERSION")) {
+        return Ok(None);
+    }
+    if let Some(staged) = read_staged_manifest(updates) {
+        if !is_newer(&manifest.version, &staged.version) {
+            return Ok(Some(staged.version));
+        }
+    }
+    let build = platform_build(&manifest)?;
+    anyhow::ensure!(build.size <= MAX_BUILD_SIZE, "build is too big");
+
+    // a patched build goes through exactly the same checks as a downloaded one, and we fall back to downloading it whole if anything is off
+    let binary = match download_via_delta(&client, build).await {
+        Ok(Some(binary)) if check_build(&binary, build).is_ok() => binary,
+        Ok(Some(_)) => {
+            tracing::warn!("patched build does not match the manifest, downloading it whole");
+            download_full(&client, build).await?
+        }
+        Ok(None) => download_full(&client, build).await?,
+        Err(err) => {
+            tracing::warn!(
+                err = debug(err),
+                "could not patch to the new build, downloading it whole"
+            );
+            download_full(&client, build).await?
+        }
+    };
+    check_build(&binary, build)?;
+
+    // written under temporary names first, so that a half-written update is never applied
+    smol::fs::create_dir_all(&updates.staging_dir).await?;
+    let binary_path = updates.staging_dir.join(STAGED_BINARY);
+    let info_path = updates.staging_dir.join(STAGED_INFO);
+    let _ = smol::fs::remove_file(&info_path).await;
+    smol::fs::write(binary_path.with_extension("partial"), &binary).await?;
+    smol::fs::rename(binary_path.with_extension("partial"), &binary_path).await?;
+    // the signed manifest itself, so that the build can be checked against it again when it's applied
+    smol::fs::write(
+        info_path.with_extension("partial"),
+        serde_json::to_vec(&signed)?,
+    )
+    .await?;
+    smol::fs::rename(info_path.with_extension("partial"), &info_path).await?;
+    Ok(Some(manifest.version))
+}
+
+async fn download_full(client: &reqwest::Client, build: &UpdateBuild) -> anyhow::Result<Vec<u8>> {
+    download_capped(client, &build.url, build.size).await
+}
+
+/// Downloads something that should be no bigger than `max_size`, giving up as soon as it turns out to be bigger rather than after it has all been buffered.
+async fn download_capped(
+    client: &reqwest::Client,
+    url: &str,
+    max_size: u64,
+) -> anyhow::Result<Vec<u8>> {
+    let mut response = client.get(url).send().await?.error_for_status()?;
+    let mut body = Vec::with_capacity(max_size.min(MAX_BUILD_SIZE) as usize);
+    while let Some(chunk) = response.chunk().await? {
+        anyhow::ensure!(
+            (body.len() + chunk.len()) as u64 <= max_size,
+            "download from {url} is bigger than the expected {max_size} bytes"
+        );
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// Builds the new version by patching the running executable, if the manifest has a delta from it. The result still has to be checked against the manifest.
+async fn download_via_delta(
+    client: &reqwest::Client,
+    build: &UpdateBuild,
+) -> anyhow::Result<Option<Vec<u8>>> {
+    if build.deltas.is_empty() {
+        return Ok(None);
+    }
+    let current = smol::unblock(|| std::fs::read(std::env::current_exe()?)).await?;
+    let current_hash = blake3::hash(&current);
+    let Some(delta) = build
+        .deltas
+        .iter()
+        .find(|delta| delta.from_blake3.to_ascii_lowercase() == current_hash.to_hex().as_str())
+    else {
+        return Ok(None);
+    };
+    anyhow::ensure!(delta.size <= MAX_BUILD_SIZE, "delta is too big");
+    let patch = download_capped(client, &delta.url, delta.size).await?;
+    anyhow::ensure!(
+        patch.len() as u64 == delta.size,
+        "downloaded delta has the wrong size"
+    );
+    Ok(Some(apply_delta(&current, &patch, build.size)?))
+}
+
+fn check_build(binary: &[u8], build: &UpdateBuild) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        binary.len() as u64 == build.size,
+        "downloaded build has the wrong size"
+    );
+    anyhow::ensure!(
+        blake3::hash(binary).to_hex().as_str() == build.blake3.to_ascii_lowercase(),
+        "downloaded build has the wrong hash"
+    );
+    Ok(())
+}
+
+const DELTA_MAGIC: &[u8] = b"geph5-delta-1\n";
+const DELTA_COPY: u8 = 0;
+const DELTA_INSERT: u8 = 1;
+
+/// Applies a delta to an old build, producing a new build of exactly `size` bytes.
+///
+/// A delta is [DELTA_MAGIC] followed by a sequence of operations, each a tag byte and little-endian u64 fields:
+/// - [DELTA_COPY], offset, length: copies that range of the old build.
+/// - [DELTA_INSERT], length, then that many bytes: inserts those bytes.
+pub fn apply_delta(old: &[u8], delta: &[u8], size: u64) -> anyhow::Result<Vec<u8>> {
+    let mut rest = delta
+        .strip_prefix(DELTA_MAGIC)
+        .context("delta has the wrong magic")?;
+    let mut out = Vec::with_capacity(size.min(MAX_BUILD_SIZE) as usize);
+    while let Some((&tag, tail)) = rest.split_first() {
+        rest = tail;
+        let piece = match tag {
+            DELTA_COPY => {
+                let offset = take_u64(&mut rest)?;
+                let len = take_u64(&mut rest)?;
+                old.get(offset..offset.checked_add(len).context("delta overflows")?)
+                    .context("delta copies from outside the old build")?
+            }
+            DELTA_INSERT => {
+                let len = take_u64(&mut rest)?;
+                take(&mut rest, len)?
+            }
+            tag => anyhow::bail!("unknown delta operation {tag}"),
+        };
+        anyhow::ensure!(
+            (out.len() + piece.len()) as u64 <= size,
+            "delta makes the build too big"
+        );
+        out.extend_from_slice(piece);
+    }
+    anyhow::ensure!(out.len() as u64 == size, "delta makes the build too small");
+    Ok(out)
+}
+
+fn take<'a>(rest: &mut &'a [u8], n: usize) -> anyhow::Result<&'a [u8]> {
+    anyhow::ensure!(rest.len() >= n, "delta is truncated");
+    let (head, tail) = rest.split_at(n);
+    *rest = tail;
+    Ok(head)
+}
+
+fn take_u64(rest: &mut &[u8]) -> anyhow::Result<usize> {
+    Ok(u64::from_le_bytes(take(rest, 8)?.try_into()?).try_into()?)
+}
+
+/// Replaces the running executable with the staged update, if there is one, returning whether it did. This should be called at startup, before the client starts; the update takes effect once the process is started again.
+pub fn apply_staged_update(updates: &UpdateConfig) -> anyhow::Result<bool> {
+    let Some(staged) = read_staged_manifest(updates) else {
+        return Ok(false);
+    };
+    let binary_path = updates.staging_dir.join(STAGED_BINARY);
+    // a newer version may have been installed some other way since this was staged
+    if !is_newer(&staged.version, env!("CARGO_PKG_VERSION")) {
+        std::fs::remove_file(updates.staging_dir.join(STAGED_INFO))?;
+        let _ = std::fs::remove_file(&binary_path);
+        return Ok(false);
+    }
+    let binary = std::fs::read(&binary_path)?;
+    // checked again against the signed manifest, in case the staging directory was tampered with since
+    check_build(&binary, platform_build(&staged)?)
+        .context("staged update does not match its manifest")?;
+
+    let current = std::env::current_exe()?;
+    let new = current.with_extension("new");
+    let old = current.with_extension("old");
+    std::fs::write(&new, &binary)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&new, std::fs::Permissions::from_mode(0o755))?;
+    }
+    // a running executable can be renamed, but not always overwritten
+    let _ = std::fs::remove_file(&old);
+    std::fs::rename(&current, &old)?;
+    if let Err(err) = std::fs::rename(&new, &current) {
+        std::fs::rename(&old, &current)?;
+        return Err(err.into());
+    }
+    std::fs::remove_file(&binary_path)?;
+    std::fs::remove_file(updates.staging_dir.join(STAGED_INFO))?;
+    tracing::info!(version = staged.version, "applied staged update");
+    Ok(true)
+}
+
+/// Reads the manifest of the staged update, if there is one and it's still signed by the right key.
+fn read_staged_manifest(updates: &UpdateConfig) -> Option<UpdateManifest> {
+    let info = std::fs::read(updates.staging_dir.join(STAGED_INFO)).ok()?;
+    let signed: Signed<UpdateManifest> = serde_json::from_slice(&info).ok()?;
+    match verify_manifest(updates, signed) {
+        Ok(manifest) => Some(manifest),
+        Err(err) => {
+            tracing::warn!(err = debug(err), "ignoring staged update");
+            None
+        }
+    }
+}
+
+fn verify_manifest(
+    updates: &UpdateConfig,
+    signed: Signed<UpdateManifest>,
+) -> anyhow::Result<UpdateManifest> {
+    let signing_key = VerifyingKey::from_bytes(
+        hex::decode(&updates.signing_key)
+            .context("signing key is not hexadecimal")?
+            .as_slice()
+            .try_into()
+            .context("signing key has the wrong length")?,
+    )?;
+    signed
+        .verify(DOMAIN_UPDATE_MANIFEST, |pk| *pk == signing_key)
+        .context("could not verify update manifest")
+}
+
+/// The build for the platform we are running on.
+fn platform_build(manifest: &UpdateManifest) -> anyhow::Result<&UpdateBuild> {
+    let platform = format!("{}-{}", std::env::consts::ARCH, std::env::consts::OS);
+    manifest
+        .builds
+        .get(&platform)
+        .with_context(|| format!("version {} has no build for {platform}", manifest.version))
+}
+
+/// Compares dotted version numbers like "0.2.10", treating anything that isn't a number as zero.
+fn is_newer(version: &str, than: &str) -> bool {
+    let parse = |version: &str| -> Vec<u64> {
+        version
+            .split('.')
+            .map(|part| part.parse().unwrap_or_default())
+            .collect()
+    };
+    parse(version) > parse(than)
+}
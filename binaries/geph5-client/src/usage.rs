@@ -0,0 +1,57 @@
+use std::time::Duration;
+
+use anyctx::AnyCtx;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    control_prot::CURRENT_CONN_INFO,
+    database::{db_add_usage, db_usage_since},
+    stats::stat_get_num,
+    Config, ConnInfo,
+};
+
+/// How often traffic is written to the usage ledger. Traffic since the last write is lost if the client is killed.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Traffic through one exit on one day, in UTC.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UsageRecord {
+    /// The day, as YYYY-MM-DD.
+    pub day: String,
+    /// The exit's address.
+    pub exit: String,
+    pub rx_bytes: u64,
+    pub tx_bytes: u64,
+}
+
+/// Keeps a running record of traffic per day and per exit in the database, so that it adds up across restarts.
+pub async fn usage_ledger_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    let mut last_rx = stat_get_num(ctx, "total_rx_bytes");
+    let mut last_tx = stat_get_num(ctx, "total_tx_bytes");
+    loop {
+        smol::Timer::after(FLUSH_INTERVAL).await;
+        let rx = stat_get_num(ctx, "total_rx_bytes");
+        let tx = stat_get_num(ctx, "total_tx_bytes");
+        let (rx_bytes, tx_bytes) = ((rx - last_rx) as u64, (tx - last_tx) as u64);
+        if rx_bytes == 0 && tx_bytes == 0 {
+            continue;
+        }
+        // traffic goes to whatever exit we are connected to now, which is only slightly off after a switch
+        let exit = match &*ctx.get(CURRENT_CONN_INFO).lock() {
+            ConnInfo::Connected(info) => info.exit.c2e_listen.ip().to_string(),
+            ConnInfo::Connecting => "unknown".to_string(),
+        };
+        let day = chrono::Utc::now().date_naive().to_string();
+        if let Err(err) = db_add_usage(ctx, &day, &exit, rx_bytes, tx_bytes).await {
+            tracing::warn!(err = debug(err), "could not write to the usage ledger");
+            continue;
+        }
+        (last_rx, last_tx) = (rx, tx);
+    }
+}
+
+/// The usage ledger for the last `days` days, including today.
+pub async fn usage_history(ctx: &AnyCtx<Config>, days: u32) -> anyhow::Result<Vec<UsageRecord>> {
+    let since = chrono::Utc::now().date_naive() - chrono::Days::new(days.saturating_sub(1) as u64);
+    Ok(db_usage_since(ctx, &since.to_string()).await?)
+}
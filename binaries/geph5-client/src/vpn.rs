@@ -14,11 +14,11 @@ mod dummy;
 #[cfg(any(target_os = "android", target_os = "ios"))]
 pub use dummy::*;
 
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use anyctx::AnyCtx;
 use anyhow::Context;
-use futures_util::{AsyncReadExt, AsyncWriteExt, TryFutureExt as _};
+use futures_util::{AsyncReadExt, TryFutureExt as _};
 
 #[cfg(target_os = "windows")]
 mod windows;
@@ -33,13 +33,19 @@ mod macos;
 pub use macos::*;
 
 use crate::{
+    app_rules::{app_action, AppAction},
     client::CtxField,
-    client_inner::open_conn,
+    client_inner::{backtranslate, open_app_conn},
     spoof_dns::fake_dns_respond,
-    taskpool::add_task,
+    stats::stat_get_num,
+    taskpool::{add_task, TaskPriority},
+    udp::UdpFlow,
     Config,
 };
 
+/// How often the kill switch checks whether we are connected.
+const KILL_SWITCH_INTERVAL: Duration = Duration::from_millis(200);
+
 /// Force a particular packet to be sent through VPN mode, regardless of whether VPN mode is on.
 pub async fn send_vpn_packet(ctx: &AnyCtx<Config>, bts: Bytes) {
     tracing::trace!(
@@ -82,6 +88,18 @@ pub async fn vpn_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
         recv_captured,
         send_injected,
     );
+    let _kill_switch = if ctx.init().vpn && ctx.init().kill_switch {
+        kill_switch_engage()?;
+        unsafe {
+            libc::atexit(kill_switch_release_at_exit);
+        }
+        Some(smolscale::spawn(
+            kill_switch_loop(ctx.clone())
+                .inspect_err(|e| tracing::error!(e = debug(e), "kill switch stopped")),
+        ))
+    } else {
+        None
+    };
     let _shuffle = if ctx.init().vpn {
         smolscale::spawn(
             packet_shuffle(ctx.clone(), send_captured, recv_injected)
@@ -130,10 +148,12 @@ pub async fn vpn_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
                     peer_addr = display(peer_addr),
                     "captured a TCP"
                 );
+                let app_addr = captured.local_addr();
                 let ctx_clone = ctx.clone();
 
                 let task = smolscale::spawn(async move {
-                    let tunneled = open_conn(&ctx_clone, "tcp", &peer_addr.to_string()).await?;
+                    let tunneled =
+                        open_app_conn(&ctx_clone, app_addr, "tcp", &peer_addr.to_string()).await?;
                     tracing::trace!(peer_addr = display(peer_addr), "dialed through VPN");
                     let (read_tunneled, write_tunneled) = tunneled.split();
                     let (read_captured, write_captured) = captured.split();
@@ -143,11 +163,7 @@ pub async fn vpn_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
                     anyhow::Ok(())
                 });
 
-                if let Some(task_limit) = ctx.init().task_limit {
-                    add_task(task_limit, task);
-                } else {
-                    task.detach();
-                }
+                add_task(ctx, TaskPriority::Normal, task);
             }
             ipstack_geph::stream::IpStackStream::Udp(captured) => {
                 let peer_addr = captured.peer_addr();
@@ -170,36 +186,30 @@ pub async fn vpn_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
                             captured.send(&fake_dns_respond(&ctx_clone, &pkt)?).await?;
                         }
                     } else {
-                        let tunneled = open_conn(&ctx_clone, "udp", &peer_addr.to_string()).await?;
-                        let (mut read_tunneled, mut write_tunneled) = tunneled.split();
+                        let flow = if app_action(&ctx_clone, "udp", captured.local_addr()).await
+                            == AppAction::Direct
+                        {
+                            let dest_addr = backtranslate(&ctx_clone, &peer_addr.to_string());
+                            UdpFlow::direct(&ctx_clone, &dest_addr).await?
+                        } else {
+                            UdpFlow::open(&ctx_clone, &peer_addr.to_string()).await?
+                        };
                         let up_loop = async {
                             loop {
                                 let to_up = captured.recv().await?;
-                                write_tunneled
-                                    .write_all(&(to_up.len() as u16).to_le_bytes())
-                                    .await?;
-                                write_tunneled.write_all(&to_up).await?;
-                                write_tunneled.flush().await?;
+                                flow.send(&to_up).await?;
                             }
                         };
                         let dn_loop = async {
                             loop {
-                                let mut len_buf = [0u8; 2];
-                                read_tunneled.read_exact(&mut len_buf).await?;
-                                let len = u16::from_le_bytes(len_buf) as usize;
-                                let mut buf = vec![0u8; len];
-                                read_tunneled.read_exact(&mut buf).await?;
-                                captured.send(&buf).await?;
+                                let to_down = flow.recv().await?;
+                                captured.send(&to_down).await?;
                             }
                         };
                         up_loop.race(dn_loop).await
                     }
                 });
-                if let Some(task_limit) = ctx.init().task_limit {
-                    add_task(task_limit, task);
-                } else {
-                    task.detach();
-                }
+                add_task(ctx, TaskPriority::Low, task);
             }
             ipstack_geph::stream::IpStackStream::UnknownTransport(_) => {
                 // tracing::warn!("captured an UnknownTransport")
@@ -210,3 +220,24 @@ pub async fn vpn_loop(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
         }
     }
 }
+
+/// Keeps the kill switch engaged whenever no session is up, so that nothing leaks around the tunnel before we first connect or while we reconnect. It starts out engaged.
+async fn kill_switch_loop(ctx: AnyCtx<Config>) -> anyhow::Result<()> {
+    let mut engaged = true;
+    loop {
+        smol::Timer::after(KILL_SWITCH_INTERVAL).await;
+        let connected = stat_get_num(&ctx, "active_sessions") > 0.0;
+        if connected && engaged {
+            smol::unblock(kill_switch_release).await;
+            tracing::info!("connected, kill switch released");
+        } else if !connected && !engaged {
+            smol::unblock(kill_switch_engage).await?;
+            tracing::warn!("not connected, kill switch engaged");
+        }
+        engaged = !connected;
+    }
+}
+
+extern "C" fn kill_switch_release_at_exit() {
+    kill_switch_release();
+}
@@ -17,3 +17,9 @@ pub(super) async fn packet_shuffle(
 pub fn vpn_whitelist(_addr: IpAddr) {
     // noop
 }
+
+pub(super) fn kill_switch_engage() -> anyhow::Result<()> {
+    anyhow::bail!("the kill switch is not supported on this platform")
+}
+
+pub(super) fn kill_switch_release() {}
@@ -12,15 +12,23 @@ use smol::{
     net::UdpSocket,
 };
 use std::{
-    net::{IpAddr, Ipv4Addr},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
     process::Command,
-    sync::LazyLock,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        LazyLock,
+    },
 };
 
-use crate::{client_inner::open_conn, spoof_dns::fake_dns_respond, Config};
+use crate::{
+    client_inner::open_conn, dns::tunnel_dns_resolve, spoof_dns::fake_dns_respond, Config,
+};
 
 const FAKE_LOCAL_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(100, 64, 89, 64));
 
+/// Whether traffic is currently being routed into the TUN device.
+static ROUTING_UP: AtomicBool = AtomicBool::new(false);
+
 pub fn vpn_whitelist(addr: IpAddr) {
     WHITELIST.entry(addr).or_insert_with(|| {
         tracing::warn!(addr = display(addr), "*** WHITELIST ***");
@@ -28,6 +36,87 @@ pub fn vpn_whitelist(addr: IpAddr) {
     });
 }
 
+/// Whether traffic to this address is routed around the tunnel.
+pub fn vpn_whitelisted(addr: IpAddr) -> bool {
+    WHITELIST.contains_key(&addr)
+}
+
+/// Whether VPN mode has set up routing into the TUN device, which only happens once the first connection is up.
+pub fn vpn_routing_active() -> bool {
+    ROUTING_UP.load(Ordering::SeqCst)
+}
+
+/// Starts recording, packet by packet, where traffic that goes around the tunnel is headed. The record is kept in nftables until routing is torn down.
+pub fn leak_watch_start() -> anyhow::Result<()> {
+    let (cgroup, cgroup_level) = own_cgroup()?;
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(include_str!("linux_leak_watch_setup.sh"))
+        .env("GEPH_CGROUP", cgroup)
+        .env("GEPH_CGROUP_LEVEL", cgroup_level.to_string())
+        .status()?;
+    anyhow::ensure!(
+        status.success(),
+        "nftables leak watch was not set up properly"
+    );
+    Ok(())
+}
+
+/// The flows that sent packets around the tunnel within the last 30 seconds, as their protocol, local address, and remote address.
+pub fn leaked_flows() -> Vec<(&'static str, IpAddr, SocketAddr)> {
+    let mut flows = vec![];
+    for set in ["leaks4", "leaks6"] {
+        let Ok(output) = Command::new("sh")
+            .arg("-c")
+            .arg(format!(
+                "export PATH=$PATH:/usr/sbin/:/sbin/; nft -nnn list set inet geph5_leak_watch {set}"
+            ))
+            .output()
+        else {
+            continue;
+        };
+        let listing = String::from_utf8_lossy(&output.stdout);
+        let Some((_, elements)) = listing.split_once("elements = {") else {
+            continue;
+        };
+        let elements = elements.split('}').next().unwrap_or_default();
+        for element in elements.split(',') {
+            // "local . remote . protocol . port", then the timeout and when it expires
+            let words: Vec<&str> = element.split_whitespace().collect();
+            let (Some(local), Some(remote), Some(protocol), Some(port)) = (
+                words.first().and_then(|s| s.parse::<IpAddr>().ok()),
+                words.get(2).and_then(|s| s.parse::<IpAddr>().ok()),
+                words.get(4).and_then(|s| match *s {
+                    "tcp" | "6" => Some("tcp"),
+                    "udp" | "17" => Some("udp"),
+                    _ => None,
+                }),
+                words.get(6).and_then(|s| s.parse::<u16>().ok()),
+            ) else {
+                continue;
+            };
+            flows.push((protocol, local, SocketAddr::new(remote, port)));
+        }
+    }
+    flows
+}
+
+/// The cgroup v2 path we already live in, which may well be our systemd unit's, along with how deep it is. The root cgroup is an empty path.
+fn own_cgroup() -> anyhow::Result<(String, usize)> {
+    let cgroup = std::fs::read_to_string("/proc/self/cgroup")?
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .context("not running under cgroup v2")?
+        .trim_matches('/')
+        .to_string();
+    let level = if cgroup.is_empty() {
+        0
+    } else {
+        cgroup.split('/').count()
+    };
+    Ok((cgroup, level))
+}
+
 #[allow(clippy::redundant_closure)]
 fn setup_routing() -> anyhow::Result<()> {
     let cmd = include_str!("linux_routing_setup.sh");
@@ -42,14 +131,44 @@ fn setup_routing() -> anyhow::Result<()> {
     anyhow::Ok(())
 }
 
+/// Blocks all traffic that would leak around the tunnel.
+pub(super) fn kill_switch_engage() -> anyhow::Result<()> {
+    let cmd = include_str!("linux_kill_switch_setup.sh");
+    let (cgroup, cgroup_level) = own_cgroup()?;
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(cmd)
+        .env("GEPH_PID", std::process::id().to_string())
+        .env("GEPH_CGROUP", &cgroup)
+        .env("GEPH_CGROUP_LEVEL", cgroup_level.to_string())
+        .status()?;
+    anyhow::ensure!(
+        status.success(),
+        "nftables kill switch was not set up properly"
+    );
+    Ok(())
+}
+
+/// Lifts the block put in place by [kill_switch_engage].
+pub(super) fn kill_switch_release() {
+    let _ = Command::new("sh")
+        .arg("-c")
+        .arg("export PATH=$PATH:/usr/sbin/:/sbin/; nft delete table inet geph5_kill_switch")
+        .status();
+}
+
 static GEPH_DNS: LazyLock<Mutex<String>> = LazyLock::new(|| Mutex::new(String::new()));
 
+static GEPH_DNS_IPV6: LazyLock<Mutex<String>> = LazyLock::new(|| Mutex::new(String::new()));
+
 extern "C" fn teardown_routing() {
     tracing::debug!(
         "!!!!!!!!!!!!!!!!!!!!!!! teardown_routing starting !!!!!!!!!!!!!!!!!!!!!!!!!!!!!"
     );
+    ROUTING_UP.store(false, Ordering::SeqCst);
     WHITELIST.clear();
     std::env::set_var("GEPH_DNS", GEPH_DNS.lock().clone());
+    std::env::set_var("GEPH_DNS_IPV6", GEPH_DNS_IPV6.lock().clone());
     let cmd = include_str!("linux_routing_teardown.sh");
     let mut child = Command::new("sh").arg("-c").arg(cmd).spawn().unwrap();
     child.wait().expect("iptables was not set up properly");
@@ -64,40 +183,11 @@ pub(super) async fn packet_shuffle(
     let dns_proxy = UdpSocket::bind("127.0.0.1:0").await?;
     *GEPH_DNS.lock() = dns_proxy.local_addr()?.to_string();
     std::env::set_var("GEPH_DNS", GEPH_DNS.lock().clone());
+    let dns_proxy_v6 = UdpSocket::bind("[::1]:0").await?;
+    *GEPH_DNS_IPV6.lock() = dns_proxy_v6.local_addr()?.to_string();
+    std::env::set_var("GEPH_DNS_IPV6", GEPH_DNS_IPV6.lock().clone());
 
-    tracing::info!(
-        addr = display(dns_proxy.local_addr().unwrap()),
-        "start DNS proxy"
-    );
-    let dns_proxy_loop = async {
-        loop {
-            let mut buf = [0u8; 8192];
-            let (n, src) = dns_proxy.recv_from(&mut buf).await?;
-            tracing::trace!(n, src = display(src), "received DNS packet");
-            if ctx.init().spoof_dns {
-                if let Ok(resp) = fake_dns_respond(&ctx, &buf[..n]) {
-                    let _ = dns_proxy.send_to(&resp, src).await;
-                }
-            } else {
-                let dns_proxy = dns_proxy.clone();
-                let ctx = ctx.clone();
-                smolscale::spawn(async move {
-                    let buf = &buf[..n];
-                    let mut conn = open_conn(&ctx, "udp", "1.1.1.1:53").await?;
-                    conn.write_all(&(buf.len() as u16).to_le_bytes()).await?;
-                    conn.write_all(buf).await?;
-                    let mut len_buf = [0u8; 2];
-                    conn.read_exact(&mut len_buf).await?;
-                    let len = u16::from_le_bytes(len_buf) as usize;
-                    let mut buf = vec![0u8; len];
-                    conn.read_exact(&mut buf).await?;
-                    dns_proxy.send_to(&buf, src).await?;
-                    anyhow::Ok(())
-                })
-                .detach();
-            }
-        }
-    };
+    let dns_proxy_loops = dns_proxy_loop(&ctx, dns_proxy).race(dns_proxy_loop(&ctx, dns_proxy_v6));
 
     use std::os::fd::{AsRawFd, FromRawFd};
     let tun_device = configure_tun_device();
@@ -108,6 +198,7 @@ pub(super) async fn packet_shuffle(
     // wait until we have a connection
     open_conn(&ctx, "", "").await?;
     setup_routing().unwrap();
+    ROUTING_UP.store(true, Ordering::SeqCst);
     scopeguard::defer!(teardown_routing());
     let (mut read, mut write) = up_file.split();
     let inject = async {
@@ -126,7 +217,33 @@ pub(super) async fn packet_shuffle(
             send_captured.send(Bytes::copy_from_slice(buf)).await?;
         }
     };
-    inject.race(capture).race(dns_proxy_loop).await
+    inject.race(capture).race(dns_proxy_loops).await
+}
+
+async fn dns_proxy_loop(ctx: &AnyCtx<Config>, dns_proxy: UdpSocket) -> anyhow::Result<()> {
+    tracing::info!(
+        addr = display(dns_proxy.local_addr().unwrap()),
+        "start DNS proxy"
+    );
+    loop {
+        let mut buf = [0u8; 8192];
+        let (n, src) = dns_proxy.recv_from(&mut buf).await?;
+        tracing::trace!(n, src = display(src), "received DNS packet");
+        if ctx.init().spoof_dns {
+            if let Ok(resp) = fake_dns_respond(ctx, &buf[..n]) {
+                let _ = dns_proxy.send_to(&resp, src).await;
+            }
+        } else {
+            let dns_proxy = dns_proxy.clone();
+            let ctx = ctx.clone();
+            smolscale::spawn(async move {
+                let resp = tunnel_dns_resolve(&ctx, &buf[..n]).await?;
+                dns_proxy.send_to(&resp, src).await?;
+                anyhow::Ok(())
+            })
+            .detach();
+        }
+    }
 }
 
 #[cfg(target_os = "linux")]
@@ -154,7 +271,8 @@ impl Drop for SingleWhitelister {
         Command::new("sh")
             .arg("-c")
             .arg(format!(
-                "/usr/bin/env ip rule del to {} lookup main pref 1",
+                "/usr/bin/env ip {} rule del to {} lookup main pref 1",
+                ip_family(self.dest),
                 self.dest
             ))
             .status()
@@ -167,7 +285,8 @@ impl SingleWhitelister {
         Command::new("sh")
             .arg("-c")
             .arg(format!(
-                "/usr/bin/env ip rule add to {} lookup main pref 1",
+                "/usr/bin/env ip {} rule add to {} lookup main pref 1",
+                ip_family(dest),
                 dest
             ))
             .status()
@@ -176,4 +295,12 @@ impl SingleWhitelister {
     }
 }
 
+fn ip_family(addr: IpAddr) -> &'static str {
+    if addr.is_ipv6() {
+        "-6"
+    } else {
+        "-4"
+    }
+}
+
 static WHITELIST: Lazy<DashMap<IpAddr, SingleWhitelister>> = Lazy::new(DashMap::new);
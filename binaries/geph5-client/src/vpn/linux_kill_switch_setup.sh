@@ -0,0 +1,34 @@
+export PATH=$PATH:/usr/sbin/:/sbin/
+
+# Our own traffic is recognized by the cgroup we already run in, so we never leave our systemd unit.
+# Only if we sit in the root cgroup, and thus in no unit at all, do we get a cgroup of our own.
+if [ -z "$GEPH_CGROUP" ]; then
+    mkdir -p /sys/fs/cgroup/geph5
+    echo $GEPH_PID > /sys/fs/cgroup/geph5/cgroup.procs
+    GEPH_CGROUP=geph5
+    GEPH_CGROUP_LEVEL=1
+fi
+
+# The configured resolvers stay reachable, since whatever resolves names for us (e.g. systemd-resolved,
+# whose upstream servers are listed in its own resolv.conf) runs outside our cgroup.
+RESOLVERS4=$(cat /etc/resolv.conf /run/systemd/resolve/resolv.conf 2>/dev/null | awk '$1 == "nameserver" && $2 !~ /:/ && $2 !~ /^127\./ { print $2 }' | sort -u | paste -sd, -)
+RESOLVERS6=$(cat /etc/resolv.conf /run/systemd/resolve/resolv.conf 2>/dev/null | awk '$1 == "nameserver" && $2 ~ /:/ && $2 != "::1" { sub(/%.*/, "", $2); print $2 }' | sort -u | paste -sd, -)
+
+# Drop everything that leaves neither through the TUN device nor from us, except to the local network and the resolvers
+nft -f - <<RULES
+table inet geph5_kill_switch
+delete table inet geph5_kill_switch
+table inet geph5_kill_switch {
+    chain output {
+        type filter hook output priority 0; policy drop;
+        oifname "lo" accept
+        oifname "tun-geph" accept
+        socket cgroupv2 level $GEPH_CGROUP_LEVEL "$GEPH_CGROUP" accept
+        ip daddr { 10.0.0.0/8, 172.16.0.0/12, 192.168.0.0/16, 169.254.0.0/16 } accept
+        ip6 daddr { fc00::/7, fe80::/10 } accept
+        udp dport 67 accept
+        ${RESOLVERS4:+ip daddr { $RESOLVERS4 \} meta l4proto { tcp, udp \} th dport 53 accept}
+        ${RESOLVERS6:+ip6 daddr { $RESOLVERS6 \} meta l4proto { tcp, udp \} th dport 53 accept}
+    }
+}
+RULES
@@ -0,0 +1,34 @@
+export PATH=$PATH:/usr/sbin/:/sbin/
+
+# Our own traffic is recognized by our cgroup, as in the kill switch. From the root cgroup, it can't be told apart from anybody else's.
+if [ -n "$GEPH_CGROUP" ]; then
+    OWN_TRAFFIC="socket cgroupv2 level $GEPH_CGROUP_LEVEL \"$GEPH_CGROUP\" return"
+fi
+
+# Record where every packet that leaves neither through the TUN device nor from us is headed, until 30 seconds after the last such packet.
+# This runs after the kill switch, so whatever the kill switch drops doesn't count.
+nft -f - <<RULES
+table inet geph5_leak_watch
+delete table inet geph5_leak_watch
+table inet geph5_leak_watch {
+    set leaks4 {
+        type ipv4_addr . ipv4_addr . inet_proto . inet_service
+        flags dynamic, timeout
+        timeout 30s
+        size 4096
+    }
+    set leaks6 {
+        type ipv6_addr . ipv6_addr . inet_proto . inet_service
+        flags dynamic, timeout
+        timeout 30s
+        size 4096
+    }
+    chain output {
+        type filter hook output priority 10; policy accept;
+        oifname { "lo", "tun-geph" } return
+        $OWN_TRAFFIC
+        meta l4proto { tcp, udp } update @leaks4 { ip saddr . ip daddr . meta l4proto . th dport }
+        meta l4proto { tcp, udp } update @leaks6 { ip6 saddr . ip6 daddr . meta l4proto . th dport }
+    }
+}
+RULES
@@ -6,7 +6,8 @@ ip route add default dev tun-geph table 8964
 
 # Clear IPv6 table (create it if it doesn't exist)
 ip -6 route flush table 8964
-ip -6 route add blackhole ::/0 table 8964
+ip -6 addr add fd64:6489:64::64/64 dev tun-geph
+ip -6 route add default dev tun-geph table 8964
 
 # Set up rules for IPv4
 ip rule add table main suppress_prefixlength 0
@@ -21,5 +22,5 @@ iptables -t nat -A OUTPUT -p udp --dport 53 -j DNAT --to $GEPH_DNS
 iptables -t nat -A OUTPUT -p tcp --dport 53 -j DNAT --to $GEPH_DNS
 
 # Redirect DNS requests for IPv6
-#ip6tables -t nat -A OUTPUT -p udp --dport 53 -j DNAT --to $GEPH_DNS_IPV6
-#ip6tables -t nat -A OUTPUT -p tcp --dport 53 -j DNAT --to $GEPH_DNS_IPV6
+ip6tables -t nat -A OUTPUT -p udp --dport 53 -j DNAT --to $GEPH_DNS_IPV6
+ip6tables -t nat -A OUTPUT -p tcp --dport 53 -j DNAT --to $GEPH_DNS_IPV6
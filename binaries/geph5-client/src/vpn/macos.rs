@@ -1,19 +1,196 @@
-use crate::Config;
 use anyctx::AnyCtx;
+use anyhow::Context;
 use bytes::Bytes;
-use ipstack_geph::IpStack;
+use dashmap::DashMap;
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+use once_cell::sync::Lazy;
 use smol::{
     channel::{Receiver, Sender},
     future::FutureExt as _,
 };
-use std::net::IpAddr;
+use std::{
+    net::{IpAddr, Ipv4Addr},
+    process::Command,
+};
+
+use crate::{client_inner::open_conn, Config};
+
+const FAKE_LOCAL_ADDR: IpAddr = IpAddr::V4(Ipv4Addr::new(100, 64, 89, 64));
+
+/// macOS only lets us name a utun by its unit number, so we pick one that nothing else is likely to use.
+const TUN_NAME: &str = "utun8964";
+
+/// The DNS server that network services are pointed at while VPN mode is on. It's inside the tunnel, so its queries get captured and answered like any other DNS traffic.
+const TUN_DNS: &str = "100.64.0.53";
+
+/// Where the DNS servers that network services had before VPN mode are kept, so that they can be put back.
+const DNS_BACKUP: &str = "/var/run/geph5-dns.backup";
+
+pub fn vpn_whitelist(addr: IpAddr) {
+    WHITELIST.entry(addr).or_insert_with(|| {
+        tracing::debug!(addr = display(addr), "whitelisting");
+        SingleWhitelister::new(addr)
+    });
+}
+
+fn routing_script(script: &str) -> Command {
+    let mut cmd = Command::new("bash");
+    cmd.arg("-c")
+        .arg(script)
+        .env("GEPH_TUN", TUN_NAME)
+        .env("GEPH_DNS", TUN_DNS)
+        .env("GEPH_DNS_BACKUP", DNS_BACKUP);
+    cmd
+}
+
+fn setup_routing() -> anyhow::Result<()> {
+    let status = routing_script(include_str!("macos_routing_setup.sh")).status()?;
+    anyhow::ensure!(status.success(), "routes were not set up properly");
+
+    unsafe {
+        libc::atexit(teardown_routing);
+    }
+    ctrlc::set_handler(|| {
+        teardown_routing();
+        std::process::exit(0);
+    })?;
+
+    anyhow::Ok(())
+}
+
+pub(super) fn kill_switch_engage() -> anyhow::Result<()> {
+    anyhow::bail!("the kill switch is not supported on this platform")
+}
+
+pub(super) fn kill_switch_release() {}
+
+extern "C" fn teardown_routing() {
+    tracing::debug!("tearing down routes");
+    WHITELIST.clear();
+    if let Err(err) = routing_script(include_str!("macos_routing_teardown.sh")).status() {
+        tracing::error!(err = debug(err), "could not tear down routes");
+    }
+}
 
 pub(super) async fn packet_shuffle(
     ctx: AnyCtx<Config>,
     send_captured: Sender<Bytes>,
     recv_injected: Receiver<Bytes>,
 ) -> anyhow::Result<()> {
-    todo!()
+    use std::os::fd::{AsRawFd, FromRawFd};
+    let tun_device = configure_tun_device()?;
+    // duplicated, so that the file and the device don't both close the same descriptor
+    let fd_num = unsafe { libc::dup(tun_device.as_raw_fd()) };
+    anyhow::ensure!(fd_num >= 0, "cannot duplicate the utun descriptor");
+    let up_file = smol::Async::new(unsafe { std::fs::File::from_raw_fd(fd_num) })
+        .context("cannot init up_file")?;
+
+    // wait until we have a connection
+    open_conn(&ctx, "", "").await?;
+    setup_routing()?;
+    scopeguard::defer!(teardown_routing());
+    let (mut read, mut write) = up_file.split();
+    // unlike a Linux TUN, every packet through a utun starts with its address family as a 4-byte big-endian integer
+    let inject = async {
+        loop {
+            let injected = recv_injected.recv().await?;
+            tracing::trace!(n = injected.len(), "going to inject into the utun");
+            let family = match injected.first().map(|b| b >> 4) {
+                Some(6) => libc::AF_INET6,
+                _ => libc::AF_INET,
+            };
+            let mut framed = Vec::with_capacity(injected.len() + 4);
+            framed.extend_from_slice(&(family as u32).to_be_bytes());
+            framed.extend_from_slice(&injected);
+            let _ = write.write(&framed).await?;
+        }
+    };
+    let capture = async {
+        let mut buf = vec![0u8; 65536];
+        loop {
+            let n = read.read(&mut buf).await?;
+            if n <= 4 {
+                continue;
+            }
+            tracing::trace!(n, "captured packet from utun");
+            send_captured
+                .send(Bytes::copy_from_slice(&buf[4..n]))
+                .await?;
+        }
+    };
+    inject.race(capture).await
+}
+
+fn configure_tun_device() -> anyhow::Result<tun::platform::Device> {
+    let device = tun::platform::Device::new(
+        tun::Configuration::default()
+            .name(TUN_NAME)
+            .address(FAKE_LOCAL_ADDR)
+            .netmask("255.255.255.0")
+            .destination("100.64.0.1")
+            .mtu(16384)
+            .up(),
+    )
+    .context("could not initialize utun device")?;
+    Ok(device)
+}
+
+/// Finds the gateway of the real default route. VPN mode never replaces it, only overrides it with more specific routes, so this keeps working while VPN mode is on.
+fn default_gateway(ipv6: bool) -> Option<String> {
+    let family = if ipv6 { "-inet6" } else { "-inet" };
+    let output = Command::new("/sbin/route")
+        .args(["-n", "get", family, "default"])
+        .output()
+        .ok()?;
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.trim().strip_prefix("gateway:"))
+        .map(|gateway| gateway.trim().to_string())
+}
+
+/// Routes one host around the tunnel, through the real default gateway, for as long as it lives.
+struct SingleWhitelister {
+    dest: IpAddr,
+}
+
+impl Drop for SingleWhitelister {
+    fn drop(&mut self) {
+        tracing::debug!("DROPPING whitelist to {}", self.dest);
+        let _ = Command::new("/sbin/route")
+            .args(["-q", "-n", "delete", family(self.dest), "-host"])
+            .arg(self.dest.to_string())
+            .status();
+    }
+}
+
+impl SingleWhitelister {
+    fn new(dest: IpAddr) -> Self {
+        match default_gateway(dest.is_ipv6()) {
+            Some(gateway) => {
+                let status = Command::new("/sbin/route")
+                    .args(["-q", "-n", "add", family(dest), "-host"])
+                    .arg(dest.to_string())
+                    .arg(gateway)
+                    .status();
+                if !status.is_ok_and(|status| status.success()) {
+                    tracing::warn!(dest = display(dest), "could not whitelist");
+                }
+            }
+            None => tracing::warn!(
+                dest = display(dest),
+                "no default gateway to whitelist through"
+            ),
+        }
+        Self { dest }
+    }
+}
+
+fn family(addr: IpAddr) -> &'static str {
+    if addr.is_ipv6() {
+        "-inet6"
+    } else {
+        "-inet"
+    }
 }
 
-pub fn vpn_whitelist(addr: IpAddr) {}
+static WHITELIST: Lazy<DashMap<IpAddr, SingleWhitelister>> = Lazy::new(DashMap::new);
@@ -0,0 +1,21 @@
+export PATH=$PATH:/usr/sbin/:/sbin/
+
+# Route everything into the utun with two half-routes. They win over the default route without replacing it, so whitelisted hosts can still be reached through the original gateway.
+route -q -n add -inet 0.0.0.0/1 -interface $GEPH_TUN
+route -q -n add -inet 128.0.0.0/1 -interface $GEPH_TUN
+
+ifconfig $GEPH_TUN inet6 fd64:6489:64::64 prefixlen 64
+route -q -n add -inet6 ::/1 -interface $GEPH_TUN
+route -q -n add -inet6 8000::/1 -interface $GEPH_TUN
+
+# Point every network service at a DNS server inside the tunnel. What each one had is saved first, unless a backup is still around from a run that didn't get to clean up, since that one holds the real settings.
+network_services=$(networksetup -listallnetworkservices | tail -n +2 | sed 's/^\*//')
+if [ ! -f "$GEPH_DNS_BACKUP" ]; then
+  while IFS= read -r service; do
+    servers=$(networksetup -getdnsservers "$service" | grep -v "aren't any" | tr '\n' ' ')
+    printf '%s\t%s\n' "$service" "${servers:-Empty}" >> "$GEPH_DNS_BACKUP"
+  done <<< "$network_services"
+fi
+while IFS= read -r service; do
+  networksetup -setdnsservers "$service" $GEPH_DNS
+done <<< "$network_services"
@@ -0,0 +1,14 @@
+export PATH=$PATH:/usr/sbin/:/sbin/
+
+route -q -n delete -inet 0.0.0.0/1 -interface $GEPH_TUN
+route -q -n delete -inet 128.0.0.0/1 -interface $GEPH_TUN
+route -q -n delete -inet6 ::/1 -interface $GEPH_TUN
+route -q -n delete -inet6 8000::/1 -interface $GEPH_TUN
+
+# Put back the DNS servers that each network service had before
+if [ -f "$GEPH_DNS_BACKUP" ]; then
+  while IFS=$'\t' read -r service servers; do
+    networksetup -setdnsservers "$service" $servers
+  done < "$GEPH_DNS_BACKUP"
+  rm -f "$GEPH_DNS_BACKUP"
+fi
@@ -1,7 +1,12 @@
 #[cfg(feature = "windivert")]
 mod windivert;
 
-use std::{net::IpAddr, time::Duration};
+use std::{
+    collections::HashSet,
+    net::{IpAddr, SocketAddr},
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
 
 use anyctx::AnyCtx;
 use anyhow::Context;
@@ -11,6 +16,7 @@ use bytes::Bytes;
 use dashmap::DashSet;
 
 use once_cell::sync::Lazy;
+use parking_lot::Mutex;
 use smol::channel::{Receiver, Sender};
 
 use crate::{client_inner::open_conn, Config};
@@ -37,12 +43,24 @@ pub(super) async fn packet_shuffle(
 fn up_shuffle(ctx: AnyCtx<Config>, send_captured: Sender<bytes::Bytes>) -> anyhow::Result<()> {
     smol::future::block_on(open_conn(&ctx, "", ""))?;
     let handle = windivert::PacketHandle::open("outbound and not loopback", -100)?;
+    ROUTING_UP.store(true, Ordering::SeqCst);
     loop {
         let fallible = || {
             let raw_pkt = handle.receive()?;
-            let ip_pkt = pnet_packet::ipv4::Ipv4Packet::new(&raw_pkt)
-                .context("cannot parse packet as IPv4")?;
-            if WHITELIST.contains(&IpAddr::V4(ip_pkt.get_destination())) {
+            let destination = match raw_pkt.first().map(|b| b >> 4) {
+                Some(4) => IpAddr::V4(
+                    pnet_packet::ipv4::Ipv4Packet::new(&raw_pkt)
+                        .context("cannot parse packet as IPv4")?
+                        .get_destination(),
+                ),
+                Some(6) => IpAddr::V6(
+                    pnet_packet::ipv6::Ipv6Packet::new(&raw_pkt)
+                        .context("cannot parse packet as IPv6")?
+                        .get_destination(),
+                ),
+                _ => anyhow::bail!("captured a packet that is neither IPv4 nor IPv6"),
+            };
+            if WHITELIST.contains(&destination) {
                 handle.inject(&raw_pkt, true)?;
                 anyhow::Ok(None)
             } else {
@@ -70,8 +88,190 @@ fn dn_shuffle(ctx: AnyCtx<Config>, recv_injected: Receiver<bytes::Bytes>) -> any
     }
 }
 
+/// The name of the Windows Firewall rules that let our own traffic and local network traffic through while the kill switch is engaged.
+const KILL_SWITCH_RULE: &str = "geph5-kill-switch";
+
+/// Blocks all traffic that would leak around the tunnel, by making Windows Firewall block outbound connections other than ours.
+///
+/// This also blocks connections that WinDivert would capture into the tunnel, since the firewall decides on connections before WinDivert sees their packets, so it can only be engaged while we are not connected.
+pub(super) fn kill_switch_engage() -> anyhow::Result<()> {
+    {
+        let mut saved = SAVED_FIREWALL_POLICIES.lock();
+        if saved.is_empty() {
+            for profile in FIREWALL_PROFILES {
+                saved.push((profile, firewall_policy(profile)?));
+            }
+        }
+    }
+    let exe = std::env::current_exe()?;
+    netsh(&[
+        "advfirewall",
+        "firewall",
+        "add",
+        "rule",
+        &format!("name={KILL_SWITCH_RULE}"),
+        "dir=out",
+        "action=allow",
+        &format!("program={}", exe.display()),
+        "enable=yes",
+    ])?;
+    netsh(&[
+        "advfirewall",
+        "firewall",
+        "add",
+        "rule",
+        &format!("name={KILL_SWITCH_RULE}"),
+        "dir=out",
+        "action=allow",
+        "remoteip=LocalSubnet",
+        "enable=yes",
+    ])?;
+    netsh(&[
+        "advfirewall",
+        "set",
+        "allprofiles",
+        "firewallpolicy",
+        "blockinbound,blockoutbound",
+    ])
+}
+
+/// Lifts the block put in place by [kill_switch_engage], going back to the firewall policies the user had before.
+pub(super) fn kill_switch_release() {
+    for (profile, policy) in SAVED_FIREWALL_POLICIES.lock().drain(..) {
+        let _ = netsh(&["advfirewall", "set", profile, "firewallpolicy", &policy]);
+    }
+    let _ = netsh(&[
+        "advfirewall",
+        "firewall",
+        "delete",
+        "rule",
+        &format!("name={KILL_SWITCH_RULE}"),
+    ]);
+}
+
+const FIREWALL_PROFILES: [&str; 3] = ["domainprofile", "privateprofile", "publicprofile"];
+
+/// The firewall policy of every profile from before the kill switch was engaged, to be restored when it is released.
+static SAVED_FIREWALL_POLICIES: Mutex<Vec<(&str, String)>> = parking_lot::const_mutex(Vec::new());
+
+/// Reads the current firewall policy of a profile, such as "BlockInbound,AllowOutbound".
+fn firewall_policy(profile: &str) -> anyhow::Result<String> {
+    use std::os::windows::process::CommandExt;
+    use winapi::um::winbase::CREATE_NO_WINDOW;
+    let output = std::process::Command::new("netsh")
+        .args(["advfirewall", "show", profile, "firewallpolicy"])
+        .creation_flags(CREATE_NO_WINDOW)
+        .output()?;
+    anyhow::ensure!(
+        output.status.success(),
+        "netsh could not show the {profile} firewall policy"
+    );
+    // the label in front of the policy is localized, but the policy itself is not
+    String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .find(|word| word.contains("Inbound,") && word.ends_with("Outbound"))
+        .map(|policy| policy.to_string())
+        .with_context(|| format!("no firewall policy found for {profile}"))
+}
+
+fn netsh(args: &[&str]) -> anyhow::Result<()> {
+    use std::os::windows::process::CommandExt;
+    use winapi::um::winbase::CREATE_NO_WINDOW;
+    let status = std::process::Command::new("netsh")
+        .args(args)
+        .creation_flags(CREATE_NO_WINDOW)
+        .status()?;
+    anyhow::ensure!(status.success(), "netsh {} failed", args.join(" "));
+    Ok(())
+}
+
 static WHITELIST: Lazy<DashSet<IpAddr>> = Lazy::new(DashSet::new);
 
 pub fn vpn_whitelist(addr: IpAddr) {
     WHITELIST.insert(addr);
 }
+
+/// Whether traffic to this address is let through around the tunnel.
+pub fn vpn_whitelisted(addr: IpAddr) -> bool {
+    WHITELIST.contains(&addr)
+}
+
+/// Whether traffic is being captured into the tunnel yet, which only starts once the first connection is up.
+pub fn vpn_routing_active() -> bool {
+    ROUTING_UP.load(Ordering::SeqCst)
+}
+
+static ROUTING_UP: AtomicBool = AtomicBool::new(false);
+
+/// The flows seen sending around the tunnel since [leaked_flows] was last called.
+#[cfg(feature = "windivert")]
+static LEAKED_FLOWS: Lazy<Mutex<HashSet<(&'static str, IpAddr, SocketAddr)>>> =
+    Lazy::new(Default::default);
+
+/// Starts watching, packet by packet, for traffic that goes around the tunnel.
+///
+/// The watching handle sits below the one that captures packets into the tunnel, so it only sees the packets that were left alone. Whitelisted packets, which the capturing handle reinjects, are marked as impostors and skipped.
+#[cfg(feature = "windivert")]
+pub fn leak_watch_start() -> anyhow::Result<()> {
+    let handle = windivert::SniffHandle::open(
+        "outbound and not loopback and not impostor and (tcp or udp)",
+        -150,
+    )?;
+    std::thread::spawn(move || loop {
+        match handle.receive() {
+            Ok(pkt) => {
+                if let Some(flow) = packet_flow(&pkt) {
+                    LEAKED_FLOWS.lock().insert(flow);
+                }
+            }
+            Err(err) => {
+                tracing::warn!(err = debug(err), "windivert leak watch failed");
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        }
+    });
+    Ok(())
+}
+
+/// The flows that sent packets around the tunnel since the last call, as their protocol, local address, and remote address.
+#[cfg(feature = "windivert")]
+pub fn leaked_flows() -> Vec<(&'static str, IpAddr, SocketAddr)> {
+    LEAKED_FLOWS.lock().drain().collect()
+}
+
+/// Reads the protocol, source address, and destination of a TCP or UDP packet.
+#[cfg(feature = "windivert")]
+fn packet_flow(pkt: &[u8]) -> Option<(&'static str, IpAddr, SocketAddr)> {
+    use pnet_packet::{
+        ip::IpNextHeaderProtocols, ipv4::Ipv4Packet, ipv6::Ipv6Packet, tcp::TcpPacket,
+        udp::UdpPacket, Packet,
+    };
+
+    let (source, destination, next_header, payload) = match pkt.first().map(|b| b >> 4) {
+        Some(4) => {
+            let pkt = Ipv4Packet::new(pkt)?;
+            (
+                IpAddr::V4(pkt.get_source()),
+                IpAddr::V4(pkt.get_destination()),
+                pkt.get_next_level_protocol(),
+                pkt.payload().to_vec(),
+            )
+        }
+        Some(6) => {
+            let pkt = Ipv6Packet::new(pkt)?;
+            (
+                IpAddr::V6(pkt.get_source()),
+                IpAddr::V6(pkt.get_destination()),
+                pkt.get_next_header(),
+                pkt.payload().to_vec(),
+            )
+        }
+        _ => return None,
+    };
+    let (protocol, port) = match next_header {
+        IpNextHeaderProtocols::Tcp => ("tcp", TcpPacket::new(&payload)?.get_destination()),
+        IpNextHeaderProtocols::Udp => ("udp", UdpPacket::new(&payload)?.get_destination()),
+        _ => return None,
+    };
+    Some((protocol, source, SocketAddr::new(destination, port)))
+}
@@ -70,3 +70,24 @@ impl PacketHandle {
         Ok(())
     }
 }
+
+/// A handle that only watches packets go by, without taking them out of the network stack.
+pub struct SniffHandle {
+    handle: Handle,
+}
+
+impl SniffHandle {
+    pub fn open(filter: &str, priority: i16) -> Result<Self, InternalError> {
+        let flag: u32 = bindings::WINDIVERT_FLAG_SNIFF | bindings::WINDIVERT_FLAG_RECV_ONLY;
+        Ok(Self {
+            handle: Handle::open(filter, Layer::Network, priority, flag as _)?,
+        })
+    }
+
+    pub fn receive(&self) -> Result<Vec<u8>, InternalError> {
+        let mut packet: Vec<u8> = vec![0; 4096];
+        let packet_len = self.handle.receive(Some(&mut packet), None)?;
+        packet.truncate(packet_len);
+        Ok(packet)
+    }
+}
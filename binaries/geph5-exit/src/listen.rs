@@ -147,6 +147,8 @@ async fn handle_client(mut client: impl Pipe) -> anyhow::Result<()> {
     };
 
     let mut is_free = false;
+    // without a broker, every session counts as its own user
+    let mut user = blake3::Hash::from(rand::random::<[u8; 32]>());
     let ratelimit = if CONFIG_FILE.wait().broker.is_some() {
         let (level, token, sig): (AccountLevel, ClientToken, UnblindedSignature) =
             stdcode::deserialize(&client_hello.credentials)
@@ -158,6 +160,7 @@ async fn handle_client(mut client: impl Pipe) -> anyhow::Result<()> {
             tracing::warn!(err = debug(e), "**** BAD BAD bad token received ***")
         })?;
         is_free = level == AccountLevel::Free;
+        user = blake3::hash(&(level, token).stdcode());
         get_ratelimiter(level, token).await
     } else {
         RateLimiter::unlimited()
@@ -196,6 +199,7 @@ async fn handle_client(mut client: impl Pipe) -> anyhow::Result<()> {
                 ratelimit.clone(),
                 stream,
                 is_free,
+                user,
             )
             .race(new_task_until_death(Duration::from_secs(30)))
             .map_err(|e| tracing::debug!(err = debug(e), "stream died with")),
@@ -25,6 +25,7 @@ mod broker;
 mod listen;
 mod proxy;
 mod ratelimit;
+mod reverse;
 mod schedlag;
 
 #[cfg(target_env = "musl")]
@@ -68,6 +69,14 @@ struct ConfigFile {
     #[serde(default = "default_task_limit")]
     task_limit: usize,
 
+    /// Whether to forward UDP to port 443. QUIC is banned by default, since it's much harder on traffic management than TCP.
+    #[serde(default)]
+    allow_quic: bool,
+
+    /// The ports, inclusive, that paid users may ask us to listen on for reverse port forwarding. Reverse forwarding is off unless this is set.
+    #[serde(default)]
+    reverse_port_range: Option<(u16, u16)>,
+
     #[serde_as(as = "DisplayFromStr")]
     #[serde(default)]
     ipv6_subnet: Ipv6Net,
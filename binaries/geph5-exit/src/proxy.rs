@@ -14,6 +14,8 @@ use crate::{
     dns::{dns_resolve, raw_dns_respond, FilterOptions},
     ipv6::EyeballDialer,
     ratelimit::RateLimiter,
+    reverse::{reverse_accept, reverse_listen},
+    CONFIG_FILE,
 };
 
 use smol_timeout2::TimeoutExt;
@@ -25,6 +27,7 @@ pub async fn proxy_stream(
     ratelimit: RateLimiter,
     stream: picomux::Stream,
     is_free: bool,
+    user: blake3::Hash,
 ) -> anyhow::Result<()> {
     let dest_host = String::from_utf8_lossy(stream.metadata());
     let (protocol, dest_host): (&str, &str) = if dest_host.contains('$') {
@@ -32,6 +35,11 @@ pub async fn proxy_stream(
     } else {
         ("tcp", &dest_host)
     };
+    match protocol {
+        "rlisten" => return reverse_listen(dest_host.parse()?, stream, is_free, user).await,
+        "raccept" => return reverse_accept(dest_host.parse()?, stream, ratelimit).await,
+        _ => {}
+    }
     let filter: FilterOptions =
         serde_json::from_value(sess_metadata["filter"].clone()).unwrap_or_default();
     let dest_addrs = dns_resolve(dest_host, filter)
@@ -68,14 +76,20 @@ pub async fn proxy_stream(
             let addr = *dest_addrs
                 .iter()
                 .find(|s| s.is_ipv4())
-                .context("UDP only supports ipv4 for now")?;
+                .or_else(|| dest_addrs.first())
+                .context("no addresses for UDP destination")?;
             if addr.port() == 53 {
                 return proxy_dns(stream, filter).await;
             }
-            if addr.port() == 443 {
+            if addr.port() == 443 && !CONFIG_FILE.wait().allow_quic {
                 anyhow::bail!("special-case banning QUIC to improve traffic management")
             }
-            let udp_socket: UdpSocket = UdpSocket::bind("0.0.0.0:0")
+            let bind_addr = if addr.is_ipv4() {
+                "0.0.0.0:0"
+            } else {
+                "[::]:0"
+            };
+            let udp_socket: UdpSocket = UdpSocket::bind(bind_addr)
                 .await
                 .context("UDP bind failed")?;
             udp_socket.connect(addr).await?;
@@ -100,7 +114,7 @@ pub async fn proxy_stream(
                 }
             };
             let dn_loop = async {
-                let mut buf = [0u8; 8192];
+                let mut buf = vec![0u8; 65536];
                 loop {
                     // Receive data into the buffer starting from the third byte
                     let len = udp_socket
@@ -0,0 +1,153 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Context;
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+use moka::future::Cache;
+use once_cell::sync::Lazy;
+use smol::{
+    future::FutureExt as _,
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{ratelimit::RateLimiter, CONFIG_FILE};
+
+/// How many ports one user can have reverse-forwarded at once.
+const MAX_LISTENERS_PER_USER: usize = 8;
+
+/// How many inbound connections one user can have waiting to be picked up at once, across all their ports.
+const MAX_PENDING_PER_USER: usize = 64;
+
+/// Inbound connections on reverse-forwarded ports, waiting for the client to come pick them up with a `raccept` stream.
+static PENDING: Lazy<Cache<u64, (TcpStream, Arc<Slot>)>> = Lazy::new(|| {
+    Cache::builder()
+        .time_to_live(Duration::from_secs(30))
+        .build()
+});
+
+static LISTENER_COUNTS: Lazy<Mutex<HashMap<blake3::Hash, usize>>> = Lazy::new(Default::default);
+
+static PENDING_COUNTS: Lazy<Mutex<HashMap<blake3::Hash, usize>>> = Lazy::new(Default::default);
+
+/// One of a user's limited listeners or pending connections, given back on drop.
+struct Slot {
+    counts: &'static Mutex<HashMap<blake3::Hash, usize>>,
+    user: blake3::Hash,
+}
+
+impl Slot {
+    fn acquire(
+        counts: &'static Mutex<HashMap<blake3::Hash, usize>>,
+        user: blake3::Hash,
+        max: usize,
+    ) -> Option<Self> {
+        let mut counts_inner = counts.lock().unwrap();
+        let count = counts_inner.entry(user).or_default();
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(Self { counts, user })
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.user) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.user);
+            }
+        }
+    }
+}
+
+/// Serves a `rlisten` stream. We listen on the requested port, or any allowed port if it's 0, and tell the client which port we got. Then, for every inbound connection, we send the client an ID that it redeems by opening a `raccept` stream. The port stays open until the `rlisten` stream closes.
+///
+/// `user` identifies whoever opened the stream, so that nobody can hog all the ports or pile up unclaimed connections.
+pub async fn reverse_listen(
+    port: u16,
+    stream: picomux::Stream,
+    is_free: bool,
+    user: blake3::Hash,
+) -> anyhow::Result<()> {
+    if is_free {
+        anyhow::bail!("reverse forwarding is not available to free users")
+    }
+    let (low, high) = CONFIG_FILE
+        .wait()
+        .reverse_port_range
+        .context("reverse forwarding is not enabled on this exit")?;
+    let _listener_slot = Slot::acquire(&LISTENER_COUNTS, user, MAX_LISTENERS_PER_USER)
+        .context("too many reverse-forwarded ports")?;
+    let listener = if port == 0 {
+        let mut listener = None;
+        for _ in 0..10 {
+            let port = fastrand::u16(low..=high);
+            if let Ok(bound) = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port))).await {
+                listener = Some(bound);
+                break;
+            }
+        }
+        listener.context("no free port for reverse forwarding")?
+    } else if (low..=high).contains(&port) {
+        TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port))).await?
+    } else {
+        anyhow::bail!("port {port} is outside of the reverse forwarding range {low}-{high}")
+    };
+    let bound_port = listener.local_addr()?.port();
+    tracing::debug!(bound_port, "listening for reverse forwarding");
+
+    let (mut read_stream, mut write_stream) = stream.split();
+    write_stream.write_all(&bound_port.to_le_bytes()).await?;
+    write_stream.flush().await?;
+    let accept_loop = async {
+        loop {
+            let (inbound, _) = listener.accept().await?;
+            // expired connections only give back their slots once the cache gets around to dropping them
+            PENDING.run_pending_tasks().await;
+            let Some(slot) = Slot::acquire(&PENDING_COUNTS, user, MAX_PENDING_PER_USER) else {
+                tracing::debug!(
+                    bound_port,
+                    "too many pending inbound connections, dropping one"
+                );
+                continue;
+            };
+            let id: u64 = rand::random();
+            PENDING.insert(id, (inbound, Arc::new(slot))).await;
+            write_stream.write_all(&id.to_le_bytes()).await?;
+            write_stream.flush().await?;
+        }
+    };
+    let close_loop = async {
+        let mut buf = [0u8; 1];
+        while read_stream.read(&mut buf).await? > 0 {}
+        anyhow::Ok(())
+    };
+    accept_loop.race(close_loop).await
+}
+
+/// Serves a `raccept` stream, connecting it to the pending inbound connection with the given ID.
+pub async fn reverse_accept(
+    id: u64,
+    stream: picomux::Stream,
+    ratelimit: RateLimiter,
+) -> anyhow::Result<()> {
+    let (inbound, _slot) = PENDING
+        .remove(&id)
+        .await
+        .context("no such pending inbound connection")?;
+    let (read_stream, mut write_stream) = stream.split();
+    let (read_inbound, mut write_inbound) = inbound.split();
+    smol::future::race(
+        ratelimit.io_copy(read_stream, &mut write_inbound),
+        ratelimit.io_copy(read_inbound, &mut write_stream),
+    )
+    .await?;
+    Ok(())
+}
@@ -9,6 +9,8 @@ use isocountry::CountryCode;
 use language_tags::LanguageTag;
 use serde::{Deserialize, Serialize};
 
+use crate::RouteDescriptor;
+
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
 /// This fully describes a particular exit.
 pub struct ExitDescriptor {
@@ -34,6 +36,16 @@ pub struct ExitList {
     pub city_names: HashMap<String, HashMap<LanguageTag, String>>,
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+/// A set of exits and the routes to them, meant to be signed and handed out some other way than through the broker, such as while the broker is unreachable.
+pub struct RouteBundle {
+    pub exits: Vec<(VerifyingKey, ExitDescriptor)>,
+    /// Routes to the exits, keyed by each exit's bridge-to-exit address
+    pub routes: Vec<(SocketAddr, RouteDescriptor)>,
+    /// When does this bundle expire? Unlike exit descriptors, bundles are meant to stay valid for days.
+    pub expiry: u64,
+}
+
 impl ExitList {
     /// A convenience method to find the overall expiry time of the exit list.
     pub fn expiry(&self) -> SystemTime {
@@ -34,6 +34,7 @@ pub trait BrokerProtocol {
 
     async fn get_exits(&self) -> Result<Signed<ExitList>, GenericError>;
     async fn get_free_exits(&self) -> Result<Signed<ExitList>, GenericError>;
+    async fn get_news(&self, lang: String) -> Result<Vec<NewsItem>, GenericError>;
     async fn get_routes(
         &self,
         token: ClientToken,
@@ -53,6 +54,13 @@ pub trait BrokerProtocol {
     async fn set_stat(&self, stat: String, value: f64);
 
     async fn upload_available(&self, data: AvailabilityData);
+
+    /// Stores logs and other debugging information that a logged-in user chose to send, returning an ID they can give to support. Each user can only upload a few a day.
+    async fn upload_debug_pack(
+        &self,
+        auth_token: String,
+        pack: String,
+    ) -> Result<String, GenericError>;
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
@@ -69,6 +77,14 @@ pub struct UserInfo {
     pub plus_expires_unix: Option<u64>,
 }
 
+/// An announcement shown to users, such as an outage notice or advice about a blocking event.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct NewsItem {
+    pub title: String,
+    pub date_unix: u64,
+    pub contents: String,
+}
+
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq)]
 pub enum AccountLevel {
     Free,
@@ -100,6 +116,7 @@ impl Default for Credential {
 }
 
 pub const DOMAIN_EXIT_DESCRIPTOR: &str = "exit-descriptor";
+pub const DOMAIN_ROUTE_BUNDLE: &str = "route-bundle";
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 #[serde(transparent)]
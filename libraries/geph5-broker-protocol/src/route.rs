@@ -1,4 +1,4 @@
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, SocketAddr};
 
 use serde::{Deserialize, Serialize};
 
@@ -21,6 +21,15 @@ pub enum RouteDescriptor {
         milliseconds: u32,
         lower: Box<RouteDescriptor>,
     },
+    /// Tunnels through DNS queries sent to a public resolver, for subdomains of a domain whose nameserver is the bridge. This is very slow, so it should only ever be the last option in a Fallback.
+    Dns {
+        resolver: SocketAddr,
+        domain: String,
+    },
+    /// A KCP stream over UDP, which copes with heavy packet loss much better than TCP. It carries no encryption or obfuscation of its own, so it should always be the lower layer of something like Sosistab3.
+    Kcp(SocketAddr),
+    /// A KCP stream carried in ICMP echo requests and replies, for networks that let nothing but ping through. It needs raw sockets on the client, so clients only use it when explicitly allowed to, and it should only ever be the last option in a Fallback.
+    Icmp(Ipv4Addr),
 
     #[serde(untagged)]
     Other(serde_json::Value),
@@ -0,0 +1,151 @@
+use base64::Engine as _;
+
+use crate::{Alphabet, CodecConfig};
+
+/// Encodes plaintext chunks into text. Every chunk is encoded on its own, padding included, so nothing is held back between writes.
+#[derive(Clone)]
+pub struct Encoder {
+    config: CodecConfig,
+    column: usize,
+}
+
+impl Encoder {
+    pub fn new(config: CodecConfig) -> Self {
+        Self { config, column: 0 }
+    }
+
+    /// Encodes the chunk, appending the text to `out`.
+    pub fn encode(&mut self, plain: &[u8], out: &mut Vec<u8>) {
+        let encoded = match self.config.alphabet {
+            Alphabet::Base64 => base64::engine::general_purpose::STANDARD.encode(plain),
+            Alphabet::Base32 => base32::encode(base32::Alphabet::Rfc4648 { padding: true }, plain),
+        };
+        let Some(width) = self.config.line_wrap.filter(|w| *w > 0) else {
+            out.extend_from_slice(encoded.as_bytes());
+            return;
+        };
+        // the line position carries over between chunks, so lines stay the same width no matter how writes are split
+        for &c in encoded.as_bytes() {
+            out.push(c);
+            self.column += 1;
+            if self.column == width {
+                out.extend_from_slice(b"\r\n");
+                self.column = 0;
+            }
+        }
+    }
+}
+
+/// Decodes text back into plaintext, accepting any whitespace and any mix of padded chunks.
+#[derive(Clone)]
+pub struct Decoder {
+    alphabet: Alphabet,
+    pending: Vec<u8>,
+}
+
+impl Decoder {
+    pub fn new(alphabet: Alphabet) -> Self {
+        Self {
+            alphabet,
+            pending: vec![],
+        }
+    }
+
+    /// Whether there is a partial quantum waiting for more input.
+    pub fn has_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    /// Decodes as much of the text as possible, appending the plaintext to `out`. Partial quanta are kept until the rest arrives.
+    pub fn decode(&mut self, encoded: &[u8], out: &mut Vec<u8>) -> std::io::Result<()> {
+        self.pending
+            .extend(encoded.iter().copied().filter(|c| !c.is_ascii_whitespace()));
+        let quantum = self.alphabet.quantum();
+        let full = self.pending.len() / quantum * quantum;
+        // a quantum with padding ends a chunk, so we decode chunk by chunk
+        let mut start = 0;
+        for end in (quantum..=full).step_by(quantum) {
+            if self.pending[end - quantum..end].contains(&b'=') {
+                self.decode_chunk(start, end, out)?;
+                start = end;
+            }
+        }
+        if start < full {
+            self.decode_chunk(start, full, out)?;
+        }
+        self.pending.drain(..full);
+        Ok(())
+    }
+
+    fn decode_chunk(&self, start: usize, end: usize, out: &mut Vec<u8>) -> std::io::Result<()> {
+        let chunk = &self.pending[start..end];
+        let decoded = match self.alphabet {
+            Alphabet::Base64 => base64::engine::general_purpose::STANDARD.decode(chunk).ok(),
+            Alphabet::Base32 => std::str::from_utf8(chunk)
+                .ok()
+                .and_then(|s| base32::decode(base32::Alphabet::Rfc4648 { padding: true }, s)),
+        };
+        let decoded = decoded.ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid encoded data")
+        })?;
+        out.extend_from_slice(&decoded);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(config: CodecConfig, chunks: &[&[u8]], split: usize) {
+        let mut encoder = Encoder::new(config);
+        let mut text = vec![];
+        for chunk in chunks {
+            encoder.encode(chunk, &mut text);
+        }
+        let mut decoder = Decoder::new(config.alphabet);
+        let mut plain = vec![];
+        for piece in text.chunks(split) {
+            decoder.decode(piece, &mut plain).unwrap();
+        }
+        assert!(!decoder.has_pending());
+        assert_eq!(plain, chunks.concat());
+    }
+
+    #[test]
+    fn test_round_trip_uneven_chunks() {
+        let chunks: &[&[u8]] = &[b"h", b"ello", b" wor", b"ld, this is a longer chunk", b"!!"];
+        for alphabet in [Alphabet::Base64, Alphabet::Base32] {
+            for line_wrap in [None, Some(7), Some(76)] {
+                for split in [1, 3, 5, 1000] {
+                    round_trip(
+                        CodecConfig {
+                            alphabet,
+                            line_wrap,
+                        },
+                        chunks,
+                        split,
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_line_wrap() {
+        let mut encoder = Encoder::new(CodecConfig {
+            alphabet: Alphabet::Base64,
+            line_wrap: Some(8),
+        });
+        let mut text = vec![];
+        encoder.encode(&[0u8; 9], &mut text);
+        encoder.encode(&[0u8; 3], &mut text);
+        assert_eq!(text, b"AAAAAAAA\r\nAAAAAAAA\r\n");
+    }
+
+    #[test]
+    fn test_garbage_rejected() {
+        let mut decoder = Decoder::new(Alphabet::Base64);
+        assert!(decoder.decode(b"!!!!", &mut vec![]).is_err());
+    }
+}
@@ -0,0 +1,123 @@
+use codec::{Decoder, Encoder};
+use sillad::codec::{ByteCodec, CodecDialer, CodecListener, CodecPipe};
+
+mod codec;
+
+/// Which text encoding to put on the wire.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Alphabet {
+    /// Standard base64 (RFC 4648), with padding.
+    #[default]
+    Base64,
+    /// Uppercase base32 (RFC 4648), with padding. Bulkier than base64, but survives case-insensitive middleboxes.
+    Base32,
+}
+
+impl Alphabet {
+    /// The number of characters in one quantum, i.e. the smallest unit that decodes on its own.
+    fn quantum(self) -> usize {
+        match self {
+            Alphabet::Base64 => 4,
+            Alphabet::Base32 => 8,
+        }
+    }
+}
+
+/// How a [Base64Pipe] encodes the stream.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CodecConfig {
+    pub alphabet: Alphabet,
+    /// If set, a CRLF is inserted after every this many characters, so that the traffic looks like MIME-style text.
+    pub line_wrap: Option<usize>,
+}
+
+/// A [ByteCodec] that carries the stream as base64 or base32 text.
+#[derive(Clone)]
+pub struct Base64Codec {
+    encoder: Encoder,
+    decoder: Decoder,
+}
+
+impl Base64Codec {
+    pub fn new(config: CodecConfig) -> Self {
+        Self {
+            encoder: Encoder::new(config),
+            decoder: Decoder::new(config.alphabet),
+        }
+    }
+}
+
+impl ByteCodec for Base64Codec {
+    fn encode(&mut self, plain: &[u8], out: &mut Vec<u8>) {
+        self.encoder.encode(plain, out)
+    }
+
+    fn decode(&mut self, encoded: &[u8], out: &mut Vec<u8>) -> std::io::Result<()> {
+        self.decoder.decode(encoded, out)
+    }
+
+    fn has_pending(&self) -> bool {
+        self.decoder.has_pending()
+    }
+
+    fn protocol(&self) -> &str {
+        "base64"
+    }
+}
+
+/// A dialer that encodes the stream of its inner dialer as base64 or base32 text.
+pub type Base64Dialer<D> = CodecDialer<D, Base64Codec>;
+
+/// A listener that decodes the base64 or base32 text sent by a [Base64Dialer].
+pub type Base64Listener<L> = CodecListener<L, Base64Codec>;
+
+/// A pipe that carries its bytes as base64 or base32 text over a lower pipe.
+pub type Base64Pipe<P> = CodecPipe<P, Base64Codec>;
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::{AsyncReadExt, AsyncWriteExt};
+    use sillad::{
+        dialer::Dialer,
+        listener::Listener,
+        tcp::{TcpDialer, TcpListener},
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_round_trip_over_tcp() {
+        futures_lite::future::block_on(async {
+            let config = CodecConfig {
+                alphabet: Alphabet::Base32,
+                line_wrap: Some(76),
+            };
+            let tcp = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let addr = tcp.local_addr().await;
+            let mut listener = Base64Listener::new(tcp, Base64Codec::new(config));
+            let dialer = Base64Dialer::new(TcpDialer { dest_addr: addr }, Base64Codec::new(config));
+            let message: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+            let server = async {
+                let mut pipe = listener.accept().await.unwrap();
+                let mut received = vec![0u8; message.len()];
+                pipe.read_exact(&mut received).await.unwrap();
+                pipe.write_all(&received).await.unwrap();
+                pipe.flush().await.unwrap();
+                received
+            };
+            let client = async {
+                let mut pipe = dialer.dial().await.unwrap();
+                pipe.write_all(&message).await.unwrap();
+                pipe.flush().await.unwrap();
+                let mut echoed = vec![0u8; message.len()];
+                pipe.read_exact(&mut echoed).await.unwrap();
+                echoed
+            };
+            let (received, echoed) = futures_lite::future::zip(server, client).await;
+            assert_eq!(received, message);
+            assert_eq!(echoed, message);
+        });
+    }
+}
@@ -0,0 +1,125 @@
+use std::{net::SocketAddr, time::Duration};
+
+use async_io::Timer;
+use async_net::UdpSocket;
+use bipe::{BipeReader, BipeWriter};
+use futures_lite::{AsyncReadExt, AsyncWriteExt, FutureExt};
+
+use crate::wire::{self, UpPacket};
+
+/// How long we wait for an answer before asking again.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How many unanswered queries in a row kill the session.
+const MAX_TIMEOUTS: usize = 15;
+
+/// The longest we wait between polls when nothing is happening.
+const MAX_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Runs the client side of a session: one query at a time, each carrying at most one upstream chunk and fetching at most one downstream chunk.
+pub async fn client_session(
+    socket: UdpSocket,
+    resolver: SocketAddr,
+    domain: String,
+    session: u32,
+    mut up_reader: BipeReader,
+    mut down_writer: BipeWriter,
+) -> std::io::Result<()> {
+    let max_up = wire::max_up(&domain);
+    let mut up_seq = 0u32;
+    let mut down_next = 0u32;
+    // the chunk waiting to be acknowledged, and whether it ends the stream
+    let mut inflight: Option<(Vec<u8>, bool)> = None;
+    let mut up_done = false;
+    let mut down_done = false;
+    let mut poll_interval = Duration::ZERO;
+    let mut timeouts = 0;
+    let mut buf = vec![0u8; 65536];
+
+    while !(up_done && down_done) {
+        if inflight.is_none() && !up_done {
+            // wait a little for something to send, so that idle sessions don't flood the resolver
+            let read = async { Some(up_reader.read(&mut buf[..max_up]).await) }
+                .or(async {
+                    Timer::after(poll_interval).await;
+                    None
+                })
+                .await;
+            match read {
+                Some(Ok(0)) | Some(Err(_)) => inflight = Some((vec![], true)),
+                Some(Ok(n)) => inflight = Some((buf[..n].to_vec(), false)),
+                None => {}
+            }
+        } else if down_done {
+            Timer::after(poll_interval).await;
+        }
+
+        let packet = UpPacket {
+            session,
+            up_seq,
+            down_next,
+            fin: inflight.as_ref().is_some_and(|(_, fin)| *fin),
+            data: inflight
+                .as_ref()
+                .map(|(d, _)| d.clone())
+                .unwrap_or_default(),
+        };
+        let id = rand::random();
+        socket
+            .send_to(&wire::build_query(id, &packet, &domain)?, resolver)
+            .await?;
+        let response = async {
+            loop {
+                let (n, from) = socket.recv_from(&mut buf).await?;
+                if from != resolver {
+                    continue;
+                }
+                match wire::parse_response(&buf[..n]) {
+                    Ok((resp_id, down)) if resp_id == id => return std::io::Result::Ok(Some(down)),
+                    Ok(_) => continue,
+                    Err(err) => tracing::debug!(err = debug(err), "bad DNS response"),
+                }
+            }
+        }
+        .or(async {
+            Timer::after(QUERY_TIMEOUT).await;
+            Ok(None)
+        })
+        .await?;
+        let Some(down) = response else {
+            timeouts += 1;
+            if timeouts >= MAX_TIMEOUTS {
+                return Err(std::io::ErrorKind::TimedOut.into());
+            }
+            continue;
+        };
+        timeouts = 0;
+
+        let mut progress = false;
+        if down.up_ack > up_seq {
+            if let Some((_, fin)) = inflight.take() {
+                up_seq += 1;
+                up_done |= fin;
+                progress = true;
+            }
+        }
+        if down.down_seq == down_next && (!down.data.is_empty() || down.fin) {
+            down_next += 1;
+            progress = true;
+            if !down_done && down_writer.write_all(&down.data).await.is_err() {
+                // nobody is reading anymore, but we keep acknowledging until the server is done
+                down_done = true;
+            }
+            down_done |= down.fin;
+        }
+        // back off while idle, and go back to polling fast as soon as data moves
+        poll_interval = if progress {
+            Duration::ZERO
+        } else {
+            (poll_interval * 2)
+                .max(Duration::from_millis(50))
+                .min(MAX_POLL_INTERVAL)
+        };
+    }
+    Ok(())
+}
@@ -0,0 +1,192 @@
+//! A slow but hard-to-block transport that tunnels the byte stream through DNS. The client asks a recursive resolver TXT questions about subdomains of a tunnel domain, and the answers come from a nameserver that is authoritative for that domain.
+
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use async_net::UdpSocket;
+use async_trait::async_trait;
+use bipe::{BipeReader, BipeWriter};
+use futures_lite::{AsyncRead, AsyncWrite};
+use sillad::{dialer::Dialer, listener::Listener, Pipe};
+
+mod client;
+mod server;
+mod wire;
+
+/// A dialer that tunnels through DNS queries sent to a resolver.
+pub struct DnsDialer {
+    resolver: SocketAddr,
+    domain: String,
+}
+
+impl DnsDialer {
+    /// Creates a dialer that sends its queries to the given resolver, for subdomains of the given tunnel domain.
+    pub fn new(resolver: SocketAddr, domain: impl Into<String>) -> Self {
+        Self {
+            resolver,
+            domain: domain.into().trim_end_matches('.').to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl Dialer for DnsDialer {
+    type P = DnsPipe;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let bind_addr: SocketAddr = if self.resolver.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        let (up_writer, up_reader) = bipe::bipe(65536);
+        let (down_writer, down_reader) = bipe::bipe(65536);
+        // the session outlives the pipe, so that whatever was written before closing still gets delivered
+        smolscale::spawn(client::client_session(
+            socket,
+            self.resolver,
+            self.domain.clone(),
+            rand::random(),
+            up_reader,
+            down_writer,
+        ))
+        .detach();
+        Ok(DnsPipe {
+            write: up_writer,
+            read: down_reader,
+            remote_addr: self.resolver.to_string(),
+        })
+    }
+}
+
+/// A listener that acts as the authoritative nameserver for a tunnel domain, accepting the sessions of [DnsDialer]s.
+pub struct DnsListener {
+    local_addr: SocketAddr,
+    recv: tachyonix::Receiver<std::io::Result<DnsPipe>>,
+    _task: async_task::Task<()>,
+}
+
+impl DnsListener {
+    /// Listens for queries on the given UDP address, usually port 53.
+    pub async fn bind(addr: SocketAddr, domain: impl Into<String>) -> std::io::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let local_addr = socket.local_addr()?;
+        let domain = domain.into().trim_end_matches('.').to_string();
+        let (send, recv) = tachyonix::channel(1);
+        let _task = smolscale::spawn(async move {
+            if let Err(err) = server::server_loop(socket, domain, send.clone()).await {
+                let _ = send.send(Err(err)).await;
+            }
+        });
+        Ok(Self {
+            local_addr,
+            recv,
+            _task,
+        })
+    }
+
+    /// Get the local listening address.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+#[async_trait]
+impl Listener for DnsListener {
+    type P = DnsPipe;
+
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        self.recv.recv().await.map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "listener has shut down for some reason",
+            )
+        })?
+    }
+}
+
+/// One end of a DNS tunnel session.
+pub struct DnsPipe {
+    write: BipeWriter,
+    read: BipeReader,
+    remote_addr: String,
+}
+
+impl AsyncRead for DnsPipe {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.read).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for DnsPipe {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.write).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.write).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.write).poll_close(cx)
+    }
+}
+
+impl Pipe for DnsPipe {
+    fn protocol(&self) -> &str {
+        "dns"
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        Some(&self.remote_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[test]
+    fn test_dns_tunnel_echo() {
+        smolscale::block_on(async {
+            let mut listener = DnsListener::bind("127.0.0.1:0".parse().unwrap(), "t.example.com")
+                .await
+                .unwrap();
+            let addr = listener.local_addr();
+            let dialer = DnsDialer::new(addr, "T.example.com.");
+            let message: Vec<u8> = (0..3000u32).map(|i| (i % 251) as u8).collect();
+            let client = async {
+                let mut pipe = dialer.dial().await.unwrap();
+                pipe.write_all(&message).await.unwrap();
+                pipe.close().await.unwrap();
+                let mut echoed = vec![];
+                pipe.read_to_end(&mut echoed).await.unwrap();
+                echoed
+            };
+            let server = async {
+                let mut pipe = listener.accept().await.unwrap();
+                let mut received = vec![];
+                pipe.read_to_end(&mut received).await.unwrap();
+                pipe.write_all(&received).await.unwrap();
+                pipe.close().await.unwrap();
+            };
+            let ((), echoed) = futures_lite::future::zip(server, client).await;
+            assert_eq!(echoed, message);
+        });
+    }
+}
@@ -0,0 +1,148 @@
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use async_io::Timer;
+use async_net::UdpSocket;
+use bipe::{BipeReader, BipeWriter};
+use futures_lite::{future::poll_once, AsyncReadExt, AsyncWriteExt, FutureExt};
+
+use crate::{
+    wire::{self, DownPacket, ReplyTo, UpPacket},
+    DnsPipe,
+};
+
+/// How long a session may go without any queries before we give up on it.
+const SESSION_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// How long we remember finished sessions, so that late retransmissions don't resurrect them.
+const DEAD_SESSION_MEMORY: Duration = Duration::from_secs(600);
+
+/// Answers queries for the tunnel domain, handing every new session to the listener.
+pub async fn server_loop(
+    socket: Arc<UdpSocket>,
+    domain: String,
+    send_pipe: tachyonix::Sender<std::io::Result<DnsPipe>>,
+) -> std::io::Result<()> {
+    let mut sessions: HashMap<u32, async_channel::Sender<(UpPacket, ReplyTo)>> = HashMap::new();
+    let mut dead: HashMap<u32, Instant> = HashMap::new();
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (n, addr) = socket.recv_from(&mut buf).await?;
+        let Some((id, qname, packet)) = wire::parse_query(&buf[..n], &domain) else {
+            continue;
+        };
+        let reply_to = ReplyTo { id, qname, addr };
+        let session_id = packet.session;
+        if let Some(send) = sessions.get(&session_id) {
+            // a full queue just means the client is retransmitting faster than we answer
+            match send.try_send((packet, reply_to)) {
+                Err(async_channel::TrySendError::Closed(_)) => {
+                    sessions.remove(&session_id);
+                    dead.insert(session_id, Instant::now());
+                }
+                _ => continue,
+            }
+            continue;
+        }
+        // only the very first packet of a session may start it
+        if dead.contains_key(&session_id) || packet.up_seq != 0 || packet.down_next != 0 {
+            continue;
+        }
+        dead.retain(|_, died| died.elapsed() < DEAD_SESSION_MEMORY);
+
+        let (send_query, recv_query) = async_channel::bounded(16);
+        let (up_writer, up_reader) = bipe::bipe(65536);
+        let (down_writer, down_reader) = bipe::bipe(65536);
+        let pipe = DnsPipe {
+            write: down_writer,
+            read: up_reader,
+            remote_addr: addr.to_string(),
+        };
+        if send_pipe.send(Ok(pipe)).await.is_err() {
+            return Ok(());
+        }
+        let _ = send_query.try_send((packet, reply_to));
+        sessions.insert(session_id, send_query);
+        smolscale::spawn(server_session(
+            socket.clone(),
+            recv_query,
+            up_writer,
+            down_reader,
+        ))
+        .detach();
+    }
+}
+
+/// Runs the server side of a session, answering every query with the downstream chunk currently in flight.
+async fn server_session(
+    socket: Arc<UdpSocket>,
+    recv_query: async_channel::Receiver<(UpPacket, ReplyTo)>,
+    mut up_writer: BipeWriter,
+    mut down_reader: BipeReader,
+) {
+    let mut up_next = 0u32;
+    let mut down_seq = 0u32;
+    let mut inflight: Option<(Vec<u8>, bool)> = None;
+    let mut up_done = false;
+    let mut down_done = false;
+    let mut buf = vec![0u8; wire::MAX_DOWN];
+
+    while !(up_done && down_done) {
+        let query = recv_query
+            .recv()
+            .or(async {
+                Timer::after(SESSION_IDLE_TIMEOUT).await;
+                Err(async_channel::RecvError)
+            })
+            .await;
+        let Ok((packet, reply_to)) = query else {
+            tracing::debug!("DNS session timed out");
+            return;
+        };
+
+        if packet.up_seq == up_next && (!packet.data.is_empty() || packet.fin) {
+            up_next += 1;
+            if !up_done && up_writer.write_all(&packet.data).await.is_err() {
+                up_done = true;
+            }
+            if packet.fin {
+                up_done = true;
+                let _ = up_writer.close().await;
+            }
+        }
+        if packet.down_next > down_seq {
+            if let Some((_, fin)) = inflight.take() {
+                down_seq += 1;
+                down_done |= fin;
+            }
+        }
+        if inflight.is_none() && !down_done {
+            // whatever is ready right now goes out, since the answer can't wait for more
+            if let Some(res) = poll_once(down_reader.read(&mut buf)).await {
+                inflight = match res {
+                    Ok(0) | Err(_) => Some((vec![], true)),
+                    Ok(n) => Some((buf[..n].to_vec(), false)),
+                };
+            }
+        }
+
+        let down = DownPacket {
+            down_seq,
+            up_ack: up_next,
+            fin: inflight.as_ref().is_some_and(|(_, fin)| *fin),
+            data: inflight
+                .as_ref()
+                .map(|(d, _)| d.clone())
+                .unwrap_or_default(),
+        };
+        match wire::build_response(reply_to.id, &reply_to.qname, &down) {
+            Ok(response) => {
+                let _ = socket.send_to(&response, reply_to.addr).await;
+            }
+            Err(err) => tracing::debug!(err = debug(err), "could not build DNS response"),
+        }
+    }
+}
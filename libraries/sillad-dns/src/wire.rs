@@ -0,0 +1,234 @@
+use std::net::SocketAddr;
+
+use base64::Engine as _;
+use simple_dns::{
+    rdata::{RData, OPT, TXT},
+    CharacterString, Name, Packet, PacketFlag, Question, ResourceRecord, CLASS, QCLASS, QTYPE,
+    TYPE,
+};
+
+const UP_HEADER_LEN: usize = 15;
+const DOWN_HEADER_LEN: usize = 9;
+
+const FLAG_FIN: u8 = 1;
+
+/// The most data that fits in one response, conservatively sized so that the whole message stays within the usual 1232-byte EDNS limit.
+pub const MAX_DOWN: usize = 600;
+
+/// The EDNS payload size we advertise.
+const EDNS_UDP_SIZE: u16 = 1232;
+
+/// One client-to-server packet, carried in the name being queried.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpPacket {
+    pub session: u32,
+    /// The sequence number of the chunk in `data`. Only meaningful if there is data or `fin`.
+    pub up_seq: u32,
+    /// The next downstream sequence number the client wants, which acknowledges everything before it.
+    pub down_next: u32,
+    pub fin: bool,
+    pub data: Vec<u8>,
+}
+
+/// One server-to-client packet, carried in a TXT answer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DownPacket {
+    /// The sequence number of the chunk in `data`. Only meaningful if there is data or `fin`.
+    pub down_seq: u32,
+    /// The next upstream sequence number the server wants, which acknowledges everything before it.
+    pub up_ack: u32,
+    pub fin: bool,
+    pub data: Vec<u8>,
+}
+
+/// How much upstream data fits in one query for the given tunnel domain.
+pub fn max_up(domain: &str) -> usize {
+    // names are at most 253 characters, and every 63 characters of data cost a dot
+    let chars = 252usize.saturating_sub(domain.len()) * 63 / 64;
+    (chars * 5 / 8).saturating_sub(UP_HEADER_LEN)
+}
+
+/// Builds a TXT query carrying the given packet as a subdomain of the tunnel domain.
+pub fn build_query(id: u16, packet: &UpPacket, domain: &str) -> std::io::Result<Vec<u8>> {
+    let mut raw = Vec::with_capacity(UP_HEADER_LEN + packet.data.len());
+    raw.extend_from_slice(&packet.session.to_be_bytes());
+    raw.extend_from_slice(&packet.up_seq.to_be_bytes());
+    raw.extend_from_slice(&packet.down_next.to_be_bytes());
+    raw.push(if packet.fin { FLAG_FIN } else { 0 });
+    // a fresh nonce in every name keeps resolvers from answering out of their caches
+    raw.extend_from_slice(&rand::random::<u16>().to_be_bytes());
+    raw.extend_from_slice(&packet.data);
+    let encoded = base32::encode(base32::Alphabet::Rfc4648Lower { padding: false }, &raw);
+    let mut qname = encoded
+        .as_bytes()
+        .chunks(63)
+        .map(|label| std::str::from_utf8(label).unwrap())
+        .collect::<Vec<_>>()
+        .join(".");
+    qname.push('.');
+    qname.push_str(domain);
+    if qname.len() > 253 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "upstream packet too big for a DNS name",
+        ));
+    }
+
+    let mut query = Packet::new_query(id);
+    query.set_flags(PacketFlag::RECURSION_DESIRED);
+    query.questions.push(Question::new(
+        Name::new(&qname).map_err(invalid_data)?,
+        QTYPE::TYPE(TYPE::TXT),
+        QCLASS::CLASS(CLASS::IN),
+        false,
+    ));
+    *query.opt_mut() = Some(OPT {
+        opt_codes: vec![],
+        udp_packet_size: EDNS_UDP_SIZE,
+        version: 0,
+    });
+    query.build_bytes_vec().map_err(invalid_data)
+}
+
+/// Parses a query for the tunnel domain, returning its id, the name asked about, and the packet it carries. Returns `None` for queries that aren't ours.
+pub fn parse_query(query: &[u8], domain: &str) -> Option<(u16, String, UpPacket)> {
+    let query = Packet::parse(query).ok()?;
+    let question = query.questions.first()?;
+    let qname = question.qname.to_string();
+    // resolvers may randomize the case of names
+    let lower = qname.to_ascii_lowercase();
+    let prefix = lower
+        .trim_end_matches('.')
+        .strip_suffix(&domain.to_ascii_lowercase())?
+        .strip_suffix('.')?;
+    let encoded: String = prefix.chars().filter(|c| *c != '.').collect();
+    let raw = base32::decode(base32::Alphabet::Rfc4648Lower { padding: false }, &encoded)?;
+    if raw.len() < UP_HEADER_LEN {
+        return None;
+    }
+    let packet = UpPacket {
+        session: u32::from_be_bytes(raw[0..4].try_into().unwrap()),
+        up_seq: u32::from_be_bytes(raw[4..8].try_into().unwrap()),
+        down_next: u32::from_be_bytes(raw[8..12].try_into().unwrap()),
+        fin: raw[12] & FLAG_FIN != 0,
+        data: raw[UP_HEADER_LEN..].to_vec(),
+    };
+    Some((query.id(), qname, packet))
+}
+
+/// Builds the TXT answer to a query, carrying the given packet.
+pub fn build_response(id: u16, qname: &str, packet: &DownPacket) -> std::io::Result<Vec<u8>> {
+    let mut raw = Vec::with_capacity(DOWN_HEADER_LEN + packet.data.len());
+    raw.extend_from_slice(&packet.down_seq.to_be_bytes());
+    raw.extend_from_slice(&packet.up_ack.to_be_bytes());
+    raw.push(if packet.fin { FLAG_FIN } else { 0 });
+    raw.extend_from_slice(&packet.data);
+    let encoded = base64::engine::general_purpose::STANDARD.encode(raw);
+    let mut txt = TXT::new();
+    for chunk in encoded.as_bytes().chunks(255) {
+        txt.add_char_string(CharacterString::new(chunk).map_err(invalid_data)?);
+    }
+
+    let name = Name::new(qname).map_err(invalid_data)?;
+    let mut response = Packet::new_reply(id);
+    response.set_flags(PacketFlag::AUTHORITATIVE_ANSWER | PacketFlag::RECURSION_DESIRED);
+    response.questions.push(Question::new(
+        name.clone(),
+        QTYPE::TYPE(TYPE::TXT),
+        QCLASS::CLASS(CLASS::IN),
+        false,
+    ));
+    // a zero TTL, since every answer is only good once
+    response
+        .answers
+        .push(ResourceRecord::new(name, CLASS::IN, 0, RData::TXT(txt)));
+    *response.opt_mut() = Some(OPT {
+        opt_codes: vec![],
+        udp_packet_size: EDNS_UDP_SIZE,
+        version: 0,
+    });
+    // the answer repeats the long question name, so it had better be compressed into a pointer
+    response.build_bytes_vec_compressed().map_err(invalid_data)
+}
+
+/// Parses a response to one of our queries, returning its id and the packet it carries.
+pub fn parse_response(response: &[u8]) -> std::io::Result<(u16, DownPacket)> {
+    let response = Packet::parse(response).map_err(invalid_data)?;
+    let txt = response
+        .answers
+        .iter()
+        .find_map(|answer| match &answer.rdata {
+            RData::TXT(txt) => Some(txt.clone()),
+            _ => None,
+        })
+        .ok_or_else(|| invalid_data("DNS response has no TXT answer"))?;
+    let encoded = String::try_from(txt).map_err(invalid_data)?;
+    let raw = base64::engine::general_purpose::STANDARD
+        .decode(encoded)
+        .map_err(invalid_data)?;
+    if raw.len() < DOWN_HEADER_LEN {
+        return Err(invalid_data("downstream packet too short"));
+    }
+    Ok((
+        response.id(),
+        DownPacket {
+            down_seq: u32::from_be_bytes(raw[0..4].try_into().unwrap()),
+            up_ack: u32::from_be_bytes(raw[4..8].try_into().unwrap()),
+            fin: raw[8] & FLAG_FIN != 0,
+            data: raw[DOWN_HEADER_LEN..].to_vec(),
+        },
+    ))
+}
+
+/// Where a query came from and how to answer it.
+#[derive(Clone, Debug)]
+pub struct ReplyTo {
+    pub id: u16,
+    pub qname: String,
+    pub addr: SocketAddr,
+}
+
+fn invalid_data(err: impl ToString) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packets_survive_dns_messages() {
+        let domain = "t.example.com";
+        let up = UpPacket {
+            session: 42,
+            up_seq: 7,
+            down_next: 9,
+            fin: true,
+            data: (0..max_up(domain)).map(|i| i as u8).collect(),
+        };
+        let query = build_query(1234, &up, domain).unwrap();
+        // a resolver that uses 0x20 encoding would mix up the case
+        let mut query_upper = query.clone();
+        query_upper.iter_mut().for_each(|b| {
+            if b.is_ascii_lowercase() {
+                *b = b.to_ascii_uppercase()
+            }
+        });
+        for query in [query, query_upper] {
+            let (id, qname, parsed) = parse_query(&query, domain).unwrap();
+            assert_eq!(id, 1234);
+            assert_eq!(parsed, up);
+
+            let down = DownPacket {
+                down_seq: 3,
+                up_ack: 8,
+                fin: false,
+                data: vec![0xab; MAX_DOWN],
+            };
+            let response = build_response(id, &qname, &down).unwrap();
+            assert!(response.len() <= EDNS_UDP_SIZE as usize);
+            assert_eq!(parse_response(&response).unwrap(), (1234, down));
+        }
+        assert!(parse_query(&build_query(1, &up, domain).unwrap(), "other.com").is_none());
+    }
+}
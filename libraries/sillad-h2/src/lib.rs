@@ -0,0 +1,372 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use async_compat::Compat;
+use async_trait::async_trait;
+use bytes::{BufMut, Bytes, BytesMut};
+use futures_lite::{AsyncRead, AsyncWrite};
+use h2::{Reason, RecvStream, SendStream};
+use sillad::{
+    accept::{AcceptQueue, HandshakeLimits},
+    dialer::Dialer,
+    listener::Listener,
+    Pipe,
+};
+
+/// The most data that a single write puts into one gRPC message.
+const MAX_MESSAGE: usize = 16384;
+
+/// The largest gRPC message we accept from the other side.
+const MAX_INCOMING_MESSAGE: usize = 1 << 22;
+
+const GRPC_CONTENT_TYPE: &str = "application/grpc";
+
+/// A dialer that tunnels through a single gRPC-shaped HTTP/2 stream over each connection of its inner dialer. Use a TLS dialer that negotiates "h2" over ALPN as the inner dialer to look like real gRPC.
+pub struct H2Dialer<D: Dialer> {
+    inner: D,
+    authority: String,
+    path: String,
+}
+
+impl<D: Dialer> H2Dialer<D> {
+    /// Creates a dialer that posts to the given path (which should look like "/package.Service/Method") at the given authority.
+    pub fn new(inner: D, authority: impl Into<String>, path: impl Into<String>) -> Self {
+        Self {
+            inner,
+            authority: authority.into(),
+            path: path.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl<D: Dialer> Dialer for H2Dialer<D> {
+    type P = H2Pipe;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let lower = self.inner.dial().await?;
+        let remote_addr = lower.remote_addr().map(|s| s.to_string());
+        let (send_request, conn) = h2::client::handshake(Compat::new(lower))
+            .await
+            .map_err(h2_to_io)?;
+        smolscale::spawn(async move {
+            if let Err(err) = conn.await {
+                tracing::debug!(err = debug(err), "h2 client connection died");
+            }
+        })
+        .detach();
+
+        let request = http::Request::builder()
+            .method("POST")
+            .uri(format!("https://{}{}", self.authority, self.path))
+            .header("content-type", GRPC_CONTENT_TYPE)
+            .header("te", "trailers")
+            .body(())
+            .map_err(std::io::Error::other)?;
+        let mut send_request = send_request.ready().await.map_err(h2_to_io)?;
+        let (response, send) = send_request
+            .send_request(request, false)
+            .map_err(h2_to_io)?;
+        let response = response.await.map_err(h2_to_io)?;
+        if response.status() != http::StatusCode::OK {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::ConnectionRefused,
+                format!("h2 stream refused with status {}", response.status()),
+            ));
+        }
+        Ok(H2Pipe::new(send, response.into_body(), false, remote_addr))
+    }
+}
+
+/// A listener that accepts the gRPC-shaped HTTP/2 streams made by an [H2Dialer], one per connection of its inner listener. This is what an h2-only reverse proxy in front of a bridge would forward to.
+///
+/// Handshakes run concurrently in the background, within the given [HandshakeLimits]. Connections that fail or time out their handshakes are dropped without being returned from `accept`.
+pub struct H2Listener<L: Listener> {
+    queue: AcceptQueue<H2Pipe>,
+    _phantom: std::marker::PhantomData<fn() -> L>,
+}
+
+impl<L: Listener> H2Listener<L> {
+    pub fn new(inner: L) -> Self {
+        Self::with_limits(inner, HandshakeLimits::default())
+    }
+
+    /// Like [H2Listener::new], but with specific limits on handshakes.
+    pub fn with_limits(inner: L, limits: HandshakeLimits) -> Self {
+        let queue = AcceptQueue::spawn(inner, limits, |lower: L::P| {
+            let remote_addr = lower.remote_addr().map(|s| s.to_string());
+            server_handshake(lower, remote_addr)
+        });
+        Self {
+            queue,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+#[async_trait]
+impl<L: Listener> Listener for H2Listener<L> {
+    type P = H2Pipe;
+
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        self.queue.accept().await
+    }
+}
+
+async fn server_handshake<P: Pipe>(
+    lower: P,
+    remote_addr: Option<String>,
+) -> std::io::Result<H2Pipe> {
+    let mut conn = h2::server::handshake(Compat::new(lower))
+        .await
+        .map_err(h2_to_io)?;
+    let (request, mut respond) = conn
+        .accept()
+        .await
+        .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?
+        .map_err(h2_to_io)?;
+    let is_grpc = request.method() == http::Method::POST
+        && request
+            .headers()
+            .get("content-type")
+            .is_some_and(|v| v.as_bytes().starts_with(GRPC_CONTENT_TYPE.as_bytes()));
+    if !is_grpc {
+        let response = http::Response::builder()
+            .status(http::StatusCode::NOT_FOUND)
+            .body(())
+            .map_err(std::io::Error::other)?;
+        let _ = respond.send_response(response, true);
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "not a gRPC request",
+        ));
+    }
+    let response = http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header("content-type", GRPC_CONTENT_TYPE)
+        .body(())
+        .map_err(std::io::Error::other)?;
+    let send = respond.send_response(response, false).map_err(h2_to_io)?;
+    // the connection only makes progress while it is being polled, and we have no use for further streams
+    smolscale::spawn(async move {
+        while let Some(res) = conn.accept().await {
+            match res {
+                Ok((_, mut respond)) => respond.send_reset(Reason::REFUSED_STREAM),
+                Err(err) => {
+                    tracing::debug!(err = debug(err), "h2 server connection died");
+                    break;
+                }
+            }
+        }
+    })
+    .detach();
+    Ok(H2Pipe::new(send, request.into_body(), true, remote_addr))
+}
+
+/// A pipe that carries its bytes as length-prefixed gRPC messages in one HTTP/2 stream.
+pub struct H2Pipe {
+    send: SendStream<Bytes>,
+    recv: RecvStream,
+    is_server: bool,
+    remote_addr: Option<String>,
+
+    raw_read_buf: BytesMut,
+    read_buf: Bytes,
+    read_closed: bool,
+    write_closed: bool,
+}
+
+impl H2Pipe {
+    fn new(
+        send: SendStream<Bytes>,
+        recv: RecvStream,
+        is_server: bool,
+        remote_addr: Option<String>,
+    ) -> Self {
+        Self {
+            send,
+            recv,
+            is_server,
+            remote_addr,
+            raw_read_buf: BytesMut::new(),
+            read_buf: Bytes::new(),
+            read_closed: false,
+            write_closed: false,
+        }
+    }
+
+    /// Takes the next whole gRPC message out of the raw read buffer, if there is one.
+    fn next_message(&mut self) -> std::io::Result<Option<Bytes>> {
+        if self.raw_read_buf.len() < 5 {
+            return Ok(None);
+        }
+        if self.raw_read_buf[0] != 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "compressed gRPC messages are not supported",
+            ));
+        }
+        let len = u32::from_be_bytes(self.raw_read_buf[1..5].try_into().unwrap()) as usize;
+        if len > MAX_INCOMING_MESSAGE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "gRPC message too large",
+            ));
+        }
+        if self.raw_read_buf.len() < 5 + len {
+            return Ok(None);
+        }
+        let mut message = self.raw_read_buf.split_to(5 + len);
+        Ok(Some(message.split_off(5).freeze()))
+    }
+}
+
+impl AsyncRead for H2Pipe {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if !this.read_buf.is_empty() || this.read_closed {
+                let n = buf.len().min(this.read_buf.len());
+                buf[..n].copy_from_slice(&this.read_buf.split_to(n));
+                return Poll::Ready(Ok(n));
+            }
+            if let Some(message) = this.next_message()? {
+                this.read_buf = message;
+                continue;
+            }
+            match futures_lite::ready!(this.recv.poll_data(cx)) {
+                Some(Ok(data)) => {
+                    let _ = this.recv.flow_control().release_capacity(data.len());
+                    this.raw_read_buf.extend_from_slice(&data);
+                }
+                Some(Err(err)) => return Poll::Ready(Err(h2_to_io(err))),
+                None => this.read_closed = true,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for H2Pipe {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        // waiting for flow-control capacity is what gives us backpressure, since h2 would otherwise buffer without limit
+        this.send.reserve_capacity(buf.len().min(MAX_MESSAGE) + 5);
+        let capacity = loop {
+            let capacity = this.send.capacity();
+            if capacity > 5 {
+                break capacity;
+            }
+            match futures_lite::ready!(this.send.poll_capacity(cx)) {
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => return Poll::Ready(Err(h2_to_io(err))),
+                None => return Poll::Ready(Err(std::io::ErrorKind::BrokenPipe.into())),
+            }
+        };
+        let n = buf.len().min(capacity - 5).min(MAX_MESSAGE);
+        let mut message = BytesMut::with_capacity(n + 5);
+        message.put_u8(0);
+        message.put_u32(n as u32);
+        message.put_slice(&buf[..n]);
+        this.send
+            .send_data(message.freeze(), false)
+            .map_err(h2_to_io)?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        // the connection task writes out whatever h2 has queued
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        if !this.write_closed {
+            this.write_closed = true;
+            // servers end gRPC streams with a status in the trailers
+            let res = if this.is_server {
+                let mut trailers = http::HeaderMap::new();
+                trailers.insert("grpc-status", http::HeaderValue::from_static("0"));
+                this.send.send_trailers(trailers)
+            } else {
+                this.send.send_data(Bytes::new(), true)
+            };
+            res.map_err(h2_to_io)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Pipe for H2Pipe {
+    fn protocol(&self) -> &str {
+        "h2-grpc"
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.remote_addr.as_deref()
+    }
+}
+
+fn h2_to_io(err: h2::Error) -> std::io::Error {
+    if err.is_io() {
+        return err.into_io().unwrap();
+    }
+    if err.reason() == Some(Reason::NO_ERROR) {
+        return std::io::ErrorKind::ConnectionAborted.into();
+    }
+    std::io::Error::new(std::io::ErrorKind::ConnectionReset, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::{AsyncReadExt, AsyncWriteExt};
+    use sillad::tcp::{TcpDialer, TcpListener};
+
+    use super::*;
+
+    #[test]
+    fn test_h2_echo() {
+        smolscale::block_on(async {
+            let tcp = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let addr = tcp.local_addr().await;
+            let mut listener = H2Listener::new(tcp);
+            let dialer = H2Dialer::new(
+                TcpDialer { dest_addr: addr },
+                "example.com",
+                "/helloworld.Greeter/SayHello",
+            );
+            // bigger than the default flow-control windows, so that they must get released
+            let message: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+            let server = async {
+                let mut pipe = listener.accept().await.unwrap();
+                let mut received = vec![0u8; message.len()];
+                pipe.read_exact(&mut received).await.unwrap();
+                pipe.write_all(&received).await.unwrap();
+                pipe.close().await.unwrap();
+            };
+            let client = async {
+                let mut pipe = dialer.dial().await.unwrap();
+                pipe.write_all(&message).await.unwrap();
+                let mut echoed = vec![];
+                pipe.read_to_end(&mut echoed).await.unwrap();
+                echoed
+            };
+            let ((), echoed) = futures_lite::future::zip(server, client).await;
+            assert_eq!(echoed, message);
+        });
+    }
+}
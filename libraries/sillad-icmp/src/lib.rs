@@ -0,0 +1,398 @@
+//! A last-ditch transport for networks that let nothing through but ping. It carries a KCP conversation inside ICMP echo requests and replies. Clients keep a few requests outstanding, so that the server always has replies to put its data into. Both ends need raw sockets, and so root or CAP_NET_RAW. Servers should also turn off the kernel's own echo replies, with `net.ipv4.icmp_echo_ignore_all = 1`.
+
+use std::{
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    net::Ipv4Addr,
+    pin::Pin,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use async_io::Timer;
+use async_trait::async_trait;
+use futures_lite::{AsyncRead, AsyncWrite, FutureExt};
+use sillad::{dialer::Dialer, listener::Listener, Pipe};
+use sillad_kcp::KcpPipe;
+
+mod wire;
+
+use wire::{Echo, ECHO_REPLY, ECHO_REQUEST, MAGIC_DOWN, MAGIC_UP};
+
+/// How often an active client polls, to give the server room to reply in.
+const ACTIVE_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How often an idle client polls.
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// How long after the last data a client counts as idle.
+const ACTIVE_PERIOD: Duration = Duration::from_secs(1);
+
+/// The most outstanding requests the server keeps per conversation. Older ones have likely been forgotten by NATs anyway.
+const MAX_SLOTS: usize = 64;
+
+/// The most replies the server holds back while it waits for requests to answer.
+const MAX_QUEUED: usize = 256;
+
+/// How long we remember finished conversations, so that late retransmissions don't resurrect them.
+const DEAD_CONV_MEMORY: Duration = Duration::from_secs(600);
+
+/// A dialer that tunnels a KCP conversation through pings to an [IcmpListener].
+pub struct IcmpDialer {
+    dest: Ipv4Addr,
+}
+
+impl IcmpDialer {
+    pub fn new(dest: Ipv4Addr) -> Self {
+        Self { dest }
+    }
+}
+
+#[async_trait]
+impl Dialer for IcmpDialer {
+    type P = IcmpPipe;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let socket = wire::raw_socket()?;
+        let conv: u32 = rand::random();
+        let (send_outgoing, recv_outgoing) = async_channel::bounded(256);
+        let (send_incoming, recv_incoming) = async_channel::bounded(256);
+        let pipe = sillad_kcp::kcp_over_datagrams(
+            conv,
+            self.dest.to_string(),
+            send_outgoing,
+            recv_incoming,
+        );
+        smolscale::spawn(client_loop(
+            socket,
+            self.dest,
+            conv,
+            recv_outgoing,
+            send_incoming,
+        ))
+        .detach();
+        Ok(IcmpPipe(pipe))
+    }
+}
+
+/// Carries a client's datagrams in echo requests, and picks the server's out of the replies, until the conversation is over.
+async fn client_loop(
+    socket: async_net::UdpSocket,
+    dest: Ipv4Addr,
+    conv: u32,
+    recv_outgoing: async_channel::Receiver<Vec<u8>>,
+    send_incoming: async_channel::Sender<Vec<u8>>,
+) {
+    enum Event {
+        Outgoing(Option<Vec<u8>>),
+        Reply(std::io::Result<usize>),
+        Poll,
+    }
+
+    let id: u16 = rand::random();
+    let mut seq = 0u16;
+    let mut last_data = Instant::now();
+    let mut last_sent = Instant::now();
+    let mut buf = vec![0u8; 65536];
+    let poll_payload = [&MAGIC_UP[..], &conv.to_le_bytes()].concat();
+    loop {
+        let interval = if last_data.elapsed() < ACTIVE_PERIOD {
+            ACTIVE_POLL_INTERVAL
+        } else {
+            IDLE_POLL_INTERVAL
+        };
+        let event = async { Event::Outgoing(recv_outgoing.recv().await.ok()) }
+            .or(async { Event::Reply(socket.recv(&mut buf).await) })
+            .or(async {
+                Timer::at(last_sent + interval).await;
+                Event::Poll
+            })
+            .await;
+        let payload = match event {
+            Event::Outgoing(None) => return,
+            Event::Outgoing(Some(datagram)) => {
+                last_data = Instant::now();
+                [&MAGIC_UP[..], &datagram].concat()
+            }
+            Event::Reply(Err(err)) => {
+                tracing::debug!(err = debug(err), "ICMP socket failed");
+                return;
+            }
+            Event::Reply(Ok(n)) => {
+                let Some(echo) = wire::parse_echo(&buf[..n]) else {
+                    continue;
+                };
+                let Some(datagram) = echo.payload.strip_prefix(MAGIC_DOWN) else {
+                    continue;
+                };
+                if echo.kind != ECHO_REPLY
+                    || echo.id != id
+                    || sillad_kcp::conv_of(datagram) != Some(conv)
+                {
+                    continue;
+                }
+                if datagram.len() > 4 {
+                    last_data = Instant::now();
+                    let _ = send_incoming.try_send(datagram.to_vec());
+                }
+                // every reply used up one of our requests, so send another
+                poll_payload.clone()
+            }
+            Event::Poll => poll_payload.clone(),
+        };
+        seq = seq.wrapping_add(1);
+        let request = Echo {
+            kind: ECHO_REQUEST,
+            id,
+            seq,
+            payload: &payload,
+        };
+        let _ = socket
+            .send_to(&request.encode(), wire::icmp_addr(dest))
+            .await;
+        last_sent = Instant::now();
+    }
+}
+
+/// A listener that answers the pings of [IcmpDialer]s. It sees every echo request that reaches this host, whatever address it was sent to.
+pub struct IcmpListener {
+    recv: tachyonix::Receiver<std::io::Result<IcmpPipe>>,
+    _task: async_task::Task<()>,
+}
+
+impl IcmpListener {
+    pub async fn bind() -> std::io::Result<Self> {
+        let socket = wire::raw_socket()?;
+        let (send, recv) = tachyonix::channel(1);
+        let _task = smolscale::spawn(async move {
+            if let Err(err) = server_loop(socket, send.clone()).await {
+                let _ = send.send(Err(err)).await;
+            }
+        });
+        Ok(Self { recv, _task })
+    }
+}
+
+#[async_trait]
+impl Listener for IcmpListener {
+    type P = IcmpPipe;
+
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        self.recv.recv().await.map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "listener has shut down for some reason",
+            )
+        })?
+    }
+}
+
+/// The server's view of one conversation.
+struct ServerConv {
+    send_incoming: async_channel::Sender<Vec<u8>>,
+    /// The requests we haven't replied to yet, as (id, seq).
+    slots: VecDeque<(u16, u16)>,
+    queued: VecDeque<Vec<u8>>,
+}
+
+/// Demultiplexes echo requests by sender and conversation, and answers them with whatever each conversation has to send.
+async fn server_loop(
+    socket: async_net::UdpSocket,
+    send_pipe: tachyonix::Sender<std::io::Result<IcmpPipe>>,
+) -> std::io::Result<()> {
+    enum Event {
+        Request(std::io::Result<(usize, std::net::SocketAddr)>),
+        Outgoing((Ipv4Addr, u32), Vec<u8>),
+    }
+
+    let mut convs: HashMap<(Ipv4Addr, u32), ServerConv> = HashMap::new();
+    let mut dead: HashMap<(Ipv4Addr, u32), Instant> = HashMap::new();
+    let (send_outgoing, recv_outgoing) = async_channel::unbounded();
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let event = async { Event::Request(socket.recv_from(&mut buf).await) }
+            .or(async {
+                let (key, datagram) = recv_outgoing.recv().await.unwrap();
+                Event::Outgoing(key, datagram)
+            })
+            .await;
+        let key = match event {
+            Event::Request(res) => {
+                let (n, from) = res?;
+                let std::net::IpAddr::V4(from) = from.ip() else {
+                    continue;
+                };
+                let Some(echo) = wire::parse_echo(&buf[..n]) else {
+                    continue;
+                };
+                let Some(datagram) = echo.payload.strip_prefix(MAGIC_UP) else {
+                    continue;
+                };
+                if echo.kind != ECHO_REQUEST {
+                    continue;
+                }
+                let Some(conv) = sillad_kcp::conv_of(datagram) else {
+                    continue;
+                };
+                let key = (from, conv);
+                if let Entry::Vacant(entry) = convs.entry(key) {
+                    if dead.contains_key(&key) || !sillad_kcp::is_first_push(datagram) {
+                        continue;
+                    }
+                    dead.retain(|_, died| died.elapsed() < DEAD_CONV_MEMORY);
+                    let (send_conv_outgoing, recv_conv_outgoing) = async_channel::bounded(256);
+                    let (send_incoming, recv_incoming) = async_channel::bounded(256);
+                    let pipe = sillad_kcp::kcp_over_datagrams(
+                        conv,
+                        from.to_string(),
+                        send_conv_outgoing,
+                        recv_incoming,
+                    );
+                    let send_outgoing = send_outgoing.clone();
+                    smolscale::spawn(async move {
+                        while let Ok(datagram) = recv_conv_outgoing.recv().await {
+                            let _ = send_outgoing.send((key, datagram)).await;
+                        }
+                    })
+                    .detach();
+                    entry.insert(ServerConv {
+                        send_incoming,
+                        slots: VecDeque::new(),
+                        queued: VecDeque::new(),
+                    });
+                    if send_pipe.send(Ok(IcmpPipe(pipe))).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                let state = convs.get_mut(&key).unwrap();
+                state.slots.push_back((echo.id, echo.seq));
+                if state.slots.len() > MAX_SLOTS {
+                    state.slots.pop_front();
+                }
+                if datagram.len() > 4 {
+                    if let Err(async_channel::TrySendError::Closed(_)) =
+                        state.send_incoming.try_send(datagram.to_vec())
+                    {
+                        convs.remove(&key);
+                        dead.insert(key, Instant::now());
+                        continue;
+                    }
+                }
+                key
+            }
+            Event::Outgoing(key, datagram) => {
+                let Some(state) = convs.get_mut(&key) else {
+                    continue;
+                };
+                state.queued.push_back(datagram);
+                if state.queued.len() > MAX_QUEUED {
+                    state.queued.pop_front();
+                }
+                key
+            }
+        };
+
+        let state = convs.get_mut(&key).unwrap();
+        while !state.queued.is_empty() && !state.slots.is_empty() {
+            let datagram = state.queued.pop_front().unwrap();
+            // the newest requests are the likeliest to still be let through
+            let (id, seq) = state.slots.pop_back().unwrap();
+            let payload = [&MAGIC_DOWN[..], &datagram].concat();
+            let reply = Echo {
+                kind: ECHO_REPLY,
+                id,
+                seq,
+                payload: &payload,
+            };
+            let _ = socket
+                .send_to(&reply.encode(), wire::icmp_addr(key.0))
+                .await;
+        }
+    }
+}
+
+/// A KCP conversation carried over pings.
+pub struct IcmpPipe(KcpPipe);
+
+impl AsyncRead for IcmpPipe {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for IcmpPipe {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.0).poll_close(cx)
+    }
+}
+
+impl Pipe for IcmpPipe {
+    fn protocol(&self) -> &str {
+        "icmp"
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.0.remote_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[test]
+    fn test_icmp_echo() {
+        smolscale::block_on(async {
+            let mut listener = match IcmpListener::bind().await {
+                Ok(listener) => listener,
+                Err(err) if err.kind() == std::io::ErrorKind::PermissionDenied => {
+                    eprintln!("skipping, since raw sockets need privileges");
+                    return;
+                }
+                Err(err) => panic!("{err}"),
+            };
+            let dialer = IcmpDialer::new(Ipv4Addr::LOCALHOST);
+            let message: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+            let client = async {
+                let pipe = dialer.dial().await.unwrap();
+                assert_eq!(pipe.protocol(), "icmp");
+                let (mut read, mut write) = futures_lite::io::split(pipe);
+                let writer = async {
+                    write.write_all(&message).await.unwrap();
+                    write.close().await.unwrap();
+                };
+                let reader = async {
+                    let mut echoed = vec![];
+                    read.read_to_end(&mut echoed).await.unwrap();
+                    echoed
+                };
+                futures_lite::future::zip(writer, reader).await.1
+            };
+            let server = async {
+                let pipe = listener.accept().await.unwrap();
+                let (read, mut write) = futures_lite::io::split(pipe);
+                futures_lite::io::copy(read, &mut write).await.unwrap();
+                write.close().await.unwrap();
+            };
+            let ((), echoed) = futures_lite::future::zip(server, client).await;
+            assert_eq!(echoed, message);
+        });
+    }
+}
@@ -0,0 +1,109 @@
+use std::net::SocketAddr;
+
+use async_net::UdpSocket;
+use socket2::{Domain, Protocol, Socket, Type};
+
+pub const ECHO_REPLY: u8 = 0;
+pub const ECHO_REQUEST: u8 = 8;
+
+/// Marks echo requests from our clients.
+pub const MAGIC_UP: &[u8; 4] = b"g5iu";
+
+/// Marks echo replies from our servers. It differs from [MAGIC_UP] so that clients can tell our replies apart from the ones the kernel sends back automatically.
+pub const MAGIC_DOWN: &[u8; 4] = b"g5id";
+
+/// One ICMP echo message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Echo<'a> {
+    pub kind: u8,
+    pub id: u16,
+    pub seq: u16,
+    pub payload: &'a [u8],
+}
+
+impl Echo<'_> {
+    pub fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(8 + self.payload.len());
+        out.push(self.kind);
+        out.push(0);
+        out.extend_from_slice(&[0, 0]);
+        out.extend_from_slice(&self.id.to_be_bytes());
+        out.extend_from_slice(&self.seq.to_be_bytes());
+        out.extend_from_slice(self.payload);
+        let checksum = checksum(&out);
+        out[2..4].copy_from_slice(&checksum.to_be_bytes());
+        out
+    }
+}
+
+/// Parses an echo message out of what a raw IPv4 ICMP socket returns, which includes the IP header.
+pub fn parse_echo(packet: &[u8]) -> Option<Echo<'_>> {
+    let header_len = ((*packet.first()? & 0x0f) as usize) * 4;
+    let icmp = packet.get(header_len..)?;
+    if icmp.len() < 8 || checksum(icmp) != 0 {
+        return None;
+    }
+    let kind = icmp[0];
+    if kind != ECHO_REQUEST && kind != ECHO_REPLY {
+        return None;
+    }
+    Some(Echo {
+        kind,
+        id: u16::from_be_bytes([icmp[4], icmp[5]]),
+        seq: u16::from_be_bytes([icmp[6], icmp[7]]),
+        payload: &icmp[8..],
+    })
+}
+
+/// The internet checksum, which comes out as zero over a message that already carries a correct one.
+fn checksum(data: &[u8]) -> u16 {
+    let mut sum = 0u32;
+    for chunk in data.chunks(2) {
+        let word = if chunk.len() == 2 {
+            u16::from_be_bytes([chunk[0], chunk[1]])
+        } else {
+            u16::from_be_bytes([chunk[0], 0])
+        };
+        sum += word as u32;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// Opens a raw ICMP socket, which needs root or CAP_NET_RAW.
+pub fn raw_socket() -> std::io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::RAW, Some(Protocol::ICMPV4))?;
+    socket.set_nonblocking(true)?;
+    // a raw socket takes the same send_to and recv_from calls as a UDP one
+    UdpSocket::try_from(std::net::UdpSocket::from(socket))
+}
+
+/// Where to send ICMP messages for a host, since raw sockets ignore the port.
+pub fn icmp_addr(ip: std::net::Ipv4Addr) -> SocketAddr {
+    SocketAddr::new(ip.into(), 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_echo_round_trip() {
+        let echo = Echo {
+            kind: ECHO_REQUEST,
+            id: 0x1234,
+            seq: 7,
+            payload: b"hello!!",
+        };
+        // a minimal IPv4 header, which only needs its length to be right
+        let mut packet = vec![0x45];
+        packet.resize(20, 0);
+        packet.extend_from_slice(&echo.encode());
+        assert_eq!(parse_echo(&packet), Some(echo));
+        let last = packet.len() - 1;
+        packet[last] ^= 1;
+        assert_eq!(parse_echo(&packet), None);
+    }
+}
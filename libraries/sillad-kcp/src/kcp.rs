@@ -0,0 +1,645 @@
+use std::collections::VecDeque;
+
+const CMD_PUSH: u8 = 81;
+const CMD_ACK: u8 = 82;
+const CMD_WASK: u8 = 83;
+const CMD_WINS: u8 = 84;
+
+const ASK_SEND: u32 = 1;
+const ASK_TELL: u32 = 2;
+
+const OVERHEAD: usize = 24;
+const RTO_NDL: u32 = 30;
+const RTO_DEF: u32 = 200;
+const RTO_MAX: u32 = 60000;
+const THRESH_INIT: u32 = 2;
+const THRESH_MIN: u32 = 2;
+const PROBE_INIT: u32 = 7000;
+const PROBE_LIMIT: u32 = 120000;
+const FASTACK_LIMIT: u32 = 5;
+const DEAD_LINK: u32 = 20;
+
+/// The biggest datagram we send, leaving room for IPv6 and a layer of obfuscation over it.
+pub const MTU: usize = 1350;
+
+/// The send and receive windows, in segments.
+pub const WINDOW: u32 = 512;
+
+/// How often the state machine wants to be updated, in milliseconds.
+pub const INTERVAL: u32 = 20;
+
+/// Compares two wrapping timestamps or sequence numbers.
+fn diff(later: u32, earlier: u32) -> i32 {
+    later.wrapping_sub(earlier) as i32
+}
+
+#[derive(Clone, Debug, Default)]
+struct Segment {
+    conv: u32,
+    cmd: u8,
+    frg: u8,
+    wnd: u16,
+    ts: u32,
+    sn: u32,
+    una: u32,
+    resendts: u32,
+    rto: u32,
+    fastack: u32,
+    xmit: u32,
+    data: Vec<u8>,
+}
+
+impl Segment {
+    fn encode(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.conv.to_le_bytes());
+        out.push(self.cmd);
+        out.push(self.frg);
+        out.extend_from_slice(&self.wnd.to_le_bytes());
+        out.extend_from_slice(&self.ts.to_le_bytes());
+        out.extend_from_slice(&self.sn.to_le_bytes());
+        out.extend_from_slice(&self.una.to_le_bytes());
+        out.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+        out.extend_from_slice(&self.data);
+    }
+}
+
+/// Reads the conversation number of a datagram, if it is long enough to be one of ours.
+pub fn conv_of(datagram: &[u8]) -> Option<u32> {
+    Some(u32::from_le_bytes(datagram.get(..4)?.try_into().ok()?))
+}
+
+/// Whether a datagram carries the very first data segment of a conversation.
+pub fn is_first_push(datagram: &[u8]) -> bool {
+    let mut rest = datagram;
+    while rest.len() >= OVERHEAD {
+        let sn = u32::from_le_bytes(rest[12..16].try_into().unwrap());
+        let len = u32::from_le_bytes(rest[20..24].try_into().unwrap()) as usize;
+        if rest[4] == CMD_PUSH && sn == 0 {
+            return true;
+        }
+        rest = rest.get(OVERHEAD + len..).unwrap_or_default();
+    }
+    false
+}
+
+/// The KCP automatic repeat-request protocol, in stream mode and wire-compatible with ikcp. Ends of stream are marked by empty data segments, which ikcp itself would deliver as empty messages.
+pub struct Kcp {
+    conv: u32,
+    mss: usize,
+    snd_una: u32,
+    snd_nxt: u32,
+    rcv_nxt: u32,
+    ssthresh: u32,
+    rx_rttval: u32,
+    rx_srtt: u32,
+    rx_rto: u32,
+    rx_minrto: u32,
+    snd_wnd: u32,
+    rcv_wnd: u32,
+    rmt_wnd: u32,
+    cwnd: u32,
+    incr: u32,
+    probe: u32,
+    ts_flush: u32,
+    ts_probe: u32,
+    probe_wait: u32,
+    updated: bool,
+    nodelay: bool,
+    fastresend: u32,
+    nocwnd: bool,
+    dead: bool,
+
+    snd_queue: VecDeque<Segment>,
+    snd_buf: VecDeque<Segment>,
+    rcv_buf: VecDeque<Segment>,
+    rcv_queue: VecDeque<Segment>,
+    acklist: Vec<(u32, u32)>,
+
+    send_closed: bool,
+    recv_closed: bool,
+}
+
+impl Kcp {
+    /// Creates the state for one end of a conversation, tuned for lossy links: no congestion window, fast resends after two skipping acks, and a low minimum retransmission timeout.
+    pub fn new(conv: u32) -> Self {
+        Self {
+            conv,
+            mss: MTU - OVERHEAD,
+            snd_una: 0,
+            snd_nxt: 0,
+            rcv_nxt: 0,
+            ssthresh: THRESH_INIT,
+            rx_rttval: 0,
+            rx_srtt: 0,
+            rx_rto: RTO_DEF,
+            rx_minrto: RTO_NDL,
+            snd_wnd: WINDOW,
+            rcv_wnd: WINDOW,
+            rmt_wnd: WINDOW,
+            cwnd: 0,
+            incr: 0,
+            probe: 0,
+            ts_flush: INTERVAL,
+            ts_probe: 0,
+            probe_wait: 0,
+            updated: false,
+            nodelay: true,
+            fastresend: 2,
+            nocwnd: true,
+            dead: false,
+
+            snd_queue: VecDeque::new(),
+            snd_buf: VecDeque::new(),
+            rcv_buf: VecDeque::new(),
+            rcv_queue: VecDeque::new(),
+            acklist: Vec::new(),
+
+            send_closed: false,
+            recv_closed: false,
+        }
+    }
+
+    /// Queues bytes for sending, filling up the last queued segment first.
+    pub fn send(&mut self, mut data: &[u8]) {
+        if let Some(last) = self.snd_queue.back_mut() {
+            if !last.data.is_empty() && last.data.len() < self.mss {
+                let n = (self.mss - last.data.len()).min(data.len());
+                last.data.extend_from_slice(&data[..n]);
+                data = &data[n..];
+            }
+        }
+        for chunk in data.chunks(self.mss) {
+            self.snd_queue.push_back(Segment {
+                data: chunk.to_vec(),
+                ..Default::default()
+            });
+        }
+    }
+
+    /// Queues the end of the stream. Nothing may be sent after this.
+    pub fn close(&mut self) {
+        if !self.send_closed {
+            self.send_closed = true;
+            self.snd_queue.push_back(Segment::default());
+        }
+    }
+
+    /// Takes in-order received bytes. Returns `Some(0)` at the end of the stream, and `None` if nothing is ready yet.
+    pub fn recv(&mut self, buf: &mut [u8]) -> Option<usize> {
+        let recovering = self.rcv_queue.len() as u32 >= self.rcv_wnd;
+        let mut n = 0;
+        while n < buf.len() {
+            let Some(seg) = self.rcv_queue.front_mut() else {
+                break;
+            };
+            if seg.data.is_empty() {
+                self.recv_closed = true;
+                break;
+            }
+            let take = seg.data.len().min(buf.len() - n);
+            buf[n..n + take].copy_from_slice(&seg.data[..take]);
+            seg.data.drain(..take);
+            n += take;
+            if seg.data.is_empty() {
+                self.rcv_queue.pop_front();
+            }
+        }
+        self.move_to_rcv_queue();
+        // tell the other side as soon as the window opens back up
+        if recovering && (self.rcv_queue.len() as u32) < self.rcv_wnd {
+            self.probe |= ASK_TELL;
+        }
+        if n > 0 {
+            Some(n)
+        } else if self.recv_closed {
+            Some(0)
+        } else {
+            None
+        }
+    }
+
+    /// How many segments are waiting to be sent or acknowledged.
+    pub fn waitsnd(&self) -> usize {
+        self.snd_buf.len() + self.snd_queue.len()
+    }
+
+    /// Whether the other side has stopped responding altogether.
+    pub fn is_dead(&self) -> bool {
+        self.dead
+    }
+
+    /// Whether both directions have ended and everything we sent was acknowledged.
+    pub fn is_finished(&self) -> bool {
+        self.send_closed && self.recv_closed && self.waitsnd() == 0
+    }
+
+    /// Feeds in one datagram from the other side.
+    pub fn input(&mut self, current: u32, mut data: &[u8]) -> std::io::Result<()> {
+        let prev_una = self.snd_una;
+        let mut max_ack = None;
+        if data.len() < OVERHEAD {
+            return Err(invalid_data("KCP datagram too short"));
+        }
+        while data.len() >= OVERHEAD {
+            let conv = u32::from_le_bytes(data[0..4].try_into().unwrap());
+            let cmd = data[4];
+            let frg = data[5];
+            let wnd = u16::from_le_bytes(data[6..8].try_into().unwrap());
+            let ts = u32::from_le_bytes(data[8..12].try_into().unwrap());
+            let sn = u32::from_le_bytes(data[12..16].try_into().unwrap());
+            let una = u32::from_le_bytes(data[16..20].try_into().unwrap());
+            let len = u32::from_le_bytes(data[20..24].try_into().unwrap()) as usize;
+            data = &data[OVERHEAD..];
+            if conv != self.conv {
+                return Err(invalid_data("KCP conversation mismatch"));
+            }
+            if data.len() < len {
+                return Err(invalid_data("KCP segment truncated"));
+            }
+            if !(CMD_PUSH..=CMD_WINS).contains(&cmd) {
+                return Err(invalid_data("unknown KCP command"));
+            }
+
+            self.rmt_wnd = wnd as u32;
+            self.parse_una(una);
+            self.shrink_buf();
+            match cmd {
+                CMD_ACK => {
+                    if diff(current, ts) >= 0 {
+                        self.update_ack(diff(current, ts) as u32);
+                    }
+                    self.parse_ack(sn);
+                    self.shrink_buf();
+                    max_ack = match max_ack {
+                        Some((max_sn, _)) if diff(sn, max_sn) <= 0 => max_ack,
+                        _ => Some((sn, ts)),
+                    };
+                }
+                CMD_PUSH if diff(sn, self.rcv_nxt.wrapping_add(self.rcv_wnd)) < 0 => {
+                    self.acklist.push((sn, ts));
+                    if diff(sn, self.rcv_nxt) >= 0 {
+                        self.parse_data(Segment {
+                            conv,
+                            cmd,
+                            frg,
+                            wnd,
+                            ts,
+                            sn,
+                            una,
+                            data: data[..len].to_vec(),
+                            ..Default::default()
+                        });
+                    }
+                }
+                CMD_WASK => self.probe |= ASK_TELL,
+                _ => {}
+            }
+            data = &data[len..];
+        }
+
+        if let Some((sn, ts)) = max_ack {
+            self.parse_fastack(sn, ts);
+        }
+        if diff(self.snd_una, prev_una) > 0 && self.cwnd < self.rmt_wnd {
+            let mss = self.mss as u32;
+            if self.cwnd < self.ssthresh {
+                self.cwnd += 1;
+                self.incr += mss;
+            } else {
+                self.incr = self.incr.max(mss);
+                self.incr += (mss * mss) / self.incr + (mss / 16);
+                if (self.cwnd + 1) * mss <= self.incr {
+                    self.cwnd = self.incr.div_ceil(mss);
+                }
+            }
+            if self.cwnd > self.rmt_wnd {
+                self.cwnd = self.rmt_wnd;
+                self.incr = self.rmt_wnd * mss;
+            }
+        }
+        Ok(())
+    }
+
+    fn update_ack(&mut self, rtt: u32) {
+        if self.rx_srtt == 0 {
+            self.rx_srtt = rtt;
+            self.rx_rttval = rtt / 2;
+        } else {
+            let delta = rtt.abs_diff(self.rx_srtt);
+            self.rx_rttval = (3 * self.rx_rttval + delta) / 4;
+            self.rx_srtt = ((7 * self.rx_srtt + rtt) / 8).max(1);
+        }
+        let rto = self.rx_srtt + INTERVAL.max(4 * self.rx_rttval);
+        self.rx_rto = rto.clamp(self.rx_minrto, RTO_MAX);
+    }
+
+    fn shrink_buf(&mut self) {
+        self.snd_una = self.snd_buf.front().map_or(self.snd_nxt, |seg| seg.sn);
+    }
+
+    fn parse_ack(&mut self, sn: u32) {
+        if diff(sn, self.snd_una) < 0 || diff(sn, self.snd_nxt) >= 0 {
+            return;
+        }
+        if let Some(pos) = self.snd_buf.iter().position(|seg| seg.sn == sn) {
+            self.snd_buf.remove(pos);
+        }
+    }
+
+    fn parse_una(&mut self, una: u32) {
+        while self
+            .snd_buf
+            .front()
+            .is_some_and(|seg| diff(una, seg.sn) > 0)
+        {
+            self.snd_buf.pop_front();
+        }
+    }
+
+    fn parse_fastack(&mut self, sn: u32, ts: u32) {
+        if diff(sn, self.snd_una) < 0 || diff(sn, self.snd_nxt) >= 0 {
+            return;
+        }
+        for seg in self.snd_buf.iter_mut() {
+            if diff(sn, seg.sn) < 0 {
+                break;
+            }
+            if sn != seg.sn && diff(ts, seg.ts) >= 0 {
+                seg.fastack += 1;
+            }
+        }
+    }
+
+    fn parse_data(&mut self, seg: Segment) {
+        let sn = seg.sn;
+        if diff(sn, self.rcv_nxt.wrapping_add(self.rcv_wnd)) >= 0 || diff(sn, self.rcv_nxt) < 0 {
+            return;
+        }
+        // the buffer is sorted by sequence number, and duplicates are dropped
+        let pos = self
+            .rcv_buf
+            .iter()
+            .rposition(|other| diff(sn, other.sn) >= 0);
+        match pos {
+            Some(pos) if self.rcv_buf[pos].sn == sn => {}
+            Some(pos) => self.rcv_buf.insert(pos + 1, seg),
+            None => self.rcv_buf.push_front(seg),
+        }
+        self.move_to_rcv_queue();
+    }
+
+    fn move_to_rcv_queue(&mut self) {
+        while self
+            .rcv_buf
+            .front()
+            .is_some_and(|seg| seg.sn == self.rcv_nxt)
+            && (self.rcv_queue.len() as u32) < self.rcv_wnd
+        {
+            let seg = self.rcv_buf.pop_front().unwrap();
+            self.rcv_queue.push_back(seg);
+            self.rcv_nxt = self.rcv_nxt.wrapping_add(1);
+        }
+    }
+
+    fn wnd_unused(&self) -> u16 {
+        self.rcv_wnd.saturating_sub(self.rcv_queue.len() as u32) as u16
+    }
+
+    /// Advances the clock, calling `output` with every datagram that should go out now.
+    pub fn update(&mut self, current: u32, output: &mut impl FnMut(&[u8])) {
+        if !self.updated {
+            self.updated = true;
+            self.ts_flush = current;
+        }
+        let mut slap = diff(current, self.ts_flush);
+        if !(-10000..10000).contains(&slap) {
+            self.ts_flush = current;
+            slap = 0;
+        }
+        if slap >= 0 {
+            self.ts_flush = self.ts_flush.wrapping_add(INTERVAL);
+            if diff(current, self.ts_flush) >= 0 {
+                self.ts_flush = current.wrapping_add(INTERVAL);
+            }
+            self.flush(current, output);
+        }
+    }
+
+    /// How many milliseconds until `update` should be called again.
+    pub fn check(&self, current: u32) -> u32 {
+        if !self.updated {
+            return 0;
+        }
+        let mut next = diff(self.ts_flush, current).max(0) as u32;
+        for seg in self.snd_buf.iter() {
+            let until = diff(seg.resendts, current);
+            if until <= 0 {
+                return 0;
+            }
+            next = next.min(until as u32);
+        }
+        next.min(INTERVAL)
+    }
+
+    fn flush(&mut self, current: u32, output: &mut impl FnMut(&[u8])) {
+        let mut buffer = Vec::with_capacity(MTU);
+        let wnd = self.wnd_unused();
+        let mut seg = Segment {
+            conv: self.conv,
+            cmd: CMD_ACK,
+            wnd,
+            una: self.rcv_nxt,
+            ..Default::default()
+        };
+
+        for (sn, ts) in std::mem::take(&mut self.acklist) {
+            make_room(&mut buffer, OVERHEAD, output);
+            seg.sn = sn;
+            seg.ts = ts;
+            seg.encode(&mut buffer);
+        }
+
+        // probe the window when the other side says it's full
+        if self.rmt_wnd == 0 {
+            if self.probe_wait == 0 {
+                self.probe_wait = PROBE_INIT;
+                self.ts_probe = current.wrapping_add(self.probe_wait);
+            } else if diff(current, self.ts_probe) >= 0 {
+                self.probe_wait = self.probe_wait.max(PROBE_INIT);
+                self.probe_wait = (self.probe_wait + self.probe_wait / 2).min(PROBE_LIMIT);
+                self.ts_probe = current.wrapping_add(self.probe_wait);
+                self.probe |= ASK_SEND;
+            }
+        } else {
+            self.ts_probe = 0;
+            self.probe_wait = 0;
+        }
+        seg.sn = 0;
+        seg.ts = 0;
+        for (flag, cmd) in [(ASK_SEND, CMD_WASK), (ASK_TELL, CMD_WINS)] {
+            if self.probe & flag != 0 {
+                make_room(&mut buffer, OVERHEAD, output);
+                seg.cmd = cmd;
+                seg.encode(&mut buffer);
+            }
+        }
+        self.probe = 0;
+
+        let mut cwnd = self.snd_wnd.min(self.rmt_wnd);
+        if !self.nocwnd {
+            cwnd = cwnd.min(self.cwnd);
+        }
+        while diff(self.snd_nxt, self.snd_una.wrapping_add(cwnd)) < 0 {
+            let Some(mut new) = self.snd_queue.pop_front() else {
+                break;
+            };
+            new.conv = self.conv;
+            new.cmd = CMD_PUSH;
+            new.wnd = wnd;
+            new.ts = current;
+            new.sn = self.snd_nxt;
+            new.una = self.rcv_nxt;
+            new.resendts = current;
+            new.rto = self.rx_rto;
+            self.snd_nxt = self.snd_nxt.wrapping_add(1);
+            self.snd_buf.push_back(new);
+        }
+
+        let resent = if self.fastresend > 0 {
+            self.fastresend
+        } else {
+            u32::MAX
+        };
+        let rtomin = if self.nodelay { 0 } else { self.rx_rto >> 3 };
+        let mut lost = false;
+        let mut change = false;
+        for seg in self.snd_buf.iter_mut() {
+            let mut needsend = false;
+            if seg.xmit == 0 {
+                needsend = true;
+                seg.xmit += 1;
+                seg.rto = self.rx_rto;
+                seg.resendts = current.wrapping_add(seg.rto + rtomin);
+            } else if diff(current, seg.resendts) >= 0 {
+                needsend = true;
+                seg.xmit += 1;
+                if self.nodelay {
+                    seg.rto += self.rx_rto / 2;
+                } else {
+                    seg.rto += seg.rto.max(self.rx_rto);
+                }
+                seg.resendts = current.wrapping_add(seg.rto);
+                lost = true;
+            } else if seg.fastack >= resent && seg.xmit <= FASTACK_LIMIT {
+                needsend = true;
+                seg.xmit += 1;
+                seg.fastack = 0;
+                seg.resendts = current.wrapping_add(seg.rto);
+                change = true;
+            }
+            if needsend {
+                seg.ts = current;
+                seg.wnd = wnd;
+                seg.una = self.rcv_nxt;
+                make_room(&mut buffer, OVERHEAD + seg.data.len(), output);
+                seg.encode(&mut buffer);
+                if seg.xmit >= DEAD_LINK {
+                    self.dead = true;
+                }
+            }
+        }
+        if !buffer.is_empty() {
+            output(&buffer);
+        }
+
+        let mss = self.mss as u32;
+        if change {
+            let inflight = self.snd_nxt.wrapping_sub(self.snd_una);
+            self.ssthresh = (inflight / 2).max(THRESH_MIN);
+            self.cwnd = self.ssthresh + resent;
+            self.incr = self.cwnd * mss;
+        }
+        if lost {
+            self.ssthresh = (cwnd / 2).max(THRESH_MIN);
+            self.cwnd = 1;
+            self.incr = mss;
+        }
+        if self.cwnd < 1 {
+            self.cwnd = 1;
+            self.incr = mss;
+        }
+    }
+}
+
+/// Sends off what has been batched so far if another `extra` bytes wouldn't fit in one datagram.
+fn make_room(buffer: &mut Vec<u8>, extra: usize, output: &mut impl FnMut(&[u8])) {
+    if buffer.len() + extra > MTU && !buffer.is_empty() {
+        output(buffer);
+        buffer.clear();
+    }
+}
+
+fn invalid_data(msg: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs two ends against each other over a link that drops every third datagram.
+    #[test]
+    fn test_lossy_link() {
+        let mut alice = Kcp::new(42);
+        let mut bob = Kcp::new(42);
+        let message: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        alice.send(&message);
+        alice.close();
+
+        let mut received = Vec::new();
+        let mut buf = vec![0u8; 4096];
+        let mut dropped = 0u32;
+        for now in (0..200_000u32).step_by(10) {
+            let mut to_bob = Vec::new();
+            alice.update(now, &mut |d| to_bob.push(d.to_vec()));
+            for datagram in to_bob {
+                dropped += 1;
+                if !dropped.is_multiple_of(3) {
+                    bob.input(now, &datagram).unwrap();
+                }
+            }
+            while let Some(n) = bob.recv(&mut buf) {
+                if n == 0 {
+                    break;
+                }
+                received.extend_from_slice(&buf[..n]);
+            }
+            let mut to_alice = Vec::new();
+            bob.update(now, &mut |d| to_alice.push(d.to_vec()));
+            for datagram in to_alice {
+                dropped += 1;
+                if !dropped.is_multiple_of(3) {
+                    alice.input(now, &datagram).unwrap();
+                }
+            }
+            if bob.recv(&mut buf) == Some(0) && alice.waitsnd() == 0 {
+                break;
+            }
+        }
+        assert_eq!(received, message);
+        assert_eq!(alice.waitsnd(), 0);
+        assert!(!alice.is_dead());
+    }
+
+    #[test]
+    fn test_rejects_garbage() {
+        let mut kcp = Kcp::new(1);
+        assert!(kcp.input(0, &[0u8; 10]).is_err());
+        let mut other = Kcp::new(2);
+        other.send(b"hello");
+        let mut datagrams = Vec::new();
+        other.update(0, &mut |d| datagrams.push(d.to_vec()));
+        assert!(is_first_push(&datagrams[0]));
+        assert!(kcp.input(0, &datagrams[0]).is_err());
+    }
+}
@@ -0,0 +1,272 @@
+//! A reliable stream over UDP using the KCP protocol, tuned for links that lose a lot of packets. KCP trades bandwidth for latency: it retransmits aggressively instead of backing off the way TCP does.
+
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use async_net::UdpSocket;
+use async_trait::async_trait;
+use bipe::{BipeReader, BipeWriter};
+use futures_lite::{AsyncRead, AsyncWrite, FutureExt};
+use sillad::{dialer::Dialer, listener::Listener, Pipe};
+
+mod kcp;
+mod session;
+
+pub use kcp::{conv_of, is_first_push};
+
+/// How long we remember finished conversations, so that late retransmissions don't resurrect them.
+const DEAD_CONV_MEMORY: Duration = Duration::from_secs(600);
+
+/// A dialer that starts a KCP conversation with a [KcpListener].
+pub struct KcpDialer {
+    dest_addr: SocketAddr,
+}
+
+impl KcpDialer {
+    pub fn new(dest_addr: SocketAddr) -> Self {
+        Self { dest_addr }
+    }
+}
+
+#[async_trait]
+impl Dialer for KcpDialer {
+    type P = KcpPipe;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let bind_addr: SocketAddr = if self.dest_addr.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(bind_addr).await?;
+        let conv: u32 = rand::random();
+        let (send_outgoing, recv_outgoing) = async_channel::bounded(256);
+        let (send_incoming, recv_incoming) = async_channel::bounded(256);
+        let dest_addr = self.dest_addr;
+        let pipe = kcp_over_datagrams(conv, dest_addr.to_string(), send_outgoing, recv_incoming);
+        // the socket lives until the conversation is over and stops sending
+        smolscale::spawn(async move {
+            let receive = async {
+                let mut buf = vec![0u8; 65536];
+                while let Ok((n, from)) = socket.recv_from(&mut buf).await {
+                    if from == dest_addr && kcp::conv_of(&buf[..n]) == Some(conv) {
+                        let _ = send_incoming.try_send(buf[..n].to_vec());
+                    }
+                }
+            };
+            let send = async {
+                while let Ok(datagram) = recv_outgoing.recv().await {
+                    let _ = socket.send_to(&datagram, dest_addr).await;
+                }
+            };
+            send.or(receive).await
+        })
+        .detach();
+        Ok(pipe)
+    }
+}
+
+/// Runs a KCP conversation over any datagram carrier, which takes the datagrams to send from `outgoing` and puts the ones that arrive into `incoming`. Both channels close when the conversation is over.
+pub fn kcp_over_datagrams(
+    conv: u32,
+    remote_addr: String,
+    outgoing: async_channel::Sender<Vec<u8>>,
+    incoming: async_channel::Receiver<Vec<u8>>,
+) -> KcpPipe {
+    let (up_writer, up_reader) = bipe::bipe(65536);
+    let (down_writer, down_reader) = bipe::bipe(65536);
+    // the conversation outlives the pipe, so that whatever was written before closing still gets delivered
+    smolscale::spawn(session::drive(
+        kcp::Kcp::new(conv),
+        outgoing,
+        incoming,
+        up_reader,
+        down_writer,
+    ))
+    .detach();
+    KcpPipe {
+        write: up_writer,
+        read: down_reader,
+        remote_addr,
+    }
+}
+
+/// A listener that accepts KCP conversations on a UDP socket.
+pub struct KcpListener {
+    local_addr: SocketAddr,
+    recv: tachyonix::Receiver<std::io::Result<KcpPipe>>,
+    _task: async_task::Task<()>,
+}
+
+impl KcpListener {
+    pub async fn bind(addr: SocketAddr) -> std::io::Result<Self> {
+        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let local_addr = socket.local_addr()?;
+        let (send, recv) = tachyonix::channel(1);
+        let _task = smolscale::spawn(async move {
+            if let Err(err) = listen_loop(socket, send.clone()).await {
+                let _ = send.send(Err(err)).await;
+            }
+        });
+        Ok(Self {
+            local_addr,
+            recv,
+            _task,
+        })
+    }
+
+    /// Get the local listening address.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+#[async_trait]
+impl Listener for KcpListener {
+    type P = KcpPipe;
+
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        self.recv.recv().await.map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "listener has shut down for some reason",
+            )
+        })?
+    }
+}
+
+/// Demultiplexes incoming datagrams by sender and conversation, starting a new conversation for every first data segment we haven't seen before.
+async fn listen_loop(
+    socket: Arc<UdpSocket>,
+    send_pipe: tachyonix::Sender<std::io::Result<KcpPipe>>,
+) -> std::io::Result<()> {
+    let mut convs: HashMap<(SocketAddr, u32), async_channel::Sender<Vec<u8>>> = HashMap::new();
+    let mut dead: HashMap<(SocketAddr, u32), Instant> = HashMap::new();
+    let mut buf = vec![0u8; 65536];
+    loop {
+        let (n, addr) = socket.recv_from(&mut buf).await?;
+        let datagram = &buf[..n];
+        let Some(conv) = kcp::conv_of(datagram) else {
+            continue;
+        };
+        let key = (addr, conv);
+        if let Some(send) = convs.get(&key) {
+            if let Err(async_channel::TrySendError::Closed(_)) = send.try_send(datagram.to_vec()) {
+                convs.remove(&key);
+                dead.insert(key, Instant::now());
+            }
+            continue;
+        }
+        if dead.contains_key(&key) || !kcp::is_first_push(datagram) {
+            continue;
+        }
+        dead.retain(|_, died| died.elapsed() < DEAD_CONV_MEMORY);
+
+        let (send_outgoing, recv_outgoing) = async_channel::bounded(256);
+        let (send_incoming, recv_incoming) = async_channel::bounded(256);
+        let _ = send_incoming.try_send(datagram.to_vec());
+        convs.insert(key, send_incoming);
+        let pipe = kcp_over_datagrams(conv, addr.to_string(), send_outgoing, recv_incoming);
+        let socket = socket.clone();
+        smolscale::spawn(async move {
+            while let Ok(datagram) = recv_outgoing.recv().await {
+                let _ = socket.send_to(&datagram, addr).await;
+            }
+        })
+        .detach();
+        if send_pipe.send(Ok(pipe)).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// One end of a KCP conversation.
+pub struct KcpPipe {
+    write: BipeWriter,
+    read: BipeReader,
+    remote_addr: String,
+}
+
+impl AsyncRead for KcpPipe {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.read).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for KcpPipe {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.write).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.write).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.write).poll_close(cx)
+    }
+}
+
+impl Pipe for KcpPipe {
+    fn protocol(&self) -> &str {
+        "kcp"
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        Some(&self.remote_addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[test]
+    fn test_kcp_echo() {
+        smolscale::block_on(async {
+            let mut listener = KcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let dialer = KcpDialer::new(listener.local_addr());
+            let message: Vec<u8> = (0..500_000u32).map(|i| (i % 251) as u8).collect();
+            let client = async {
+                let pipe = dialer.dial().await.unwrap();
+                let (mut read, mut write) = futures_lite::io::split(pipe);
+                let writer = async {
+                    write.write_all(&message).await.unwrap();
+                    write.close().await.unwrap();
+                };
+                let reader = async {
+                    let mut echoed = vec![];
+                    read.read_to_end(&mut echoed).await.unwrap();
+                    echoed
+                };
+                futures_lite::future::zip(writer, reader).await.1
+            };
+            let server = async {
+                let pipe = listener.accept().await.unwrap();
+                let (read, mut write) = futures_lite::io::split(pipe);
+                futures_lite::io::copy(read, &mut write).await.unwrap();
+                write.close().await.unwrap();
+            };
+            let ((), echoed) = futures_lite::future::zip(server, client).await;
+            assert_eq!(echoed, message);
+        });
+    }
+}
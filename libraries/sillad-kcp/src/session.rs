@@ -0,0 +1,117 @@
+use std::time::{Duration, Instant};
+
+use async_io::Timer;
+use bipe::{BipeReader, BipeWriter};
+use futures_lite::{AsyncReadExt, AsyncWriteExt, FutureExt};
+
+use crate::kcp::{Kcp, WINDOW};
+
+/// How long a conversation may go without hearing anything from the other side.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long a finished conversation sticks around, to acknowledge retransmissions of the other side's last segments.
+const LINGER: Duration = Duration::from_secs(2);
+
+/// Drives one conversation, sending what comes out of the reader and writing what arrives into the writer, until it finishes, dies or goes idle. Datagrams go out through `outgoing` and come in through `incoming`, and are dropped when the carrier can't keep up, just like UDP would.
+pub async fn drive(
+    mut kcp: Kcp,
+    outgoing: async_channel::Sender<Vec<u8>>,
+    incoming: async_channel::Receiver<Vec<u8>>,
+    mut outgoing_reader: BipeReader,
+    mut incoming_writer: BipeWriter,
+) {
+    enum Event {
+        Incoming(Option<Vec<u8>>),
+        Up(std::io::Result<usize>),
+        Down(std::io::Result<usize>),
+        Tick,
+    }
+
+    let start = Instant::now();
+    let now = || start.elapsed().as_millis() as u32;
+    let mut last_heard = Instant::now();
+    let mut finished_at: Option<Instant> = None;
+    let mut up_buf = vec![0u8; 65536];
+    let mut recv_buf = vec![0u8; 65536];
+    let mut pending_down: Vec<u8> = Vec::new();
+    let mut up_done = false;
+    let mut down_done = false;
+    loop {
+        kcp.update(now(), &mut |datagram| {
+            let _ = outgoing.try_send(datagram.to_vec());
+        });
+        if kcp.is_dead() {
+            tracing::debug!("KCP conversation died");
+            return;
+        }
+        if last_heard.elapsed() > IDLE_TIMEOUT {
+            tracing::debug!("KCP conversation timed out");
+            return;
+        }
+        if kcp.is_finished() {
+            let finished_at = *finished_at.get_or_insert_with(Instant::now);
+            if finished_at.elapsed() > LINGER {
+                return;
+            }
+        }
+
+        if pending_down.is_empty() && !down_done {
+            match kcp.recv(&mut recv_buf) {
+                Some(0) => {
+                    down_done = true;
+                    let _ = incoming_writer.close().await;
+                }
+                Some(n) => pending_down.extend_from_slice(&recv_buf[..n]),
+                None => {}
+            }
+        }
+
+        let wait = Duration::from_millis(kcp.check(now()) as u64);
+        // don't take in more than two windows' worth, so that a slow link pushes back on the writer
+        let can_send = !up_done && kcp.waitsnd() < WINDOW as usize * 2;
+        let event = async { Event::Incoming(incoming.recv().await.ok()) }
+            .or(async {
+                if can_send {
+                    Event::Up(outgoing_reader.read(&mut up_buf).await)
+                } else {
+                    futures_lite::future::pending().await
+                }
+            })
+            .or(async {
+                if pending_down.is_empty() {
+                    futures_lite::future::pending().await
+                } else {
+                    Event::Down(incoming_writer.write(&pending_down).await)
+                }
+            })
+            .or(async {
+                Timer::after(wait).await;
+                Event::Tick
+            })
+            .await;
+        match event {
+            Event::Incoming(Some(datagram)) => {
+                last_heard = Instant::now();
+                if let Err(err) = kcp.input(now(), &datagram) {
+                    tracing::debug!(err = debug(err), "bad KCP datagram");
+                }
+            }
+            Event::Incoming(None) => return,
+            Event::Up(Ok(0)) | Event::Up(Err(_)) => {
+                up_done = true;
+                kcp.close();
+            }
+            Event::Up(Ok(n)) => kcp.send(&up_buf[..n]),
+            Event::Down(Ok(n)) => {
+                pending_down.drain(..n);
+            }
+            Event::Down(Err(_)) => {
+                // nobody is reading anymore, so whatever arrives is thrown away
+                down_done = true;
+                pending_down.clear();
+            }
+            Event::Tick => {}
+        }
+        while down_done && kcp.recv(&mut recv_buf).is_some_and(|n| n > 0) {}
+    }
+}
@@ -0,0 +1,510 @@
+use std::{
+    io::ErrorKind,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use async_io::Timer;
+use async_trait::async_trait;
+use base64::Engine as _;
+use futures_lite::{AsyncReadExt, AsyncWriteExt, FutureExt};
+use rustls::{
+    client::EchConfig,
+    internal::msgs::codec::Codec,
+    pki_types::{EchConfigListBytes, ServerName},
+    PeerIncompatible, RootCertStore,
+};
+use sillad::{dialer::Dialer, tcp::TcpDialer, Pipe};
+use simple_dns::{
+    rdata::{RData, SVCB},
+    Name, Packet, PacketFlag, Question, CLASS, QCLASS, QTYPE, TYPE,
+};
+
+use crate::{
+    hpke::X25519_CHACHA20POLY1305,
+    rustls_tls::{client_handshake, ClientHelloProfile, RustlsPipe},
+};
+
+/// How long ECH configs from a lookup or a server's retry are trusted before being looked up again. Servers rotate their ECH keys every few hours.
+const CONFIG_TTL: Duration = Duration::from_secs(3600);
+
+/// How long a DNS-over-HTTPS lookup may take.
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The largest DNS-over-HTTPS response we accept.
+const MAX_RESPONSE_LEN: usize = 65536;
+
+/// What an [EchDialer] does when it has no usable ECH config for the server, because the lookup failed or the server publishes none.
+#[derive(Clone, Debug)]
+pub enum EchFallback {
+    /// Fail the dial, so that the real server name never goes out in the clear.
+    Refuse,
+    /// Connect with an innocuous server name in the SNI instead, as with domain fronting. The certificate is checked against that name.
+    Front(String),
+    /// Connect with the real server name in the clear.
+    Plain,
+}
+
+/// A DNS-over-HTTPS server to look up ECH configs from, so that the lookup itself doesn't give away the server name.
+#[derive(Clone, Debug)]
+pub struct DohServer {
+    pub addr: SocketAddr,
+    /// The name in the server's certificate.
+    pub name: String,
+}
+
+impl DohServer {
+    /// Cloudflare's public resolver, which also serves the ECH configs of every site behind Cloudflare.
+    pub fn cloudflare() -> Self {
+        Self {
+            addr: "1.1.1.1:443".parse().unwrap(),
+            name: "cloudflare-dns.com".into(),
+        }
+    }
+}
+
+/// EchDialer wraps a Dialer to establish a TLS connection with Encrypted Client Hello, so that SNI-based filtering only ever sees the public name of the ECH config, such as that of a CDN, and never the real server name.
+pub struct EchDialer<D: Dialer> {
+    inner: D,
+    profile: ClientHelloProfile,
+    roots: RootCertStore,
+    domain: String,
+    fallback: EchFallback,
+    static_configs: Option<Vec<u8>>,
+    doh: Option<DohServer>,
+    cache: Mutex<Option<(Vec<u8>, Instant)>>,
+}
+
+impl<D: Dialer> EchDialer<D> {
+    pub fn new(
+        inner: D,
+        profile: &ClientHelloProfile,
+        roots: RootCertStore,
+        domain: String,
+        fallback: EchFallback,
+    ) -> Self {
+        Self {
+            inner,
+            profile: profile.clone(),
+            roots,
+            domain,
+            fallback,
+            static_configs: None,
+            doh: None,
+            cache: Mutex::new(None),
+        }
+    }
+
+    /// Uses the given ECHConfigList, such as one distributed along with the bridge, instead of looking one up.
+    pub fn with_ech_configs(mut self, configs: Vec<u8>) -> Self {
+        self.static_configs = Some(configs);
+        self
+    }
+
+    /// Looks up ECH configs through the given DNS-over-HTTPS server, whenever there are no fresh ones at hand.
+    pub fn with_doh(mut self, doh: DohServer) -> Self {
+        self.doh = Some(doh);
+        self
+    }
+
+    /// Finds an ECH config we can use, preferring ones the server itself sent, then configured ones, then looked-up ones.
+    async fn ech_config(&self) -> Option<EchConfig> {
+        let cached = self
+            .cache
+            .lock()
+            .unwrap()
+            .clone()
+            .filter(|(_, fetched)| fetched.elapsed() < CONFIG_TTL);
+        let configs = match (cached, &self.static_configs, &self.doh) {
+            (Some((configs, _)), _, _) => configs,
+            (None, Some(configs), _) => configs.clone(),
+            (None, None, Some(doh)) => match lookup_ech_configs(doh, &self.roots, &self.domain)
+                .or(async {
+                    Timer::after(LOOKUP_TIMEOUT).await;
+                    Err(ErrorKind::TimedOut.into())
+                })
+                .await
+            {
+                Ok(configs) => {
+                    *self.cache.lock().unwrap() = Some((configs.clone(), Instant::now()));
+                    configs
+                }
+                Err(err) => {
+                    tracing::debug!(err = debug(err), domain = self.domain, "ECH lookup failed");
+                    return None;
+                }
+            },
+            (None, None, None) => return None,
+        };
+        EchConfig::new(
+            EchConfigListBytes::from(configs),
+            &[X25519_CHACHA20POLY1305],
+        )
+        .inspect_err(|err| {
+            tracing::debug!(
+                err = debug(err),
+                domain = self.domain,
+                "unusable ECH configs"
+            )
+        })
+        .ok()
+    }
+
+    async fn dial_with(
+        &self,
+        config: rustls::ClientConfig,
+        domain: &str,
+    ) -> std::io::Result<RustlsPipe<D::P>> {
+        let domain = ServerName::try_from(domain.to_string())
+            .map_err(|err| std::io::Error::new(ErrorKind::InvalidInput, err))?;
+        let stream = self.inner.dial().await?;
+        client_handshake(stream, Arc::new(config), domain).await
+    }
+
+    async fn dial_ech(&self, ech: EchConfig) -> std::io::Result<RustlsPipe<D::P>> {
+        let config = self.profile.ech_client_config(self.roots.clone(), ech)?;
+        self.dial_with(config, &self.domain).await
+    }
+}
+
+#[async_trait]
+impl<D: Dialer> Dialer for EchDialer<D> {
+    type P = RustlsPipe<D::P>;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let Some(ech) = self.ech_config().await else {
+            return match &self.fallback {
+                EchFallback::Refuse => Err(std::io::Error::new(
+                    ErrorKind::NotFound,
+                    "no ECH config for the server, and falling back is not allowed",
+                )),
+                EchFallback::Front(front) => {
+                    let config = self.profile.client_config(self.roots.clone())?;
+                    self.dial_with(config, front).await
+                }
+                EchFallback::Plain => {
+                    let config = self.profile.client_config(self.roots.clone())?;
+                    self.dial_with(config, &self.domain).await
+                }
+            };
+        };
+        match self.dial_ech(ech).await {
+            Err(err) => {
+                // a server that rejects our config because it rotated its keys sends along the new ones
+                let Some(retry) = retry_configs(&err) else {
+                    return Err(err);
+                };
+                tracing::debug!(
+                    domain = self.domain,
+                    "retrying with the server's ECH configs"
+                );
+                *self.cache.lock().unwrap() = Some((retry.clone(), Instant::now()));
+                let ech =
+                    EchConfig::new(EchConfigListBytes::from(retry), &[X25519_CHACHA20POLY1305])
+                        .map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))?;
+                self.dial_ech(ech).await
+            }
+            res => res,
+        }
+    }
+}
+
+/// Gets the ECHConfigList that a server sent along with rejecting our ECH offer, if it did.
+fn retry_configs(err: &std::io::Error) -> Option<Vec<u8>> {
+    match err.get_ref()?.downcast_ref::<rustls::Error>()? {
+        rustls::Error::PeerIncompatible(PeerIncompatible::ServerRejectedEncryptedClientHello(
+            Some(configs),
+        )) => {
+            let mut out = vec![];
+            configs.encode(&mut out);
+            Some(out)
+        }
+        _ => None,
+    }
+}
+
+/// Looks up the ECHConfigList in the HTTPS record of `domain`.
+async fn lookup_ech_configs(
+    doh: &DohServer,
+    roots: &RootCertStore,
+    domain: &str,
+) -> std::io::Result<Vec<u8>> {
+    let invalid_data = |err| std::io::Error::new(ErrorKind::InvalidData, err);
+
+    // DNS-over-HTTPS asks for an ID of zero, so that responses cache well
+    let mut query = Packet::new_query(0);
+    query.set_flags(PacketFlag::RECURSION_DESIRED);
+    query.questions.push(Question::new(
+        Name::new(domain).map_err(invalid_data)?,
+        QTYPE::TYPE(TYPE::HTTPS),
+        QCLASS::CLASS(CLASS::IN),
+        false,
+    ));
+    let query = query.build_bytes_vec().map_err(invalid_data)?;
+
+    let mut profile = ClientHelloProfile::chrome();
+    profile.alpn = vec![b"http/1.1".to_vec()];
+    let config = profile.client_config(roots.clone())?;
+    let name = ServerName::try_from(doh.name.clone())
+        .map_err(|err| std::io::Error::new(ErrorKind::InvalidInput, err))?;
+    let stream = TcpDialer {
+        dest_addr: doh.addr,
+    }
+    .dial()
+    .await?;
+    let mut pipe = client_handshake(stream, Arc::new(config), name).await?;
+    let request = format!(
+        "GET /dns-query?dns={} HTTP/1.1\r\nHost: {}\r\nAccept: application/dns-message\r\nConnection: close\r\n\r\n",
+        base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(query),
+        doh.name
+    );
+    pipe.write_all(request.as_bytes()).await?;
+    pipe.flush().await?;
+    let body = read_http_body(&mut pipe).await?;
+
+    let response = Packet::parse(&body).map_err(invalid_data)?;
+    response
+        .answers
+        .iter()
+        .filter_map(|answer| match &answer.rdata {
+            RData::HTTPS(https) => Some(https),
+            _ => None,
+        })
+        .filter(|https| https.priority > 0)
+        .min_by_key(|https| https.priority)
+        .and_then(|https| https.get_param(SVCB::ECH))
+        .map(|configs| configs.to_vec())
+        .ok_or_else(|| std::io::Error::new(ErrorKind::NotFound, "no ECH config in DNS"))
+}
+
+/// Reads the body of a successful HTTP/1.1 response, which must have a Content-Length.
+async fn read_http_body(pipe: &mut (impl Pipe + ?Sized)) -> std::io::Result<Vec<u8>> {
+    let mut header = vec![];
+    while !header.ends_with(b"\r\n\r\n") {
+        if header.len() > MAX_RESPONSE_LEN {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "HTTP header too long",
+            ));
+        }
+        let mut byte = [0u8; 1];
+        pipe.read_exact(&mut byte).await?;
+        header.push(byte[0]);
+    }
+    let header = String::from_utf8_lossy(&header);
+    let mut lines = header.lines();
+    let status = lines.next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            format!("DNS-over-HTTPS server said {status:?}"),
+        ));
+    }
+    let content_length: usize = lines
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.trim().parse().ok())
+        .filter(|len| *len <= MAX_RESPONSE_LEN)
+        .ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                "no usable Content-Length in response",
+            )
+        })?;
+    let mut body = vec![0u8; content_length];
+    pipe.read_exact(&mut body).await?;
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use async_native_tls::TlsAcceptor;
+    use rustls::pki_types::CertificateDer;
+    use sillad::{listener::Listener, tcp::TcpListener};
+    use simple_dns::ResourceRecord;
+
+    use super::*;
+    use crate::{test_util::self_signed, TlsListener};
+
+    /// Builds an ECHConfigList with one config for the given public name and key.
+    fn ech_config_list(public_name: &str, public_key: &[u8]) -> Vec<u8> {
+        let mut contents = vec![7];
+        contents.extend_from_slice(&0x0020u16.to_be_bytes());
+        contents.extend_from_slice(&(public_key.len() as u16).to_be_bytes());
+        contents.extend_from_slice(public_key);
+        contents.extend_from_slice(&4u16.to_be_bytes());
+        contents.extend_from_slice(&0x0001u16.to_be_bytes());
+        contents.extend_from_slice(&0x0003u16.to_be_bytes());
+        contents.push(0);
+        contents.push(public_name.len() as u8);
+        contents.extend_from_slice(public_name.as_bytes());
+        contents.extend_from_slice(&0u16.to_be_bytes());
+        let mut config = 0xfe0du16.to_be_bytes().to_vec();
+        config.extend_from_slice(&(contents.len() as u16).to_be_bytes());
+        config.extend_from_slice(&contents);
+        let mut list = (config.len() as u16).to_be_bytes().to_vec();
+        list.extend_from_slice(&config);
+        list
+    }
+
+    fn tls_server(name: &str) -> (RootCertStore, TlsAcceptor) {
+        let (cert, key) = self_signed(name);
+        let identity = native_tls::Identity::from_pkcs8(
+            &cert.to_pem().unwrap(),
+            &key.private_key_to_pem_pkcs8().unwrap(),
+        )
+        .unwrap();
+        let mut roots = RootCertStore::empty();
+        roots
+            .add(CertificateDer::from(cert.to_der().unwrap()))
+            .unwrap();
+        (
+            roots,
+            native_tls::TlsAcceptor::new(identity).unwrap().into(),
+        )
+    }
+
+    #[test]
+    fn test_ech_hides_server_name() {
+        futures_lite::future::block_on(async {
+            let mut listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let dest_addr = listener.local_addr().await;
+            let (public_key, _) = X25519_CHACHA20POLY1305.generate_key_pair().unwrap();
+            let dialer = EchDialer::new(
+                TcpDialer { dest_addr },
+                &ClientHelloProfile::chrome(),
+                RootCertStore::empty(),
+                "secret.example".into(),
+                EchFallback::Refuse,
+            )
+            .with_ech_configs(ech_config_list("public.example", &public_key.0));
+            let server = async {
+                let mut pipe = listener.accept().await.unwrap();
+                let mut hello = vec![0u8; 5];
+                pipe.read_exact(&mut hello).await.unwrap();
+                let len = u16::from_be_bytes([hello[3], hello[4]]) as usize;
+                hello.resize(5 + len, 0);
+                pipe.read_exact(&mut hello[5..]).await.unwrap();
+                hello
+            };
+            let client = async {
+                let _ = dialer.dial().await;
+                futures_lite::future::pending().await
+            };
+            let hello = futures_lite::future::or(server, client).await;
+            let contains = |needle: &[u8]| hello.windows(needle.len()).any(|w| w == needle);
+            assert!(contains(b"public.example"));
+            assert!(!contains(b"secret.example"));
+        })
+    }
+
+    #[test]
+    fn test_ech_fallback() {
+        futures_lite::future::block_on(async {
+            let (roots, acceptor) = tls_server("cover.example");
+            let tcp_listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let dest_addr = tcp_listener.local_addr().await;
+            let mut listener = TlsListener::new(tcp_listener, acceptor);
+            let dialer = |fallback| {
+                EchDialer::new(
+                    TcpDialer { dest_addr },
+                    &ClientHelloProfile::chrome(),
+                    roots.clone(),
+                    "secret.example".into(),
+                    fallback,
+                )
+            };
+
+            // without a config, refusing never touches the network
+            let err = dialer(EchFallback::Refuse).dial().await.err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::NotFound);
+
+            // the lookup fails since nothing serves DNS-over-HTTPS there, so we front instead
+            let dialer = dialer(EchFallback::Front("cover.example".into())).with_doh(DohServer {
+                addr: "127.0.0.1:1".parse().unwrap(),
+                name: "doh.example".into(),
+            });
+            let server = async {
+                let mut pipe = listener.accept().await.unwrap();
+                pipe.write_all(b"hello").await.unwrap();
+                pipe.flush().await.unwrap();
+                pipe
+            };
+            let client = async {
+                let mut pipe = dialer.dial().await.unwrap();
+                let mut buf = [0u8; 5];
+                pipe.read_exact(&mut buf).await.unwrap();
+                buf
+            };
+            let (_pipe, buf) = futures_lite::future::zip(server, client).await;
+            assert_eq!(&buf, b"hello");
+        })
+    }
+
+    #[test]
+    fn test_doh_lookup() {
+        futures_lite::future::block_on(async {
+            let (roots, acceptor) = tls_server("doh.example");
+            let tcp_listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let doh = DohServer {
+                addr: tcp_listener.local_addr().await,
+                name: "doh.example".into(),
+            };
+            let mut listener = TlsListener::new(tcp_listener, acceptor);
+            let configs = ech_config_list("public.example", &[1u8; 32]);
+            let server = async {
+                let mut pipe = listener.accept().await.unwrap();
+                let mut request = vec![];
+                while !request.ends_with(b"\r\n\r\n") {
+                    let mut byte = [0u8; 1];
+                    pipe.read_exact(&mut byte).await.unwrap();
+                    request.push(byte[0]);
+                }
+                let request = String::from_utf8(request).unwrap();
+                let query = request
+                    .split_once("dns=")
+                    .unwrap()
+                    .1
+                    .split_once(' ')
+                    .unwrap()
+                    .0;
+                let query = base64::engine::general_purpose::URL_SAFE_NO_PAD
+                    .decode(query)
+                    .unwrap();
+                let query = Packet::parse(&query).unwrap();
+                let question = query.questions[0].clone();
+                assert_eq!(question.qname.to_string(), "secret.example");
+
+                let mut https = SVCB::new(1, Name::new_unchecked("."));
+                https.set_param(SVCB::ECH, configs.clone()).unwrap();
+                let mut response = Packet::new_reply(0);
+                response.questions.push(question);
+                response.answers.push(ResourceRecord::new(
+                    Name::new_unchecked("secret.example"),
+                    CLASS::IN,
+                    300,
+                    RData::HTTPS(https.into()),
+                ));
+                let body = response.build_bytes_vec().unwrap();
+                let header = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/dns-message\r\nContent-Length: {}\r\n\r\n",
+                    body.len()
+                );
+                pipe.write_all(header.as_bytes()).await.unwrap();
+                pipe.write_all(&body).await.unwrap();
+                pipe.flush().await.unwrap();
+                pipe
+            };
+            let client = lookup_ech_configs(&doh, &roots, "secret.example");
+            let (_pipe, looked_up) = futures_lite::future::zip(server, client).await;
+            assert_eq!(looked_up.unwrap(), configs);
+        })
+    }
+}
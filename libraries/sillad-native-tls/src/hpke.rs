@@ -0,0 +1,312 @@
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305,
+};
+use hkdf::Hkdf;
+use rustls::{
+    crypto::hpke::{
+        EncapsulatedSecret, Hpke, HpkeOpener, HpkePrivateKey, HpkePublicKey, HpkeSealer, HpkeSuite,
+    },
+    // rustls only exposes the suite identifiers here, but every third-party HPKE provider needs them
+    internal::msgs::{
+        enums::{HpkeAead, HpkeKdf, HpkeKem},
+        handshake::HpkeSymmetricCipherSuite,
+    },
+    Error,
+};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+/// RFC 9180 HPKE with DHKEM(X25519, HKDF-SHA256), HKDF-SHA256 and ChaCha20-Poly1305, in base mode.
+///
+/// rustls only ships HPKE for its aws-lc-rs provider, so ECH with the ring provider needs this. It is the suite that ECH deployments such as Cloudflare's offer.
+pub static X25519_CHACHA20POLY1305: &dyn Hpke = &X25519ChaCha20Poly1305;
+
+#[derive(Debug)]
+struct X25519ChaCha20Poly1305;
+
+const KEM_ID: u16 = 0x0020;
+const KDF_ID: u16 = 0x0001;
+const AEAD_ID: u16 = 0x0003;
+
+fn kem_suite_id() -> Vec<u8> {
+    [&b"KEM"[..], &KEM_ID.to_be_bytes()].concat()
+}
+
+fn hpke_suite_id() -> Vec<u8> {
+    [
+        &b"HPKE"[..],
+        &KEM_ID.to_be_bytes(),
+        &KDF_ID.to_be_bytes(),
+        &AEAD_ID.to_be_bytes(),
+    ]
+    .concat()
+}
+
+fn labeled_extract(suite_id: &[u8], salt: &[u8], label: &[u8], ikm: &[u8]) -> [u8; 32] {
+    let labeled_ikm = [b"HPKE-v1", suite_id, label, ikm].concat();
+    Hkdf::<Sha256>::extract(Some(salt), &labeled_ikm).0.into()
+}
+
+fn labeled_expand(suite_id: &[u8], prk: &[u8], label: &[u8], info: &[u8], out: &mut [u8]) {
+    let labeled_info = [
+        &(out.len() as u16).to_be_bytes()[..],
+        b"HPKE-v1",
+        suite_id,
+        label,
+        info,
+    ]
+    .concat();
+    Hkdf::<Sha256>::from_prk(prk)
+        .expect("PRK is the right size")
+        .expand(&labeled_info, out)
+        .expect("output is small enough")
+}
+
+/// Turns an X25519 shared secret into the KEM's shared secret, binding in both public keys.
+fn kem_shared_secret(dh: &[u8; 32], enc: &[u8], pk_r: &[u8]) -> Result<[u8; 32], Error> {
+    // a small-order public key gives an all-zero result, which must be refused
+    if dh.iter().all(|b| *b == 0) {
+        return Err(Error::General("HPKE key agreement gave zero".into()));
+    }
+    let suite_id = kem_suite_id();
+    let eae_prk = labeled_extract(&suite_id, b"", b"eae_prk", dh);
+    let mut shared_secret = [0u8; 32];
+    labeled_expand(
+        &suite_id,
+        &eae_prk,
+        b"shared_secret",
+        &[enc, pk_r].concat(),
+        &mut shared_secret,
+    );
+    Ok(shared_secret)
+}
+
+fn key_schedule(shared_secret: &[u8; 32], info: &[u8]) -> Context {
+    let suite_id = hpke_suite_id();
+    let psk_id_hash = labeled_extract(&suite_id, b"", b"psk_id_hash", b"");
+    let info_hash = labeled_extract(&suite_id, b"", b"info_hash", info);
+    let ks_context = [&[0u8][..], &psk_id_hash, &info_hash].concat();
+    let secret = labeled_extract(&suite_id, shared_secret, b"secret", b"");
+    let mut key = [0u8; 32];
+    labeled_expand(&suite_id, &secret, b"key", &ks_context, &mut key);
+    let mut base_nonce = [0u8; 12];
+    labeled_expand(
+        &suite_id,
+        &secret,
+        b"base_nonce",
+        &ks_context,
+        &mut base_nonce,
+    );
+    Context {
+        aead: ChaCha20Poly1305::new(&key.into()),
+        base_nonce,
+        seq: 0,
+    }
+}
+
+fn to_key(bytes: &[u8]) -> Result<[u8; 32], Error> {
+    bytes
+        .try_into()
+        .map_err(|_| Error::General("HPKE key has the wrong length".into()))
+}
+
+/// Sets up a sender's context with the given ephemeral secret.
+fn setup_base_s(
+    sk_e: StaticSecret,
+    info: &[u8],
+    pub_key: &HpkePublicKey,
+) -> Result<(EncapsulatedSecret, Context), Error> {
+    let pk_r = PublicKey::from(to_key(&pub_key.0)?);
+    let enc = PublicKey::from(&sk_e).to_bytes();
+    let dh = sk_e.diffie_hellman(&pk_r).to_bytes();
+    let shared_secret = kem_shared_secret(&dh, &enc, pk_r.as_bytes())?;
+    Ok((
+        EncapsulatedSecret(enc.to_vec()),
+        key_schedule(&shared_secret, info),
+    ))
+}
+
+fn setup_base_r(
+    enc: &EncapsulatedSecret,
+    info: &[u8],
+    secret_key: &HpkePrivateKey,
+) -> Result<Context, Error> {
+    let sk_r = StaticSecret::from(to_key(secret_key.secret_bytes())?);
+    let pk_e = PublicKey::from(to_key(&enc.0)?);
+    let dh = sk_r.diffie_hellman(&pk_e).to_bytes();
+    let shared_secret = kem_shared_secret(&dh, &enc.0, PublicKey::from(&sk_r).as_bytes())?;
+    Ok(key_schedule(&shared_secret, info))
+}
+
+struct Context {
+    aead: ChaCha20Poly1305,
+    base_nonce: [u8; 12],
+    seq: u64,
+}
+
+impl std::fmt::Debug for Context {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Context").field("seq", &self.seq).finish()
+    }
+}
+
+impl Context {
+    fn next_nonce(&mut self) -> [u8; 12] {
+        let mut nonce = self.base_nonce;
+        for (n, s) in nonce[4..].iter_mut().zip(self.seq.to_be_bytes()) {
+            *n ^= s;
+        }
+        self.seq += 1;
+        nonce
+    }
+}
+
+impl HpkeSealer for Context {
+    fn seal(&mut self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = self.next_nonce();
+        self.aead
+            .encrypt(
+                &nonce.into(),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .map_err(|_| Error::EncryptError)
+    }
+}
+
+impl HpkeOpener for Context {
+    fn open(&mut self, aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+        let nonce = self.next_nonce();
+        self.aead
+            .decrypt(
+                &nonce.into(),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| Error::DecryptError)
+    }
+}
+
+impl Hpke for X25519ChaCha20Poly1305 {
+    fn seal(
+        &self,
+        info: &[u8],
+        aad: &[u8],
+        plaintext: &[u8],
+        pub_key: &HpkePublicKey,
+    ) -> Result<(EncapsulatedSecret, Vec<u8>), Error> {
+        let (enc, mut sealer) = self.setup_sealer(info, pub_key)?;
+        Ok((enc, sealer.seal(aad, plaintext)?))
+    }
+
+    fn setup_sealer(
+        &self,
+        info: &[u8],
+        pub_key: &HpkePublicKey,
+    ) -> Result<(EncapsulatedSecret, Box<dyn HpkeSealer + 'static>), Error> {
+        let sk_e = StaticSecret::random_from_rng(rand::rngs::OsRng);
+        let (enc, context) = setup_base_s(sk_e, info, pub_key)?;
+        Ok((enc, Box::new(context)))
+    }
+
+    fn open(
+        &self,
+        enc: &EncapsulatedSecret,
+        info: &[u8],
+        aad: &[u8],
+        ciphertext: &[u8],
+        secret_key: &HpkePrivateKey,
+    ) -> Result<Vec<u8>, Error> {
+        setup_base_r(enc, info, secret_key)?.open(aad, ciphertext)
+    }
+
+    fn setup_opener(
+        &self,
+        enc: &EncapsulatedSecret,
+        info: &[u8],
+        secret_key: &HpkePrivateKey,
+    ) -> Result<Box<dyn HpkeOpener + 'static>, Error> {
+        Ok(Box::new(setup_base_r(enc, info, secret_key)?))
+    }
+
+    fn generate_key_pair(&self) -> Result<(HpkePublicKey, HpkePrivateKey), Error> {
+        let secret = StaticSecret::random_from_rng(rand::rngs::OsRng);
+        Ok((
+            HpkePublicKey(PublicKey::from(&secret).to_bytes().to_vec()),
+            HpkePrivateKey::from(secret.to_bytes().to_vec()),
+        ))
+    }
+
+    fn suite(&self) -> HpkeSuite {
+        HpkeSuite {
+            kem: HpkeKem::DHKEM_X25519_HKDF_SHA256,
+            sym: HpkeSymmetricCipherSuite {
+                kdf_id: HpkeKdf::HKDF_SHA256,
+                aead_id: HpkeAead::CHACHA20_POLY_1305,
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let hpke = X25519_CHACHA20POLY1305;
+        let (pk, sk) = hpke.generate_key_pair().unwrap();
+        let (enc, mut sealer) = hpke.setup_sealer(b"info", &pk).unwrap();
+        let first = sealer.seal(b"aad", b"hello").unwrap();
+        let second = sealer.seal(b"aad", b"world").unwrap();
+        let mut opener = hpke.setup_opener(&enc, b"info", &sk).unwrap();
+        assert_eq!(opener.open(b"aad", &first).unwrap(), b"hello");
+        assert_eq!(opener.open(b"aad", &second).unwrap(), b"world");
+
+        // a different info gives different keys
+        let mut opener = hpke.setup_opener(&enc, b"other", &sk).unwrap();
+        assert!(opener.open(b"aad", &first).is_err());
+    }
+
+    /// The first encryption of RFC 9180, appendix A.2.1.
+    #[test]
+    fn test_rfc9180_vector() {
+        let sk_e = StaticSecret::from(
+            to_key(&unhex(
+                "f4ec9b33b792c372c1d2c2063507b684ef925b8c75a42dbcbf57d63ccd381600",
+            ))
+            .unwrap(),
+        );
+        let pk_r = HpkePublicKey(unhex(
+            "4310ee97d88cc1f088a5576c77ab0cf5c3ac797f3d95139c6c84b5429c59662a",
+        ));
+        let info = unhex("4f6465206f6e2061204772656369616e2055726e");
+        let (enc, mut context) = setup_base_s(sk_e, &info, &pk_r).unwrap();
+        assert_eq!(
+            enc.0,
+            unhex("1afa08d3dec047a643885163f1180476fa7ddb54c6a8029ea33f95796bf2ac4a")
+        );
+        let ciphertext = context
+            .seal(
+                &unhex("436f756e742d30"),
+                &unhex("4265617574792069732074727574682c20747275746820626561757479"),
+            )
+            .unwrap();
+        assert_eq!(
+            ciphertext,
+            unhex("1c5250d8034ec2b784ba2cfd69dbdb8af406cfe3ff938e131f0def8c8b60b4db21993c62ce81883d2dd1b51a28")
+        );
+    }
+}
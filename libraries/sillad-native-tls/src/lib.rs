@@ -1,10 +1,25 @@
 use std::pin::Pin;
 
-use async_native_tls::{TlsAcceptor, TlsConnector, TlsStream};
+use async_native_tls::{Identity, TlsAcceptor, TlsConnector, TlsStream};
 use async_trait::async_trait;
 use futures_lite::{AsyncRead, AsyncWrite};
+use rustls::pki_types::{CertificateDer, ServerName};
 
-use sillad::{dialer::Dialer, listener::Listener, Pipe};
+use sillad::{accept::AcceptQueue, dialer::Dialer, listener::Listener, Pipe};
+
+pub use sillad::accept::HandshakeLimits;
+
+mod ech;
+mod hpke;
+mod rustls_tls;
+#[cfg(test)]
+mod test_util;
+pub use ech::{DohServer, EchDialer, EchFallback};
+pub use hpke::X25519_CHACHA20POLY1305;
+pub use rustls_tls::{
+    server_config_with_client_auth, webpki_roots, ClientHelloProfile, RustlsDialer, RustlsListener,
+    RustlsPipe,
+};
 
 /// TlsPipe wraps a TLS stream to implement the Pipe trait.
 pub struct TlsPipe<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> {
@@ -54,23 +69,83 @@ impl<T: AsyncRead + AsyncWrite + Unpin + Send> Pipe for TlsPipe<T> {
     fn remote_addr(&self) -> Option<&str> {
         self.remote_addr.as_deref()
     }
+
+    // native-tls has no way to export keying material, so there is no shared secret here. RustlsPipe has one.
+}
+
+/// How a [TlsDialer] names the server, and what application protocols it offers.
+///
+/// Where to actually connect is up to the inner dialer, so together these cover setups like domain fronting, where the address connected to, the SNI value, and the name on the certificate all differ.
+#[derive(Clone, Debug)]
+pub struct TlsNames {
+    /// The server name sent in SNI, or None to send no SNI at all.
+    pub sni: Option<String>,
+    /// The name that the server's certificate must be valid for.
+    pub verify_name: String,
+    /// ALPN protocols to offer, in order of preference.
+    pub alpn: Vec<String>,
+}
+
+impl TlsNames {
+    /// Uses the same domain for SNI and certificate validation, without ALPN.
+    pub fn new(domain: String) -> Self {
+        Self {
+            sni: Some(domain.clone()),
+            verify_name: domain,
+            alpn: vec![],
+        }
+    }
+
+    fn sni_differs(&self) -> bool {
+        self.sni
+            .as_ref()
+            .is_some_and(|sni| sni != &self.verify_name)
+    }
 }
 
 /// TlsDialer wraps a Dialer to establish a TLS connection.
 pub struct TlsDialer<D: Dialer> {
     inner: D,
     connector: TlsConnector,
-    domain: String,
+    names: TlsNames,
 }
 
 impl<D: Dialer> TlsDialer<D> {
     pub fn new(inner: D, connector: TlsConnector, domain: String) -> Self {
+        Self::with_names(inner, connector, TlsNames::new(domain))
+    }
+
+    /// Like [TlsDialer::new], but with separate names for SNI and certificate validation, and an ALPN list.
+    pub fn with_names(inner: D, connector: TlsConnector, names: TlsNames) -> Self {
+        let alpn: Vec<&str> = names.alpn.iter().map(|s| s.as_str()).collect();
+        let connector = connector
+            .use_sni(names.sni.is_some())
+            .request_alpns(&alpn)
+            // native-tls checks the certificate against the SNI value, so when they differ, we check the name ourselves
+            .danger_accept_invalid_hostnames(names.sni_differs());
         Self {
             inner,
             connector,
-            domain,
+            names,
         }
     }
+
+    /// Presents a client certificate, for servers that require mutual TLS.
+    pub fn client_identity(mut self, identity: Identity) -> Self {
+        self.connector = std::mem::take(&mut self.connector).identity(identity);
+        self
+    }
+}
+
+/// Checks that a DER-encoded certificate is valid for the given name. Whether it chains up to a trusted root is left to the TLS library.
+fn verify_cert_name(cert_der: &[u8], name: &str) -> std::io::Result<()> {
+    let cert_der = CertificateDer::from(cert_der);
+    let cert = webpki::EndEntityCert::try_from(&cert_der)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?;
+    let name = ServerName::try_from(name)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidInput, err))?;
+    cert.verify_is_valid_for_subject_name(&name)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
 }
 
 #[async_trait]
@@ -83,11 +158,25 @@ where
     async fn dial(&self) -> std::io::Result<Self::P> {
         let stream = self.inner.dial().await?;
         let remote_addr = stream.remote_addr().map(|s| s.to_string());
+        let domain = self.names.sni.as_ref().unwrap_or(&self.names.verify_name);
         let tls_stream = self
             .connector
-            .connect(&self.domain, stream)
+            .connect(domain, stream)
             .await
-            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
+            .map_err(std::io::Error::other)?;
+        if self.names.sni_differs() {
+            let cert = tls_stream
+                .peer_certificate()
+                .map_err(std::io::Error::other)?
+                .ok_or_else(|| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "server sent no certificate",
+                    )
+                })?;
+            let cert_der = cert.to_der().map_err(std::io::Error::other)?;
+            verify_cert_name(&cert_der, &self.names.verify_name)?;
+        }
         Ok(TlsPipe {
             inner: tls_stream,
             remote_addr,
@@ -95,36 +184,158 @@ where
     }
 }
 
-/// TlsListener wraps a Listener to accept TLS connections.
+/// TlsListener wraps a Listener to accept TLS connections. native-tls cannot require client certificates, so mutual TLS needs a [RustlsListener] instead.
+///
+/// Handshakes run concurrently in the background, within the given [HandshakeLimits]. Connections that fail or time out their handshakes are dropped without being returned from `accept`.
 pub struct TlsListener<L: Listener> {
-    inner: L,
-    acceptor: TlsAcceptor,
+    queue: AcceptQueue<TlsPipe<L::P>>,
 }
 
 impl<L: Listener> TlsListener<L> {
     pub fn new(inner: L, acceptor: TlsAcceptor) -> Self {
-        Self { inner, acceptor }
+        Self::with_limits(inner, acceptor, HandshakeLimits::default())
+    }
+
+    /// Like [TlsListener::new], but with specific limits on handshakes.
+    pub fn with_limits(inner: L, acceptor: TlsAcceptor, limits: HandshakeLimits) -> Self {
+        let queue = AcceptQueue::spawn(inner, limits, move |stream: L::P| {
+            let acceptor = acceptor.clone();
+            async move {
+                let remote_addr = stream.remote_addr().map(|s| s.to_string());
+                let tls_stream = acceptor
+                    .accept(stream)
+                    .await
+                    .map_err(std::io::Error::other)?;
+                Ok(TlsPipe {
+                    inner: tls_stream,
+                    remote_addr,
+                })
+            }
+        });
+        Self { queue }
     }
 }
 
 #[async_trait]
-impl<L: Listener> Listener for TlsListener<L>
-where
-    L::P: AsyncRead + AsyncWrite + Unpin + Send,
-{
+impl<L: Listener> Listener for TlsListener<L> {
     type P = TlsPipe<L::P>;
 
     async fn accept(&mut self) -> std::io::Result<Self::P> {
-        let stream = self.inner.accept().await?;
-        let remote_addr = stream.remote_addr().map(|s| s.to_string());
-        let tls_stream = self
-            .acceptor
-            .accept(stream)
-            .await
-            .map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err))?;
-        Ok(TlsPipe {
-            inner: tls_stream,
-            remote_addr,
+        self.queue.accept().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use async_native_tls::Certificate;
+    use futures_lite::{AsyncReadExt, AsyncWriteExt};
+    use sillad::tcp::{TcpDialer, TcpListener};
+
+    use super::*;
+    use crate::test_util::self_signed;
+
+    #[test]
+    fn test_sni_differs_from_verify_name() {
+        futures_lite::future::block_on(async {
+            let (cert, key) = self_signed("localhost");
+            let identity = native_tls::Identity::from_pkcs8(
+                &cert.to_pem().unwrap(),
+                &key.private_key_to_pem_pkcs8().unwrap(),
+            )
+            .unwrap();
+            let tcp_listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let dest_addr = tcp_listener.local_addr().await;
+            let mut listener = TlsListener::new(
+                tcp_listener,
+                native_tls::TlsAcceptor::new(identity).unwrap().into(),
+            );
+            let dial = |names: TlsNames| {
+                let connector = TlsConnector::new()
+                    .add_root_certificate(Certificate::from_der(&cert.to_der().unwrap()).unwrap());
+                async move {
+                    TlsDialer::with_names(TcpDialer { dest_addr }, connector, names)
+                        .dial()
+                        .await
+                }
+            };
+            let server = async {
+                loop {
+                    if let Ok(mut pipe) = listener.accept().await {
+                        let _ = pipe.write_all(b"hello").await;
+                        let _ = pipe.flush().await;
+                    }
+                }
+            };
+            let client = async {
+                // fronting: a different SNI, but the certificate is still checked against the real name
+                let mut pipe = dial(TlsNames {
+                    sni: Some("example.com".into()),
+                    verify_name: "localhost".into(),
+                    alpn: vec!["h2".into()],
+                })
+                .await
+                .unwrap();
+                let mut buf = [0u8; 5];
+                pipe.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello");
+                // no SNI at all
+                dial(TlsNames {
+                    sni: None,
+                    verify_name: "localhost".into(),
+                    alpn: vec![],
+                })
+                .await
+                .unwrap();
+                // a certificate that isn't valid for the verify name gets rejected
+                assert!(dial(TlsNames {
+                    sni: Some("localhost".into()),
+                    verify_name: "example.com".into(),
+                    alpn: vec![],
+                })
+                .await
+                .is_err());
+            };
+            futures_lite::future::or(client, server).await;
+        })
+    }
+
+    #[test]
+    fn test_stalled_handshake_times_out() {
+        futures_lite::future::block_on(async {
+            let (cert, key) = self_signed("localhost");
+            let identity = native_tls::Identity::from_pkcs8(
+                &cert.to_pem().unwrap(),
+                &key.private_key_to_pem_pkcs8().unwrap(),
+            )
+            .unwrap();
+            let tcp_listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let dest_addr = tcp_listener.local_addr().await;
+            // room for just one handshake at a time
+            let mut listener = TlsListener::with_limits(
+                tcp_listener,
+                native_tls::TlsAcceptor::new(identity).unwrap().into(),
+                HandshakeLimits {
+                    timeout: Duration::from_millis(200),
+                    max_concurrent: 1,
+                },
+            );
+            // a client that connects but never says anything holds up the only slot, until it times out
+            let _stalled = TcpDialer { dest_addr }.dial().await.unwrap();
+            let start = Instant::now();
+            let connector = TlsConnector::new()
+                .add_root_certificate(Certificate::from_der(&cert.to_der().unwrap()).unwrap());
+            let dialer = TlsDialer::new(TcpDialer { dest_addr }, connector, "localhost".into());
+            let (accepted, dialed) =
+                futures_lite::future::zip(listener.accept(), dialer.dial()).await;
+            accepted.unwrap();
+            dialed.unwrap();
+            assert!(start.elapsed() >= Duration::from_millis(150));
         })
     }
 }
@@ -0,0 +1,597 @@
+use std::{
+    io::{ErrorKind, Read, Write},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use async_trait::async_trait;
+use futures_lite::{AsyncRead, AsyncWrite};
+use rustls::{
+    client::{ClientConfig, EchConfig, EchMode, WantsClientCert},
+    crypto::{
+        ring::{cipher_suite::*, default_provider, kx_group},
+        CryptoProvider, SupportedKxGroup,
+    },
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    server::{ServerConfig, WebPkiClientVerifier},
+    ClientConnection, ConfigBuilder, Connection, RootCertStore, ServerConnection,
+    SupportedCipherSuite,
+};
+use sillad::{
+    accept::{AcceptQueue, HandshakeLimits},
+    dialer::Dialer,
+    listener::Listener,
+    Pipe,
+};
+
+/// The shape of the ClientHello that a [RustlsDialer] sends.
+///
+/// rustls cannot send GREASE values or arbitrary extensions, so this only gets the parts of a browser fingerprint that rustls lets us control right: cipher suite order, key exchange groups, ALPN, SNI, and protocol versions.
+#[derive(Clone, Debug)]
+pub struct ClientHelloProfile {
+    /// Cipher suites, in the order they are offered.
+    pub cipher_suites: Vec<SupportedCipherSuite>,
+    /// Key exchange groups, in the order they are offered. The first one also gets a key share.
+    pub kx_groups: Vec<&'static dyn SupportedKxGroup>,
+    /// ALPN protocols, in the order they are offered.
+    pub alpn: Vec<Vec<u8>>,
+    /// Whether to send the server name indication.
+    pub enable_sni: bool,
+    /// Whether to offer TLS 1.2 in addition to TLS 1.3.
+    pub tls12: bool,
+}
+
+impl ClientHelloProfile {
+    /// Approximates the ClientHello of a current Chrome.
+    pub fn chrome() -> Self {
+        Self {
+            cipher_suites: vec![
+                TLS13_AES_128_GCM_SHA256,
+                TLS13_AES_256_GCM_SHA384,
+                TLS13_CHACHA20_POLY1305_SHA256,
+                TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+                TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+                TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+                TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+                TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+                TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+            ],
+            kx_groups: vec![kx_group::X25519, kx_group::SECP256R1, kx_group::SECP384R1],
+            alpn: vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            enable_sni: true,
+            tls12: true,
+        }
+    }
+
+    /// Approximates the ClientHello of a current Firefox.
+    pub fn firefox() -> Self {
+        Self {
+            cipher_suites: vec![
+                TLS13_AES_128_GCM_SHA256,
+                TLS13_CHACHA20_POLY1305_SHA256,
+                TLS13_AES_256_GCM_SHA384,
+                TLS_ECDHE_ECDSA_WITH_AES_128_GCM_SHA256,
+                TLS_ECDHE_RSA_WITH_AES_128_GCM_SHA256,
+                TLS_ECDHE_ECDSA_WITH_CHACHA20_POLY1305_SHA256,
+                TLS_ECDHE_RSA_WITH_CHACHA20_POLY1305_SHA256,
+                TLS_ECDHE_ECDSA_WITH_AES_256_GCM_SHA384,
+                TLS_ECDHE_RSA_WITH_AES_256_GCM_SHA384,
+            ],
+            kx_groups: vec![kx_group::X25519, kx_group::SECP256R1, kx_group::SECP384R1],
+            alpn: vec![b"h2".to_vec(), b"http/1.1".to_vec()],
+            enable_sni: true,
+            tls12: true,
+        }
+    }
+
+    /// Builds a rustls client config that sends this ClientHello and trusts the given roots.
+    pub fn client_config(&self, roots: RootCertStore) -> std::io::Result<ClientConfig> {
+        let config = self.config_builder(roots)?.with_no_client_auth();
+        Ok(self.finish_config(config))
+    }
+
+    /// Like [ClientHelloProfile::client_config], but also presents a client certificate, for servers that require mutual TLS.
+    pub fn client_config_with_cert(
+        &self,
+        roots: RootCertStore,
+        cert_chain: Vec<CertificateDer<'static>>,
+        key: PrivateKeyDer<'static>,
+    ) -> std::io::Result<ClientConfig> {
+        let config = self
+            .config_builder(roots)?
+            .with_client_auth_cert(cert_chain, key)
+            .map_err(|err| std::io::Error::new(ErrorKind::InvalidInput, err))?;
+        Ok(self.finish_config(config))
+    }
+
+    /// Like [ClientHelloProfile::client_config], but encrypts the real server name and the rest of the ClientHello under the given ECH config, leaving only the config's public name in the clear. ECH needs TLS 1.3, so this never offers TLS 1.2.
+    pub fn ech_client_config(
+        &self,
+        roots: RootCertStore,
+        ech: EchConfig,
+    ) -> std::io::Result<ClientConfig> {
+        let config = ClientConfig::builder_with_provider(Arc::new(self.provider()))
+            .with_ech(EchMode::Enable(ech))
+            .map_err(|err| std::io::Error::new(ErrorKind::InvalidInput, err))?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        Ok(self.finish_config(config))
+    }
+
+    fn provider(&self) -> CryptoProvider {
+        CryptoProvider {
+            cipher_suites: self.cipher_suites.clone(),
+            kx_groups: self.kx_groups.clone(),
+            ..default_provider()
+        }
+    }
+
+    fn config_builder(
+        &self,
+        roots: RootCertStore,
+    ) -> std::io::Result<ConfigBuilder<ClientConfig, WantsClientCert>> {
+        let versions: &[&rustls::SupportedProtocolVersion] = if self.tls12 {
+            &[&rustls::version::TLS13, &rustls::version::TLS12]
+        } else {
+            &[&rustls::version::TLS13]
+        };
+        Ok(
+            ClientConfig::builder_with_provider(Arc::new(self.provider()))
+                .with_protocol_versions(versions)
+                .map_err(|err| std::io::Error::new(ErrorKind::InvalidInput, err))?
+                .with_root_certificates(roots),
+        )
+    }
+
+    fn finish_config(&self, mut config: ClientConfig) -> ClientConfig {
+        config.alpn_protocols = self.alpn.clone();
+        config.enable_sni = self.enable_sni;
+        config
+    }
+}
+
+/// The web PKI roots that browsers trust.
+pub fn webpki_roots() -> RootCertStore {
+    RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    }
+}
+
+/// RustlsDialer wraps a Dialer to establish a TLS connection with rustls, which gives control over the ClientHello.
+pub struct RustlsDialer<D: Dialer> {
+    inner: D,
+    config: Arc<ClientConfig>,
+    domain: ServerName<'static>,
+}
+
+impl<D: Dialer> RustlsDialer<D> {
+    pub fn new(
+        inner: D,
+        profile: &ClientHelloProfile,
+        roots: RootCertStore,
+        domain: String,
+    ) -> std::io::Result<Self> {
+        Self::with_config(inner, profile.client_config(roots)?, domain)
+    }
+
+    /// Like [RustlsDialer::new], but with a ready-made client config, such as one from [ClientHelloProfile::client_config_with_cert].
+    pub fn with_config(inner: D, config: ClientConfig, domain: String) -> std::io::Result<Self> {
+        Ok(Self {
+            inner,
+            config: Arc::new(config),
+            domain: ServerName::try_from(domain)
+                .map_err(|err| std::io::Error::new(ErrorKind::InvalidInput, err))?,
+        })
+    }
+}
+
+#[async_trait]
+impl<D: Dialer> Dialer for RustlsDialer<D>
+where
+    D::P: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    type P = RustlsPipe<D::P>;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let stream = self.inner.dial().await?;
+        client_handshake(stream, self.config.clone(), self.domain.clone()).await
+    }
+}
+
+/// Runs a client handshake over an already-established stream.
+pub(crate) async fn client_handshake<P: Pipe>(
+    stream: P,
+    config: Arc<ClientConfig>,
+    domain: ServerName<'static>,
+) -> std::io::Result<RustlsPipe<P>> {
+    let remote_addr = stream.remote_addr().map(|s| s.to_string());
+    let conn = ClientConnection::new(config, domain)
+        .map_err(|err| std::io::Error::new(ErrorKind::InvalidInput, err))?;
+    let mut pipe = RustlsPipe {
+        io: stream,
+        conn: conn.into(),
+        remote_addr,
+        shared_secret: None,
+    };
+    futures_lite::future::poll_fn(|cx| pipe.poll_handshake(cx)).await?;
+    Ok(pipe)
+}
+
+/// Builds a server config that presents the given certificate, and only accepts clients whose certificates chain up to `client_roots`.
+pub fn server_config_with_client_auth(
+    cert_chain: Vec<CertificateDer<'static>>,
+    key: PrivateKeyDer<'static>,
+    client_roots: RootCertStore,
+) -> std::io::Result<ServerConfig> {
+    let provider = Arc::new(default_provider());
+    let verifier =
+        WebPkiClientVerifier::builder_with_provider(Arc::new(client_roots), provider.clone())
+            .build()
+            .map_err(|err| std::io::Error::new(ErrorKind::InvalidInput, err))?;
+    ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|err| std::io::Error::new(ErrorKind::InvalidInput, err))?
+        .with_client_cert_verifier(verifier)
+        .with_single_cert(cert_chain, key)
+        .map_err(|err| std::io::Error::new(ErrorKind::InvalidInput, err))
+}
+
+/// RustlsListener wraps a Listener to accept TLS connections with rustls. Unlike [crate::TlsListener], it can require clients to authenticate with certificates.
+///
+/// Handshakes run concurrently in the background, within the given [HandshakeLimits]. Connections that fail or time out their handshakes are dropped without being returned from `accept`.
+pub struct RustlsListener<L: Listener> {
+    queue: AcceptQueue<RustlsPipe<L::P>>,
+}
+
+impl<L: Listener> RustlsListener<L> {
+    pub fn new(inner: L, config: ServerConfig) -> Self {
+        Self::with_limits(inner, config, HandshakeLimits::default())
+    }
+
+    /// Like [RustlsListener::new], but with specific limits on handshakes.
+    pub fn with_limits(inner: L, config: ServerConfig, limits: HandshakeLimits) -> Self {
+        let config = Arc::new(config);
+        let queue = AcceptQueue::spawn(inner, limits, move |stream: L::P| {
+            let config = config.clone();
+            async move {
+                let remote_addr = stream.remote_addr().map(|s| s.to_string());
+                let conn = ServerConnection::new(config)
+                    .map_err(|err| std::io::Error::new(ErrorKind::InvalidInput, err))?;
+                let mut pipe = RustlsPipe {
+                    io: stream,
+                    conn: conn.into(),
+                    remote_addr,
+                    shared_secret: None,
+                };
+                futures_lite::future::poll_fn(|cx| pipe.poll_handshake(cx)).await?;
+                Ok(pipe)
+            }
+        });
+        Self { queue }
+    }
+}
+
+#[async_trait]
+impl<L: Listener> Listener for RustlsListener<L> {
+    type P = RustlsPipe<L::P>;
+
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        self.queue.accept().await
+    }
+}
+
+/// RustlsPipe is a TLS connection driven by rustls.
+pub struct RustlsPipe<T: AsyncRead + AsyncWrite + Unpin + Send + 'static> {
+    io: T,
+    conn: Connection,
+    remote_addr: Option<String>,
+    shared_secret: Option<[u8; 32]>,
+}
+
+/// The RFC 5705 exporter label for [Pipe::shared_secret].
+const SHARED_SECRET_LABEL: &[u8] = b"EXPORTER-sillad-shared-secret";
+
+/// Lets rustls do blocking-style IO on an async stream, turning Pending into WouldBlock.
+struct SyncIo<'a, 'b, T> {
+    io: &'a mut T,
+    cx: &'a mut Context<'b>,
+}
+
+impl<T: AsyncRead + Unpin> Read for SyncIo<'_, '_, T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match Pin::new(&mut *self.io).poll_read(self.cx, buf) {
+            Poll::Ready(res) => res,
+            Poll::Pending => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+impl<T: AsyncWrite + Unpin> Write for SyncIo<'_, '_, T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match Pin::new(&mut *self.io).poll_write(self.cx, buf) {
+            Poll::Ready(res) => res,
+            Poll::Pending => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match Pin::new(&mut *self.io).poll_flush(self.cx) {
+            Poll::Ready(res) => res,
+            Poll::Pending => Err(ErrorKind::WouldBlock.into()),
+        }
+    }
+}
+
+fn would_block<T>(res: std::io::Result<T>) -> Poll<std::io::Result<T>> {
+    match res {
+        Err(err) if err.kind() == ErrorKind::WouldBlock => Poll::Pending,
+        res => Poll::Ready(res),
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> RustlsPipe<T> {
+    /// The certificate chain the other side presented, if any. On the server side, this identifies a client that authenticated with mutual TLS.
+    pub fn peer_certificates(&self) -> Option<&[CertificateDer<'static>]> {
+        self.conn.peer_certificates()
+    }
+
+    /// Writes out as much pending TLS data as the lower stream takes, returning Pending only if some is left over.
+    fn poll_write_tls(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while self.conn.wants_write() {
+            let mut io = SyncIo {
+                io: &mut self.io,
+                cx,
+            };
+            futures_lite::ready!(would_block(self.conn.write_tls(&mut io)))?;
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Reads more TLS data from the lower stream and processes it, returning how many bytes were read.
+    fn poll_read_tls(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<usize>> {
+        let mut io = SyncIo {
+            io: &mut self.io,
+            cx,
+        };
+        let n = futures_lite::ready!(would_block(self.conn.read_tls(&mut io)))?;
+        if let Err(err) = self.conn.process_new_packets() {
+            // try to let the other side know what went wrong
+            let _ = self.poll_write_tls(cx);
+            return Poll::Ready(Err(std::io::Error::new(ErrorKind::InvalidData, err)));
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while self.conn.is_handshaking() {
+            futures_lite::ready!(self.poll_write_tls(cx))?;
+            if !self.conn.is_handshaking() {
+                break;
+            }
+            if futures_lite::ready!(self.poll_read_tls(cx))? == 0 {
+                return Poll::Ready(Err(ErrorKind::UnexpectedEof.into()));
+            }
+        }
+        if self.shared_secret.is_none() {
+            // this can fail on old TLS 1.2 servers, in which case there is just no shared secret
+            self.shared_secret = self
+                .conn
+                .export_keying_material([0u8; 32], SHARED_SECRET_LABEL, None)
+                .ok();
+        }
+        self.poll_write_tls(cx)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncRead for RustlsPipe<T> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut [u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            match this.conn.reader().read(buf) {
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+                res => return Poll::Ready(res),
+            }
+            // things like key updates need answers, but never hold up reading for them
+            let _ = this.poll_write_tls(cx)?;
+            futures_lite::ready!(this.poll_read_tls(cx))?;
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> AsyncWrite for RustlsPipe<T> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        // only take in more data once the previous data is on its way, so that rustls doesn't buffer without bound
+        futures_lite::ready!(this.poll_write_tls(cx))?;
+        let n = this.conn.writer().write(buf)?;
+        let _ = this.poll_write_tls(cx)?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        futures_lite::ready!(this.poll_write_tls(cx))?;
+        Pin::new(&mut this.io).poll_flush(cx)
+    }
+
+    fn poll_close(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        this.conn.send_close_notify();
+        futures_lite::ready!(this.poll_write_tls(cx))?;
+        Pin::new(&mut this.io).poll_close(cx)
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Pipe for RustlsPipe<T> {
+    fn protocol(&self) -> &str {
+        "tls"
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.remote_addr.as_deref()
+    }
+
+    /// Keying material exported from the TLS session, which both ends agree on only if the TLS connection really ends at the other side, rather than at some middlebox.
+    fn shared_secret(&self) -> Option<&[u8]> {
+        self.shared_secret.as_ref().map(|s| s.as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::{AsyncReadExt, AsyncWriteExt};
+    use rustls::pki_types::PrivatePkcs8KeyDer;
+    use sillad::tcp::{TcpDialer, TcpListener};
+
+    use super::*;
+    use crate::{test_util::self_signed, TlsListener};
+
+    #[test]
+    fn test_rustls_dialer_round_trip() {
+        futures_lite::future::block_on(async {
+            let (cert, key) = self_signed("localhost");
+            let identity = native_tls::Identity::from_pkcs8(
+                &cert.to_pem().unwrap(),
+                &key.private_key_to_pem_pkcs8().unwrap(),
+            )
+            .unwrap();
+            let acceptor = native_tls::TlsAcceptor::new(identity).unwrap();
+            let tcp_listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let dest_addr = tcp_listener.local_addr().await;
+            let mut listener = TlsListener::new(tcp_listener, acceptor.into());
+
+            let mut roots = RootCertStore::empty();
+            roots
+                .add(CertificateDer::from(cert.to_der().unwrap()))
+                .unwrap();
+            for profile in [ClientHelloProfile::chrome(), ClientHelloProfile::firefox()] {
+                let dialer = RustlsDialer::new(
+                    TcpDialer { dest_addr },
+                    &profile,
+                    roots.clone(),
+                    "localhost".into(),
+                )
+                .unwrap();
+                let server = async {
+                    let mut pipe = listener.accept().await.unwrap();
+                    let mut buf = [0u8; 5];
+                    pipe.read_exact(&mut buf).await.unwrap();
+                    pipe.write_all(&buf).await.unwrap();
+                    pipe.flush().await.unwrap();
+                };
+                let client = async {
+                    let mut pipe = dialer.dial().await.unwrap();
+                    pipe.write_all(b"hello").await.unwrap();
+                    pipe.flush().await.unwrap();
+                    let mut buf = [0u8; 5];
+                    pipe.read_exact(&mut buf).await.unwrap();
+                    assert_eq!(&buf, b"hello");
+                };
+                futures_lite::future::zip(server, client).await;
+            }
+        })
+    }
+
+    #[test]
+    fn test_mutual_tls() {
+        futures_lite::future::block_on(async {
+            let (server_cert, server_key) = self_signed("localhost");
+            let (client_cert, client_key) = self_signed("client");
+            let der = |cert: &openssl::x509::X509| CertificateDer::from(cert.to_der().unwrap());
+            let key_der = |key: &openssl::pkey::PKey<openssl::pkey::Private>| {
+                PrivateKeyDer::from(PrivatePkcs8KeyDer::from(
+                    key.private_key_to_pkcs8().unwrap(),
+                ))
+            };
+            let mut server_roots = RootCertStore::empty();
+            server_roots.add(der(&server_cert)).unwrap();
+            let mut client_roots = RootCertStore::empty();
+            client_roots.add(der(&client_cert)).unwrap();
+
+            let tcp_listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let dest_addr = tcp_listener.local_addr().await;
+            let mut listener = RustlsListener::new(
+                tcp_listener,
+                server_config_with_client_auth(
+                    vec![der(&server_cert)],
+                    key_der(&server_key),
+                    client_roots,
+                )
+                .unwrap(),
+            );
+            let profile = ClientHelloProfile::chrome();
+
+            // a client with the right certificate gets through, and the server can see who it is
+            let dialer = RustlsDialer::with_config(
+                TcpDialer { dest_addr },
+                profile
+                    .client_config_with_cert(
+                        server_roots.clone(),
+                        vec![der(&client_cert)],
+                        key_der(&client_key),
+                    )
+                    .unwrap(),
+                "localhost".into(),
+            )
+            .unwrap();
+            let server = async {
+                let mut pipe = listener.accept().await.unwrap();
+                assert_eq!(pipe.peer_certificates().unwrap()[0], der(&client_cert));
+                pipe.write_all(b"hello").await.unwrap();
+                pipe.flush().await.unwrap();
+                pipe
+            };
+            let client = async {
+                let mut pipe = dialer.dial().await.unwrap();
+                let mut buf = [0u8; 5];
+                pipe.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello");
+                pipe
+            };
+            let (server_pipe, client_pipe) = futures_lite::future::zip(server, client).await;
+            // both ends export the same keying material
+            assert!(client_pipe.shared_secret().is_some());
+            assert_eq!(client_pipe.shared_secret(), server_pipe.shared_secret());
+
+            // a client without a certificate is turned away
+            let dialer = RustlsDialer::new(
+                TcpDialer { dest_addr },
+                &profile,
+                server_roots,
+                "localhost".into(),
+            )
+            .unwrap();
+            let server = async {
+                let _ = listener.accept().await;
+                panic!("accepted a client without a certificate");
+            };
+            let client = async {
+                // with TLS 1.3, the client only finds out when it next reads
+                if let Ok(mut pipe) = dialer.dial().await {
+                    let mut buf = [0u8; 1];
+                    assert!(!matches!(pipe.read(&mut buf).await, Ok(1)));
+                }
+            };
+            futures_lite::future::or(client, server).await;
+        })
+    }
+}
@@ -0,0 +1,407 @@
+//! Drives external Pluggable Transports binaries, such as obfs4proxy, lyrebird or snowflake-client, so that the transports of the wider circumvention ecosystem can be used without porting them. We speak the managed side of the PT 2.1 spec: the binary is configured through environment variables, reports where it listens on stdout, and takes client connections through SOCKS5.
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use async_trait::async_trait;
+use futures_lite::{AsyncRead, AsyncWrite};
+use managed::PtProcess;
+use sillad::{
+    dialer::Dialer,
+    listener::Listener,
+    tcp::{TcpDialer, TcpListener, TcpPipe},
+    Pipe,
+};
+
+mod managed;
+mod socks;
+
+/// How to start a PT binary.
+#[derive(Clone, Debug)]
+pub struct PtCommand {
+    program: PathBuf,
+    args: Vec<String>,
+    state_dir: Option<PathBuf>,
+}
+
+impl PtCommand {
+    pub fn new(program: impl Into<PathBuf>) -> Self {
+        Self {
+            program: program.into(),
+            args: vec![],
+            state_dir: None,
+        }
+    }
+
+    /// Passes command-line arguments to the binary.
+    pub fn with_args(mut self, args: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        self.args = args.into_iter().map(|a| a.into()).collect();
+        self
+    }
+
+    /// Sets where the binary keeps its state, such as obfs4's server keys. Defaults to a directory under the system's temporary directory, which servers should override so that their keys survive reboots.
+    pub fn with_state_dir(mut self, state_dir: impl Into<PathBuf>) -> Self {
+        self.state_dir = Some(state_dir.into());
+        self
+    }
+}
+
+/// A PT binary running in client mode, which can be shared by any number of [PtDialer]s.
+#[derive(Clone)]
+pub struct PtClient {
+    transport: String,
+    socks_addr: SocketAddr,
+    _process: Arc<PtProcess>,
+}
+
+impl PtClient {
+    /// Starts the binary and waits until it is ready to take connections for the given transport.
+    pub async fn launch(command: &PtCommand, transport: &str) -> std::io::Result<Self> {
+        let (process, methods) = managed::launch(
+            command,
+            &[("TOR_PT_CLIENT_TRANSPORTS", transport.to_string())],
+            "CMETHOD",
+        )
+        .await?;
+        let socks_addr = methods
+            .iter()
+            .find_map(|method| match method.as_slice() {
+                [name, proto, addr, ..] if name == transport && proto == "socks5" => {
+                    addr.parse().ok()
+                }
+                _ => None,
+            })
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!("PT does not offer {transport} over SOCKS5"),
+                )
+            })?;
+        Ok(Self {
+            transport: transport.to_string(),
+            socks_addr,
+            _process: Arc::new(process),
+        })
+    }
+
+    /// Creates a dialer that reaches a bridge at `dest`, given the arguments the bridge advertises (such as obfs4's `cert` and `iat-mode`).
+    pub fn dialer(&self, dest: SocketAddr, args: BTreeMap<String, String>) -> PtDialer {
+        PtDialer {
+            client: self.clone(),
+            dest,
+            args,
+        }
+    }
+}
+
+/// A dialer that connects through a PT running in client mode.
+pub struct PtDialer {
+    client: PtClient,
+    dest: SocketAddr,
+    args: BTreeMap<String, String>,
+}
+
+#[async_trait]
+impl Dialer for PtDialer {
+    type P = PtPipe;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let mut inner = TcpDialer {
+            dest_addr: self.client.socks_addr,
+        }
+        .dial()
+        .await?;
+        socks::socks5_connect(&mut inner, self.dest, &self.args).await?;
+        Ok(PtPipe {
+            inner,
+            protocol: self.client.transport.clone(),
+            remote_addr: Some(self.dest.to_string()),
+        })
+    }
+}
+
+/// A listener that accepts connections through a PT running in server mode. The PT hands each deobfuscated connection to a loopback port, without Extended ORPort authentication, so this should only run on hosts without untrusted local users.
+pub struct PtListener {
+    transport: String,
+    listen_addr: SocketAddr,
+    args: BTreeMap<String, String>,
+    inner: TcpListener,
+    _process: PtProcess,
+}
+
+impl PtListener {
+    /// Starts the binary so that it listens for the given transport at `bind_addr`, passing it transport-specific options.
+    pub async fn launch(
+        command: &PtCommand,
+        transport: &str,
+        bind_addr: SocketAddr,
+        options: BTreeMap<String, String>,
+    ) -> std::io::Result<Self> {
+        let inner = TcpListener::bind("127.0.0.1:0".parse().unwrap()).await?;
+        let orport = inner.local_addr().await;
+        let options = options
+            .iter()
+            .map(|(k, v)| {
+                let special = [':', ';', '='];
+                format!(
+                    "{transport}:{}={}",
+                    managed::escape(k, &special),
+                    managed::escape(v, &special)
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(";");
+        let (process, methods) = managed::launch(
+            command,
+            &[
+                ("TOR_PT_SERVER_TRANSPORTS", transport.to_string()),
+                ("TOR_PT_SERVER_BINDADDR", format!("{transport}-{bind_addr}")),
+                ("TOR_PT_ORPORT", orport.to_string()),
+                ("TOR_PT_SERVER_TRANSPORT_OPTIONS", options),
+            ],
+            "SMETHOD",
+        )
+        .await?;
+        let (listen_addr, args) = methods
+            .iter()
+            .find_map(|method| match method.as_slice() {
+                [name, addr, options @ ..] if name == transport => {
+                    let args = options
+                        .iter()
+                        .find_map(|opt| opt.strip_prefix("ARGS:"))
+                        .map(managed::parse_args)
+                        .unwrap_or_default();
+                    Some((addr.parse().ok()?, args))
+                }
+                _ => None,
+            })
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!("PT does not offer {transport}"),
+                )
+            })?;
+        Ok(Self {
+            transport: transport.to_string(),
+            listen_addr,
+            args,
+            inner,
+            _process: process,
+        })
+    }
+
+    /// Get the address where the PT listens for clients.
+    pub fn listen_addr(&self) -> SocketAddr {
+        self.listen_addr
+    }
+
+    /// The arguments that clients need to connect, which should be passed to [PtClient::dialer].
+    pub fn args(&self) -> &BTreeMap<String, String> {
+        &self.args
+    }
+}
+
+#[async_trait]
+impl Listener for PtListener {
+    type P = PtPipe;
+
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        let inner = self.inner.accept().await?;
+        Ok(PtPipe {
+            inner,
+            protocol: self.transport.clone(),
+            // the PT is the one we see connecting, so we don't know who the client really is
+            remote_addr: None,
+        })
+    }
+}
+
+/// A connection carried by a PT.
+pub struct PtPipe {
+    inner: TcpPipe,
+    protocol: String,
+    remote_addr: Option<String>,
+}
+
+impl AsyncRead for PtPipe {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for PtPipe {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+impl Pipe for PtPipe {
+    fn protocol(&self) -> &str {
+        &self.protocol
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.remote_addr.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    /// A stand-in for a real PT, which checks the connection arguments but otherwise relays bytes unchanged.
+    const FAKE_PT: &str = r#"
+import os, socket, sys, threading
+
+def relay(a, b):
+    def pump(src, dst):
+        try:
+            while data := src.recv(65536):
+                dst.sendall(data)
+            dst.shutdown(socket.SHUT_WR)
+        except OSError:
+            for s in (src, dst):
+                try:
+                    s.shutdown(socket.SHUT_RDWR)
+                except OSError:
+                    pass
+    threading.Thread(target=pump, args=(a, b), daemon=True).start()
+    threading.Thread(target=pump, args=(b, a), daemon=True).start()
+
+def recv_exact(conn, n):
+    data = b""
+    while len(data) < n:
+        data += conn.recv(n - len(data))
+    return data
+
+def socks(conn):
+    recv_exact(conn, recv_exact(conn, 2)[1])
+    conn.sendall(b"\x05\x02")
+    ulen = recv_exact(conn, 2)[1]
+    args = recv_exact(conn, ulen)
+    recv_exact(conn, recv_exact(conn, 1)[0])
+    conn.sendall(b"\x01\x00")
+    recv_exact(conn, 4)
+    host = socket.inet_ntoa(recv_exact(conn, 4))
+    port = int.from_bytes(recv_exact(conn, 2), "big")
+    upstream = socket.create_connection((host, port))
+    conn.sendall(b"\x05\x00\x00\x01" + bytes(6))
+    upstream.sendall(args + b"\n")
+    relay(conn, upstream)
+
+def server(conn, expected):
+    line = b""
+    while not line.endswith(b"\n"):
+        line += conn.recv(1)
+    if line.strip() != expected:
+        conn.close()
+        return
+    host, port = os.environ["TOR_PT_ORPORT"].split(":")
+    relay(conn, socket.create_connection((host, int(port))))
+
+listener = socket.socket()
+if "TOR_PT_CLIENT_TRANSPORTS" in os.environ:
+    listener.bind(("127.0.0.1", 0))
+    listener.listen()
+    print("VERSION 1")
+    print("CMETHOD fake socks5 127.0.0.1:%d" % listener.getsockname()[1])
+    print("CMETHODS DONE", flush=True)
+    handler = lambda conn: socks(conn)
+else:
+    host, port = os.environ["TOR_PT_SERVER_BINDADDR"].split("-", 1)[1].split(":")
+    listener.bind((host, int(port)))
+    listener.listen()
+    secret = os.environ["TOR_PT_SERVER_TRANSPORT_OPTIONS"].split("=", 1)[1]
+    print("VERSION 1")
+    print("SMETHOD fake 127.0.0.1:%d ARGS:secret=%s" % (listener.getsockname()[1], secret))
+    print("SMETHODS DONE", flush=True)
+    expected = ("secret=" + secret).encode()
+    handler = lambda conn: server(conn, expected)
+threading.Thread(target=lambda: (sys.stdin.read(), os._exit(0)), daemon=True).start()
+while True:
+    conn, _ = listener.accept()
+    threading.Thread(target=handler, args=(conn,), daemon=True).start()
+"#;
+
+    #[test]
+    fn test_fake_pt() {
+        smolscale::block_on(async {
+            let script = std::env::temp_dir().join(format!("fake-pt-{}.py", std::process::id()));
+            std::fs::write(&script, FAKE_PT).unwrap();
+            let command = PtCommand::new("python3").with_args([script.to_str().unwrap()]);
+
+            let options = [("secret".to_string(), "hunter2".to_string())]
+                .into_iter()
+                .collect();
+            let mut listener =
+                match PtListener::launch(&command, "fake", "127.0.0.1:0".parse().unwrap(), options)
+                    .await
+                {
+                    Ok(listener) => listener,
+                    Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                        eprintln!("skipping, since there is no python3 to run the fake PT");
+                        return;
+                    }
+                    Err(err) => panic!("{err}"),
+                };
+            assert_eq!(listener.args()["secret"], "hunter2");
+
+            let pt_client = PtClient::launch(&command, "fake").await.unwrap();
+            let dialer = pt_client.dialer(listener.listen_addr(), listener.args().clone());
+            let server = async {
+                let mut pipe = listener.accept().await.unwrap();
+                assert_eq!(pipe.protocol(), "fake");
+                let mut buf = [0u8; 5];
+                pipe.read_exact(&mut buf).await.unwrap();
+                pipe.write_all(&buf).await.unwrap();
+            };
+            let client = async {
+                let mut pipe = dialer.dial().await.unwrap();
+                pipe.write_all(b"hello").await.unwrap();
+                let mut buf = [0u8; 5];
+                pipe.read_exact(&mut buf).await.unwrap();
+                buf
+            };
+            let ((), echoed) = futures_lite::future::zip(server, client).await;
+            assert_eq!(&echoed, b"hello");
+
+            // a client with the wrong arguments gets nowhere
+            let mut args = listener.args().clone();
+            args.insert("secret".into(), "wrong".into());
+            let mut pipe = pt_client
+                .dialer(listener.listen_addr(), args)
+                .dial()
+                .await
+                .unwrap();
+            let mut buf = vec![];
+            let _ = pipe.write_all(b"hello").await;
+            let _ = pipe.read_to_end(&mut buf).await;
+            assert!(buf.is_empty());
+            std::fs::remove_file(script).unwrap();
+        });
+    }
+}
@@ -0,0 +1,168 @@
+use std::{collections::BTreeMap, process::Stdio, time::Duration};
+
+use async_io::Timer;
+use async_process::{Child, ChildStdin, ChildStdout, Command};
+use futures_lite::{io::BufReader, AsyncBufReadExt, FutureExt};
+
+use crate::PtCommand;
+
+/// How long a PT gets to report its methods before we give up on it.
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A running PT. Dropping it kills the process.
+pub struct PtProcess {
+    _child: Child,
+    // the PT exits by itself once its stdin closes, in case we die without getting to kill it
+    _stdin: ChildStdin,
+    _drain: async_task::Task<()>,
+}
+
+/// Starts a PT with the given environment and waits for it to report its methods, returning the arguments of each line that starts with `keyword` (CMETHOD or SMETHOD).
+pub async fn launch(
+    command: &PtCommand,
+    env: &[(&str, String)],
+    keyword: &str,
+) -> std::io::Result<(PtProcess, Vec<Vec<String>>)> {
+    let state_dir = command
+        .state_dir
+        .clone()
+        .unwrap_or_else(|| std::env::temp_dir().join("sillad-pt"));
+    let mut child = Command::new(&command.program)
+        .args(&command.args)
+        .env("TOR_PT_MANAGED_TRANSPORT_VER", "1")
+        .env("TOR_PT_STATE_LOCATION", state_dir)
+        .env("TOR_PT_EXIT_ON_STDIN_CLOSE", "1")
+        .envs(env.iter().map(|(k, v)| (k, v)))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+
+    let methods = read_methods(&mut stdout, keyword)
+        .or(async {
+            Timer::after(STARTUP_TIMEOUT).await;
+            Err(std::io::Error::new(
+                std::io::ErrorKind::TimedOut,
+                "PT did not report its methods in time",
+            ))
+        })
+        .await?;
+    // the PT blocks if nobody reads what it prints, so keep reading for as long as it runs
+    let _drain = smolscale::spawn(async move {
+        let mut lines = stdout.lines();
+        while let Some(Ok(line)) = futures_lite::StreamExt::next(&mut lines).await {
+            tracing::debug!(line, "PT says");
+        }
+    });
+    Ok((
+        PtProcess {
+            _child: child,
+            _stdin: stdin,
+            _drain,
+        },
+        methods,
+    ))
+}
+
+async fn read_methods(
+    stdout: &mut BufReader<ChildStdout>,
+    keyword: &str,
+) -> std::io::Result<Vec<Vec<String>>> {
+    let error_keyword = format!("{keyword}-ERROR");
+    let done_keyword = format!("{keyword}S");
+    let mut methods = vec![];
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if stdout.read_line(&mut line).await? == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "PT exited before reporting its methods",
+            ));
+        }
+        tracing::debug!(line = line.trim_end(), "PT says");
+        let mut words = line.split_whitespace();
+        let Some(first) = words.next() else {
+            continue;
+        };
+        let rest: Vec<String> = words.map(|s| s.to_string()).collect();
+        match first {
+            "VERSION-ERROR" | "ENV-ERROR" => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("PT refused to start: {}", rest.join(" ")),
+                ))
+            }
+            first if first == error_keyword => {
+                return Err(std::io::Error::other(format!(
+                    "PT could not start a method: {}",
+                    rest.join(" ")
+                )))
+            }
+            first if first == keyword => methods.push(rest),
+            first if first == done_keyword => return Ok(methods),
+            _ => {}
+        }
+    }
+}
+
+/// Backslash-escapes backslashes and the given special characters.
+pub fn escape(s: &str, special: &[char]) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        if c == '\\' || special.contains(&c) {
+            out.push('\\');
+        }
+        out.push(c);
+    }
+    out
+}
+
+/// Parses the `k=v,k=v` list that a server reports with `ARGS:`, where commas and equals signs may be backslash-escaped.
+pub fn parse_args(s: &str) -> BTreeMap<String, String> {
+    let mut args = BTreeMap::new();
+    let mut key = String::new();
+    let mut value = String::new();
+    let mut in_value = false;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => chars.next().unwrap_or('\\'),
+            '=' if !in_value => {
+                in_value = true;
+                continue;
+            }
+            ',' => {
+                args.insert(std::mem::take(&mut key), std::mem::take(&mut value));
+                in_value = false;
+                continue;
+            }
+            c => c,
+        };
+        if in_value {
+            value.push(c);
+        } else {
+            key.push(c);
+        }
+    }
+    if !key.is_empty() {
+        args.insert(key, value);
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_args() {
+        let args = parse_args("cert=abc\\,def\\=,iat-mode=0");
+        assert_eq!(args["cert"], "abc,def=");
+        assert_eq!(args["iat-mode"], "0");
+        assert_eq!(args.len(), 2);
+        assert!(parse_args("").is_empty());
+    }
+}
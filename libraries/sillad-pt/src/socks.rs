@@ -0,0 +1,128 @@
+use std::{collections::BTreeMap, net::SocketAddr};
+
+use futures_lite::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::managed::escape;
+
+/// Asks a PT's SOCKS5 proxy to connect to `dest`, handing it the per-connection arguments the way the PT spec prescribes: packed into the username and password.
+pub async fn socks5_connect(
+    mut conn: impl AsyncRead + AsyncWrite + Unpin,
+    dest: SocketAddr,
+    args: &BTreeMap<String, String>,
+) -> std::io::Result<()> {
+    let (username, password) = encode_args(args);
+    if username.is_empty() {
+        conn.write_all(&[5, 1, 0]).await?;
+    } else {
+        conn.write_all(&[5, 1, 2]).await?;
+    }
+    let mut reply = [0u8; 2];
+    conn.read_exact(&mut reply).await?;
+    match reply {
+        [5, 0] if username.is_empty() => {}
+        [5, 2] if !username.is_empty() => {
+            let mut auth = vec![1, username.len() as u8];
+            auth.extend_from_slice(&username);
+            auth.push(password.len() as u8);
+            auth.extend_from_slice(&password);
+            conn.write_all(&auth).await?;
+            conn.read_exact(&mut reply).await?;
+            if reply[1] != 0 {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::PermissionDenied,
+                    "PT rejected the connection arguments",
+                ));
+            }
+        }
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "PT offered an unexpected SOCKS5 auth method",
+            ))
+        }
+    }
+
+    let mut request = vec![5, 1, 0];
+    match dest {
+        SocketAddr::V4(addr) => {
+            request.push(1);
+            request.extend_from_slice(&addr.ip().octets());
+        }
+        SocketAddr::V6(addr) => {
+            request.push(4);
+            request.extend_from_slice(&addr.ip().octets());
+        }
+    }
+    request.extend_from_slice(&dest.port().to_be_bytes());
+    conn.write_all(&request).await?;
+
+    let mut header = [0u8; 4];
+    conn.read_exact(&mut header).await?;
+    if header[1] != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionRefused,
+            format!("PT could not connect, SOCKS5 error {}", header[1]),
+        ));
+    }
+    // the bound address is meaningless to us, but we still need to skip over it
+    let addr_len = match header[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0u8; 1];
+            conn.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        _ => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "bad address type in SOCKS5 reply",
+            ))
+        }
+    };
+    let mut rest = vec![0u8; addr_len + 2];
+    conn.read_exact(&mut rest).await?;
+    Ok(())
+}
+
+/// Splits the arguments across the username and password, which can hold 255 bytes each. A password can't be empty, so short arguments get a lone NUL for one.
+fn encode_args(args: &BTreeMap<String, String>) -> (Vec<u8>, Vec<u8>) {
+    let encoded = args
+        .iter()
+        .map(|(k, v)| format!("{}={}", escape(k, &['=', ';']), escape(v, &['=', ';'])))
+        .collect::<Vec<_>>()
+        .join(";")
+        .into_bytes();
+    if encoded.is_empty() {
+        (vec![], vec![])
+    } else if encoded.len() <= 255 {
+        (encoded, vec![0])
+    } else {
+        (encoded[..255].to_vec(), encoded[255..].to_vec())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_args() {
+        let args: BTreeMap<String, String> = [
+            ("cert".to_string(), "a;b=c".to_string()),
+            ("iat-mode".to_string(), "0".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            encode_args(&args),
+            (b"cert=a\\;b\\=c;iat-mode=0".to_vec(), vec![0])
+        );
+
+        let long: BTreeMap<String, String> =
+            [("k".to_string(), "v".repeat(300))].into_iter().collect();
+        let (username, password) = encode_args(&long);
+        assert_eq!(username.len(), 255);
+        assert_eq!(password.len(), 302 - 255);
+    }
+}
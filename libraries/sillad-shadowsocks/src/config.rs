@@ -0,0 +1,223 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+
+use base64::Engine as _;
+
+/// The only method we speak. The AES-GCM flavors of Shadowsocks 2022 are not supported.
+pub const METHOD: &str = "2022-blake3-chacha20-poly1305";
+
+/// The pre-shared key and method of a Shadowsocks 2022 server.
+#[derive(Clone)]
+pub struct SsConfig {
+    pub(crate) psk: [u8; 32],
+}
+
+impl SsConfig {
+    /// Creates a config from the base64 pre-shared key, which is what Shadowsocks 2022 uses as its "password".
+    pub fn new(password: &str) -> std::io::Result<Self> {
+        let psk = base64::engine::general_purpose::STANDARD
+            .decode(password.trim())
+            .map_err(invalid_input)?;
+        let psk = psk
+            .try_into()
+            .map_err(|_| invalid_input("pre-shared key must be 32 bytes"))?;
+        Ok(Self { psk })
+    }
+}
+
+/// A parsed `ss://` URI, in the SIP002 format.
+#[derive(Clone)]
+pub struct SsUri {
+    pub config: SsConfig,
+    /// The server, as host:port.
+    pub server: String,
+    pub tag: Option<String>,
+}
+
+impl FromStr for SsUri {
+    type Err = std::io::Error;
+
+    fn from_str(uri: &str) -> Result<Self, Self::Err> {
+        let rest = uri
+            .strip_prefix("ss://")
+            .ok_or_else(|| invalid_input("not an ss:// URI"))?;
+        let (rest, tag) = match rest.split_once('#') {
+            Some((rest, tag)) => (rest, Some(percent_decode(tag)?)),
+            None => (rest, None),
+        };
+        // plugins are not supported, so anything after the server is ignored
+        let rest = rest.split(['/', '?']).next().unwrap_or_default();
+        let (userinfo, server) = rest
+            .rsplit_once('@')
+            .ok_or_else(|| invalid_input("ss:// URI has no userinfo"))?;
+        // 2022 methods percent-encode "method:password", while older clients base64 it
+        let userinfo = match percent_decode(userinfo)? {
+            plain if plain.contains(':') => plain,
+            encoded => {
+                let encoded = encoded.trim_end_matches('=');
+                let decoded = base64::engine::general_purpose::URL_SAFE_NO_PAD
+                    .decode(encoded)
+                    .or_else(|_| base64::engine::general_purpose::STANDARD_NO_PAD.decode(encoded))
+                    .map_err(invalid_input)?;
+                String::from_utf8(decoded).map_err(invalid_input)?
+            }
+        };
+        let (method, password) = userinfo
+            .split_once(':')
+            .ok_or_else(|| invalid_input("ss:// userinfo has no method"))?;
+        if method != METHOD {
+            return Err(invalid_input(format!("unsupported method {method}")));
+        }
+        if server.rsplit_once(':').is_none() {
+            return Err(invalid_input("ss:// server has no port"));
+        }
+        Ok(Self {
+            config: SsConfig::new(password)?,
+            server: server.to_string(),
+            tag,
+        })
+    }
+}
+
+/// The address that a client asks the server to connect to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SsTarget {
+    Ip(SocketAddr),
+    Domain(String, u16),
+}
+
+impl SsTarget {
+    /// Appends the SOCKS-style encoding of the address.
+    pub(crate) fn encode(&self, out: &mut Vec<u8>) {
+        match self {
+            SsTarget::Ip(SocketAddr::V4(addr)) => {
+                out.push(1);
+                out.extend_from_slice(&addr.ip().octets());
+            }
+            SsTarget::Ip(SocketAddr::V6(addr)) => {
+                out.push(4);
+                out.extend_from_slice(&addr.ip().octets());
+            }
+            SsTarget::Domain(domain, _) => {
+                out.push(3);
+                out.push(domain.len().min(255) as u8);
+                out.extend_from_slice(&domain.as_bytes()[..domain.len().min(255)]);
+            }
+        }
+        let port = match self {
+            SsTarget::Ip(addr) => addr.port(),
+            SsTarget::Domain(_, port) => *port,
+        };
+        out.extend_from_slice(&port.to_be_bytes());
+    }
+
+    /// Parses an encoded address from the front of `buf`, returning it and its length.
+    pub(crate) fn decode(buf: &[u8]) -> Option<(Self, usize)> {
+        let (target, len) = match *buf.first()? {
+            1 => {
+                let ip: [u8; 4] = buf.get(1..5)?.try_into().ok()?;
+                (SsTarget::Ip(SocketAddr::new(IpAddr::from(ip), 0)), 5)
+            }
+            4 => {
+                let ip: [u8; 16] = buf.get(1..17)?.try_into().ok()?;
+                (SsTarget::Ip(SocketAddr::new(IpAddr::from(ip), 0)), 17)
+            }
+            3 => {
+                let len = *buf.get(1)? as usize;
+                let domain = std::str::from_utf8(buf.get(2..2 + len)?).ok()?;
+                (SsTarget::Domain(domain.to_string(), 0), 2 + len)
+            }
+            _ => return None,
+        };
+        let port = u16::from_be_bytes(buf.get(len..len + 2)?.try_into().ok()?);
+        let target = match target {
+            SsTarget::Ip(addr) => SsTarget::Ip(SocketAddr::new(addr.ip(), port)),
+            SsTarget::Domain(domain, _) => SsTarget::Domain(domain, port),
+        };
+        Some((target, len + 2))
+    }
+}
+
+impl FromStr for SsTarget {
+    type Err = std::io::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(addr) = s.parse() {
+            return Ok(SsTarget::Ip(addr));
+        }
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| invalid_input("target has no port"))?;
+        Ok(SsTarget::Domain(
+            host.to_string(),
+            port.parse().map_err(invalid_input)?,
+        ))
+    }
+}
+
+fn percent_decode(s: &str) -> std::io::Result<String> {
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [
+                bytes.next().unwrap_or_default(),
+                bytes.next().unwrap_or_default(),
+            ];
+            let hex = std::str::from_utf8(&hex).map_err(invalid_input)?;
+            out.push(u8::from_str_radix(hex, 16).map_err(invalid_input)?);
+        } else {
+            out.push(b);
+        }
+    }
+    String::from_utf8(out).map_err(invalid_input)
+}
+
+fn invalid_input(err: impl ToString) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PSK: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    #[test]
+    fn test_parse_uris() {
+        let plain: SsUri = format!(
+            "ss://2022-blake3-chacha20-poly1305:{}@example.com:8388/?plugin=#My%20Server",
+            PSK.replace('=', "%3D")
+        )
+        .parse()
+        .unwrap();
+        assert_eq!(plain.server, "example.com:8388");
+        assert_eq!(plain.tag.as_deref(), Some("My Server"));
+        assert_eq!(plain.config.psk[31], 31);
+
+        let encoded = base64::engine::general_purpose::URL_SAFE_NO_PAD
+            .encode(format!("2022-blake3-chacha20-poly1305:{PSK}"));
+        let legacy: SsUri = format!("ss://{encoded}@[::1]:8388").parse().unwrap();
+        assert_eq!(legacy.server, "[::1]:8388");
+        assert_eq!(legacy.config.psk, plain.config.psk);
+
+        assert!("ss://aes-256-gcm:pass@example.com:8388"
+            .parse::<SsUri>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_target_round_trip() {
+        for target in [
+            "1.2.3.4:80".parse::<SsTarget>().unwrap(),
+            "[::1]:443".parse().unwrap(),
+            "example.com:8080".parse().unwrap(),
+        ] {
+            let mut buf = vec![];
+            target.encode(&mut buf);
+            assert_eq!(SsTarget::decode(&buf), Some((target, buf.len())));
+        }
+    }
+}
@@ -0,0 +1,45 @@
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, Key, KeyInit, Nonce};
+
+pub const SALT_LEN: usize = 32;
+pub const TAG_LEN: usize = 16;
+
+/// One direction of a session: an AEAD keyed by the session subkey, with a nonce that counts up from zero.
+pub struct Cipher {
+    aead: ChaCha20Poly1305,
+    nonce: u128,
+}
+
+impl Cipher {
+    /// Derives the session subkey from the pre-shared key and the salt of this direction.
+    pub fn new(psk: &[u8; 32], salt: &[u8; SALT_LEN]) -> Self {
+        let mut material = [0u8; 64];
+        material[..32].copy_from_slice(psk);
+        material[32..].copy_from_slice(salt);
+        let subkey = blake3::derive_key("shadowsocks 2022 session subkey", &material);
+        Self {
+            aead: ChaCha20Poly1305::new(Key::from_slice(&subkey)),
+            nonce: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> Nonce {
+        let nonce = *Nonce::from_slice(&self.nonce.to_le_bytes()[..12]);
+        self.nonce += 1;
+        nonce
+    }
+
+    pub fn seal(&mut self, plain: &[u8], out: &mut Vec<u8>) {
+        let nonce = self.next_nonce();
+        out.extend_from_slice(&self.aead.encrypt(&nonce, plain).unwrap());
+    }
+
+    pub fn open(&mut self, sealed: &[u8]) -> std::io::Result<Vec<u8>> {
+        let nonce = self.next_nonce();
+        self.aead.decrypt(&nonce, sealed).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "shadowsocks chunk failed to decrypt",
+            )
+        })
+    }
+}
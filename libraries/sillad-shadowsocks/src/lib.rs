@@ -0,0 +1,449 @@
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant, SystemTime},
+};
+
+use async_trait::async_trait;
+use crypto::{Cipher, SALT_LEN, TAG_LEN};
+use futures_lite::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use sillad::{
+    accept::{AcceptQueue, HandshakeLimits},
+    dialer::Dialer,
+    listener::Listener,
+    Pipe,
+};
+
+mod config;
+mod crypto;
+
+pub use config::{SsConfig, SsTarget, SsUri, METHOD};
+
+const HEADER_TYPE_CLIENT: u8 = 0;
+const HEADER_TYPE_SERVER: u8 = 1;
+
+/// How far a header timestamp may be from our clock.
+const MAX_TIME_DIFF: u64 = 30;
+
+/// The most data in one chunk.
+const MAX_PAYLOAD: usize = 0xffff;
+
+/// A dialer that connects through a Shadowsocks 2022 server to a given target.
+pub struct SsDialer<D: Dialer> {
+    inner: D,
+    config: SsConfig,
+    target: SsTarget,
+}
+
+impl<D: Dialer> SsDialer<D> {
+    /// Creates a dialer that asks the server reached by the inner dialer to connect to the given target.
+    pub fn new(inner: D, config: SsConfig, target: SsTarget) -> Self {
+        Self {
+            inner,
+            config,
+            target,
+        }
+    }
+}
+
+#[async_trait]
+impl<D: Dialer> Dialer for SsDialer<D> {
+    type P = SsPipe<D::P>;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let mut lower = self.inner.dial().await?;
+        let salt: [u8; SALT_LEN] = rand::random();
+        let mut send = Cipher::new(&self.config.psk, &salt);
+
+        // without an initial payload, the spec requires some padding
+        let mut variable = vec![];
+        self.target.encode(&mut variable);
+        let padding_len = rand::random::<u16>() % 900 + 1;
+        variable.extend_from_slice(&padding_len.to_be_bytes());
+        variable.resize(variable.len() + padding_len as usize, 0);
+
+        let mut fixed = vec![HEADER_TYPE_CLIENT];
+        fixed.extend_from_slice(&unix_now().to_be_bytes());
+        fixed.extend_from_slice(&(variable.len() as u16).to_be_bytes());
+
+        let mut request = salt.to_vec();
+        send.seal(&fixed, &mut request);
+        send.seal(&variable, &mut request);
+        lower.write_all(&request).await?;
+        Ok(SsPipe::new(
+            lower,
+            self.config.clone(),
+            Some(send),
+            ReadState::ResponseHeader { request_salt: salt },
+            None,
+        ))
+    }
+}
+
+/// A listener that acts as a Shadowsocks 2022 server, accepting the connections of [SsDialer]s and other Shadowsocks clients.
+///
+/// Handshakes run concurrently in the background, within the given [HandshakeLimits]. Connections that fail or time out their handshakes are dropped without being returned from `accept`.
+pub struct SsListener<L: Listener> {
+    queue: AcceptQueue<SsPipe<L::P>>,
+}
+
+impl<L: Listener> SsListener<L> {
+    pub fn new(inner: L, config: SsConfig) -> Self {
+        Self::with_limits(inner, config, HandshakeLimits::default())
+    }
+
+    /// Like [SsListener::new], but with specific limits on handshakes.
+    pub fn with_limits(inner: L, config: SsConfig, limits: HandshakeLimits) -> Self {
+        // salts seen recently, so that replayed requests get rejected
+        let seen_salts: Arc<Mutex<HashMap<[u8; SALT_LEN], Instant>>> = Default::default();
+        let queue = AcceptQueue::spawn(inner, limits, move |lower: L::P| {
+            server_handshake(lower, config.clone(), seen_salts.clone())
+        });
+        Self { queue }
+    }
+}
+
+#[async_trait]
+impl<L: Listener> Listener for SsListener<L> {
+    type P = SsPipe<L::P>;
+
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        self.queue.accept().await
+    }
+}
+
+async fn server_handshake<P: Pipe>(
+    mut lower: P,
+    config: SsConfig,
+    seen_salts: Arc<Mutex<HashMap<[u8; SALT_LEN], Instant>>>,
+) -> std::io::Result<SsPipe<P>> {
+    let mut salt = [0u8; SALT_LEN];
+    lower.read_exact(&mut salt).await?;
+    let mut recv = Cipher::new(&config.psk, &salt);
+    let mut fixed = [0u8; 1 + 8 + 2 + TAG_LEN];
+    lower.read_exact(&mut fixed).await?;
+    let fixed = recv.open(&fixed)?;
+    if fixed[0] != HEADER_TYPE_CLIENT {
+        return Err(invalid_data("wrong shadowsocks header type"));
+    }
+    check_timestamp(&fixed[1..9])?;
+    {
+        let mut seen_salts = seen_salts.lock().unwrap();
+        seen_salts.retain(|_, seen| seen.elapsed() < Duration::from_secs(MAX_TIME_DIFF * 2));
+        if seen_salts.insert(salt, Instant::now()).is_some() {
+            return Err(invalid_data("replayed shadowsocks request"));
+        }
+    }
+    let len = u16::from_be_bytes(fixed[9..11].try_into().unwrap()) as usize;
+    let mut variable = vec![0u8; len + TAG_LEN];
+    lower.read_exact(&mut variable).await?;
+    let variable = recv.open(&variable)?;
+    let (target, n) =
+        SsTarget::decode(&variable).ok_or_else(|| invalid_data("bad shadowsocks target"))?;
+    let padding_len = variable
+        .get(n..n + 2)
+        .map(|b| u16::from_be_bytes(b.try_into().unwrap()) as usize)
+        .ok_or_else(|| invalid_data("shadowsocks header too short"))?;
+    let initial_payload = variable
+        .get(n + 2 + padding_len..)
+        .ok_or_else(|| invalid_data("shadowsocks padding too long"))?
+        .to_vec();
+
+    let mut pipe = SsPipe::new(
+        lower,
+        config,
+        None,
+        ReadState::Length,
+        Some(PendingResponse {
+            request_salt: salt,
+            recv,
+        }),
+    );
+    pipe.read_buf = initial_payload;
+    pipe.target = Some(target);
+    Ok(pipe)
+}
+
+enum ReadState {
+    ResponseHeader { request_salt: [u8; SALT_LEN] },
+    Length,
+    Payload(usize),
+}
+
+/// What a server needs to send its response header, which goes out with its first write.
+struct PendingResponse {
+    request_salt: [u8; SALT_LEN],
+    recv: Cipher,
+}
+
+/// A Shadowsocks 2022 stream.
+pub struct SsPipe<P: Pipe> {
+    lower: P,
+    config: SsConfig,
+    send: Option<Cipher>,
+    recv: Option<Cipher>,
+    pending_response: Option<[u8; SALT_LEN]>,
+    target: Option<SsTarget>,
+
+    to_write_buf: Vec<u8>,
+    to_write_plain: usize,
+
+    read_state: ReadState,
+    raw_read_buf: Vec<u8>,
+    read_buf: Vec<u8>,
+    read_closed: bool,
+}
+
+impl<P: Pipe> SsPipe<P> {
+    fn new(
+        lower: P,
+        config: SsConfig,
+        send: Option<Cipher>,
+        read_state: ReadState,
+        pending: Option<PendingResponse>,
+    ) -> Self {
+        let (pending_response, recv) = match pending {
+            Some(pending) => (Some(pending.request_salt), Some(pending.recv)),
+            None => (None, None),
+        };
+        Self {
+            lower,
+            config,
+            send,
+            recv,
+            pending_response,
+            target: None,
+            to_write_buf: vec![],
+            to_write_plain: 0,
+            read_state,
+            raw_read_buf: vec![],
+            read_buf: vec![],
+            read_closed: false,
+        }
+    }
+
+    /// On the server side, the target that the client asked for.
+    pub fn target(&self) -> Option<&SsTarget> {
+        self.target.as_ref()
+    }
+
+    /// Decrypts whatever whole chunks are in the raw read buffer. Returns whether it made progress.
+    fn process_raw(&mut self) -> std::io::Result<bool> {
+        let need = match self.read_state {
+            ReadState::ResponseHeader { .. } => SALT_LEN + 1 + 8 + SALT_LEN + 2 + TAG_LEN,
+            ReadState::Length => 2 + TAG_LEN,
+            ReadState::Payload(len) => len + TAG_LEN,
+        };
+        if self.raw_read_buf.len() < need {
+            return Ok(false);
+        }
+        let chunk: Vec<u8> = self.raw_read_buf.drain(..need).collect();
+        match self.read_state {
+            ReadState::ResponseHeader { request_salt } => {
+                let salt: [u8; SALT_LEN] = chunk[..SALT_LEN].try_into().unwrap();
+                let mut recv = Cipher::new(&self.config.psk, &salt);
+                let fixed = recv.open(&chunk[SALT_LEN..])?;
+                if fixed[0] != HEADER_TYPE_SERVER {
+                    return Err(invalid_data("wrong shadowsocks header type"));
+                }
+                check_timestamp(&fixed[1..9])?;
+                if fixed[9..9 + SALT_LEN] != request_salt {
+                    return Err(invalid_data("shadowsocks response is for another request"));
+                }
+                let len = u16::from_be_bytes(fixed[9 + SALT_LEN..].try_into().unwrap());
+                self.recv = Some(recv);
+                self.read_state = ReadState::Payload(len as usize);
+            }
+            ReadState::Length => {
+                let len = self.recv.as_mut().unwrap().open(&chunk)?;
+                let len = u16::from_be_bytes(len[..].try_into().unwrap());
+                self.read_state = ReadState::Payload(len as usize);
+            }
+            ReadState::Payload(_) => {
+                let payload = self.recv.as_mut().unwrap().open(&chunk)?;
+                self.read_buf.extend_from_slice(&payload);
+                self.read_state = ReadState::Length;
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl<P: Pipe> AsyncRead for SsPipe<P> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if !this.read_buf.is_empty() || this.read_closed {
+                let n = buf.len().min(this.read_buf.len());
+                buf[..n].copy_from_slice(&this.read_buf[..n]);
+                this.read_buf.drain(..n);
+                return Poll::Ready(Ok(n));
+            }
+            if this.process_raw()? {
+                continue;
+            }
+            let mut chunk = [0u8; 8192];
+            let n = futures_lite::ready!(Pin::new(&mut this.lower).poll_read(cx, &mut chunk))?;
+            if n == 0 {
+                this.read_closed = true;
+                continue;
+            }
+            this.raw_read_buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+impl<P: Pipe> AsyncWrite for SsPipe<P> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        // This assumes the caller polls the *same* buffer until completion, so that nothing is held back between writes.
+        let this = self.get_mut();
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        if this.to_write_buf.is_empty() {
+            let plain_n = buf.len().min(MAX_PAYLOAD);
+            if let Some(request_salt) = this.pending_response.take() {
+                // the server's first chunk carries its salt and header, and has no separate length chunk
+                let salt: [u8; SALT_LEN] = rand::random();
+                let mut send = Cipher::new(&this.config.psk, &salt);
+                let mut fixed = vec![HEADER_TYPE_SERVER];
+                fixed.extend_from_slice(&unix_now().to_be_bytes());
+                fixed.extend_from_slice(&request_salt);
+                fixed.extend_from_slice(&(plain_n as u16).to_be_bytes());
+                this.to_write_buf.extend_from_slice(&salt);
+                send.seal(&fixed, &mut this.to_write_buf);
+                this.send = Some(send);
+            } else {
+                let send = this.send.as_mut().unwrap();
+                send.seal(&(plain_n as u16).to_be_bytes(), &mut this.to_write_buf);
+            }
+            let send = this.send.as_mut().unwrap();
+            send.seal(&buf[..plain_n], &mut this.to_write_buf);
+            this.to_write_plain = plain_n;
+        }
+        while !this.to_write_buf.is_empty() {
+            let n =
+                futures_lite::ready!(Pin::new(&mut this.lower).poll_write(cx, &this.to_write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            this.to_write_buf.drain(..n);
+        }
+        Poll::Ready(Ok(this.to_write_plain))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().lower).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().lower).poll_close(cx)
+    }
+}
+
+impl<P: Pipe> Pipe for SsPipe<P> {
+    fn protocol(&self) -> &str {
+        "shadowsocks-2022"
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.lower.remote_addr()
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+fn check_timestamp(bytes: &[u8]) -> std::io::Result<()> {
+    let timestamp = u64::from_be_bytes(bytes.try_into().unwrap());
+    if unix_now().abs_diff(timestamp) > MAX_TIME_DIFF {
+        return Err(invalid_data("shadowsocks header timestamp too far off"));
+    }
+    Ok(())
+}
+
+fn invalid_data(err: impl ToString) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use async_io::Timer;
+    use futures_lite::FutureExt;
+    use sillad::tcp::{TcpDialer, TcpListener};
+
+    use super::*;
+
+    const PSK: &str = "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=";
+
+    #[test]
+    fn test_shadowsocks_echo() {
+        smolscale::block_on(async {
+            let config = SsConfig::new(PSK).unwrap();
+            let tcp = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let addr = tcp.local_addr().await;
+            let mut listener = SsListener::new(tcp, config.clone());
+            let target: SsTarget = "example.com:443".parse().unwrap();
+            let dialer = SsDialer::new(TcpDialer { dest_addr: addr }, config, target.clone());
+            let message: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+            let server = async {
+                let mut pipe = listener.accept().await.unwrap();
+                assert_eq!(pipe.target(), Some(&target));
+                let mut received = vec![0u8; message.len()];
+                pipe.read_exact(&mut received).await.unwrap();
+                pipe.write_all(&received).await.unwrap();
+                pipe.close().await.unwrap();
+            };
+            let client = async {
+                let mut pipe = dialer.dial().await.unwrap();
+                pipe.write_all(&message).await.unwrap();
+                let mut echoed = vec![];
+                pipe.read_to_end(&mut echoed).await.unwrap();
+                echoed
+            };
+            let ((), echoed) = futures_lite::future::zip(server, client).await;
+            assert_eq!(echoed, message);
+        });
+    }
+
+    #[test]
+    fn test_wrong_key_rejected() {
+        smolscale::block_on(async {
+            let tcp = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let addr = tcp.local_addr().await;
+            let mut listener = SsListener::new(tcp, SsConfig::new(PSK).unwrap());
+            let wrong = SsConfig::new(&PSK.replace('A', "B")).unwrap();
+            let dialer = SsDialer::new(
+                TcpDialer { dest_addr: addr },
+                wrong,
+                "1.1.1.1:53".parse().unwrap(),
+            );
+            let _pipe = dialer.dial().await.unwrap();
+            let accepted = async { Some(listener.accept().await) }
+                .or(async {
+                    Timer::after(Duration::from_millis(500)).await;
+                    None
+                })
+                .await;
+            assert!(accepted.is_none());
+        });
+    }
+}
@@ -0,0 +1,70 @@
+//! Measures the throughput of the sosistab3 write path over loopback TCP, comparing one record per small write against coalescing small writes with vectored writes.
+//!
+//! Run with `cargo bench -p sillad-sosistab3`.
+
+use std::{
+    io::IoSlice,
+    time::{Duration, Instant},
+};
+
+use futures_util::{AsyncReadExt, AsyncWriteExt};
+use sillad::{
+    dialer::Dialer,
+    listener::Listener,
+    tcp::{TcpDialer, TcpListener},
+};
+use sillad_sosistab3::{dialer::SosistabDialer, listener::SosistabListener, Cookie};
+
+const TOTAL_BYTES: usize = 64 * 1024 * 1024;
+
+async fn run(write_size: usize, batch: usize) -> Duration {
+    let cookie = Cookie::random();
+    let tcp_listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+        .await
+        .unwrap();
+    let dest_addr = tcp_listener.local_addr().await;
+    let mut listener = SosistabListener::new(tcp_listener, cookie);
+    let server = smolscale::spawn(async move {
+        let mut pipe = listener.accept().await.unwrap();
+        let mut buf = vec![0u8; 65536];
+        let mut total = 0;
+        while total < TOTAL_BYTES {
+            total += pipe.read(&mut buf).await.unwrap();
+        }
+    });
+    let mut pipe = SosistabDialer {
+        inner: TcpDialer { dest_addr },
+        cookie,
+    }
+    .dial()
+    .await
+    .unwrap();
+
+    let chunk = vec![0u8; write_size];
+    let start = Instant::now();
+    let mut written = 0;
+    while written < TOTAL_BYTES {
+        if batch == 1 {
+            written += pipe.write(&chunk).await.unwrap();
+        } else {
+            let bufs = vec![IoSlice::new(&chunk); batch];
+            written += pipe.write_vectored(&bufs).await.unwrap();
+        }
+    }
+    pipe.flush().await.unwrap();
+    server.await;
+    start.elapsed()
+}
+
+fn main() {
+    for write_size in [64, 512, 4096] {
+        for batch in [1, 16] {
+            let elapsed = async_io::block_on(run(write_size, batch));
+            let mbps = TOTAL_BYTES as f64 / elapsed.as_secs_f64() / 1_000_000.0;
+            println!(
+                "write_size={write_size:<5} batch={batch:<3} {:>8.1} MB/s ({elapsed:?})",
+                mbps
+            );
+        }
+    }
+}
@@ -0,0 +1,78 @@
+use crate::resume::Ticket;
+
+/// Prefix that distinguishes control messages from ordinary padding records. Peers that don't understand control messages treat them as padding and ignore them.
+const CONTROL_MAGIC: &[u8; 8] = b"sosi3ctl";
+
+/// An in-band control message, carried inside a padding record.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Control {
+    /// Announces support for rekeying, and the limits after which we want keys to be rotated.
+    Hello { rekey_bytes: u64, rekey_secs: u64 },
+    /// Everything after this record, in this direction, is encrypted with the next key.
+    Rekey,
+    /// A ticket that the client can use to resume the session later.
+    Ticket(Ticket),
+    /// In a resumed session, everything after this record from the client is encrypted with the keys from the fresh key exchange.
+    Upgrade,
+}
+
+impl Control {
+    /// Encodes the control message into bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut toret = CONTROL_MAGIC.to_vec();
+        match self {
+            Control::Hello {
+                rekey_bytes,
+                rekey_secs,
+            } => {
+                toret.push(0);
+                toret.extend_from_slice(&rekey_bytes.to_be_bytes());
+                toret.extend_from_slice(&rekey_secs.to_be_bytes());
+            }
+            Control::Rekey => toret.push(1),
+            Control::Ticket(ticket) => {
+                toret.push(2);
+                toret.extend_from_slice(ticket);
+            }
+            Control::Upgrade => toret.push(3),
+        }
+        toret
+    }
+
+    /// Decodes a control message from the body of a padding record, returning None if it is just padding.
+    pub fn decode(bts: &[u8]) -> Option<Self> {
+        let rest = bts.strip_prefix(CONTROL_MAGIC)?;
+        match rest.split_first()? {
+            (0, rest) if rest.len() == 16 => Some(Control::Hello {
+                rekey_bytes: u64::from_be_bytes(rest[..8].try_into().unwrap()),
+                rekey_secs: u64::from_be_bytes(rest[8..].try_into().unwrap()),
+            }),
+            (1, []) => Some(Control::Rekey),
+            (2, rest) => Some(Control::Ticket(rest.try_into().ok()?)),
+            (3, []) => Some(Control::Upgrade),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::resume::TICKET_LEN;
+
+    #[test]
+    fn test_control_round_trip() {
+        for ctl in [
+            Control::Hello {
+                rekey_bytes: 12345,
+                rekey_secs: 678,
+            },
+            Control::Rekey,
+            Control::Ticket([42; TICKET_LEN]),
+            Control::Upgrade,
+        ] {
+            assert_eq!(Control::decode(&ctl.encode()), Some(ctl));
+        }
+        assert_eq!(Control::decode(b"random padding bytes"), None);
+    }
+}
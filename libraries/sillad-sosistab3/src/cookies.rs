@@ -0,0 +1,89 @@
+use std::sync::{Arc, RwLock};
+
+use crate::Cookie;
+
+/// A shared, rotatable set of cookies that a listener accepts: the current one, plus the one before it.
+///
+/// Every cookie in a set has the same [crate::ObfsParams], those of the cookie it was created with. The listener has to settle on things like the fake TLS framing before it can tell which cookie a client used, so only the keys can differ.
+///
+/// Cloning gives another handle to the same set, so a listener can keep running while its cookies are rotated from elsewhere.
+#[derive(Clone)]
+pub struct CookieSet {
+    inner: Arc<RwLock<Vec<Cookie>>>,
+}
+
+impl CookieSet {
+    /// Creates a set containing a single cookie.
+    pub fn new(current: Cookie) -> Self {
+        Self {
+            inner: Arc::new(RwLock::new(vec![current])),
+        }
+    }
+
+    /// Makes a new cookie the current one. The previously current cookie keeps being accepted until the next rotation, so that clients who learned of it before the rotation can still connect.
+    ///
+    /// Only the new cookie's key is used; it takes on the parameters of the rest of the set.
+    pub fn rotate(&self, new_current: Cookie) {
+        let mut inner = self.inner.write().unwrap();
+        let new_current = Cookie {
+            key: new_current.key,
+            params: inner[0].params,
+        };
+        inner.truncate(1);
+        inner.insert(0, new_current);
+    }
+
+    /// The current cookie.
+    pub fn current(&self) -> Cookie {
+        self.inner.read().unwrap()[0]
+    }
+
+    /// All the accepted cookies, current one first.
+    pub fn all(&self) -> Vec<Cookie> {
+        self.inner.read().unwrap().clone()
+    }
+}
+
+impl From<Cookie> for CookieSet {
+    fn from(value: Cookie) -> Self {
+        Self::new(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ObfsParams;
+
+    #[test]
+    fn test_rotation_keeps_previous() {
+        let first = Cookie::random();
+        let second = Cookie::random();
+        let third = Cookie::random();
+        let set = CookieSet::new(first);
+        let handle = set.clone();
+        handle.rotate(second);
+        assert_eq!(set.current().key, second.key);
+        assert_eq!(
+            set.all().iter().map(|c| c.key).collect::<Vec<_>>(),
+            vec![second.key, first.key]
+        );
+        handle.rotate(third);
+        assert_eq!(
+            set.all().iter().map(|c| c.key).collect::<Vec<_>>(),
+            vec![third.key, second.key]
+        );
+    }
+
+    #[test]
+    fn test_rotation_keeps_params() {
+        let set = CookieSet::new(Cookie::random_with_params(ObfsParams {
+            tls_mimicry: true,
+            ..Default::default()
+        }));
+        let plain = Cookie::random();
+        set.rotate(plain);
+        assert_eq!(set.current().key, plain.key);
+        assert!(set.all().iter().all(|cookie| cookie.params.tls_mimicry));
+    }
+}
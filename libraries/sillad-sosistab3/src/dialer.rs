@@ -9,7 +9,14 @@ use rand::{Rng, RngCore};
 use sillad::dialer::Dialer;
 use tap::Tap;
 
-use crate::{handshake::Handshake, state::State, Cookie, SosistabPipe};
+use crate::{
+    framing::Framed,
+    handshake::Handshake,
+    pq,
+    resume::{resumed_shared_secret, take_ticket, TICKET_LEN},
+    state::State,
+    Cookie, SosistabPipe,
+};
 
 pub struct SosistabDialer<D: Dialer> {
     pub inner: D,
@@ -21,13 +28,49 @@ impl<D: Dialer> Dialer for SosistabDialer<D> {
     type P = SosistabPipe<D::P>;
     #[tracing::instrument(skip(self))]
     async fn dial(&self) -> std::io::Result<Self::P> {
-        let mut lower = self.inner.dial().await?;
+        let lower = self.inner.dial().await?;
+        let mut lower = if self.cookie.params.tls_mimicry {
+            Framed::tls_client(lower).await?
+        } else {
+            Framed::plain(lower)
+        };
         // send the upstream handshake
         let eph_sk = x25519_dalek::EphemeralSecret::random_from_rng(rand::thread_rng());
         let eph_pk: x25519_dalek::PublicKey = (&eph_sk).into();
-        // we generate a whole lot of random padding
-        let padding_len: u64 = rand::thread_rng().gen_range(0..=8192);
-        let padding = vec![0; padding_len as usize].tap_mut(|v| rand::thread_rng().fill_bytes(v));
+        // if resumption is on and we have a ticket from an earlier session, we resume rather than doing a full handshake
+        let resumption = if self.cookie.params.resumption {
+            take_ticket(self.cookie)
+        } else {
+            None
+        };
+        // in hybrid post-quantum mode, a full handshake also carries an ML-KEM encapsulation key
+        let pq_keypair =
+            (self.cookie.params.hybrid_pq && resumption.is_none()).then(pq::client_keypair);
+        // we generate a whole lot of random padding. when resuming, the ticket rides at the start of the padding, and so does the encapsulation key in post-quantum mode
+        let min_padding = if pq_keypair.is_some() {
+            pq::EK_LEN
+        } else {
+            TICKET_LEN
+        };
+        let padding_len: u64 =
+            rand::thread_rng().gen_range(min_padding as u64..=8192 + min_padding as u64);
+        let mut padding =
+            vec![0; padding_len as usize].tap_mut(|v| rand::thread_rng().fill_bytes(v));
+        if let Some((_, ek)) = &pq_keypair {
+            padding[..pq::EK_LEN].copy_from_slice(ek);
+            pq::mask(
+                pq::EK_MASK,
+                &self.cookie,
+                eph_pk.as_bytes(),
+                &mut padding[..pq::EK_LEN],
+            );
+        }
+        let responding_to = if let Some((ticket, _)) = resumption {
+            padding[..TICKET_LEN].copy_from_slice(&ticket);
+            blake3::hash(&ticket)
+        } else {
+            blake3::hash(b"")
+        };
         let padding_hash = blake3::hash(&padding);
         // generate the handshake
         let my_handshake = Handshake {
@@ -38,7 +81,7 @@ impl<D: Dialer> Dialer for SosistabDialer<D> {
                 .as_secs(),
             padding_len,
             padding_hash,
-            responding_to: blake3::hash(b""),
+            responding_to,
         };
         tracing::debug!(
             cookie = debug(self.cookie),
@@ -58,6 +101,26 @@ impl<D: Dialer> Dialer for SosistabDialer<D> {
             padding_hash = debug(padding_hash),
             "handshake sent"
         );
+        if let Some((_, resumption_secret)) = resumption {
+            // 0-RTT: the early keys come from the ticket, so we can start sending right away. the server's confirmation, and with it the switch to forward-secure keys, happens when we first read.
+            let mut state = State::new(
+                &resumed_shared_secret(&resumption_secret, &my_handshake),
+                false,
+                self.cookie.params,
+            );
+            state.store_tickets_for(self.cookie);
+            let mut hello = vec![];
+            state.encrypt_hello(&mut hello);
+            lower.write_all(&hello).await?;
+            tracing::debug!(cookie = debug(self.cookie), "session resumed");
+            return Ok(SosistabPipe::new_resumed(
+                lower,
+                state,
+                self.cookie,
+                blake3::hash(&my_handshake),
+                eph_sk,
+            ));
+        }
         // receive their handshake
         let mut their_handshake = [0u8; 140];
         lower.read_exact(&mut their_handshake).await?;
@@ -84,11 +147,24 @@ impl<D: Dialer> Dialer for SosistabDialer<D> {
             "their handshake received"
         );
         // we are ready for the shared secret
-        let state = State::new(
-            eph_sk.diffie_hellman(&their_handshake.eph_pk).as_bytes(),
-            false,
-            self.cookie.params,
-        );
+        let mut ss = *eph_sk.diffie_hellman(&their_handshake.eph_pk).as_bytes();
+        if let Some((dk, _)) = &pq_keypair {
+            let mut ct = buff[..pq::CT_LEN.min(buff.len())].to_vec();
+            pq::mask(
+                pq::CT_MASK,
+                &self.cookie,
+                &[eph_pk.as_bytes(), their_handshake.eph_pk.as_bytes()].concat(),
+                &mut ct,
+            );
+            ss = pq::hybrid_secret(&ss, &pq::client_decapsulate(dk, &ct)?);
+        }
+        let mut state = State::new(&ss, false, self.cookie.params);
+        if self.cookie.params.resumption {
+            state.store_tickets_for(self.cookie);
+        }
+        let mut hello = vec![];
+        state.encrypt_hello(&mut hello);
+        lower.write_all(&hello).await?;
         tracing::debug!(
             cookie = debug(self.cookie),
             padding_len,
@@ -0,0 +1,343 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use pin_project::pin_project;
+use rand::{Rng, RngCore};
+use sillad::Pipe;
+
+const CONTENT_CHANGE_CIPHER_SPEC: u8 = 0x14;
+const CONTENT_HANDSHAKE: u8 = 0x16;
+const CONTENT_APPLICATION_DATA: u8 = 0x17;
+
+/// The largest record body that TLS 1.3 permits for application data.
+const MAX_RECORD_BODY: usize = 16384 + 256;
+
+/// A framing layer under the sosistab3 encryption. In TLS mimicry mode, everything is wrapped in syntactically valid TLS 1.3 application-data records, after a fake TLS handshake. Otherwise, bytes pass through unchanged.
+#[pin_project]
+pub struct Framed<P: Pipe> {
+    #[pin]
+    inner: P,
+    tls: bool,
+
+    write_pending: Vec<u8>,
+    write_plain: usize,
+
+    read_header: [u8; 5],
+    read_header_len: usize,
+    read_remaining: usize,
+    read_skipping: bool,
+}
+
+impl<P: Pipe> Framed<P> {
+    /// Passes bytes through unchanged.
+    pub fn plain(inner: P) -> Self {
+        Self::new(inner, false)
+    }
+
+    fn new(inner: P, tls: bool) -> Self {
+        Self {
+            inner,
+            tls,
+            write_pending: vec![],
+            write_plain: 0,
+            read_header: [0; 5],
+            read_header_len: 0,
+            read_remaining: 0,
+            read_skipping: false,
+        }
+    }
+
+    /// Performs the client side of the fake TLS handshake, returning a pipe that speaks in TLS records.
+    pub async fn tls_client(mut inner: P) -> std::io::Result<Self> {
+        inner.write_all(&client_hello()).await?;
+        let (content_type, _) = read_record(&mut inner, &mut vec![]).await?;
+        if content_type != CONTENT_HANDSHAKE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "expected a ServerHello",
+            ));
+        }
+        // like real TLS 1.3 clients in middlebox compatibility mode, send a dummy ChangeCipherSpec
+        inner.write_all(&change_cipher_spec()).await?;
+        Ok(Self::new(inner, true))
+    }
+
+    /// Performs the server side of the fake TLS handshake, returning a pipe that speaks in TLS records. Everything read from the client is recorded into `consumed`, so that a failure can be handed to the probe action.
+    pub async fn tls_server(
+        mut inner: P,
+        consumed: &mut Vec<u8>,
+    ) -> Result<Self, (P, std::io::Error)> {
+        let session_id = match read_client_hello(&mut inner, consumed).await {
+            Ok(session_id) => session_id,
+            Err(err) => return Err((inner, err)),
+        };
+        let mut to_send = server_hello(&session_id);
+        to_send.extend_from_slice(&change_cipher_spec());
+        if let Err(err) = inner.write_all(&to_send).await {
+            return Err((inner, err));
+        }
+        Ok(Self::new(inner, true))
+    }
+}
+
+async fn read_client_hello<P: Pipe>(
+    inner: &mut P,
+    consumed: &mut Vec<u8>,
+) -> std::io::Result<Vec<u8>> {
+    let (content_type, body) = read_record(inner, consumed).await?;
+    if content_type != CONTENT_HANDSHAKE || body.first() != Some(&0x01) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "expected a ClientHello",
+        ));
+    }
+    // handshake type (1), length (3), legacy version (2), random (32), then the session id
+    let session_id_len = *body.get(38).unwrap_or(&0) as usize;
+    body.get(39..39 + session_id_len)
+        .map(|s| s.to_vec())
+        .ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidData, "truncated ClientHello")
+        })
+}
+
+/// Reads one whole TLS record, returning its content type and body.
+async fn read_record<P: Pipe>(
+    inner: &mut P,
+    consumed: &mut Vec<u8>,
+) -> std::io::Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 5];
+    inner.read_exact(&mut header).await?;
+    consumed.extend_from_slice(&header);
+    let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+    if header[1] != 0x03 || len > MAX_RECORD_BODY {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "not a TLS record",
+        ));
+    }
+    let mut body = vec![0u8; len];
+    inner.read_exact(&mut body).await?;
+    consumed.extend_from_slice(&body);
+    Ok((header[0], body))
+}
+
+fn record(content_type: u8, legacy_version: u16, body: &[u8]) -> Vec<u8> {
+    let mut toret = vec![content_type];
+    toret.extend_from_slice(&legacy_version.to_be_bytes());
+    toret.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    toret.extend_from_slice(body);
+    toret
+}
+
+fn change_cipher_spec() -> Vec<u8> {
+    record(CONTENT_CHANGE_CIPHER_SPEC, 0x0303, &[0x01])
+}
+
+fn handshake_message(msg_type: u8, body: &[u8]) -> Vec<u8> {
+    let mut toret = vec![msg_type];
+    toret.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    toret.extend_from_slice(body);
+    toret
+}
+
+fn extension(ext_type: u16, body: &[u8]) -> Vec<u8> {
+    let mut toret = ext_type.to_be_bytes().to_vec();
+    toret.extend_from_slice(&(body.len() as u16).to_be_bytes());
+    toret.extend_from_slice(body);
+    toret
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut toret = [0u8; N];
+    rand::thread_rng().fill_bytes(&mut toret);
+    toret
+}
+
+fn client_hello() -> Vec<u8> {
+    let mut body = vec![0x03, 0x03];
+    body.extend_from_slice(&random_bytes::<32>());
+    body.push(32);
+    body.extend_from_slice(&random_bytes::<32>());
+    let cipher_suites: &[u16] = &[0x1301, 0x1302, 0x1303, 0xc02b, 0xc02f, 0xc02c, 0xc030];
+    body.extend_from_slice(&(cipher_suites.len() as u16 * 2).to_be_bytes());
+    for suite in cipher_suites {
+        body.extend_from_slice(&suite.to_be_bytes());
+    }
+    // only the null compression method
+    body.extend_from_slice(&[0x01, 0x00]);
+
+    let mut extensions = vec![];
+    // supported_versions: TLS 1.3 and 1.2
+    extensions.extend(extension(0x002b, &[0x04, 0x03, 0x04, 0x03, 0x03]));
+    // supported_groups: x25519 and secp256r1
+    extensions.extend(extension(0x000a, &[0x00, 0x04, 0x00, 0x1d, 0x00, 0x17]));
+    // signature_algorithms
+    extensions.extend(extension(
+        0x000d,
+        &[0x00, 0x08, 0x04, 0x03, 0x08, 0x04, 0x04, 0x01, 0x05, 0x01],
+    ));
+    // key_share with an x25519 "public key"
+    let mut key_share = vec![0x00, 0x24, 0x00, 0x1d, 0x00, 0x20];
+    key_share.extend_from_slice(&random_bytes::<32>());
+    extensions.extend(extension(0x0033, &key_share));
+    // psk_key_exchange_modes
+    extensions.extend(extension(0x002d, &[0x01, 0x01]));
+    // padding, so that the length varies like it does with real clients
+    let padding_len = rand::thread_rng().gen_range(0..=256);
+    extensions.extend(extension(0x0015, &vec![0u8; padding_len]));
+
+    body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    body.extend_from_slice(&extensions);
+    record(CONTENT_HANDSHAKE, 0x0301, &handshake_message(0x01, &body))
+}
+
+fn server_hello(session_id: &[u8]) -> Vec<u8> {
+    let mut body = vec![0x03, 0x03];
+    body.extend_from_slice(&random_bytes::<32>());
+    body.push(session_id.len() as u8);
+    body.extend_from_slice(session_id);
+    // TLS_AES_128_GCM_SHA256, null compression
+    body.extend_from_slice(&[0x13, 0x01, 0x00]);
+
+    let mut extensions = vec![];
+    extensions.extend(extension(0x002b, &[0x03, 0x04]));
+    let mut key_share = vec![0x00, 0x1d, 0x00, 0x20];
+    key_share.extend_from_slice(&random_bytes::<32>());
+    extensions.extend(extension(0x0033, &key_share));
+
+    body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    body.extend_from_slice(&extensions);
+    record(CONTENT_HANDSHAKE, 0x0303, &handshake_message(0x02, &body))
+}
+
+impl<P: Pipe> AsyncWrite for Framed<P> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut this = self.project();
+        if !*this.tls {
+            return this.inner.poll_write(cx, buf);
+        }
+        // same caveat as SosistabPipe: the caller must poll with the same buffer until completion
+        if this.write_pending.is_empty() {
+            let n = buf.len().min(16384);
+            *this.write_pending = record(CONTENT_APPLICATION_DATA, 0x0303, &buf[..n]);
+            *this.write_plain = n;
+        }
+        while !this.write_pending.is_empty() {
+            let n = futures_util::ready!(this.inner.as_mut().poll_write(cx, this.write_pending))?;
+            if n == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            this.write_pending.drain(..n);
+        }
+        Poll::Ready(Ok(*this.write_plain))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
+impl<P: Pipe> AsyncRead for Framed<P> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut this = self.project();
+        if !*this.tls {
+            return this.inner.poll_read(cx, buf);
+        }
+        loop {
+            if *this.read_remaining == 0 {
+                // read the next record header
+                let n = futures_util::ready!(this
+                    .inner
+                    .as_mut()
+                    .poll_read(cx, &mut this.read_header[*this.read_header_len..]))?;
+                if n == 0 {
+                    return Poll::Ready(Ok(0));
+                }
+                *this.read_header_len += n;
+                if *this.read_header_len < 5 {
+                    continue;
+                }
+                *this.read_header_len = 0;
+                let len = u16::from_be_bytes([this.read_header[3], this.read_header[4]]) as usize;
+                if len > MAX_RECORD_BODY {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "TLS record too long",
+                    )));
+                }
+                *this.read_remaining = len;
+                // anything other than application data, like the ChangeCipherSpec, is thrown away
+                *this.read_skipping = this.read_header[0] != CONTENT_APPLICATION_DATA;
+            } else if *this.read_skipping {
+                let mut scratch = [0u8; 256];
+                let to_read = (*this.read_remaining).min(scratch.len());
+                let n = futures_util::ready!(this
+                    .inner
+                    .as_mut()
+                    .poll_read(cx, &mut scratch[..to_read]))?;
+                if n == 0 {
+                    return Poll::Ready(Ok(0));
+                }
+                *this.read_remaining -= n;
+            } else {
+                let to_read = (*this.read_remaining).min(buf.len());
+                let n =
+                    futures_util::ready!(this.inner.as_mut().poll_read(cx, &mut buf[..to_read]))?;
+                *this.read_remaining -= n;
+                return Poll::Ready(Ok(n));
+            }
+        }
+    }
+}
+
+impl<P: Pipe> Pipe for Framed<P> {
+    fn protocol(&self) -> &str {
+        self.inner.protocol()
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.inner.remote_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hellos_are_well_formed() {
+        let hello = client_hello();
+        assert_eq!(hello[0], CONTENT_HANDSHAKE);
+        assert_eq!(
+            u16::from_be_bytes([hello[3], hello[4]]) as usize,
+            hello.len() - 5
+        );
+        let body = &hello[5..];
+        assert_eq!(body[0], 0x01);
+        assert_eq!(
+            u32::from_be_bytes([0, body[1], body[2], body[3]]) as usize,
+            body.len() - 4
+        );
+        assert_eq!(body[38], 32);
+
+        let session_id = &body[39..][..32];
+        let reply = server_hello(session_id);
+        assert_eq!(reply[0], CONTENT_HANDSHAKE);
+        assert_eq!(&reply[5 + 39..][..32], session_id);
+    }
+}
@@ -51,6 +51,11 @@ impl Handshake {
         Ok(Handshake::from_bytes(array_ref![encrypted_data, 0, 112]))
     }
 
+    /// A key that uniquely identifies this handshake, for detecting replays.
+    pub fn replay_key(&self) -> [u8; 32] {
+        *blake3::hash(&self.bytes()).as_bytes()
+    }
+
     /// Generates the bytes representation.
     fn bytes(&self) -> [u8; 112] {
         let mut toret = [0u8; 112];
@@ -1,22 +1,41 @@
 use std::{
     collections::VecDeque,
     fmt::Debug,
-    io::{ErrorKind, Read, Write},
-    task::Poll,
+    io::{ErrorKind, IoSlice, Read, Write},
+    pin::Pin,
+    task::{Context, Poll},
 };
 
-use futures_util::{AsyncRead, AsyncWrite};
+use async_io::Timer;
+use futures_util::{AsyncRead, AsyncWrite, FutureExt};
 use pin_project::pin_project;
 
+use framing::Framed;
 use serde::{Deserialize, Serialize};
 use sillad::Pipe;
 use state::State;
 
+pub use cookies::CookieSet;
+use padding::RECORD_OVERHEAD;
+pub use padding::{LengthDist, PaddingProfile, PaddingSpec};
+pub use probe::ProbeAction;
+pub use resume::set_ticket_secret;
+pub use stats::PipeStats;
+
+mod control;
+mod cookies;
 mod dedup;
 pub mod dialer;
+mod framing;
 mod handshake;
 pub mod listener;
+mod padding;
+mod pq;
+mod probe;
+mod replay;
+mod resume;
 mod state;
+mod stats;
 
 #[derive(Clone, Copy)]
 pub struct Cookie {
@@ -30,6 +49,35 @@ pub struct ObfsParams {
     pub obfs_lengths: bool,
     // whether or not to add delays
     pub obfs_timing: bool,
+    // rotate keys after this many bytes in one direction (0 for the default)
+    #[serde(default)]
+    pub rekey_bytes: u64,
+    // rotate keys after this many seconds (0 for the default)
+    #[serde(default)]
+    pub rekey_secs: u64,
+    // the named padding profile to use
+    #[serde(default)]
+    pub padding: PaddingProfile,
+    // whether to disguise everything as TLS 1.3 records, fake handshake included
+    #[serde(default)]
+    pub tls_mimicry: bool,
+    // whether to use a hybrid X25519 + ML-KEM-768 key exchange, so that recorded traffic stays safe from quantum computers
+    #[serde(default)]
+    pub hybrid_pq: bool,
+    // whether to hand out and accept resumption tickets, so that reconnecting clients can send data in their first flight. servers that restart should call [set_ticket_secret] with a persisted secret
+    #[serde(default)]
+    pub resumption: bool,
+}
+
+impl ObfsParams {
+    /// Returns the padding parameters to use. The old `obfs_lengths` flag is equivalent to the uniform profile.
+    pub fn padding_spec(&self) -> PaddingSpec {
+        if self.padding == PaddingProfile::None && self.obfs_lengths {
+            PaddingProfile::Uniform.spec()
+        } else {
+            self.padding.spec()
+        }
+    }
 }
 
 impl Debug for Cookie {
@@ -84,7 +132,7 @@ impl Cookie {
 #[pin_project]
 pub struct SosistabPipe<P: Pipe> {
     #[pin]
-    lower: P,
+    lower: Framed<P>,
     state: State,
 
     read_buf: VecDeque<u8>,
@@ -92,10 +140,24 @@ pub struct SosistabPipe<P: Pipe> {
     raw_read_buf: Vec<u8>,
 
     to_write_buf: Vec<u8>,
+    to_write_plain: usize,
+    coalesce_buf: Vec<u8>,
+
+    dummy_buf: Vec<u8>,
+    dummy_timer: Option<Timer>,
+
+    // for a resumed session, the server's confirmation that we still need to read and check, and our half of the fresh key exchange it completes
+    awaiting_confirm: Option<(Cookie, blake3::Hash, x25519_dalek::EphemeralSecret)>,
 }
 
 impl<P: Pipe> SosistabPipe<P> {
-    fn new(lower: P, state: State) -> Self {
+    /// Returns the traffic and crypto counters of this pipe so far.
+    pub fn stats(&self) -> PipeStats {
+        self.state.stats()
+    }
+
+    fn new(lower: Framed<P>, state: State) -> Self {
+        let dummy_timer = state.padding().next_dummy_interval().map(Timer::after);
         Self {
             lower,
             state,
@@ -103,23 +165,109 @@ impl<P: Pipe> SosistabPipe<P> {
             read_closed: false,
             raw_read_buf: Default::default(),
             to_write_buf: Default::default(),
+            to_write_plain: 0,
+            coalesce_buf: Default::default(),
+            dummy_buf: Default::default(),
+            dummy_timer,
+            awaiting_confirm: None,
         }
     }
+
+    fn new_resumed(
+        lower: Framed<P>,
+        state: State,
+        cookie: Cookie,
+        my_handshake_hash: blake3::Hash,
+        eph_sk: x25519_dalek::EphemeralSecret,
+    ) -> Self {
+        let mut pipe = Self::new(lower, state);
+        pipe.awaiting_confirm = Some((cookie, my_handshake_hash, eph_sk));
+        pipe
+    }
 }
 
-impl<P: Pipe> AsyncWrite for SosistabPipe<P> {
-    #[tracing::instrument(name = "sosistab_write", skip(self, cx, buf))]
-    fn poll_write(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-        buf: &[u8],
+/// Sends dummy traffic while the pipe is idle, if the padding profile calls for it.
+fn poll_dummy<P: Pipe>(
+    mut lower: Pin<&mut Framed<P>>,
+    cx: &mut Context<'_>,
+    state: &mut State,
+    dummy_timer: &mut Option<Timer>,
+    dummy_buf: &mut Vec<u8>,
+    to_write_buf: &[u8],
+) {
+    // a half-written record must be finished by the writer before anything else goes on the wire
+    if !to_write_buf.is_empty() {
+        return;
+    }
+    if let Some(timer) = dummy_timer {
+        if timer.poll_unpin(cx).is_ready() {
+            let spec = state.padding();
+            if dummy_buf.is_empty() {
+                state.encrypt_padding(spec.dummy_len(), dummy_buf);
+            }
+            if let Some(interval) = spec.next_dummy_interval() {
+                timer.set_after(interval);
+                let _ = timer.poll_unpin(cx);
+            }
+        }
+    }
+    if !dummy_buf.is_empty() {
+        if let Poll::Ready(Ok(n)) = lower.as_mut().poll_write(cx, dummy_buf) {
+            dummy_buf.drain(..n);
+        }
+    }
+}
+
+impl<P: Pipe> SosistabPipe<P> {
+    /// Writes out the plaintext in `bufs` as a single record, returning how many plaintext bytes it carried.
+    fn poll_write_bufs(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
     ) -> Poll<std::io::Result<usize>> {
         // This implementation here is technically incorrect, if the caller doesn't poll the *same* buffer until completion.
         // But it seems like it's not possible to be technically correct without spawning a background thread and introducing an extra copy, and this is pretty hot code.
 
         let mut this = self.project();
         if this.to_write_buf.is_empty() {
-            this.state.encrypt(buf, this.to_write_buf);
+            // dummy traffic that's already encrypted must go out before any new records
+            while !this.dummy_buf.is_empty() {
+                let n = futures_util::ready!(this.lower.as_mut().poll_write(cx, this.dummy_buf))?;
+                this.dummy_buf.drain(..n);
+            }
+            let spec = this.state.padding();
+            // several small buffers get coalesced into one record, so that they share the per-record overhead
+            let mut bufs = bufs.iter().filter(|b| !b.is_empty());
+            let plain_n = match (bufs.next(), bufs.next()) {
+                (None, _) => {
+                    this.state.encrypt(&[], this.to_write_buf);
+                    0
+                }
+                (Some(first), None) => {
+                    let plain_n = first.len().min(spec.max_burst);
+                    this.state.encrypt(&first[..plain_n], this.to_write_buf);
+                    plain_n
+                }
+                (Some(first), Some(second)) => {
+                    this.coalesce_buf.clear();
+                    for buf in [first, second].into_iter().chain(bufs) {
+                        let room = spec.max_burst - this.coalesce_buf.len();
+                        this.coalesce_buf
+                            .extend_from_slice(&buf[..buf.len().min(room)]);
+                        if this.coalesce_buf.len() == spec.max_burst {
+                            break;
+                        }
+                    }
+                    this.state.encrypt(this.coalesce_buf, this.to_write_buf);
+                    this.coalesce_buf.len()
+                }
+            };
+            let padding = spec.padding_for(this.to_write_buf.len());
+            if padding > 0 {
+                this.state
+                    .encrypt_padding(padding - RECORD_OVERHEAD, this.to_write_buf);
+            }
+            *this.to_write_plain = plain_n;
         }
         loop {
             tracing::trace!(bytes_to_write = this.to_write_buf.len(), "polling write");
@@ -129,7 +277,7 @@ impl<P: Pipe> AsyncWrite for SosistabPipe<P> {
                     tracing::trace!(
                         bytes_to_write = this.to_write_buf.len(),
                         just_wrote = n,
-                        plain_n = buf.len(),
+                        plain_n = *this.to_write_plain,
                         "successfully wrote"
                     );
                     this.to_write_buf.drain(..n);
@@ -139,20 +287,45 @@ impl<P: Pipe> AsyncWrite for SosistabPipe<P> {
                             just_wrote = n,
                             "returning Ready from write"
                         );
-                        return Poll::Ready(Ok(buf.len()));
+                        return Poll::Ready(Ok(*this.to_write_plain));
                     }
                 }
                 Err(err) => return Poll::Ready(Err(err)),
             }
         }
     }
+}
+
+impl<P: Pipe> AsyncWrite for SosistabPipe<P> {
+    #[tracing::instrument(name = "sosistab_write", skip(self, cx, buf))]
+    fn poll_write(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        self.poll_write_bufs(cx, &[IoSlice::new(buf)])
+    }
+
+    #[tracing::instrument(name = "sosistab_write_vectored", skip(self, cx, bufs))]
+    fn poll_write_vectored(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+        self.poll_write_bufs(cx, bufs)
+    }
 
     fn poll_flush(
         self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> Poll<std::io::Result<()>> {
         let mut this = self.project();
-        if !this.to_write_buf.is_empty() {
+        if this.to_write_buf.is_empty() {
+            while !this.dummy_buf.is_empty() {
+                let n = futures_util::ready!(this.lower.as_mut().poll_write(cx, this.dummy_buf))?;
+                this.dummy_buf.drain(..n);
+            }
+        } else {
             match futures_util::ready!(this.lower.as_mut().poll_write(cx, this.to_write_buf)) {
                 Ok(n) => {
                     this.to_write_buf.drain(..n);
@@ -189,6 +362,14 @@ impl<P: Pipe> AsyncRead for SosistabPipe<P> {
                 tracing::trace!(buf_len = this.read_buf.len(), "reading from the read_buf");
                 return Poll::Ready(this.read_buf.read(buf));
             } else {
+                poll_dummy(
+                    this.lower.as_mut(),
+                    cx,
+                    this.state,
+                    this.dummy_timer,
+                    this.dummy_buf,
+                    this.to_write_buf,
+                );
                 // we reuse buf as a temporary buffer
                 let n = futures_util::ready!(this.lower.as_mut().poll_read(cx, buf));
                 match n {
@@ -205,6 +386,25 @@ impl<P: Pipe> AsyncRead for SosistabPipe<P> {
                             buf_len = this.read_buf.len(),
                             "read returned from lower"
                         );
+                        if let Some((cookie, my_handshake_hash, _)) = this.awaiting_confirm {
+                            match resume::check_confirmation(
+                                this.raw_read_buf,
+                                *cookie,
+                                *my_handshake_hash,
+                            )? {
+                                Some((n, their_handshake)) => {
+                                    this.raw_read_buf.drain(..n);
+                                    let (_, _, eph_sk) = this.awaiting_confirm.take().unwrap();
+                                    let dh = eph_sk.diffie_hellman(&their_handshake.eph_pk);
+                                    let ss = resume::upgraded_secret(
+                                        this.state.shared_secret(),
+                                        dh.as_bytes(),
+                                    );
+                                    this.state.upgrade(&ss);
+                                }
+                                None => continue,
+                            }
+                        }
                         // attempt to decrypt in order to fill the read_buf. we decrypt as many fragments as possible until we cannot decrypt anymore. at that point, we would need more fresh data to decrypt more.
                         loop {
                             match this.state.decrypt(this.raw_read_buf, &mut this.read_buf) {
@@ -252,3 +452,344 @@ impl<P: Pipe> Pipe for SosistabPipe<P> {
         Some(self.state.shared_secret())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::IoSlice;
+
+    use futures_util::{AsyncReadExt, AsyncWriteExt};
+    use sillad::{
+        dialer::Dialer,
+        listener::Listener,
+        tcp::{TcpDialer, TcpListener},
+        Pipe,
+    };
+
+    use crate::{
+        dialer::SosistabDialer, listener::SosistabListener, Cookie, CookieSet, ObfsParams,
+        PaddingProfile, ProbeAction,
+    };
+
+    /// Sends a bunch of data through an echo server over a sosistab3 pipe with the given parameters, reading and writing at the same time.
+    async fn echo_roundtrip(params: ObfsParams) {
+        let cookie = Cookie::random_with_params(params);
+        let tcp_listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let dest_addr = tcp_listener.local_addr().await;
+        let mut listener = SosistabListener::new(tcp_listener, cookie);
+        let dialer = SosistabDialer {
+            inner: TcpDialer { dest_addr },
+            cookie,
+        };
+
+        const CHUNKS: usize = 500;
+        let server = smolscale::spawn(async move {
+            let pipe = listener.accept().await.unwrap();
+            let (mut read, mut write) = pipe.split();
+            // the client hangs up on us at the end, so errors are expected here
+            let _ = futures_util::io::copy(&mut read, &mut write).await;
+        });
+
+        let pipe = dialer.dial().await.unwrap();
+        let (mut read, mut write) = pipe.split();
+        let writer = smolscale::spawn(async move {
+            for i in 0..CHUNKS {
+                write.write_all(&[i as u8; 1000]).await.unwrap();
+            }
+            write.flush().await.unwrap();
+            write
+        });
+        let mut received = vec![0u8; CHUNKS * 1000];
+        read.read_exact(&mut received).await.unwrap();
+        for (i, chunk) in received.chunks(1000).enumerate() {
+            assert!(chunk.iter().all(|b| *b == i as u8));
+        }
+        drop(writer.await);
+        drop(server);
+    }
+
+    #[test]
+    fn test_rekey_during_concurrent_io() {
+        async_io::block_on(echo_roundtrip(ObfsParams {
+            rekey_bytes: 10_000,
+            ..Default::default()
+        }))
+    }
+
+    #[test]
+    fn test_padding_profiles() {
+        for padding in [
+            PaddingProfile::Uniform,
+            PaddingProfile::Web,
+            PaddingProfile::Stream,
+            PaddingProfile::Chatty,
+        ] {
+            async_io::block_on(echo_roundtrip(ObfsParams {
+                padding,
+                ..Default::default()
+            }))
+        }
+    }
+
+    #[test]
+    fn test_tls_mimicry() {
+        async_io::block_on(echo_roundtrip(ObfsParams {
+            tls_mimicry: true,
+            padding: PaddingProfile::Web,
+            ..Default::default()
+        }))
+    }
+
+    #[test]
+    fn test_resumption() {
+        async_io::block_on(async {
+            let cookie = Cookie::random_with_params(ObfsParams {
+                resumption: true,
+                ..Default::default()
+            });
+            let tcp_listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let dest_addr = tcp_listener.local_addr().await;
+            let mut listener = SosistabListener::new(tcp_listener, cookie);
+            let dialer = SosistabDialer {
+                inner: TcpDialer { dest_addr },
+                cookie,
+            };
+            let _server = smolscale::spawn(async move {
+                loop {
+                    let pipe = listener.accept().await.unwrap();
+                    smolscale::spawn(async move {
+                        let (mut read, mut write) = pipe.split();
+                        let _ = futures_util::io::copy(&mut read, &mut write).await;
+                    })
+                    .detach();
+                }
+            });
+
+            // the first connection does a full handshake, and gets a ticket in return
+            let mut first = dialer.dial().await.unwrap();
+            assert!(first.awaiting_confirm.is_none());
+            first.write_all(b"hello").await.unwrap();
+            let mut buf = [0u8; 5];
+            first.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello");
+
+            // the second one resumes, sending data before hearing from the server
+            let mut second = dialer.dial().await.unwrap();
+            assert!(second.awaiting_confirm.is_some());
+            second.write_all(b"world").await.unwrap();
+            second.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"world");
+            assert!(second.awaiting_confirm.is_none());
+            assert_ne!(first.shared_secret(), second.shared_secret());
+            // after the server's confirmation, both directions are on the forward-secure keys
+            second.write_all(b"again").await.unwrap();
+            second.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"again");
+        })
+    }
+
+    #[test]
+    fn test_resumption_is_opt_in() {
+        async_io::block_on(async {
+            let cookie = Cookie::random();
+            let tcp_listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let dest_addr = tcp_listener.local_addr().await;
+            let mut listener = SosistabListener::new(tcp_listener, cookie);
+            let dialer = SosistabDialer {
+                inner: TcpDialer { dest_addr },
+                cookie,
+            };
+            let _server = smolscale::spawn(async move {
+                loop {
+                    let pipe = listener.accept().await.unwrap();
+                    smolscale::spawn(async move {
+                        let (mut read, mut write) = pipe.split();
+                        let _ = futures_util::io::copy(&mut read, &mut write).await;
+                    })
+                    .detach();
+                }
+            });
+
+            for _ in 0..2 {
+                let mut pipe = dialer.dial().await.unwrap();
+                assert!(pipe.awaiting_confirm.is_none());
+                pipe.write_all(b"hello").await.unwrap();
+                let mut buf = [0u8; 5];
+                pipe.read_exact(&mut buf).await.unwrap();
+                assert_eq!(&buf, b"hello");
+            }
+        })
+    }
+
+    #[test]
+    fn test_hybrid_pq() {
+        async_io::block_on(echo_roundtrip(ObfsParams {
+            hybrid_pq: true,
+            ..Default::default()
+        }))
+    }
+
+    #[test]
+    fn test_probe_mirror() {
+        async_io::block_on(async {
+            let tcp_listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let dest_addr = tcp_listener.local_addr().await;
+            let _listener = SosistabListener::with_probe_action(
+                tcp_listener,
+                Cookie::random(),
+                ProbeAction::Mirror,
+            );
+
+            // garbage that doesn't decrypt should just be echoed back
+            let mut probe = TcpDialer { dest_addr }.dial().await.unwrap();
+            let garbage: Vec<u8> = (0..1000).map(|_| rand::random()).collect();
+            probe.write_all(&garbage).await.unwrap();
+            let mut echoed = vec![0u8; garbage.len()];
+            probe.read_exact(&mut echoed).await.unwrap();
+            assert_eq!(echoed, garbage);
+        })
+    }
+    #[test]
+    fn test_cookie_rotation() {
+        async_io::block_on(async {
+            let tcp_listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let dest_addr = tcp_listener.local_addr().await;
+            let (first, second, third) = (Cookie::random(), Cookie::random(), Cookie::random());
+            let cookies = CookieSet::new(first);
+            let mut listener =
+                SosistabListener::with_cookies(tcp_listener, cookies.clone(), ProbeAction::Close);
+            let _server = smolscale::spawn(async move {
+                loop {
+                    let pipe = listener.accept().await.unwrap();
+                    smolscale::spawn(async move {
+                        let (mut read, mut write) = pipe.split();
+                        let _ = futures_util::io::copy(&mut read, &mut write).await;
+                    })
+                    .detach();
+                }
+            });
+            let ping = |cookie: Cookie| async move {
+                let mut pipe = SosistabDialer {
+                    inner: TcpDialer { dest_addr },
+                    cookie,
+                }
+                .dial()
+                .await?;
+                pipe.write_all(b"hello").await?;
+                pipe.flush().await?;
+                let mut buf = [0u8; 5];
+                pipe.read_exact(&mut buf).await?;
+                assert_eq!(&buf, b"hello");
+                std::io::Result::Ok(pipe)
+            };
+
+            let mut established = ping(first).await.unwrap();
+            cookies.rotate(second);
+            // both the current and the previous cookie work
+            ping(second).await.unwrap();
+            ping(first).await.unwrap();
+            cookies.rotate(third);
+            // the cookie from two rotations ago no longer works
+            assert!(ping(first).await.is_err());
+            ping(second).await.unwrap();
+            // but connections established with it are unaffected
+            established.write_all(b"world").await.unwrap();
+            established.flush().await.unwrap();
+            let mut buf = [0u8; 5];
+            established.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"world");
+        })
+    }
+    #[test]
+    fn test_vectored_write() {
+        async_io::block_on(async {
+            let cookie = Cookie::random_with_params(ObfsParams {
+                padding: PaddingProfile::Chatty,
+                ..Default::default()
+            });
+            let tcp_listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let dest_addr = tcp_listener.local_addr().await;
+            let mut listener = SosistabListener::new(tcp_listener, cookie);
+            let dialer = SosistabDialer {
+                inner: TcpDialer { dest_addr },
+                cookie,
+            };
+            let server = smolscale::spawn(async move {
+                let mut pipe = listener.accept().await.unwrap();
+                let mut received = vec![0u8; 100 * 50];
+                pipe.read_exact(&mut received).await.unwrap();
+                received
+            });
+
+            // lots of small slices, more than fit into one record of the chatty profile
+            let slices: Vec<Vec<u8>> = (0..100u8).map(|i| vec![i; 50]).collect();
+            let mut pipe = dialer.dial().await.unwrap();
+            let mut offset = 0;
+            while offset < 100 * 50 {
+                let mut bufs = vec![];
+                let mut skip = offset;
+                for slice in slices.iter() {
+                    if skip >= slice.len() {
+                        skip -= slice.len();
+                        continue;
+                    }
+                    bufs.push(IoSlice::new(&slice[skip..]));
+                    skip = 0;
+                }
+                let n = pipe.write_vectored(&bufs).await.unwrap();
+                assert!(n > 0);
+                offset += n;
+            }
+            pipe.flush().await.unwrap();
+            assert_eq!(server.await, slices.concat());
+        })
+    }
+    #[test]
+    fn test_pipe_stats() {
+        async_io::block_on(async {
+            let cookie = Cookie::random_with_params(ObfsParams {
+                padding: PaddingProfile::Web,
+                ..Default::default()
+            });
+            let tcp_listener = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let dest_addr = tcp_listener.local_addr().await;
+            let mut listener = SosistabListener::new(tcp_listener, cookie);
+            let dialer = SosistabDialer {
+                inner: TcpDialer { dest_addr },
+                cookie,
+            };
+            let server = smolscale::spawn(async move {
+                let mut pipe = listener.accept().await.unwrap();
+                let mut buf = [0u8; 1000];
+                pipe.read_exact(&mut buf).await.unwrap();
+                pipe
+            });
+            let mut pipe = dialer.dial().await.unwrap();
+            pipe.write_all(&[1u8; 1000]).await.unwrap();
+            pipe.flush().await.unwrap();
+            let server_pipe = server.await;
+
+            let sent = pipe.stats();
+            assert_eq!(sent.data_bytes_sent, 1000);
+            assert!(sent.wire_bytes_sent > 1000);
+            assert!(sent.padding_overhead() > 0.0 && sent.padding_overhead() < 1.0);
+            assert_eq!(sent.decrypt_failures, 0);
+            let received = server_pipe.stats();
+            assert_eq!(received.data_bytes_received, 1000);
+            assert!(received.records_received >= 2);
+        })
+    }
+}
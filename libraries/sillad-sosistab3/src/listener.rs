@@ -1,5 +1,4 @@
 use std::{
-    collections::{HashSet, VecDeque},
     io::ErrorKind,
     sync::Mutex,
     time::{Duration, SystemTime, UNIX_EPOCH},
@@ -16,7 +15,20 @@ use sillad::{listener::Listener, Pipe};
 use tachyonix::{Receiver, Sender};
 use tap::Tap;
 
-use crate::{dedup::Dedup, handshake::Handshake, state::State, Cookie, SosistabPipe};
+use crate::{
+    dedup::Dedup,
+    framing::Framed,
+    handshake::Handshake,
+    pq,
+    probe::ProbeAction,
+    replay::{ReplayFilter, REPLAY_WINDOW_SECS},
+    resume::{
+        open_ticket, resumed_shared_secret, resumption_secret, seal_ticket, upgraded_secret,
+        Ticket, TICKET_LEN,
+    },
+    state::State,
+    Cookie, CookieSet, SosistabPipe,
+};
 
 /// A sosistab3 listener.
 pub struct SosistabListener<P: Pipe> {
@@ -27,17 +39,36 @@ pub struct SosistabListener<P: Pipe> {
 impl<P: Pipe> SosistabListener<P> {
     /// Listens to incoming sosistab3 pipes by wrapping an existing sillad Listener.
     pub fn new(listener: impl Listener<P = P>, cookie: Cookie) -> Self {
+        Self::with_probe_action(listener, cookie, ProbeAction::default())
+    }
+
+    /// Like [SosistabListener::new], but with a specific way of dealing with connections that fail the handshake.
+    pub fn with_probe_action(
+        listener: impl Listener<P = P>,
+        cookie: Cookie,
+        probe_action: ProbeAction,
+    ) -> Self {
+        Self::with_cookies(listener, CookieSet::new(cookie), probe_action)
+    }
+
+    /// Like [SosistabListener::with_probe_action], but accepts every cookie in a [CookieSet]. Rotating the set takes effect for all subsequent connections, without disturbing the established ones.
+    pub fn with_cookies(
+        listener: impl Listener<P = P>,
+        cookies: CookieSet,
+        probe_action: ProbeAction,
+    ) -> Self {
         let (send_pipe, recv_pipe) = tachyonix::channel(1);
-        let _task = smolscale::spawn(listen_loop(listener, send_pipe, cookie));
+        let _task = smolscale::spawn(listen_loop(listener, send_pipe, cookies, probe_action));
         Self { recv_pipe, _task }
     }
 }
 
-#[tracing::instrument(skip(listener, send_pipe))]
+#[tracing::instrument(skip(listener, send_pipe, cookies))]
 async fn listen_loop<P: Pipe>(
     mut listener: impl Listener<P = P>,
     send_pipe: Sender<SosistabPipe<P>>,
-    cookie: Cookie,
+    cookies: CookieSet,
+    probe_action: ProbeAction,
 ) -> std::io::Result<()> {
     const WAIT_INTERVAL: Duration = Duration::from_secs(30);
 
@@ -51,77 +82,116 @@ async fn listen_loop<P: Pipe>(
     lexec
         .run(async {
             loop {
-                let mut lower = listener.accept().await?;
+                let lower = listener.accept().await?;
                 let send_pipe = send_pipe.clone();
+                let cookies = cookies.all();
                 lexec
                     .spawn(async move {
-                        let current_timestamp = SystemTime::now()
-                            .duration_since(UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs();
-                        // receive their handshake
-                        let mut their_handshake = [0u8; 140];
-                        lower.read_exact(&mut their_handshake).await?;
-                        let their_handshake_hash = blake3::hash(&their_handshake);
-                        let their_handshake = Handshake::decrypt(their_handshake, cookie, false)?;
-                        tracing::debug!(
-                            their_handshake_hash = debug(their_handshake_hash),
-                            "handshake received"
-                        );
-                        dedup_handshake(current_timestamp, their_handshake)?;
-                        // read their padding
-                        let mut buff = vec![0u8; their_handshake.padding_len as usize];
-                        lower.read_exact(&mut buff).await?;
-                        if blake3::hash(&buff) != their_handshake.padding_hash {
-                            return Err(std::io::Error::new(
-                                ErrorKind::InvalidData,
-                                "the client handshake gave us an incorrect padding hash",
-                            ));
-                        }
-                        tracing::debug!(
-                            their_handshake_hash = debug(their_handshake_hash),
-                            their_padding_hash = debug(their_handshake.padding_hash),
-                            "handshake verified"
-                        );
-                        {
-                            let mut dedup = dedup.lock().unwrap();
-                            if dedup.contains(&their_handshake_hash) {
-                                return Err(std::io::Error::new(
-                                    ErrorKind::InvalidData,
-                                    "handshake already seen",
-                                ));
+                        // receive and check their handshake. if anything's amiss, we act like some other service
+                        let mut consumed = vec![];
+                        // every cookie in the set has the same params, so any of them tells us the framing
+                        let mut lower = if cookies[0].params.tls_mimicry {
+                            match Framed::tls_server(lower, &mut consumed).await {
+                                Ok(lower) => lower,
+                                Err((lower, err)) => {
+                                    tracing::debug!(
+                                        err = debug(&err),
+                                        probe_action = debug(probe_action),
+                                        "bad fake TLS handshake"
+                                    );
+                                    probe_action.act(lower, consumed).await?;
+                                    return Err(err);
+                                }
                             }
-                            dedup.insert(their_handshake_hash);
-                        }
+                        } else {
+                            Framed::plain(lower)
+                        };
+                        consumed.clear();
+                        let client =
+                            match read_client_handshake(&mut lower, &mut consumed, &cookies, dedup)
+                                .await
+                            {
+                                Ok(v) => v,
+                                Err(err) => {
+                                    tracing::debug!(
+                                        err = debug(&err),
+                                        probe_action = debug(probe_action),
+                                        "bad client handshake"
+                                    );
+                                    probe_action.act(lower, consumed).await?;
+                                    return Err(err);
+                                }
+                            };
+                        // everything from here on uses the cookie that the client picked
+                        let cookie = client.cookie;
+                        let their_handshake = client.handshake;
+                        let their_handshake_hash = client.hash;
+                        // in hybrid post-quantum mode, we encapsulate a secret to the client's key
+                        let pq_encapsulated = match &client.pq_ek {
+                            Some(ek) => Some(pq::server_encapsulate(ek)?),
+                            None => None,
+                        };
                         // send the upstream handshake
                         let eph_sk =
                             x25519_dalek::EphemeralSecret::random_from_rng(rand::thread_rng());
                         let eph_pk: x25519_dalek::PublicKey = (&eph_sk).into();
-                        // we generate a whole lot of random padding
-                        let padding_len: u64 = rand::thread_rng().gen_range(0..=8192);
-                        let padding = vec![0; padding_len as usize]
+                        // we generate a whole lot of random padding, with the ML-KEM ciphertext at the start if there is one
+                        let min_padding = if pq_encapsulated.is_some() {
+                            pq::CT_LEN as u64
+                        } else {
+                            0
+                        };
+                        let padding_len: u64 =
+                            rand::thread_rng().gen_range(min_padding..=8192 + min_padding);
+                        let mut padding = vec![0; padding_len as usize]
                             .tap_mut(|v| rand::thread_rng().fill_bytes(v));
+                        if let Some((ct, _)) = &pq_encapsulated {
+                            padding[..pq::CT_LEN].copy_from_slice(ct);
+                            pq::mask(
+                                pq::CT_MASK,
+                                &cookie,
+                                &[their_handshake.eph_pk.as_bytes(), eph_pk.as_bytes()].concat(),
+                                &mut padding[..pq::CT_LEN],
+                            );
+                        }
                         let padding_hash = blake3::hash(&padding);
                         // generate the handshake
                         let my_handshake = Handshake {
                             eph_pk,
-                            timestamp: current_timestamp,
+                            timestamp: SystemTime::now()
+                                .duration_since(UNIX_EPOCH)
+                                .unwrap()
+                                .as_secs(),
                             padding_len,
                             padding_hash,
                             responding_to: their_handshake_hash,
                         };
-                        // send the stuff
+                        // we are ready for the shared secret
+                        let mut state = if let Some(resumed_ss) = client.resumed_ss {
+                            // the early data is under the ticket's keys, but everything after our handshake mixes in a fresh exchange
+                            let dh = eph_sk.diffie_hellman(&their_handshake.eph_pk);
+                            let mut state = State::new(&resumed_ss, true, cookie.params);
+                            state.upgrade(&upgraded_secret(&resumed_ss, dh.as_bytes()));
+                            state
+                        } else {
+                            let mut ss = *eph_sk.diffie_hellman(&their_handshake.eph_pk).as_bytes();
+                            if let Some((_, pq_secret)) = &pq_encapsulated {
+                                ss = pq::hybrid_secret(&ss, pq_secret);
+                            }
+                            State::new(&ss, true, cookie.params)
+                        };
+                        // send the stuff, together with our hello and, if resumption is on, a ticket for next time
                         let mut to_send = vec![];
                         let my_handshake = my_handshake.encrypt(cookie, true);
                         to_send.extend_from_slice(&my_handshake);
                         to_send.extend_from_slice(&padding);
+                        state.encrypt_hello(&mut to_send);
+                        if cookie.params.resumption {
+                            let ticket =
+                                seal_ticket(cookie, &resumption_secret(state.ticket_secret()));
+                            state.encrypt_ticket(ticket, &mut to_send);
+                        }
                         lower.write_all(&to_send).await?;
-                        // we are ready for the shared secret
-                        let state = State::new(
-                            eph_sk.diffie_hellman(&their_handshake.eph_pk).as_bytes(),
-                            true,
-                            cookie.params,
-                        );
                         tracing::debug!(
                             their_handshake_hash = debug(their_handshake_hash),
                             their_padding_hash = debug(their_handshake.padding_hash),
@@ -137,6 +207,128 @@ async fn listen_loop<P: Pipe>(
         .await
 }
 
+/// A client handshake that checked out.
+struct ClientHandshake {
+    cookie: Cookie,
+    handshake: Handshake,
+    hash: blake3::Hash,
+    // the shared secret, if the client is resuming an earlier session
+    resumed_ss: Option<[u8; 32]>,
+    // the client's ML-KEM encapsulation key, in hybrid post-quantum mode
+    pq_ek: Option<Vec<u8>>,
+}
+
+/// Reads and verifies the client's handshake, recording every byte read into `consumed`.
+async fn read_client_handshake<P: Pipe>(
+    lower: &mut P,
+    consumed: &mut Vec<u8>,
+    cookies: &[Cookie],
+    dedup: &Mutex<Dedup<blake3::Hash>>,
+) -> std::io::Result<ClientHandshake> {
+    let mut raw_handshake = [0u8; 140];
+    lower.read_exact(&mut raw_handshake).await?;
+    // taken only now, since the client controls how long we wait for the handshake
+    let current_timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    consumed.extend_from_slice(&raw_handshake);
+    let their_handshake_hash = blake3::hash(&raw_handshake);
+    let (cookie, their_handshake) = cookies
+        .iter()
+        .find_map(|cookie| {
+            Handshake::decrypt(raw_handshake, *cookie, false)
+                .ok()
+                .map(|hs| (*cookie, hs))
+        })
+        .ok_or_else(|| {
+            std::io::Error::new(ErrorKind::InvalidData, "handshake matches no cookie")
+        })?;
+    tracing::debug!(
+        their_handshake_hash = debug(their_handshake_hash),
+        "handshake received"
+    );
+    dedup_handshake(current_timestamp, their_handshake)?;
+    // read their padding
+    let mut buff = vec![0u8; their_handshake.padding_len as usize];
+    lower.read_exact(&mut buff).await?;
+    consumed.extend_from_slice(&buff);
+    if blake3::hash(&buff) != their_handshake.padding_hash {
+        return Err(std::io::Error::new(
+            ErrorKind::InvalidData,
+            "the client handshake gave us an incorrect padding hash",
+        ));
+    }
+    tracing::debug!(
+        their_handshake_hash = debug(their_handshake_hash),
+        their_padding_hash = debug(their_handshake.padding_hash),
+        "handshake verified"
+    );
+    {
+        let mut dedup = dedup.lock().unwrap();
+        if dedup.contains(&their_handshake_hash) {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "handshake already seen",
+            ));
+        }
+        dedup.insert(their_handshake_hash);
+    }
+    // a client handshake that responds to something is resuming a session, with the ticket at the start of its padding
+    let resumed_ss = if their_handshake.responding_to != blake3::hash(b"") {
+        if !cookie.params.resumption {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidData,
+                "resumption is not enabled",
+            ));
+        }
+        let ticket: &Ticket = buff
+            .get(..TICKET_LEN)
+            .and_then(|t| <&Ticket>::try_from(t).ok())
+            .filter(|t| blake3::hash(*t) == their_handshake.responding_to)
+            .ok_or_else(|| {
+                std::io::Error::new(ErrorKind::InvalidData, "resumption ticket missing")
+            })?;
+        let secret = open_ticket(cookie, ticket).ok_or_else(|| {
+            std::io::Error::new(
+                ErrorKind::InvalidData,
+                "resumption ticket invalid or expired",
+            )
+        })?;
+        tracing::debug!(
+            their_handshake_hash = debug(their_handshake_hash),
+            "resuming session"
+        );
+        Some(resumed_shared_secret(&secret, &raw_handshake))
+    } else {
+        None
+    };
+    let pq_ek = if resumed_ss.is_none() && cookie.params.hybrid_pq {
+        let mut ek = buff
+            .get(..pq::EK_LEN)
+            .ok_or_else(|| {
+                std::io::Error::new(ErrorKind::InvalidData, "encapsulation key missing")
+            })?
+            .to_vec();
+        pq::mask(
+            pq::EK_MASK,
+            &cookie,
+            their_handshake.eph_pk.as_bytes(),
+            &mut ek,
+        );
+        Some(ek)
+    } else {
+        None
+    };
+    Ok(ClientHandshake {
+        cookie,
+        handshake: their_handshake,
+        hash: their_handshake_hash,
+        resumed_ss,
+        pq_ek,
+    })
+}
+
 #[async_trait]
 impl<P: Pipe> Listener for SosistabListener<P> {
     type P = SosistabPipe<P>;
@@ -151,39 +343,19 @@ impl<P: Pipe> Listener for SosistabListener<P> {
 }
 
 fn dedup_handshake(current_timestamp: u64, handshake: Handshake) -> std::io::Result<()> {
-    if current_timestamp.abs_diff(handshake.timestamp) > 600 {
-        return Err(std::io::Error::new(
-            ErrorKind::InvalidData,
-            "current timestamp too far away from handshake time",
-        ));
-    }
-    // deduplicate now
-    static DEDUP_MAP: Lazy<Mutex<(VecDeque<(Handshake, u64)>, HashSet<Handshake>)>> =
-        Lazy::new(Default::default);
+    static REPLAY_FILTER: Lazy<Mutex<ReplayFilter>> =
+        Lazy::new(|| Mutex::new(ReplayFilter::new(REPLAY_WINDOW_SECS)));
 
-    let mut dedup_map = DEDUP_MAP.lock().unwrap();
-    let (handshake_list, handshake_set) = &mut *dedup_map;
-
-    // Check if the handshake is already seen
-    if handshake_set.contains(&handshake) {
+    // stale and replayed handshakes get exactly the same treatment as garbage that fails to decrypt, so that probes learn nothing
+    if !REPLAY_FILTER
+        .lock()
+        .unwrap()
+        .check_and_insert(current_timestamp, &handshake)
+    {
         return Err(std::io::Error::new(
             ErrorKind::InvalidData,
-            "handshake already seen",
+            "handshake is stale or replayed",
         ));
     }
-
-    // Insert the new handshake
-    handshake_list.push_back((handshake, current_timestamp));
-    handshake_set.insert(handshake);
-
-    // Remove outdated handshakes
-    while let Some((old_handshake, timestamp)) = handshake_list.front() {
-        if current_timestamp.abs_diff(*timestamp) <= 600 {
-            break;
-        }
-        handshake_set.remove(old_handshake);
-        handshake_list.pop_front();
-    }
-
     Ok(())
 }
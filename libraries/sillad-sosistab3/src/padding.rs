@@ -0,0 +1,161 @@
+use std::time::Duration;
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Overhead of a single record on the wire: the encrypted length, its tag, and the body tag.
+pub const RECORD_OVERHEAD: usize = 4 + 16 + 16;
+
+/// A named padding profile, selected through the cookie suffix. Profiles shape the lengths of records on the wire, how much data goes into a single burst, and how much dummy traffic is sent while the pipe is idle.
+#[derive(Clone, Copy, Default, Deserialize, Serialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PaddingProfile {
+    /// No padding at all.
+    #[default]
+    None,
+    /// Record lengths uniformly distributed up to a typical MTU.
+    Uniform,
+    /// Records sized like those of a typical HTTPS web browsing session.
+    Web,
+    /// Full-sized records with light cover traffic, resembling a video stream.
+    Stream,
+    /// Small records with constant cover traffic, resembling an interactive session.
+    Chatty,
+}
+
+/// The distribution of record lengths on the wire.
+#[derive(Clone, Copy, Debug)]
+pub enum LengthDist {
+    /// Leave lengths alone.
+    Unchanged,
+    /// Pad to a uniformly random length in the given range.
+    Uniform(usize, usize),
+    /// Pad to the smallest bucket that fits, choosing randomly between it and the next one up.
+    Buckets(&'static [usize]),
+}
+
+/// The concrete parameters behind a padding profile.
+#[derive(Clone, Copy, Debug)]
+pub struct PaddingSpec {
+    /// How long records on the wire should be.
+    pub lengths: LengthDist,
+    /// The most plaintext bytes that go into one record. Larger writes are split into several bursts.
+    pub max_burst: usize,
+    /// Average bytes per second of dummy traffic sent while waiting for data. Zero disables dummy traffic.
+    pub dummy_rate: u64,
+}
+
+impl PaddingProfile {
+    /// Returns the parameters behind this profile.
+    pub fn spec(&self) -> PaddingSpec {
+        match self {
+            PaddingProfile::None => PaddingSpec {
+                lengths: LengthDist::Unchanged,
+                max_burst: usize::MAX,
+                dummy_rate: 0,
+            },
+            PaddingProfile::Uniform => PaddingSpec {
+                lengths: LengthDist::Uniform(64, 1460),
+                max_burst: usize::MAX,
+                dummy_rate: 0,
+            },
+            PaddingProfile::Web => PaddingSpec {
+                lengths: LengthDist::Buckets(&[517, 1400, 4096, 16384]),
+                max_burst: 16384 - RECORD_OVERHEAD,
+                dummy_rate: 0,
+            },
+            PaddingProfile::Stream => PaddingSpec {
+                lengths: LengthDist::Buckets(&[1448, 2896, 14480]),
+                max_burst: 14480 - RECORD_OVERHEAD,
+                dummy_rate: 2000,
+            },
+            PaddingProfile::Chatty => PaddingSpec {
+                lengths: LengthDist::Uniform(100, 600),
+                max_burst: 600 - RECORD_OVERHEAD,
+                dummy_rate: 5000,
+            },
+        }
+    }
+}
+
+impl PaddingSpec {
+    /// Given how many bytes a record takes on the wire, returns how many bytes of padding record should follow it. Zero means no padding record.
+    pub fn padding_for(&self, record_len: usize) -> usize {
+        let target = match self.lengths {
+            LengthDist::Unchanged => return 0,
+            LengthDist::Uniform(lo, hi) => rand::thread_rng().gen_range(lo..=hi),
+            LengthDist::Buckets(buckets) => {
+                // the padding record itself has overhead, so a bucket only fits if it leaves room for one
+                let Some(idx) = buckets
+                    .iter()
+                    .position(|b| *b == record_len || *b >= record_len + RECORD_OVERHEAD)
+                else {
+                    return 0;
+                };
+                let idx = (idx + rand::thread_rng().gen_range(0..=1)).min(buckets.len() - 1);
+                buckets[idx]
+            }
+        };
+        let gap = target.saturating_sub(record_len);
+        if gap >= RECORD_OVERHEAD {
+            gap
+        } else {
+            0
+        }
+    }
+
+    /// Returns the body length of one dummy record.
+    pub fn dummy_len(&self) -> usize {
+        match self.lengths {
+            LengthDist::Unchanged => 512,
+            _ => self.padding_for(0).saturating_sub(RECORD_OVERHEAD),
+        }
+    }
+
+    /// Returns how long to wait before the next dummy record, or None if dummy traffic is disabled.
+    pub fn next_dummy_interval(&self) -> Option<Duration> {
+        if self.dummy_rate == 0 {
+            return None;
+        }
+        let mean = (self.dummy_len() + RECORD_OVERHEAD) as f64 / self.dummy_rate as f64;
+        // exponentially distributed, so that dummy traffic isn't periodic
+        let sample = -mean * (1.0 - rand::thread_rng().gen::<f64>()).ln();
+        Some(Duration::from_secs_f64(sample.min(mean * 10.0)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profile_names() {
+        let profile: PaddingProfile = serde_json::from_str("\"web\"").unwrap();
+        assert_eq!(profile, PaddingProfile::Web);
+        assert_eq!(
+            serde_json::to_string(&PaddingProfile::Chatty).unwrap(),
+            "\"chatty\""
+        );
+    }
+
+    #[test]
+    fn test_buckets() {
+        let spec = PaddingProfile::Web.spec();
+        for record_len in [RECORD_OVERHEAD, 100, 500, 1000, 5000] {
+            let total = record_len + spec.padding_for(record_len);
+            assert!(
+                [517, 1400, 4096, 16384].contains(&total),
+                "{record_len} -> {total}"
+            );
+        }
+        // records that already fill the biggest bucket are left alone
+        assert_eq!(spec.padding_for(16384), 0);
+    }
+
+    #[test]
+    fn test_no_padding() {
+        let spec = PaddingProfile::None.spec();
+        assert_eq!(spec.padding_for(100), 0);
+        assert!(spec.next_dummy_interval().is_none());
+    }
+}
@@ -0,0 +1,118 @@
+use ml_kem::{
+    kem::{Decapsulate, Encapsulate},
+    Ciphertext, EncodedSizeUser, KemCore, MlKem768,
+};
+
+use crate::Cookie;
+
+/// Length of an ML-KEM-768 encapsulation key, which the client puts at the start of its padding.
+pub const EK_LEN: usize = 1184;
+/// Length of an ML-KEM-768 ciphertext, which the server puts at the start of its padding.
+pub const CT_LEN: usize = 1088;
+
+/// Labels for [mask], one for each direction.
+pub const EK_MASK: &str = "sosistab3 pq ek mask";
+pub const CT_MASK: &str = "sosistab3 pq ct mask";
+
+pub type DecapsulationKey = <MlKem768 as KemCore>::DecapsulationKey;
+
+type EncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+
+fn bad_kem(what: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("bad ML-KEM {what}"),
+    )
+}
+
+/// Generates a fresh client keypair, returning the decapsulation key and the encoded encapsulation key.
+pub fn client_keypair() -> (DecapsulationKey, Vec<u8>) {
+    let (dk, ek) = MlKem768::generate(&mut rand::thread_rng());
+    (dk, ek.as_bytes().to_vec())
+}
+
+/// Encapsulates a fresh secret to the client's encapsulation key, returning the ciphertext and the secret.
+pub fn server_encapsulate(ek: &[u8]) -> std::io::Result<(Vec<u8>, [u8; 32])> {
+    let ek = EncapsulationKey::from_bytes(
+        ek.get(..EK_LEN)
+            .and_then(|ek| ek.try_into().ok())
+            .ok_or_else(|| bad_kem("encapsulation key"))?,
+    );
+    let (ct, secret) = ek
+        .encapsulate(&mut rand::thread_rng())
+        .map_err(|_| bad_kem("encapsulation key"))?;
+    Ok((ct.to_vec(), secret.into()))
+}
+
+/// Recovers the secret from the server's ciphertext.
+pub fn client_decapsulate(dk: &DecapsulationKey, ct: &[u8]) -> std::io::Result<[u8; 32]> {
+    let ct: Ciphertext<MlKem768> = ct
+        .get(..CT_LEN)
+        .and_then(|ct| ct.try_into().ok())
+        .ok_or_else(|| bad_kem("ciphertext"))?;
+    let secret = dk.decapsulate(&ct).map_err(|_| bad_kem("ciphertext"))?;
+    Ok(secret.into())
+}
+
+/// Masks, or unmasks, ML-KEM material that rides in the padding. Encapsulation keys and ciphertexts are far from uniform, since every 12-bit coefficient of a key is below 3329, so sent as they are, they would stand out from the random padding around them. XORing them with a keystream keyed by the cookie, and bound to the handshake's ephemeral keys in `context`, makes them look random to anybody without the cookie.
+pub fn mask(label: &str, cookie: &Cookie, context: &[u8], buf: &mut [u8]) {
+    let mut keystream = vec![0u8; buf.len()];
+    blake3::Hasher::new_keyed(&blake3::derive_key(label, &cookie.key))
+        .update(context)
+        .finalize_xof()
+        .fill(&mut keystream);
+    for (b, k) in buf.iter_mut().zip(keystream) {
+        *b ^= k;
+    }
+}
+
+/// Combines the classical and post-quantum secrets, so that the result is safe as long as either one is.
+pub fn hybrid_secret(x25519_secret: &[u8; 32], mlkem_secret: &[u8; 32]) -> [u8; 32] {
+    let mut input = x25519_secret.to_vec();
+    input.extend_from_slice(mlkem_secret);
+    blake3::derive_key("hybrid", &input)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_kem_round_trip() {
+        let (dk, ek) = client_keypair();
+        assert_eq!(ek.len(), EK_LEN);
+        let (ct, server_secret) = server_encapsulate(&ek).unwrap();
+        assert_eq!(ct.len(), CT_LEN);
+        let client_secret = client_decapsulate(&dk, &ct).unwrap();
+        assert_eq!(server_secret, client_secret);
+        assert!(server_encapsulate(&ek[..100]).is_err());
+    }
+
+    /// The 12-bit coefficients packed into an encapsulation key, leaving out the seed at the end.
+    fn coefficients(ek: &[u8]) -> impl Iterator<Item = u16> + '_ {
+        ek[..EK_LEN - 32].chunks(3).flat_map(|c| {
+            let (a, b, c) = (c[0] as u16, c[1] as u16, c[2] as u16);
+            [a | ((b & 0xf) << 8), (b >> 4) | (c << 4)]
+        })
+    }
+
+    #[test]
+    fn test_masked_ek_is_unbiased() {
+        let cookie = Cookie::random();
+        let (_, ek) = client_keypair();
+        // a raw key never has a coefficient of 3329 or more, which random bytes do about 19% of the time
+        assert!(coefficients(&ek).all(|coeff| coeff < 3329));
+        let mut masked = ek.clone();
+        mask(EK_MASK, &cookie, b"context", &mut masked);
+        let total = coefficients(&masked).count();
+        let high = coefficients(&masked).filter(|&coeff| coeff >= 3329).count();
+        assert!(high * 10 > total, "{high} of {total} coefficients are high");
+        // and every bit position is set about half the time
+        for bit in 0..8 {
+            let set = masked.iter().filter(|b| *b & (1 << bit) != 0).count();
+            assert!((400..=784).contains(&set), "bit {bit} is set {set} times");
+        }
+        mask(EK_MASK, &cookie, b"context", &mut masked);
+        assert_eq!(masked, ek);
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    net::SocketAddr,
+    pin::pin,
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use futures_util::{future::Either, AsyncReadExt, AsyncWriteExt, Future};
+use sillad::{dialer::Dialer, tcp::TcpDialer, Pipe};
+
+/// How long we keep up the act before hanging up on a probe.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// How many probe connections we keep up the act for at once, across all listeners. Past this, we just close them, so that a flood of bogus connections can't pile up sockets for the whole timeout.
+const MAX_HELD_PROBES: usize = 1000;
+
+static HELD_PROBES: AtomicUsize = AtomicUsize::new(0);
+
+/// What a listener does with a connection whose handshake doesn't check out. Anything other than closing makes the listener look like some generic TCP service to an active prober, rather than something that hangs up after exactly one handshake's worth of bytes.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ProbeAction {
+    /// Close the connection immediately.
+    #[default]
+    Close,
+    /// Silently read and discard everything, until the other side gives up.
+    ReadForever,
+    /// Echo everything back, like an echo service.
+    Mirror,
+    /// Transparently proxy the connection to a decoy server.
+    Decoy(SocketAddr),
+}
+
+impl FromStr for ProbeAction {
+    type Err = anyhow::Error;
+
+    /// Parses one of `close`, `read_forever`, `mirror`, or `decoy:<address>`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "close" => Ok(Self::Close),
+            "read_forever" => Ok(Self::ReadForever),
+            "mirror" => Ok(Self::Mirror),
+            s => {
+                let addr = s
+                    .strip_prefix("decoy:")
+                    .ok_or_else(|| anyhow::anyhow!("unknown probe action {s}"))?;
+                Ok(Self::Decoy(addr.parse()?))
+            }
+        }
+    }
+}
+
+impl ProbeAction {
+    /// Deals with a failed connection, given everything that has been read from it so far.
+    pub(crate) async fn act<P: Pipe>(self, mut lower: P, consumed: Vec<u8>) -> std::io::Result<()> {
+        if self == ProbeAction::Close {
+            return Ok(());
+        }
+        let Some(_held) = HeldProbe::acquire() else {
+            tracing::debug!("too many probe connections held, closing instead");
+            return Ok(());
+        };
+        match self {
+            ProbeAction::Close => Ok(()),
+            ProbeAction::ReadForever => {
+                with_timeout(async {
+                    let mut buf = [0u8; 4096];
+                    while lower.read(&mut buf).await? > 0 {}
+                    Ok(())
+                })
+                .await
+            }
+            ProbeAction::Mirror => {
+                with_timeout(async {
+                    lower.write_all(&consumed).await?;
+                    let (mut read, mut write) = lower.split();
+                    futures_util::io::copy(&mut read, &mut write).await?;
+                    Ok(())
+                })
+                .await
+            }
+            ProbeAction::Decoy(dest_addr) => {
+                with_timeout(async {
+                    let mut decoy = TcpDialer { dest_addr }.dial().await?;
+                    decoy.write_all(&consumed).await?;
+                    let (mut lower_read, mut lower_write) = lower.split();
+                    let (mut decoy_read, mut decoy_write) = decoy.split();
+                    let up = futures_util::io::copy(&mut lower_read, &mut decoy_write);
+                    let down = futures_util::io::copy(&mut decoy_read, &mut lower_write);
+                    match futures_util::future::select(pin!(up), pin!(down)).await {
+                        Either::Left((res, _)) | Either::Right((res, _)) => res?,
+                    };
+                    Ok(())
+                })
+                .await
+            }
+        }
+    }
+}
+
+/// One of the [MAX_HELD_PROBES] slots, given back on drop.
+struct HeldProbe;
+
+impl HeldProbe {
+    fn acquire() -> Option<Self> {
+        HELD_PROBES
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| {
+                (n < MAX_HELD_PROBES).then_some(n + 1)
+            })
+            .ok()
+            .map(|_| Self)
+    }
+}
+
+impl Drop for HeldProbe {
+    fn drop(&mut self) {
+        HELD_PROBES.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+async fn with_timeout(fut: impl Future<Output = std::io::Result<()>>) -> std::io::Result<()> {
+    let timer = async_io::Timer::after(PROBE_TIMEOUT);
+    match futures_util::future::select(pin!(fut), timer).await {
+        Either::Left((res, _)) => res,
+        Either::Right(_) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_probe_action() {
+        assert_eq!("close".parse::<ProbeAction>().unwrap(), ProbeAction::Close);
+        assert_eq!(
+            "mirror".parse::<ProbeAction>().unwrap(),
+            ProbeAction::Mirror
+        );
+        assert_eq!(
+            "decoy:127.0.0.1:80".parse::<ProbeAction>().unwrap(),
+            ProbeAction::Decoy("127.0.0.1:80".parse().unwrap())
+        );
+        assert!("decoy:nonsense".parse::<ProbeAction>().is_err());
+        assert!("explode".parse::<ProbeAction>().is_err());
+        assert_eq!(ProbeAction::default(), ProbeAction::Close);
+    }
+
+    #[test]
+    fn test_held_probes_capped() {
+        let held: Vec<_> = std::iter::from_fn(HeldProbe::acquire)
+            .take(MAX_HELD_PROBES + 1)
+            .collect();
+        // other tests may be holding a few slots themselves
+        assert!(held.len() <= MAX_HELD_PROBES);
+        drop(held);
+        assert!(HeldProbe::acquire().is_some());
+    }
+}
@@ -0,0 +1,147 @@
+use crate::handshake::Handshake;
+
+/// How far, in seconds, a handshake timestamp may be from our own clock before it's rejected outright.
+pub const REPLAY_WINDOW_SECS: u64 = 600;
+
+const BLOOM_BITS: usize = 1 << 22;
+const BLOOM_HASHES: usize = 6;
+
+/// A replay window for handshakes. Handshakes with timestamps outside the window are rejected, while handshakes within the window are remembered in a pair of rotating bloom filters.
+///
+/// Every handshake stays in the filter for at least as long as its timestamp remains acceptable, so a captured handshake can never be replayed successfully. False positives only cause a fresh handshake to be refused, and are astronomically rare at realistic handshake rates.
+pub struct ReplayFilter {
+    window_secs: u64,
+    current: Bloom,
+    previous: Bloom,
+    current_epoch: u64,
+}
+
+impl ReplayFilter {
+    /// Creates a new replay filter with the given window, in seconds.
+    pub fn new(window_secs: u64) -> Self {
+        Self {
+            window_secs,
+            current: Bloom::new(),
+            previous: Bloom::new(),
+            current_epoch: 0,
+        }
+    }
+
+    /// Checks a handshake against the window, recording it if it's fresh. Returns false if the handshake should be rejected.
+    pub fn check_and_insert(&mut self, current_timestamp: u64, handshake: &Handshake) -> bool {
+        if current_timestamp.abs_diff(handshake.timestamp) > self.window_secs {
+            return false;
+        }
+        self.rotate(current_timestamp);
+        let key = handshake.replay_key();
+        if self.current.contains(&key) || self.previous.contains(&key) {
+            return false;
+        }
+        self.current.insert(&key);
+        true
+    }
+
+    fn rotate(&mut self, current_timestamp: u64) {
+        // an entry must survive for 2x the window, since a handshake can be accepted from window seconds in the future until window seconds in the past. keeping two generations of 2x the window each guarantees that.
+        let epoch = current_timestamp / (self.window_secs * 2).max(1);
+        // a timestamp from an earlier epoch, say from a connection that was slow on purpose, must never wipe what we remember
+        if epoch <= self.current_epoch {
+            return;
+        }
+        if epoch == self.current_epoch + 1 {
+            self.previous = std::mem::replace(&mut self.current, Bloom::new());
+        } else {
+            self.previous = Bloom::new();
+            self.current = Bloom::new();
+        }
+        self.current_epoch = epoch;
+    }
+}
+
+struct Bloom {
+    bits: Vec<u64>,
+}
+
+impl Bloom {
+    fn new() -> Self {
+        Self {
+            bits: vec![0; BLOOM_BITS / 64],
+        }
+    }
+
+    fn indices(key: &[u8; 32]) -> impl Iterator<Item = usize> + '_ {
+        (0..BLOOM_HASHES).map(move |i| {
+            let word = u64::from_le_bytes(key[i * 4..][..8].try_into().unwrap());
+            (word % BLOOM_BITS as u64) as usize
+        })
+    }
+
+    fn insert(&mut self, key: &[u8; 32]) {
+        for idx in Self::indices(key) {
+            self.bits[idx / 64] |= 1 << (idx % 64);
+        }
+    }
+
+    fn contains(&self, key: &[u8; 32]) -> bool {
+        Self::indices(key).all(|idx| self.bits[idx / 64] & (1 << (idx % 64)) != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::OsRng;
+    use x25519_dalek::EphemeralSecret;
+
+    fn handshake(timestamp: u64) -> Handshake {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        Handshake {
+            eph_pk: x25519_dalek::PublicKey::from(&secret),
+            timestamp,
+            padding_len: 0,
+            padding_hash: blake3::hash(b""),
+            responding_to: blake3::hash(b""),
+        }
+    }
+
+    #[test]
+    fn test_rejects_replay() {
+        let mut filter = ReplayFilter::new(REPLAY_WINDOW_SECS);
+        let hs = handshake(10_000);
+        assert!(filter.check_and_insert(10_000, &hs));
+        assert!(!filter.check_and_insert(10_001, &hs));
+        assert!(filter.check_and_insert(10_001, &handshake(10_000)));
+    }
+
+    #[test]
+    fn test_rejects_stale_timestamps() {
+        let mut filter = ReplayFilter::new(REPLAY_WINDOW_SECS);
+        assert!(!filter.check_and_insert(10_000, &handshake(10_000 - REPLAY_WINDOW_SECS - 1)));
+        assert!(!filter.check_and_insert(10_000, &handshake(10_000 + REPLAY_WINDOW_SECS + 1)));
+    }
+
+    #[test]
+    fn test_remembers_across_rotation() {
+        let mut filter = ReplayFilter::new(REPLAY_WINDOW_SECS);
+        // a handshake from the far edge of the future, inserted right before a rotation
+        let start = REPLAY_WINDOW_SECS * 2 - 1;
+        let hs = handshake(start + REPLAY_WINDOW_SECS);
+        assert!(filter.check_and_insert(start, &hs));
+        // it must still be rejected for as long as its timestamp is acceptable
+        for now in start..=start + REPLAY_WINDOW_SECS * 2 {
+            assert!(!filter.check_and_insert(now, &hs));
+        }
+    }
+
+    #[test]
+    fn test_never_rotates_backwards() {
+        let mut filter = ReplayFilter::new(REPLAY_WINDOW_SECS);
+        let now = REPLAY_WINDOW_SECS * 20;
+        let hs = handshake(now);
+        assert!(filter.check_and_insert(now, &hs));
+        // a connection that was timestamped in the previous epoch shows up late
+        let earlier = now - REPLAY_WINDOW_SECS * 2;
+        filter.check_and_insert(earlier, &handshake(earlier));
+        assert!(!filter.check_and_insert(now, &hs));
+    }
+}
@@ -0,0 +1,221 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use arrayref::array_ref;
+use chacha20poly1305::{
+    aead::{AeadInPlace, OsRng},
+    AeadCore, ChaCha20Poly1305, KeyInit,
+};
+use once_cell::sync::Lazy;
+
+use crate::{handshake::Handshake, Cookie};
+
+/// Length of a resumption ticket on the wire: a nonce, the sealed secret and expiry, and a tag.
+pub const TICKET_LEN: usize = 12 + 40 + 16;
+
+/// How long a resumption ticket stays valid.
+const TICKET_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// An opaque resumption ticket. The server seals the resumption secret into it with a key that only the server knows, so that knowing the cookie, which every client does, doesn't open tickets seen on the wire.
+pub type Ticket = [u8; TICKET_LEN];
+
+/// The secret that ticket keys are derived from. It starts out random, so unless the server sets a persisted one with [set_ticket_secret], tickets from before a restart no longer open, and the first dial that tries one fails.
+static TICKET_SECRET: Lazy<Mutex<[u8; 32]>> = Lazy::new(|| Mutex::new(rand::random()));
+
+/// Sets the secret that this process seals and opens resumption tickets with. The actual key rotates every ticket lifetime, but is derived from this secret alone, so a server that keeps the secret across restarts keeps accepting the tickets it handed out before.
+pub fn set_ticket_secret(secret: [u8; 32]) {
+    *TICKET_SECRET.lock().unwrap() = secret;
+}
+
+/// Derives the resumption secret from a session's shared secret. Both ends can compute this on their own.
+pub fn resumption_secret(ss: &[u8]) -> [u8; 32] {
+    blake3::derive_key("resumption", ss)
+}
+
+/// Derives the secret that a resumed session's early data is encrypted with, which is bound to the exact client handshake that resumed it.
+pub fn resumed_shared_secret(resumption_secret: &[u8; 32], client_handshake: &[u8]) -> [u8; 32] {
+    *blake3::keyed_hash(resumption_secret, client_handshake).as_bytes()
+}
+
+/// Mixes a fresh Diffie-Hellman result into a resumed session's secret. Everything after the early data uses this, so that a leaked ticket key can't decrypt it.
+pub fn upgraded_secret(resumed_ss: &[u8], dh: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = blake3::Hasher::new_derive_key("resumed session");
+    hasher.update(resumed_ss);
+    hasher.update(dh);
+    *hasher.finalize().as_bytes()
+}
+
+/// The key for tickets sealed during the given epoch, which is the time divided by the ticket lifetime.
+fn ticket_aead(epoch: u64) -> ChaCha20Poly1305 {
+    let mut hasher = blake3::Hasher::new_derive_key("ticket key");
+    hasher.update(&*TICKET_SECRET.lock().unwrap());
+    hasher.update(&epoch.to_be_bytes());
+    ChaCha20Poly1305::new_from_slice(hasher.finalize().as_bytes()).unwrap()
+}
+
+fn epoch(secs: u64) -> u64 {
+    secs / TICKET_LIFETIME.as_secs()
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Seals a resumption secret into a ticket that only opens under the same cookie.
+pub fn seal_ticket(cookie: Cookie, resumption_secret: &[u8; 32]) -> Ticket {
+    seal_ticket_at(cookie, resumption_secret, now_secs())
+}
+
+fn seal_ticket_at(cookie: Cookie, resumption_secret: &[u8; 32], now: u64) -> Ticket {
+    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let mut toret = [0u8; TICKET_LEN];
+    toret[..12].copy_from_slice(&nonce);
+    toret[12..][..32].copy_from_slice(resumption_secret);
+    toret[44..][..8].copy_from_slice(&(now + TICKET_LIFETIME.as_secs()).to_be_bytes());
+    let tag = ticket_aead(epoch(now))
+        .encrypt_in_place_detached(&nonce, &cookie.key, &mut toret[12..][..40])
+        .unwrap();
+    toret[52..].copy_from_slice(&tag);
+    toret
+}
+
+/// Opens a ticket, returning the resumption secret if the ticket is authentic and hasn't expired.
+pub fn open_ticket(cookie: Cookie, ticket: &Ticket) -> Option<[u8; 32]> {
+    open_ticket_at(cookie, ticket, now_secs())
+}
+
+fn open_ticket_at(cookie: Cookie, ticket: &Ticket, now: u64) -> Option<[u8; 32]> {
+    // a ticket lives for one lifetime, so it was sealed either in this epoch or the one before
+    let body = [epoch(now), epoch(now).saturating_sub(1)]
+        .into_iter()
+        .find_map(|epoch| {
+            let mut body = *array_ref![ticket, 12, 40];
+            ticket_aead(epoch)
+                .decrypt_in_place_detached(
+                    array_ref![ticket, 0, 12].into(),
+                    &cookie.key,
+                    &mut body,
+                    array_ref![ticket, 52, 16].into(),
+                )
+                .ok()?;
+            Some(body)
+        })?;
+    let expiry = u64::from_be_bytes(*array_ref![body, 32, 8]);
+    if expiry < now {
+        return None;
+    }
+    Some(*array_ref![body, 0, 32])
+}
+
+struct CachedTicket {
+    ticket: Ticket,
+    resumption_secret: [u8; 32],
+    expiry: SystemTime,
+}
+
+static TICKET_CACHE: Lazy<Mutex<HashMap<[u8; 32], CachedTicket>>> = Lazy::new(Default::default);
+
+/// Remembers a ticket the server gave us, together with the resumption secret it seals.
+pub fn store_ticket(cookie: Cookie, ticket: Ticket, resumption_secret: [u8; 32]) {
+    TICKET_CACHE.lock().unwrap().insert(
+        cookie.key,
+        CachedTicket {
+            ticket,
+            resumption_secret,
+            expiry: SystemTime::now() + TICKET_LIFETIME,
+        },
+    );
+}
+
+/// Takes a still-valid ticket for the given cookie out of the cache. Tickets are single-use, since a fresh one is issued on every connection.
+pub fn take_ticket(cookie: Cookie) -> Option<(Ticket, [u8; 32])> {
+    let cached = TICKET_CACHE.lock().unwrap().remove(&cookie.key)?;
+    // leave some slack, since the server's clock might be ahead of ours
+    if cached.expiry < SystemTime::now() + Duration::from_secs(60) {
+        return None;
+    }
+    Some((cached.ticket, cached.resumption_secret))
+}
+
+/// Checks the server's confirmation of a resumed session at the start of `raw`. Returns how many bytes it took up along with the server's handshake, or None if more bytes are needed.
+pub fn check_confirmation(
+    raw: &[u8],
+    cookie: Cookie,
+    my_handshake_hash: blake3::Hash,
+) -> std::io::Result<Option<(usize, Handshake)>> {
+    if raw.len() < 140 {
+        return Ok(None);
+    }
+    let their_handshake = Handshake::decrypt(*array_ref![raw, 0, 140], cookie, true)?;
+    if their_handshake.responding_to != my_handshake_hash {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "the server did not correctly confirm our resumed session",
+        ));
+    }
+    let total = 140 + their_handshake.padding_len as usize;
+    if raw.len() < total {
+        return Ok(None);
+    }
+    if blake3::hash(&raw[140..total]) != their_handshake.padding_hash {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "the server handshake gave us an incorrect padding hash",
+        ));
+    }
+    Ok(Some((total, their_handshake)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ticket_round_trip() {
+        let cookie = Cookie::random();
+        let secret = resumption_secret(b"hello world");
+        let ticket = seal_ticket(cookie, &secret);
+        assert_eq!(open_ticket(cookie, &ticket), Some(secret));
+        // another cookie can't open it
+        assert_eq!(open_ticket(Cookie::random(), &ticket), None);
+        // nor can a tampered ticket be opened
+        let mut tampered = ticket;
+        tampered[20] ^= 1;
+        assert_eq!(open_ticket(cookie, &tampered), None);
+    }
+
+    #[test]
+    fn test_tickets_survive_key_rotation() {
+        let cookie = Cookie::random();
+        let secret = resumption_secret(b"hello world");
+        let lifetime = TICKET_LIFETIME.as_secs();
+        // sealed at the very end of an epoch, the ticket must still open under the next epoch's key
+        let sealed_at = 1000 * lifetime - 1;
+        let ticket = seal_ticket_at(cookie, &secret, sealed_at);
+        assert_eq!(open_ticket_at(cookie, &ticket, sealed_at + 1), Some(secret));
+        assert_eq!(
+            open_ticket_at(cookie, &ticket, sealed_at + lifetime),
+            Some(secret)
+        );
+        // but not once it has expired
+        assert_eq!(
+            open_ticket_at(cookie, &ticket, sealed_at + lifetime + 1),
+            None
+        );
+    }
+
+    #[test]
+    fn test_tickets_are_single_use() {
+        let cookie = Cookie::random();
+        let secret = resumption_secret(b"hello world");
+        store_ticket(cookie, seal_ticket(cookie, &secret), secret);
+        assert!(take_ticket(cookie).is_some());
+        assert!(take_ticket(cookie).is_none());
+    }
+}
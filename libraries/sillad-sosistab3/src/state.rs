@@ -1,36 +1,62 @@
-use std::io::Write;
+use std::{
+    io::Write,
+    time::{Duration, Instant},
+};
 
 use arrayref::array_ref;
 use blake3::derive_key;
 use chacha20poly1305::{AeadInPlace, ChaCha20Poly1305, Key, KeyInit};
 use smallvec::{SmallVec, ToSmallVec};
 
-use crate::ObfsParams;
+use crate::{
+    control::Control,
+    padding::{PaddingSpec, RECORD_OVERHEAD},
+    resume::{self, Ticket},
+    Cookie, ObfsParams, PipeStats,
+};
+
+/// By default, rotate keys after a gibibyte of traffic in one direction.
+const DEFAULT_REKEY_BYTES: u64 = 1 << 30;
+/// By default, rotate keys every hour.
+const DEFAULT_REKEY_SECS: u64 = 3600;
+
+/// The tightest limits we let the other side impose, so that it can't make us rekey on every single write.
+const MIN_PEER_REKEY_BYTES: u64 = 1 << 20;
+const MIN_PEER_REKEY_SECS: u64 = 60;
 
 pub struct State {
+    is_server: bool,
     shared_secret: Vec<u8>,
+    send_key: [u8; 32],
     send_aead: ChaCha20Poly1305,
     send_nonce: u64,
     send_buf: Vec<u8>,
+    recv_key: [u8; 32],
     recv_aead: ChaCha20Poly1305,
     recv_nonce: u64,
 
     obfs_params: ObfsParams,
+
+    // the rekey limits the other side announced, if it supports rekeying at all
+    peer_rekey: Option<(u64, u64)>,
+    bytes_since_rekey: u64,
+    last_rekey: Instant,
+
+    ticket_cookie: Option<Cookie>,
+
+    // for a resumed session, the secret from the fresh key exchange, and whether each direction still has to switch to it
+    upgraded_secret: Option<[u8; 32]>,
+    send_upgrade_pending: bool,
+    recv_upgrade_pending: bool,
+
+    stats: PipeStats,
 }
 
 impl State {
     /// Derives a state from a given shared secret.
     #[tracing::instrument]
     pub fn new(ss: &[u8], is_server: bool, obfs_params: ObfsParams) -> Self {
-        let (send_key_label, recv_key_label) = if is_server {
-            ("dn", "up")
-        } else {
-            ("up", "dn")
-        };
-        let send_key = derive_key(send_key_label, ss);
-        let send_key = Key::from_slice(&send_key);
-        let recv_key = derive_key(recv_key_label, ss);
-        let recv_key = Key::from_slice(&recv_key);
+        let (send_key, recv_key) = directional_keys(ss, is_server);
 
         tracing::debug!(
             send_key = hex::encode(send_key),
@@ -38,24 +64,79 @@ impl State {
             "created a new state"
         );
 
-        let send_aead = ChaCha20Poly1305::new(send_key);
-        let recv_aead = ChaCha20Poly1305::new(recv_key);
+        let send_aead = ChaCha20Poly1305::new(Key::from_slice(&send_key));
+        let recv_aead = ChaCha20Poly1305::new(Key::from_slice(&recv_key));
 
         State {
+            is_server,
             shared_secret: ss.to_vec(),
+            send_key,
             send_aead,
             send_nonce: 0,
             send_buf: vec![],
+            recv_key,
             recv_aead,
             recv_nonce: 0,
             obfs_params,
+
+            peer_rekey: None,
+            bytes_since_rekey: 0,
+            last_rekey: Instant::now(),
+
+            ticket_cookie: None,
+
+            upgraded_secret: None,
+            send_upgrade_pending: false,
+            recv_upgrade_pending: false,
+
+            stats: PipeStats::default(),
         }
     }
 
+    /// Moves a resumed session from the ticket-derived keys to ones from a fresh key exchange. Records from the server after its handshake always use the new keys, so the server switches its sending side and the client its receiving side right away. The client may already have sent early data with the old keys, so it marks the switch of its sending side in-band.
+    pub fn upgrade(&mut self, ss: &[u8; 32]) {
+        let (send_key, recv_key) = directional_keys(ss, self.is_server);
+        if self.is_server {
+            self.set_send_key(send_key);
+            self.recv_upgrade_pending = true;
+        } else {
+            self.set_recv_key(recv_key);
+            self.send_upgrade_pending = true;
+        }
+        self.upgraded_secret = Some(*ss);
+        tracing::debug!("upgraded a resumed session");
+    }
+
+    /// The secret that resumption tickets are derived from. For a resumed session, that's the one from the fresh key exchange, so that every resumption is as forward-secure as the session it came from.
+    pub fn ticket_secret(&self) -> &[u8] {
+        self.upgraded_secret
+            .as_ref()
+            .map_or(&self.shared_secret, |ss| ss)
+    }
+
+    fn set_send_key(&mut self, key: [u8; 32]) {
+        self.send_key = key;
+        self.send_aead = ChaCha20Poly1305::new(Key::from_slice(&self.send_key));
+        self.send_nonce = 0;
+        self.bytes_since_rekey = 0;
+        self.last_rekey = Instant::now();
+    }
+
+    fn set_recv_key(&mut self, key: [u8; 32]) {
+        self.recv_key = key;
+        self.recv_aead = ChaCha20Poly1305::new(Key::from_slice(&self.recv_key));
+        self.recv_nonce = 0;
+    }
+
     pub fn shared_secret(&self) -> &[u8] {
         &self.shared_secret
     }
 
+    /// The traffic counters so far.
+    pub fn stats(&self) -> PipeStats {
+        self.stats
+    }
+
     fn send_nonce(&mut self) -> [u8; 12] {
         let mut nonce = [0u8; 12];
         nonce[..8].copy_from_slice(&self.send_nonce.to_le_bytes());
@@ -63,9 +144,88 @@ impl State {
         nonce
     }
 
+    /// Our own rekeying limits, in bytes and seconds.
+    fn rekey_limits(&self) -> (u64, u64) {
+        let bytes = match self.obfs_params.rekey_bytes {
+            0 => DEFAULT_REKEY_BYTES,
+            n => n,
+        };
+        let secs = match self.obfs_params.rekey_secs {
+            0 => DEFAULT_REKEY_SECS,
+            n => n,
+        };
+        (bytes, secs)
+    }
+
+    /// Whether or not the send direction is due for a new key. We only ever rekey if the other side told us it understands rekeying, and we use the stricter of the two sides' limits.
+    fn should_rekey(&self) -> bool {
+        let Some((peer_bytes, peer_secs)) = self.peer_rekey else {
+            return false;
+        };
+        let (my_bytes, my_secs) = self.rekey_limits();
+        self.bytes_since_rekey >= my_bytes.min(peer_bytes)
+            || self.last_rekey.elapsed() >= Duration::from_secs(my_secs.min(peer_secs))
+    }
+
+    /// Encrypts the hello message that must be sent to the other side before any data.
+    pub fn encrypt_hello(&mut self, output: &mut Vec<u8>) {
+        let (rekey_bytes, rekey_secs) = self.rekey_limits();
+        self.encrypt_record(
+            &Control::Hello {
+                rekey_bytes,
+                rekey_secs,
+            }
+            .encode(),
+            true,
+            output,
+        );
+    }
+
+    /// Encrypts a resumption ticket for the client.
+    pub fn encrypt_ticket(&mut self, ticket: Ticket, output: &mut Vec<u8>) {
+        self.encrypt_record(&Control::Ticket(ticket).encode(), true, output);
+    }
+
+    /// Makes tickets received from the server go into the client-side ticket cache, under the given cookie.
+    pub fn store_tickets_for(&mut self, cookie: Cookie) {
+        self.ticket_cookie = Some(cookie);
+    }
+
     /// Encrypts a hunk of data.
     pub fn encrypt(&mut self, bts: &[u8], output: &mut Vec<u8>) {
-        let mut length = (bts.len() as i32).to_le_bytes();
+        if self.send_upgrade_pending {
+            self.encrypt_record(&Control::Upgrade.encode(), true, output);
+            let (send_key, _) = directional_keys(self.ticket_secret(), self.is_server);
+            self.set_send_key(send_key);
+            self.send_upgrade_pending = false;
+            tracing::debug!("upgraded the send direction");
+        }
+        if self.should_rekey() {
+            self.encrypt_record(&Control::Rekey.encode(), true, output);
+            self.set_send_key(next_key(&self.send_key));
+            tracing::debug!("rekeyed the send direction");
+        }
+        self.bytes_since_rekey += bts.len() as u64;
+        self.stats.data_bytes_sent += bts.len() as u64;
+        self.encrypt_record(bts, false, output)
+    }
+
+    /// Encrypts a padding record with a body of the given length.
+    pub fn encrypt_padding(&mut self, body_len: usize, output: &mut Vec<u8>) {
+        self.encrypt_record(&vec![0u8; body_len], true, output)
+    }
+
+    /// Returns the padding parameters in use.
+    pub fn padding(&self) -> PaddingSpec {
+        self.obfs_params.padding_spec()
+    }
+
+    /// Encrypts a single record. Padding records are encoded with a negative length, and are never passed up to the application.
+    fn encrypt_record(&mut self, bts: &[u8], is_padding: bool, output: &mut Vec<u8>) {
+        self.stats.records_sent += 1;
+        self.stats.wire_bytes_sent += (bts.len() + RECORD_OVERHEAD) as u64;
+        let length = bts.len() as i32;
+        let mut length = if is_padding { -length } else { length }.to_le_bytes();
 
         // Pad the nonce to 96 bits (12 bytes)
         let nonce = self.send_nonce();
@@ -135,6 +295,7 @@ impl State {
                 array_ref![tag_length, 0, 16].into(),
             )
             .map_err(|e| {
+                self.stats.decrypt_failures += 1;
                 std::io::Error::new(
                     std::io::ErrorKind::BrokenPipe,
                     format!("decryption of the length failed: {e}"),
@@ -169,6 +330,7 @@ impl State {
         self.recv_aead
             .decrypt_in_place_detached(&nonce.into(), &[], &mut enc_body, tag_body.into())
             .map_err(|e| {
+                self.stats.decrypt_failures += 1;
                 std::io::Error::new(
                     std::io::ErrorKind::BrokenPipe,
                     format!("decrypt of the body of length {} failed: {e}", length),
@@ -176,12 +338,71 @@ impl State {
             })?;
 
         // Append the decrypted body to the output
+        self.recv_nonce += 2;
+        self.stats.records_received += 1;
+        self.stats.wire_bytes_received += (actual_length + RECORD_OVERHEAD) as u64;
         if length > 0 {
+            self.stats.data_bytes_received += actual_length as u64;
             output.write_all(&enc_body).unwrap();
+        } else if let Some(control) = Control::decode(&enc_body) {
+            self.handle_control(control);
         }
-        self.recv_nonce += 2;
         Ok(enc_length.len() + tag_length.len() + tag_body.len() + enc_body.len())
     }
+
+    fn handle_control(&mut self, control: Control) {
+        tracing::trace!(control = debug(control), "received a control message");
+        match control {
+            Control::Hello {
+                rekey_bytes,
+                rekey_secs,
+            } => {
+                self.peer_rekey = Some((
+                    rekey_bytes.max(MIN_PEER_REKEY_BYTES),
+                    rekey_secs.max(MIN_PEER_REKEY_SECS),
+                ))
+            }
+            Control::Rekey => {
+                self.set_recv_key(next_key(&self.recv_key));
+                tracing::debug!("rekeyed the receive direction");
+            }
+            Control::Ticket(ticket) => {
+                if let Some(cookie) = self.ticket_cookie {
+                    resume::store_ticket(
+                        cookie,
+                        ticket,
+                        resume::resumption_secret(self.ticket_secret()),
+                    );
+                }
+            }
+            Control::Upgrade => {
+                if self.recv_upgrade_pending {
+                    let (_, recv_key) = directional_keys(self.ticket_secret(), self.is_server);
+                    self.set_recv_key(recv_key);
+                    self.recv_upgrade_pending = false;
+                    tracing::debug!("upgraded the receive direction");
+                }
+            }
+        }
+    }
+}
+
+/// Derives the sending and receiving keys from a shared secret.
+fn directional_keys(ss: &[u8], is_server: bool) -> ([u8; 32], [u8; 32]) {
+    let (send_key_label, recv_key_label) = if is_server {
+        ("dn", "up")
+    } else {
+        ("up", "dn")
+    };
+    (
+        derive_key(send_key_label, ss),
+        derive_key(recv_key_label, ss),
+    )
+}
+
+/// Derives the key that follows the given key after a rekey.
+fn next_key(key: &[u8; 32]) -> [u8; 32] {
+    derive_key("rekey", key)
 }
 
 #[cfg(test)]
@@ -237,4 +458,62 @@ mod tests {
         assert_eq!(data1, decrypted_data1.as_slice());
         assert_eq!(data2, decrypted_data2.as_slice());
     }
+
+    #[test]
+    fn test_state_rekey() {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let shared_secret = x25519_dalek::PublicKey::from(&secret).as_bytes().to_vec();
+        let params = ObfsParams {
+            rekey_bytes: 100,
+            ..Default::default()
+        };
+
+        let mut client = State::new(&shared_secret, false, params);
+        let mut server = State::new(&shared_secret, true, params);
+
+        // the server needs to hear the client's hello, and vice versa, before anybody rekeys
+        let mut wire = vec![];
+        client.encrypt_hello(&mut wire);
+        let mut decrypted = vec![];
+        while !wire.is_empty() {
+            let n = server.decrypt(&wire, &mut decrypted).unwrap();
+            wire.drain(..n);
+        }
+        server.encrypt_hello(&mut wire);
+        while !wire.is_empty() {
+            let n = client.decrypt(&wire, &mut decrypted).unwrap();
+            wire.drain(..n);
+        }
+        let initial_send_key = client.send_key;
+
+        let mut expected = vec![];
+        for i in 0..100u8 {
+            let chunk = [i; 33];
+            expected.extend_from_slice(&chunk);
+            client.encrypt(&chunk, &mut wire);
+        }
+        let mut decrypted = vec![];
+        while !wire.is_empty() {
+            let n = server.decrypt(&wire, &mut decrypted).unwrap();
+            wire.drain(..n);
+        }
+        assert_eq!(decrypted, expected);
+        assert_ne!(client.send_key, initial_send_key);
+        assert_eq!(client.send_key, server.recv_key);
+    }
+
+    #[test]
+    fn test_peer_rekey_limits_clamped() {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let shared_secret = x25519_dalek::PublicKey::from(&secret).as_bytes().to_vec();
+        let mut state = State::new(&shared_secret, false, ObfsParams::default());
+        state.handle_control(Control::Hello {
+            rekey_bytes: 0,
+            rekey_secs: 0,
+        });
+        state.bytes_since_rekey = 1;
+        assert!(!state.should_rekey());
+        state.bytes_since_rekey = MIN_PEER_REKEY_BYTES;
+        assert!(state.should_rekey());
+    }
 }
@@ -0,0 +1,42 @@
+use std::ops::AddAssign;
+
+/// Traffic and crypto counters for a single sosistab3 pipe.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PipeStats {
+    /// Records sent, of every kind.
+    pub records_sent: u64,
+    /// Records received, of every kind.
+    pub records_received: u64,
+    /// Application bytes sent.
+    pub data_bytes_sent: u64,
+    /// Application bytes received.
+    pub data_bytes_received: u64,
+    /// Record bytes sent, including record overhead, padding, and control messages. The handshake and any fake TLS framing are not counted.
+    pub wire_bytes_sent: u64,
+    /// Record bytes received, counted the same way as `wire_bytes_sent`.
+    pub wire_bytes_received: u64,
+    /// Records that failed to authenticate. Since that is always fatal to the pipe, anything nonzero hints at tampering on the path.
+    pub decrypt_failures: u64,
+}
+
+impl PipeStats {
+    /// The fraction of the bytes we sent that were not application data.
+    pub fn padding_overhead(&self) -> f64 {
+        if self.wire_bytes_sent == 0 {
+            return 0.0;
+        }
+        1.0 - self.data_bytes_sent as f64 / self.wire_bytes_sent as f64
+    }
+}
+
+impl AddAssign for PipeStats {
+    fn add_assign(&mut self, rhs: Self) {
+        self.records_sent += rhs.records_sent;
+        self.records_received += rhs.records_received;
+        self.data_bytes_sent += rhs.data_bytes_sent;
+        self.data_bytes_received += rhs.data_bytes_received;
+        self.wire_bytes_sent += rhs.wire_bytes_sent;
+        self.wire_bytes_received += rhs.wire_bytes_received;
+        self.decrypt_failures += rhs.decrypt_failures;
+    }
+}
@@ -0,0 +1,413 @@
+use bipe::{BipeReader, BipeWriter};
+use ed25519_dalek::Signer;
+use futures_lite::{io::WriteHalf, AsyncReadExt, AsyncWriteExt, FutureExt};
+use sillad::Pipe;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::{
+    transport::*,
+    wire::{invalid_data, SshReader, SshWrite},
+    SshAuth, SshConfig,
+};
+
+/// The receive window we grant the server.
+const LOCAL_WINDOW: u32 = 2 * 1024 * 1024;
+
+/// The biggest data message we accept, and the biggest we send.
+const MAX_DATA: u32 = 32768;
+
+/// The channel number we use for our one channel.
+const LOCAL_CHANNEL: u32 = 0;
+
+/// The client side of an SSH transport: packets come in through a reader task, which keeps up with the keys, and go out directly.
+pub struct ClientTransport<P: Pipe> {
+    writer: PacketWriter<WriteHalf<P>>,
+    packets: async_channel::Receiver<std::io::Result<Vec<u8>>>,
+    rekey: async_channel::Sender<(PacketCipher, bool)>,
+    client_version: String,
+    server_version: String,
+    session_id: Option<[u8; 32]>,
+    strict: bool,
+    host_key: String,
+    _reader: async_task::Task<()>,
+}
+
+impl<P: Pipe> ClientTransport<P> {
+    /// Exchanges versions and keys over the lower pipe, checking the server's host key against the pinned fingerprint.
+    pub async fn connect(lower: P, host_key: String) -> std::io::Result<Self> {
+        let (mut read, mut write) = futures_lite::io::split(lower);
+        let server_version = exchange_versions(&mut read, &mut write).await?;
+        let (send_packet, packets) = async_channel::bounded(16);
+        let (rekey, recv_rekey) = async_channel::bounded(1);
+        let _reader = smolscale::spawn(read_loop(PacketReader::new(read), send_packet, recv_rekey));
+        let mut transport = Self {
+            writer: PacketWriter::new(write),
+            packets,
+            rekey,
+            client_version: VERSION.to_string(),
+            server_version,
+            session_id: None,
+            strict: false,
+            host_key,
+            _reader,
+        };
+        transport.kex(None).await?;
+        Ok(transport)
+    }
+
+    pub async fn send(&mut self, payload: &[u8]) -> std::io::Result<()> {
+        self.writer.write_packet(payload).await
+    }
+
+    /// Receives the next packet, dropping the ones that carry no meaning. Cancel-safe.
+    pub async fn recv_raw(&mut self) -> std::io::Result<Vec<u8>> {
+        loop {
+            let packet = self.packets.recv().await.map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::BrokenPipe, "SSH reader has shut down")
+            })??;
+            match packet[0] {
+                MSG_IGNORE | MSG_DEBUG | MSG_UNIMPLEMENTED => continue,
+                MSG_DISCONNECT => {
+                    let mut reader = SshReader::new(&packet[1..]);
+                    let _reason = reader.get_u32()?;
+                    let description = String::from_utf8_lossy(reader.get_string()?).to_string();
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::ConnectionAborted,
+                        format!("SSH server disconnected: {description}"),
+                    ));
+                }
+                _ => return Ok(packet),
+            }
+        }
+    }
+
+    /// Receives the next packet, taking care of key re-exchanges and global requests along the way.
+    pub async fn recv(&mut self) -> std::io::Result<Vec<u8>> {
+        loop {
+            let packet = self.recv_raw().await?;
+            if !self.handle_transport_message(&packet).await? {
+                return Ok(packet);
+            }
+        }
+    }
+
+    /// Handles packets that belong to the transport rather than to authentication or channels, returning whether this was one.
+    pub async fn handle_transport_message(&mut self, packet: &[u8]) -> std::io::Result<bool> {
+        match packet[0] {
+            MSG_KEXINIT => {
+                self.kex(Some(packet.to_vec())).await?;
+                Ok(true)
+            }
+            MSG_GLOBAL_REQUEST => {
+                let mut reader = SshReader::new(&packet[1..]);
+                let _name = reader.get_string()?;
+                if reader.get_bool()? {
+                    self.send(&[MSG_REQUEST_FAILURE]).await?;
+                }
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    async fn recv_expect(&mut self, msg: u8) -> std::io::Result<Vec<u8>> {
+        let packet = self.recv_raw().await?;
+        if packet[0] != msg {
+            return Err(invalid_data(format!(
+                "expected SSH message {msg}, got {}",
+                packet[0]
+            )));
+        }
+        Ok(packet)
+    }
+
+    /// Runs a key exchange, either our first one or one the server started with the given KEXINIT.
+    async fn kex(&mut self, their_kexinit: Option<Vec<u8>>) -> std::io::Result<()> {
+        let our_kexinit = build_kexinit(false);
+        self.send(&our_kexinit).await?;
+        let their_kexinit = match their_kexinit {
+            Some(kexinit) => kexinit,
+            None => self.recv_expect(MSG_KEXINIT).await?,
+        };
+        let strict = check_kexinit(&their_kexinit, false)?;
+        if self.session_id.is_none() {
+            self.strict = strict;
+        }
+
+        let secret = EphemeralSecret::random_from_rng(rand::thread_rng());
+        let our_public = PublicKey::from(&secret);
+        let mut init = vec![MSG_KEX_ECDH_INIT];
+        init.put_string(our_public.as_bytes());
+        self.send(&init).await?;
+
+        let reply = self.recv_expect(MSG_KEX_ECDH_REPLY).await?;
+        let mut reader = SshReader::new(&reply[1..]);
+        let host_key_blob = reader.get_string()?;
+        let their_public: [u8; 32] = reader
+            .get_string()?
+            .try_into()
+            .map_err(|_| invalid_data("bad curve25519 public key length"))?;
+        let signature = reader.get_string()?;
+
+        let host_key = parse_ed25519_key_blob(host_key_blob)?;
+        let fingerprint = fingerprint(host_key_blob);
+        if self.host_key.trim() != fingerprint {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                format!(
+                    "SSH host key {fingerprint} does not match the pinned {}",
+                    self.host_key
+                ),
+            ));
+        }
+
+        let shared = secret.diffie_hellman(&PublicKey::from(their_public));
+        if !shared.was_contributory() {
+            return Err(invalid_data("degenerate curve25519 public key"));
+        }
+        let hash = exchange_hash(
+            &[
+                self.client_version.as_bytes(),
+                self.server_version.as_bytes(),
+                &our_kexinit,
+                &their_kexinit,
+                host_key_blob,
+                our_public.as_bytes(),
+                &their_public,
+            ],
+            shared.as_bytes(),
+        );
+        verify_ed25519_signature(&host_key, &hash, signature)?;
+        let session_id = *self.session_id.get_or_insert(hash);
+        let (c2s, s2c) = derive_keys(shared.as_bytes(), &hash, &session_id);
+
+        self.send(&[MSG_NEWKEYS]).await?;
+        self.writer.rekey(c2s, self.strict);
+        let _ = self.rekey.send((s2c, self.strict)).await;
+        self.recv_expect(MSG_NEWKEYS).await?;
+        Ok(())
+    }
+
+    /// Logs in as the given user.
+    pub async fn authenticate(&mut self, config: &SshConfig) -> std::io::Result<()> {
+        let mut request = vec![MSG_SERVICE_REQUEST];
+        request.put_string(b"ssh-userauth");
+        self.send(&request).await?;
+        let accept = self.recv().await?;
+        if accept[0] != MSG_SERVICE_ACCEPT {
+            return Err(invalid_data("SSH server refused the userauth service"));
+        }
+
+        let mut request = vec![MSG_USERAUTH_REQUEST];
+        request.put_string(config.user.as_bytes());
+        request.put_string(b"ssh-connection");
+        match &config.auth {
+            SshAuth::Password(password) => {
+                request.put_string(b"password");
+                request.put_bool(false);
+                request.put_string(password.as_bytes());
+            }
+            SshAuth::Ed25519(key) => {
+                request.put_string(b"publickey");
+                request.put_bool(true);
+                request.put_string(b"ssh-ed25519");
+                request.put_string(&ed25519_key_blob(&key.verifying_key()));
+                // the signature covers the session id followed by the request so far
+                let mut signed = Vec::new();
+                signed.put_string(&self.session_id.unwrap());
+                signed.extend_from_slice(&request);
+                request.put_string(&ed25519_signature_blob(&key.sign(&signed)));
+            }
+        }
+        self.send(&request).await?;
+        loop {
+            let reply = self.recv().await?;
+            match reply[0] {
+                MSG_USERAUTH_BANNER => continue,
+                MSG_USERAUTH_SUCCESS => return Ok(()),
+                MSG_USERAUTH_FAILURE => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::PermissionDenied,
+                        "SSH server rejected our credentials",
+                    ))
+                }
+                other => {
+                    return Err(invalid_data(format!(
+                        "unexpected SSH message {other} during authentication"
+                    )))
+                }
+            }
+        }
+    }
+
+    /// Asks the server to connect to the given host and port, returning the server's channel number, window and maximum packet size.
+    pub async fn open_direct_tcpip(
+        &mut self,
+        host: &str,
+        port: u16,
+    ) -> std::io::Result<(u32, u32, u32)> {
+        let mut open = vec![MSG_CHANNEL_OPEN];
+        open.put_string(b"direct-tcpip");
+        open.put_u32(LOCAL_CHANNEL);
+        open.put_u32(LOCAL_WINDOW);
+        open.put_u32(MAX_DATA);
+        open.put_string(host.as_bytes());
+        open.put_u32(port as u32);
+        open.put_string(b"127.0.0.1");
+        open.put_u32(0);
+        self.send(&open).await?;
+        let reply = self.recv().await?;
+        let mut reader = SshReader::new(&reply[1..]);
+        match reply[0] {
+            MSG_CHANNEL_OPEN_CONFIRMATION => {
+                let _recipient = reader.get_u32()?;
+                let remote = reader.get_u32()?;
+                let window = reader.get_u32()?;
+                let max_packet = reader.get_u32()?;
+                Ok((remote, window, max_packet))
+            }
+            MSG_CHANNEL_OPEN_FAILURE => {
+                let _recipient = reader.get_u32()?;
+                let _reason = reader.get_u32()?;
+                let description = String::from_utf8_lossy(reader.get_string()?).to_string();
+                Err(std::io::Error::new(
+                    std::io::ErrorKind::ConnectionRefused,
+                    format!("SSH server could not open the tunnel: {description}"),
+                ))
+            }
+            other => Err(invalid_data(format!(
+                "unexpected SSH message {other} while opening a channel"
+            ))),
+        }
+    }
+}
+
+/// Reads packets into the channel, switching keys whenever a NEWKEYS message goes by.
+async fn read_loop<R: futures_lite::AsyncRead + Unpin>(
+    mut reader: PacketReader<R>,
+    send_packet: async_channel::Sender<std::io::Result<Vec<u8>>>,
+    recv_rekey: async_channel::Receiver<(PacketCipher, bool)>,
+) {
+    loop {
+        let packet = reader.read_packet().await;
+        let newkeys = matches!(&packet, Ok(p) if p[0] == MSG_NEWKEYS);
+        let failed = packet.is_err();
+        if send_packet.send(packet).await.is_err() || failed {
+            return;
+        }
+        if newkeys {
+            let Ok((cipher, strict)) = recv_rekey.recv().await else {
+                return;
+            };
+            reader.rekey(cipher, strict);
+        }
+    }
+}
+
+/// Shuttles bytes between the pipe halves and the channel until the channel closes.
+pub async fn channel_loop<P: Pipe>(
+    mut transport: ClientTransport<P>,
+    (remote, mut remote_window, remote_max): (u32, u32, u32),
+    mut up_reader: BipeReader,
+    mut down_writer: BipeWriter,
+) -> std::io::Result<()> {
+    enum Event {
+        Packet(std::io::Result<Vec<u8>>),
+        Up(std::io::Result<usize>),
+    }
+
+    let mut buf = vec![0u8; MAX_DATA as usize];
+    let mut unacked = 0u32;
+    let mut up_done = false;
+    let mut down_done = false;
+    let mut close_sent = false;
+    loop {
+        let limit = remote_window.min(remote_max).min(MAX_DATA) as usize;
+        let event = async { Event::Packet(transport.recv_raw().await) }
+            .or(async {
+                if up_done || limit == 0 {
+                    futures_lite::future::pending().await
+                } else {
+                    Event::Up(up_reader.read(&mut buf[..limit]).await)
+                }
+            })
+            .await;
+        match event {
+            Event::Up(Ok(0)) | Event::Up(Err(_)) => {
+                up_done = true;
+                let mut eof = vec![MSG_CHANNEL_EOF];
+                eof.put_u32(remote);
+                transport.send(&eof).await?;
+            }
+            Event::Up(Ok(n)) => {
+                remote_window -= n as u32;
+                let mut data = vec![MSG_CHANNEL_DATA];
+                data.put_u32(remote);
+                data.put_string(&buf[..n]);
+                transport.send(&data).await?;
+            }
+            Event::Packet(packet) => {
+                let packet = packet?;
+                if transport.handle_transport_message(&packet).await? {
+                    continue;
+                }
+                let mut reader = SshReader::new(&packet[1..]);
+                match packet[0] {
+                    MSG_CHANNEL_DATA | MSG_CHANNEL_EXTENDED_DATA => {
+                        let _recipient = reader.get_u32()?;
+                        if packet[0] == MSG_CHANNEL_EXTENDED_DATA {
+                            let _kind = reader.get_u32()?;
+                        }
+                        let data = reader.get_string()?;
+                        if packet[0] == MSG_CHANNEL_DATA
+                            && !down_done
+                            && down_writer.write_all(data).await.is_err()
+                        {
+                            // nobody is reading anymore, so the channel is done for
+                            down_done = true;
+                            close_sent = true;
+                            let mut close = vec![MSG_CHANNEL_CLOSE];
+                            close.put_u32(remote);
+                            transport.send(&close).await?;
+                        }
+                        unacked += data.len() as u32;
+                        if unacked >= LOCAL_WINDOW / 2 {
+                            let mut adjust = vec![MSG_CHANNEL_WINDOW_ADJUST];
+                            adjust.put_u32(remote);
+                            adjust.put_u32(unacked);
+                            transport.send(&adjust).await?;
+                            unacked = 0;
+                        }
+                    }
+                    MSG_CHANNEL_WINDOW_ADJUST => {
+                        let _recipient = reader.get_u32()?;
+                        remote_window = remote_window.saturating_add(reader.get_u32()?);
+                    }
+                    MSG_CHANNEL_EOF => {
+                        down_done = true;
+                        let _ = down_writer.close().await;
+                    }
+                    MSG_CHANNEL_CLOSE => {
+                        let _ = down_writer.close().await;
+                        if !close_sent {
+                            let mut close = vec![MSG_CHANNEL_CLOSE];
+                            close.put_u32(remote);
+                            let _ = transport.send(&close).await;
+                        }
+                        return Ok(());
+                    }
+                    MSG_CHANNEL_REQUEST => {
+                        let _recipient = reader.get_u32()?;
+                        let _name = reader.get_string()?;
+                        if reader.get_bool()? {
+                            let mut failure = vec![MSG_CHANNEL_FAILURE];
+                            failure.put_u32(remote);
+                            transport.send(&failure).await?;
+                        }
+                    }
+                    other => tracing::debug!(msg = other, "ignoring unexpected SSH message"),
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,160 @@
+//! Just enough of an SSH server to test the client against: it accepts one password or any Ed25519 key, and echoes whatever comes through a direct-tcpip channel.
+
+use ed25519_dalek::Signer;
+use sillad::Pipe;
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+use crate::{
+    transport::*,
+    wire::{invalid_data, SshReader, SshWrite},
+};
+
+const MSG_USERAUTH_PK_OK: u8 = 60;
+
+pub async fn serve(
+    pipe: impl Pipe,
+    host_key: ed25519_dalek::SigningKey,
+    password: &str,
+) -> std::io::Result<()> {
+    let (mut read, mut write) = futures_lite::io::split(pipe);
+    let client_version = exchange_versions(&mut read, &mut write).await?;
+    let mut reader = PacketReader::new(read);
+    let mut writer = PacketWriter::new(write);
+
+    let our_kexinit = build_kexinit(true);
+    writer.write_packet(&our_kexinit).await?;
+    let their_kexinit = reader.read_packet().await?;
+    let strict = check_kexinit(&their_kexinit, true)?;
+    let init = reader.read_packet().await?;
+    let their_public: [u8; 32] = SshReader::new(&init[1..])
+        .get_string()?
+        .try_into()
+        .map_err(|_| invalid_data("bad key"))?;
+    let secret = EphemeralSecret::random_from_rng(rand::thread_rng());
+    let our_public = PublicKey::from(&secret);
+    let shared = secret.diffie_hellman(&PublicKey::from(their_public));
+    let host_key_blob = ed25519_key_blob(&host_key.verifying_key());
+    let hash = exchange_hash(
+        &[
+            client_version.as_bytes(),
+            VERSION.as_bytes(),
+            &their_kexinit,
+            &our_kexinit,
+            &host_key_blob,
+            &their_public,
+            our_public.as_bytes(),
+        ],
+        shared.as_bytes(),
+    );
+    let mut reply = vec![MSG_KEX_ECDH_REPLY];
+    reply.put_string(&host_key_blob);
+    reply.put_string(our_public.as_bytes());
+    reply.put_string(&ed25519_signature_blob(&host_key.sign(&hash)));
+    writer.write_packet(&reply).await?;
+    writer.write_packet(&[MSG_NEWKEYS]).await?;
+    let (c2s, s2c) = derive_keys(shared.as_bytes(), &hash, &hash);
+    writer.rekey(s2c, strict);
+    if reader.read_packet().await? != [MSG_NEWKEYS] {
+        return Err(invalid_data("expected NEWKEYS"));
+    }
+    reader.rekey(c2s, strict);
+
+    let _service_request = reader.read_packet().await?;
+    let mut accept = vec![MSG_SERVICE_ACCEPT];
+    accept.put_string(b"ssh-userauth");
+    writer.write_packet(&accept).await?;
+    loop {
+        let request = reader.read_packet().await?;
+        let mut req = SshReader::new(&request[1..]);
+        let _user = req.get_string()?;
+        let _service = req.get_string()?;
+        let ok = match req.get_string()? {
+            b"password" => {
+                req.get_bool()?;
+                req.get_string()? == password.as_bytes()
+            }
+            b"publickey" => {
+                let has_signature = req.get_bool()?;
+                let algorithm = req.get_string()?;
+                let blob = req.get_string()?;
+                if !has_signature {
+                    let mut pk_ok = vec![MSG_USERAUTH_PK_OK];
+                    pk_ok.put_string(algorithm);
+                    pk_ok.put_string(blob);
+                    writer.write_packet(&pk_ok).await?;
+                    continue;
+                }
+                let signature = req.get_string()?;
+                let signed_len = request.len() - 4 - signature.len();
+                let mut signed = Vec::new();
+                signed.put_string(&hash);
+                signed.extend_from_slice(&request[..signed_len]);
+                verify_ed25519_signature(&parse_ed25519_key_blob(blob)?, &signed, signature).is_ok()
+            }
+            _ => false,
+        };
+        if ok {
+            writer.write_packet(&[MSG_USERAUTH_SUCCESS]).await?;
+            break;
+        }
+        let mut failure = vec![MSG_USERAUTH_FAILURE];
+        failure.put_string(b"password,publickey");
+        failure.put_bool(false);
+        writer.write_packet(&failure).await?;
+    }
+    // real servers send this right after login, and clients have to cope
+    let mut hostkeys = vec![MSG_GLOBAL_REQUEST];
+    hostkeys.put_string(b"hostkeys-00@openssh.com");
+    hostkeys.put_bool(false);
+    writer.write_packet(&hostkeys).await?;
+
+    let open = reader.read_packet().await?;
+    let mut req = SshReader::new(&open[1..]);
+    if req.get_string()? != b"direct-tcpip" {
+        return Err(invalid_data("expected direct-tcpip"));
+    }
+    let remote = req.get_u32()?;
+    let mut confirm = vec![MSG_CHANNEL_OPEN_CONFIRMATION];
+    confirm.put_u32(remote);
+    confirm.put_u32(7);
+    // a small window, so that the client has to wait for adjustments
+    confirm.put_u32(50000);
+    confirm.put_u32(16384);
+    writer.write_packet(&confirm).await?;
+
+    let mut consumed = 0;
+    loop {
+        let packet = reader.read_packet().await?;
+        let mut msg = SshReader::new(&packet[1..]);
+        match packet[0] {
+            MSG_CHANNEL_DATA => {
+                msg.get_u32()?;
+                let data = msg.get_string()?;
+                for chunk in data.chunks(10000) {
+                    let mut echo = vec![MSG_CHANNEL_DATA];
+                    echo.put_u32(remote);
+                    echo.put_string(chunk);
+                    writer.write_packet(&echo).await?;
+                }
+                consumed += data.len() as u32;
+                if consumed >= 25000 {
+                    let mut adjust = vec![MSG_CHANNEL_WINDOW_ADJUST];
+                    adjust.put_u32(remote);
+                    adjust.put_u32(consumed);
+                    writer.write_packet(&adjust).await?;
+                    consumed = 0;
+                }
+            }
+            MSG_CHANNEL_EOF => {
+                let mut eof = vec![MSG_CHANNEL_EOF];
+                eof.put_u32(remote);
+                writer.write_packet(&eof).await?;
+                let mut close = vec![MSG_CHANNEL_CLOSE];
+                close.put_u32(remote);
+                writer.write_packet(&close).await?;
+            }
+            MSG_CHANNEL_CLOSE => return Ok(()),
+            _ => {}
+        }
+    }
+}
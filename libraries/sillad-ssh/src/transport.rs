@@ -0,0 +1,463 @@
+use chacha20::{
+    cipher::{KeyIvInit, StreamCipher},
+    ChaCha20Legacy,
+};
+use futures_lite::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use poly1305::{universal_hash::KeyInit, Poly1305};
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::wire::{invalid_data, SshReader, SshWrite};
+
+pub const MSG_DISCONNECT: u8 = 1;
+pub const MSG_IGNORE: u8 = 2;
+pub const MSG_UNIMPLEMENTED: u8 = 3;
+pub const MSG_DEBUG: u8 = 4;
+pub const MSG_SERVICE_REQUEST: u8 = 5;
+pub const MSG_SERVICE_ACCEPT: u8 = 6;
+pub const MSG_KEXINIT: u8 = 20;
+pub const MSG_NEWKEYS: u8 = 21;
+pub const MSG_KEX_ECDH_INIT: u8 = 30;
+pub const MSG_KEX_ECDH_REPLY: u8 = 31;
+pub const MSG_USERAUTH_REQUEST: u8 = 50;
+pub const MSG_USERAUTH_FAILURE: u8 = 51;
+pub const MSG_USERAUTH_SUCCESS: u8 = 52;
+pub const MSG_USERAUTH_BANNER: u8 = 53;
+pub const MSG_GLOBAL_REQUEST: u8 = 80;
+pub const MSG_REQUEST_FAILURE: u8 = 82;
+pub const MSG_CHANNEL_OPEN: u8 = 90;
+pub const MSG_CHANNEL_OPEN_CONFIRMATION: u8 = 91;
+pub const MSG_CHANNEL_OPEN_FAILURE: u8 = 92;
+pub const MSG_CHANNEL_WINDOW_ADJUST: u8 = 93;
+pub const MSG_CHANNEL_DATA: u8 = 94;
+pub const MSG_CHANNEL_EXTENDED_DATA: u8 = 95;
+pub const MSG_CHANNEL_EOF: u8 = 96;
+pub const MSG_CHANNEL_CLOSE: u8 = 97;
+pub const MSG_CHANNEL_REQUEST: u8 = 98;
+pub const MSG_CHANNEL_FAILURE: u8 = 100;
+
+/// The version string we announce.
+pub const VERSION: &str = "SSH-2.0-sillad_0.1";
+
+const KEX_ALGORITHMS: &[&str] = &["curve25519-sha256", "curve25519-sha256@libssh.org"];
+const HOST_KEY_ALGORITHM: &str = "ssh-ed25519";
+const CIPHER: &str = "chacha20-poly1305@openssh.com";
+// never used, since the cipher authenticates itself, but some peers insist on agreeing on one anyway
+const MAC: &str = "hmac-sha2-256";
+
+/// The pseudo-algorithms that turn on "strict kex", which closes the Terrapin prefix-truncation attack.
+const STRICT_KEX_CLIENT: &str = "kex-strict-c-v00@openssh.com";
+const STRICT_KEX_SERVER: &str = "kex-strict-s-v00@openssh.com";
+
+/// The biggest packet we accept, which is what OpenSSH accepts too.
+const MAX_PACKET_LEN: usize = 256 * 1024;
+
+const TAG_LEN: usize = 16;
+
+/// One direction of the chacha20-poly1305@openssh.com cipher.
+#[derive(Clone)]
+pub struct PacketCipher {
+    main_key: [u8; 32],
+    header_key: [u8; 32],
+}
+
+impl PacketCipher {
+    pub fn new(key: &[u8; 64]) -> Self {
+        Self {
+            main_key: key[..32].try_into().unwrap(),
+            header_key: key[32..].try_into().unwrap(),
+        }
+    }
+
+    fn nonce(seq: u32) -> [u8; 8] {
+        (seq as u64).to_be_bytes()
+    }
+
+    /// Encrypts a packet, length field included, in place and appends its tag.
+    fn seal(&self, seq: u32, packet: &mut Vec<u8>) {
+        let nonce = Self::nonce(seq);
+        ChaCha20Legacy::new(&self.header_key.into(), &nonce.into())
+            .apply_keystream(&mut packet[..4]);
+        let mut main = ChaCha20Legacy::new(&self.main_key.into(), &nonce.into());
+        // the first block of keystream keys the MAC, and the payload starts at the second
+        let mut poly_key = [0u8; 64];
+        main.apply_keystream(&mut poly_key);
+        main.apply_keystream(&mut packet[4..]);
+        let tag = Poly1305::new(poly_key[..32].into()).compute_unpadded(packet);
+        packet.extend_from_slice(&tag);
+    }
+
+    fn open_length(&self, seq: u32, mut length: [u8; 4]) -> u32 {
+        ChaCha20Legacy::new(&self.header_key.into(), &Self::nonce(seq).into())
+            .apply_keystream(&mut length);
+        u32::from_be_bytes(length)
+    }
+
+    /// Checks the tag of an encrypted packet, length field included, and decrypts the rest of it in place.
+    fn open(&self, seq: u32, packet: &mut [u8], tag: &[u8]) -> std::io::Result<()> {
+        let mut main = ChaCha20Legacy::new(&self.main_key.into(), &Self::nonce(seq).into());
+        let mut poly_key = [0u8; 64];
+        main.apply_keystream(&mut poly_key);
+        let expected = Poly1305::new(poly_key[..32].into()).compute_unpadded(packet);
+        let diff = expected
+            .iter()
+            .zip(tag)
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b));
+        if diff != 0 {
+            return Err(invalid_data("SSH packet failed to authenticate"));
+        }
+        main.apply_keystream(&mut packet[4..]);
+        Ok(())
+    }
+}
+
+/// Reads binary packets off the lower pipe.
+pub struct PacketReader<R> {
+    io: R,
+    cipher: Option<PacketCipher>,
+    seq: u32,
+}
+
+impl<R: AsyncRead + Unpin> PacketReader<R> {
+    pub fn new(io: R) -> Self {
+        Self {
+            io,
+            cipher: None,
+            seq: 0,
+        }
+    }
+
+    /// Switches to new keys, as happens right after a NEWKEYS message is read.
+    pub fn rekey(&mut self, cipher: PacketCipher, strict: bool) {
+        self.cipher = Some(cipher);
+        if strict {
+            self.seq = 0;
+        }
+    }
+
+    /// Reads the payload of the next packet.
+    pub async fn read_packet(&mut self) -> std::io::Result<Vec<u8>> {
+        let mut length = [0u8; 4];
+        self.io.read_exact(&mut length).await?;
+        let len = match &self.cipher {
+            Some(cipher) => cipher.open_length(self.seq, length),
+            None => u32::from_be_bytes(length),
+        } as usize;
+        if !(5..=MAX_PACKET_LEN).contains(&len) {
+            return Err(invalid_data("bad SSH packet length"));
+        }
+        let tag_len = if self.cipher.is_some() { TAG_LEN } else { 0 };
+        let mut packet = vec![0u8; 4 + len + tag_len];
+        packet[..4].copy_from_slice(&length);
+        self.io.read_exact(&mut packet[4..]).await?;
+        if let Some(cipher) = &self.cipher {
+            let (packet, tag) = packet.split_at_mut(4 + len);
+            cipher.open(self.seq, packet, tag)?;
+        }
+        self.seq = self.seq.wrapping_add(1);
+
+        let padding = packet[4] as usize;
+        if padding < 4 || padding + 1 > len {
+            return Err(invalid_data("bad SSH packet padding"));
+        }
+        let payload = packet[5..4 + len - padding].to_vec();
+        if payload.is_empty() {
+            return Err(invalid_data("empty SSH packet"));
+        }
+        Ok(payload)
+    }
+}
+
+/// Writes binary packets to the lower pipe.
+pub struct PacketWriter<W> {
+    io: W,
+    cipher: Option<PacketCipher>,
+    seq: u32,
+}
+
+impl<W: AsyncWrite + Unpin> PacketWriter<W> {
+    pub fn new(io: W) -> Self {
+        Self {
+            io,
+            cipher: None,
+            seq: 0,
+        }
+    }
+
+    /// Switches to new keys, as happens right after a NEWKEYS message is written.
+    pub fn rekey(&mut self, cipher: PacketCipher, strict: bool) {
+        self.cipher = Some(cipher);
+        if strict {
+            self.seq = 0;
+        }
+    }
+
+    pub async fn write_packet(&mut self, payload: &[u8]) -> std::io::Result<()> {
+        // with the AEAD cipher the length field is not part of what gets padded to a whole block
+        let unpadded = 1 + payload.len() + if self.cipher.is_some() { 0 } else { 4 };
+        let mut padding = 8 - unpadded % 8;
+        if padding < 4 {
+            padding += 8;
+        }
+        let len = 1 + payload.len() + padding;
+        let mut packet = Vec::with_capacity(4 + len + TAG_LEN);
+        packet.put_u32(len as u32);
+        packet.put_u8(padding as u8);
+        packet.extend_from_slice(payload);
+        let start = packet.len();
+        packet.resize(start + padding, 0);
+        rand::thread_rng().fill_bytes(&mut packet[start..]);
+        if let Some(cipher) = &self.cipher {
+            cipher.seal(self.seq, &mut packet);
+        }
+        self.seq = self.seq.wrapping_add(1);
+        self.io.write_all(&packet).await?;
+        self.io.flush().await
+    }
+}
+
+/// Sends our version line and reads the other side's, skipping any banner lines before it.
+pub async fn exchange_versions(
+    read: &mut (impl AsyncRead + Unpin),
+    write: &mut (impl AsyncWrite + Unpin),
+) -> std::io::Result<String> {
+    write.write_all(format!("{VERSION}\r\n").as_bytes()).await?;
+    write.flush().await?;
+    for _ in 0..64 {
+        let mut line = Vec::new();
+        loop {
+            let mut byte = [0u8];
+            read.read_exact(&mut byte).await?;
+            if byte[0] == b'\n' {
+                break;
+            }
+            if line.len() >= 255 {
+                return Err(invalid_data("SSH version line too long"));
+            }
+            line.push(byte[0]);
+        }
+        if line.last() == Some(&b'\r') {
+            line.pop();
+        }
+        let line = String::from_utf8(line).map_err(invalid_data)?;
+        if line.starts_with("SSH-") {
+            if !(line.starts_with("SSH-2.0-") || line.starts_with("SSH-1.99-")) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::Unsupported,
+                    format!("unsupported SSH version {line}"),
+                ));
+            }
+            return Ok(line);
+        }
+    }
+    Err(invalid_data("no SSH version line"))
+}
+
+/// Builds our KEXINIT message.
+pub fn build_kexinit(is_server: bool) -> Vec<u8> {
+    let mut kex = KEX_ALGORITHMS.to_vec();
+    kex.push(if is_server {
+        STRICT_KEX_SERVER
+    } else {
+        STRICT_KEX_CLIENT
+    });
+    let mut msg = vec![MSG_KEXINIT];
+    let mut cookie = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut cookie);
+    msg.extend_from_slice(&cookie);
+    msg.put_string(kex.join(",").as_bytes());
+    msg.put_string(HOST_KEY_ALGORITHM.as_bytes());
+    msg.put_string(CIPHER.as_bytes());
+    msg.put_string(CIPHER.as_bytes());
+    msg.put_string(MAC.as_bytes());
+    msg.put_string(MAC.as_bytes());
+    msg.put_string(b"none");
+    msg.put_string(b"none");
+    msg.put_string(b"");
+    msg.put_string(b"");
+    msg.put_bool(false);
+    msg.put_u32(0);
+    msg
+}
+
+/// Checks that the other side's KEXINIT can agree with ours, returning whether both sides asked for strict kex.
+pub fn check_kexinit(theirs: &[u8], is_server: bool) -> std::io::Result<bool> {
+    let mut reader = SshReader::new(theirs);
+    reader.take(17)?;
+    let kex = reader.get_name_list()?;
+    let host_key = reader.get_name_list()?;
+    let cipher_c2s = reader.get_name_list()?;
+    let cipher_s2c = reader.get_name_list()?;
+    let _mac_c2s = reader.get_name_list()?;
+    let _mac_s2c = reader.get_name_list()?;
+    let compression_c2s = reader.get_name_list()?;
+    let compression_s2c = reader.get_name_list()?;
+    let _languages_c2s = reader.get_name_list()?;
+    let _languages_s2c = reader.get_name_list()?;
+    let guess_follows = reader.get_bool()?;
+
+    let unsupported = |what: &str| {
+        std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            format!("SSH peer does not support {what}"),
+        )
+    };
+    if !KEX_ALGORITHMS.iter().any(|k| kex.contains(k)) {
+        return Err(unsupported("curve25519-sha256"));
+    }
+    if !host_key.contains(&HOST_KEY_ALGORITHM) {
+        return Err(unsupported(HOST_KEY_ALGORITHM));
+    }
+    if !cipher_c2s.contains(&CIPHER) || !cipher_s2c.contains(&CIPHER) {
+        return Err(unsupported(CIPHER));
+    }
+    if !compression_c2s.contains(&"none") || !compression_s2c.contains(&"none") {
+        return Err(unsupported("running without compression"));
+    }
+    // a guessed first packet is only right if the guess was curve25519, which is what we'd have agreed on anyway
+    if guess_follows && !KEX_ALGORITHMS.contains(&kex[0]) {
+        return Err(unsupported("our key exchange as its first guess"));
+    }
+    Ok(kex.contains(if is_server {
+        &STRICT_KEX_CLIENT
+    } else {
+        &STRICT_KEX_SERVER
+    }))
+}
+
+/// Computes the exchange hash over the transcript strings, in protocol order, followed by the shared secret.
+pub fn exchange_hash(transcript: &[&[u8]], shared: &[u8; 32]) -> [u8; 32] {
+    let mut buf = Vec::new();
+    for item in transcript {
+        buf.put_string(item);
+    }
+    buf.put_mpint(shared);
+    Sha256::digest(&buf).into()
+}
+
+/// Derives the client-to-server and server-to-client ciphers from a finished key exchange.
+pub fn derive_keys(
+    shared: &[u8; 32],
+    hash: &[u8; 32],
+    session_id: &[u8; 32],
+) -> (PacketCipher, PacketCipher) {
+    let mut secret = Vec::new();
+    secret.put_mpint(shared);
+    let derive = |letter: u8| {
+        let first: [u8; 32] = Sha256::new()
+            .chain_update(&secret)
+            .chain_update(hash)
+            .chain_update([letter])
+            .chain_update(session_id)
+            .finalize()
+            .into();
+        let second: [u8; 32] = Sha256::new()
+            .chain_update(&secret)
+            .chain_update(hash)
+            .chain_update(first)
+            .finalize()
+            .into();
+        let mut key = [0u8; 64];
+        key[..32].copy_from_slice(&first);
+        key[32..].copy_from_slice(&second);
+        PacketCipher::new(&key)
+    };
+    (derive(b'C'), derive(b'D'))
+}
+
+/// Encodes an Ed25519 public key as an SSH key blob.
+pub fn ed25519_key_blob(key: &ed25519_dalek::VerifyingKey) -> Vec<u8> {
+    let mut blob = Vec::new();
+    blob.put_string(HOST_KEY_ALGORITHM.as_bytes());
+    blob.put_string(key.as_bytes());
+    blob
+}
+
+/// Decodes an SSH key blob, which must hold an Ed25519 key.
+pub fn parse_ed25519_key_blob(blob: &[u8]) -> std::io::Result<ed25519_dalek::VerifyingKey> {
+    let mut reader = SshReader::new(blob);
+    if reader.get_string()? != HOST_KEY_ALGORITHM.as_bytes() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "only ssh-ed25519 keys are supported",
+        ));
+    }
+    let key: [u8; 32] = reader
+        .get_string()?
+        .try_into()
+        .map_err(|_| invalid_data("bad ssh-ed25519 key length"))?;
+    ed25519_dalek::VerifyingKey::from_bytes(&key).map_err(invalid_data)
+}
+
+/// Encodes an Ed25519 signature as an SSH signature blob.
+pub fn ed25519_signature_blob(signature: &ed25519_dalek::Signature) -> Vec<u8> {
+    let mut blob = Vec::new();
+    blob.put_string(HOST_KEY_ALGORITHM.as_bytes());
+    blob.put_string(&signature.to_bytes());
+    blob
+}
+
+/// Checks an SSH signature blob over the given message.
+pub fn verify_ed25519_signature(
+    key: &ed25519_dalek::VerifyingKey,
+    message: &[u8],
+    blob: &[u8],
+) -> std::io::Result<()> {
+    let mut reader = SshReader::new(blob);
+    if reader.get_string()? != HOST_KEY_ALGORITHM.as_bytes() {
+        return Err(invalid_data("signature is not ssh-ed25519"));
+    }
+    let signature: [u8; 64] = reader
+        .get_string()?
+        .try_into()
+        .map_err(|_| invalid_data("bad ssh-ed25519 signature length"))?;
+    key.verify_strict(message, &ed25519_dalek::Signature::from_bytes(&signature))
+        .map_err(|_| invalid_data("bad ssh-ed25519 signature"))
+}
+
+/// The OpenSSH-style fingerprint of a key blob, like `SHA256:...`.
+pub fn fingerprint(blob: &[u8]) -> String {
+    use base64::Engine as _;
+    format!(
+        "SHA256:{}",
+        base64::engine::general_purpose::STANDARD_NO_PAD.encode(Sha256::digest(blob))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packets_round_trip() {
+        futures_lite::future::block_on(async {
+            let cipher = PacketCipher::new(&[7u8; 64]);
+            let mut wire = Vec::new();
+            let mut writer = PacketWriter::new(&mut wire);
+            writer.write_packet(b"\x14plaintext").await.unwrap();
+            writer.rekey(cipher.clone(), false);
+            for len in [1, 7, 8, 9, 1000] {
+                writer.write_packet(&vec![0x5e; len]).await.unwrap();
+            }
+
+            let mut reader = PacketReader::new(&wire[..]);
+            assert_eq!(reader.read_packet().await.unwrap(), b"\x14plaintext");
+            reader.rekey(cipher.clone(), false);
+            for len in [1, 7, 8, 9, 1000] {
+                assert_eq!(reader.read_packet().await.unwrap(), vec![0x5e; len]);
+            }
+
+            // flipping any ciphertext bit must be caught
+            let mut tampered = wire.clone();
+            let last = tampered.len() - 20;
+            tampered[last] ^= 1;
+            let mut reader = PacketReader::new(&tampered[..]);
+            reader.read_packet().await.unwrap();
+            reader.rekey(cipher, false);
+            for _ in 0..4 {
+                reader.read_packet().await.unwrap();
+            }
+            assert!(reader.read_packet().await.is_err());
+        })
+    }
+}
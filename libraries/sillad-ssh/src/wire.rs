@@ -0,0 +1,108 @@
+/// Appends the SSH wire encodings of the basic data types to a buffer.
+pub trait SshWrite {
+    fn put_u8(&mut self, v: u8);
+    fn put_bool(&mut self, v: bool);
+    fn put_u32(&mut self, v: u32);
+    fn put_string(&mut self, v: &[u8]);
+    fn put_mpint(&mut self, v: &[u8]);
+}
+
+impl SshWrite for Vec<u8> {
+    fn put_u8(&mut self, v: u8) {
+        self.push(v);
+    }
+
+    fn put_bool(&mut self, v: bool) {
+        self.push(v as u8);
+    }
+
+    fn put_u32(&mut self, v: u32) {
+        self.extend_from_slice(&v.to_be_bytes());
+    }
+
+    fn put_string(&mut self, v: &[u8]) {
+        self.put_u32(v.len() as u32);
+        self.extend_from_slice(v);
+    }
+
+    fn put_mpint(&mut self, v: &[u8]) {
+        // an unsigned big-endian number, minimal, with a zero byte in front if the top bit is set
+        let start = v.iter().position(|b| *b != 0).unwrap_or(v.len());
+        let v = &v[start..];
+        if v.first().is_some_and(|b| b & 0x80 != 0) {
+            self.put_u32(v.len() as u32 + 1);
+            self.push(0);
+            self.extend_from_slice(v);
+        } else {
+            self.put_string(v);
+        }
+    }
+}
+
+/// Reads the SSH wire encodings of the basic data types off the front of a message.
+pub struct SshReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> SshReader<'a> {
+    pub fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    pub fn get_u8(&mut self) -> std::io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn get_bool(&mut self) -> std::io::Result<bool> {
+        Ok(self.get_u8()? != 0)
+    }
+
+    pub fn get_u32(&mut self) -> std::io::Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn get_string(&mut self) -> std::io::Result<&'a [u8]> {
+        let len = self.get_u32()? as usize;
+        self.take(len)
+    }
+
+    pub fn get_name_list(&mut self) -> std::io::Result<Vec<&'a str>> {
+        let list = std::str::from_utf8(self.get_string()?).map_err(invalid_data)?;
+        Ok(list.split(',').filter(|s| !s.is_empty()).collect())
+    }
+
+    pub fn take(&mut self, n: usize) -> std::io::Result<&'a [u8]> {
+        if self.buf.len() < n {
+            return Err(invalid_data("SSH message truncated"));
+        }
+        let (head, tail) = self.buf.split_at(n);
+        self.buf = tail;
+        Ok(head)
+    }
+}
+
+pub fn invalid_data(err: impl ToString) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mpint_encoding() {
+        // the examples from RFC 4251, section 5
+        let mut buf = vec![];
+        buf.put_mpint(&[0, 0]);
+        assert_eq!(buf, [0, 0, 0, 0]);
+        let mut buf = vec![];
+        buf.put_mpint(&[0x00, 0x80]);
+        assert_eq!(buf, [0, 0, 0, 2, 0, 0x80]);
+        let mut buf = vec![];
+        buf.put_mpint(&[0x09, 0xa3, 0x78, 0xf9, 0xb2, 0xe3, 0x32, 0xa7]);
+        assert_eq!(
+            buf,
+            [0, 0, 0, 8, 0x09, 0xa3, 0x78, 0xf9, 0xb2, 0xe3, 0x32, 0xa7]
+        );
+    }
+}
@@ -0,0 +1,120 @@
+pub const OP_CONTINUATION: u8 = 0x0;
+pub const OP_TEXT: u8 = 0x1;
+pub const OP_BINARY: u8 = 0x2;
+pub const OP_CLOSE: u8 = 0x8;
+pub const OP_PING: u8 = 0x9;
+pub const OP_PONG: u8 = 0xa;
+
+/// The largest frame payload we accept from the other side.
+const MAX_PAYLOAD: u64 = 1 << 24;
+
+/// A single WebSocket frame, already unmasked.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Appends a final frame to `out`, masking it if a mask is given. Clients must mask, servers must not.
+pub fn write_frame(opcode: u8, payload: &[u8], mask: Option<[u8; 4]>, out: &mut Vec<u8>) {
+    out.push(0x80 | opcode);
+    let mask_bit = if mask.is_some() { 0x80 } else { 0 };
+    match payload.len() {
+        len @ 0..=125 => out.push(mask_bit | len as u8),
+        len @ 126..=0xffff => {
+            out.push(mask_bit | 126);
+            out.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            out.push(mask_bit | 127);
+            out.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    match mask {
+        Some(mask) => {
+            out.extend_from_slice(&mask);
+            out.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        }
+        None => out.extend_from_slice(payload),
+    }
+}
+
+/// Parses one frame from the front of `buf`, returning it and how many bytes it took up, or `None` if the frame is not complete yet.
+pub fn parse_frame(buf: &[u8]) -> std::io::Result<Option<(Frame, usize)>> {
+    if buf.len() < 2 {
+        return Ok(None);
+    }
+    if buf[0] & 0x70 != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "websocket frame uses unnegotiated extension bits",
+        ));
+    }
+    let opcode = buf[0] & 0x0f;
+    let masked = buf[1] & 0x80 != 0;
+    let (len, mut pos) = match buf[1] & 0x7f {
+        126 => {
+            let Some(bytes) = buf.get(2..4) else {
+                return Ok(None);
+            };
+            (u16::from_be_bytes(bytes.try_into().unwrap()) as u64, 4)
+        }
+        127 => {
+            let Some(bytes) = buf.get(2..10) else {
+                return Ok(None);
+            };
+            (u64::from_be_bytes(bytes.try_into().unwrap()), 10)
+        }
+        len => (len as u64, 2),
+    };
+    if len > MAX_PAYLOAD {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "websocket frame too large",
+        ));
+    }
+    let mask = if masked {
+        let Some(bytes) = buf.get(pos..pos + 4) else {
+            return Ok(None);
+        };
+        pos += 4;
+        Some(<[u8; 4]>::try_from(bytes).unwrap())
+    } else {
+        None
+    };
+    let end = pos + len as usize;
+    let Some(payload) = buf.get(pos..end) else {
+        return Ok(None);
+    };
+    let payload = match mask {
+        Some(mask) => payload
+            .iter()
+            .enumerate()
+            .map(|(i, b)| b ^ mask[i % 4])
+            .collect(),
+        None => payload.to_vec(),
+    };
+    Ok(Some((Frame { opcode, payload }, end)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frame_round_trip() {
+        for len in [0, 5, 125, 126, 1000, 70000] {
+            let payload: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            for mask in [None, Some([1, 2, 3, 4])] {
+                let mut buf = vec![];
+                write_frame(OP_BINARY, &payload, mask, &mut buf);
+                // every strict prefix is incomplete
+                assert!(parse_frame(&buf[..buf.len() - 1]).unwrap().is_none());
+                let (frame, n) = parse_frame(&buf).unwrap().unwrap();
+                assert_eq!(n, buf.len());
+                assert_eq!(frame.opcode, OP_BINARY);
+                assert_eq!(frame.payload, payload);
+            }
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use base64::Engine as _;
+use futures_lite::{AsyncReadExt, AsyncWriteExt};
+use sha1::{Digest, Sha1};
+use sillad::Pipe;
+
+const WS_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// The longest HTTP head we are willing to read.
+const MAX_HEAD: usize = 16384;
+
+/// Computes the Sec-WebSocket-Accept value for a given Sec-WebSocket-Key.
+pub fn accept_key(key: &str) -> String {
+    let digest = Sha1::digest(format!("{key}{WS_GUID}").as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(digest)
+}
+
+/// Performs the client side of the upgrade. Returns whatever bytes arrived after the response head.
+pub async fn client_handshake(
+    pipe: &mut impl Pipe,
+    host: &str,
+    path: &str,
+) -> std::io::Result<Vec<u8>> {
+    let key = base64::engine::general_purpose::STANDARD.encode(rand::random::<[u8; 16]>());
+    let request = format!(
+        "GET {path} HTTP/1.1\r\nHost: {host}\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: {key}\r\nSec-WebSocket-Version: 13\r\n\r\n"
+    );
+    pipe.write_all(request.as_bytes()).await?;
+    pipe.flush().await?;
+
+    let (head, leftover) = read_head(pipe).await?;
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut response = httparse::Response::new(&mut headers);
+    response.parse(&head).map_err(invalid_data)?;
+    if response.code != Some(101) {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionRefused,
+            format!("websocket upgrade refused with status {:?}", response.code),
+        ));
+    }
+    if header(response.headers, "sec-websocket-accept") != Some(accept_key(&key).as_str()) {
+        return Err(invalid_data(
+            "wrong Sec-WebSocket-Accept in upgrade response",
+        ));
+    }
+    Ok(leftover)
+}
+
+/// The result of a successful server-side upgrade.
+pub struct ServerHandshake {
+    pub leftover: Vec<u8>,
+    /// The client address as reported by a reverse proxy, if any.
+    pub forwarded_for: Option<String>,
+}
+
+/// Performs the server side of the upgrade, answering anything that isn't a WebSocket upgrade with a 400.
+pub async fn server_handshake(pipe: &mut impl Pipe) -> std::io::Result<ServerHandshake> {
+    let (head, leftover) = read_head(pipe).await?;
+    let mut headers = [httparse::EMPTY_HEADER; 64];
+    let mut request = httparse::Request::new(&mut headers);
+    let parsed = request.parse(&head).map_err(invalid_data).and_then(|_| {
+        let is_upgrade = request.method == Some("GET")
+            && header(request.headers, "upgrade")
+                .is_some_and(|v| v.eq_ignore_ascii_case("websocket"));
+        match header(request.headers, "sec-websocket-key") {
+            Some(key) if is_upgrade => Ok(key.to_string()),
+            _ => Err(invalid_data("not a websocket upgrade request")),
+        }
+    });
+    let key = match parsed {
+        Ok(key) => key,
+        Err(err) => {
+            let _ = pipe
+                .write_all(b"HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n")
+                .await;
+            return Err(err);
+        }
+    };
+    // the leftmost X-Forwarded-For entry is the original client
+    let forwarded_for = header(request.headers, "x-forwarded-for")
+        .and_then(|v| v.split(',').next())
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty());
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(&key)
+    );
+    pipe.write_all(response.as_bytes()).await?;
+    pipe.flush().await?;
+    Ok(ServerHandshake {
+        leftover,
+        forwarded_for,
+    })
+}
+
+/// Reads up to and including the blank line that ends an HTTP head, returning the head and any bytes after it.
+async fn read_head(pipe: &mut impl Pipe) -> std::io::Result<(Vec<u8>, Vec<u8>)> {
+    let mut buf = vec![];
+    let mut chunk = [0u8; 4096];
+    loop {
+        let n = pipe.read(&mut chunk).await?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        // the terminator may straddle two reads
+        let search_from = buf.len().saturating_sub(3);
+        buf.extend_from_slice(&chunk[..n]);
+        if let Some(pos) = buf[search_from..].windows(4).position(|w| w == b"\r\n\r\n") {
+            let leftover = buf.split_off(search_from + pos + 4);
+            return Ok((buf, leftover));
+        }
+        if buf.len() > MAX_HEAD {
+            return Err(invalid_data("HTTP head too long"));
+        }
+    }
+}
+
+fn header<'a>(headers: &[httparse::Header<'a>], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .and_then(|h| std::str::from_utf8(h.value).ok())
+}
+
+fn invalid_data(err: impl ToString) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_accept_key_matches_rfc() {
+        // the example from RFC 6455, section 1.3
+        assert_eq!(
+            accept_key("dGhlIHNhbXBsZSBub25jZQ=="),
+            "s3pPLMBiTxaQ9kYGzzhZRbK+xOo="
+        );
+    }
+}
@@ -0,0 +1,297 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use async_trait::async_trait;
+use frame::{write_frame, OP_BINARY, OP_CLOSE, OP_CONTINUATION, OP_PING, OP_PONG, OP_TEXT};
+use futures_lite::{AsyncRead, AsyncWrite};
+use sillad::{
+    accept::{AcceptQueue, HandshakeLimits},
+    dialer::Dialer,
+    listener::Listener,
+    Pipe,
+};
+
+mod frame;
+mod handshake;
+
+/// The most data that a single write puts into one frame.
+const MAX_WRITE: usize = 65536;
+
+/// A dialer that upgrades the connections of its inner dialer to WebSockets. Use a TLS dialer as the inner dialer for wss://.
+pub struct WsDialer<D: Dialer> {
+    inner: D,
+    host: String,
+    path: String,
+}
+
+impl<D: Dialer> WsDialer<D> {
+    /// Creates a dialer that sends its upgrade request for the given path, with the given Host header.
+    pub fn new(inner: D, host: impl Into<String>, path: impl Into<String>) -> Self {
+        Self {
+            inner,
+            host: host.into(),
+            path: path.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl<D: Dialer> Dialer for WsDialer<D> {
+    type P = WsPipe<D::P>;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        let mut lower = self.inner.dial().await?;
+        let leftover = handshake::client_handshake(&mut lower, &self.host, &self.path).await?;
+        Ok(WsPipe::new(lower, true, leftover, None))
+    }
+}
+
+/// A listener that accepts WebSocket upgrades on the connections of its inner listener, so that it can sit behind CDNs and reverse proxies. Use a TLS listener as the inner listener for wss://.
+///
+/// Upgrades run concurrently in the background, within the given [HandshakeLimits]. Connections that fail or time out their upgrades are dropped without being returned from `accept`.
+pub struct WsListener<L: Listener> {
+    queue: AcceptQueue<WsPipe<L::P>>,
+}
+
+impl<L: Listener> WsListener<L> {
+    pub fn new(inner: L) -> Self {
+        Self::with_limits(inner, HandshakeLimits::default())
+    }
+
+    /// Like [WsListener::new], but with specific limits on upgrades.
+    pub fn with_limits(inner: L, limits: HandshakeLimits) -> Self {
+        let queue = AcceptQueue::spawn(inner, limits, |mut lower: L::P| async move {
+            let hs = handshake::server_handshake(&mut lower).await?;
+            Ok(WsPipe::new(lower, false, hs.leftover, hs.forwarded_for))
+        });
+        Self { queue }
+    }
+}
+
+#[async_trait]
+impl<L: Listener> Listener for WsListener<L> {
+    type P = WsPipe<L::P>;
+
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        self.queue.accept().await
+    }
+}
+
+/// A pipe that carries its bytes in WebSocket binary frames.
+pub struct WsPipe<P: Pipe> {
+    lower: P,
+    is_client: bool,
+    remote_addr: Option<String>,
+
+    to_write_buf: Vec<u8>,
+    to_write_plain: usize,
+    // pongs and close frames, which go out ahead of data
+    control_buf: Vec<u8>,
+    close_sent: bool,
+
+    raw_read_buf: Vec<u8>,
+    read_buf: Vec<u8>,
+    read_closed: bool,
+}
+
+impl<P: Pipe> WsPipe<P> {
+    fn new(lower: P, is_client: bool, leftover: Vec<u8>, forwarded_for: Option<String>) -> Self {
+        let remote_addr = forwarded_for.or_else(|| lower.remote_addr().map(|s| s.to_string()));
+        Self {
+            lower,
+            is_client,
+            remote_addr,
+            to_write_buf: vec![],
+            to_write_plain: 0,
+            control_buf: vec![],
+            close_sent: false,
+            raw_read_buf: leftover,
+            read_buf: vec![],
+            read_closed: false,
+        }
+    }
+
+    fn mask(&self) -> Option<[u8; 4]> {
+        self.is_client.then(rand::random)
+    }
+
+    fn queue_control(&mut self, opcode: u8, payload: &[u8]) {
+        if self.close_sent {
+            return;
+        }
+        if opcode == OP_CLOSE {
+            self.close_sent = true;
+        }
+        let mask = self.mask();
+        write_frame(opcode, payload, mask, &mut self.control_buf);
+    }
+
+    fn poll_control(&mut self, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        while !self.control_buf.is_empty() {
+            let n =
+                futures_lite::ready!(Pin::new(&mut self.lower).poll_write(cx, &self.control_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            self.control_buf.drain(..n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<P: Pipe> AsyncRead for WsPipe<P> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if !this.read_buf.is_empty() || this.read_closed {
+                let n = buf.len().min(this.read_buf.len());
+                buf[..n].copy_from_slice(&this.read_buf[..n]);
+                this.read_buf.drain(..n);
+                return Poll::Ready(Ok(n));
+            }
+            if let Some((frame, n)) = frame::parse_frame(&this.raw_read_buf)? {
+                this.raw_read_buf.drain(..n);
+                match frame.opcode {
+                    OP_BINARY | OP_TEXT | OP_CONTINUATION => {
+                        this.read_buf.extend_from_slice(&frame.payload)
+                    }
+                    OP_PING => this.queue_control(OP_PONG, &frame.payload),
+                    OP_CLOSE => {
+                        this.queue_control(OP_CLOSE, &frame.payload);
+                        this.read_closed = true;
+                    }
+                    _ => {}
+                }
+                continue;
+            }
+            // replies to pings go out whenever the lower pipe has room, without blocking reads
+            if let Poll::Ready(Err(err)) = this.poll_control(cx) {
+                return Poll::Ready(Err(err));
+            }
+            let mut chunk = [0u8; 8192];
+            let n = futures_lite::ready!(Pin::new(&mut this.lower).poll_read(cx, &mut chunk))?;
+            if n == 0 {
+                this.read_closed = true;
+                continue;
+            }
+            this.raw_read_buf.extend_from_slice(&chunk[..n]);
+        }
+    }
+}
+
+impl<P: Pipe> AsyncWrite for WsPipe<P> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        // This assumes the caller polls the *same* buffer until completion, so that nothing is held back between writes.
+        let this = self.get_mut();
+        futures_lite::ready!(this.poll_control(cx))?;
+        if this.to_write_buf.is_empty() {
+            let plain_n = buf.len().min(MAX_WRITE);
+            let mask = this.mask();
+            write_frame(OP_BINARY, &buf[..plain_n], mask, &mut this.to_write_buf);
+            this.to_write_plain = plain_n;
+        }
+        while !this.to_write_buf.is_empty() {
+            let n =
+                futures_lite::ready!(Pin::new(&mut this.lower).poll_write(cx, &this.to_write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            this.to_write_buf.drain(..n);
+        }
+        Poll::Ready(Ok(this.to_write_plain))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        futures_lite::ready!(this.poll_control(cx))?;
+        Pin::new(&mut this.lower).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        // status code 1000, normal closure
+        this.queue_control(OP_CLOSE, &1000u16.to_be_bytes());
+        futures_lite::ready!(this.poll_control(cx))?;
+        Pin::new(&mut this.lower).poll_close(cx)
+    }
+}
+
+impl<P: Pipe> Pipe for WsPipe<P> {
+    fn protocol(&self) -> &str {
+        "websocket"
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.remote_addr.as_deref()
+    }
+
+    fn shared_secret(&self) -> Option<&[u8]> {
+        self.lower.shared_secret()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_lite::{AsyncReadExt, AsyncWriteExt};
+    use sillad::tcp::{TcpDialer, TcpListener};
+
+    use super::*;
+
+    #[test]
+    fn test_websocket_echo() {
+        smolscale::block_on(async {
+            let tcp = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let addr = tcp.local_addr().await;
+            let mut listener = WsListener::new(tcp);
+            let dialer = WsDialer::new(TcpDialer { dest_addr: addr }, "example.com", "/ws");
+            let message: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+            let server = async {
+                let mut pipe = listener.accept().await.unwrap();
+                assert_eq!(pipe.protocol(), "websocket");
+                let mut received = vec![0u8; message.len()];
+                pipe.read_exact(&mut received).await.unwrap();
+                pipe.write_all(&received).await.unwrap();
+                pipe.close().await.unwrap();
+            };
+            let client = async {
+                let mut pipe = dialer.dial().await.unwrap();
+                pipe.write_all(&message).await.unwrap();
+                let mut echoed = vec![];
+                pipe.read_to_end(&mut echoed).await.unwrap();
+                echoed
+            };
+            let ((), echoed) = futures_lite::future::zip(server, client).await;
+            assert_eq!(echoed, message);
+        });
+    }
+
+    #[test]
+    fn test_non_upgrade_rejected() {
+        smolscale::block_on(async {
+            let tcp = TcpListener::bind("127.0.0.1:0".parse().unwrap())
+                .await
+                .unwrap();
+            let addr = tcp.local_addr().await;
+            let _listener = WsListener::new(tcp);
+            let mut pipe = TcpDialer { dest_addr: addr }.dial().await.unwrap();
+            pipe.write_all(b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n")
+                .await
+                .unwrap();
+            let mut response = vec![];
+            pipe.read_to_end(&mut response).await.unwrap();
+            assert!(response.starts_with(b"HTTP/1.1 400"));
+        });
+    }
+}
@@ -0,0 +1,115 @@
+//! Running the handshakes of a layered listener in the background, so that one slow client can't hold up the others.
+
+use std::{future::Future, sync::Arc, time::Duration};
+
+use async_io::Timer;
+use async_lock::Semaphore;
+use async_task::Task;
+use futures_lite::FutureExt;
+use tachyonix::{Receiver, Sender};
+
+use crate::{listener::Listener, Pipe};
+
+/// How long to wait after the lower listener fails before accepting again, doubling with every failure in a row up to [MAX_ERROR_BACKOFF].
+const MIN_ERROR_BACKOFF: Duration = Duration::from_millis(5);
+const MAX_ERROR_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Limits on the handshakes that a listener runs at the same time, so that half-open handshakes can't exhaust it.
+#[derive(Clone, Copy, Debug)]
+pub struct HandshakeLimits {
+    /// How long a single handshake may take before it is abandoned.
+    pub timeout: Duration,
+    /// How many handshakes may be in progress at once. Beyond this, we stop accepting new connections until a handshake finishes.
+    pub max_concurrent: usize,
+}
+
+impl Default for HandshakeLimits {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(10),
+            max_concurrent: 256,
+        }
+    }
+}
+
+/// Accepts connections from a lower listener in the background, running the handshakes concurrently within the given limits. Connections that fail or time out their handshakes are dropped without being returned from `accept`.
+pub struct AcceptQueue<P> {
+    recv: Receiver<std::io::Result<P>>,
+    _task: Task<()>,
+}
+
+impl<P: Pipe> AcceptQueue<P> {
+    pub fn spawn<L: Listener, F, Fut>(listener: L, limits: HandshakeLimits, handshake: F) -> Self
+    where
+        F: Fn(L::P) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = std::io::Result<P>> + Send + 'static,
+    {
+        let (send, recv) = tachyonix::channel(1);
+        let _task = smolscale::spawn(accept_loop(listener, limits, handshake, send));
+        Self { recv, _task }
+    }
+
+    pub async fn accept(&mut self) -> std::io::Result<P> {
+        self.recv.recv().await.map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "listener has shut down for some reason",
+            )
+        })?
+    }
+}
+
+async fn accept_loop<L: Listener, P: Pipe, F, Fut>(
+    mut listener: L,
+    limits: HandshakeLimits,
+    handshake: F,
+    send: Sender<std::io::Result<P>>,
+) where
+    F: Fn(L::P) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = std::io::Result<P>> + Send + 'static,
+{
+    let semaphore = Arc::new(Semaphore::new(limits.max_concurrent));
+    let mut error_backoff = Duration::ZERO;
+    loop {
+        let permit = semaphore.acquire_arc().await;
+        let lower = match listener.accept().await {
+            Ok(lower) => {
+                error_backoff = Duration::ZERO;
+                lower
+            }
+            Err(err) => {
+                // errors from the lower listener are the caller's business
+                if send.send(Err(err)).await.is_err() {
+                    return;
+                }
+                // but a listener that keeps failing, say because we're out of file descriptors, must not be spun on
+                error_backoff = (error_backoff * 2).clamp(MIN_ERROR_BACKOFF, MAX_ERROR_BACKOFF);
+                Timer::after(error_backoff).await;
+                continue;
+            }
+        };
+        let remote_addr = lower.remote_addr().map(|s| s.to_string());
+        let handshake = handshake(lower);
+        let send = send.clone();
+        smolscale::spawn(async move {
+            let res = handshake
+                .or(async {
+                    Timer::after(limits.timeout).await;
+                    Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "handshake timed out",
+                    ))
+                })
+                .await;
+            drop(permit);
+            match res {
+                Ok(pipe) => {
+                    let _ = send.send(Ok(pipe)).await;
+                }
+                // a client failing its handshake is not an error for the listener as a whole
+                Err(err) => tracing::debug!(remote_addr, err = debug(err), "handshake failed"),
+            }
+        })
+        .detach();
+    }
+}
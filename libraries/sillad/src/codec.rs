@@ -0,0 +1,181 @@
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use async_trait::async_trait;
+use futures_util::{AsyncRead, AsyncWrite};
+
+use crate::{dialer::Dialer, listener::Listener, Pipe};
+
+/// The most plaintext that a single write hands to the codec at once.
+const MAX_CHUNK: usize = 3 * 5 * 4096;
+
+/// A ByteCodec is a byte-level transformation of a stream, such as an encoding or a simple obfuscation. Wrapping it in a [CodecPipe] turns it into a transport.
+pub trait ByteCodec: Send + Unpin + 'static {
+    /// Encodes one chunk of outgoing plaintext, appending the result to `out`. The output must go out without waiting for more input, since callers don't always flush.
+    fn encode(&mut self, plain: &[u8], out: &mut Vec<u8>);
+
+    /// Decodes incoming bytes, appending whatever plaintext is complete to `out`. Incomplete input may be kept until more arrives.
+    fn decode(&mut self, encoded: &[u8], out: &mut Vec<u8>) -> std::io::Result<()>;
+
+    /// Whether decoding has incomplete input, in which case the stream ending now is an error.
+    fn has_pending(&self) -> bool {
+        false
+    }
+
+    /// Uniquely identifies the codec, as in [Pipe::protocol].
+    fn protocol(&self) -> &str;
+}
+
+/// A pipe that passes everything going through a lower pipe through a [ByteCodec].
+pub struct CodecPipe<P: Pipe, C: ByteCodec> {
+    lower: P,
+    codec: C,
+
+    to_write_buf: Vec<u8>,
+    to_write_plain: usize,
+
+    raw_read_buf: Vec<u8>,
+    read_buf: Vec<u8>,
+    read_closed: bool,
+}
+
+impl<P: Pipe, C: ByteCodec> CodecPipe<P, C> {
+    pub fn new(lower: P, codec: C) -> Self {
+        Self {
+            lower,
+            codec,
+            to_write_buf: vec![],
+            to_write_plain: 0,
+            raw_read_buf: vec![0; 8192],
+            read_buf: vec![],
+            read_closed: false,
+        }
+    }
+}
+
+impl<P: Pipe, C: ByteCodec> AsyncRead for CodecPipe<P, C> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        loop {
+            if !this.read_buf.is_empty() || this.read_closed {
+                let n = buf.len().min(this.read_buf.len());
+                buf[..n].copy_from_slice(&this.read_buf[..n]);
+                this.read_buf.drain(..n);
+                return Poll::Ready(Ok(n));
+            }
+            let n = futures_util::ready!(
+                Pin::new(&mut this.lower).poll_read(cx, &mut this.raw_read_buf)
+            )?;
+            if n == 0 {
+                if this.codec.has_pending() {
+                    return Poll::Ready(Err(std::io::Error::new(
+                        std::io::ErrorKind::UnexpectedEof,
+                        "stream ended in the middle of an encoded unit",
+                    )));
+                }
+                this.read_closed = true;
+                continue;
+            }
+            this.codec
+                .decode(&this.raw_read_buf[..n], &mut this.read_buf)?;
+        }
+    }
+}
+
+impl<P: Pipe, C: ByteCodec> AsyncWrite for CodecPipe<P, C> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        // This assumes the caller polls the *same* buffer until completion, so that nothing is held back between writes.
+        let this = self.get_mut();
+        if this.to_write_buf.is_empty() {
+            let plain_n = buf.len().min(MAX_CHUNK);
+            this.codec.encode(&buf[..plain_n], &mut this.to_write_buf);
+            this.to_write_plain = plain_n;
+        }
+        while !this.to_write_buf.is_empty() {
+            let n =
+                futures_util::ready!(Pin::new(&mut this.lower).poll_write(cx, &this.to_write_buf))?;
+            if n == 0 {
+                return Poll::Ready(Err(std::io::ErrorKind::WriteZero.into()));
+            }
+            this.to_write_buf.drain(..n);
+        }
+        Poll::Ready(Ok(this.to_write_plain))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().lower).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().lower).poll_close(cx)
+    }
+}
+
+impl<P: Pipe, C: ByteCodec> Pipe for CodecPipe<P, C> {
+    fn shared_secret(&self) -> Option<&[u8]> {
+        self.lower.shared_secret()
+    }
+
+    fn protocol(&self) -> &str {
+        self.codec.protocol()
+    }
+
+    fn remote_addr(&self) -> Option<&str> {
+        self.lower.remote_addr()
+    }
+}
+
+/// A dialer that wraps every pipe of its inner dialer in a [CodecPipe], each with a fresh clone of the given codec.
+pub struct CodecDialer<D: Dialer, C: ByteCodec + Clone + Sync> {
+    inner: D,
+    codec: C,
+}
+
+impl<D: Dialer, C: ByteCodec + Clone + Sync> CodecDialer<D, C> {
+    pub fn new(inner: D, codec: C) -> Self {
+        Self { inner, codec }
+    }
+}
+
+#[async_trait]
+impl<D: Dialer, C: ByteCodec + Clone + Sync> Dialer for CodecDialer<D, C> {
+    type P = CodecPipe<D::P, C>;
+
+    async fn dial(&self) -> std::io::Result<Self::P> {
+        Ok(CodecPipe::new(self.inner.dial().await?, self.codec.clone()))
+    }
+}
+
+/// A listener that wraps every pipe of its inner listener in a [CodecPipe], each with a fresh clone of the given codec.
+pub struct CodecListener<L: Listener, C: ByteCodec + Clone + Sync> {
+    inner: L,
+    codec: C,
+}
+
+impl<L: Listener, C: ByteCodec + Clone + Sync> CodecListener<L, C> {
+    pub fn new(inner: L, codec: C) -> Self {
+        Self { inner, codec }
+    }
+}
+
+#[async_trait]
+impl<L: Listener, C: ByteCodec + Clone + Sync> Listener for CodecListener<L, C> {
+    type P = CodecPipe<L::P, C>;
+
+    async fn accept(&mut self) -> std::io::Result<Self::P> {
+        Ok(CodecPipe::new(
+            self.inner.accept().await?,
+            self.codec.clone(),
+        ))
+    }
+}
@@ -3,6 +3,8 @@ use std::pin::{pin, Pin};
 use futures_util::{AsyncRead, AsyncWrite};
 use pin_project::pin_project;
 
+pub mod accept;
+pub mod codec;
 pub mod dialer;
 pub mod listener;
 pub mod tcp;
@@ -19,6 +21,11 @@ pub trait Pipe: AsyncRead + AsyncWrite + Send + Unpin + 'static {
 
     /// This might return a string that is some sort of human-readable identifier of the remote address.
     fn remote_addr(&self) -> Option<&str>;
+
+    /// If this pipe is a plain TCP connection with nothing layered on top, returns the socket, so that data can be relayed without passing through userspace.
+    fn as_tcp(&self) -> Option<&async_io::Async<std::net::TcpStream>> {
+        None
+    }
 }
 
 impl Pipe for Box<dyn Pipe> {
@@ -33,6 +40,10 @@ impl Pipe for Box<dyn Pipe> {
     fn remote_addr(&self) -> Option<&str> {
         (**self).remote_addr()
     }
+
+    fn as_tcp(&self) -> Option<&async_io::Async<std::net::TcpStream>> {
+        (**self).as_tcp()
+    }
 }
 
 /// EitherPipe is a pipe that is either left or right.
@@ -109,4 +120,11 @@ impl<L: Pipe, R: Pipe> Pipe for EitherPipe<L, R> {
             EitherPipe::Right(r) => r.remote_addr(),
         }
     }
+
+    fn as_tcp(&self) -> Option<&async_io::Async<std::net::TcpStream>> {
+        match self {
+            EitherPipe::Left(l) => l.as_tcp(),
+            EitherPipe::Right(r) => r.as_tcp(),
+        }
+    }
 }
@@ -183,4 +183,20 @@ impl Pipe for TcpPipe {
     fn remote_addr(&self) -> Option<&str> {
         Some(&self.1)
     }
+
+    fn as_tcp(&self) -> Option<&Async<TcpStream>> {
+        Some(&self.0)
+    }
+}
+
+impl TcpPipe {
+    /// Wraps a TCP connection that was set up some other way, such as by accepting from a transparent-proxy socket.
+    pub fn new(inner: Async<TcpStream>) -> Self {
+        let addr = inner
+            .get_ref()
+            .peer_addr()
+            .map(|addr| addr.to_string())
+            .unwrap_or_default();
+        Self(inner, addr)
+    }
 }